@@ -0,0 +1,7 @@
+CREATE TABLE IF NOT EXISTS repository_config (
+	id	INTEGER PRIMARY KEY,
+	name	VARCHAR,
+	value	VARCHAR
+);
+
+CREATE UNIQUE INDEX IF NOT EXISTS RepositoryConfig_UniqueName ON repository_config(name);
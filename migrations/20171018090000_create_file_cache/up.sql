@@ -0,0 +1,8 @@
+CREATE TABLE IF NOT EXISTS file_cache (
+	path		BLOB PRIMARY KEY,
+	inode		INTEGER,
+	size		INTEGER,
+	mtime_ns	INTEGER,
+	ctime_ns	INTEGER,
+	hash		BLOB
+);
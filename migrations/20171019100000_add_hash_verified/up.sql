@@ -0,0 +1 @@
+ALTER TABLE hashes ADD COLUMN verified INTEGER;
@@ -0,0 +1,2 @@
+ALTER TABLE snapshots DROP COLUMN bytes_new;
+ALTER TABLE snapshots DROP COLUMN bytes_read;
@@ -0,0 +1,2 @@
+ALTER TABLE snapshots ADD COLUMN bytes_read INTEGER;
+ALTER TABLE snapshots ADD COLUMN bytes_new INTEGER;
@@ -0,0 +1 @@
+DROP TABLE append_cache;
@@ -0,0 +1,7 @@
+CREATE TABLE IF NOT EXISTS append_cache (
+	path		BLOB PRIMARY KEY,
+	inode		INTEGER,
+	resume_offset	INTEGER,
+	tail_hash	BLOB,
+	tree_state	BLOB
+);
@@ -0,0 +1,3 @@
+ALTER TABLE snapshots DROP COLUMN bytes_total;
+ALTER TABLE snapshots DROP COLUMN file_count;
+ALTER TABLE snapshots DROP COLUMN finished_datetime;
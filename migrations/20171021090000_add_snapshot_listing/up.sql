@@ -0,0 +1,3 @@
+ALTER TABLE snapshots ADD COLUMN finished_datetime TIMESTAMP;
+ALTER TABLE snapshots ADD COLUMN file_count INTEGER;
+ALTER TABLE snapshots ADD COLUMN bytes_total INTEGER;
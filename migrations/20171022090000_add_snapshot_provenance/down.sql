@@ -0,0 +1,5 @@
+ALTER TABLE snapshots DROP COLUMN source_path;
+ALTER TABLE snapshots DROP COLUMN command_line;
+ALTER TABLE snapshots DROP COLUMN hat_version;
+ALTER TABLE snapshots DROP COLUMN username;
+ALTER TABLE snapshots DROP COLUMN hostname;
@@ -0,0 +1,5 @@
+ALTER TABLE snapshots ADD COLUMN hostname VARCHAR;
+ALTER TABLE snapshots ADD COLUMN username VARCHAR;
+ALTER TABLE snapshots ADD COLUMN hat_version VARCHAR;
+ALTER TABLE snapshots ADD COLUMN command_line VARCHAR;
+ALTER TABLE snapshots ADD COLUMN source_path VARCHAR;
@@ -0,0 +1,7 @@
+CREATE TABLE IF NOT EXISTS snapshot_labels (
+	id			INTEGER PRIMARY KEY,
+	snapshot_unique_id	INTEGER,
+	label			VARCHAR
+);
+
+CREATE UNIQUE INDEX IF NOT EXISTS SnapshotLabels_UniqueLabel ON snapshot_labels(snapshot_unique_id, label);
@@ -0,0 +1,2 @@
+ALTER TABLE key_data DROP COLUMN default_acl;
+ALTER TABLE key_data DROP COLUMN acl;
@@ -0,0 +1,2 @@
+ALTER TABLE key_data ADD COLUMN acl TEXT;
+ALTER TABLE key_data ADD COLUMN default_acl TEXT;
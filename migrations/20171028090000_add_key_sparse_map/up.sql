@@ -0,0 +1 @@
+ALTER TABLE key_data ADD COLUMN sparse_map BLOB;
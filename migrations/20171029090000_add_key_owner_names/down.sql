@@ -0,0 +1,2 @@
+ALTER TABLE key_data DROP COLUMN group_name;
+ALTER TABLE key_data DROP COLUMN user_name;
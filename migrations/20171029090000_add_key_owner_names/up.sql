@@ -0,0 +1,2 @@
+ALTER TABLE key_data ADD COLUMN user_name TEXT;
+ALTER TABLE key_data ADD COLUMN group_name TEXT;
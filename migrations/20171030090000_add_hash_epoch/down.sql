@@ -0,0 +1 @@
+ALTER TABLE hashes DROP COLUMN epoch;
@@ -0,0 +1 @@
+ALTER TABLE hashes ADD COLUMN epoch INTEGER NOT NULL DEFAULT 0;
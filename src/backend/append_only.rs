@@ -0,0 +1,175 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use backend::{ObjectTags, StoreBackend};
+use blob::LOCK_PREFIX;
+use crypto::CipherText;
+use std::collections::HashSet;
+use std::sync::Mutex;
+
+/// A backend that never loses data once stored.
+///
+/// Deletes are refused, as are writes that would replace a blob with different contents.
+/// Rewriting a blob with the same contents is allowed, so that interrupted uploads can be
+/// retried. This lets a client that may be compromised back up to a repository without being
+/// able to destroy its history; pruning is left to a privileged client (see `with_privileged`).
+/// Repository locks hold no data, and can always be replaced and removed.
+///
+/// Which blobs exist is listed once, rather than looked up for every store. A blob stored by
+/// another client since is not noticed, but blob names are unique to the data they hold.
+pub struct AppendOnlyBackend<B> {
+    inner: B,
+    enforcing: bool,
+    privileged: bool,
+    // Names of the blobs known to exist, once listed.
+    existing: Mutex<Option<HashSet<Vec<u8>>>>,
+}
+
+impl<B: StoreBackend> AppendOnlyBackend<B> {
+    pub fn new(inner: B) -> AppendOnlyBackend<B> {
+        AppendOnlyBackend {
+            inner: inner,
+            enforcing: true,
+            privileged: false,
+            existing: Mutex::new(None),
+        }
+    }
+
+    /// Pass deletes and overwrites through to the inner backend instead.
+    pub fn with_enforcing(self, enforcing: bool) -> AppendOnlyBackend<B> {
+        AppendOnlyBackend { enforcing: enforcing, ..self }
+    }
+
+    /// Pass deletes and overwrites through, also of a repository created append-only, to
+    /// prune it.
+    pub fn with_privileged(self, privileged: bool) -> AppendOnlyBackend<B> {
+        AppendOnlyBackend {
+            enforcing: self.enforcing && !privileged,
+            privileged: privileged,
+            ..self
+        }
+    }
+
+    /// The wrapped backend.
+    pub fn inner(&self) -> &B {
+        &self.inner
+    }
+
+    fn check_overwrite(&self, name: &[u8], data: Option<&CipherText>) -> Result<(), String> {
+        if !self.enforcing || name.starts_with(LOCK_PREFIX) {
+            return Ok(());
+        }
+        {
+            let mut existing = self.existing.lock().unwrap();
+            if existing.is_none() {
+                let names = self.inner.list()?.into_iter().map(|n| n.into_vec()).collect();
+                *existing = Some(names);
+            }
+            if !existing.as_ref().unwrap().contains(name) {
+                return Ok(());
+            }
+        }
+        match self.inner.retrieve(name)? {
+            None => Ok(()),
+            Some(ref old) if data.map_or(false, |d| *old == d.to_vec()) => Ok(()),
+            Some(_) => Err(format!("Append-only: refusing to overwrite blob '{:?}'", name)),
+        }
+    }
+
+    fn stored(&self, name: &[u8]) {
+        if let Some(ref mut existing) = *self.existing.lock().unwrap() {
+            existing.insert(name.to_vec());
+        }
+    }
+}
+
+impl<B: StoreBackend> StoreBackend for AppendOnlyBackend<B> {
+    fn store(&self, name: &[u8], data: &CipherText) -> Result<(), String> {
+        self.check_overwrite(name, Some(data))?;
+        self.inner.store(name, data)?;
+        self.stored(name);
+        Ok(())
+    }
+
+    fn retrieve(&self, name: &[u8]) -> Result<Option<Vec<u8>>, String> {
+        self.inner.retrieve(name)
+    }
+
+    fn delete(&self, name: &[u8]) -> Result<(), String> {
+        if self.enforcing && !name.starts_with(LOCK_PREFIX) {
+            return Err(format!("Append-only: refusing to delete blob '{:?}'", name));
+        }
+        self.inner.delete(name)?;
+        if let Some(ref mut existing) = *self.existing.lock().unwrap() {
+            existing.remove(name);
+        }
+        Ok(())
+    }
+
+    fn list(&self) -> Result<Vec<Box<[u8]>>, String> {
+        self.inner.list()
+    }
+
+    fn flush(&self) -> Result<(), String> {
+        self.inner.flush()
+    }
+
+    fn store_part(&self, name: &[u8], part: usize, data: &[u8]) -> Result<(), String> {
+        self.inner.store_part(name, part, data)
+    }
+
+    fn commit_parts(&self, name: &[u8], count: usize) -> Result<(), String> {
+        // The assembled contents are not known here, so any existing blob is kept as is.
+        self.check_overwrite(name, None)?;
+        self.inner.commit_parts(name, count)?;
+        self.stored(name);
+        Ok(())
+    }
+
+    fn abort_parts(&self, name: &[u8]) -> Result<(), String> {
+        self.inner.abort_parts(name)
+    }
+
+    fn replicas(&self) -> usize {
+        self.inner.replicas()
+    }
+
+    fn retrieve_replica(&self, name: &[u8], replica: usize) -> Result<Option<Vec<u8>>, String> {
+        self.inner.retrieve_replica(name, replica)
+    }
+
+    fn set_object_tags(&self, name: &[u8], tags: &ObjectTags) -> Result<(), String> {
+        self.inner.set_object_tags(name, tags)
+    }
+
+    fn object_tags(&self, name: &[u8]) -> Result<Option<ObjectTags>, String> {
+        self.inner.object_tags(name)
+    }
+
+    fn size(&self, name: &[u8]) -> Result<Option<u64>, String> {
+        self.inner.size(name)
+    }
+
+    fn append_only(&self) -> bool {
+        self.enforcing || self.inner.append_only()
+    }
+
+    fn privileged(&self) -> bool {
+        self.privileged || self.inner.privileged()
+    }
+
+    fn delete_concurrency(&self) -> usize {
+        self.inner.delete_concurrency()
+    }
+}
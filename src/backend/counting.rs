@@ -0,0 +1,116 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use backend::{ObjectTags, StoreBackend};
+use crypto::CipherText;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// A backend that counts the calls to its inner backend that failed, for monitoring.
+pub struct CountingBackend<B> {
+    inner: B,
+    errors: Arc<AtomicUsize>,
+}
+
+impl<B: StoreBackend> CountingBackend<B> {
+    pub fn new(inner: B) -> CountingBackend<B> {
+        CountingBackend {
+            inner: inner,
+            errors: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// The number of failed calls so far; it keeps counting after this returns.
+    pub fn errors(&self) -> Arc<AtomicUsize> {
+        self.errors.clone()
+    }
+
+    /// The wrapped backend.
+    pub fn inner(&self) -> &B {
+        &self.inner
+    }
+
+    fn count<T>(&self, res: Result<T, String>) -> Result<T, String> {
+        if res.is_err() {
+            self.errors.fetch_add(1, Ordering::SeqCst);
+        }
+        res
+    }
+}
+
+impl<B: StoreBackend> StoreBackend for CountingBackend<B> {
+    fn store(&self, name: &[u8], data: &CipherText) -> Result<(), String> {
+        self.count(self.inner.store(name, data))
+    }
+
+    fn retrieve(&self, name: &[u8]) -> Result<Option<Vec<u8>>, String> {
+        self.count(self.inner.retrieve(name))
+    }
+
+    fn delete(&self, name: &[u8]) -> Result<(), String> {
+        self.count(self.inner.delete(name))
+    }
+
+    fn list(&self) -> Result<Vec<Box<[u8]>>, String> {
+        self.count(self.inner.list())
+    }
+
+    fn flush(&self) -> Result<(), String> {
+        self.count(self.inner.flush())
+    }
+
+    fn store_part(&self, name: &[u8], part: usize, data: &[u8]) -> Result<(), String> {
+        self.count(self.inner.store_part(name, part, data))
+    }
+
+    fn commit_parts(&self, name: &[u8], count: usize) -> Result<(), String> {
+        self.count(self.inner.commit_parts(name, count))
+    }
+
+    fn abort_parts(&self, name: &[u8]) -> Result<(), String> {
+        self.count(self.inner.abort_parts(name))
+    }
+
+    fn replicas(&self) -> usize {
+        self.inner.replicas()
+    }
+
+    fn retrieve_replica(&self, name: &[u8], replica: usize) -> Result<Option<Vec<u8>>, String> {
+        self.count(self.inner.retrieve_replica(name, replica))
+    }
+
+    fn set_object_tags(&self, name: &[u8], tags: &ObjectTags) -> Result<(), String> {
+        self.count(self.inner.set_object_tags(name, tags))
+    }
+
+    fn object_tags(&self, name: &[u8]) -> Result<Option<ObjectTags>, String> {
+        self.count(self.inner.object_tags(name))
+    }
+
+    fn size(&self, name: &[u8]) -> Result<Option<u64>, String> {
+        self.count(self.inner.size(name))
+    }
+
+    fn append_only(&self) -> bool {
+        self.inner.append_only()
+    }
+
+    fn privileged(&self) -> bool {
+        self.inner.privileged()
+    }
+
+    fn delete_concurrency(&self) -> usize {
+        self.inner.delete_concurrency()
+    }
+}
@@ -37,4 +37,16 @@ impl StoreBackend for DevNullBackend {
     fn flush(&self) -> Result<(), String> {
         Ok(())
     }
+
+    fn store_part(&self, _name: &[u8], _part: usize, _data: &[u8]) -> Result<(), String> {
+        Ok(())
+    }
+
+    fn commit_parts(&self, _name: &[u8], _count: usize) -> Result<(), String> {
+        Ok(())
+    }
+
+    fn abort_parts(&self, _name: &[u8]) -> Result<(), String> {
+        Ok(())
+    }
 }
@@ -70,6 +70,12 @@ impl FileBackend {
         self.read_cache.lock().unwrap().remove(name);
     }
 
+    fn part_path(&self, name: &[u8], part: usize) -> PathBuf {
+        let mut p = self.root.clone();
+        p.push(&format!("{}.part{}", name.to_hex(), part));
+        p
+    }
+
     fn guarded_cache_put(&self, name: Vec<u8>, result: Result<Option<Vec<u8>>, String>) {
         let mut cache = self.read_cache.lock().unwrap();
         if cache.len() >= self.max_cache_size {
@@ -85,6 +91,7 @@ impl StoreBackend for FileBackend {
 
         let mut path = self.root.clone();
         path.push(&name.to_hex());
+        debug!("Backend store: {} ({} bytes)", path.display(), data.len());
 
         let mut file = match fs::File::create(&path) {
             Err(e) => return Err(e.to_string()),
@@ -103,9 +110,11 @@ impl StoreBackend for FileBackend {
         // Check for key in cache:
         let value_opt = self.guarded_cache_get(name);
         if let Some(r) = value_opt {
+            trace!("Backend retrieve (cached): {}", name.to_hex());
             return r;
         }
 
+        debug!("Backend retrieve: {}", name.to_hex());
         let res = self.get(name);
 
         // Update cache to contain key:
@@ -115,6 +124,7 @@ impl StoreBackend for FileBackend {
     }
 
     fn delete(&self, name: &[u8]) -> Result<(), String> {
+        debug!("Backend delete: {}", name.to_hex());
         let name = name.to_vec();
         self.guarded_cache_delete(&name);
 
@@ -132,11 +142,13 @@ impl StoreBackend for FileBackend {
 
     fn list(&self) -> Result<Vec<Box<[u8]>>, String> {
         let es = &|e: io::Error| e.to_string();
+        debug!("Backend list: {}", self.root.display());
 
         let mut out = vec![];
         for p in fs::read_dir(&self.root).map_err(es)? {
             if let Some(name) = p.map_err(es)?.path().file_name() {
-                name.to_str().map(|s| Vec::from_hex(s).unwrap()).map(|b| {
+                // Skip anything that is not a blob name (e.g. parts of unfinished uploads).
+                name.to_str().and_then(|s| Vec::from_hex(s).ok()).map(|b| {
                     out.push(b.into_boxed_slice())
                 });
             }
@@ -147,4 +159,46 @@ impl StoreBackend for FileBackend {
     fn flush(&self) -> Result<(), String> {
         Ok(())
     }
+
+    fn size(&self, name: &[u8]) -> Result<Option<u64>, String> {
+        let mut path = self.root.clone();
+        path.push(&name.to_hex());
+        match fs::metadata(&path) {
+            Ok(meta) => Ok(Some(meta.len())),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.to_string()),
+        }
+    }
+
+    fn store_part(&self, name: &[u8], part: usize, data: &[u8]) -> Result<(), String> {
+        use self::io::Write;
+
+        let path = self.part_path(name, part);
+        let mut file = fs::File::create(&path).map_err(|e| e.to_string())?;
+        file.write_all(data).map_err(|e| e.to_string())
+    }
+
+    fn commit_parts(&self, name: &[u8], count: usize) -> Result<(), String> {
+        let es = &|e: io::Error| e.to_string();
+        self.guarded_cache_delete(name);
+
+        let mut path = self.root.clone();
+        path.push(&name.to_hex());
+
+        let mut file = fs::File::create(&path).map_err(es)?;
+        for i in 0..count {
+            let mut part = fs::File::open(self.part_path(name, i)).map_err(es)?;
+            io::copy(&mut part, &mut file).map_err(es)?;
+        }
+
+        self.abort_parts(name)
+    }
+
+    fn abort_parts(&self, name: &[u8]) -> Result<(), String> {
+        let mut part = 0;
+        while fs::remove_file(self.part_path(name, part)).is_ok() {
+            part += 1;
+        }
+        Ok(())
+    }
 }
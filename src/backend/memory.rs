@@ -13,18 +13,24 @@
 // limitations under the License.
 
 
-use backend::StoreBackend;
+use backend::{ObjectTags, StoreBackend};
 use crypto::CipherText;
 use std::collections::BTreeMap;
 use std::sync::Mutex;
 
 pub struct MemoryBackend {
     files: Mutex<BTreeMap<Vec<u8>, Vec<u8>>>,
+    parts: Mutex<BTreeMap<Vec<u8>, BTreeMap<usize, Vec<u8>>>>,
+    tags: Mutex<BTreeMap<Vec<u8>, ObjectTags>>,
 }
 
 impl MemoryBackend {
     pub fn new() -> MemoryBackend {
-        MemoryBackend { files: Mutex::new(BTreeMap::new()) }
+        MemoryBackend {
+            files: Mutex::new(BTreeMap::new()),
+            parts: Mutex::new(BTreeMap::new()),
+            tags: Mutex::new(BTreeMap::new()),
+        }
     }
 
     fn guarded_insert(&self, key: Vec<u8>, value: Vec<u8>) -> Result<(), String> {
@@ -46,6 +52,7 @@ impl MemoryBackend {
     fn guarded_delete(&self, key: &[u8]) -> Result<(), String> {
         let mut guarded_files = self.files.lock().unwrap();
         guarded_files.remove(key);
+        self.tags.lock().unwrap().remove(key);
         Ok(())
     }
 
@@ -59,6 +66,32 @@ impl MemoryBackend {
                 .collect(),
         )
     }
+
+    fn guarded_insert_part(&self, key: Vec<u8>, part: usize, value: Vec<u8>) -> Result<(), String> {
+        let mut guarded_parts = self.parts.lock().unwrap();
+        guarded_parts.entry(key).or_insert_with(BTreeMap::new).insert(
+            part,
+            value,
+        );
+        Ok(())
+    }
+
+    fn guarded_take_parts(&self, key: &[u8], count: usize) -> Result<Vec<u8>, String> {
+        let mut guarded_parts = self.parts.lock().unwrap();
+        let parts = match guarded_parts.remove(key) {
+            Some(parts) => parts,
+            None => return Err(format!("No parts uploaded for key: '{:?}'", key)),
+        };
+
+        let mut value = vec![];
+        for i in 0..count {
+            match parts.get(&i) {
+                Some(part) => value.extend_from_slice(&part[..]),
+                None => return Err(format!("Missing part {} for key: '{:?}'", i, key)),
+            }
+        }
+        Ok(value)
+    }
 }
 
 impl StoreBackend for MemoryBackend {
@@ -81,4 +114,30 @@ impl StoreBackend for MemoryBackend {
     fn flush(&self) -> Result<(), String> {
         Ok(())
     }
+
+    fn store_part(&self, name: &[u8], part: usize, data: &[u8]) -> Result<(), String> {
+        self.guarded_insert_part(name.to_vec(), part, data.to_vec())
+    }
+
+    fn commit_parts(&self, name: &[u8], count: usize) -> Result<(), String> {
+        let value = self.guarded_take_parts(name, count)?;
+        self.guarded_insert(name.to_vec(), value)
+    }
+
+    fn abort_parts(&self, name: &[u8]) -> Result<(), String> {
+        self.parts.lock().unwrap().remove(name);
+        Ok(())
+    }
+
+    fn set_object_tags(&self, name: &[u8], tags: &ObjectTags) -> Result<(), String> {
+        if !self.files.lock().unwrap().contains_key(name) {
+            return Err(format!("Cannot tag missing key: '{:?}'", name));
+        }
+        self.tags.lock().unwrap().insert(name.to_vec(), tags.clone());
+        Ok(())
+    }
+
+    fn object_tags(&self, name: &[u8]) -> Result<Option<ObjectTags>, String> {
+        Ok(self.tags.lock().unwrap().get(name).cloned())
+    }
 }
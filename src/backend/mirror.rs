@@ -0,0 +1,171 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+use backend::{ObjectTags, StoreBackend};
+use crypto::CipherText;
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+/// A backend replicated across several sources.
+///
+/// Writes go to every source. Reads are served by the first source that has the blob, so a
+/// damaged primary falls back to its replicas one blob at a time. Blobs that are present but
+/// corrupt are retried through `retrieve_replica`. The source that served each blob is recorded.
+pub struct MirrorBackend<B> {
+    sources: Vec<B>,
+    served: Mutex<BTreeMap<Vec<u8>, usize>>,
+}
+
+impl<B: StoreBackend> MirrorBackend<B> {
+    /// The first source is the primary; the rest are replicas in order of preference.
+    pub fn new(sources: Vec<B>) -> MirrorBackend<B> {
+        assert!(!sources.is_empty(), "MirrorBackend needs at least one source");
+        MirrorBackend {
+            sources: sources,
+            served: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// The index of the source that served the last read of `name`, if it has been read.
+    pub fn served_by(&self, name: &[u8]) -> Option<usize> {
+        self.served.lock().unwrap().get(name).cloned()
+    }
+
+    /// Number of blobs read from each source.
+    pub fn served_counts(&self) -> Vec<usize> {
+        let mut counts = vec![0; self.sources.len()];
+        for i in self.served.lock().unwrap().values() {
+            counts[*i] += 1;
+        }
+        counts
+    }
+
+    /// The underlying sources, primary first.
+    pub fn sources(&self) -> &[B] {
+        &self.sources
+    }
+
+    fn each<F>(&self, f: F) -> Result<(), String>
+    where
+        F: Fn(&B) -> Result<(), String>,
+    {
+        for s in &self.sources {
+            f(s)?;
+        }
+        Ok(())
+    }
+}
+
+impl<B: StoreBackend> StoreBackend for MirrorBackend<B> {
+    fn store(&self, name: &[u8], data: &CipherText) -> Result<(), String> {
+        self.each(|s| s.store(name, data))
+    }
+
+    fn retrieve(&self, name: &[u8]) -> Result<Option<Vec<u8>>, String> {
+        let mut errors = vec![];
+        for (i, s) in self.sources.iter().enumerate() {
+            match s.retrieve(name) {
+                Ok(Some(data)) => {
+                    if i > 0 {
+                        warn!("Blob {:?} served by replica {}", name, i);
+                    }
+                    self.served.lock().unwrap().insert(name.to_vec(), i);
+                    return Ok(Some(data));
+                }
+                Ok(None) => (),
+                Err(e) => errors.push(format!("source {}: {}", i, e)),
+            }
+        }
+        if errors.is_empty() {
+            Ok(None)
+        } else {
+            Err(errors.join("; "))
+        }
+    }
+
+    fn delete(&self, name: &[u8]) -> Result<(), String> {
+        self.each(|s| s.delete(name))
+    }
+
+    fn list(&self) -> Result<Vec<Box<[u8]>>, String> {
+        let mut last_err = None;
+        for s in &self.sources {
+            match s.list() {
+                Ok(names) => return Ok(names),
+                Err(e) => last_err = Some(e),
+            }
+        }
+        Err(last_err.unwrap())
+    }
+
+    fn flush(&self) -> Result<(), String> {
+        self.each(|s| s.flush())
+    }
+
+    fn store_part(&self, name: &[u8], part: usize, data: &[u8]) -> Result<(), String> {
+        self.each(|s| s.store_part(name, part, data))
+    }
+
+    fn commit_parts(&self, name: &[u8], count: usize) -> Result<(), String> {
+        self.each(|s| s.commit_parts(name, count))
+    }
+
+    fn abort_parts(&self, name: &[u8]) -> Result<(), String> {
+        self.each(|s| s.abort_parts(name))
+    }
+
+    fn replicas(&self) -> usize {
+        self.sources.len()
+    }
+
+    fn retrieve_replica(&self, name: &[u8], replica: usize) -> Result<Option<Vec<u8>>, String> {
+        let found = self.sources[replica].retrieve(name)?;
+        if found.is_some() {
+            warn!("Blob {:?} served by replica {}", name, replica);
+            self.served.lock().unwrap().insert(name.to_vec(), replica);
+        }
+        Ok(found)
+    }
+
+    fn set_object_tags(&self, name: &[u8], tags: &ObjectTags) -> Result<(), String> {
+        self.each(|s| s.set_object_tags(name, tags))
+    }
+
+    fn object_tags(&self, name: &[u8]) -> Result<Option<ObjectTags>, String> {
+        for s in &self.sources {
+            if let Some(tags) = s.object_tags(name)? {
+                return Ok(Some(tags));
+            }
+        }
+        Ok(None)
+    }
+
+    fn size(&self, name: &[u8]) -> Result<Option<u64>, String> {
+        for s in &self.sources {
+            if let Some(size) = s.size(name)? {
+                return Ok(Some(size));
+            }
+        }
+        Ok(None)
+    }
+
+    fn append_only(&self) -> bool {
+        self.sources.iter().any(|s| s.append_only())
+    }
+
+    fn privileged(&self) -> bool {
+        self.sources.iter().all(|s| s.privileged())
+    }
+}
@@ -12,15 +12,49 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+mod append_only;
+mod counting;
 mod devnull;
 mod file;
 mod memory;
+mod mirror;
+mod throttled;
 
 use crypto::CipherText;
 
+pub use self::append_only::AppendOnlyBackend;
+pub use self::counting::CountingBackend;
 pub use self::devnull::DevNullBackend;
 pub use self::file::FileBackend;
 pub use self::memory::MemoryBackend;
+pub use self::mirror::MirrorBackend;
+pub use self::throttled::{Throttle, ThrottledBackend};
+
+/// Provider-side metadata attached to stored objects, such as S3 object tags or GCS metadata.
+/// Lets lifecycle policies and bucket inventories tell objects apart without reading them.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ObjectTags {
+    /// Identifies the repository that wrote the object.
+    pub repository: String,
+    /// What kind of object this is: "data", "root" or "quarantine".
+    pub class: String,
+    /// Identifies the process run that created the object.
+    pub run: String,
+}
+
+impl ObjectTags {
+    pub fn new(repository: String, run: String) -> ObjectTags {
+        ObjectTags {
+            repository: repository,
+            class: "data".to_string(),
+            run: run,
+        }
+    }
+
+    pub fn with_class(&self, class: &str) -> ObjectTags {
+        ObjectTags { class: class.to_string(), ..self.clone() }
+    }
+}
 
 pub trait StoreBackend: Sync + Send + 'static {
     fn store(&self, name: &[u8], data: &CipherText) -> Result<(), String>;
@@ -28,4 +62,58 @@ pub trait StoreBackend: Sync + Send + 'static {
     fn delete(&self, name: &[u8]) -> Result<(), String>;
     fn list(&self) -> Result<Vec<Box<[u8]>>, String>;
     fn flush(&self) -> Result<(), String>;
+
+    /// Upload one part of a multipart blob. Parts are numbered from zero and must not be visible
+    /// through `retrieve` or `list` before `commit_parts` has been called for the same name.
+    fn store_part(&self, name: &[u8], part: usize, data: &[u8]) -> Result<(), String>;
+
+    /// Assemble the parts `0..count` of a multipart blob into a single blob named `name`.
+    fn commit_parts(&self, name: &[u8], count: usize) -> Result<(), String>;
+
+    /// Discard any uploaded parts of an unfinished multipart blob.
+    fn abort_parts(&self, name: &[u8]) -> Result<(), String>;
+
+    /// Number of independent copies that blobs can be read from.
+    fn replicas(&self) -> usize {
+        1
+    }
+
+    /// Retrieve a blob from one specific copy, where copy 0 is the primary.
+    fn retrieve_replica(&self, name: &[u8], replica: usize) -> Result<Option<Vec<u8>>, String> {
+        assert_eq!(0, replica);
+        self.retrieve(name)
+    }
+
+    /// Attach provider-side metadata to a stored object. Backends without support for object
+    /// metadata ignore it.
+    fn set_object_tags(&self, _name: &[u8], _tags: &ObjectTags) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// Read back the metadata attached by `set_object_tags`, if the backend keeps any.
+    fn object_tags(&self, _name: &[u8]) -> Result<Option<ObjectTags>, String> {
+        Ok(None)
+    }
+
+    /// Bytes stored for a blob, or `None` if there is no such blob. Backends that can tell
+    /// without reading the blob should say so.
+    fn size(&self, name: &[u8]) -> Result<Option<u64>, String> {
+        Ok(self.retrieve(name)?.map(|data| data.len() as u64))
+    }
+
+    /// Whether deletes are refused, so that obsolete blobs must be left in place.
+    fn append_only(&self) -> bool {
+        false
+    }
+
+    /// Whether deletes go through although the repository was created append-only, e.g. to
+    /// prune it.
+    fn privileged(&self) -> bool {
+        false
+    }
+
+    /// How many deletes callers may issue at once, e.g. when `gc` sweeps many blobs.
+    fn delete_concurrency(&self) -> usize {
+        1
+    }
 }
@@ -0,0 +1,159 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use backend::{ObjectTags, StoreBackend};
+use crypto::CipherText;
+use std::sync::{Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Limits on the deletes issued to a backend, e.g. to stay below an object store's request
+/// rate limits while `gc` sweeps. `None` means no limit.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Throttle {
+    /// Deletes started per second.
+    pub ops_per_sec: Option<u32>,
+    /// Deletes in flight at once.
+    pub concurrency: Option<usize>,
+}
+
+/// A backend that holds back deletes to stay within a `Throttle`. Other calls pass straight
+/// through, so that the traffic of a commit running alongside is not slowed down.
+pub struct ThrottledBackend<B> {
+    inner: B,
+    throttle: Throttle,
+    // When the next delete may start.
+    next_slot: Mutex<Instant>,
+    in_flight: Mutex<usize>,
+    done: Condvar,
+}
+
+impl<B: StoreBackend> ThrottledBackend<B> {
+    pub fn new(inner: B, throttle: Throttle) -> ThrottledBackend<B> {
+        ThrottledBackend {
+            inner: inner,
+            throttle: throttle,
+            next_slot: Mutex::new(Instant::now()),
+            in_flight: Mutex::new(0),
+            done: Condvar::new(),
+        }
+    }
+
+    /// The wrapped backend.
+    pub fn inner(&self) -> &B {
+        &self.inner
+    }
+
+    fn wait_for_slot(&self) {
+        let ops = match self.throttle.ops_per_sec {
+            Some(ops) if ops > 0 => ops,
+            _ => return,
+        };
+        let interval = Duration::from_secs(1) / ops;
+        let wait = {
+            let mut next = self.next_slot.lock().unwrap();
+            let now = Instant::now();
+            let slot = if *next > now { *next } else { now };
+            *next = slot + interval;
+            slot - now
+        };
+        if wait > Duration::from_secs(0) {
+            thread::sleep(wait);
+        }
+    }
+
+    fn throttled<T, F: FnOnce() -> T>(&self, op: F) -> T {
+        let limit = self.throttle.concurrency.unwrap_or(0);
+        if limit > 0 {
+            let mut in_flight = self.in_flight.lock().unwrap();
+            while *in_flight >= limit {
+                in_flight = self.done.wait(in_flight).unwrap();
+            }
+            *in_flight += 1;
+        }
+        self.wait_for_slot();
+        let res = op();
+        if limit > 0 {
+            *self.in_flight.lock().unwrap() -= 1;
+            self.done.notify_one();
+        }
+        res
+    }
+}
+
+impl<B: StoreBackend> StoreBackend for ThrottledBackend<B> {
+    fn store(&self, name: &[u8], data: &CipherText) -> Result<(), String> {
+        self.inner.store(name, data)
+    }
+
+    fn retrieve(&self, name: &[u8]) -> Result<Option<Vec<u8>>, String> {
+        self.inner.retrieve(name)
+    }
+
+    fn delete(&self, name: &[u8]) -> Result<(), String> {
+        self.throttled(|| self.inner.delete(name))
+    }
+
+    fn list(&self) -> Result<Vec<Box<[u8]>>, String> {
+        self.inner.list()
+    }
+
+    fn flush(&self) -> Result<(), String> {
+        self.inner.flush()
+    }
+
+    fn store_part(&self, name: &[u8], part: usize, data: &[u8]) -> Result<(), String> {
+        self.inner.store_part(name, part, data)
+    }
+
+    fn commit_parts(&self, name: &[u8], count: usize) -> Result<(), String> {
+        self.inner.commit_parts(name, count)
+    }
+
+    fn abort_parts(&self, name: &[u8]) -> Result<(), String> {
+        self.inner.abort_parts(name)
+    }
+
+    fn replicas(&self) -> usize {
+        self.inner.replicas()
+    }
+
+    fn retrieve_replica(&self, name: &[u8], replica: usize) -> Result<Option<Vec<u8>>, String> {
+        self.inner.retrieve_replica(name, replica)
+    }
+
+    fn set_object_tags(&self, name: &[u8], tags: &ObjectTags) -> Result<(), String> {
+        self.inner.set_object_tags(name, tags)
+    }
+
+    fn object_tags(&self, name: &[u8]) -> Result<Option<ObjectTags>, String> {
+        self.inner.object_tags(name)
+    }
+
+    fn size(&self, name: &[u8]) -> Result<Option<u64>, String> {
+        self.inner.size(name)
+    }
+
+    fn append_only(&self) -> bool {
+        self.inner.append_only()
+    }
+
+    fn privileged(&self) -> bool {
+        self.inner.privileged()
+    }
+
+    fn delete_concurrency(&self) -> usize {
+        self.throttle.concurrency.unwrap_or(1).max(1)
+    }
+}
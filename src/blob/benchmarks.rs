@@ -36,7 +36,7 @@ fn dummy_hashref(keys: &crypto::keys::Keeper) -> HashRef {
         leaf: leaf,
         persistent_ref: ChunkRef {
             blob_id: None,
-            blob_name: vec![],
+            blob_name: vec![].into(),
             offset: 0,
             length: 0,
             packing: None,
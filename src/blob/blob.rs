@@ -16,6 +16,7 @@ use crypto;
 use crypto::{CipherText, CipherTextRef, PlainTextRef};
 use hash::tree::HashRef;
 
+use std::cmp;
 use std::mem;
 use std::sync::Arc;
 
@@ -59,14 +60,16 @@ impl Blob {
         assert!(href_bytes.len() < 65535);
 
         if self.upperbound_len() + 1 + href_bytes.len() + ct.len() >= self.max_len {
-            if self.chunks.len() == 0 {
-                panic!(
-                    "Can never fit chunk of size {} in blob of size {}",
-                    chunk.len(),
-                    self.max_len
-                );
+            if self.chunks.len() > 0 {
+                return Err(());
             }
-            return Err(());
+            // The chunk can never fit in a regular blob, so it gets an oversized blob to itself.
+            // Oversized blobs are uploaded in parts by the blob store.
+            debug!(
+                "Oversized blob for chunk of size {} (max blob size is {})",
+                chunk.len(),
+                self.max_len
+            );
         }
 
         self.chunks.append(ct);
@@ -99,14 +102,15 @@ impl Blob {
         );
         self.footer.truncate(0);
 
-        assert!(self.chunks.len() + footer_overhead <= self.max_len);
+        // Only a blob holding a single oversized chunk may exceed the maximum length.
+        let blob_len = cmp::max(self.max_len, self.chunks.len() + footer_overhead);
 
         let mut out = mem::replace(&mut self.chunks, CipherText::empty());
-        out.random_pad_upto(self.max_len - footer_overhead);
+        out.random_pad_upto(blob_len - footer_overhead);
         out.append(footer);
         out.append_authentication(&self.keys);
 
-        assert_eq!(out.len(), self.max_len);
+        assert_eq!(out.len(), blob_len);
 
         // Everything has been reset. We are ready to go again.
         assert_eq!(0, self.chunks.len());
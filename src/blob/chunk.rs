@@ -12,6 +12,7 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use blob::BlobId;
 use capnp;
 use root_capnp;
 use secstr;
@@ -100,7 +101,7 @@ impl From<LeafType> for u64 {
 #[derive(Debug, Clone)]
 pub struct ChunkRef {
     pub blob_id: Option<i64>,
-    pub blob_name: Vec<u8>,
+    pub blob_name: BlobId,
     pub offset: usize,
     pub length: usize,
     pub packing: Option<Packing>,
@@ -169,7 +170,7 @@ impl ChunkRef {
     pub fn read_msg(msg: &root_capnp::chunk_ref::Reader) -> Result<ChunkRef, capnp::Error> {
         Ok(ChunkRef {
             blob_id: None,
-            blob_name: msg.get_blob_name()?.to_owned(),
+            blob_name: BlobId::from(msg.get_blob_name()?.to_owned()),
             offset: msg.get_offset() as usize,
             length: msg.get_length() as usize,
             packing: match msg.get_packing().which()? {
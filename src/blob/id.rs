@@ -0,0 +1,80 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+use blob::{LOCK_PREFIX, MANIFEST_NAME, QUARANTINE_PREFIX, ROOT_PREFIX};
+use hex::ToHex;
+use std::cmp;
+use std::fmt;
+use std::ops::Deref;
+
+/// The name of a blob in external storage.
+///
+/// Names are opaque bytes. Chunk references that do not point into any blob carry an empty name
+/// or a single zero byte.
+#[derive(Clone, Debug, Default, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct BlobId(Vec<u8>);
+
+impl BlobId {
+    /// Validate a name found in external storage. Names that are too short to be blob names, or
+    /// that belong to root documents, locks, quarantined blobs or the manifest, are rejected.
+    pub fn new(bytes: Vec<u8>) -> Result<BlobId, String> {
+        if bytes.len() <= 4 {
+            return Err(format!("Not a blob name: {}", bytes.to_hex()));
+        }
+        if bytes.starts_with(QUARANTINE_PREFIX) || bytes.starts_with(ROOT_PREFIX) ||
+            bytes.starts_with(LOCK_PREFIX) || &bytes[..] == MANIFEST_NAME
+        {
+            return Err(format!(
+                "Reserved name: {}",
+                String::from_utf8_lossy(&bytes[..])
+            ));
+        }
+        Ok(BlobId(bytes))
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0[..]
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.0
+    }
+
+    /// Hex encoding of the first `len` bytes of the name, for spreading blobs over directories.
+    pub fn shard(&self, len: usize) -> String {
+        self.0[..cmp::min(len, self.0.len())].to_hex()
+    }
+}
+
+/// Wrap a name read back from our own metadata, without validation.
+impl From<Vec<u8>> for BlobId {
+    fn from(bytes: Vec<u8>) -> BlobId {
+        BlobId(bytes)
+    }
+}
+
+impl Deref for BlobId {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.0[..]
+    }
+}
+
+impl fmt::Display for BlobId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0.to_hex())
+    }
+}
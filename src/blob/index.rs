@@ -15,6 +15,7 @@
 //! Local state for external blobs and their states.
 
 
+use blob::BlobId;
 use crypto;
 use db;
 
@@ -27,7 +28,7 @@ use tags;
 
 #[derive(Clone, Debug, Default)]
 pub struct BlobDesc {
-    pub name: Vec<u8>,
+    pub name: BlobId,
     pub id: i64,
 }
 
@@ -54,16 +55,19 @@ impl InternalBlobIndex {
         Ok(bi)
     }
 
-    fn name_of_id(&self, id: i64) -> Vec<u8> {
-        return crypto::FixedKey::new(&self.keys)
-            .seal_blob_name(crypto::PlainText::from_i64(id).as_ref())
-            .to_vec();
+    fn name_of_id(&self, id: i64) -> BlobId {
+        return BlobId::from(
+            crypto::FixedKey::new(&self.keys)
+                .seal_blob_name(crypto::PlainText::from_i64(id).as_ref())
+                .to_vec(),
+        );
     }
 
     fn id_of_name(&self, name: &[u8]) -> Result<i64, String> {
         return Ok(
             crypto::FixedKey::new(&self.keys)
                 .unseal_blob_name(crypto::CipherTextRef::new(name))
+                .map_err(|e| e.to_string())?
                 .as_ref()
                 .read_i64()
                 .unwrap(),
@@ -92,7 +96,7 @@ impl InternalBlobIndex {
         *id
     }
 
-    fn recover(&self, name: Vec<u8>) -> BlobDesc {
+    fn recover(&self, name: BlobId) -> BlobDesc {
         let wanted_id = self.id_of_name(&name).unwrap();
         if let Some(id) = {
             self.index.lock().blob_id_from_name(&name[..])
@@ -145,16 +149,26 @@ impl BlobIndex {
         self.0.index.lock().blob_commit(blob)
     }
 
+    /// Record how many bytes of the backend this blob takes up.
+    pub fn set_length(&self, blob: &BlobDesc, bytes: u64) {
+        self.0.index.lock().blob_set_length(blob, bytes)
+    }
+
+    /// The bytes this blob takes up, as recorded when it was stored.
+    pub fn length(&self, blob: &BlobDesc) -> Option<u64> {
+        self.0.index.lock().blob_length(blob)
+    }
+
     /// Reinstall blob recovered by from external storage.
     /// Creates a new blob by a known external name.
-    pub fn recover(&self, name: Vec<u8>) -> BlobDesc {
+    pub fn recover(&self, name: BlobId) -> BlobDesc {
         self.0.recover(name)
     }
 
     pub fn find(&self, name: &[u8]) -> Option<BlobDesc> {
         if let Some(id) = self.0.index.lock().blob_id_from_name(&name) {
             Some(BlobDesc {
-                name: name.to_vec(),
+                name: BlobId::from(name.to_vec()),
                 id: id,
             })
         } else {
@@ -178,7 +192,44 @@ impl BlobIndex {
         self.0.index.lock().blob_delete_by_tag(tag)
     }
 
+    pub fn delete(&self, blob: &BlobDesc) {
+        self.0.index.lock().blob_delete(blob)
+    }
+
+    /// Mark an unused blob for deletion by a later garbage collection run.
+    pub fn condemn(&self, blob: &BlobDesc, utc_secs: i64) {
+        self.0.index.lock().blob_condemn(blob, utc_secs)
+    }
+
+    /// When this blob was first found unused, if it has been since it was last in use.
+    pub fn condemned_at(&self, blob: &BlobDesc) -> Option<i64> {
+        self.0.index.lock().blob_condemned_at(blob)
+    }
+
+    pub fn clear_condemned(&self, blob: &BlobDesc) {
+        self.0.index.lock().blob_clear_condemned(blob)
+    }
+
+    pub fn clear_condemned_by_tag(&self, tag: tags::Tag) {
+        self.0.index.lock().blob_clear_condemned_by_tag(tag)
+    }
+
+    /// Record that the chunks of this blob were just read back and verified.
+    pub fn mark_verified(&self, blob: &BlobDesc, utc_secs: i64) {
+        self.0.index.lock().blob_set_verified(blob, utc_secs)
+    }
+
+    /// The committed blobs with when they were last verified, never verified ones first.
+    pub fn list_by_verified(&self) -> Vec<(BlobDesc, Option<i64>)> {
+        self.0.index.lock().blob_list_by_verified()
+    }
+
     pub fn flush(&self) {
         self.0.index.lock().flush()
     }
+
+    /// Like `flush`, but the commit survives a power loss (see `db::Index::flush_durably`).
+    pub fn flush_durably(&self) {
+        self.0.index.lock().flush_durably()
+    }
 }
@@ -15,23 +15,27 @@
 //! Combines data chunks into larger blobs to be stored externally.
 
 
-use backend::StoreBackend;
+use backend::{ObjectTags, StoreBackend};
 use capnp;
+use chrono;
 use crypto;
 use errors;
 use hash::Hash;
 use hash::tree::HashRef;
+use hex::ToHex;
 use std::borrow::Cow;
 use std::mem;
 use std::sync::{Arc, Mutex, MutexGuard};
 use std::thread;
 use tags;
-use util::FnBox;
+use util::{self, FnBox};
 use key;
+use scoped_pool;
 
 
 mod chunk;
 mod blob;
+mod id;
 mod index;
 #[cfg(test)]
 pub mod tests;
@@ -42,6 +46,7 @@ mod benchmarks;
 
 pub use self::blob::{Blob, BlobReader};
 pub use self::chunk::{ChunkRef, Key, NodeType, LeafType, Packing};
+pub use self::id::BlobId;
 pub use self::index::{BlobDesc, BlobIndex};
 
 
@@ -62,6 +67,29 @@ error_type! {
     }
 }
 
+// Number of times a single part of a multipart upload is attempted before giving up.
+const MAX_PART_ATTEMPTS: usize = 5;
+
+/// Backend name prefix under which corrupt blobs are preserved for later inspection.
+/// Quarantined blobs are never treated as data blobs.
+pub const QUARANTINE_PREFIX: &'static [u8] = b"quarantine:";
+
+/// Backend name prefix of root documents (see `hat::root`). These are never data blobs.
+pub const ROOT_PREFIX: &'static [u8] = b"root:";
+
+/// Backend name prefix of repository locks (see `hat::lock`). These are never data blobs.
+pub const LOCK_PREFIX: &'static [u8] = b"lock:";
+
+/// Backend name of the repository manifest (see `hat::manifest`). This is never a data blob.
+pub const MANIFEST_NAME: &'static [u8] = b"manifest";
+
+fn quarantine_name(name: &[u8], suffix: &[u8]) -> Vec<u8> {
+    let mut q = QUARANTINE_PREFIX.to_vec();
+    q.extend_from_slice(name);
+    q.extend_from_slice(suffix);
+    q
+}
+
 pub struct BlobStore<B>(Arc<Mutex<StoreInner<B>>>);
 
 pub struct StoreInner<B> {
@@ -71,6 +99,8 @@ pub struct StoreInner<B> {
     blob_desc: BlobDesc,
     blob_refs: Vec<(Box<FnBox<(), ()>>)>,
     blob: Blob,
+    part_size: usize,
+    object_tags: Option<ObjectTags>,
 }
 
 impl<B> Drop for StoreInner<B> {
@@ -94,6 +124,8 @@ impl<B: StoreBackend> StoreInner<B> {
             blob_desc: Default::default(),
             blob_refs: Vec::new(),
             blob: Blob::new(keys, max_blob_size),
+            part_size: max_blob_size,
+            object_tags: None,
         };
         bs.reserve_new_blob();
         bs
@@ -108,14 +140,26 @@ impl<B: StoreBackend> StoreInner<B> {
             None => return,
             Some(ct) => ct,
         };
+        // Uploads that already started are allowed to finish; new ones wait.
+        util::wait_while_paused();
 
         // Replace blob id
         let old_blob_desc = self.reserve_new_blob();
+        debug!(
+            "Flushing blob {} ({} bytes, {} chunks)",
+            old_blob_desc.name[..].to_hex(),
+            ct.len(),
+            self.blob_refs.len()
+        );
 
         self.blob_index.in_air(&old_blob_desc);
-        self.backend.store(&old_blob_desc.name[..], &ct).expect(
-            "Store operation failed",
-        );
+        if ct.len() > self.part_size {
+            self.store_multipart(&old_blob_desc.name[..], &ct)
+        } else {
+            self.backend.store(&old_blob_desc.name[..], &ct)
+        }.expect("Store operation failed");
+        self.tag_object(&old_blob_desc.name[..], "data");
+        self.blob_index.set_length(&old_blob_desc, ct.len() as u64);
         self.blob_index.commit_done(&old_blob_desc);
 
         // Go through callbacks
@@ -124,6 +168,34 @@ impl<B: StoreBackend> StoreInner<B> {
         }
     }
 
+    /// Attach provider-side tags to a stored object. Tags only serve lifecycle policies and
+    /// inventories, so failing to set them does not fail the store.
+    fn tag_object(&self, name: &[u8], class: &str) {
+        if let Some(ref tags) = self.object_tags {
+            if let Err(e) = self.backend.set_object_tags(name, &tags.with_class(class)) {
+                warn!("Could not tag object {}: {}", name.to_hex(), e);
+            }
+        }
+    }
+
+    fn store_multipart(&self, name: &[u8], ct: &crypto::CipherText) -> Result<(), String> {
+        let data = ct.to_vec();
+        let mut count = 0;
+        for part in data.chunks(self.part_size) {
+            let mut attempt = 1;
+            while let Err(e) = self.backend.store_part(name, count, part) {
+                if attempt >= MAX_PART_ATTEMPTS {
+                    self.backend.abort_parts(name)?;
+                    return Err(e);
+                }
+                warn!("Retrying upload of part {} (attempt {}): {}", count, attempt, e);
+                attempt += 1;
+            }
+            count += 1;
+        }
+        self.backend.commit_parts(name, count)
+    }
+
     fn store(
         &mut self,
         chunk: &[u8],
@@ -140,7 +212,7 @@ impl<B: StoreBackend> StoreInner<B> {
             info: info.cloned(),
             persistent_ref: ChunkRef {
                 blob_id: Some(0),
-                blob_name: vec![0],
+                blob_name: BlobId::from(vec![0]),
                 packing: None,
                 // Updated by try_append.
                 offset: 0,
@@ -173,17 +245,83 @@ impl<B: StoreBackend> StoreInner<B> {
         href
     }
 
+    /// Preserve a copy of a corrupt blob together with a report describing what was wrong.
+    /// The original blob is left in place; only the first detection is recorded.
+    fn quarantine(&self, name: &[u8], data: &[u8], reason: &str) -> Result<(), String> {
+        let copy_name = quarantine_name(name, b"");
+        if self.backend.size(&copy_name)?.is_some() {
+            return Ok(());
+        }
+        warn!("Quarantining corrupt blob {}: {}", name.to_hex(), reason);
+
+        let report = format!(
+            "blob: {}\nsize: {}\ndetected: {}\nreason: {}\n",
+            name.to_hex(),
+            data.len(),
+            chrono::Utc::now().to_rfc3339(),
+            reason
+        );
+        let report_name = quarantine_name(name, b".report");
+        self.backend.store(
+            &report_name,
+            &crypto::CipherText::new(report.into_bytes()),
+        )?;
+        self.backend.store(
+            &copy_name,
+            &crypto::CipherText::new(data.to_vec()),
+        )?;
+        self.tag_object(&report_name, "quarantine");
+        self.tag_object(&copy_name, "quarantine");
+        self.backend.flush()
+    }
+
+    /// Like `quarantine`, but failing to keep the copy is only logged: it must not fail a read.
+    fn try_quarantine(&self, name: &[u8], data: &[u8], reason: &str) {
+        if let Err(e) = self.quarantine(name, data, reason) {
+            warn!("Could not quarantine blob {}: {}", name.to_hex(), e);
+        }
+    }
+
+    fn read_chunk(&self, blob: &[u8], href: &HashRef) -> Result<Vec<u8>, BlobError> {
+        BlobReader::new(self.keys.clone(), crypto::CipherTextRef::new(blob))
+            .map_err(From::from)
+            .and_then(|r| r.read_chunk(href))
+    }
+
     fn retrieve(&mut self, href: &HashRef) -> Result<Option<Vec<u8>>, BlobError> {
         if href.persistent_ref.offset == 0 && href.persistent_ref.length == 0 {
             return Ok(Some(Vec::new()));
         }
-        match self.backend.retrieve(&href.persistent_ref.blob_name[..]) {
+        let name = &href.persistent_ref.blob_name[..];
+        match self.backend.retrieve(name) {
             Ok(Some(blob)) => {
-                Ok(Some(BlobReader::new(
-                    self.keys.clone(),
-                    crypto::CipherTextRef::new(&blob[..]),
-                )?
-                    .read_chunk(href)?))
+                match self.read_chunk(&blob[..], href) {
+                    Ok(chunk) => Ok(Some(chunk)),
+                    Err(e) => {
+                        let reason = format!(
+                            "chunk {} at offset {} (length {}) is unreadable: {}",
+                            href.hash.bytes.to_hex(),
+                            href.persistent_ref.offset,
+                            href.persistent_ref.length,
+                            e
+                        );
+                        // Fall back to the other copies of this blob, if the backend has any.
+                        let mut chunk = None;
+                        for replica in 1..self.backend.replicas() {
+                            if let Ok(Some(copy)) = self.backend.retrieve_replica(name, replica) {
+                                if let Ok(c) = self.read_chunk(&copy[..], href) {
+                                    chunk = Some(c);
+                                    break;
+                                }
+                            }
+                        }
+                        self.try_quarantine(name, &blob[..], &reason);
+                        match chunk {
+                            Some(chunk) => Ok(Some(chunk)),
+                            None => Err(e),
+                        }
+                    }
+                }
             }
             Ok(None) => Ok(None),
             Err(e) => Err(e.into()),
@@ -194,8 +332,17 @@ impl<B: StoreBackend> StoreInner<B> {
         match self.backend.retrieve(&blob.name[..])? {
             None => Ok(None),
             Some(ct) => {
-                let hrefs = BlobReader::new(self.keys.clone(), crypto::CipherTextRef::new(&ct[..]))?
-                    .refs()?;
+                let refs = BlobReader::new(self.keys.clone(), crypto::CipherTextRef::new(&ct[..]))
+                    .map_err(From::from)
+                    .and_then(|r| r.refs());
+                let hrefs = match refs {
+                    Ok(hrefs) => hrefs,
+                    Err(e) => {
+                        let reason = format!("blob footer is unreadable: {}", e);
+                        self.try_quarantine(&blob.name[..], &ct[..], &reason);
+                        return Err(e);
+                    }
+                };
                 if hrefs.len() == 0 {
                     Ok(None)
                 } else {
@@ -208,8 +355,8 @@ impl<B: StoreBackend> StoreInner<B> {
 
     fn recover(&mut self) -> Result<(), String> {
         self.backend.list()?.into_iter()
-            .filter(|b| b.len() > 4)  // FIXME(jos): Remove when "root" is gone.
-            .map(|b| self.blob_index.recover(b.into_vec())).last();
+            .filter_map(|b| BlobId::new(b.into_vec()).ok())
+            .map(|b| self.blob_index.recover(b)).last();
         Ok(())
     }
 
@@ -227,14 +374,58 @@ impl<B: StoreBackend> StoreInner<B> {
         self.blob_index.tag_all(tag);
     }
 
+    fn delete(&mut self, blob: &BlobDesc) -> Result<(), String> {
+        self.blob_index.flush_durably();
+        self.backend.delete(&blob.name)?;
+        self.blob_index.delete(blob);
+        Ok(())
+    }
+
     fn delete_by_tag(&mut self, tag: tags::Tag) -> Result<(), String> {
         let blobs = self.blob_index.list_by_tag(tag);
-        for b in &blobs {
-            self.backend.delete(&b.name)?;
-        }
+        debug!("Deleting {} blobs tagged {:?}", blobs.len(), tag);
+        self.delete_from_backend(&blobs)?;
         self.blob_index.delete_by_tag(tag);
         Ok(())
     }
+
+    fn delete_all(&mut self, blobs: &[BlobDesc]) -> Result<(), String> {
+        self.delete_from_backend(blobs)?;
+        for b in blobs {
+            self.blob_index.delete(b);
+        }
+        Ok(())
+    }
+
+    // Issue the deletes from as many threads as the backend allows to run at once. What the
+    // index forgot about the blobs first, e.g. the hashes they held, is made durable before: a
+    // crash must not bring back index entries for data that is gone.
+    fn delete_from_backend(&self, blobs: &[BlobDesc]) -> Result<(), String> {
+        self.blob_index.flush_durably();
+        let threads = self.backend.delete_concurrency();
+        if threads <= 1 || blobs.len() <= 1 {
+            for b in blobs {
+                self.backend.delete(&b.name)?;
+            }
+            return Ok(());
+        }
+
+        let backend = &self.backend;
+        let failure = Mutex::new(None);
+        let pool = scoped_pool::Pool::new(threads);
+        pool.scoped(|scope| for b in blobs {
+            let failure = &failure;
+            scope.execute(move || if let Err(e) = backend.delete(&b.name) {
+                *failure.lock().unwrap() = Some(e);
+            });
+        });
+        pool.shutdown();
+
+        match failure.into_inner().unwrap() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
 }
 
 impl<B: StoreBackend> BlobStore<B> {
@@ -249,6 +440,12 @@ impl<B: StoreBackend> BlobStore<B> {
         )))
     }
 
+    /// Attach `tags` to every object this store writes to the backend.
+    pub fn with_object_tags(self, tags: ObjectTags) -> BlobStore<B> {
+        self.lock().object_tags = Some(tags);
+        self
+    }
+
     fn lock(&self) -> MutexGuard<StoreInner<B>> {
         self.0.lock().expect("Blob store was poisoned")
     }
@@ -284,6 +481,22 @@ impl<B: StoreBackend> BlobStore<B> {
         self.lock().recover()
     }
 
+    /// Reinstall a single blob found in external storage, such as one left out of the index.
+    pub fn adopt(&self, name: BlobId) -> BlobDesc {
+        self.lock().blob_index.recover(name)
+    }
+
+    /// List the names of blobs that have been quarantined as corrupt.
+    pub fn list_quarantined(&self) -> Result<Vec<BlobId>, String> {
+        let list = self.lock().backend.list()?;
+        Ok(
+            list.into_iter()
+                .filter(|b| b.starts_with(QUARANTINE_PREFIX) && !b.ends_with(b".report"))
+                .map(|b| BlobId::from(b[QUARANTINE_PREFIX.len()..].to_vec()))
+                .collect(),
+        )
+    }
+
     pub fn tag(&self, chunk: ChunkRef, tag: tags::Tag) {
         self.lock().tag(chunk, tag)
     }
@@ -296,6 +509,17 @@ impl<B: StoreBackend> BlobStore<B> {
         self.lock().delete_by_tag(tag)
     }
 
+    /// Delete a blob from the backend and the index.
+    pub fn delete(&self, blob: &BlobDesc) -> Result<(), String> {
+        self.lock().delete(blob)
+    }
+
+    /// Delete several blobs from the backend and the index, running as many deletes at once
+    /// as the backend allows. On failure, the index keeps all of them.
+    pub fn delete_all(&self, blobs: &[BlobDesc]) -> Result<(), String> {
+        self.lock().delete_all(blobs)
+    }
+
     pub fn list_by_tag(&self, tag: tags::Tag) -> Vec<BlobDesc> {
         self.lock().blob_index.list_by_tag(tag)
     }
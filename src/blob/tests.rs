@@ -12,8 +12,8 @@
 // See the License for the specific language governing permissions and
 // limitations under the License
 
-use backend::{MemoryBackend, StoreBackend};
-use blob::{Blob, BlobReader, BlobError, BlobIndex, BlobStore, ChunkRef, NodeType, LeafType};
+use backend::{MemoryBackend, MirrorBackend, StoreBackend};
+use blob::{Blob, BlobId, BlobReader, BlobError, BlobIndex, BlobStore, ChunkRef, NodeType, LeafType};
 use crypto;
 use db;
 use hash;
@@ -126,7 +126,7 @@ fn blobid_identity() {
     fn prop(name: Vec<u8>, offset: usize, length: usize) -> bool {
         let blob_name = ChunkRef {
             blob_id: None,
-            blob_name: name.to_vec(),
+            blob_name: name.to_vec().into(),
             offset: offset,
             length: length,
             packing: None,
@@ -159,7 +159,7 @@ fn blob_reuse() {
         info: None,
         persistent_ref: ChunkRef {
             blob_id: None,
-            blob_name: Vec::new(),
+            blob_name: Vec::new().into(),
             offset: 0,
             length: 0,
             packing: None,
@@ -209,7 +209,7 @@ fn blob_identity() {
                 info: None,
                 persistent_ref: ChunkRef {
                     blob_id: None,
-                    blob_name: Vec::new(),
+                    blob_name: Vec::new().into(),
                     offset: 0,
                     length: 0,
                     packing: None,
@@ -274,7 +274,7 @@ fn empty_blocks_blob_ciphertext(blob: &mut Blob, blocksize: usize) -> Vec<u8> {
             info: None,
             persistent_ref: ChunkRef {
                 blob_id: None,
-                blob_name: Vec::new(),
+                blob_name: Vec::new().into(),
                 offset: 0,
                 length: block.len(),
                 packing: None,
@@ -348,3 +348,142 @@ fn blob_ciphertext_authed_allbytes() {
     // We did not corrupt the blob.
     assert_eq!(vs, verify(&keys, &bytes[..]).unwrap());
 }
+
+#[test]
+fn oversized_chunk_multipart() {
+    let backend = Arc::new(MemoryBackend::new());
+
+    let keys = Arc::new(crypto::keys::Keeper::new_for_testing());
+    let db = Arc::new(db::Index::new_for_testing());
+    let blob_index = Arc::new(BlobIndex::new(keys.clone(), db).unwrap());
+    let bs_p = BlobStore::new(keys.clone(), blob_index, backend.clone(), 1024);
+
+    let node = NodeType::Leaf;
+    let leaf = LeafType::FileChunk;
+    let chunks = vec![vec![1u8; 10], vec![2u8; 10 * 1024], vec![3u8; 10]];
+
+    let mut ids = Vec::new();
+    for chunk in chunks.iter() {
+        ids.push((
+            bs_p.store(
+                &chunk[..],
+                hash::Hash::new(&keys, node, leaf, chunk),
+                node,
+                leaf,
+                None,
+                Box::new(move |_| {}),
+            ),
+            chunk,
+        ));
+    }
+    bs_p.flush();
+
+    // The oversized chunk lives alone in its own blob.
+    let big_blob = backend
+        .retrieve(&ids[1].0.persistent_ref.blob_name[..])
+        .unwrap()
+        .unwrap();
+    assert!(big_blob.len() > 10 * 1024);
+
+    for &(ref id, chunk) in ids.iter() {
+        assert_eq!(bs_p.retrieve(&id).unwrap().unwrap(), &chunk[..]);
+    }
+}
+
+#[test]
+fn corrupt_blob_is_quarantined() {
+    use blob::QUARANTINE_PREFIX;
+
+    let backend = Arc::new(MemoryBackend::new());
+    let keys = Arc::new(crypto::keys::Keeper::new_for_testing());
+    let db = Arc::new(db::Index::new_for_testing());
+    let blob_index = Arc::new(BlobIndex::new(keys.clone(), db).unwrap());
+    let bs_p = BlobStore::new(keys.clone(), blob_index, backend.clone(), 1024);
+
+    let chunk = vec![42u8; 100];
+    let node = NodeType::Leaf;
+    let leaf = LeafType::FileChunk;
+    let href = bs_p.store(
+        &chunk[..],
+        hash::Hash::new(&keys, node, leaf, &chunk[..]),
+        node,
+        leaf,
+        None,
+        Box::new(move |_| {}),
+    );
+    bs_p.flush();
+    assert!(bs_p.list_quarantined().unwrap().is_empty());
+
+    // Flip a single byte of the stored blob.
+    let name = href.persistent_ref.blob_name.clone();
+    let mut bytes = backend.retrieve(&name[..]).unwrap().unwrap();
+    bytes[10] ^= 1;
+    backend.delete(&name[..]).unwrap();
+    backend
+        .store(&name[..], &crypto::CipherText::new(bytes.clone()))
+        .unwrap();
+
+    assert!(bs_p.retrieve(&href).is_err());
+    assert!(bs_p.retrieve(&href).is_err());
+    assert_eq!(bs_p.list_quarantined().unwrap(), vec![name.clone()]);
+
+    // The quarantined copy holds the original bytes, next to a report.
+    let mut copy_name = QUARANTINE_PREFIX.to_vec();
+    copy_name.extend_from_slice(&name[..]);
+    assert_eq!(backend.retrieve(&copy_name[..]).unwrap().unwrap(), bytes);
+    copy_name.extend_from_slice(b".report");
+    assert!(backend.retrieve(&copy_name[..]).unwrap().is_some());
+}
+
+#[test]
+fn mirror_falls_back_to_replica() {
+    let backend = Arc::new(MirrorBackend::new(vec![MemoryBackend::new(), MemoryBackend::new()]));
+    let keys = Arc::new(crypto::keys::Keeper::new_for_testing());
+    let db = Arc::new(db::Index::new_for_testing());
+    let blob_index = Arc::new(BlobIndex::new(keys.clone(), db).unwrap());
+    let bs_p = BlobStore::new(keys.clone(), blob_index, backend.clone(), 1024);
+
+    let chunk = vec![7u8; 100];
+    let node = NodeType::Leaf;
+    let leaf = LeafType::FileChunk;
+    let href = bs_p.store(
+        &chunk[..],
+        hash::Hash::new(&keys, node, leaf, &chunk[..]),
+        node,
+        leaf,
+        None,
+        Box::new(move |_| {}),
+    );
+    bs_p.flush();
+
+    let name = href.persistent_ref.blob_name.clone();
+    assert_eq!(bs_p.retrieve(&href).unwrap(), Some(chunk.clone()));
+    assert_eq!(backend.served_by(&name[..]), Some(0));
+
+    // Damage the primary copy only.
+    let primary = &backend.sources()[0];
+    let mut bytes = primary.retrieve(&name[..]).unwrap().unwrap();
+    bytes[10] ^= 1;
+    primary.delete(&name[..]).unwrap();
+    primary.store(&name[..], &crypto::CipherText::new(bytes)).unwrap();
+
+    assert_eq!(bs_p.retrieve(&href).unwrap(), Some(chunk.clone()));
+    assert_eq!(backend.served_by(&name[..]), Some(1));
+
+    // A blob missing from the primary is read from the replica directly.
+    primary.delete(&name[..]).unwrap();
+    assert_eq!(bs_p.retrieve(&href).unwrap(), Some(chunk));
+    assert_eq!(backend.served_counts(), vec![0, 1]);
+}
+
+#[test]
+fn blob_id_rejects_reserved_names() {
+    assert!(BlobId::new(b"keys".to_vec()).is_err());
+    assert!(BlobId::new(b"root:0001".to_vec()).is_err());
+    assert!(BlobId::new(b"quarantine:abcdef".to_vec()).is_err());
+
+    let id = BlobId::new(vec![0xab, 0xcd, 0xef, 0x01, 0x23]).unwrap();
+    assert_eq!(format!("{}", id), "abcdef0123");
+    assert_eq!(id.shard(2), "abcd");
+    assert_eq!(&id[..], &[0xab, 0xcd, 0xef, 0x01, 0x23][..]);
+}
@@ -13,9 +13,13 @@
 // limitations under the License.
 
 use blob;
+use blake3;
+use errors::CryptoError;
 use libsodium_sys;
 use secstr;
+use std::ptr;
 use argon2rs;
+use std::str::FromStr;
 
 struct PublicKey(secstr::SecStr);
 struct SecretKey(secstr::SecStr);
@@ -66,8 +70,73 @@ pub fn keyed_fingerprint(sk: &[u8], msg: &[u8], salt: &[u8], out: &mut [u8]) {
     assert_eq!(ret, 0);
 }
 
+/// Content-hash algorithm used for fingerprinting.
+///
+/// The algorithm is chosen when a repository is created and must stay the same for its lifetime,
+/// as fingerprints computed with different algorithms never match.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum HashAlgorithm {
+    /// Keyed BLAKE2b (the original algorithm).
+    Blake2b,
+    /// Keyed BLAKE3 (considerably faster on modern hardware).
+    Blake3,
+}
+
+impl HashAlgorithm {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            HashAlgorithm::Blake2b => "blake2b",
+            HashAlgorithm::Blake3 => "blake3",
+        }
+    }
+}
+
+impl Default for HashAlgorithm {
+    fn default() -> HashAlgorithm {
+        HashAlgorithm::Blake2b
+    }
+}
+
+impl FromStr for HashAlgorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<HashAlgorithm, String> {
+        match s {
+            "blake2b" => Ok(HashAlgorithm::Blake2b),
+            "blake3" => Ok(HashAlgorithm::Blake3),
+            _ => Err(format!("Unknown hash algorithm: {}", s)),
+        }
+    }
+}
+
+pub fn keyed_fingerprint_blake3(sk: &[u8], msg: &[u8], salt: &[u8], out: &mut [u8]) {
+    assert!(sk.len() >= 32);
+    let mut key = [0u8; 32];
+    key.copy_from_slice(&sk[..32]);
+
+    let mut hasher = blake3::Hasher::new_keyed(&key);
+    hasher.update(b"hat-backup~~~~~a");
+    hasher.update(salt);
+    hasher.update(msg);
+    hasher.finalize_xof().fill(out);
+}
+
+/// What the key material held by a `Keeper` allows its owner to do.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Capabilities {
+    /// Create new fingerprints and seal new blobs.
+    pub write: bool,
+    /// Read blob data through a known reference.
+    pub read: bool,
+    /// Read blob names (needed to enumerate and recover blobs).
+    pub read_names: bool,
+    /// Read blob data without a reference (needed to recover references from blobs).
+    pub read_unreferenced: bool,
+}
+
 pub struct Keeper {
     universal_key: secstr::SecStr,
+    hash_algorithm: HashAlgorithm,
     fingerprint_key: Option<secstr::SecStr>,
     blob_authentication_key: Option<secstr::SecStr>,
 
@@ -79,13 +148,17 @@ pub struct Keeper {
 
     access_key_pk: Option<PublicKey>,
     access_key_sk: Option<SecretKey>,
+
+    signing_key_pk: Option<PublicKey>,
+    signing_key_sk: Option<SecretKey>,
 }
 
 impl Keeper {
-    pub fn new(universal: &str) -> Keeper {
+    pub fn new(universal: &[u8]) -> Keeper {
         let app: &str = "hat-backup:universal-key";
         let mut keeper = Keeper {
             universal_key: Keeper::strengthen(universal, app),
+            hash_algorithm: HashAlgorithm::default(),
             fingerprint_key: None,
             blob_authentication_key: None,
             data_key_pk: None,
@@ -94,6 +167,8 @@ impl Keeper {
             access_key_sk: None,
             naming_key_pk: None,
             naming_key_sk: None,
+            signing_key_pk: None,
+            signing_key_sk: None,
         };
         keeper.init();
         keeper
@@ -103,6 +178,7 @@ impl Keeper {
     pub fn new_for_testing() -> Keeper {
         let mut keeper = Keeper {
             universal_key: secstr::SecStr::new(vec![0; 32]),
+            hash_algorithm: HashAlgorithm::default(),
             fingerprint_key: None,
             blob_authentication_key: None,
             data_key_pk: None,
@@ -111,11 +187,41 @@ impl Keeper {
             access_key_sk: None,
             naming_key_pk: None,
             naming_key_sk: None,
+            signing_key_pk: None,
+            signing_key_sk: None,
         };
         keeper.init();
         keeper
     }
 
+    pub fn with_hash_algorithm(mut self, algorithm: HashAlgorithm) -> Keeper {
+        self.hash_algorithm = algorithm;
+        self
+    }
+
+    /// Mix a per-repository secret into the fingerprint key, so that fingerprints of known
+    /// content cannot be computed without access to the repository's own key material.
+    pub fn with_fingerprint_secret(mut self, secret: &[u8]) -> Keeper {
+        let mut key = secstr::SecStr::new(vec![0; 64]);
+        {
+            let base = self.fingerprint_key.as_ref().expect("need fingerprint key");
+            let salt: &[u8; 16] = b"secret~~secret~~";
+            keyed_fingerprint(base.unsecure(), secret, salt, key.unsecure_mut());
+        }
+        self.fingerprint_key = Some(key);
+        self
+    }
+
+    /// Key for encrypting the local indexes. Only depends on the universal key, so it is
+    /// available before any repository settings have been read.
+    pub fn index_key(&self) -> secstr::SecStr {
+        self.from_nonce("hat:INDEX-key".as_bytes(), 32)
+    }
+
+    pub fn hash_algorithm(&self) -> HashAlgorithm {
+        self.hash_algorithm
+    }
+
     fn init(&mut self) {
         // Generate key used for fingerprinting.
         self.fingerprint_key = Some(self.from_nonce("hat:FINGERPRINT-key".as_bytes(), 64));
@@ -143,9 +249,15 @@ impl Keeper {
         let (pk, sk) = self.x25519_key_pair_from_nonce("hat:NAMING-key-x25519".as_bytes());
         self.naming_key_pk = Some(pk);
         self.naming_key_sk = Some(sk);
+
+        // Generate signing key.
+        // Required for signing snapshots, and for checking their signatures.
+        let (pk, sk) = self.ed25519_key_pair_from_nonce("hat:SIGNING-key-ed25519".as_bytes());
+        self.signing_key_pk = Some(pk);
+        self.signing_key_sk = Some(sk);
     }
 
-    fn strengthen(phrase: &str, salt: &str) -> secstr::SecStr {
+    fn strengthen(phrase: &[u8], salt: &str) -> secstr::SecStr {
         let passes = 5;
         let threads = 2;
         let kib = 16 * 1024;
@@ -154,7 +266,7 @@ impl Keeper {
             .unwrap();
 
         let mut out = vec![0; 64];
-        argon2.hash(&mut out[..], phrase.as_bytes(), salt.as_bytes(), &[], &[]);
+        argon2.hash(&mut out[..], phrase, salt.as_bytes(), &[], &[]);
         secstr::SecStr::new(out)
     }
 
@@ -188,6 +300,24 @@ impl Keeper {
         (PublicKey(pk), SecretKey(sk))
     }
 
+    fn ed25519_key_pair_from_nonce(&self, nonce: &[u8]) -> (PublicKey, SecretKey) {
+        let mut pk = secstr::SecStr::new(vec![0; 32]);
+        let mut sk = secstr::SecStr::new(vec![0; 64]);
+
+        let seed = self.from_nonce(nonce, 32);
+
+        let ret = unsafe {
+            libsodium_sys::crypto_sign_ed25519_seed_keypair(
+                pk.unsecure_mut().as_mut_ptr() as *mut [u8; 32],
+                sk.unsecure_mut().as_mut_ptr() as *mut [u8; 64],
+                seed.unsecure().as_ptr() as *const [u8; 32],
+            )
+        };
+        assert_eq!(ret, 0);
+
+        (PublicKey(pk), SecretKey(sk))
+    }
+
     fn asymmetric_lock(pk: &PublicKey, msg: &[u8]) -> Vec<u8> {
         let mut out = vec![0; msg.len() + libsodium_sys::crypto_box_SEALBYTES];
         let ret = unsafe {
@@ -203,7 +333,14 @@ impl Keeper {
         out
     }
 
-    fn asymmetric_unlock(pk: &PublicKey, sk: &SecretKey, ciphertext: &[u8]) -> Vec<u8> {
+    fn asymmetric_unlock(
+        pk: &PublicKey,
+        sk: &SecretKey,
+        ciphertext: &[u8],
+    ) -> Result<Vec<u8>, CryptoError> {
+        if ciphertext.len() < libsodium_sys::crypto_box_SEALBYTES {
+            return Err("crypto read failed: sealed box is too short".into());
+        }
         let mut out = vec![0; ciphertext.len() - libsodium_sys::crypto_box_SEALBYTES];
         let ret = unsafe {
             libsodium_sys::crypto_box_seal_open(
@@ -214,9 +351,23 @@ impl Keeper {
                 sk.0.unsecure().as_ptr() as *const [u8; 32],
             )
         };
-        assert_eq!(0, ret);
+        if ret != 0 {
+            return Err("crypto read failed: cannot open sealed box".into());
+        }
 
-        out
+        Ok(out)
+    }
+
+    pub fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            write: self.fingerprint_key.is_some() && self.blob_authentication_key.is_some() &&
+                self.data_key_pk.is_some() && self.access_key_pk.is_some() &&
+                self.naming_key_pk.is_some(),
+            read: self.blob_authentication_key.is_some() && self.access_key_pk.is_some() &&
+                self.access_key_sk.is_some(),
+            read_names: self.naming_key_pk.is_some() && self.naming_key_sk.is_some(),
+            read_unreferenced: self.data_key_pk.is_some() && self.data_key_sk.is_some(),
+        }
     }
 
     pub fn data_lock(&self, msg: &[u8]) -> Vec<u8> {
@@ -226,7 +377,7 @@ impl Keeper {
         )
     }
 
-    pub fn data_unlock(&self, ciphertext: &[u8]) -> Vec<u8> {
+    pub fn data_unlock(&self, ciphertext: &[u8]) -> Result<Vec<u8>, CryptoError> {
         Keeper::asymmetric_unlock(
             self.data_key_pk.as_ref().expect("need data public key"),
             self.data_key_sk.as_ref().expect("need data private key"),
@@ -243,7 +394,7 @@ impl Keeper {
         )
     }
 
-    pub fn access_unlock(&self, ciphertext: &[u8]) -> Vec<u8> {
+    pub fn access_unlock(&self, ciphertext: &[u8]) -> Result<Vec<u8>, CryptoError> {
         Keeper::asymmetric_unlock(
             self.access_key_pk.as_ref().expect("need access public key"),
             self.access_key_sk.as_ref().expect(
@@ -260,7 +411,7 @@ impl Keeper {
         )
     }
 
-    pub fn naming_unlock(&self, ciphertext: &[u8]) -> Vec<u8> {
+    pub fn naming_unlock(&self, ciphertext: &[u8]) -> Result<Vec<u8>, CryptoError> {
         Keeper::asymmetric_unlock(
             self.naming_key_pk.as_ref().expect("need naming public key"),
             self.naming_key_sk.as_ref().expect(
@@ -270,9 +421,47 @@ impl Keeper {
         )
     }
 
+    /// Sign `msg` with the ed25519 signing key.
+    pub fn sign(&self, msg: &[u8]) -> Vec<u8> {
+        let sk = self.signing_key_sk.as_ref().expect("need signing private key");
+        let mut signature = vec![0u8; 64];
+        let ret = unsafe {
+            libsodium_sys::crypto_sign_ed25519_detached(
+                signature.as_mut_ptr() as *mut [u8; 64],
+                ptr::null_mut(),
+                msg.as_ptr(),
+                msg.len() as u64,
+                sk.0.unsecure().as_ptr() as *const [u8; 64],
+            )
+        };
+        assert_eq!(0, ret);
+
+        signature
+    }
+
+    /// Check a signature made by `sign`.
+    pub fn verify_signature(&self, msg: &[u8], signature: &[u8]) -> bool {
+        let pk = self.signing_key_pk.as_ref().expect("need signing public key");
+        if signature.len() != 64 {
+            return false;
+        }
+        let ret = unsafe {
+            libsodium_sys::crypto_sign_ed25519_verify_detached(
+                signature.as_ptr() as *const [u8; 64],
+                msg.as_ptr(),
+                msg.len() as u64,
+                pk.0.unsecure().as_ptr() as *const [u8; 32],
+            )
+        };
+        ret == 0
+    }
+
     pub fn fingerprint(&self, msg: &[u8], salt: &[u8], out: &mut [u8]) {
         let key = self.fingerprint_key.as_ref().expect("need fingerprint key");
-        keyed_fingerprint(key.unsecure(), msg, salt, out);
+        match self.hash_algorithm {
+            HashAlgorithm::Blake2b => keyed_fingerprint(key.unsecure(), msg, salt, out),
+            HashAlgorithm::Blake3 => keyed_fingerprint_blake3(key.unsecure(), msg, salt, out),
+        }
     }
 
     pub fn blob_authentication(&self, blob: &[u8], out: &mut [u8]) {
@@ -376,24 +376,24 @@ impl<'k> FixedKey<'k> {
         CipherText::new(self.keeper.naming_lock(pt.0))
     }
 
-    pub fn unseal_blob_name(&self, ct: CipherTextRef) -> PlainText {
-        PlainText::new(self.keeper.naming_unlock(ct.0))
+    pub fn unseal_blob_name(&self, ct: CipherTextRef) -> Result<PlainText, CryptoError> {
+        Ok(PlainText::new(self.keeper.naming_unlock(ct.0)?))
     }
 
     pub fn seal_blob_data(&self, pt: PlainTextRef) -> CipherText {
         CipherText::new(self.keeper.data_lock(pt.0))
     }
 
-    pub fn unseal_blob_data(&self, ct: CipherTextRef) -> PlainText {
-        PlainText::new(self.keeper.data_unlock(ct.0))
+    pub fn unseal_blob_data(&self, ct: CipherTextRef) -> Result<PlainText, CryptoError> {
+        Ok(PlainText::new(self.keeper.data_unlock(ct.0)?))
     }
 
     pub fn seal_blob_access(&self, pt: PlainTextRef) -> CipherText {
         CipherText::new(self.keeper.access_lock(pt.0))
     }
 
-    pub fn unseal_blob_access(&self, ct: CipherTextRef) -> PlainText {
-        PlainText::new(self.keeper.access_unlock(ct.0))
+    pub fn unseal_blob_access(&self, ct: CipherTextRef) -> Result<PlainText, CryptoError> {
+        Ok(PlainText::new(self.keeper.access_unlock(ct.0)?))
     }
 
     pub fn new_access_partial_key() -> ::crypto::authed::desc::Key {
@@ -441,7 +441,7 @@ impl<'k> FixedKey<'k> {
     ) -> Result<(::crypto::authed::desc::Key, CipherText, CipherTextRef<'a>), CryptoError> {
         // Read sealed ciphertext length and unseal it.
         let (rest, access_ct) = ct.split_from_right(sealed::desc::access_cipher_bytes())?;
-        let mut access_pt = self.unseal_blob_access(access_ct).into_vec();
+        let mut access_pt = self.unseal_blob_access(access_ct)?.into_vec();
         assert_eq!(access_pt.len(), sealed::desc::access_plain_bytes());
 
         let access_key = access_pt.split_off(
@@ -461,7 +461,7 @@ impl<'k> FixedKey<'k> {
         ct: CipherTextRef<'a>,
     ) -> Result<(CipherTextRef<'a>, PlainText), CryptoError> {
         assert_eq!(footer_ct.len(), sealed::desc::footer_cipher_bytes());
-        let foot_pt = self.unseal_blob_data(footer_ct);
+        let foot_pt = self.unseal_blob_data(footer_ct)?;
         assert_eq!(foot_pt.len(), sealed::desc::footer_plain_bytes());
 
         // Read length as LittleEndian and inner key.
@@ -0,0 +1,89 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Maintenance of the local index files.
+
+
+use diesel;
+use diesel::prelude::*;
+use errors::DieselError;
+use std::fs;
+
+use super::{Connection, sqlite_path};
+
+/// The outcome of maintaining one index.
+#[derive(Clone, Debug)]
+pub struct IndexReport {
+    pub path: String,
+    pub bytes_before: u64,
+    pub bytes_after: u64,
+    /// Problems reported by SQLite's integrity check; empty if the index is sound.
+    pub problems: Vec<String>,
+}
+
+impl IndexReport {
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+/// Bytes used by an index, including its write-ahead log.
+fn index_bytes(conn: &Connection, path: &str) -> Result<u64, DieselError> {
+    if path == ":memory:" {
+        let pages = diesel::expression::sql::<diesel::types::BigInt>("PRAGMA page_count;")
+            .get_result::<i64>(conn)?;
+        let page_size = diesel::expression::sql::<diesel::types::BigInt>("PRAGMA page_size;")
+            .get_result::<i64>(conn)?;
+        return Ok((pages * page_size) as u64);
+    }
+    let wal = fs::metadata(format!("{}-wal", path)).map(|m| m.len()).unwrap_or(0);
+    Ok(fs::metadata(path)?.len() + wal)
+}
+
+/// Bytes used by an index, as reported by `run` without changing it.
+pub fn size(conn: &Connection, url: &str) -> Result<u64, DieselError> {
+    index_bytes(conn, sqlite_path(url))
+}
+
+fn integrity_problems(conn: &Connection) -> Result<Vec<String>, DieselError> {
+    let rows = diesel::expression::sql::<diesel::types::Text>("PRAGMA integrity_check;")
+        .load::<String>(conn)?;
+    Ok(rows.into_iter().filter(|r| r != "ok").collect())
+}
+
+/// Check, reindex and vacuum an index. Must not be called inside a transaction.
+///
+/// Damaged indexes are reindexed, which repairs broken SQL indexes, but are not vacuumed: we
+/// would rather leave a damaged file as it is than rewrite it.
+pub fn run(conn: &Connection, url: &str) -> Result<IndexReport, DieselError> {
+    let path = sqlite_path(url);
+    let bytes_before = index_bytes(conn, path)?;
+
+    conn.execute("REINDEX;")?;
+    let problems = integrity_problems(conn)?;
+    if problems.is_empty() {
+        conn.execute("VACUUM;")?;
+        // Fold the log into the main file so that the reclaimed space shows up on disk.
+        conn.execute("PRAGMA wal_checkpoint(TRUNCATE);")?;
+    } else {
+        warn!("Index {} is damaged; not vacuuming it", path);
+    }
+
+    Ok(IndexReport {
+        path: path.to_string(),
+        bytes_before: bytes_before,
+        bytes_after: index_bytes(conn, path)?,
+        problems: problems,
+    })
+}
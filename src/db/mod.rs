@@ -19,30 +19,95 @@ use blob;
 
 use capnp;
 use chrono;
+use crypto;
 
 use diesel;
-use diesel::connection::TransactionManager;
 use diesel::prelude::*;
 use diesel::sqlite::SqliteConnection;
 use errors::DieselError;
 
 use hash;
+use hex::ToHex;
 use root_capnp;
+use secstr;
+use std::cell::Cell;
+use std::collections::HashMap;
 use std::sync::{Mutex, MutexGuard};
 use std::path::Path;
+use std::thread;
 use tags;
 use time::Duration;
-use util::{Counter, InfoWriter, PeriodicTimer};
+use util::{Counter, PeriodicTimer};
 
+mod maintenance;
 mod schema;
+mod upgrade;
+
+pub use self::maintenance::{run as maintain, size as index_size, IndexReport};
+pub use self::upgrade::{run as upgrade_schema, SCHEMA_VERSION};
+
+
+/// The diesel connection type shared by all indexes.
+pub type Connection = SqliteConnection;
+
+/// Open an index database by URL. Plain paths and `sqlite://` URLs open SQLite databases; other
+/// URLs are refused, as the indexes only support SQLite (their queries and migrations rely on
+/// `last_insert_rowid()`, pragmas and `BLOB` columns).
+///
+/// With a `key` the database is opened with SQLCipher, which must then be the SQLite library in
+/// use; see `index_key`.
+pub fn establish(url: &str, key: Option<&[u8]>) -> Result<Connection, DieselError> {
+    if url.contains("://") && !url.starts_with("sqlite://") {
+        return Err(From::from(diesel::ConnectionError::BadConnection(
+            format!("Only SQLite indexes are supported, not {}", url),
+        )));
+    }
+    let conn = SqliteConnection::establish(sqlite_path(url))?;
+    if let Some(key) = key {
+        // Plain SQLite silently ignores the key, which would leave the index unencrypted.
+        let cipher = diesel::expression::sql::<diesel::types::Text>("PRAGMA cipher_version;")
+            .load::<String>(&conn)?;
+        if cipher.is_empty() {
+            return Err(From::from("Encrypted indexes need SQLite built with SQLCipher"));
+        }
+        // The key must be set before anything else touches the database.
+        conn.execute(&format!("PRAGMA key = \"x'{}'\";", key.to_hex()))?;
+    }
+    Ok(conn)
+}
+
+/// The SQLCipher key for the local indexes, derived from the repository key material.
+///
+/// Indexes are only encrypted in builds with the `sqlcipher` feature. Unencrypted indexes of an
+/// existing repository are not converted and will fail to open in such a build.
+pub fn index_key(keys: &crypto::keys::Keeper) -> Option<secstr::SecStr> {
+    if cfg!(feature = "sqlcipher") {
+        Some(keys.index_key())
+    } else {
+        None
+    }
+}
+
+/// The SQLite database path named by an index URL.
+fn sqlite_path(url: &str) -> &str {
+    if url.starts_with("sqlite://") {
+        &url["sqlite://".len()..]
+    } else {
+        url
+    }
+}
 
 
 pub struct Index(Mutex<InternalIndex>);
 pub type IndexGuard<'a> = MutexGuard<'a, InternalIndex>;
 
 impl Index {
-    pub fn new(migrations_dir: &Path, path: &str) -> Result<Index, DieselError> {
-        Ok(Index(Mutex::new(InternalIndex::new(migrations_dir, path)?)))
+    pub fn new(
+        migrations_dir: &Path,
+        path: &str,
+        key: Option<&[u8]>,
+    ) -> Result<Index, DieselError> {
+        Ok(Index(Mutex::new(InternalIndex::new(migrations_dir, path, key)?)))
     }
     pub fn lock(&self) -> MutexGuard<InternalIndex> {
         self.0.lock().expect("Database mutex is poisoned")
@@ -50,7 +115,7 @@ impl Index {
     #[cfg(test)]
     pub fn new_for_testing() -> Index {
         Index(Mutex::new(
-            InternalIndex::new(Path::new("migrations"), ":memory:")
+            InternalIndex::new(Path::new("migrations"), ":memory:", None)
                 .unwrap(),
         ))
     }
@@ -86,6 +151,31 @@ fn decode_childs(bytes: &[u8]) -> Result<Vec<u64>, capnp::Error> {
     Ok(out)
 }
 
+/// Kept by `Index::hash_totals`, as "last counted id, count, bytes".
+const HASH_TOTALS_CONFIG: &'static str = "stats_hash_totals";
+
+/// Totals of the committed blobs, kept by `Hat::repository_stats` like `HASH_TOTALS_CONFIG`.
+pub const BLOB_TOTALS_CONFIG: &'static str = "stats_blob_totals";
+
+/// Kept by `Index::gc_set_epoch`, as "epoch, start time, newest blob id".
+const GC_EPOCH_CONFIG: &'static str = "gc_epoch";
+
+/// A run of the concurrent garbage collector, as remembered by the next run. Hashes that
+/// commits use from the start of the run on are stamped with its `epoch`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct GcEpoch {
+    pub epoch: i64,
+    /// When the run started, in seconds since the epoch.
+    pub started_utc: i64,
+    /// The newest blob when the run started.
+    pub blob_mark: i64,
+}
+
+/// Parse running totals stored as numbers separated by spaces.
+pub fn parse_totals(value: &str) -> Option<Vec<u64>> {
+    value.split(' ').map(|v| v.parse().ok()).collect()
+}
+
 fn decode_chunk_ref(
     cref: Option<&Vec<u8>>,
     blob: Option<self::schema::Blob>,
@@ -93,9 +183,9 @@ fn decode_chunk_ref(
     cref.map(|c| {
         let mut r = blob::ChunkRef::from_bytes(&mut &c[..]).expect("Failed to decode chunk");
         if r.length > 0 {
-            r.blob_name = blob.expect("Non-empty chunk without blob name").name;
+            r.blob_name = blob.expect("Non-empty chunk without blob name").name.into();
         } else {
-            r.blob_name = vec![0];
+            r.blob_name = vec![0].into();
         }
         r
     })
@@ -129,6 +219,69 @@ pub enum SnapshotWorkStatus {
     RecoverInProgress,
 }
 
+/// How much file data a backup run read, and how much of it was not already stored.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Serialize)]
+pub struct SnapshotStats {
+    pub bytes_read: u64,
+    pub bytes_new: u64,
+}
+
+impl SnapshotStats {
+    pub fn bytes_deduplicated(&self) -> u64 {
+        self.bytes_read.saturating_sub(self.bytes_new)
+    }
+}
+
+/// The files recorded in a snapshot, whether or not they were read by its backup run.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Serialize)]
+pub struct SnapshotContents {
+    pub files: u64,
+    /// Sum of the sizes of the files.
+    pub bytes: u64,
+}
+
+/// Where and how a snapshot was made.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize)]
+pub struct Provenance {
+    pub hostname: String,
+    pub username: String,
+    /// The version of hat that made the snapshot.
+    pub version: String,
+    pub command_line: Vec<String>,
+    pub source_path: String,
+}
+
+// The arguments of a command line are kept in a single column, separated by NUL, which cannot
+// occur within an argument.
+const ARG_SEPARATOR: &'static str = "\0";
+
+impl Provenance {
+    pub fn populate_msg(&self, mut msg: root_capnp::provenance::Builder) {
+        msg.set_hostname(&self.hostname);
+        msg.set_username(&self.username);
+        msg.set_version(&self.version);
+        msg.set_source_path(&self.source_path);
+        let mut args = msg.init_command_line(self.command_line.len() as u32);
+        for (i, arg) in self.command_line.iter().enumerate() {
+            args.set(i as u32, arg);
+        }
+    }
+
+    pub fn read(msg: root_capnp::provenance::Reader) -> Result<Provenance, capnp::Error> {
+        let mut command_line = vec![];
+        for arg in msg.get_command_line()?.iter() {
+            command_line.push(arg?.to_string());
+        }
+        Ok(Provenance {
+            hostname: msg.get_hostname()?.to_string(),
+            username: msg.get_username()?.to_string(),
+            version: msg.get_version()?.to_string(),
+            command_line: command_line,
+            source_path: msg.get_source_path()?.to_string(),
+        })
+    }
+}
+
 #[derive(Debug)]
 pub struct SnapshotStatus {
     pub family_name: String,
@@ -138,6 +291,12 @@ pub struct SnapshotStatus {
     pub created: chrono::DateTime<chrono::Utc>,
     pub msg: Option<String>,
     pub status: SnapshotWorkStatus,
+    pub stats: Option<SnapshotStats>,
+    pub contents: Option<SnapshotContents>,
+    pub finished: Option<chrono::DateTime<chrono::Utc>>,
+    pub provenance: Option<Provenance>,
+    pub labels: Vec<String>,
+    pub signature: Option<Vec<u8>>,
 }
 
 fn tag_to_work_status(tag: tags::Tag) -> SnapshotWorkStatus {
@@ -192,34 +351,70 @@ pub struct QueueEntry {
 }
 
 pub struct InternalIndex {
-    conn: SqliteConnection,
+    conn: Connection,
+    url: String,
     hash_id_counter: Counter,
     flush_timer: PeriodicTimer,
     flush_periodically: bool,
+    // The GC epoch as of the open transaction, once read; see `gc_epoch`.
+    gc_epoch: Option<Option<GcEpoch>>,
+    // Whether the open transaction has forgotten the totals and not stored any since; see
+    // `forget_totals`.
+    totals_forgotten: Cell<bool>,
+}
+
+/// Begin the transaction kept open between flushes, taking the write lock of the database right
+/// away. A transaction that only took it at its first write could not write at all if another
+/// process, e.g. a concurrent gc, committed after its first read (`SQLITE_BUSY_SNAPSHOT` in WAL
+/// mode); waiting for the lock instead is covered by the busy timeout.
+fn begin(conn: &Connection) -> Result<(), DieselError> {
+    conn.execute("BEGIN IMMEDIATE")?;
+    Ok(())
+}
+
+fn commit(conn: &Connection) -> Result<(), DieselError> {
+    conn.execute("COMMIT")?;
+    Ok(())
 }
 
 
 impl InternalIndex {
-    fn new(migrations_dir: &Path, path: &str) -> Result<InternalIndex, DieselError> {
-        let conn = SqliteConnection::establish(path)?;
+    fn new(
+        migrations_dir: &Path,
+        path: &str,
+        key: Option<&[u8]>,
+    ) -> Result<InternalIndex, DieselError> {
+        let conn = establish(path, key)?;
 
         let mut idx = InternalIndex {
             conn: conn,
+            url: path.to_string(),
             hash_id_counter: Counter::new(0),
             flush_timer: PeriodicTimer::new(Duration::seconds(10)),
             flush_periodically: true,
+            gc_epoch: None,
+            totals_forgotten: Cell::new(false),
         };
 
-        diesel::migrations::run_pending_migrations_in_directory(
-            &idx.conn,
-            &migrations_dir,
-            &mut InfoWriter,
+        // Write-ahead logging turns our periodic commits into sequential appends, and allows
+        // fsync to be skipped for all but checkpoints. Another process committing to the index,
+        // e.g. a concurrent gc, is waited for rather than failed on.
+        //
+        // The trade-off: a power loss or kernel crash may undo the transactions committed since
+        // the last checkpoint, though never leave the index corrupt. An index lagging the
+        // backend costs re-uploads of chunks it forgot and leaves blobs for `gc` to remove. The
+        // other way round is unsafe, as commits would reuse chunks that are gone; so blobs are
+        // only deleted from the backend once the index changes before are checkpointed by
+        // `flush_durably`. Finished operations are checkpointed too, so that only the progress
+        // of an interrupted one is at risk.
+        idx.conn.execute(
+            "PRAGMA journal_mode = WAL; PRAGMA synchronous = NORMAL; \
+             PRAGMA busy_timeout = 60000;",
         )?;
 
-        {
-            let tm = idx.conn.transaction_manager();
-            tm.begin_transaction(&idx.conn)?;
-        }
+        upgrade::run(&idx.conn, path, migrations_dir)?;
+
+        begin(&idx.conn)?;
 
         idx.hash_refresh_id_counter();
         Ok(idx)
@@ -317,6 +512,7 @@ impl InternalIndex {
             blob_id: entry.persistent_ref.and_then(|r| r.blob_id).unwrap_or(0),
             blob_ref: blob_ref_.as_ref().map(|v| &v[..]),
             ready: false,
+            epoch: self.gc_epoch().map_or(0, |e| e.epoch),
         };
 
         diesel::insert(&new)
@@ -395,6 +591,20 @@ impl InternalIndex {
         tag_opt.and_then(tags::tag_from_num)
     }
 
+    /// List the ids of all hashes, top-down like `hash_list_ids_by_tag`.
+    pub fn hash_list_ids(&mut self) -> Vec<u64> {
+        use self::schema::hashes::dsl::*;
+
+        hashes
+            .order(height.desc())
+            .select(id)
+            .load::<i64>(&self.conn)
+            .expect("Error listing hashes")
+            .into_iter()
+            .map(|i| i as u64)
+            .collect()
+    }
+
     pub fn hash_list_ids_by_tag(&mut self, tag_: u64) -> Vec<u64> {
         // We list hashes top-down.
         // This is required for safe deletion.
@@ -531,7 +741,19 @@ impl InternalIndex {
             .collect()
     }
 
+    pub fn hash_any(&mut self) -> bool {
+        use self::schema::hashes::dsl::*;
+
+        hashes
+            .select(id)
+            .first::<i64>(&self.conn)
+            .optional()
+            .expect("Error querying hashes")
+            .is_some()
+    }
+
     pub fn hash_delete(&mut self, id_: u64) {
+        self.forget_totals();
         {
             use self::schema::hashes::dsl::*;
             let hash_count = diesel::delete(hashes.find(id_ as i64))
@@ -548,13 +770,194 @@ impl InternalIndex {
         }
     }
 
+    /// Stamp a hash that is being used with the current GC epoch, so that a concurrent
+    /// garbage collector leaves it alone.
+    pub fn hash_touch(&mut self, id_: u64) {
+        let current = match self.gc_epoch() {
+            Some(e) => e.epoch,
+            None => return,
+        };
+        use self::schema::hashes::dsl::*;
+        diesel::update(hashes.find(id_ as i64).filter(epoch.lt(current)))
+            .set(epoch.eq(current))
+            .execute(&self.conn)
+            .expect("Error updating hash epoch");
+    }
+
+    /// Delete a hash, like `hash_delete`, unless it was used since the start of GC epoch
+    /// `epoch_`. The check and the deletion are one statement, so that a commit either sees
+    /// the hash gone or keeps it. Returns whether the hash was deleted.
+    pub fn hash_delete_untouched(&mut self, id_: u64, epoch_: i64) -> bool {
+        let count = {
+            use self::schema::hashes::dsl::*;
+            diesel::delete(hashes.find(id_ as i64).filter(epoch.lt(epoch_)))
+                .execute(&self.conn)
+                .expect("Error deleting hash")
+        };
+        if count == 0 {
+            return false;
+        }
+        self.hash_delete(id_);
+        true
+    }
+
+    /// Delete committed hashes whose blob no longer exists, along with GC metadata that
+    /// refers to hashes that are gone. Returns the number of hashes deleted.
+    pub fn hash_prune_orphans(&mut self) -> usize {
+        self.forget_totals();
+        let count = self.conn
+            .execute(
+                "DELETE FROM hashes WHERE ready AND blob_id != 0 \
+                 AND blob_id NOT IN (SELECT id FROM blobs)",
+            )
+            .expect("Error pruning hashes");
+        self.conn
+            .execute(
+                "DELETE FROM gc_metadata WHERE hash_id NOT IN (SELECT id FROM hashes)",
+            )
+            .expect("Error pruning GC metadata");
+        count
+    }
+
+    /// Record that the chunk behind `hash_` was read back and matched its hash at `utc_secs`.
+    pub fn hash_set_verified(&mut self, hash_: &[u8], utc_secs: i64) {
+        use self::schema::hashes::dsl::*;
+        diesel::update(hashes.filter(hash.eq(hash_)))
+            .set(verified.eq(Some(utc_secs)))
+            .execute(&self.conn)
+            .expect("Error updating hash verification time");
+    }
+
+    pub fn hash_verified(&mut self, hash_: &[u8]) -> Option<i64> {
+        use self::schema::hashes::dsl::*;
+        hashes
+            .filter(hash.eq(hash_))
+            .select(verified)
+            .first::<Option<i64>>(&self.conn)
+            .optional()
+            .expect("Error querying hash verification time")
+            .and_then(|v| v)
+    }
+
+    /// Commit and rebuild the database file to reclaim space left by deleted rows.
+    pub fn vacuum(&mut self) {
+        debug!("SQL: vacuum");
+
+        // VACUUM cannot run inside a transaction.
+        commit(&self.conn).unwrap();
+        self.gc_epoch = None;
+        self.totals_forgotten.set(false);
+        self.conn.execute("VACUUM").expect("Error vacuuming database");
+        begin(&self.conn).unwrap();
+    }
+
+    /// Like `vacuum`, but only once at least a quarter of the database file is free pages, as
+    /// vacuuming rewrites the whole file. `hat index vacuum` always vacuums.
+    pub fn vacuum_if_worthwhile(&mut self) {
+        let (free, pages) = {
+            let pragma = |p: &str| {
+                diesel::expression::sql::<diesel::types::BigInt>(p)
+                    .get_result::<i64>(&self.conn)
+                    .expect("Error reading database page counts")
+            };
+            (pragma("PRAGMA freelist_count;"), pragma("PRAGMA page_count;"))
+        };
+        if pages > 0 && free * 4 >= pages {
+            self.vacuum();
+        }
+    }
+
+    /// Bytes used by the database file.
+    pub fn size(&mut self) -> Result<u64, DieselError> {
+        index_size(&self.conn, &self.url)
+    }
+
+    /// The number and stored size of the committed chunks.
+    ///
+    /// The totals up to some hash id are kept in the repository config, so that only newer
+    /// hashes are read. Deleting hashes or blobs forgets them, to be counted again.
+    pub fn hash_totals(&mut self) -> (u64, u64) {
+        use diesel::expression::min;
+        use self::schema::hashes::dsl::*;
+
+        let (mut mark, mut count, mut bytes) =
+            match self.config_get(HASH_TOTALS_CONFIG).and_then(|v| parse_totals(&v)) {
+                Some(ref t) if t.len() == 3 => (t[0], t[1], t[2]),
+                _ => (0, 0, 0),
+            };
+        // A hash that is not ready yet can still be committed, so counting stops before it.
+        let pending = hashes
+            .filter(ready.eq(false))
+            .select(min(id))
+            .first::<Option<i64>>(&self.conn)
+            .optional()
+            .expect("Error querying hashes")
+            .and_then(|x| x);
+        let rows = hashes
+            .filter(id.gt(mark as i64))
+            .filter(ready.eq(true))
+            .order(id.asc())
+            .select((id, blob_ref))
+            .load::<(i64, Option<Vec<u8>>)>(&self.conn)
+            .expect("Error listing hashes");
+        for (id_, cref) in rows {
+            if pending.map_or(false, |p| id_ > p) {
+                break;
+            }
+            count += 1;
+            if let Some(c) = cref {
+                let chunk = blob::ChunkRef::from_bytes(&mut &c[..])
+                    .expect("Failed to decode chunk");
+                bytes += chunk.length as u64;
+            }
+            mark = id_ as u64;
+        }
+        self.config_set(HASH_TOTALS_CONFIG, &format!("{} {} {}", mark, count, bytes));
+        (count, bytes)
+    }
+
+    /// Forget the totals kept by `hash_totals` and by callers, after something was deleted.
+    /// Deleting many hashes or blobs in one transaction only forgets them once.
+    fn forget_totals(&self) {
+        if self.totals_forgotten.get() {
+            return;
+        }
+        self.conn
+            .execute("DELETE FROM repository_config WHERE name LIKE 'stats_%'")
+            .expect("Error deleting repository config");
+        self.totals_forgotten.set(true);
+    }
+
+    /// Commit, then check, reindex and vacuum the database; see `maintain`.
+    pub fn maintain(&mut self) -> Result<IndexReport, DieselError> {
+        debug!("SQL: maintain");
+
+        commit(&self.conn)?;
+        self.gc_epoch = None;
+        self.totals_forgotten.set(false);
+        let report = maintain(&self.conn, &self.url);
+        begin(&self.conn)?;
+        report
+    }
+
     pub fn maybe_flush(&mut self) {
         if self.flush_periodically && self.flush_timer.did_fire() {
             debug!("SQL: hash db maybe_flush commit");
-            self.flush();
+            self.flush_and_yield();
         }
     }
 
+    /// Commit, and leave the write lock to other processes for a moment before taking it again:
+    /// the busy handler of SQLite only looks for it every 100ms, and would otherwise never find
+    /// it free between the periodic commits of a long backup.
+    fn flush_and_yield(&mut self) {
+        commit(&self.conn).unwrap();
+        self.gc_epoch = None;
+        self.totals_forgotten.set(false);
+        thread::sleep(::std::time::Duration::from_millis(150));
+        begin(&self.conn).unwrap();
+    }
+
     pub fn set_auto_flush(&mut self, enabled: bool) {
         self.flush_periodically = enabled;
     }
@@ -562,9 +965,26 @@ impl InternalIndex {
     pub fn flush(&mut self) {
         debug!("SQL: hash db commit");
 
-        let tm = self.conn.transaction_manager();
-        tm.commit_transaction(&self.conn).unwrap();
-        tm.begin_transaction(&self.conn).unwrap();
+        commit(&self.conn).unwrap();
+        self.gc_epoch = None;
+        self.totals_forgotten.set(false);
+        begin(&self.conn).unwrap();
+    }
+
+    /// Commit, and checkpoint the write-ahead log, which syncs it to disk, so that what has
+    /// been committed survives a power loss.
+    pub fn flush_durably(&mut self) {
+        debug!("SQL: hash db commit and checkpoint");
+
+        commit(&self.conn).unwrap();
+        self.gc_epoch = None;
+        self.totals_forgotten.set(false);
+        // A passive checkpoint does not wait for other processes using the index; what they
+        // still read from the log is synced all the same.
+        if let Err(e) = self.conn.execute("PRAGMA wal_checkpoint(PASSIVE);") {
+            warn!("Could not checkpoint the index: {}", e);
+        }
+        begin(&self.conn).unwrap();
     }
 
     pub fn blob_next_id(&mut self) -> i64 {
@@ -586,7 +1006,7 @@ impl InternalIndex {
 
         let new = schema::NewBlob {
             id: blob.id,
-            name: &blob.name,
+            name: &blob.name[..],
             tag: tags::Tag::InProgress as i32,
         };
         diesel::insert(&new)
@@ -633,7 +1053,7 @@ impl InternalIndex {
                     .expect("Error updating blob tags")
             }
             Some(t) if !t.name.is_empty() => {
-                diesel::update(blobs.filter(name.eq(&t.name)))
+                diesel::update(blobs.filter(name.eq(&t.name[..])))
                     .set(tag.eq(tag_ as i32))
                     .execute(&self.conn)
                     .expect("Error updating blob tags")
@@ -650,11 +1070,22 @@ impl InternalIndex {
 
     pub fn blob_delete_by_tag(&self, tag_: tags::Tag) {
         use self::schema::blobs::dsl::*;
+
+        self.forget_totals();
         diesel::delete(blobs.filter(tag.eq(tag_ as i32)))
             .execute(&self.conn)
             .expect("Error deleting blobs");
     }
 
+    pub fn blob_delete(&self, blob: &blob::BlobDesc) {
+        use self::schema::blobs::dsl::*;
+
+        self.forget_totals();
+        diesel::delete(blobs.find(blob.id))
+            .execute(&self.conn)
+            .expect("Error deleting blob");
+    }
+
     pub fn blob_list_by_tag(&self, tag_: tags::Tag) -> Vec<blob::BlobDesc> {
         use self::schema::blobs::dsl::*;
         blobs
@@ -666,18 +1097,193 @@ impl InternalIndex {
             .map(|blob_| {
                 blob::BlobDesc {
                     id: blob_.id,
-                    name: blob_.name,
+                    name: blob_.name.into(),
                 }
             })
             .collect()
     }
 
+    /// Record that all chunks of `blob` were read back and matched their hashes at `utc_secs`.
+    pub fn blob_set_verified(&self, blob: &blob::BlobDesc, utc_secs: i64) {
+        use self::schema::blobs::dsl::*;
+        diesel::update(blobs.find(blob.id))
+            .set(verified.eq(Some(utc_secs)))
+            .execute(&self.conn)
+            .expect("Error updating blob verification time");
+    }
+
+    /// Record the number of bytes stored in the backend for `blob`.
+    pub fn blob_set_length(&self, blob: &blob::BlobDesc, bytes: u64) {
+        use self::schema::blobs::dsl::*;
+        diesel::update(blobs.find(blob.id))
+            .set(length.eq(Some(bytes as i64)))
+            .execute(&self.conn)
+            .expect("Error updating blob length");
+    }
+
+    /// The number of bytes stored for `blob`, unless it was stored before lengths were kept.
+    pub fn blob_length(&self, blob: &blob::BlobDesc) -> Option<u64> {
+        use self::schema::blobs::dsl::*;
+        blobs
+            .find(blob.id)
+            .select(length)
+            .first::<Option<i64>>(&self.conn)
+            .optional()
+            .expect("Error querying blob")
+            .and_then(|l| l)
+            .map(|l| l as u64)
+    }
+
+    /// Record that `blob` was found unused at `utc_secs`, and is to be deleted once it has
+    /// stayed unused for the grace period.
+    pub fn blob_condemn(&self, blob: &blob::BlobDesc, utc_secs: i64) {
+        use self::schema::blobs::dsl::*;
+        diesel::update(blobs.find(blob.id))
+            .set(condemned.eq(Some(utc_secs)))
+            .execute(&self.conn)
+            .expect("Error condemning blob");
+    }
+
+    pub fn blob_condemned_at(&self, blob: &blob::BlobDesc) -> Option<i64> {
+        use self::schema::blobs::dsl::*;
+        blobs
+            .find(blob.id)
+            .select(condemned)
+            .first::<Option<i64>>(&self.conn)
+            .optional()
+            .expect("Error querying blob")
+            .and_then(|c| c)
+    }
+
+    /// Forget that `blob` was condemned, as it is in use again.
+    pub fn blob_clear_condemned(&self, blob: &blob::BlobDesc) {
+        use self::schema::blobs::dsl::*;
+        diesel::update(blobs.find(blob.id).filter(condemned.is_not_null()))
+            .set(condemned.eq(None::<i64>))
+            .execute(&self.conn)
+            .expect("Error updating blob");
+    }
+
+    /// Forget that the blobs tagged `tag_` were condemned, as they are in use again.
+    pub fn blob_clear_condemned_by_tag(&self, tag_: tags::Tag) {
+        use self::schema::blobs::dsl::*;
+        diesel::update(blobs.filter(tag.eq(tag_ as i32)))
+            .set(condemned.eq(None::<i64>))
+            .execute(&self.conn)
+            .expect("Error updating blobs");
+    }
+
+    /// The committed blobs with when they were last verified, least recently verified first.
+    pub fn blob_list_by_verified(&self) -> Vec<(blob::BlobDesc, Option<i64>)> {
+        use self::schema::blobs::dsl::*;
+        blobs
+            .filter(tag.eq(tags::Tag::Done as i32))
+            .order((verified.asc(), id.asc()))
+            .load::<schema::Blob>(&self.conn)
+            .expect("Error listing blobs")
+            .into_iter()
+            .map(|blob_| {
+                (
+                    blob::BlobDesc {
+                        id: blob_.id,
+                        name: blob_.name.into(),
+                    },
+                    blob_.verified,
+                )
+            })
+            .collect()
+    }
+
     pub fn last_insert_rowid(&self) -> i64 {
         diesel::select(diesel::expression::sql("last_insert_rowid()"))
             .first::<i64>(&self.conn)
             .unwrap()
     }
 
+    /// The newest schema migration applied to this index.
+    pub fn schema_version(&self) -> Option<String> {
+        upgrade::applied_versions(&self.conn).expect("Error reading schema version").pop()
+    }
+
+    /// Read a repository-wide setting.
+    pub fn config_get(&mut self, name_: &str) -> Option<String> {
+        use self::schema::repository_config::dsl::*;
+
+        repository_config
+            .filter(name.eq(name_))
+            .select(value)
+            .first::<String>(&self.conn)
+            .optional()
+            .expect("Error reading repository config")
+    }
+
+    /// The latest run of the concurrent garbage collector, if any. It is read once per
+    /// transaction: no other process can start a run while this one holds the write lock.
+    pub fn gc_epoch(&mut self) -> Option<GcEpoch> {
+        if let Some(epoch) = self.gc_epoch {
+            return epoch;
+        }
+        let epoch = self.read_gc_epoch();
+        self.gc_epoch = Some(epoch);
+        epoch
+    }
+
+    fn read_gc_epoch(&mut self) -> Option<GcEpoch> {
+        match self.config_get(GC_EPOCH_CONFIG).and_then(|v| parse_totals(&v)) {
+            Some(ref v) if v.len() == 3 => {
+                Some(GcEpoch {
+                    epoch: v[0] as i64,
+                    started_utc: v[1] as i64,
+                    blob_mark: v[2] as i64,
+                })
+            }
+            _ => None,
+        }
+    }
+
+    /// Start a new GC epoch, and commit it so that other processes stamp their hashes with it.
+    pub fn gc_set_epoch(&mut self, e: &GcEpoch) {
+        self.config_set(
+            GC_EPOCH_CONFIG,
+            &format!("{} {} {}", e.epoch, e.started_utc, e.blob_mark),
+        );
+        self.flush();
+    }
+
+    /// Store a repository-wide setting, replacing any previous value.
+    pub fn config_set(&mut self, name_: &str, value_: &str) {
+        use self::schema::repository_config::dsl::*;
+
+        if name_.starts_with("stats_") {
+            self.totals_forgotten.set(false);
+        }
+
+        let count = diesel::update(repository_config.filter(name.eq(name_)))
+            .set(value.eq(value_))
+            .execute(&self.conn)
+            .expect("Error updating repository config");
+        assert!(count <= 1);
+
+        if count == 0 {
+            let new = schema::NewRepositoryConfig {
+                name: name_,
+                value: value_,
+            };
+            diesel::insert(&new)
+                .into(repository_config)
+                .execute(&self.conn)
+                .expect("Error inserting repository config");
+        }
+    }
+
+    pub fn config_remove(&mut self, name_: &str) {
+        use self::schema::repository_config::dsl::*;
+
+        diesel::delete(repository_config.filter(name.eq(name_)))
+            .execute(&self.conn)
+            .expect("Error deleting repository config");
+    }
+
     pub fn family_id_from_name(&mut self, name_: &str) -> Option<i64> {
         use self::schema::family::dsl::*;
 
@@ -689,9 +1295,44 @@ impl InternalIndex {
             .expect("Error reading family")
     }
 
+    /// Names of all families, in the order they were created.
+    pub fn family_list(&mut self) -> Vec<String> {
+        use self::schema::family::dsl::*;
+
+        family
+            .order(id)
+            .select(name)
+            .load::<String>(&self.conn)
+            .expect("Error listing families")
+    }
+
+    /// Give a family a new name; its snapshots and their data are kept.
+    pub fn family_rename(&mut self, from: &str, to: &str) {
+        use self::schema::family::dsl::*;
+
+        diesel::update(family.filter(name.eq(from)))
+            .set(name.eq(to))
+            .execute(&self.conn)
+            .expect("Error renaming family");
+    }
+
+    /// Forget a family. Its snapshots must have been deleted first.
+    pub fn family_delete(&mut self, name_: &str) {
+        use self::schema::family::dsl::*;
+
+        diesel::delete(family.filter(name.eq(name_)))
+            .execute(&self.conn)
+            .expect("Error deleting family");
+    }
+
     /// Delete snapshot.
     pub fn snapshot_delete(&self, info: SnapshotInfo) {
         use self::schema::snapshots::dsl::*;
+        use self::schema::snapshot_labels::dsl::{snapshot_labels, snapshot_unique_id};
+
+        diesel::delete(snapshot_labels.filter(snapshot_unique_id.eq(info.unique_id as i64)))
+            .execute(&self.conn)
+            .expect("Error deleting snapshot labels");
 
         let count = diesel::delete(
             snapshots
@@ -747,21 +1388,11 @@ impl InternalIndex {
             .inner_join(family)
             .filter(name.eq(family_name_))
             .filter(snapshot_id.eq(snapshot_id_ as i64))
-            .select((
-                id,
-                tag,
-                family_id,
-                snapshot_id,
-                utc_datetime,
-                msg,
-                hash,
-                hash_ref,
-            ))
-            .first::<self::schema::Snapshot>(&self.conn)
+            .first::<(self::schema::Snapshot, self::schema::Family)>(&self.conn)
             .optional()
             .expect("Error reading snapshot info");
 
-        row_opt.map(|snap| {
+        row_opt.map(|(snap, _)| {
             (
                 SnapshotInfo {
                     unique_id: snap.id as u64,
@@ -825,6 +1456,111 @@ impl InternalIndex {
             .expect("Error updating snapshot");
     }
 
+    /// Record the statistics of a finished backup run, and when it finished.
+    pub fn snapshot_set_stats(
+        &mut self,
+        snapshot_: &SnapshotInfo,
+        stats: SnapshotStats,
+        contents: SnapshotContents,
+    ) {
+        use self::schema::snapshots::dsl::*;
+
+        diesel::update(snapshots.find(snapshot_.unique_id as i64))
+            .set((
+                bytes_read.eq(Some(stats.bytes_read as i64)),
+                bytes_new.eq(Some(stats.bytes_new as i64)),
+                finished_datetime.eq(Some(chrono::Utc::now().naive_utc())),
+                file_count.eq(Some(contents.files as i64)),
+                bytes_total.eq(Some(contents.bytes as i64)),
+            ))
+            .execute(&self.conn)
+            .expect("Error updating snapshot");
+    }
+
+    pub fn snapshot_set_provenance(
+        &mut self,
+        snapshot_: &SnapshotInfo,
+        provenance: &Provenance,
+    ) {
+        use self::schema::snapshots::dsl::*;
+
+        diesel::update(snapshots.find(snapshot_.unique_id as i64))
+            .set((
+                hostname.eq(Some(&provenance.hostname)),
+                username.eq(Some(&provenance.username)),
+                hat_version.eq(Some(&provenance.version)),
+                command_line.eq(Some(provenance.command_line.join(ARG_SEPARATOR))),
+                source_path.eq(Some(&provenance.source_path)),
+            ))
+            .execute(&self.conn)
+            .expect("Error updating snapshot");
+    }
+
+    pub fn snapshot_set_signature(
+        &mut self,
+        snapshot_: &SnapshotInfo,
+        signature_: Option<&[u8]>,
+    ) {
+        use self::schema::snapshots::dsl::*;
+
+        diesel::update(snapshots.find(snapshot_.unique_id as i64))
+            .set(signature.eq(signature_))
+            .execute(&self.conn)
+            .expect("Error updating snapshot");
+    }
+
+    /// Attach a label to a snapshot. Labels already attached are left as they are.
+    pub fn snapshot_add_label(&mut self, snapshot_: &SnapshotInfo, label_: &str) {
+        use self::schema::snapshot_labels::dsl::*;
+
+        let exists = snapshot_labels
+            .filter(snapshot_unique_id.eq(snapshot_.unique_id as i64))
+            .filter(label.eq(label_))
+            .select(id)
+            .first::<i64>(&self.conn)
+            .optional()
+            .expect("Error reading snapshot labels")
+            .is_some();
+        if !exists {
+            let new = schema::NewSnapshotLabel {
+                snapshot_unique_id: snapshot_.unique_id as i64,
+                label: label_,
+            };
+            diesel::insert(&new)
+                .into(snapshot_labels)
+                .execute(&self.conn)
+                .expect("Error inserting snapshot label");
+        }
+    }
+
+    /// Detach a label from a snapshot. Returns whether the snapshot had the label.
+    pub fn snapshot_remove_label(&mut self, snapshot_: &SnapshotInfo, label_: &str) -> bool {
+        use self::schema::snapshot_labels::dsl::*;
+
+        let count = diesel::delete(
+            snapshot_labels
+                .filter(snapshot_unique_id.eq(snapshot_.unique_id as i64))
+                .filter(label.eq(label_)),
+        ).execute(&self.conn)
+            .expect("Error deleting snapshot label");
+        count > 0
+    }
+
+    fn snapshot_labels(&mut self) -> HashMap<i64, Vec<String>> {
+        use self::schema::snapshot_labels::dsl::*;
+
+        let rows = snapshot_labels
+            .select((snapshot_unique_id, label))
+            .order(id)
+            .load::<(i64, String)>(&self.conn)
+            .expect("Error reading snapshot labels");
+        let mut labels = HashMap::new();
+        for (snapshot, l) in rows {
+            labels.entry(snapshot).or_insert_with(Vec::new).push(l);
+        }
+        labels
+    }
+
     pub fn snapshot_set_tag(&mut self, snapshot_: &SnapshotInfo, tag_: tags::Tag) {
         use self::schema::snapshots::dsl::*;
 
@@ -883,6 +1619,7 @@ impl InternalIndex {
                     .load::<(self::schema::Snapshot, self::schema::Family)>(&self.conn)
             }
         }.unwrap();
+        let mut labels = self.snapshot_labels();
 
         rows.into_iter()
             .map(|(snap, fam)| {
@@ -903,6 +1640,45 @@ impl InternalIndex {
                     hash: hash_,
                     hash_ref: snap.hash_ref,
                     status: status,
+                    stats: match (snap.bytes_read, snap.bytes_new) {
+                        (Some(r), Some(n)) => {
+                            Some(SnapshotStats {
+                                bytes_read: r as u64,
+                                bytes_new: n as u64,
+                            })
+                        }
+                        _ => None,
+                    },
+                    contents: match (snap.file_count, snap.bytes_total) {
+                        (Some(f), Some(b)) => {
+                            Some(SnapshotContents {
+                                files: f as u64,
+                                bytes: b as u64,
+                            })
+                        }
+                        _ => None,
+                    },
+                    finished: snap.finished_datetime.map(|t| {
+                        chrono::DateTime::from_utc(t, chrono::Utc)
+                    }),
+                    provenance: match (snap.hostname, snap.command_line) {
+                        (Some(host), Some(args)) => {
+                            Some(Provenance {
+                                hostname: host,
+                                username: snap.username.unwrap_or_default(),
+                                version: snap.hat_version.unwrap_or_default(),
+                                command_line: if args.is_empty() {
+                                    vec![]
+                                } else {
+                                    args.split(ARG_SEPARATOR).map(|a| a.to_string()).collect()
+                                },
+                                source_path: snap.source_path.unwrap_or_default(),
+                            })
+                        }
+                        _ => None,
+                    },
+                    labels: labels.remove(&snap.id).unwrap_or_default(),
+                    signature: snap.signature,
                     info: SnapshotInfo {
                         unique_id: snap.id as u64,
                         snapshot_id: snap.snapshot_id as u64,
@@ -922,6 +1698,8 @@ impl InternalIndex {
         msg_: &str,
         hash_ref_: &hash::tree::HashRef,
         work_opt_: Option<SnapshotWorkStatus>,
+        provenance: Option<&Provenance>,
+        labels: &[String],
     ) {
         let family_id_ = self.get_or_create_family_id(&family);
         let insert = match self.snapshot_lookup(family, snapshot_id_) {
@@ -951,6 +1729,18 @@ impl InternalIndex {
                 .into(snapshots)
                 .execute(&self.conn)
                 .expect("Error inserting new snapshot");
+
+            let info = SnapshotInfo {
+                unique_id: self.last_insert_rowid() as u64,
+                family_id: family_id_ as u64,
+                snapshot_id: snapshot_id_,
+            };
+            if let Some(provenance) = provenance {
+                self.snapshot_set_provenance(&info, provenance);
+            }
+            for l in labels {
+                self.snapshot_add_label(&info, l);
+            }
         }
     }
 }
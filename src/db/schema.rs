@@ -28,6 +28,8 @@ table! {
         blob_id -> BigInt,
         blob_ref -> Nullable<Binary>,
         ready -> Bool,
+        verified -> Nullable<BigInt>,
+        epoch -> BigInt,
     }
 }
 
@@ -46,6 +48,9 @@ table! {
         id -> BigInt,
         name -> Binary,
         tag -> Integer,
+        verified -> Nullable<BigInt>,
+        condemned -> Nullable<BigInt>,
+        length -> Nullable<BigInt>,
     }
 }
 
@@ -66,6 +71,33 @@ table! {
         msg -> Nullable<VarChar>,
         hash -> Nullable<Binary>,
         hash_ref -> Nullable<Binary>,
+        bytes_read -> Nullable<BigInt>,
+        bytes_new -> Nullable<BigInt>,
+        finished_datetime -> Nullable<Timestamp>,
+        file_count -> Nullable<BigInt>,
+        bytes_total -> Nullable<BigInt>,
+        hostname -> Nullable<VarChar>,
+        username -> Nullable<VarChar>,
+        hat_version -> Nullable<VarChar>,
+        command_line -> Nullable<VarChar>,
+        source_path -> Nullable<VarChar>,
+        signature -> Nullable<Binary>,
+    }
+}
+
+table! {
+    snapshot_labels {
+        id -> BigInt,
+        snapshot_unique_id -> BigInt,
+        label -> VarChar,
+    }
+}
+
+table! {
+    repository_config {
+        id -> BigInt,
+        name -> VarChar,
+        value -> VarChar,
     }
 }
 
@@ -85,6 +117,8 @@ pub struct Hash {
     pub blob_id: i64,
     pub blob_ref: Option<Vec<u8>>,
     pub ready: bool,
+    pub verified: Option<i64>,
+    pub epoch: i64,
 }
 
 #[derive(Insertable)]
@@ -99,6 +133,7 @@ pub struct NewHash<'a> {
     pub blob_id: i64,
     pub blob_ref: Option<&'a [u8]>,
     pub ready: bool,
+    pub epoch: i64,
 }
 
 #[derive(Queryable)]
@@ -124,6 +159,9 @@ pub struct Blob {
     pub id: i64,
     pub name: Vec<u8>,
     pub tag: i32,
+    pub verified: Option<i64>,
+    pub condemned: Option<i64>,
+    pub length: Option<i64>,
 }
 
 #[derive(Insertable)]
@@ -157,6 +195,17 @@ pub struct Snapshot {
     pub msg: Option<String>,
     pub hash: Option<Vec<u8>>,
     pub hash_ref: Option<Vec<u8>>,
+    pub bytes_read: Option<i64>,
+    pub bytes_new: Option<i64>,
+    pub finished_datetime: Option<chrono::NaiveDateTime>,
+    pub file_count: Option<i64>,
+    pub bytes_total: Option<i64>,
+    pub hostname: Option<String>,
+    pub username: Option<String>,
+    pub hat_version: Option<String>,
+    pub command_line: Option<String>,
+    pub source_path: Option<String>,
+    pub signature: Option<Vec<u8>>,
 }
 
 #[derive(Insertable)]
@@ -170,3 +219,17 @@ pub struct NewSnapshot<'a> {
     pub hash: Option<&'a [u8]>,
     pub hash_ref: Option<&'a [u8]>,
 }
+
+#[derive(Insertable)]
+#[table_name = "snapshot_labels"]
+pub struct NewSnapshotLabel<'a> {
+    pub snapshot_unique_id: i64,
+    pub label: &'a str,
+}
+
+#[derive(Insertable)]
+#[table_name = "repository_config"]
+pub struct NewRepositoryConfig<'a> {
+    pub name: &'a str,
+    pub value: &'a str,
+}
@@ -0,0 +1,88 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Schema upgrades for the local indexes.
+//!
+//! Every index records the migrations applied to it in diesel's `__diesel_schema_migrations`
+//! table; the newest applied migration is the schema version of the index.
+
+
+use diesel;
+use diesel::prelude::*;
+use errors::DieselError;
+use std::fs;
+use std::path::Path;
+use util::InfoWriter;
+
+use super::{Connection, sqlite_path};
+
+embed_migrations!("migrations");
+
+/// Version of the newest migration known to this build. Must be bumped with every migration.
+pub const SCHEMA_VERSION: &'static str = "20171102090000";
+
+/// Versions of the migrations already applied to an index, oldest first.
+pub fn applied_versions(conn: &Connection) -> Result<Vec<String>, DieselError> {
+    let tables = diesel::expression::sql::<diesel::types::BigInt>(
+        "SELECT COUNT(*) FROM sqlite_master \
+         WHERE type = 'table' AND name = '__diesel_schema_migrations'",
+    ).get_result::<i64>(conn)?;
+    if tables == 0 {
+        return Ok(vec![]);
+    }
+    Ok(
+        diesel::expression::sql::<diesel::types::Text>(
+            "SELECT version FROM __diesel_schema_migrations ORDER BY version",
+        ).load::<String>(conn)?,
+    )
+}
+
+/// Bring the schema of an index up to date.
+///
+/// The migrations are embedded in the binary; an existing `migrations_dir` takes precedence so
+/// that new migrations can be tried out without a rebuild. Indexes written by a newer version
+/// of hat are refused rather than misread, and an on-disk index is copied aside before it is
+/// upgraded.
+pub fn run(conn: &Connection, url: &str, migrations_dir: &Path) -> Result<(), DieselError> {
+    let path = sqlite_path(url);
+    let applied = applied_versions(conn)?;
+    if let Some(current) = applied.last() {
+        if &current[..] > SCHEMA_VERSION {
+            return Err(From::from(format!(
+                "Index {} has schema version {}, newer than the supported {}",
+                path,
+                current,
+                SCHEMA_VERSION
+            )));
+        }
+        if &current[..] < SCHEMA_VERSION && path != ":memory:" {
+            let backup = format!("{}.schema-{}.bak", path, current);
+            info!("Upgrading index {} from schema version {}", path, current);
+            // Fold the write-ahead log into the main file so that the copy is complete.
+            conn.execute("PRAGMA wal_checkpoint(TRUNCATE);")?;
+            fs::copy(path, &backup)?;
+        }
+    }
+
+    if migrations_dir.is_dir() {
+        diesel::migrations::run_pending_migrations_in_directory(
+            conn,
+            migrations_dir,
+            &mut InfoWriter,
+        )?;
+    } else {
+        embedded_migrations::run_with_output(conn, &mut InfoWriter)?;
+    }
+    Ok(())
+}
@@ -84,6 +84,8 @@ mod hat_error {
 
 mod diesel_error {
     use diesel;
+    use std::io;
+    use std::borrow::Cow;
 
     error_type! {
         #[derive(Debug)]
@@ -100,6 +102,14 @@ mod diesel_error {
             SqlExecute(diesel::result::Error) {
                 cause;
             },
+            IO(io::Error) {
+                cause;
+            },
+            Schema(Cow<'static, str>) {
+                desc (e) &**e;
+                from (s: &'static str) s.into();
+                from (s: String) s.into();
+            },
         }
     }
 }
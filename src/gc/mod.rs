@@ -16,6 +16,7 @@
 use db::{GcData, UpdateFn, SnapshotInfo};
 #[cfg(test)]
 use std::collections::HashMap;
+use std::collections::HashSet;
 #[cfg(test)]
 use std::fmt;
 #[cfg(test)]
@@ -64,24 +65,23 @@ pub trait GcBackend {
     fn reverse_refs(&self, hash_id: Id) -> Result<Vec<Id>, Self::Err>;
 
     fn list_ids_by_tag(&self, tag: tags::Tag) -> Result<mpsc::Receiver<Id>, Self::Err>;
+    fn list_ids(&self) -> Result<mpsc::Receiver<Id>, Self::Err>;
 
     fn manual_commit(&mut self) -> Result<(), Self::Err>;
 }
 
 
-pub fn mark_tree<B>(backend: &mut B, root: Id, tag: tags::Tag) -> Result<(), B::Err>
+/// Add `root` and everything it refers to, directly or not, to `used`. The marks are kept in
+/// memory rather than in tags, as commits running meanwhile tag the hashes they register.
+pub fn mark_tree<B>(backend: &B, root: Id, used: &mut HashSet<Id>) -> Result<(), B::Err>
 where
     B: GcBackend,
 {
-    backend.set_tag(root, tag)?;
-    for r in backend.reverse_refs(root)? {
-        if let Some(current) = backend.get_tag(r)? {
-            if current == tag {
-                continue;
-            }
+    let mut todo = vec![root];
+    while let Some(id) = todo.pop() {
+        if used.insert(id) {
+            todo.extend(backend.reverse_refs(id)?);
         }
-        backend.set_tag(r, tag)?;
-        mark_tree(backend, r, tag)?;
     }
 
     Ok(())
@@ -109,6 +109,10 @@ pub trait Gc<B> {
 
     fn list_unused_ids(&mut self, refs: mpsc::Sender<Id>) -> Result<(), Self::Err>;
 
+    /// Rebuild the bookkeeping from `roots`, the final references of all registered snapshots
+    /// with one entry per snapshot, for when it no longer agrees with the snapshots.
+    fn reset_roots(&mut self, roots: &[Id]) -> Result<(), Self::Err>;
+
     fn status(&mut self, final_ref: Id) -> Result<Option<Status>, Self::Err>;
 }
 
@@ -296,6 +300,23 @@ impl GcBackend for SafeMemoryBackend {
         Ok(receiver)
     }
 
+    fn list_ids(&self) -> Result<mpsc::Receiver<Id>, Self::Err> {
+        let mut ids: Vec<Id> = self.backend
+            .lock()
+            .unwrap()
+            .snapshot_refs
+            .values()
+            .flat_map(|refs| refs.iter().cloned())
+            .collect();
+        ids.sort();
+        ids.dedup();
+
+        let (sender, receiver) = mpsc::channel();
+        ids.iter().map(|id| sender.send(*id)).last();
+
+        Ok(receiver)
+    }
+
     fn manual_commit(&mut self) -> Result<(), Self::Err> {
         self.commit();
         Ok(())
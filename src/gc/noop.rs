@@ -64,6 +64,10 @@ impl<B: gc::GcBackend> gc::Gc<B> for GcNoop {
         Ok(())
     }
 
+    fn reset_roots(&mut self, _roots: &[gc::Id]) -> Result<(), Self::Err> {
+        Ok(())
+    }
+
     fn status(&mut self, _final_ref: gc::Id) -> Result<Option<gc::Status>, Self::Err> {
         Ok(Some(gc::Status::Complete))
     }
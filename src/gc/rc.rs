@@ -15,6 +15,7 @@
 
 use db::{GcData, SnapshotInfo};
 use gc;
+use std::collections::{HashMap, HashSet};
 use std::sync::mpsc;
 use tags;
 
@@ -120,17 +121,17 @@ impl<B: gc::GcBackend> gc::Gc<B> for GcRc<B> {
 
 
     fn list_unused_ids(&mut self, refs: mpsc::Sender<gc::Id>) -> Result<(), Self::Err> {
-        self.backend.set_all_tags(tags::Tag::Done)?;
-        for r in self.backend.list_ids_by_tag(tags::Tag::Done)? {
+        let ids: Vec<gc::Id> = self.backend.list_ids()?.iter().collect();
+        let mut used = HashSet::new();
+        for &r in &ids {
             let data = self.backend.get_data(r, DATA_FAMILY)?;
             assert!(data.num >= 0);
             if data.num > 0 {
-                gc::mark_tree(&mut self.backend, r, tags::Tag::Reserved)?;
+                gc::mark_tree(&self.backend, r, &mut used)?;
             }
         }
-        // Everything that is still 'Done' is unused.
-        // Everything that is 'Reserved' is used.
-        for r in self.backend.list_ids_by_tag(tags::Tag::Done)?.iter() {
+        // Everything that was not reached from a registered snapshot is unused.
+        for r in ids.into_iter().filter(|r| !used.contains(r)) {
             if refs.send(r).is_err() {
                 break;
             }
@@ -139,6 +140,33 @@ impl<B: gc::GcBackend> gc::Gc<B> for GcRc<B> {
         Ok(())
     }
 
+    fn reset_roots(&mut self, roots: &[gc::Id]) -> Result<(), Self::Err> {
+        let mut counts = HashMap::new();
+        for &r in roots {
+            *counts.entry(r).or_insert(0) += 1;
+        }
+
+        self.backend.manual_commit()?;
+        let ids: Vec<gc::Id> = self.backend.list_ids()?.iter().collect();
+        for r in ids {
+            let num = counts.get(&r).cloned().unwrap_or(0);
+            if self.backend.get_data(r, DATA_FAMILY)?.num != num {
+                self.backend.update_data(
+                    r,
+                    DATA_FAMILY,
+                    move |GcData { bytes, .. }| {
+                        Some(GcData {
+                            num: num,
+                            bytes: bytes,
+                        })
+                    },
+                )?;
+            }
+        }
+
+        Ok(())
+    }
+
     fn status(&mut self, final_ref: gc::Id) -> Result<Option<gc::Status>, Self::Err> {
         Ok(match self.backend.get_tag(final_ref)? {
             Some(tags::Tag::Complete) |
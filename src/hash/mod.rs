@@ -16,6 +16,7 @@
 
 
 use blob;
+use chrono;
 use crypto;
 use db;
 
@@ -238,11 +239,18 @@ impl HashIndex {
         self.0.index.lock().hash_locate_by_id(id)
     }
 
-    /// Check whether this `Hash` already exists in the system.
+    /// Check whether this `Hash` already exists in the system. An existing hash is about to be
+    /// reused, so it is stamped with the current GC epoch.
     pub fn hash_exists(&self, hash: &Hash) -> bool {
         assert!(!hash.bytes.is_empty());
         let (queue, mut index) = self.0.lock();
-        self.0.locate(hash, &queue, &mut index).is_some()
+        match self.0.locate(hash, &queue, &mut index) {
+            Some(entry) => {
+                index.hash_touch(entry.id);
+                true
+            }
+            None => false,
+        }
     }
 
     /// Locate the local childs of the `Hash`.
@@ -293,7 +301,10 @@ impl HashIndex {
         // through and delete uncommitted entries.
         let (mut queue, mut index) = self.0.lock();
         match self.0.locate(&hash_entry.hash, &queue, &mut index) {
-            Some(entry) => ReserveResult::HashKnown(entry.id),
+            Some(entry) => {
+                index.hash_touch(entry.id);
+                ReserveResult::HashKnown(entry.id)
+            }
             None => {
                 let id = self.0.reserve(hash_entry, &mut queue, &mut index);
                 ReserveResult::ReserveOk(id)
@@ -328,11 +339,39 @@ impl HashIndex {
         self.0.index.lock().hash_list()
     }
 
+    /// Point a committed hash at another copy of its chunk.
+    pub fn relocate(&self, id: u64, persistent_ref: blob::ChunkRef) {
+        let mut index = self.0.index.lock();
+        let entry = index.hash_locate_by_id(id).expect("relocate unknown hash");
+        index.hash_set_ready(
+            id,
+            &db::QueueEntry {
+                id: id,
+                node: entry.node,
+                leaf: entry.leaf,
+                childs: entry.childs,
+                persistent_ref: Some(persistent_ref),
+                tag: None,
+            },
+        );
+    }
+
     /// Permanently delete hash by its ID.
     pub fn delete(&self, id: u64) {
         self.0.index.lock().hash_delete(id)
     }
 
+    /// Delete a hash unless it was used since GC epoch `epoch` started. Returns whether it was
+    /// deleted.
+    pub fn delete_untouched(&self, id: u64, epoch: i64) -> bool {
+        self.0.index.lock().hash_delete_untouched(id, epoch)
+    }
+
+    /// List the IDs of all hashes, parents before their children.
+    pub fn list_ids(&self) -> Vec<u64> {
+        self.0.index.lock().hash_list_ids()
+    }
+
     /// API related to tagging, which is useful to indicate state during operation stages.
     /// It operates directly on the underlying IDs.
     pub fn set_tag(&self, id: u64, tag: tags::Tag) {
@@ -393,6 +432,28 @@ impl HashIndex {
         )
     }
 
+    /// Remove hashes whose chunks are no longer stored in any blob, and compact the index if
+    /// enough of it is free. Returns the number of hashes removed.
+    pub fn prune(&self) -> usize {
+        let mut index = self.0.index.lock();
+        let count = index.hash_prune_orphans();
+        index.vacuum_if_worthwhile();
+        count
+    }
+
+    /// Record that the chunk of `hash` has just been read back and verified.
+    pub fn mark_verified(&self, hash: &Hash) {
+        self.0.index.lock().hash_set_verified(
+            &hash.bytes[..],
+            chrono::Utc::now().timestamp(),
+        );
+    }
+
+    /// When the chunk of `hash` was last verified, in seconds since the epoch.
+    pub fn verified_at(&self, hash: &Hash) -> Option<i64> {
+        self.0.index.lock().hash_verified(&hash.bytes[..])
+    }
+
     /// Manual commit. This also disables automatic periodic commit.
     pub fn manual_commit(&self) {
         let mut guard = self.0.index.lock();
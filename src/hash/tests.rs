@@ -70,7 +70,7 @@ impl HashTreeBackend for MemoryBackend {
             Some(&(_, _, _, ref chunk)) => {
                 Some(ChunkRef {
                     blob_id: None,
-                    blob_name: hash.bytes.clone(),
+                    blob_name: hash.bytes.clone().into(),
                     offset: 0,
                     length: chunk.len(),
                     packing: None,
@@ -108,7 +108,7 @@ impl HashTreeBackend for MemoryBackend {
                 info: None,
                 persistent_ref: ChunkRef {
                     blob_id: None,
-                    blob_name: hash.bytes.clone(),
+                    blob_name: hash.bytes.clone().into(),
                     offset: 0,
                     length: len,
                     packing: None,
@@ -280,3 +280,49 @@ fn identity_1_short_of_flush() {
         assert_eq!(bytes, chunk);
     }
 }
+
+#[test]
+fn hash_algorithms_differ() {
+    use crypto::keys::{HashAlgorithm, Keeper};
+
+    let blake2b = Keeper::new_for_testing();
+    let blake3 = Keeper::new_for_testing().with_hash_algorithm(HashAlgorithm::Blake3);
+
+    let h2 = Hash::new(&blake2b, NodeType::Leaf, LeafType::FileChunk, b"hello");
+    let h3 = Hash::new(&blake3, NodeType::Leaf, LeafType::FileChunk, b"hello");
+    assert_eq!(h2.bytes.len(), h3.bytes.len());
+    assert!(h2.bytes != h3.bytes);
+
+    // Fingerprints are deterministic and sensitive to the node type.
+    assert_eq!(
+        h3,
+        Hash::new(&blake3, NodeType::Leaf, LeafType::FileChunk, b"hello")
+    );
+    assert!(h3 != Hash::new(&blake3, NodeType::Branch(1), LeafType::FileChunk, b"hello"));
+}
+
+#[test]
+fn trees_are_readable_with_any_fanout() {
+    for &order in &[2, 3, DEFAULT_FANOUT, 64] {
+        let backend = MemoryBackend::new();
+        let mut ht = SimpleHashTreeWriter::new(LeafType::FileChunk, order, backend.clone());
+        for i in 0u8..100 {
+            ht.append(&[i]).unwrap();
+        }
+        let state = ht.state();
+        let hash_ref = ht.hash(None).unwrap();
+
+        let it = LeafIterator::new(backend.clone(), hash_ref).unwrap().expect(
+            "tree not found",
+        );
+        assert_eq!(it.collect::<Vec<_>>(), (0u8..100).map(|i| vec![i]).collect::<Vec<_>>());
+
+        // Only writers of at least the same order may continue the tree.
+        assert!(SimpleHashTreeWriter::resume(LeafType::FileChunk, order, backend.clone(), &state)
+            .is_ok());
+        if order > 2 {
+            assert!(SimpleHashTreeWriter::resume(LeafType::FileChunk, 2, backend, &state)
+                .is_err());
+        }
+    }
+}
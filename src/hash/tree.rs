@@ -128,7 +128,7 @@ fn hash_refs_to_bytes(refs: &Vec<HashRef>) -> Vec<u8> {
     out
 }
 
-fn hash_refs_from_bytes(bytes: &[u8]) -> Option<Vec<HashRef>> {
+pub fn hash_refs_from_bytes(bytes: &[u8]) -> Option<Vec<HashRef>> {
     let mut out = Vec::new();
     if bytes.is_empty() {
         return Some(out);
@@ -154,7 +154,7 @@ fn test_hash_refs_identity() {
     fn prop(count: u8, hash: Vec<u8>, blob: Vec<u8>, n: usize) -> bool {
         let chunk_ref = ChunkRef {
             blob_id: None,
-            blob_name: blob.clone(),
+            blob_name: blob.clone().into(),
             offset: n,
             length: n,
             packing: None,
@@ -190,6 +190,9 @@ fn test_hash_refs_identity() {
 }
 
 
+/// Number of children per branch node, unless the repository says otherwise.
+pub const DEFAULT_FANOUT: usize = 8;
+
 /// A simple implementation of a hash-tree stream writer.
 ///
 /// The hash-tree is "created" as append-only and is streamed from first to last data-block. The
@@ -221,6 +224,76 @@ impl<B: HashTreeBackend> SimpleHashTreeWriter<B> {
         }
     }
 
+    /// Continue a tree from the state of an earlier writer, as returned by `state`. Appending
+    /// to the resumed tree gives the same tree as if the earlier writer had continued.
+    ///
+    /// States of writers with a larger order than `order` are rejected.
+    pub fn resume(
+        leaf_type: LeafType,
+        order: usize,
+        backend: B,
+        state: &[u8],
+    ) -> Result<SimpleHashTreeWriter<B>, capnp::Error> {
+        let reader = capnp::serialize_packed::read_message(
+            &mut &state[..],
+            capnp::message::ReaderOptions::new(),
+        )?;
+        let msg = reader.get_root::<root_capnp::tree_state::Reader>()?;
+
+        let mut levels = vec![];
+        for level in msg.get_levels()?.iter() {
+            let ids = level.get_hash_ids()?;
+            let refs = level.get_hash_refs()?;
+            let mut entries = vec![];
+            for i in 0..refs.len() {
+                entries.push((ids.get(i), HashRef::read_msg(&refs.get(i))?));
+            }
+            if entries.len() >= order {
+                return Err(capnp::Error::failed(
+                    format!("Tree state does not fit order {}", order),
+                ));
+            }
+            levels.push(entries);
+        }
+
+        Ok(SimpleHashTreeWriter {
+            backend: backend,
+            order: order,
+            leaf: leaf_type,
+            levels: levels,
+        })
+    }
+
+    /// Serialize the rightmost path of the tree written so far.
+    pub fn state(&self) -> Vec<u8> {
+        let mut message = capnp::message::Builder::new_default();
+        {
+            let root = message.init_root::<root_capnp::tree_state::Builder>();
+            let mut levels = root.init_levels(self.levels.len() as u32);
+            for (i, level) in self.levels.iter().enumerate() {
+                let mut l = levels.borrow().get(i as u32);
+                {
+                    let mut ids = l.borrow().init_hash_ids(level.len() as u32);
+                    for (j, &(id, _)) in level.iter().enumerate() {
+                        ids.set(j as u32, id);
+                    }
+                }
+                let mut refs = l.init_hash_refs(level.len() as u32);
+                for (j, &(_, ref href)) in level.iter().enumerate() {
+                    href.populate_msg(refs.borrow().get(j as u32));
+                }
+            }
+        }
+        let mut out = Vec::new();
+        capnp::serialize_packed::write_message(&mut out, &message).unwrap();
+        out
+    }
+
+    /// The hash ids referenced by the rightmost path of the tree.
+    pub fn path_ids(&self) -> Vec<u64> {
+        self.levels.iter().flat_map(|l| l.iter().map(|&(id, _)| id)).collect()
+    }
+
     fn top_level(&self) -> Option<usize> {
         self.levels.len().checked_sub(1)
     }
@@ -450,6 +523,12 @@ where
             }
         }))
     }
+
+    /// Like `next`, but returns an error instead of panicking when a chunk cannot be read.
+    pub fn try_next(&mut self) -> Result<Option<Vec<u8>>, B::Err> {
+        while self.visitor.leafs.is_empty() && self.walker.resume(&mut self.visitor)? {}
+        Ok(self.visitor.leafs.pop_front())
+    }
 }
 
 pub struct LeafVisitor {
@@ -467,7 +546,6 @@ impl<B: HashTreeBackend> Iterator for LeafIterator<B> {
     type Item = Vec<u8>;
 
     fn next(&mut self) -> Option<Vec<u8>> {
-        while self.visitor.leafs.is_empty() && self.walker.resume(&mut self.visitor).unwrap() {}
-        self.visitor.leafs.pop_front()
+        self.try_next().unwrap()
     }
 }
@@ -0,0 +1,397 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Snapshots as tar archives.
+
+use backend::StoreBackend;
+use errors::HatError;
+use hash;
+use key;
+use std::cmp;
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Component, Path, PathBuf};
+use std::sync::mpsc;
+use std::thread;
+use tar;
+use util::{self, FileIterator, HoleFiller};
+
+use super::HatRc;
+use super::family::{Family, FileError};
+use super::walker::Content;
+
+
+/// Reads the data of a file from its hash tree, as exactly `size` bytes: data beyond `size` is
+/// dropped, and data that ends short of `size` is an error, as the size has been promised to
+/// the reader already, e.g. in an archive header.
+pub struct DataReader<B: StoreBackend> {
+    leafs: Option<hash::tree::LeafIterator<key::HashStoreBackend<B>>>,
+    chunk: Vec<u8>,
+    pos: usize,
+    remaining: u64,
+}
+
+impl<B: StoreBackend> DataReader<B> {
+    pub fn new(
+        leafs: Option<hash::tree::LeafIterator<key::HashStoreBackend<B>>>,
+        size: u64,
+    ) -> DataReader<B> {
+        DataReader {
+            leafs: leafs,
+            chunk: vec![],
+            pos: 0,
+            remaining: size,
+        }
+    }
+}
+
+impl<B: StoreBackend> Read for DataReader<B> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.remaining == 0 || buf.is_empty() {
+            return Ok(0);
+        }
+        while self.pos == self.chunk.len() {
+            let next = match self.leafs {
+                Some(ref mut leafs) => {
+                    leafs.try_next().map_err(|e| {
+                        io::Error::new(io::ErrorKind::Other, e.to_string())
+                    })?
+                }
+                None => None,
+            };
+            match next {
+                Some(chunk) => {
+                    self.chunk = chunk;
+                    self.pos = 0;
+                }
+                None => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        format!("File data is {} bytes shorter than recorded", self.remaining),
+                    ));
+                }
+            }
+        }
+        let len = cmp::min(
+            cmp::min(buf.len(), self.chunk.len() - self.pos) as u64,
+            self.remaining,
+        ) as usize;
+        buf[..len].copy_from_slice(&self.chunk[self.pos..self.pos + len]);
+        self.pos += len;
+        self.remaining -= len as u64;
+        Ok(len)
+    }
+}
+
+/// Reads the data sent over a channel, until the sender is dropped. An error sent over the
+/// channel is returned as is, so that the reader knows the data is incomplete.
+struct ChannelReader {
+    receiver: mpsc::Receiver<io::Result<Vec<u8>>>,
+    chunk: Vec<u8>,
+    pos: usize,
+}
+
+impl Read for ChannelReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.chunk.len() {
+            match self.receiver.recv() {
+                Ok(Ok(chunk)) => {
+                    self.chunk = chunk;
+                    self.pos = 0;
+                }
+                Ok(Err(e)) => return Err(e),
+                Err(_) => return Ok(0),
+            }
+        }
+        let len = cmp::min(buf.len(), self.chunk.len() - self.pos);
+        buf[..len].copy_from_slice(&self.chunk[self.pos..self.pos + len]);
+        self.pos += len;
+        Ok(len)
+    }
+}
+
+fn header(info: &key::Info, entry_type: tar::EntryType, default_mode: u32) -> tar::Header {
+    let mut header = tar::Header::new_gnu();
+    header.set_entry_type(entry_type);
+    header.set_size(0);
+    header.set_mode(info.permissions.as_ref().map_or(default_mode, |p| p.mode() & 0o7777));
+    header.set_mtime(info.modified_ts_secs.unwrap_or(0));
+    header.set_uid(info.user_id.unwrap_or(0));
+    header.set_gid(info.group_id.unwrap_or(0));
+    // Names that do not fit the header are left out; the ids are still there.
+    if let Some(ref name) = info.user_name {
+        let _ = header.set_username(name);
+    }
+    if let Some(ref name) = info.group_name {
+        let _ = header.set_groupname(name);
+    }
+    header
+}
+
+impl<B: StoreBackend> HatRc<B> {
+    /// Write a snapshot of a family to `out` as a tar archive, or its latest snapshot if no id
+    /// is given. Paths in the archive are relative to the snapshot root. Nothing is written to
+    /// local disk; file data is streamed from the repository.
+    pub fn export_tar<W: Write>(
+        &mut self,
+        family_name: &str,
+        snapshot_id: Option<u64>,
+        out: W,
+    ) -> Result<(), HatError> {
+        let (_, dir_ref) = self.complete_snapshot(family_name, snapshot_id)?;
+        let family = self.open_family(family_name.to_string())?;
+
+        let mut builder = tar::Builder::new(out);
+        self.export_dir(&family, &mut builder, PathBuf::new(), dir_ref)?;
+        builder.into_inner()?.flush()?;
+        Ok(())
+    }
+
+    fn export_dir<W: Write>(
+        &self,
+        family: &Family<B>,
+        builder: &mut tar::Builder<W>,
+        dir: PathBuf,
+        dir_ref: hash::tree::HashRef,
+    ) -> Result<(), HatError> {
+        for res in family.iter_dir_data(dir_ref, self.hash_backend())? {
+            let (entry, content) = res?;
+            let path = dir.join(OsStr::from_bytes(&entry.info.name[..]));
+            let info = &entry.info;
+            match content {
+                Content::Dir(href) => {
+                    let mut h = header(info, tar::EntryType::Directory, 0o755);
+                    builder.append_data(&mut h, &path, io::empty())?;
+                    self.export_dir(family, builder, path, href)?;
+                }
+                Content::Link(target) => {
+                    let mut h = header(info, tar::EntryType::Symlink, 0o777);
+                    h.set_link_name(&target)?;
+                    builder.append_data(&mut h, &path, io::empty())?;
+                }
+                Content::Inline(bytes) => {
+                    let mut h = header(info, tar::EntryType::Regular, 0o644);
+                    match (info.sparse_map.as_ref(), info.byte_length) {
+                        (Some(extents), Some(size)) => {
+                            h.set_size(size);
+                            let reader = HoleFiller::new(&bytes[..], extents.clone(), size);
+                            builder.append_data(&mut h, &path, reader)?;
+                        }
+                        _ => {
+                            h.set_size(bytes.len() as u64);
+                            builder.append_data(&mut h, &path, &bytes[..])?;
+                        }
+                    }
+                }
+                Content::Data(href) => {
+                    let mut h = header(info, tar::EntryType::Regular, 0o644);
+                    let leafs = hash::tree::LeafIterator::new(self.hash_backend(), href.clone())?;
+                    match info.byte_length {
+                        Some(size) => {
+                            h.set_size(size);
+                            // The holes of a sparse file are filled in with zeros.
+                            let extents =
+                                info.sparse_map.clone().unwrap_or_else(|| vec![(0, size)]);
+                            let reader = DataReader::new(leafs, util::data_length(&extents));
+                            let reader = HoleFiller::new(reader, extents, size);
+                            builder.append_data(&mut h, &path, reader)?;
+                        }
+                        None => {
+                            // Without a recorded size, the data is read once to find it, and
+                            // again to stream it after the header.
+                            let mut size = 0;
+                            if let Some(mut leafs) = leafs {
+                                while let Some(chunk) = leafs.try_next()? {
+                                    size += chunk.len() as u64;
+                                }
+                            }
+                            h.set_size(size);
+                            let leafs = hash::tree::LeafIterator::new(self.hash_backend(), href)?;
+                            builder.append_data(&mut h, &path, DataReader::new(leafs, size))?;
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The components of a path in an archive, without any leading `/` or `./`.
+fn archive_path(path: &Path) -> Result<Vec<Vec<u8>>, HatError> {
+    let mut names = vec![];
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => names.push(name.as_bytes().to_vec()),
+            Component::RootDir | Component::CurDir => (),
+            _ => return Err(From::from(format!("Invalid path in archive: {}", path.display()))),
+        }
+    }
+    Ok(names)
+}
+
+impl<B: StoreBackend> Family<B> {
+    /// Read a tar archive into the family's index, as `snapshot_dir` does for a directory. File
+    /// data is chunked and deduplicated as it is read, without being written to disk. Entries
+    /// not in the archive are dropped from the index, so that the next commit holds exactly
+    /// the archive's files. Hard links and special files are skipped.
+    pub fn snapshot_tar<R: Read>(&self, archive: R) -> Result<(), HatError> {
+        let mut archive = tar::Archive::new(archive);
+        // Directories by path, including those only implied by the paths of their files.
+        let mut dirs: HashMap<Vec<Vec<u8>>, u64> = HashMap::new();
+
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            let path = entry.path()?.into_owned();
+            let mut names = archive_path(&path)?;
+            let name = match names.pop() {
+                Some(name) => name,
+                None => continue,
+            };
+
+            let header = entry.header().clone();
+            let data = match header.entry_type() {
+                tar::EntryType::Regular | tar::EntryType::Continuous => {
+                    key::Data::FilePlaceholder
+                }
+                tar::EntryType::Directory => key::Data::DirPlaceholder,
+                tar::EntryType::Symlink => {
+                    match entry.link_name()? {
+                        Some(target) => key::Data::Symlink(target.into_owned()),
+                        None => return Err(From::from("Symlink without target in archive")),
+                    }
+                }
+                _ => {
+                    warn!("Skipping '{}': unsupported entry type", path.display());
+                    continue;
+                }
+            };
+
+            let parent = self.tar_parent(&mut dirs, &names)?;
+            let mut key_entry = key::Entry::new(parent, name.clone(), data, None);
+            key_entry.info.modified_ts_secs = header.mtime().ok();
+            key_entry.info.permissions = header.mode().ok().map(fs::Permissions::from_mode);
+            key_entry.info.user_id = header.uid().ok();
+            key_entry.info.group_id = header.gid().ok();
+            key_entry.info.user_name = header.username().ok().and_then(|n| n.map(String::from));
+            key_entry.info.group_name = header.groupname().ok().and_then(|n| n.map(String::from));
+
+            match key_entry.data {
+                key::Data::DirPlaceholder => {
+                    let id = self.snapshot_direct(key_entry, true, None)?;
+                    names.push(name);
+                    dirs.insert(names, id);
+                }
+                key::Data::FilePlaceholder => {
+                    key_entry.info.byte_length = Some(header.size()?);
+                    self.snapshot_tar_file(&path, key_entry, &mut entry)?;
+                }
+                _ => {
+                    self.snapshot_direct(key_entry, false, None)?;
+                }
+            }
+        }
+
+        match self.key_store_process[0].send_reply(key::Msg::CommitReservedNodes(Some(None)))? {
+            key::Reply::Ok => Ok(()),
+            _ => Err(From::from("Unexpected reply from key store")),
+        }
+    }
+
+    /// The id of the directory `names`, inserting it and its parents if they have not been seen.
+    fn tar_parent(
+        &self,
+        dirs: &mut HashMap<Vec<Vec<u8>>, u64>,
+        names: &[Vec<u8>],
+    ) -> Result<Option<u64>, HatError> {
+        let mut parent = None;
+        for i in 0..names.len() {
+            parent = Some(match dirs.get(&names[..i + 1]).cloned() {
+                Some(id) => id,
+                None => {
+                    let mut e = key::Entry::new(
+                        parent,
+                        names[i].clone(),
+                        key::Data::DirPlaceholder,
+                        None,
+                    );
+                    e.info.permissions = Some(fs::Permissions::from_mode(0o755));
+                    let id = self.snapshot_direct(e, true, None)?;
+                    dirs.insert(names[..i + 1].to_vec(), id);
+                    id
+                }
+            });
+        }
+        Ok(parent)
+    }
+
+    /// Insert a file, feeding it its data from `data` while a key store reads it. If `data`
+    /// fails, the file is kept as far as it was read, marked to be read again like a file that
+    /// failed during a commit, and recorded in the family's errors.
+    fn snapshot_tar_file<R: Read>(
+        &self,
+        path: &Path,
+        entry: key::Entry,
+        data: &mut R,
+    ) -> Result<(), HatError> {
+        let (sender, receiver) = mpsc::sync_channel(4);
+        let family = self.clone();
+        let insert = thread::spawn(move || {
+            let reader = ChannelReader {
+                receiver: receiver,
+                chunk: vec![],
+                pos: 0,
+            };
+            family.snapshot_direct(entry, false, Some(FileIterator::from_reader(Box::new(reader))))
+        });
+
+        let mut buf = vec![0; 128 * 1024];
+        let mut read = Ok(());
+        loop {
+            match data.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => {
+                    if sender.send(Ok(buf[..n].to_vec())).is_err() {
+                        // The key store stopped reading, e.g. as it failed to insert the file.
+                        break;
+                    }
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => {
+                    // The key store must not take the file to be complete.
+                    let _ = sender.send(Err(io::Error::new(e.kind(), e.to_string())));
+                    read = Err(e);
+                    break;
+                }
+            }
+        }
+        drop(sender);
+
+        let inserted = insert.join().map_err(|_| "Key store insert panicked")?;
+        if let Err(ref e) = read {
+            warn!("Incomplete '{}': {}", path.display(), e);
+            self.errors.lock().unwrap().push(FileError {
+                path: path.to_path_buf(),
+                error: e.to_string(),
+            });
+        }
+        read?;
+        inserted?;
+        Ok(())
+    }
+}
@@ -0,0 +1,187 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The entry point for programs that embed hat.
+
+use backend::StoreBackend;
+use errors::HatError;
+use secstr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use util::Progress;
+
+use super::{FileError, HashAlgorithm, HatRc, Limits, SnapshotSummary};
+
+
+/// Default size of the blobs that chunks are packed into.
+pub const DEFAULT_MAX_BLOB_SIZE: usize = 4 * 1024 * 1024;
+
+/// Opens a repository with the settings the command line takes as flags.
+///
+/// ```ignore
+/// let mut hat = HatBuilder::new(backend, PathBuf::from("/var/cache/hat")).open()?;
+/// let report = hat.commit_dir("home", Path::new("/home"))?;
+/// hat.checkout("home".to_string(), None, PathBuf::from("/tmp/home"), &Default::default())?;
+/// for snapshot in hat.list_snapshots() { ... }
+/// hat.gc()?;
+/// ```
+///
+/// A new repository is created with `init` instead of `open`.
+///
+/// Several processes may use a repository at once; those that do should hold a `lock` of it
+/// while they work, and `watch_lock` it so as to stop if it is lost.
+pub struct HatBuilder<B> {
+    backend: Arc<B>,
+    cache_dir: PathBuf,
+    migrations_dir: PathBuf,
+    max_blob_size: usize,
+    hash_algorithm: Option<HashAlgorithm>,
+    passphrase: Option<secstr::SecStr>,
+    namespace: Option<String>,
+    limits: Limits,
+    inline_max: Option<usize>,
+    progress: Option<Arc<Progress>>,
+}
+
+impl<B: StoreBackend> HatBuilder<B> {
+    /// A repository storing its blobs in `backend` and its local indexes in `cache_dir`.
+    pub fn new(backend: Arc<B>, cache_dir: PathBuf) -> HatBuilder<B> {
+        HatBuilder {
+            backend: backend,
+            cache_dir: cache_dir,
+            migrations_dir: PathBuf::new(),
+            max_blob_size: DEFAULT_MAX_BLOB_SIZE,
+            hash_algorithm: None,
+            passphrase: None,
+            namespace: None,
+            limits: Limits::default(),
+            inline_max: None,
+            progress: None,
+        }
+    }
+
+    /// Read the SQL migrations from `dir` instead of using the built in ones.
+    pub fn migrations_dir(mut self, dir: PathBuf) -> HatBuilder<B> {
+        self.migrations_dir = dir;
+        self
+    }
+
+    pub fn max_blob_size(mut self, size: usize) -> HatBuilder<B> {
+        self.max_blob_size = size;
+        self
+    }
+
+    /// The hash algorithm of a new repository. An existing repository keeps its own.
+    pub fn hash_algorithm(mut self, algorithm: HashAlgorithm) -> HatBuilder<B> {
+        self.hash_algorithm = Some(algorithm);
+        self
+    }
+
+    /// Derive the repository keys from `passphrase`, e.g. as read from a `PassphraseSource`.
+    pub fn passphrase(mut self, passphrase: secstr::SecStr) -> HatBuilder<B> {
+        self.passphrase = Some(passphrase);
+        self
+    }
+
+    /// Keep the families of this machine apart from those of others sharing the repository.
+    pub fn namespace(mut self, namespace: &str) -> HatBuilder<B> {
+        self.namespace = Some(namespace.to_string());
+        self
+    }
+
+    pub fn limits(mut self, limits: Limits) -> HatBuilder<B> {
+        self.limits = limits;
+        self
+    }
+
+    /// Keep files of at most `max` bytes inside their directory listing.
+    pub fn inline_max(mut self, max: usize) -> HatBuilder<B> {
+        self.inline_max = Some(max);
+        self
+    }
+
+    pub fn progress(mut self, progress: Arc<Progress>) -> HatBuilder<B> {
+        self.progress = Some(progress);
+        self
+    }
+
+    /// Open an initialized repository, and finish what an interrupted run of any process left
+    /// behind.
+    pub fn open(self) -> Result<HatRc<B>, HatError> {
+        let hat = HatRc::open_repository(
+            &self.migrations_dir,
+            self.cache_dir.clone(),
+            self.backend.clone(),
+            self.max_blob_size,
+            self.hash_algorithm,
+            self.passphrase.as_ref().map(|p| p.unsecure()),
+        )?;
+        self.configure(hat)
+    }
+
+    /// Create the repository, as `hat init` does, and open it.
+    pub fn init(self) -> Result<HatRc<B>, HatError> {
+        let (hat, _) = HatRc::init_repository(
+            &self.migrations_dir,
+            self.cache_dir.clone(),
+            self.backend.clone(),
+            self.max_blob_size,
+            self.hash_algorithm,
+            self.passphrase.as_ref().map(|p| p.unsecure()),
+        )?;
+        self.configure(hat)
+    }
+
+    fn configure(self, mut hat: HatRc<B>) -> Result<HatRc<B>, HatError> {
+        hat.set_namespace(self.namespace)?;
+        hat.set_limits(self.limits);
+        hat.set_inline_max(self.inline_max);
+        if let Some(progress) = self.progress {
+            hat.set_progress(progress);
+        }
+        Ok(hat)
+    }
+}
+
+/// The outcome of `Hat::commit_dir`.
+#[derive(Clone, Debug)]
+pub struct CommitReport {
+    pub snapshot: SnapshotSummary,
+    /// Files that could not be read and are missing from the snapshot.
+    pub errors: Vec<FileError>,
+}
+
+impl<B: StoreBackend> HatRc<B> {
+    /// Commit a new snapshot of `dir` to `family`, and store it durably.
+    pub fn commit_dir(&mut self, family: &str, dir: &Path) -> Result<CommitReport, HatError> {
+        let name = self.family_name(family);
+        let mut fam = self.open_family(name.clone())?;
+        fam.snapshot_dir(dir.to_owned());
+        fam.flush()?;
+        self.commit(&mut fam, None)?;
+        self.meta_commit()?;
+        self.data_flush()?;
+
+        let snapshot = self.list_snapshots()
+            .into_iter()
+            .filter(|s| s.family == name)
+            .last()
+            .ok_or("Committed snapshot is missing")?;
+        let errors = fam.errors.lock().unwrap().clone();
+        Ok(CommitReport {
+            snapshot: snapshot,
+            errors: errors,
+        })
+    }
+}
@@ -0,0 +1,725 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Consistency checks and repairs across the indexes, the snapshot trees and the backend.
+
+use backend::StoreBackend;
+use blob;
+use chrono;
+use db;
+use errors::HatError;
+use hash;
+use hash::tree::HashTreeBackend;
+use hex::ToHex;
+use rand;
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use std::path::PathBuf;
+use tags;
+
+use super::{HatRc, root, synthetic_roots_family};
+use super::family::parse_dir_data;
+use super::walker::Content;
+
+
+/// The label `Hat::repair` puts on snapshots that lost data.
+pub const DAMAGED_LABEL: &'static str = "damaged";
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProblemKind {
+    /// Referenced, but not where it should be.
+    Missing,
+    /// Present, but its contents are wrong or unreadable.
+    Corrupt,
+    /// Present, but nothing refers to it.
+    Orphaned,
+}
+
+/// An inconsistency found by `Hat::check`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Problem {
+    pub kind: ProblemKind,
+    /// What is affected, e.g. `blob 0a1b...` or `snapshot home/3`.
+    pub object: String,
+    pub detail: String,
+}
+
+/// The outcome of `Hat::check`.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct CheckReport {
+    pub snapshots: u64,
+    pub blobs: u64,
+    /// Nodes of the snapshot trees that were checked.
+    pub chunks: u64,
+    /// Chunks whose data was read back and compared with their hash.
+    pub chunks_read: u64,
+    pub problems: Vec<Problem>,
+}
+
+impl CheckReport {
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+
+    fn add(&mut self, kind: ProblemKind, object: String, detail: String) {
+        warn!("{:?} {}: {}", kind, object, detail);
+        self.problems.push(Problem {
+            kind: kind,
+            object: object,
+            detail: detail,
+        });
+    }
+}
+
+/// The outcome of `Hat::scrub`.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct ScrubReport {
+    /// Blobs that were read back, and their size in the backend.
+    pub blobs: u64,
+    pub bytes: u64,
+    pub chunks_read: u64,
+    /// After this run: the committed blobs that have never been verified, and when the least
+    /// recently verified of the others was.
+    pub never_verified: u64,
+    pub oldest_verified_utc: Option<i64>,
+    pub problems: Vec<Problem>,
+}
+
+impl ScrubReport {
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+
+    fn add(&mut self, kind: ProblemKind, object: String, detail: String) {
+        warn!("{:?} {}: {}", kind, object, detail);
+        self.problems.push(Problem {
+            kind: kind,
+            object: object,
+            detail: detail,
+        });
+    }
+}
+
+/// A snapshot that lost data, as found by `Hat::repair`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DamagedSnapshot {
+    pub family: String,
+    pub snapshot_id: u64,
+    /// The files and directories that can no longer be restored in full, from the snapshot root.
+    pub paths: Vec<PathBuf>,
+}
+
+/// The outcome of `Hat::repair`.
+#[derive(Clone, Debug, Default)]
+pub struct RepairReport {
+    /// Blobs found in the backend and taken back into the blob index.
+    pub blobs_adopted: u64,
+    /// Hash index rows restored or pointed at another copy of their chunk, from the headers of
+    /// the adopted blobs.
+    pub chunks_restored: u64,
+    /// Blobs missing from the backend, now removed from the blob index.
+    pub blobs_lost: u64,
+    /// Hash index rows dropped because their data, or data they refer to, is lost.
+    pub chunks_dropped: u64,
+    pub damaged: Vec<DamagedSnapshot>,
+}
+
+impl<B: StoreBackend> HatRc<B> {
+    /// Cross-check the snapshot index, the root document, the snapshot trees, the hash and
+    /// blob indexes and the blobs in the backend, without changing any of them.
+    ///
+    /// Only the nodes needed to find the files of each snapshot are read; with `read_data`
+    /// set to a fraction between 0 and 1, about that share of all chunks is also read back and
+    /// compared with its hash.
+    pub fn check(&mut self, read_data: Option<f64>) -> Result<CheckReport, HatError> {
+        let mut report = CheckReport::default();
+
+        // Blob index against backend.
+        let (done, lost, orphaned) = self.blob_sets()?;
+        report.blobs = done.len() as u64;
+        for name in &lost {
+            report.add(
+                ProblemKind::Missing,
+                format!("blob {}", name.to_hex()),
+                "in the blob index, but not in the backend".to_string(),
+            );
+        }
+        for name in &orphaned {
+            report.add(
+                ProblemKind::Orphaned,
+                format!("blob {}", name.to_hex()),
+                "in the backend, but not in the blob index".to_string(),
+            );
+        }
+
+        // Hash index against blob index.
+        let hashes = self.db.lock().hash_list();
+        for entry in hashes.iter().filter(|e| e.ready) {
+            if let Some(ref cref) = entry.persistent_ref {
+                if cref.length > 0 && !done.contains(cref.blob_name.as_bytes()) {
+                    report.add(
+                        ProblemKind::Missing,
+                        format!("chunk {}", entry.hash.bytes.to_hex()),
+                        format!(
+                            "its blob {} is not committed",
+                            cref.blob_name.as_bytes().to_hex()
+                        ),
+                    );
+                }
+            }
+        }
+
+        // Snapshot index against root document and snapshot trees.
+        let snapshots: Vec<db::SnapshotStatus> = self.snapshot_index
+            .list_all()
+            .into_iter()
+            .filter(|s| match s.status {
+                db::SnapshotWorkStatus::CommitComplete => true,
+                _ => false,
+            })
+            .collect();
+        if let Some(doc) = root::read_latest(&self.keys, &*self.backend)? {
+            for (family, head) in &doc.heads {
+                let object = format!("snapshot {}/{}", family, head.snapshot_id);
+                match snapshots.iter().find(|s| {
+                    &s.family_name == family && s.info.snapshot_id == head.snapshot_id
+                }) {
+                    None => {
+                        report.add(
+                            ProblemKind::Missing,
+                            object,
+                            "named by the root document, but not in the snapshot index"
+                                .to_string(),
+                        )
+                    }
+                    Some(s) if s.hash.as_ref() != Some(&head.hash_ref.hash) => {
+                        report.add(
+                            ProblemKind::Corrupt,
+                            object,
+                            "its root differs from the one in the root document".to_string(),
+                        )
+                    }
+                    Some(_) => (),
+                }
+            }
+            self.check_tree(doc.meta_ref, "snapshot listing".to_string(), &mut report);
+        }
+
+        let mut seen = HashSet::new();
+        for s in snapshots {
+            report.snapshots += 1;
+            let object = format!("snapshot {}/{}", s.family_name, s.info.snapshot_id);
+            let href = s.hash_ref.as_ref().and_then(|bytes| {
+                hash::tree::HashRef::from_bytes(&mut &bytes[..]).ok()
+            });
+            match href {
+                Some(href) => {
+                    if s.hash.as_ref() != Some(&href.hash) {
+                        report.add(
+                            ProblemKind::Corrupt,
+                            object.clone(),
+                            "its root does not match its tree".to_string(),
+                        );
+                    }
+                    if seen.insert(href.hash.bytes.clone()) {
+                        self.check_tree(href, object, &mut report);
+                    }
+                }
+                None => {
+                    report.add(
+                        ProblemKind::Missing,
+                        object,
+                        "complete, but without a readable tree".to_string(),
+                    )
+                }
+            }
+        }
+
+        // A sample of all chunk data.
+        if let Some(fraction) = read_data {
+            let backend = self.hash_backend().failing_on_mismatch();
+            for entry in hashes {
+                let cref = match entry.persistent_ref {
+                    Some(cref) => cref,
+                    None => continue,
+                };
+                if !entry.ready || cref.length == 0 || rand::random::<f64>() >= fraction {
+                    continue;
+                }
+                let object = format!("chunk {}", entry.hash.bytes.to_hex());
+                let href = hash::tree::HashRef {
+                    hash: entry.hash,
+                    node: entry.node,
+                    leaf: entry.leaf,
+                    persistent_ref: cref,
+                    info: None,
+                };
+                report.chunks_read += 1;
+                match backend.fetch_chunk(&href) {
+                    Ok(Some(_)) => (),
+                    Ok(None) => {
+                        report.add(ProblemKind::Missing, object, "could not be read".to_string())
+                    }
+                    Err(e) => report.add(ProblemKind::Corrupt, object, e.to_string()),
+                }
+            }
+        }
+
+        Ok(report)
+    }
+
+    /// Read back the least recently verified blobs, until about `budget` bytes have been read,
+    /// and compare each of their chunks with its hash. Blobs whose chunks all match are
+    /// marked as verified now, so that repeated runs rotate through the whole repository;
+    /// those with problems are tried again first the next time.
+    pub fn scrub(&mut self, budget: u64) -> Result<ScrubReport, HatError> {
+        let mut report = ScrubReport::default();
+        self.progress.start("Scrubbing", Some(budget));
+
+        let mut chunks: HashMap<Vec<u8>, Vec<hash::tree::HashRef>> = HashMap::new();
+        for entry in self.db.lock().hash_list() {
+            let cref = match entry.persistent_ref {
+                Some(cref) => cref,
+                None => continue,
+            };
+            if !entry.ready || cref.length == 0 {
+                continue;
+            }
+            chunks.entry(cref.blob_name.as_bytes().to_vec()).or_insert_with(Vec::new).push(
+                hash::tree::HashRef {
+                    hash: entry.hash,
+                    node: entry.node,
+                    leaf: entry.leaf,
+                    persistent_ref: cref,
+                    info: None,
+                },
+            );
+        }
+
+        let now = chrono::Utc::now().timestamp();
+        let backend = self.hash_backend().failing_on_mismatch();
+        let mut verified_at = vec![];
+        for (desc, verified) in self.blob_index.list_by_verified() {
+            if report.bytes >= budget {
+                verified_at.push(verified);
+                continue;
+            }
+            let object = format!("blob {}", desc.name.as_bytes().to_hex());
+            let size = match self.backend.size(&desc.name[..])? {
+                Some(size) => size,
+                None => {
+                    report.add(
+                        ProblemKind::Missing,
+                        object,
+                        "in the blob index, but not in the backend".to_string(),
+                    );
+                    verified_at.push(verified);
+                    continue;
+                }
+            };
+            report.blobs += 1;
+            report.bytes += size;
+
+            let mut ok = true;
+            for href in chunks.remove(desc.name.as_bytes()).unwrap_or_default() {
+                report.chunks_read += 1;
+                let chunk = format!("chunk {} in {}", href.hash.bytes.to_hex(), object);
+                match backend.fetch_chunk(&href) {
+                    Ok(Some(_)) => (),
+                    Ok(None) => {
+                        ok = false;
+                        report.add(ProblemKind::Missing, chunk, "could not be read".to_string());
+                    }
+                    Err(e) => {
+                        ok = false;
+                        report.add(ProblemKind::Corrupt, chunk, e.to_string());
+                    }
+                }
+            }
+            self.progress.read(size);
+            if ok {
+                self.blob_index.mark_verified(&desc, now);
+                verified_at.push(Some(now));
+            } else {
+                verified_at.push(verified);
+            }
+        }
+        self.blob_index.flush();
+
+        report.never_verified = verified_at.iter().filter(|v| v.is_none()).count() as u64;
+        report.oldest_verified_utc = verified_at.into_iter().filter_map(|v| v).min();
+        self.progress.finish();
+        Ok(report)
+    }
+
+    /// Bring the indexes back in line with the blobs in the backend, keeping as much of each
+    /// snapshot as can still be read.
+    ///
+    /// Blobs the blob index does not know are adopted, and their headers used to restore hash
+    /// index rows that are missing or that point at lost blobs. Lost blobs are then removed from
+    /// the index, together with the rows of the chunks they held and of every chunk referring to
+    /// those; the next backup therefore stores the affected files again instead of reusing their
+    /// broken trees. Snapshots that refer to lost data are labelled `DAMAGED_LABEL` and reported
+    /// with the paths that can no longer be restored; the rest of each snapshot stays usable.
+    pub fn repair(&mut self) -> Result<RepairReport, HatError> {
+        let mut report = RepairReport::default();
+        let (_, lost, orphaned) = self.blob_sets()?;
+
+        // Adopt orphaned blobs. Blobs that cannot be read are quarantined by `retrieve_refs`.
+        // An adopted blob gets the id it had before, so rows still referring to it are valid
+        // again.
+        let mut adopted = vec![];
+        for name in orphaned {
+            let desc = blob::BlobDesc {
+                name: blob::BlobId::from(name.clone()),
+                id: 0,
+            };
+            let refs = match self.blob_store.retrieve_refs(desc) {
+                Ok(Some(refs)) => refs,
+                Ok(None) => continue,
+                Err(e) => {
+                    warn!("Not adopting blob {}: {}", name.to_hex(), e);
+                    continue;
+                }
+            };
+            let desc = self.blob_store.adopt(blob::BlobId::from(name));
+            report.blobs_adopted += 1;
+            adopted.push((desc, refs));
+        }
+        // Rows of blobs that are gone from both the index and the backend cannot be restored.
+        report.chunks_dropped = self.hash_index.prune() as u64;
+
+        // Rows stored in lost blobs, before any of them are restored.
+        let mut in_lost = vec![];
+        for entry in self.hash_index.list() {
+            if let Some(ref cref) = entry.persistent_ref {
+                if lost.contains(cref.blob_name.as_bytes()) {
+                    in_lost.extend(self.hash_index.get_id(&entry.hash));
+                }
+            }
+        }
+
+        for (desc, refs) in adopted {
+            for mut href in refs {
+                href.persistent_ref.blob_id = Some(desc.id);
+                if self.restore_hash(href, &lost) {
+                    report.chunks_restored += 1;
+                }
+            }
+        }
+        self.hash_index.flush();
+
+        // Drop lost blobs, the rows of their chunks, and every row above those.
+        for name in &lost {
+            if let Some(desc) = self.blob_store.find(name) {
+                self.db.lock().blob_set_tag(tags::Tag::WillDelete, Some(&desc));
+            }
+        }
+        self.db.lock().blob_delete_by_tag(tags::Tag::WillDelete);
+        report.blobs_lost = lost.len() as u64;
+        report.chunks_dropped += self.hash_index.prune() as u64;
+
+        let snapshots: Vec<db::SnapshotStatus> = self.snapshot_index
+            .list_all()
+            .into_iter()
+            .filter(|s| match s.status {
+                db::SnapshotWorkStatus::CommitComplete => true,
+                _ => false,
+            })
+            .collect();
+        // Snapshot roots are kept, so that damaged snapshots can still be deleted.
+        let roots: HashSet<Vec<u8>> =
+            snapshots.iter().filter_map(|s| s.hash.as_ref()).map(|h| h.bytes.clone()).collect();
+        let mut parents: HashMap<u64, Vec<u64>> = HashMap::new();
+        for entry in self.hash_index.list() {
+            if roots.contains(&entry.hash.bytes) {
+                continue;
+            }
+            if let (Some(id), Some(childs)) = (self.hash_index.get_id(&entry.hash), entry.childs) {
+                for child in childs {
+                    parents.entry(child).or_insert_with(Vec::new).push(id);
+                }
+            }
+        }
+        let mut dropped = HashSet::new();
+        while let Some(id) = in_lost.pop() {
+            for &parent in parents.get(&id).map_or(&[][..], |p| &p[..]) {
+                if dropped.insert(parent) {
+                    in_lost.push(parent);
+                }
+            }
+        }
+        for id in dropped {
+            if self.hash_index.get_hash(id).is_some() {
+                self.hash_index.delete(id);
+                report.chunks_dropped += 1;
+            }
+        }
+        self.hash_index.flush();
+
+        // Find what each snapshot lost.
+        for s in snapshots {
+            if s.family_name == synthetic_roots_family() {
+                continue;
+            }
+            let href = match s.hash_ref.as_ref().and_then(|bytes| {
+                hash::tree::HashRef::from_bytes(&mut &bytes[..]).ok()
+            }) {
+                Some(href) => href,
+                None => continue,
+            };
+            let mut paths = vec![];
+            self.damaged_paths(href, PathBuf::from("/"), &lost, &mut paths);
+            if !paths.is_empty() {
+                warn!(
+                    "Snapshot {}/{} lost {} files or directories",
+                    s.family_name,
+                    s.info.snapshot_id,
+                    paths.len()
+                );
+                self.snapshot_index.add_label(&s.info, DAMAGED_LABEL);
+                report.damaged.push(DamagedSnapshot {
+                    family: s.family_name,
+                    snapshot_id: s.info.snapshot_id,
+                    paths: paths,
+                });
+            }
+        }
+        self.flush_snapshot_index();
+
+        Ok(report)
+    }
+
+    /// Restore the hash index row of a chunk found in an adopted blob, if the row is missing or
+    /// points at a lost blob. Returns whether the row was restored.
+    fn restore_hash(&self, href: hash::tree::HashRef, lost: &HashSet<Vec<u8>>) -> bool {
+        if let Some(id) = self.hash_index.get_id(&href.hash) {
+            let stale = self.hash_index
+                .get_hash(id)
+                .and_then(|e| e.persistent_ref)
+                .map_or(true, |r| lost.contains(r.blob_name.as_bytes()));
+            if stale {
+                self.hash_index.relocate(id, href.persistent_ref);
+            }
+            return stale;
+        }
+
+        // A branch can only be indexed with the ids of its children.
+        let childs = match href.node {
+            blob::NodeType::Leaf => None,
+            blob::NodeType::Branch(_) => {
+                let data = match self.blob_store.retrieve(&href) {
+                    Ok(Some(data)) => data,
+                    _ => return false,
+                };
+                let ids: Option<Vec<u64>> = hash::tree::hash_refs_from_bytes(&data[..])
+                    .and_then(|childs| {
+                        childs.iter().map(|c| self.hash_index.get_id(&c.hash)).collect()
+                    });
+                match ids {
+                    Some(ids) => Some(ids),
+                    None => return false,
+                }
+            }
+        };
+        let entry = hash::Entry {
+            hash: href.hash,
+            node: href.node,
+            leaf: href.leaf,
+            childs: childs,
+            persistent_ref: Some(href.persistent_ref),
+        };
+        match self.hash_index.reserve(&entry) {
+            hash::ReserveResult::ReserveOk(id) => {
+                self.hash_index.commit(id, Some(entry));
+                true
+            }
+            hash::ReserveResult::HashKnown(_) => false,
+        }
+    }
+
+    /// Add the paths below directory `dir` whose data refers to lost blobs to `out`. A
+    /// directory whose listing cannot be read is added as a whole.
+    fn damaged_paths(
+        &self,
+        dir: hash::tree::HashRef,
+        path: PathBuf,
+        lost: &HashSet<Vec<u8>>,
+        out: &mut Vec<PathBuf>,
+    ) {
+        let mut files = vec![];
+        let readable = match self.read_tree(dir, lost, true) {
+            Some(chunks) => chunks.iter().all(|c| parse_dir_data(&c[..], &mut files).is_ok()),
+            None => false,
+        };
+        if !readable {
+            out.push(path);
+            return;
+        }
+        for f in files {
+            let path = path.join(OsStr::from_bytes(&f.meta.info.name[..]));
+            match f.hash_ref {
+                Content::Dir(href) => self.damaged_paths(href, path, lost, out),
+                Content::Data(href) => {
+                    if self.read_tree(href, lost, false).is_none() {
+                        out.push(path);
+                    }
+                }
+                Content::Link(_) | Content::Inline(_) => (),
+            }
+        }
+    }
+
+    /// Follow the tree below `top`, reading its branches and, with `read_leafs`, returning its
+    /// leafs in order. Returns nothing if any of its nodes is in a lost blob or unreadable.
+    fn read_tree(
+        &self,
+        top: hash::tree::HashRef,
+        lost: &HashSet<Vec<u8>>,
+        read_leafs: bool,
+    ) -> Option<Vec<Vec<u8>>> {
+        let backend = self.hash_backend().failing_on_mismatch();
+        let mut leafs = vec![];
+        let mut stack = vec![top];
+        while let Some(href) = stack.pop() {
+            if lost.contains(href.persistent_ref.blob_name.as_bytes()) {
+                return None;
+            }
+            match href.node {
+                blob::NodeType::Branch(_) => {
+                    let data = match backend.fetch_chunk(&href) {
+                        Ok(Some(data)) => data,
+                        _ => return None,
+                    };
+                    let mut childs = match hash::tree::hash_refs_from_bytes(&data[..]) {
+                        Some(childs) => childs,
+                        None => return None,
+                    };
+                    childs.reverse();
+                    stack.extend(childs);
+                }
+                blob::NodeType::Leaf if read_leafs => {
+                    match backend.fetch_chunk(&href) {
+                        Ok(Some(data)) => leafs.push(data),
+                        _ => return None,
+                    }
+                }
+                blob::NodeType::Leaf => (),
+            }
+        }
+        Some(leafs)
+    }
+
+    /// The names of the committed blobs, of those among them that are missing from the backend,
+    /// and of the blobs in the backend that the blob index does not know.
+    fn blob_sets(
+        &self,
+    ) -> Result<(HashSet<Vec<u8>>, HashSet<Vec<u8>>, HashSet<Vec<u8>>), HatError> {
+        let names = |tag| -> HashSet<Vec<u8>> {
+            self.blob_store
+                .list_by_tag(tag)
+                .into_iter()
+                .map(|b| b.name.into_bytes())
+                .collect()
+        };
+        let done = names(tags::Tag::Done);
+        let in_progress = names(tags::Tag::InProgress);
+        let stored: HashSet<Vec<u8>> = self.backend
+            .list()?
+            .into_iter()
+            .filter_map(|name| blob::BlobId::new(name.into_vec()).ok())
+            .map(|name| name.into_bytes())
+            .collect();
+
+        let lost = done.difference(&stored).cloned().collect();
+        let orphaned = stored
+            .into_iter()
+            .filter(|n| !done.contains(n) && !in_progress.contains(n))
+            .collect();
+        Ok((done, lost, orphaned))
+    }
+
+    /// Check that every node of the tree below `top` is known, reading the branches and
+    /// directory listings needed to find the rest. Problems are reported against `object`.
+    fn check_tree(&self, top: hash::tree::HashRef, object: String, report: &mut CheckReport) {
+        let backend = self.hash_backend().failing_on_mismatch();
+        let mut stack = vec![top];
+        let mut seen = HashSet::new();
+        while let Some(href) = stack.pop() {
+            if !seen.insert(href.hash.bytes.clone()) {
+                continue;
+            }
+            report.chunks += 1;
+            let chunk = format!("chunk {}", href.hash.bytes.to_hex());
+
+            let is_branch = match href.node {
+                blob::NodeType::Branch(_) => true,
+                blob::NodeType::Leaf => false,
+            };
+            if !is_branch && href.leaf != blob::LeafType::TreeList {
+                if backend.fetch_persistent_ref(&href.hash).is_none() {
+                    report.add(
+                        ProblemKind::Missing,
+                        chunk,
+                        format!("part of {}, but not in the hash index", object),
+                    );
+                }
+                continue;
+            }
+
+            let data = match backend.fetch_chunk(&href) {
+                Ok(Some(data)) => data,
+                Ok(None) => {
+                    report.add(
+                        ProblemKind::Missing,
+                        chunk,
+                        format!("part of {}, but could not be read", object),
+                    );
+                    continue;
+                }
+                Err(e) => {
+                    report.add(ProblemKind::Corrupt, chunk, format!("part of {}: {}", object, e));
+                    continue;
+                }
+            };
+            if is_branch {
+                match hash::tree::hash_refs_from_bytes(&data[..]) {
+                    Some(childs) => stack.extend(childs),
+                    None => {
+                        report.add(
+                            ProblemKind::Corrupt,
+                            chunk,
+                            format!("part of {}, but not a valid tree node", object),
+                        )
+                    }
+                }
+            } else {
+                let mut files = vec![];
+                if let Err(e) = parse_dir_data(&data[..], &mut files) {
+                    report.add(ProblemKind::Corrupt, chunk, format!("part of {}: {}", object, e));
+                    continue;
+                }
+                for f in files {
+                    match f.hash_ref {
+                        Content::Data(h) | Content::Dir(h) => stack.push(h),
+                        Content::Link(_) | Content::Inline(_) => (),
+                    }
+                }
+            }
+        }
+    }
+}
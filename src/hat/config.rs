@@ -0,0 +1,140 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Configuration files, describing repositories and how to back up to them.
+
+use errors::HatError;
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use toml;
+
+use super::{Hooks, Quota, RetentionPolicy};
+
+
+/// The contents of a configuration file, by default `~/.config/hat/config.toml`:
+///
+/// ```toml
+/// default_repository = "home"
+///
+/// [repositories.home]
+/// blob_dir = "/mnt/backup/blobs"
+/// cache_dir = "/var/cache/hat"
+/// exclude = ["*.tmp", "home/*/.cache"]
+///
+/// [repositories.home.retention]
+/// keep_daily = 7
+/// keep_weekly = 4
+///
+/// [repositories.home.quota]
+/// max_bytes = 500_000_000_000
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// The repository to use when none is named. A file with a single repository does not
+    /// need one.
+    pub default_repository: Option<String>,
+    pub repositories: BTreeMap<String, RepositoryConfig>,
+}
+
+/// The settings of one repository. Command line flags and environment variables take
+/// precedence over them.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RepositoryConfig {
+    /// Where the blobs are stored.
+    pub blob_dir: Option<PathBuf>,
+    /// Copies of `blob_dir` to read from when a blob is missing or damaged there.
+    pub replicas: Vec<PathBuf>,
+    /// Where the local indexes are kept.
+    pub cache_dir: Option<PathBuf>,
+    pub namespace: Option<String>,
+    pub append_only: bool,
+    /// Hash algorithm of a new repository.
+    pub hash_algorithm: Option<String>,
+    /// Patterns of paths that are never committed, as for `commit --exclude`.
+    pub exclude: Vec<String>,
+    /// Largest blob to pack chunks into, in bytes.
+    pub max_blob_size: Option<usize>,
+    /// Files of at most this many bytes are kept inside their directory listing.
+    pub inline_max: Option<usize>,
+    /// Children per hash tree node.
+    pub fanout: Option<usize>,
+    /// The policy of `forget`, when none is given on the command line.
+    pub retention: Option<RetentionPolicy>,
+    /// Commands to run before and after commits and checkouts.
+    pub hooks: Hooks,
+    /// Where to read the passphrase from, when it is not given on the command line or in
+    /// `HAT_PASSPHRASE`; see `PassphraseSource`.
+    pub passphrase_file: Option<PathBuf>,
+    pub passphrase_command: Option<String>,
+    /// The size the repository should stay within.
+    pub quota: Option<Quota>,
+    /// How long `gc` keeps unused blobs before deleting them, in hours.
+    pub gc_grace_hours: Option<u64>,
+    /// Limits on the deletes `gc` issues to the backend, as for `gc --deletes_per_sec` and
+    /// `gc --delete_concurrency`.
+    pub gc_deletes_per_sec: Option<u32>,
+    pub gc_delete_concurrency: Option<usize>,
+}
+
+impl Config {
+    /// `$XDG_CONFIG_HOME/hat/config.toml`, or `~/.config/hat/config.toml`.
+    pub fn default_path() -> Option<PathBuf> {
+        env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".config")))
+            .map(|dir| dir.join("hat").join("config.toml"))
+    }
+
+    pub fn parse(text: &str) -> Result<Config, HatError> {
+        toml::from_str(text).map_err(|e| From::from(format!("Invalid configuration: {}", e)))
+    }
+
+    pub fn load(path: &Path) -> Result<Config, HatError> {
+        let mut text = String::new();
+        fs::File::open(path)?.read_to_string(&mut text)?;
+        Config::parse(&text).map_err(|e| From::from(format!("{}: {}", path.display(), e)))
+    }
+
+    /// The repository used when none is named: the configured default, or the only one.
+    pub fn default_name(&self) -> Option<&str> {
+        match self.default_repository {
+            Some(ref name) => Some(&name[..]),
+            None if self.repositories.len() == 1 => self.repositories.keys().next().map(|s| &s[..]),
+            None => None,
+        }
+    }
+
+    /// The settings of the repository called `name`, or of the default repository. Without
+    /// repositories, that is the empty configuration.
+    pub fn repository(&self, name: Option<&str>) -> Result<RepositoryConfig, HatError> {
+        let name = match name.or_else(|| self.default_name()) {
+            Some(name) => name.to_string(),
+            None if self.repositories.is_empty() => return Ok(RepositoryConfig::default()),
+            None => {
+                return Err(From::from(
+                    "Several repositories are configured; name one with --repo",
+                ))
+            }
+        };
+        self.repositories
+            .get(&name)
+            .cloned()
+            .ok_or_else(|| From::from(format!("No repository {} in the configuration", name)))
+    }
+}
@@ -0,0 +1,664 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Differences between two snapshots of a family, and between a snapshot and the files it was
+//! taken from or restored to.
+
+use backend::StoreBackend;
+use errors::HatError;
+use filetime::FileTime;
+use hash;
+use key;
+use std::collections::{BTreeMap, HashSet};
+use std::ffi::OsStr;
+use std::fs;
+use std::io::Read;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use util::{self, Pattern};
+
+use super::{HatRc, RestoreOptions};
+use super::family::Family;
+use super::walker::Content;
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChangeKind {
+    Added,
+    Removed,
+    Modified,
+}
+
+/// A file, link or empty directory that differs between two snapshots.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct FileChange {
+    pub path: PathBuf,
+    #[serde(rename = "change")]
+    pub kind: ChangeKind,
+    pub old_size: Option<u64>,
+    pub new_size: Option<u64>,
+}
+
+/// How the files on disk differ from the latest snapshot, as reported by `Hat::status`.
+#[derive(Clone, Debug)]
+pub struct Status {
+    pub changes: Vec<FileChange>,
+    /// File data that a new snapshot would read, and the chunks it is split into.
+    pub read_bytes: u64,
+    pub chunks: u64,
+    /// The chunks of that data that a new snapshot would have to upload, and their size.
+    pub upload_chunks: u64,
+    pub upload_bytes: u64,
+    /// Changed files that a commit would skip for exceeding a size limit.
+    pub skipped: Vec<PathBuf>,
+}
+
+/// How `Hat::commit_dry_run` walks the files, so that it sees them as the commit it stands in
+/// for would.
+#[derive(Clone, Debug, Default)]
+pub struct ScanOptions {
+    /// Do not descend into directories on other file systems than the one scanned.
+    pub one_file_system: bool,
+    /// Look at what symbolic links point to instead of the links.
+    pub follow_symlinks: bool,
+    /// Leave out the paths matching any of these, matched against absolute paths without the
+    /// leading `/` as by a commit.
+    pub excludes: Vec<Pattern>,
+}
+
+/// The state of a walk of `scan_dir`.
+struct Walk<'a> {
+    options: &'a ScanOptions,
+    device: Option<u64>,
+    /// Directories seen so far by (device, inode), when following symbolic links.
+    seen_dirs: HashSet<(u64, u64)>,
+}
+
+impl<'a> Walk<'a> {
+    fn excluded(&self, live_path: &Path) -> bool {
+        let relative = live_path.strip_prefix("/").unwrap_or(live_path);
+        self.options.excludes.iter().any(|p| p.matches(relative.as_os_str().as_bytes()))
+    }
+
+    /// The metadata of a directory entry as a commit sees it. A link to a directory that has
+    /// been seen already is kept as a link, as it is by a commit.
+    fn metadata(&mut self, entry: &fs::DirEntry) -> Result<fs::Metadata, HatError> {
+        let meta = entry.metadata()?;
+        if !self.options.follow_symlinks {
+            return Ok(meta);
+        }
+        let target = match fs::metadata(entry.path()) {
+            Ok(target) => target,
+            Err(_) => return Ok(meta),
+        };
+        if target.is_dir() && !self.seen_dirs.insert((target.dev(), target.ino())) &&
+            meta.file_type().is_symlink()
+        {
+            return Ok(meta);
+        }
+        Ok(target)
+    }
+}
+
+/// How a restored file or directory differs from the snapshot.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MismatchKind {
+    Missing,
+    /// A file where the snapshot has a directory or link, or the other way around.
+    WrongType,
+    /// The file's data or the link's target differs.
+    Content,
+    /// The snapshot's data could not be read back, or did not match its hash.
+    Unreadable(String),
+    Permissions,
+    ModifiedTime,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Mismatch {
+    pub path: PathBuf,
+    pub kind: MismatchKind,
+}
+
+/// The outcome of `Hat::verify_restore`.
+#[derive(Clone, Debug, Default)]
+pub struct RestoreCheck {
+    /// Files and links compared.
+    pub files: u64,
+    /// File data read back from the snapshot.
+    pub bytes: u64,
+    pub mismatches: Vec<Mismatch>,
+}
+
+impl RestoreCheck {
+    fn report(&mut self, path: &Path, kind: MismatchKind) {
+        self.mismatches.push(Mismatch {
+            path: path.to_owned(),
+            kind: kind,
+        });
+    }
+}
+
+type Listing = BTreeMap<Vec<u8>, (key::Entry, Content)>;
+
+fn same_content(a: &Content, b: &Content) -> bool {
+    match (a, b) {
+        (&Content::Data(ref x), &Content::Data(ref y)) => x.hash == y.hash,
+        (&Content::Dir(ref x), &Content::Dir(ref y)) => x.hash == y.hash,
+        (&Content::Link(ref x), &Content::Link(ref y)) => x == y,
+        (&Content::Inline(ref x), &Content::Inline(ref y)) => x == y,
+        _ => false,
+    }
+}
+
+fn size(entry: &key::Entry, content: &Content) -> Option<u64> {
+    match *content {
+        Content::Inline(ref bytes) => Some(bytes.len() as u64),
+        _ => entry.info.byte_length,
+    }
+}
+
+impl<B: StoreBackend> HatRc<B> {
+    /// List what changed from snapshot `from` to snapshot `to` of a family, in path order.
+    ///
+    /// Subtrees with the same hash are skipped without being read, so the cost depends on
+    /// the size of the change rather than the size of the snapshots.
+    pub fn diff_snapshots(
+        &mut self,
+        family_name: &str,
+        from: u64,
+        to: u64,
+    ) -> Result<Vec<FileChange>, HatError> {
+        let top = |hat: &mut Self, id: u64| match hat.snapshot_index.lookup(family_name, id) {
+            Some((_, _, Some(href))) => Ok(href),
+            _ => Err(HatError::from(format!(
+                "No complete snapshot {} in family {}",
+                id,
+                family_name
+            ))),
+        };
+        let from_ref = top(self, from)?;
+        let to_ref = top(self, to)?;
+        let family = self.open_family(family_name.to_string())?;
+
+        let mut changes = vec![];
+        self.diff_dirs(
+            &family,
+            PathBuf::new(),
+            Some(from_ref),
+            Some(to_ref),
+            &mut changes,
+        )?;
+        Ok(changes)
+    }
+
+    /// Compare the files below `dir` with the latest snapshot of a family. Snapshots record
+    /// absolute paths, so `dir` is looked up in the snapshot by its canonical path.
+    ///
+    /// Files whose size and modification time match the snapshot are taken to be unchanged,
+    /// as they are by a commit. Changed and new files are read to find how much of their data
+    /// is not stored yet.
+    pub fn status(&mut self, family_name: &str, dir: &Path) -> Result<Status, HatError> {
+        self.scan_dir(family_name, dir, &ScanOptions::default())
+    }
+
+    /// Report what a commit of `dir` to a family would read and upload, without storing
+    /// anything: `status`, following the family's limits and walking the files with the
+    /// `options` of that commit.
+    pub fn commit_dry_run(
+        &mut self,
+        family_name: &str,
+        dir: &Path,
+        options: &ScanOptions,
+    ) -> Result<Status, HatError> {
+        self.scan_dir(family_name, dir, options)
+    }
+
+    fn scan_dir(
+        &mut self,
+        family_name: &str,
+        dir: &Path,
+        options: &ScanOptions,
+    ) -> Result<Status, HatError> {
+        let mut top = match self.snapshot_index.latest(family_name) {
+            Some((_, _, Some(href))) => Some(href),
+            _ => None,
+        };
+        let family = self.open_family(family_name.to_string())?;
+
+        let dir = fs::canonicalize(dir)?;
+        for name in dir.iter().filter(|p| !Path::new(p).has_root()) {
+            top = match self.listing(&family, top)?.remove(name.as_bytes()) {
+                Some((_, Content::Dir(href))) => Some(href),
+                _ => None,
+            };
+        }
+
+        let top_meta = fs::metadata(&dir)?;
+        let mut walk = Walk {
+            options: options,
+            device: if options.one_file_system { Some(top_meta.dev()) } else { None },
+            seen_dirs: HashSet::new(),
+        };
+        walk.seen_dirs.insert((top_meta.dev(), top_meta.ino()));
+        let mut status = Status {
+            changes: vec![],
+            read_bytes: 0,
+            chunks: 0,
+            upload_chunks: 0,
+            upload_bytes: 0,
+            skipped: vec![],
+        };
+        self.status_dir(&family, &dir, PathBuf::new(), top, &mut walk, &mut status)?;
+        Ok(status)
+    }
+
+    /// Check that a checkout of a snapshot to `output` with `options` would leave what is
+    /// there already, without writing anything. Every chunk of the restored files is read back
+    /// and checked against its hash, so a clean result also shows that the snapshot can be
+    /// restored. The latest snapshot is checked if no id is given.
+    ///
+    /// Metadata is compared as far as the family's fidelity level restores it. Files that
+    /// are present but not in the snapshot are not reported.
+    pub fn verify_restore(
+        &mut self,
+        family_name: &str,
+        snapshot_id: Option<u64>,
+        output: &Path,
+        options: &RestoreOptions,
+    ) -> Result<RestoreCheck, HatError> {
+        let (_, dir_ref) = self.complete_snapshot(family_name, snapshot_id)?;
+        let family = self.open_family(family_name.to_string())?;
+
+        let mut check = RestoreCheck::default();
+        match options.subpath {
+            Some(ref subpath) => {
+                let item = self.lookup_path(&family, dir_ref, subpath)?;
+                self.verify_entry(&family, output, item, options, &mut check)?;
+            }
+            None => {
+                for res in family.iter_dir_data(dir_ref, self.hash_backend())? {
+                    let (entry, content) = res?;
+                    let path = PathBuf::from(OsStr::from_bytes(&entry.info.name[..]));
+                    let item = (path, entry, content);
+                    self.verify_entry(&family, output, item, options, &mut check)?;
+                }
+            }
+        }
+        Ok(check)
+    }
+
+    fn verify_entry(
+        &self,
+        family: &Family<B>,
+        output: &Path,
+        (path, entry, content): (PathBuf, key::Entry, Content),
+        options: &RestoreOptions,
+        check: &mut RestoreCheck,
+    ) -> Result<(), HatError> {
+        let out = output.join(&path);
+        let meta = fs::symlink_metadata(&out).ok();
+        match content {
+            Content::Dir(href) => {
+                if !options.wants_dir(&path) {
+                    return Ok(());
+                }
+                if meta.as_ref().map_or(false, |m| !m.is_dir()) {
+                    check.report(&path, MismatchKind::WrongType);
+                    return Ok(());
+                }
+                let before = check.mismatches.len();
+                for res in family.iter_dir_data(href, self.hash_backend())? {
+                    let (entry, content) = res?;
+                    let child = path.join(OsStr::from_bytes(&entry.info.name[..]));
+                    self.verify_entry(family, output, (child, entry, content), options, check)?;
+                }
+                match meta {
+                    Some(meta) => compare_metadata(&path, &meta, &entry, family.fidelity, check),
+                    // With filters, directories with nothing to restore are not created.
+                    None if check.mismatches.len() > before || options.is_filtered() => (),
+                    None => check.report(&path, MismatchKind::Missing),
+                }
+                return Ok(());
+            }
+            Content::Link(ref target) => {
+                if !options.wants_file(&path) {
+                    return Ok(());
+                }
+                check.files += 1;
+                match meta {
+                    None => check.report(&path, MismatchKind::Missing),
+                    Some(ref m) if !m.file_type().is_symlink() => {
+                        check.report(&path, MismatchKind::WrongType)
+                    }
+                    Some(ref m) => {
+                        if fs::read_link(&out).ok().as_ref() != Some(target) {
+                            check.report(&path, MismatchKind::Content);
+                        }
+                        // Links only have their modification time restored.
+                        let m_secs = FileTime::from_last_modification_time(m)
+                            .seconds_relative_to_1970();
+                        if family.fidelity >= key::Fidelity::Permissions &&
+                            entry.info.modified_ts_secs.map_or(false, |t| t != m_secs)
+                        {
+                            check.report(&path, MismatchKind::ModifiedTime);
+                        }
+                    }
+                }
+                return Ok(());
+            }
+            _ => (),
+        }
+
+        if !options.wants_file(&path) {
+            return Ok(());
+        }
+        check.files += 1;
+        let file: Option<Box<Read>> = match (meta.as_ref(), entry.info.sparse_map.as_ref()) {
+            // Only the data extents of a sparse file are stored.
+            (Some(m), Some(extents)) if m.is_file() => {
+                Some(Box::new(util::ExtentReader::new(fs::File::open(&out)?, extents.clone())))
+            }
+            (Some(m), None) if m.is_file() => Some(Box::new(fs::File::open(&out)?)),
+            _ => None,
+        };
+        // The snapshot's data is read back even when there is nothing to compare it to.
+        match self.compare_data(content, file, check) {
+            Err(e) => check.report(&path, MismatchKind::Unreadable(e.to_string())),
+            Ok(_) if meta.is_none() => check.report(&path, MismatchKind::Missing),
+            Ok(_) if !meta.as_ref().unwrap().is_file() => {
+                check.report(&path, MismatchKind::WrongType)
+            }
+            Ok(false) => check.report(&path, MismatchKind::Content),
+            Ok(true) => {
+                compare_metadata(&path, meta.as_ref().unwrap(), &entry, family.fidelity, check)
+            }
+        }
+        Ok(())
+    }
+
+    /// Read back the data of a file and compare it with `file`, if given.
+    fn compare_data(
+        &self,
+        content: Content,
+        file: Option<Box<Read>>,
+        check: &mut RestoreCheck,
+    ) -> Result<bool, HatError> {
+        let mut file = file;
+        let mut same = file.is_some();
+        {
+            let mut compare = |chunk: &[u8]| {
+                check.bytes += chunk.len() as u64;
+                if let (true, Some(fd)) = (same, file.as_mut()) {
+                    let mut buf = vec![0; chunk.len()];
+                    same = fd.read_exact(&mut buf).is_ok() && &buf[..] == chunk;
+                }
+            };
+            match content {
+                Content::Inline(bytes) => compare(&bytes[..]),
+                Content::Data(href) => {
+                    let backend = self.hash_backend().failing_on_mismatch();
+                    if let Some(mut leafs) = hash::tree::LeafIterator::new(backend, href)? {
+                        while let Some(chunk) = leafs.try_next()? {
+                            compare(&chunk[..]);
+                        }
+                    }
+                }
+                _ => unreachable!("directories and links are compared by the caller"),
+            }
+        }
+        // The file must not be longer than the snapshot's data, either.
+        match file {
+            Some(mut fd) if same => Ok(fd.read(&mut [0u8])? == 0),
+            _ => Ok(same),
+        }
+    }
+
+    fn status_dir(
+        &self,
+        family: &Family<B>,
+        live_dir: &Path,
+        dir: PathBuf,
+        snapshot_ref: Option<hash::tree::HashRef>,
+        walk: &mut Walk,
+        status: &mut Status,
+    ) -> Result<(), HatError> {
+        let snapshot = self.listing(family, snapshot_ref)?;
+        let mut live = BTreeMap::new();
+        for entry in fs::read_dir(live_dir)? {
+            let entry = entry?;
+            if walk.excluded(&entry.path()) {
+                // A commit leaves it out, as if it was not there.
+                continue;
+            }
+            live.insert(entry.file_name().as_bytes().to_vec(), walk.metadata(&entry)?);
+        }
+        let device = walk.device;
+
+        let mut names: Vec<&Vec<u8>> = snapshot.keys().chain(live.keys()).collect();
+        names.sort();
+        names.dedup();
+        for name in names {
+            let path = dir.join(OsStr::from_bytes(&name[..]));
+            let live_path = live_dir.join(OsStr::from_bytes(&name[..]));
+            match (snapshot.get(name), live.get(name)) {
+                (_, Some(meta)) if meta.is_dir() && device.map_or(false, |d| meta.dev() != d) => {
+                    // A commit does not descend into other file systems.
+                }
+                (Some(&(_, Content::Dir(ref href))), Some(meta)) if meta.is_dir() => {
+                    let href = Some(href.clone());
+                    self.status_dir(family, &live_path, path, href, walk, status)?;
+                }
+                (Some(&(ref e, ref c)), Some(meta)) if !is_dir(c) && !meta.is_dir() => {
+                    let unchanged = match *c {
+                        Content::Link(ref target) => {
+                            fs::read_link(&live_path).ok().as_ref() == Some(target)
+                        }
+                        _ => {
+                            let mtime = FileTime::from_last_modification_time(meta)
+                                .seconds_relative_to_1970();
+                            e.info.modified_ts_secs == Some(mtime) &&
+                                size(e, c) == Some(meta.len())
+                        }
+                    };
+                    if !unchanged {
+                        status.changes.push(FileChange {
+                            path: path.clone(),
+                            kind: ChangeKind::Modified,
+                            old_size: size(e, c),
+                            new_size: Some(meta.len()),
+                        });
+                        self.scan_file(family, &live_path, path, meta, status)?;
+                    }
+                }
+                (old, meta) => {
+                    if let Some(&(ref e, ref c)) = old {
+                        let removed = ChangeKind::Removed;
+                        self.one_sided(family, path.clone(), e, c, removed, &mut status.changes)?;
+                    }
+                    if let Some(meta) = meta {
+                        if meta.is_dir() {
+                            let before = status.changes.len();
+                            let path = path.clone();
+                            self.status_dir(family, &live_path, path, None, walk, status)?;
+                            if status.changes.len() > before {
+                                continue;
+                            }
+                        }
+                        status.changes.push(FileChange {
+                            path: path.clone(),
+                            kind: ChangeKind::Added,
+                            old_size: None,
+                            new_size: if meta.is_dir() { None } else { Some(meta.len()) },
+                        });
+                        self.scan_file(family, &live_path, path, meta, status)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Count the data of a changed file as a commit would read it, unless a limit skips it.
+    fn scan_file(
+        &self,
+        family: &Family<B>,
+        live_path: &Path,
+        path: PathBuf,
+        meta: &fs::Metadata,
+        status: &mut Status,
+    ) -> Result<(), HatError> {
+        if !meta.is_file() {
+            return Ok(());
+        }
+        if self.limits.max_file_size.map_or(false, |max| meta.len() > max) {
+            status.skipped.push(path);
+            return Ok(());
+        }
+        let scan = family.key_store.scan_chunks(live_path)?;
+        // Only new data counts against the commit budget, as in a commit.
+        let over_budget = self.limits
+            .max_commit_bytes
+            .map_or(false, |max| status.upload_bytes + scan.new_bytes > max);
+        if over_budget {
+            status.skipped.push(path);
+            return Ok(());
+        }
+        status.read_bytes += meta.len();
+        status.chunks += scan.chunks;
+        status.upload_chunks += scan.new_chunks;
+        status.upload_bytes += scan.new_bytes;
+        Ok(())
+    }
+
+    fn listing(
+        &self,
+        family: &Family<B>,
+        dir_ref: Option<hash::tree::HashRef>,
+    ) -> Result<Listing, HatError> {
+        let mut listing = BTreeMap::new();
+        if let Some(dir_ref) = dir_ref {
+            for res in family.iter_dir_data(dir_ref, self.hash_backend())? {
+                let (entry, content) = res?;
+                listing.insert(entry.info.name.clone(), (entry, content));
+            }
+        }
+        Ok(listing)
+    }
+
+    fn diff_dirs(
+        &self,
+        family: &Family<B>,
+        dir: PathBuf,
+        old_ref: Option<hash::tree::HashRef>,
+        new_ref: Option<hash::tree::HashRef>,
+        changes: &mut Vec<FileChange>,
+    ) -> Result<(), HatError> {
+        let old = self.listing(family, old_ref)?;
+        let new = self.listing(family, new_ref)?;
+        let mut names: Vec<&Vec<u8>> = old.keys().chain(new.keys()).collect();
+        names.sort();
+        names.dedup();
+        for name in names {
+            let path = dir.join(OsStr::from_bytes(&name[..]));
+            match (old.get(name), new.get(name)) {
+                (Some(&(_, ref o)), Some(&(_, ref n))) if same_content(o, n) => (),
+                (Some(&(_, Content::Dir(ref o))), Some(&(_, Content::Dir(ref n)))) => {
+                    self.diff_dirs(family, path, Some(o.clone()), Some(n.clone()), changes)?;
+                }
+                (Some(&(ref oe, ref o)), Some(&(ref ne, ref n))) if !is_dir(o) && !is_dir(n) => {
+                    changes.push(FileChange {
+                        path: path,
+                        kind: ChangeKind::Modified,
+                        old_size: size(oe, o),
+                        new_size: size(ne, n),
+                    });
+                }
+                (o, n) => {
+                    if let Some(&(ref oe, ref o)) = o {
+                        self.one_sided(family, path.clone(), oe, o, ChangeKind::Removed, changes)?;
+                    }
+                    if let Some(&(ref ne, ref n)) = n {
+                        self.one_sided(family, path, ne, n, ChangeKind::Added, changes)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Report an entry that exists in only one of the snapshots, with everything below it.
+    fn one_sided(
+        &self,
+        family: &Family<B>,
+        path: PathBuf,
+        entry: &key::Entry,
+        content: &Content,
+        kind: ChangeKind,
+        changes: &mut Vec<FileChange>,
+    ) -> Result<(), HatError> {
+        if let Content::Dir(ref href) = *content {
+            let before = changes.len();
+            let (old_ref, new_ref) = match kind {
+                ChangeKind::Removed => (Some(href.clone()), None),
+                _ => (None, Some(href.clone())),
+            };
+            self.diff_dirs(family, path.clone(), old_ref, new_ref, changes)?;
+            if changes.len() > before {
+                return Ok(());
+            }
+            // Empty directories are reported themselves.
+        }
+        let size = size(entry, content);
+        changes.push(FileChange {
+            path: path,
+            kind: kind,
+            old_size: if kind == ChangeKind::Removed { size } else { None },
+            new_size: if kind == ChangeKind::Added { size } else { None },
+        });
+        Ok(())
+    }
+}
+
+/// Compare the metadata that `restore_metadata` would restore.
+fn compare_metadata(
+    path: &Path,
+    meta: &fs::Metadata,
+    entry: &key::Entry,
+    fidelity: key::Fidelity,
+    check: &mut RestoreCheck,
+) {
+    if fidelity < key::Fidelity::Permissions {
+        return;
+    }
+    if let Some(ref perms) = entry.info.permissions {
+        if perms.mode() & 0o7777 != meta.permissions().mode() & 0o7777 {
+            check.report(path, MismatchKind::Permissions);
+        }
+    }
+    if let Some(m) = entry.info.modified_ts_secs {
+        if FileTime::from_last_modification_time(meta).seconds_relative_to_1970() != m {
+            check.report(path, MismatchKind::ModifiedTime);
+        }
+    }
+}
+
+fn is_dir(content: &Content) -> bool {
+    match *content {
+        Content::Dir(_) => true,
+        _ => false,
+    }
+}
@@ -0,0 +1,125 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A stream of JSON objects, one per line, describing what a long-running operation does.
+
+use chrono;
+use serde_json;
+use std::io::Write;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use util::Progress;
+
+
+/// Something that happened during a commit, checkout or gc.
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    /// An operation starts, expecting to process `total_bytes` if known.
+    Started {
+        operation: String,
+        total_bytes: Option<u64>,
+    },
+    /// A file is being processed.
+    File { path: String },
+    /// A chunk of new data was handed to the backend.
+    ChunkUploaded { bytes: u64 },
+    /// The operation is done.
+    Finished,
+    /// A new snapshot of `family` was committed.
+    SnapshotCommitted { family: String, snapshot_id: u64 },
+    /// Something went wrong, for `path` if given.
+    Error {
+        path: Option<String>,
+        error: String,
+    },
+}
+
+#[derive(Serialize)]
+struct Line<'a> {
+    time: chrono::DateTime<chrono::Utc>,
+    #[serde(flatten)]
+    event: &'a Event,
+}
+
+/// Writes every event as a line of JSON, for wrappers and GUIs to follow an operation.
+///
+/// It is used as the `Progress` of a `Hat`, and passes every event on to `inner`. Events that
+/// the library does not see, like a committed snapshot, are written with `emit`.
+pub struct EventLog {
+    inner: Arc<Progress>,
+    out: Mutex<Box<Write + Send>>,
+}
+
+impl EventLog {
+    pub fn new(out: Box<Write + Send>, inner: Arc<Progress>) -> EventLog {
+        EventLog {
+            inner: inner,
+            out: Mutex::new(out),
+        }
+    }
+
+    /// Write `event` as one line. Failing to write does not fail the operation.
+    pub fn emit(&self, event: &Event) {
+        let line = Line {
+            time: chrono::Utc::now(),
+            event: event,
+        };
+        let mut out = self.out.lock().unwrap();
+        let res = serde_json::to_writer(&mut *out, &line)
+            .map_err(|e| e.to_string())
+            .and_then(|()| out.write_all(b"\n").map_err(|e| e.to_string()))
+            .and_then(|()| out.flush().map_err(|e| e.to_string()));
+        if let Err(e) = res {
+            warn!("Could not write event: {}", e);
+        }
+    }
+}
+
+impl Progress for EventLog {
+    fn start(&self, operation: &str, total_bytes: Option<u64>) {
+        self.emit(&Event::Started {
+            operation: operation.to_string(),
+            total_bytes: total_bytes,
+        });
+        self.inner.start(operation, total_bytes);
+    }
+
+    fn file(&self, path: &Path) {
+        self.emit(&Event::File { path: path.to_string_lossy().into_owned() });
+        self.inner.file(path);
+    }
+
+    fn read(&self, bytes: u64) {
+        self.inner.read(bytes);
+    }
+
+    fn uploaded(&self, bytes: u64) {
+        self.emit(&Event::ChunkUploaded { bytes: bytes });
+        self.inner.uploaded(bytes);
+    }
+
+    fn stored(&self, bytes: u64) {
+        self.inner.stored(bytes);
+    }
+
+    fn deduped(&self, bytes: u64) {
+        self.inner.deduped(bytes);
+    }
+
+    fn finish(&self) {
+        self.emit(&Event::Finished);
+        self.inner.finish();
+    }
+}
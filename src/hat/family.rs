@@ -16,18 +16,24 @@
 use backend::StoreBackend;
 use blob;
 use capnp;
+use db;
 use errors::HatError;
 use hash;
 use hat::insert_path_handler::InsertPathHandler;
 use hat::walker;
 use key;
 use root_capnp;
+use std::collections::{HashMap, VecDeque};
+use std::ffi::OsStr;
 use std::fs;
-use std::io::Write;
-use std::path::PathBuf;
-use std::str;
-use util::{FileIterator, FnBox, PathHandler};
-use filetime;
+use std::io::{Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use time;
+use util::{FileIterator, PathHandler, Pattern, Progress};
 
 fn try_a_few_times_then_panic<F>(mut f: F, msg: &str)
 where
@@ -168,7 +174,58 @@ pub mod recover {
     }
 }
 
-fn parse_dir_data(chunk: &[u8], mut out: &mut Vec<walker::FileEntry>) -> Result<(), HatError> {
+pub mod verify {
+    use hash::tree;
+    use hat::walker;
+
+    /// Reads every chunk of every file, so that the hash tree backend checks each of them.
+    pub struct FileVisitor {
+        pub chunks: u64,
+        pub bytes: u64,
+    }
+
+    impl FileVisitor {
+        pub fn new() -> FileVisitor {
+            FileVisitor {
+                chunks: 0,
+                bytes: 0,
+            }
+        }
+    }
+
+    impl tree::Visitor for FileVisitor {
+        fn branch_enter(&mut self, _href: &tree::HashRef, _childs: &Vec<tree::HashRef>) -> bool {
+            self.chunks += 1;
+            true
+        }
+        fn leaf_leave(&mut self, chunk: Vec<u8>, _href: &tree::HashRef) -> bool {
+            self.chunks += 1;
+            self.bytes += chunk.len() as u64;
+            false
+        }
+    }
+
+    impl walker::LikesFiles for FileVisitor {
+        fn include_file(&mut self, file: &walker::FileEntry) -> bool {
+            match file.hash_ref {
+                walker::Content::Data(_) => true,
+                _ => false,
+            }
+        }
+
+        fn include_dir(&mut self, file: &walker::FileEntry) -> bool {
+            match file.hash_ref {
+                walker::Content::Dir(_) => true,
+                _ => false,
+            }
+        }
+    }
+}
+
+pub fn parse_dir_data(
+    chunk: &[u8],
+    mut out: &mut Vec<walker::FileEntry>,
+) -> Result<(), HatError> {
     if chunk.is_empty() {
         return Ok(());
     }
@@ -207,12 +264,18 @@ fn parse_dir_data(chunk: &[u8], mut out: &mut Vec<walker::FileEntry>) -> Result<
                 )
             }
             root_capnp::file::content::SymbolicLink(path) => {
-                let link = PathBuf::from(String::from_utf8(path?.to_owned()).unwrap());
+                let link = PathBuf::from(OsStr::from_bytes(path?));
                 (
                     key::Data::Symlink(link.clone()),
                     walker::Content::Link(link),
                 )
             }
+            root_capnp::file::content::Inline(bytes) => {
+                (
+                    key::Data::FilePlaceholder,
+                    walker::Content::Inline(bytes?.to_vec()),
+                )
+            }
         };
 
         let entry = key::Entry {
@@ -220,6 +283,7 @@ fn parse_dir_data(chunk: &[u8], mut out: &mut Vec<walker::FileEntry>) -> Result<
             data: data,
             parent_id: None,
             node_id: Some(f.get_id()),
+            stamp: None,
         };
 
         out.push(walker::FileEntry {
@@ -230,24 +294,129 @@ fn parse_dir_data(chunk: &[u8], mut out: &mut Vec<walker::FileEntry>) -> Result<
     Ok(())
 }
 
+/// Streams the entries of a directory in a snapshot, decoding one listing chunk at a time.
+pub struct DirIterator<HTB> {
+    chunks: Option<hash::tree::LeafIterator<HTB>>,
+    pending: VecDeque<walker::FileEntry>,
+}
+
+impl<HTB: hash::tree::HashTreeBackend<Err = key::MsgError>> Iterator for DirIterator<HTB> {
+    type Item = Result<(key::Entry, walker::Content), HatError>;
+
+    fn next(&mut self) -> Option<Result<(key::Entry, walker::Content), HatError>> {
+        loop {
+            if let Some(f) = self.pending.pop_front() {
+                return Some(Ok((f.meta, f.hash_ref)));
+            }
+            let chunk = match self.chunks.as_mut().and_then(|it| it.next()) {
+                Some(chunk) => chunk,
+                None => return None,
+            };
+            let mut out = Vec::new();
+            if let Err(e) = parse_dir_data(&chunk[..], &mut out) {
+                return Some(Err(e));
+            }
+            self.pending.extend(out);
+        }
+    }
+}
+
+/// A file that could not be read while taking a snapshot. The snapshot goes on without it, or
+/// with what could be read of it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FileError {
+    pub path: PathBuf,
+    pub error: String,
+}
+
 pub struct Family<B> {
     pub name: String,
+    pub fidelity: key::Fidelity,
     pub key_store: key::Store<B>,
     pub key_store_process: Vec<key::StoreProcess<FileIterator, B>>,
+    /// Recorded with the snapshots committed from this family handle.
+    pub provenance: Option<db::Provenance>,
+    /// Attached to the snapshots committed from this family handle.
+    pub labels: Vec<String>,
+    /// How often `snapshot_dir` makes its progress durable, so that a snapshot that is
+    /// interrupted can be resumed by running it again. `None` only flushes at the end.
+    pub checkpoint_interval: Option<time::Duration>,
+    /// Keep `snapshot_dir` on the file system of the directory being snapshot, skipping the
+    /// contents of mount points below it.
+    pub one_file_system: bool,
+    /// Have `snapshot_dir` keep what symbolic links point to instead of the links.
+    pub follow_symlinks: bool,
+    /// Have `snapshot_dir` read files without crowding out other programs; see
+    /// `util::limit_read_ahead`.
+    pub low_impact: bool,
+    /// Paths left out by `snapshot_dir` and `snapshot_paths`, as absolute paths without the
+    /// leading `/`.
+    pub excludes: Vec<Pattern>,
+    /// Files that could not be read by `snapshot_dir` and `snapshot_paths`; shared by clones.
+    pub errors: Arc<Mutex<Vec<FileError>>>,
+    /// Told about the files handled by `snapshot_dir` and `snapshot_paths`.
+    pub progress: Arc<Progress>,
 }
 impl<B: StoreBackend> Clone for Family<B> {
     fn clone(&self) -> Family<B> {
         Family {
             name: self.name.clone(),
+            fidelity: self.fidelity,
             key_store: self.key_store.clone(),
             key_store_process: self.key_store_process.clone(),
+            provenance: self.provenance.clone(),
+            labels: self.labels.clone(),
+            checkpoint_interval: self.checkpoint_interval,
+            one_file_system: self.one_file_system,
+            follow_symlinks: self.follow_symlinks,
+            low_impact: self.low_impact,
+            excludes: self.excludes.clone(),
+            errors: self.errors.clone(),
+            progress: self.progress.clone(),
         }
     }
 }
 
+/// Update the indexes of several families at once, each from its own directory, with a thread
+/// per source. The families share the blob store pipeline and the backend, so that their uploads
+/// overlap, while each keeps its own index. A family may be given more than one directory.
+pub fn snapshot_dirs<B: StoreBackend>(sources: Vec<(Family<B>, PathBuf)>) -> Result<(), HatError> {
+    let threads: Vec<_> = sources
+        .into_iter()
+        .map(|(family, dir)| {
+            let source = format!("{} ({})", family.name, dir.display());
+            (source, thread::spawn(move || family.snapshot_dir(dir)))
+        })
+        .collect();
+
+    let mut failed = vec![];
+    for (source, thread) in threads {
+        if thread.join().is_err() {
+            failed.push(source);
+        }
+    }
+    if failed.is_empty() {
+        Ok(())
+    } else {
+        Err(From::from(format!("Failed to snapshot {}", failed.join(", "))))
+    }
+}
+
 impl<B: StoreBackend> Family<B> {
     pub fn snapshot_dir(&self, dir: PathBuf) {
-        let handler = InsertPathHandler::new(self.key_store_process.clone());
+        let mut handler = InsertPathHandler::new(self.key_store_process.clone())
+            .with_errors(self.errors.clone())
+            .with_progress(self.progress.clone())
+            .with_excludes(self.excludes.clone());
+        if let Some(interval) = self.checkpoint_interval {
+            handler = handler.with_checkpoints(interval, self.key_store_process.clone());
+        }
+        if self.follow_symlinks {
+            handler = handler.with_follow_symlinks();
+        }
+        if self.low_impact {
+            handler = handler.with_low_impact();
+        }
 
         let mut parent_path = PathBuf::from("/");
 
@@ -280,6 +449,10 @@ impl<B: StoreBackend> Family<B> {
         }
 
         if !bailout && dir.is_dir() {
+            if self.one_file_system {
+                // The directories above may well be on other file systems; only check below.
+                handler = handler.with_one_file_system(fs::metadata(&dir).unwrap().dev());
+            }
             handler.recurse(PathBuf::from(&dir), parent);
 
             match self.key_store_process[0].send_reply(
@@ -298,6 +471,85 @@ impl<B: StoreBackend> Family<B> {
         }
     }
 
+    /// Snapshot exactly the files, directories and links in `paths`, with the directories above
+    /// them, as for a `find` listing. Directories in `paths` are not read; only what is listed is
+    /// kept, and everything else is dropped from the index. With `one_file_system`, paths below
+    /// a directory on another file system than the first path are left out.
+    pub fn snapshot_paths(&self, paths: Vec<PathBuf>) -> Result<(), HatError> {
+        let mut handler = InsertPathHandler::new(self.key_store_process.clone())
+            .with_errors(self.errors.clone())
+            .with_progress(self.progress.clone())
+            .with_excludes(self.excludes.clone());
+        if let Some(interval) = self.checkpoint_interval {
+            handler = handler.with_checkpoints(interval, self.key_store_process.clone());
+        }
+        if self.follow_symlinks {
+            handler = handler.with_follow_symlinks();
+        }
+        if self.low_impact {
+            handler = handler.with_low_impact();
+        }
+        let mut device = None;
+
+        // Directory ids by path, for those handled so far.
+        let mut dirs: HashMap<PathBuf, Option<u64>> = HashMap::new();
+        for path in paths {
+            // Resolve the directory above the path, but not the path itself: a listed link is
+            // kept as a link.
+            let path = match (path.parent(), path.file_name()) {
+                (Some(parent), Some(name)) => {
+                    let parent = if parent == Path::new("") {
+                        Path::new(".")
+                    } else {
+                        parent
+                    };
+                    match fs::canonicalize(parent) {
+                        Ok(parent) => parent.join(name),
+                        Err(e) => {
+                            warn!("Skipping '{}': {}", path.display(), e);
+                            self.errors.lock().unwrap().push(FileError {
+                                path: path.clone(),
+                                error: e.to_string(),
+                            });
+                            continue;
+                        }
+                    }
+                }
+                _ => fs::canonicalize(&path)?,
+            };
+            if self.one_file_system && device.is_none() {
+                if let Ok(meta) = fs::symlink_metadata(&path) {
+                    device = Some(meta.dev());
+                    handler = handler.with_one_file_system(meta.dev());
+                }
+            }
+
+            let mut parent = None;
+            let mut current = PathBuf::from("/");
+            for name in path.iter().filter(|n| !Path::new(n).has_root()) {
+                current.push(name);
+                parent = match dirs.get(&current).cloned() {
+                    Some(id) => id,
+                    None => {
+                        match handler.handle_path(&parent, &current) {
+                            Some(id) => {
+                                dirs.insert(current.clone(), id);
+                                id
+                            }
+                            // A file or link, or a path that could not be read.
+                            None => break,
+                        }
+                    }
+                };
+            }
+        }
+
+        match self.key_store_process[0].send_reply(key::Msg::CommitReservedNodes(Some(None)))? {
+            key::Reply::Ok => Ok(()),
+            _ => Err(From::from("Unexpected reply from key store")),
+        }
+    }
+
     pub fn snapshot_direct(
         &self,
         file: key::Entry,
@@ -307,11 +559,18 @@ impl<B: StoreBackend> Family<B> {
         let f = if is_directory {
             None
         } else {
-            Some(Box::new(move |()| contents) as Box<FnBox<(), _>>)
+            // The contents can only be read once, and from the start.
+            let contents = Mutex::new(contents);
+            Some(Box::new(move |offset: u64| if offset == 0 {
+                contents.lock().unwrap().take()
+            } else {
+                None
+            }) as key::Opener<_>)
         };
         let ks = self.key_store_process.iter().last().unwrap();
         let id = match ks.send_reply(key::Msg::Insert(file, f))? {
             key::Reply::Id(id) => id,
+            key::Reply::Skipped => return Err(From::from("File exceeds configured limits")),
             _ => return Err(From::from("Unexpected reply from key store")),
         };
         match ks.send_reply(key::Msg::CommitReservedNodes(None)) {
@@ -321,6 +580,28 @@ impl<B: StoreBackend> Family<B> {
         Ok(id)
     }
 
+    /// Read a stream, such as a database dump piped into hat, into the family's index as a
+    /// single file called `name`. Everything else is dropped from the index, so that the next
+    /// commit holds just the stream.
+    pub fn snapshot_stream<R: Read + Send + 'static>(
+        &self,
+        name: Vec<u8>,
+        stream: R,
+    ) -> Result<u64, HatError> {
+        // Without a modification time the stream is never taken to be unchanged, so it is always
+        // read in full. Its chunks are still deduplicated.
+        let entry = key::Entry::new(None, name, key::Data::FilePlaceholder, None);
+        let id = self.snapshot_direct(
+            entry,
+            false,
+            Some(FileIterator::from_reader(Box::new(stream))),
+        )?;
+        match self.key_store_process[0].send_reply(key::Msg::CommitReservedNodes(Some(None)))? {
+            key::Reply::Ok => Ok(id),
+            _ => Err(From::from("Unexpected reply from key store")),
+        }
+    }
+
     pub fn flush(&self) -> Result<(), HatError> {
         for ks in &self.key_store_process {
             if let key::Reply::FlushOk = ks.send_reply(key::Msg::Flush)? {
@@ -331,11 +612,14 @@ impl<B: StoreBackend> Family<B> {
         Ok(())
     }
 
-    pub fn write_file_chunks<HTB: hash::tree::HashTreeBackend<Err = key::MsgError>>(
+    pub fn write_file_chunks<HTB, W>(
         &self,
-        fd: &mut fs::File,
+        fd: &mut W,
         tree: hash::tree::LeafIterator<HTB>,
-    ) {
+    ) where
+        HTB: hash::tree::HashTreeBackend<Err = key::MsgError>,
+        W: Write,
+    {
         for chunk in tree {
             try_a_few_times_then_panic(
                 || fd.write_all(&chunk[..]).is_ok(),
@@ -355,7 +639,7 @@ impl<B: StoreBackend> Family<B> {
         let mut path = output_dir;
         for (entry, _ref, read_fn_opt) in self.list_from_key_store(dir_id)? {
             // Extend directory with filename:
-            path.push(str::from_utf8(&entry.info.name[..]).unwrap());
+            path.push(OsStr::from_bytes(&entry.info.name[..]));
 
             match entry.data {
                 key::Data::DirPlaceholder => {
@@ -364,11 +648,21 @@ impl<B: StoreBackend> Family<B> {
                     self.checkout_in_dir(path.clone(), entry.node_id)?;
                 }
                 key::Data::FilePlaceholder => {
-                    // This is a file, write it
-                    let mut fd = fs::File::create(&path).unwrap();
+                    // This is a file, write it; a sparse file gets its holes back.
+                    let mut fd = super::restore_file(&path, &entry.info)?;
                     if let Some(tree) = read_fn_opt.expect("File has data").init()? {
                         self.write_file_chunks(&mut fd, tree);
                     }
+                    super::finish_file(fd, &entry.info)?;
+                }
+                key::Data::FileInline(sealed) => {
+                    let bytes = self.key_store.unseal_inline(&sealed)?;
+                    let mut fd = super::restore_file(&path, &entry.info)?;
+                    try_a_few_times_then_panic(
+                        || fd.write_all(&bytes[..]).is_ok(),
+                        "Could not write inline data.",
+                    );
+                    super::finish_file(fd, &entry.info)?;
                 }
                 key::Data::Symlink(link_path) => {
                     use std::os::unix::fs::symlink;
@@ -377,15 +671,12 @@ impl<B: StoreBackend> Family<B> {
                 _ => unreachable!("Unexpected data entry"),
             }
 
-            if let Some(perms) = entry.info.permissions {
-                fs::set_permissions(&path, perms)?;
-            }
-
-            if let (Some(m), Some(a)) = (entry.info.modified_ts_secs, entry.info.accessed_ts_secs) {
-                let atime = filetime::FileTime::from_seconds_since_1970(a, 0 /* nanos */);
-                let mtime = filetime::FileTime::from_seconds_since_1970(m, 0 /* nanos */);
-                filetime::set_file_times(&path, atime, mtime).unwrap();
-            }
+            super::restore_metadata(
+                &path,
+                &entry.info,
+                self.fidelity,
+                &super::RestoreOptions::default(),
+            )?;
 
             // Prepare for next filename:
             path.pop();
@@ -411,29 +702,36 @@ impl<B: StoreBackend> Family<B> {
         dir_hash: hash::tree::HashRef,
         backend: HTB,
     ) -> Result<Vec<(key::Entry, walker::Content)>, HatError> {
-        let it = hash::tree::LeafIterator::new(backend, dir_hash)?.expect(
-            "unable to open dir",
-        );
-
-        let mut out = Vec::new();
-        for chunk in it {
-            if !chunk.is_empty() {
-                parse_dir_data(&chunk[..], &mut out)?;
-            }
-        }
+        self.iter_dir_data(dir_hash, backend)?.collect()
+    }
 
-        Ok(out.into_iter().map(|f| (f.meta, f.hash_ref)).collect())
+    /// Like `fetch_dir_data`, but without holding the whole listing in memory.
+    pub fn iter_dir_data<HTB: hash::tree::HashTreeBackend<Err = key::MsgError>>(
+        &self,
+        dir_hash: hash::tree::HashRef,
+        backend: HTB,
+    ) -> Result<DirIterator<HTB>, HatError> {
+        Ok(DirIterator {
+            chunks: hash::tree::LeafIterator::new(backend, dir_hash)?,
+            pending: VecDeque::new(),
+        })
     }
 
-    pub fn commit<F>(&mut self, top_hash_fn: &F) -> Result<hash::tree::HashRef, HatError>
+    /// Write the snapshot's directory trees. Returns the top reference and a count of the
+    /// files in the snapshot.
+    pub fn commit<F>(
+        &mut self,
+        top_hash_fn: &F,
+    ) -> Result<(hash::tree::HashRef, db::SnapshotContents), HatError>
     where
         F: Fn(&hash::Hash),
     {
         let mut top_tree = self.key_store.hash_tree_writer(blob::LeafType::TreeList);
-        self.commit_to_tree(&mut top_tree, None, top_hash_fn)?;
+        let mut contents = db::SnapshotContents::default();
+        self.commit_to_tree(&mut top_tree, None, top_hash_fn, &mut contents)?;
 
         let info = key::Info::new(self.name.clone().into_bytes(), None);
-        Ok(top_tree.hash(Some(&info))?)
+        Ok((top_tree.hash(Some(&info))?, contents))
     }
 
     pub fn commit_to_tree<F>(
@@ -441,6 +739,7 @@ impl<B: StoreBackend> Family<B> {
         tree: &mut hash::tree::SimpleHashTreeWriter<key::HashStoreBackend<B>>,
         dir_id: Option<u64>,
         top_hash_fn: &F,
+        contents: &mut db::SnapshotContents,
     ) -> Result<(), HatError>
     where
         F: Fn(&hash::Hash),
@@ -488,6 +787,9 @@ impl<B: StoreBackend> Family<B> {
                                 hash_ref_root.as_reader(),
                             )?;
 
+                            contents.files += 1;
+                            contents.bytes += entry.info.byte_length.unwrap_or(0);
+
                             top_hash_fn(&hash::Hash { bytes: href.hash.bytes });
                         }
                         key::Data::DirPlaceholder => {
@@ -498,6 +800,7 @@ impl<B: StoreBackend> Family<B> {
                                 &mut inner_tree,
                                 entry.node_id,
                                 top_hash_fn,
+                                contents,
                             )?;
                             // Store a reference for the sub-tree in our tree:
                             let dir_hash_ref = inner_tree.hash(Some(&entry.info))?;
@@ -515,12 +818,18 @@ impl<B: StoreBackend> Family<B> {
 
                             top_hash_fn(&dir_hash_ref.hash);
                         }
+                        key::Data::FileInline(sealed) => {
+                            // This is a small file, store its contents directly:
+                            let bytes = self.key_store.unseal_inline(&sealed)?;
+                            file_msg.borrow().init_content().set_inline(&bytes[..]);
+
+                            contents.files += 1;
+                            contents.bytes += bytes.len() as u64;
+                        }
                         key::Data::Symlink(path) => {
                             // Set symbolic link content.
                             file_msg.borrow().init_content().set_symbolic_link(
-                                path.to_str()
-                                    .unwrap()
-                                    .as_ref(),
+                                path.as_os_str().as_bytes(),
                             );
                         }
                         _ => unreachable!("Unexpected key::Data"),
@@ -0,0 +1,219 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! What garbage collection reclaimed, or would reclaim, found without deleting anything.
+
+use backend::StoreBackend;
+use blob;
+use db;
+use errors::HatError;
+use std::collections::{HashMap, HashSet};
+use std::sync::mpsc;
+use tags;
+use time;
+
+use super::{HatRc, synthetic_roots_family};
+
+
+/// What a garbage collection run did, as returned by `Hat::gc` and its variants.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct GcReport {
+    /// Complete snapshots, whose data was kept.
+    pub snapshots: u64,
+    /// Chunks and tree nodes removed from the hash index.
+    pub hashes_deleted: u64,
+    /// Blobs deleted from the backend, and the space they took there.
+    pub blobs_deleted: u64,
+    pub bytes_reclaimed: u64,
+    /// Unused blobs left in place until their grace period is over (see
+    /// `Hat::set_gc_grace_period`).
+    pub blobs_condemned: u64,
+    /// Chunks and tree nodes still stored in a blob.
+    pub live_chunks: u64,
+    /// Key index rows dropped because their data was deleted.
+    pub key_rows_pruned: u64,
+    /// How long each phase took, in the order they ran.
+    pub phases: Vec<GcPhase>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct GcPhase {
+    pub name: &'static str,
+    pub millis: u64,
+}
+
+/// A phase of garbage collection named `name` that started at `start` and has just ended.
+pub fn gc_phase(name: &'static str, start: time::SteadyTime) -> GcPhase {
+    GcPhase {
+        name: name,
+        millis: (time::SteadyTime::now() - start).num_milliseconds() as u64,
+    }
+}
+
+/// What `gc` would reclaim, as estimated by `Hat::gc_estimate`.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct GcEstimate {
+    /// Chunks and tree nodes that no snapshot uses, and their stored size.
+    pub unused_hashes: u64,
+    pub unused_bytes: u64,
+    /// Blobs that hold nothing but unused chunks, which are deleted, and their size in the
+    /// backend. Unused chunks in other blobs keep taking space.
+    pub blobs: u64,
+    pub blob_bytes: u64,
+    /// The snapshots keeping the most data alive that no other snapshot uses, largest first.
+    pub pinned: Vec<PinnedData>,
+}
+
+/// Data that only one snapshot uses, and that becomes garbage when it is forgotten.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct PinnedData {
+    pub family: String,
+    pub snapshot_id: u64,
+    pub bytes: u64,
+}
+
+/// Where the reference counts and a walk of all snapshots disagree about what is unused, as
+/// found by `Hat::gc_cross_check`. `gc --mark_sweep` repairs both kinds.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct GcDrift {
+    /// Hashes that no snapshot reaches, but that the counts keep.
+    pub leaked: u64,
+    /// Hashes that a snapshot reaches, but that the counts would let `gc` delete.
+    pub endangered: u64,
+}
+
+impl GcDrift {
+    pub fn is_ok(&self) -> bool {
+        self.leaked == 0 && self.endangered == 0
+    }
+}
+
+impl<B: StoreBackend> HatRc<B> {
+    /// Compare what the reference counts consider unused with what a walk of all snapshots
+    /// does not reach. Nothing is modified.
+    pub fn gc_cross_check(&mut self) -> Result<GcDrift, HatError> {
+        self.progress.start("Checking reference counts", None);
+        let roots = self.snapshot_roots()?;
+        let live = self.mark_live(&roots);
+
+        let (sender, receiver) = mpsc::channel();
+        self.gc.list_unused_ids(sender)?;
+        let unused: HashSet<u64> = receiver.iter().collect();
+
+        let mut drift = GcDrift::default();
+        for id in self.hash_index.list_ids() {
+            match (live.contains(&id), unused.contains(&id)) {
+                (false, false) => drift.leaked += 1,
+                (true, true) => drift.endangered += 1,
+                _ => (),
+            }
+        }
+        if !drift.is_ok() {
+            warn!("Reference counts disagree with the snapshots: {:?}", drift);
+        }
+
+        self.progress.finish();
+        Ok(drift)
+    }
+
+    /// Run the mark phase of `gc` and report what it would delete, along with the `top`
+    /// snapshots that pin the most data of their own. Nothing is modified.
+    pub fn gc_estimate(&mut self, top: usize) -> Result<GcEstimate, HatError> {
+        self.progress.start("Estimating garbage", None);
+        let mut estimate = GcEstimate::default();
+
+        let (sender, receiver) = mpsc::channel();
+        self.gc.list_unused_ids(sender)?;
+        let mut unused_chunks: HashMap<blob::BlobId, u64> = HashMap::new();
+        for id in receiver.iter() {
+            estimate.unused_hashes += 1;
+            if let Some(pref) = self.hash_index.get_hash(id).and_then(|e| e.persistent_ref) {
+                estimate.unused_bytes += pref.length as u64;
+                *unused_chunks.entry(pref.blob_name).or_insert(0) += 1;
+            }
+        }
+
+        // A blob goes when all of its chunks do; blobs without any chunks go as well.
+        let mut chunks: HashMap<blob::BlobId, u64> = HashMap::new();
+        for entry in self.hash_index.list() {
+            if let Some(pref) = entry.persistent_ref {
+                *chunks.entry(pref.blob_name).or_insert(0) += 1;
+            }
+        }
+        for blob in self.blob_store.list_by_tag(tags::Tag::Done) {
+            let total = chunks.get(&blob.name).cloned().unwrap_or(0);
+            if unused_chunks.get(&blob.name).cloned().unwrap_or(0) == total {
+                estimate.blobs += 1;
+                estimate.blob_bytes += self.blob_length(&blob)?;
+            }
+        }
+
+        // Count the snapshots reaching each hash; those reached once are pinned by that one.
+        let snapshots: Vec<db::SnapshotStatus> = self.snapshot_index
+            .list_all()
+            .into_iter()
+            .filter(|s| match s.status {
+                db::SnapshotWorkStatus::CommitComplete => true,
+                _ => false,
+            })
+            .filter(|s| s.family_name != synthetic_roots_family())
+            .collect();
+        let mut reached: HashMap<u64, (usize, u64)> = HashMap::new();
+        let mut owner: HashMap<u64, usize> = HashMap::new();
+        for (i, s) in snapshots.iter().enumerate() {
+            let root = match s.hash.as_ref().and_then(|h| self.hash_index.get_id(h)) {
+                Some(id) => id,
+                None => continue,
+            };
+            let mut seen = HashSet::new();
+            let mut todo = vec![root];
+            while let Some(id) = todo.pop() {
+                if !seen.insert(id) {
+                    continue;
+                }
+                if let Some(entry) = self.hash_index.get_hash(id) {
+                    let bytes = entry.persistent_ref.map_or(0, |p| p.length as u64);
+                    reached.entry(id).or_insert((0, bytes)).0 += 1;
+                    owner.insert(id, i);
+                    self.progress.read(bytes);
+                    todo.extend(entry.childs.unwrap_or_default());
+                }
+            }
+        }
+        let mut pinned = vec![0; snapshots.len()];
+        for (id, &(count, bytes)) in &reached {
+            if count == 1 {
+                pinned[owner[id]] += bytes;
+            }
+        }
+        let mut pinned: Vec<PinnedData> = snapshots
+            .iter()
+            .zip(pinned)
+            .filter(|&(_, bytes)| bytes > 0)
+            .map(|(s, bytes)| {
+                PinnedData {
+                    family: s.family_name.clone(),
+                    snapshot_id: s.info.snapshot_id,
+                    bytes: bytes,
+                }
+            })
+            .collect();
+        pinned.sort_by(|a, b| b.bytes.cmp(&a.bytes));
+        pinned.truncate(top);
+        estimate.pinned = pinned;
+
+        self.progress.finish();
+        Ok(estimate)
+    }
+}
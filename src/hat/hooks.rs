@@ -0,0 +1,96 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! External commands that run before and after backups and restores.
+
+use errors::HatError;
+use std::process::Command;
+
+
+/// The moments at which a hook can run.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Hook {
+    /// Before a commit reads any files; e.g. to quiesce a database. Failing aborts the commit.
+    PreCommit,
+    /// After a commit has been stored; e.g. to thaw the database again.
+    PostCommit,
+    /// Before a checkout writes any files. Failing aborts the checkout.
+    PreRestore,
+    /// When a commit or checkout fails or is incomplete.
+    OnError,
+}
+
+impl Hook {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Hook::PreCommit => "pre_commit",
+            Hook::PostCommit => "post_commit",
+            Hook::PreRestore => "pre_restore",
+            Hook::OnError => "on_error",
+        }
+    }
+}
+
+/// Shell commands to run at each `Hook`, as configured in the `hooks` table of a repository:
+///
+/// ```toml
+/// [repositories.home.hooks]
+/// pre_commit = "pg_ctl stop -D /var/lib/postgres"
+/// post_commit = "pg_ctl start -D /var/lib/postgres"
+/// ```
+///
+/// Commands run with `sh -c`. They are told what is happening through the environment:
+/// `HAT_HOOK` is the name of the hook, and e.g. `HAT_FAMILY`, `HAT_PATH`, `HAT_SNAPSHOT_ID`
+/// and `HAT_ERROR` are set when known. A commit of several families lists them one per line.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Hooks {
+    pub pre_commit: Option<String>,
+    pub post_commit: Option<String>,
+    pub pre_restore: Option<String>,
+    pub on_error: Option<String>,
+}
+
+impl Hooks {
+    fn command(&self, hook: Hook) -> Option<&String> {
+        match hook {
+            Hook::PreCommit => self.pre_commit.as_ref(),
+            Hook::PostCommit => self.post_commit.as_ref(),
+            Hook::PreRestore => self.pre_restore.as_ref(),
+            Hook::OnError => self.on_error.as_ref(),
+        }
+    }
+
+    /// Run the command of `hook`, if one is configured, with `env` added to its environment.
+    /// It fails if the command cannot be started or exits unsuccessfully.
+    pub fn run(&self, hook: Hook, env: &[(&str, String)]) -> Result<(), HatError> {
+        let command = match self.command(hook) {
+            Some(command) => command,
+            None => return Ok(()),
+        };
+        info!("Running {} hook: {}", hook.name(), command);
+
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c").arg(command).env("HAT_HOOK", hook.name());
+        for &(key, ref value) in env {
+            cmd.env(key, value);
+        }
+        let status = cmd.status()?;
+        if status.success() {
+            Ok(())
+        } else {
+            Err(From::from(format!("The {} hook failed: {}", hook.name(), status)))
+        }
+    }
+}
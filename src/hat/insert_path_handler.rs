@@ -14,32 +14,45 @@
 
 
 use backend::StoreBackend;
+use hat::family::FileError;
 use key;
+use std::collections::HashSet;
 use std::error::Error;
 use std::fs;
-use std::io;
+use std::io::{self, Read};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
 use std::path::PathBuf;
-use std::str;
-use std::sync::{Mutex, atomic};
+use std::sync::{Arc, Mutex};
 use time;
-use util::{FileIterator, PathHandler, SyncPool};
+use util::{self, FileIterator, PathHandler, Pattern, PeriodicTimer, Progress, SilentProgress,
+           SyncPool};
 
 struct FileEntry {
     key_entry: key::Entry,
     metadata: fs::Metadata,
     full_path: PathBuf,
+    /// The path is a symbolic link, and the entry is what it points to.
+    followed: bool,
 }
 
 impl FileEntry {
-    fn new(full_path: PathBuf, parent: Option<u64>) -> Result<FileEntry, Box<Error>> {
+    fn new(full_path: PathBuf, parent: Option<u64>, follow: bool) -> Result<FileEntry, Box<Error>> {
         debug!("FileEntry::new({:?})", full_path);
 
-        let filename_opt = full_path.file_name().and_then(|n| n.to_str()).map(|s| {
-            s.as_bytes().to_vec()
-        });
+        // Names are kept as raw bytes, whether or not they are valid UTF-8.
+        let filename_opt = full_path.file_name().map(|n| n.as_bytes().to_vec());
 
         if let Some(filename) = filename_opt {
-            let meta = fs::symlink_metadata(&full_path)?;
+            let mut meta = fs::symlink_metadata(&full_path)?;
+            let mut followed = false;
+            if follow && meta.file_type().is_symlink() {
+                // A dangling link is kept as a link.
+                if let Ok(target) = fs::metadata(&full_path) {
+                    meta = target;
+                    followed = true;
+                }
+            }
             let data = if meta.is_file() {
                 key::Data::FilePlaceholder
             } else if meta.is_dir() {
@@ -51,10 +64,25 @@ impl FileEntry {
                 // Unsupported file type. Skipping.
                 return Err(From::from(format!("unknown file kind")));
             };
+            let mut key_entry = key::Entry::new(parent, filename, data, Some(&meta));
+            if let Err(e) = key_entry.info.read_xattrs(&full_path) {
+                // E.g. the file system does not support extended attributes.
+                debug!("No extended attributes for {:?}: {}", full_path, e);
+            }
+            if util::looks_sparse(&meta) {
+                let extents = util::data_extents(&fs::File::open(&full_path)?, meta.len())?;
+                if util::data_length(&extents) < meta.len() {
+                    key_entry.info.sparse_map = Some(extents);
+                }
+            }
+            if meta.is_file() {
+                key_entry = key_entry.with_stamp(key::FileStamp::new(&full_path, &meta));
+            }
             Ok(FileEntry {
-                key_entry: key::Entry::new(parent, filename, data, Some(&meta)),
+                key_entry: key_entry,
                 metadata: meta,
                 full_path: full_path,
+                followed: followed,
             })
         } else {
             Err(From::from("Could not parse filename."[..].to_owned()))
@@ -67,20 +95,164 @@ impl FileEntry {
     fn is_directory(&self) -> bool {
         self.metadata.is_dir()
     }
+    fn device(&self) -> u64 {
+        self.metadata.dev()
+    }
+}
+
+fn record_error(errors: &Mutex<Vec<FileError>>, path: &PathBuf, error: String) {
+    errors.lock().unwrap().push(FileError {
+        path: path.clone(),
+        error: error,
+    });
+}
+
+/// Records the first error reading a file. The key store keeps what was read before it, and has
+/// the next commit read the file again.
+struct RecordReadErrors {
+    inner: FileIterator,
+    path: PathBuf,
+    errors: Option<Arc<Mutex<Vec<FileError>>>>,
+}
+
+impl Read for RecordReadErrors {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let res = self.inner.read(buf);
+        if let Err(ref e) = res {
+            if e.kind() != io::ErrorKind::Interrupted {
+                if let Some(errors) = self.errors.take() {
+                    warn!("Stopped reading '{}': {}", self.path.display(), e);
+                    record_error(&errors, &self.path, format!("stopped reading: {}", e));
+                }
+            }
+        }
+        res
+    }
+}
+
+/// Periodically flushes every key store, so that the blobs, hashes and index entries written
+/// so far survive if the snapshot is interrupted. A later snapshot of the same directory then
+/// finds the files that were already stored unchanged in the key index and skips them.
+struct Checkpoints<B: StoreBackend> {
+    timer: PeriodicTimer,
+    key_stores: Vec<key::StoreProcess<FileIterator, B>>,
+}
+
+impl<B: StoreBackend> Checkpoints<B> {
+    fn maybe_flush(&mut self) {
+        if !self.timer.did_fire() {
+            return;
+        }
+        debug!("Checkpoint: flushing key stores");
+        for ks in &self.key_stores {
+            match ks.send_reply(key::Msg::Flush) {
+                Ok(key::Reply::FlushOk) => (),
+                Err(e) => panic!("Error from key store: {:?}", e),
+                _ => panic!("Unexpected reply from key store."),
+            }
+        }
+    }
 }
 
 pub struct InsertPathHandler<B: StoreBackend> {
-    count: atomic::AtomicIsize,
-    last_print: Mutex<time::Timespec>,
+    progress: Arc<Progress>,
     key_store: SyncPool<key::StoreProcess<FileIterator, B>>,
+    checkpoints: Option<Mutex<Checkpoints<B>>>,
+    device: Option<u64>,
+    excludes: Vec<Pattern>,
+    low_impact: bool,
+    /// Directories seen so far by (device, inode), when following symbolic links.
+    seen_dirs: Option<Mutex<HashSet<(u64, u64)>>>,
+    errors: Arc<Mutex<Vec<FileError>>>,
 }
 
 impl<B: StoreBackend> InsertPathHandler<B> {
     pub fn new(key_stores: Vec<key::StoreProcess<FileIterator, B>>) -> InsertPathHandler<B> {
         InsertPathHandler {
-            count: atomic::AtomicIsize::new(0),
-            last_print: Mutex::new(time::now().to_timespec()),
+            progress: Arc::new(SilentProgress),
             key_store: SyncPool::new(key_stores),
+            checkpoints: None,
+            device: None,
+            excludes: vec![],
+            low_impact: false,
+            seen_dirs: None,
+            errors: Arc::new(Mutex::new(vec![])),
+        }
+    }
+
+    /// Record the files that cannot be read in `errors`, in addition to reporting them.
+    pub fn with_errors(mut self, errors: Arc<Mutex<Vec<FileError>>>) -> InsertPathHandler<B> {
+        self.errors = errors;
+        self
+    }
+
+    /// Report each path to `progress` as it is handled.
+    pub fn with_progress(mut self, progress: Arc<Progress>) -> InsertPathHandler<B> {
+        self.progress = progress;
+        self
+    }
+
+    /// Leave out files and directories matching any of `excludes`, which are matched against
+    /// absolute paths without the leading `/`, as paths are in a snapshot.
+    pub fn with_excludes(mut self, excludes: Vec<Pattern>) -> InsertPathHandler<B> {
+        self.excludes = excludes;
+        self
+    }
+
+    /// Read files without crowding out the page cache and read-ahead of other programs.
+    pub fn with_low_impact(mut self) -> InsertPathHandler<B> {
+        self.low_impact = true;
+        self
+    }
+
+    /// Snapshot what symbolic links point to instead of the links. A link to a directory that
+    /// has already been seen is kept as a link, so that link cycles are not followed forever.
+    pub fn with_follow_symlinks(mut self) -> InsertPathHandler<B> {
+        self.seen_dirs = Some(Mutex::new(HashSet::new()));
+        self
+    }
+
+    fn file_entry(&self, path: &PathBuf, parent: Option<u64>) -> Result<FileEntry, Box<Error>> {
+        let seen_dirs = match self.seen_dirs {
+            Some(ref seen_dirs) => seen_dirs,
+            None => return FileEntry::new(path.clone(), parent, false),
+        };
+        let entry = FileEntry::new(path.clone(), parent, true)?;
+        if entry.is_directory() {
+            let id = (entry.device(), entry.metadata.ino());
+            if !seen_dirs.lock().unwrap().insert(id) && entry.followed {
+                return FileEntry::new(path.clone(), parent, false);
+            }
+        }
+        Ok(entry)
+    }
+
+    /// Do not descend into directories on other devices than `device`, such as mount points of
+    /// other file systems. The directories themselves are kept, empty.
+    pub fn with_one_file_system(mut self, device: u64) -> InsertPathHandler<B> {
+        self.device = Some(device);
+        self
+    }
+
+    /// Flush `key_stores` at most once per `interval` while paths are handled.
+    pub fn with_checkpoints(
+        mut self,
+        interval: time::Duration,
+        key_stores: Vec<key::StoreProcess<FileIterator, B>>,
+    ) -> InsertPathHandler<B> {
+        self.checkpoints = Some(Mutex::new(Checkpoints {
+            timer: PeriodicTimer::new(interval),
+            key_stores: key_stores,
+        }));
+        self
+    }
+
+    fn maybe_checkpoint(&self) {
+        if let Some(ref checkpoints) = self.checkpoints {
+            // If another thread is already flushing, there is no need to wait for it.
+            if let Ok(mut checkpoints) = checkpoints.try_lock() {
+                checkpoints.maybe_flush();
+            }
         }
     }
 }
@@ -94,39 +266,69 @@ impl<B: StoreBackend> PathHandler<Option<u64>> for InsertPathHandler<B> {
     }
 
     fn handle_path(&self, parent: &Option<u64>, path: &PathBuf) -> Option<Option<u64>> {
-        let count = self.count.fetch_add(1, atomic::Ordering::SeqCst) + 1;
-
-        if count % 16 == 0 {
-            // don't hammer the mutex
-            let mut guarded_last_print = self.last_print.lock().unwrap();
-            let now = time::now().to_timespec();
-            if guarded_last_print.sec <= now.sec - 1 {
-                println!("#{}: {}", count, path.display());
-                *guarded_last_print = now;
-            }
+        let relative = path.strip_prefix("/").unwrap_or(path);
+        if self.excludes.iter().any(|p| p.matches(relative.as_os_str().as_bytes())) {
+            debug!("Excluding '{}'", path.display());
+            return None;
         }
+        let res = self.insert_path(parent, path);
+        self.maybe_checkpoint();
+        res
+    }
+}
+
+impl<B: StoreBackend> InsertPathHandler<B> {
+    fn insert_path(&self, parent: &Option<u64>, path: &PathBuf) -> Option<Option<u64>> {
+        self.progress.file(path);
 
-        match FileEntry::new(path.clone(), *parent) {
+        match self.file_entry(path, *parent) {
             Err(e) => {
-                println!("Skipping '{}': {}", path.display(), e);
+                warn!("Skipping '{}': {}", path.display(), e);
+                record_error(&self.errors, path, e.to_string());
             }
             Ok(file_entry) => {
                 let is_file = file_entry.is_file();
                 let is_directory = file_entry.is_directory();
+                let other_device = self.device.map_or(false, |dev| dev != file_entry.device());
                 let local_root = path.clone();
                 let full_path = file_entry.full_path.clone();
+                let sparse_map = file_entry.key_entry.info.sparse_map.clone();
+                let errors = self.errors.clone();
+                let low_impact = self.low_impact;
 
                 let ks = self.key_store.lock().unwrap();
                 match ks.send_reply(key::Msg::Insert(
                     file_entry.key_entry,
                     if is_file {
-                        Some(Box::new(move |()| {
-                        match FileIterator::new(&full_path) {
+                        Some(Box::new(move |offset: u64| {
+                        let it = match sparse_map {
+                            Some(ref extents) if offset == 0 => {
+                                FileIterator::sparse(&full_path, extents.clone())
+                            }
+                            Some(_) => Err(io::Error::new(
+                                io::ErrorKind::Other,
+                                "cannot resume a sparse file",
+                            )),
+                            None if low_impact => {
+                                FileIterator::new(&full_path)
+                                    .and_then(|it| it.skip_to(offset))
+                                    .map(FileIterator::with_low_impact)
+                            }
+                            None => FileIterator::new(&full_path).and_then(|it| it.skip_to(offset)),
+                        };
+                        match it {
                             Err(e) => {
-                                println!("Skipping '{}': {}", local_root.display(), e.to_string());
+                                warn!("Skipping '{}': {}", local_root.display(), e.to_string());
+                                record_error(&errors, &local_root, e.to_string());
                                 None
                             }
-                            Ok(it) => Some(it),
+                            Ok(it) => {
+                                Some(FileIterator::from_reader(Box::new(RecordReadErrors {
+                                    inner: it,
+                                    path: local_root.clone(),
+                                    errors: Some(errors.clone()),
+                                })))
+                            }
                         }
                     }))
                     } else {
@@ -134,10 +336,13 @@ impl<B: StoreBackend> PathHandler<Option<u64>> for InsertPathHandler<B> {
                     },
                 )) {
                     Ok(key::Reply::Id(id)) => {
-                        if is_directory {
+                        if is_directory && other_device {
+                            info!("Not descending into '{}': other file system", path.display());
+                        } else if is_directory {
                             return Some(Some(id));
                         }
                     }
+                    Ok(key::Reply::Skipped) => (),
                     Err(e) => panic!("Error from key store: {:?}", e),
                     _ => panic!("Unexpected reply from key store."),
                 }
@@ -0,0 +1,110 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! User-defined snapshot labels, and selecting snapshots by id or label.
+
+use backend::StoreBackend;
+use db;
+use errors::HatError;
+
+use super::HatRc;
+
+
+/// Labels name snapshots in place of their ids, so they must not look like one.
+pub fn check_label(label: &str) -> Result<(), HatError> {
+    if label.is_empty() || label.parse::<u64>().is_ok() {
+        return Err(From::from(format!("Invalid snapshot label '{}'", label)));
+    }
+    if label.chars().any(|c| c.is_whitespace() || c.is_control()) {
+        return Err(From::from(format!("Snapshot label '{}' contains whitespace", label)));
+    }
+    Ok(())
+}
+
+impl<B: StoreBackend> HatRc<B> {
+    fn snapshot_info(
+        &mut self,
+        family_name: &str,
+        snapshot_id: u64,
+    ) -> Result<db::SnapshotInfo, HatError> {
+        match self.snapshot_index.lookup(family_name, snapshot_id) {
+            Some((info, _, _)) => Ok(info),
+            None => Err(From::from(format!(
+                "No snapshot {} in family {}",
+                snapshot_id,
+                family_name
+            ))),
+        }
+    }
+
+    /// Attach a label to a snapshot.
+    pub fn add_label(
+        &mut self,
+        family_name: &str,
+        snapshot_id: u64,
+        label: &str,
+    ) -> Result<(), HatError> {
+        check_label(label)?;
+        let info = self.snapshot_info(family_name, snapshot_id)?;
+        self.snapshot_index.add_label(&info, label);
+        self.flush_snapshot_index();
+        Ok(())
+    }
+
+    /// Detach a label from a snapshot. Fails if the snapshot does not have the label.
+    pub fn remove_label(
+        &mut self,
+        family_name: &str,
+        snapshot_id: u64,
+        label: &str,
+    ) -> Result<(), HatError> {
+        let info = self.snapshot_info(family_name, snapshot_id)?;
+        if !self.snapshot_index.remove_label(&info, label) {
+            return Err(From::from(format!(
+                "Snapshot {} of family {} is not labelled '{}'",
+                snapshot_id,
+                family_name,
+                label
+            )));
+        }
+        self.flush_snapshot_index();
+        Ok(())
+    }
+
+    /// Find the snapshot named by `selector`: either a snapshot id, or a label, which selects
+    /// the newest completed snapshot of the family that has it.
+    pub fn resolve_snapshot(&mut self, family_name: &str, selector: &str) -> Result<u64, HatError> {
+        if let Ok(id) = selector.parse::<u64>() {
+            return Ok(id);
+        }
+        self.snapshot_index
+            .list_all()
+            .into_iter()
+            .filter(|s| s.family_name == family_name)
+            .filter(|s| match s.status {
+                db::SnapshotWorkStatus::CommitComplete => true,
+                _ => false,
+            })
+            .filter(|s| s.labels.iter().any(|l| l == selector))
+            .map(|s| s.info.snapshot_id)
+            .max()
+            .ok_or_else(|| {
+                From::from(format!(
+                    "No snapshot of family {} is labelled '{}'",
+                    family_name,
+                    selector
+                ))
+            })
+    }
+}
@@ -0,0 +1,360 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Listing the contents of snapshots without restoring them.
+
+use backend::StoreBackend;
+use errors::HatError;
+use hash;
+use key;
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Component, Path, PathBuf};
+use util::Pattern;
+
+use super::HatRc;
+use super::family::{DirIterator, Family};
+use super::walker::Content;
+
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EntryKind {
+    File,
+    Dir,
+    Symlink(PathBuf),
+}
+
+/// An entry of a snapshot, as listed by `Hat::list_dir`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct ListEntry {
+    /// Relative to the listed directory.
+    pub path: PathBuf,
+    pub kind: EntryKind,
+    /// Missing for directories and links.
+    pub size: Option<u64>,
+    pub mode: Option<u32>,
+    /// Seconds since the epoch.
+    pub modified: Option<u64>,
+}
+
+/// The space taken by an entry of a snapshot directory, as reported by `Hat::disk_usage`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct DiskUsage {
+    /// Relative to the reported directory.
+    pub path: PathBuf,
+    /// The total size of the files at or below the entry.
+    pub logical_bytes: u64,
+    /// The stored size of the file data that no other entry of the reported directory shares.
+    /// Other snapshots can hold the same data, so forgetting this one need not free any of it.
+    pub unique_bytes: u64,
+}
+
+/// The entries of a snapshot directory, read as they are iterated over; see `Hat::iter_dir`.
+pub struct DirEntries<B: StoreBackend>(DirIterator<key::HashStoreBackend<B>>);
+
+impl<B: StoreBackend> Iterator for DirEntries<B> {
+    type Item = Result<ListEntry, HatError>;
+
+    fn next(&mut self) -> Option<Result<ListEntry, HatError>> {
+        self.0.next().map(|res| {
+            res.map(|(entry, content)| list_entry(Path::new(""), entry, content).0)
+        })
+    }
+}
+
+/// Collects the hash and stored size of each node of a hash tree, without reading its leafs.
+struct ChunkSizes(Vec<(Vec<u8>, u64)>);
+
+impl hash::tree::Visitor for ChunkSizes {
+    fn branch_enter(
+        &mut self,
+        href: &hash::tree::HashRef,
+        _childs: &Vec<hash::tree::HashRef>,
+    ) -> bool {
+        self.0.push((href.hash.bytes.clone(), href.persistent_ref.length as u64));
+        true
+    }
+
+    fn leaf_enter(&mut self, href: &hash::tree::HashRef) -> bool {
+        self.0.push((href.hash.bytes.clone(), href.persistent_ref.length as u64));
+        false
+    }
+}
+
+/// Conditions besides its path that an entry found by `Hat::find` must meet. Only files have a
+/// size, so size conditions exclude directories and links.
+#[derive(Clone, Debug, Default)]
+pub struct FindFilter {
+    pub min_size: Option<u64>,
+    pub max_size: Option<u64>,
+    /// Modified at or after this many seconds since the epoch.
+    pub modified_after: Option<u64>,
+    /// Modified before this many seconds since the epoch.
+    pub modified_before: Option<u64>,
+}
+
+impl FindFilter {
+    fn matches(&self, entry: &ListEntry) -> bool {
+        let at_least = |bound: Option<u64>, value: Option<u64>| match (bound, value) {
+            (None, _) => true,
+            (Some(b), Some(v)) => v >= b,
+            (Some(_), None) => false,
+        };
+        let below = |bound: Option<u64>, value: Option<u64>| match (bound, value) {
+            (None, _) => true,
+            (Some(b), Some(v)) => v < b,
+            (Some(_), None) => false,
+        };
+        at_least(self.min_size, entry.size) &&
+            below(self.max_size.and_then(|s| s.checked_add(1)), entry.size) &&
+            at_least(self.modified_after, entry.modified) &&
+            below(self.modified_before, entry.modified)
+    }
+}
+
+impl<B: StoreBackend> HatRc<B> {
+    /// Search all completed snapshots of a family for entries whose path matches `pattern` and
+    /// that pass `filter`. Returns the matches as (snapshot id, entry), by snapshot; entry paths
+    /// are relative to the snapshot root. Directories that several snapshots share are only read
+    /// once.
+    pub fn find(
+        &mut self,
+        family_name: &str,
+        pattern: &Pattern,
+        filter: &FindFilter,
+    ) -> Result<Vec<(u64, ListEntry)>, HatError> {
+        let ids: Vec<u64> = self.list_snapshots()
+            .into_iter()
+            .filter(|s| s.family == family_name)
+            .map(|s| s.snapshot_id)
+            .collect();
+
+        let family = self.open_family(family_name.to_string())?;
+        let mut listed = HashMap::new();
+        let mut found = vec![];
+        for id in ids {
+            let (_, dir_ref) = self.complete_snapshot(family_name, Some(id))?;
+            for entry in self.list_tree(&family, dir_ref, &mut listed)? {
+                if pattern.matches(entry.path.as_os_str().as_bytes()) && filter.matches(&entry) {
+                    found.push((id, entry));
+                }
+            }
+        }
+        Ok(found)
+    }
+
+    /// Report the space taken by each entry of directory `path` of a snapshot, or of the latest
+    /// snapshot if no id is given. Data shared by several entries counts as unique to none.
+    pub fn disk_usage(
+        &mut self,
+        family_name: &str,
+        snapshot_id: Option<u64>,
+        path: &Path,
+    ) -> Result<Vec<DiskUsage>, HatError> {
+        let (_, dir_ref) = self.complete_snapshot(family_name, snapshot_id)?;
+        let family = self.open_family(family_name.to_string())?;
+        let dir_ref = self.find_dir(&family, dir_ref, path)?;
+
+        let mut usage = vec![];
+        // The stored size of each chunk, and the index of the only entry using it, if any.
+        let mut chunks: HashMap<Vec<u8>, (u64, Option<usize>)> = HashMap::new();
+        for res in self.iter_snapshot_dir(&family, dir_ref)? {
+            let (entry, content) = res?;
+            let path = PathBuf::from(OsStr::from_bytes(&entry.info.name[..]));
+            let index = usage.len();
+            let mut sizes = ChunkSizes(vec![]);
+            let mut logical_bytes = 0;
+            self.file_chunks(&family, entry, content, &mut sizes, &mut logical_bytes)?;
+            for (hash, length) in sizes.0 {
+                let chunk = chunks.entry(hash).or_insert((length, Some(index)));
+                if chunk.1 != Some(index) {
+                    chunk.1 = None;
+                }
+            }
+            usage.push(DiskUsage {
+                path: path,
+                logical_bytes: logical_bytes,
+                unique_bytes: 0,
+            });
+        }
+        for (_, (length, owner)) in chunks {
+            if let Some(i) = owner {
+                usage[i].unique_bytes += length;
+            }
+        }
+        Ok(usage)
+    }
+
+    /// Add the data chunks of an entry and of everything below it to `sizes`, and the size of
+    /// its files to `logical_bytes`.
+    fn file_chunks(
+        &self,
+        family: &Family<B>,
+        entry: key::Entry,
+        content: Content,
+        sizes: &mut ChunkSizes,
+        logical_bytes: &mut u64,
+    ) -> Result<(), HatError> {
+        match content {
+            Content::Dir(href) => {
+                for res in self.iter_snapshot_dir(family, href)? {
+                    let (entry, content) = res?;
+                    self.file_chunks(family, entry, content, sizes, logical_bytes)?;
+                }
+            }
+            Content::Data(href) => {
+                *logical_bytes += entry.info.byte_length.unwrap_or(0);
+                if let Some(mut walker) = hash::tree::Walker::new(self.hash_backend(), href)? {
+                    while walker.resume(sizes)? {}
+                }
+            }
+            Content::Inline(bytes) => {
+                *logical_bytes += entry.info.byte_length.unwrap_or(bytes.len() as u64);
+            }
+            Content::Link(_) => (),
+        }
+        Ok(())
+    }
+
+    /// The directory at `path` of the snapshot tree `dir_ref`.
+    fn find_dir(
+        &self,
+        family: &Family<B>,
+        dir_ref: hash::tree::HashRef,
+        path: &Path,
+    ) -> Result<hash::tree::HashRef, HatError> {
+        let is_root = path.components().all(|c| match c {
+            Component::RootDir | Component::CurDir => true,
+            _ => false,
+        });
+        if is_root {
+            return Ok(dir_ref);
+        }
+        match self.lookup_path(family, dir_ref, path)?.2 {
+            Content::Dir(href) => Ok(href),
+            _ => Err(From::from(format!("{} is not a directory", path.display()))),
+        }
+    }
+
+    /// Like `list_dir` without `recursive`, but the entries are read as they are iterated over,
+    /// so that a large directory is never held in memory.
+    pub fn iter_dir(
+        &mut self,
+        family_name: &str,
+        snapshot_id: Option<u64>,
+        path: &Path,
+    ) -> Result<DirEntries<B>, HatError> {
+        let (_, dir_ref) = self.complete_snapshot(family_name, snapshot_id)?;
+        let family = self.open_family(family_name.to_string())?;
+        let dir_ref = self.find_dir(&family, dir_ref, path)?;
+        Ok(DirEntries(self.iter_snapshot_dir(&family, dir_ref)?))
+    }
+
+    /// List directory `path` of a snapshot, or of the latest snapshot if no id is given. With
+    /// `recursive`, everything below it is listed too, each directory before its contents.
+    pub fn list_dir(
+        &mut self,
+        family_name: &str,
+        snapshot_id: Option<u64>,
+        path: &Path,
+        recursive: bool,
+    ) -> Result<Vec<ListEntry>, HatError> {
+        let (_, dir_ref) = self.complete_snapshot(family_name, snapshot_id)?;
+        let family = self.open_family(family_name.to_string())?;
+        let dir_ref = self.find_dir(&family, dir_ref, path)?;
+
+        let mut entries = vec![];
+        self.list_entries(&family, dir_ref, PathBuf::new(), recursive, &mut entries)?;
+        Ok(entries)
+    }
+
+    /// Everything below directory `dir_ref`, like `list_entries`. The listing of each directory
+    /// is kept in `listed` by its hash, and reused when the directory is seen again.
+    fn list_tree(
+        &self,
+        family: &Family<B>,
+        dir_ref: hash::tree::HashRef,
+        listed: &mut HashMap<Vec<u8>, Vec<ListEntry>>,
+    ) -> Result<Vec<ListEntry>, HatError> {
+        if let Some(entries) = listed.get(&dir_ref.hash.bytes) {
+            return Ok(entries.clone());
+        }
+        let dir_hash = dir_ref.hash.bytes.clone();
+        let mut entries = vec![];
+        for res in self.iter_snapshot_dir(family, dir_ref)? {
+            let (entry, content) = res?;
+            let (entry, subdir) = list_entry(Path::new(""), entry, content);
+            let path = entry.path.clone();
+            entries.push(entry);
+            if let Some(href) = subdir {
+                for mut below in self.list_tree(family, href, listed)? {
+                    below.path = path.join(&below.path);
+                    entries.push(below);
+                }
+            }
+        }
+        listed.insert(dir_hash, entries.clone());
+        Ok(entries)
+    }
+
+    fn list_entries(
+        &self,
+        family: &Family<B>,
+        dir_ref: hash::tree::HashRef,
+        dir: PathBuf,
+        recursive: bool,
+        entries: &mut Vec<ListEntry>,
+    ) -> Result<(), HatError> {
+        for res in self.iter_snapshot_dir(family, dir_ref)? {
+            let (entry, content) = res?;
+            let (entry, subdir) = list_entry(&dir, entry, content);
+            let path = entry.path.clone();
+            entries.push(entry);
+            if let (true, Some(href)) = (recursive, subdir) {
+                self.list_entries(family, href, path, true, entries)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The listing of a directory entry below `dir`, and its tree if it is a directory itself.
+fn list_entry(
+    dir: &Path,
+    entry: key::Entry,
+    content: Content,
+) -> (ListEntry, Option<hash::tree::HashRef>) {
+    let info = entry.info;
+    let (kind, subdir) = match content {
+        Content::Dir(href) => (EntryKind::Dir, Some(href)),
+        Content::Link(target) => (EntryKind::Symlink(target), None),
+        Content::Data(_) |
+        Content::Inline(_) => (EntryKind::File, None),
+    };
+    let listed = ListEntry {
+        path: dir.join(OsStr::from_bytes(&info.name[..])),
+        size: if kind == EntryKind::File {
+            info.byte_length
+        } else {
+            None
+        },
+        kind: kind,
+        mode: info.permissions.map(|p| p.mode()),
+        modified: info.modified_ts_secs,
+    };
+    (listed, subdir)
+}
@@ -0,0 +1,389 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Cooperative locks kept in the backend, so that several machines can share a repository.
+//!
+//! Every lock is a small named blob. Backups and restores take shared locks, which may be held
+//! by any number of clients at once; garbage collection takes an exclusive lock, which excludes
+//! all others. A lock is taken by writing it first and checking for conflicting locks second,
+//! so that of two clients racing for conflicting locks, at least one backs off. Held locks are
+//! refreshed in the background; a lock that has not been refreshed for `STALE_LOCK_SECS` is
+//! assumed to belong to a client that died, and is ignored. A holder that cannot refresh its
+//! lock keeps retrying, and gives it up before others could take the lock to be stale: the lock
+//! is then lost (see `RepositoryLock::is_lost`), and a repository watching it (see
+//! `Hat::watch_lock`) refuses to go on with what the lock was protecting.
+//!
+//! Locks name the host that took them by its host name and an id kept in its cache directory
+//! (see `this_host`), as host names alone are not unique. Only with both matching is the
+//! process id of a holder taken to be a process on this host.
+
+use backend::StoreBackend;
+use blob::LOCK_PREFIX;
+use capnp;
+use chrono;
+use crypto;
+use errors::HatError;
+use hex::ToHex;
+use libc;
+use root_capnp;
+use std::env;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::process;
+use std::sync::{Arc, mpsc};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+/// Seconds after which a lock that has not been refreshed is considered stale.
+pub const STALE_LOCK_SECS: i64 = 30 * 60;
+
+/// How far the clocks of the hosts sharing a repository may disagree, in seconds.
+pub const CLOCK_SKEW_SECS: i64 = 5 * 60;
+
+/// Seconds between refreshes of a held lock.
+const REFRESH_SECS: u64 = 5 * 60;
+
+/// Seconds between attempts to refresh a held lock after a failed refresh.
+const RETRY_SECS: u64 = 60;
+
+/// File in the cache directory with the id of this host.
+const HOST_ID_FILE: &'static str = "host_id";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LockKind {
+    /// Taken by operations that only add to the repository, such as backups and restores.
+    Shared,
+    /// Taken by operations that remove data, such as garbage collection.
+    Exclusive,
+}
+
+/// A lock as stored in the backend.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct LockInfo {
+    pub kind: LockKind,
+    /// Who holds the lock, e.g. the host name and process id.
+    pub owner: String,
+    /// When the lock was taken or last refreshed, in seconds since the epoch.
+    pub refreshed_utc: i64,
+    /// What the holder is doing, e.g. its command line; empty if not recorded.
+    pub operation: String,
+    /// When the lock was taken, in seconds since the epoch; `None` if not recorded.
+    pub taken_utc: Option<i64>,
+}
+
+impl LockInfo {
+    pub fn is_stale(&self, now_utc: i64) -> bool {
+        now_utc - self.refreshed_utc > STALE_LOCK_SECS
+    }
+
+    /// Whether the holder is known to be gone: the lock is stale, or was taken by a process on
+    /// `host` (see `this_host`) that no longer exists. A holder on another host is only gone
+    /// once its lock is stale.
+    pub fn holder_is_gone(&self, now_utc: i64, host: &str) -> bool {
+        if self.is_stale(now_utc) {
+            return true;
+        }
+        match self.local_pid(host) {
+            Some(pid) => {
+                let alive = unsafe { libc::kill(pid as libc::pid_t, 0) } == 0 ||
+                    io::Error::last_os_error().raw_os_error() != Some(libc::ESRCH);
+                !alive
+            }
+            None => false,
+        }
+    }
+
+    /// The process id of the holder, if it took the lock from `host` (see `this_host`).
+    pub fn local_pid(&self, host: &str) -> Option<u32> {
+        let prefix = format!("{} (pid ", host);
+        if self.owner.starts_with(&prefix) && self.owner.ends_with(')') {
+            self.owner[prefix.len()..self.owner.len() - 1].parse().ok()
+        } else {
+            None
+        }
+    }
+
+    fn conflicts_with(&self, other: &LockInfo) -> bool {
+        self.kind == LockKind::Exclusive || other.kind == LockKind::Exclusive
+    }
+}
+
+/// A held lock. It is refreshed until dropped, and removed from the backend when dropped.
+pub struct RepositoryLock<B: StoreBackend> {
+    backend: Arc<B>,
+    name: Vec<u8>,
+    info: LockInfo,
+    stop: Option<mpsc::Sender<()>>,
+    refresher: Option<thread::JoinHandle<()>>,
+    lost: LockWatch,
+}
+
+/// Tells whether a `RepositoryLock` was lost, from wherever the work it protects is done.
+#[derive(Clone, Debug, Default)]
+pub struct LockWatch(Arc<AtomicBool>);
+
+impl LockWatch {
+    pub fn is_lost(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    /// Mark the lock as lost, for all watching it.
+    pub fn lose(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    /// Fail if the lock was lost, as others may then have removed data it was protecting.
+    pub fn check(&self) -> Result<(), HatError> {
+        if self.is_lost() {
+            Err(From::from("Lost the repository lock, as it could not be refreshed"))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl<B: StoreBackend> RepositoryLock<B> {
+    pub fn info(&self) -> &LockInfo {
+        &self.info
+    }
+
+    /// Whether the lock could not be refreshed for so long that others may take it to be stale.
+    /// It is no longer refreshed then, and what it protected should be given up.
+    pub fn is_lost(&self) -> bool {
+        self.lost.is_lost()
+    }
+
+    /// A handle telling whether the lock was lost, e.g. for a `Hat` to watch.
+    pub fn watch(&self) -> LockWatch {
+        self.lost.clone()
+    }
+
+    fn start_refresh(&mut self) {
+        let (stop, stopped) = mpsc::channel();
+        let backend = self.backend.clone();
+        let name = self.name.clone();
+        let mut info = self.info.clone();
+        let lost = self.lost.clone();
+        self.stop = Some(stop);
+        self.refresher = Some(thread::spawn(move || {
+            let mut wait = REFRESH_SECS;
+            while let Err(mpsc::RecvTimeoutError::Timeout) =
+                stopped.recv_timeout(Duration::from_secs(wait))
+            {
+                let refreshed = info.refreshed_utc;
+                info.refreshed_utc = chrono::Utc::now().timestamp();
+                match write(&*backend, &name, &info) {
+                    Ok(()) => wait = REFRESH_SECS,
+                    Err(e) => {
+                        info.refreshed_utc = refreshed;
+                        let age = chrono::Utc::now().timestamp() - refreshed;
+                        // Once others may take the lock to be stale, going on is not safe.
+                        if age >= STALE_LOCK_SECS - CLOCK_SKEW_SECS {
+                            error!("Could not refresh repository lock for {}s: {}", age, e);
+                            lost.lose();
+                            return;
+                        }
+                        warn!("Could not refresh repository lock, retrying: {}", e);
+                        wait = RETRY_SECS;
+                    }
+                }
+            }
+        }));
+    }
+}
+
+impl<B: StoreBackend> Drop for RepositoryLock<B> {
+    fn drop(&mut self) {
+        if let Some(stop) = self.stop.take() {
+            let _ = stop.send(());
+        }
+        if let Some(refresher) = self.refresher.take() {
+            let _ = refresher.join();
+        }
+        if let Err(e) = self.backend.delete(&self.name) {
+            warn!("Could not release repository lock: {}", e);
+        }
+    }
+}
+
+/// Who takes locks from this process on `host`, as recorded in the lock.
+pub fn owner(host: &str) -> String {
+    format!("{} (pid {})", host, process::id())
+}
+
+/// This host as named in locks: its host name, and an id kept in `cache_dir` to tell apart
+/// hosts of the same name. The id is made up the first time.
+pub fn this_host(cache_dir: &Path) -> Result<String, HatError> {
+    let path = cache_dir.join(HOST_ID_FILE);
+    if !path.exists() {
+        fs::create_dir_all(cache_dir)?;
+        let tmp = cache_dir.join(format!("{}.{}", HOST_ID_FILE, process::id()));
+        {
+            let mut file = fs::File::create(&tmp)?;
+            file.write_all(crypto::keys::random_bytes(16).unsecure().to_hex().as_bytes())?;
+            file.sync_all()?;
+        }
+        // Linking fails if another process made up an id meanwhile, which is then used.
+        let linked = fs::hard_link(&tmp, &path);
+        fs::remove_file(&tmp)?;
+        match linked {
+            Ok(()) => (),
+            Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => (),
+            Err(e) => return Err(From::from(e)),
+        }
+    }
+    let mut id = String::new();
+    fs::File::open(&path)?.read_to_string(&mut id)?;
+    if id.trim().is_empty() {
+        return Err(From::from(format!("{} is empty", path.display())));
+    }
+    Ok(format!("{} [{}]", hostname()?, id.trim()))
+}
+
+/// The name of this host, as told by the system rather than the environment.
+pub fn hostname() -> io::Result<String> {
+    let mut buf = [0u8; 256];
+    let ret = unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    Ok(String::from_utf8_lossy(&buf[..len]).into_owned())
+}
+
+/// What this process is doing, as recorded in the lock: its command line.
+pub fn default_operation() -> String {
+    env::args().collect::<Vec<_>>().join(" ")
+}
+
+/// Take a lock of the given kind, or fail if a conflicting lock is held by someone else.
+pub fn acquire<B: StoreBackend>(
+    backend: Arc<B>,
+    kind: LockKind,
+    owner: String,
+) -> Result<RepositoryLock<B>, HatError> {
+    let mut name = LOCK_PREFIX.to_vec();
+    name.extend_from_slice(crypto::keys::random_bytes(16).unsecure().to_hex().as_bytes());
+    let now = chrono::Utc::now().timestamp();
+    let info = LockInfo {
+        kind: kind,
+        owner: owner,
+        refreshed_utc: now,
+        operation: default_operation(),
+        taken_utc: Some(now),
+    };
+    write(&*backend, &name, &info)?;
+
+    // From here on, dropping the lock removes it again.
+    let mut lock = RepositoryLock {
+        backend: backend,
+        name: name,
+        info: info,
+        stop: None,
+        refresher: None,
+        lost: LockWatch::default(),
+    };
+
+    for (other, theirs) in list(&*lock.backend)? {
+        if other == lock.name || !lock.info.conflicts_with(&theirs) {
+            continue;
+        }
+        if theirs.is_stale(now) {
+            warn!(
+                "Ignoring stale {:?} lock held by {}",
+                theirs.kind,
+                theirs.owner
+            );
+            continue;
+        }
+        return Err(From::from(format!(
+            "Repository is locked: {:?} lock held by {}",
+            theirs.kind,
+            theirs.owner
+        )));
+    }
+
+    lock.start_refresh();
+    Ok(lock)
+}
+
+/// All locks in the backend, by backend name. Locks that cannot be read are skipped.
+pub fn list<B: StoreBackend>(backend: &B) -> Result<Vec<(Vec<u8>, LockInfo)>, HatError> {
+    let mut locks = vec![];
+    for name in backend.list()? {
+        if !name.starts_with(LOCK_PREFIX) {
+            continue;
+        }
+        // The lock may have been released since listing.
+        if let Some(bytes) = backend.retrieve(&name)? {
+            match read(&bytes) {
+                Ok(info) => locks.push((name.into_vec(), info)),
+                Err(e) => warn!("Skipping unreadable lock: {}", e),
+            }
+        }
+    }
+    Ok(locks)
+}
+
+/// Store a lock under `name`, replacing any previous version.
+pub fn write<B: StoreBackend>(backend: &B, name: &[u8], info: &LockInfo) -> Result<(), HatError> {
+    let mut message = capnp::message::Builder::new_default();
+    {
+        let mut lock = message.init_root::<root_capnp::lock::Builder>();
+        lock.set_exclusive(info.kind == LockKind::Exclusive);
+        lock.set_owner(&info.owner);
+        lock.set_refreshed_utc(info.refreshed_utc);
+        lock.set_operation(&info.operation);
+        lock.set_taken_utc(info.taken_utc.unwrap_or(0));
+    }
+
+    let mut bytes = Vec::new();
+    capnp::serialize_packed::write_message(&mut bytes, &message)?;
+    // Locks carry no secrets, and must be readable by clients without the access key.
+    backend.store(name, &crypto::CipherText::new(bytes))?;
+    backend.flush()?;
+    Ok(())
+}
+
+fn read(bytes: &[u8]) -> Result<LockInfo, HatError> {
+    let reader = capnp::serialize_packed::read_message(
+        &mut &bytes[..],
+        capnp::message::ReaderOptions::new(),
+    )?;
+    let lock = reader.get_root::<root_capnp::lock::Reader>()?;
+    Ok(LockInfo {
+        kind: if lock.get_exclusive() {
+            LockKind::Exclusive
+        } else {
+            LockKind::Shared
+        },
+        owner: lock.get_owner()?.to_owned(),
+        refreshed_utc: lock.get_refreshed_utc(),
+        operation: lock.get_operation()?.to_owned(),
+        taken_utc: match lock.get_taken_utc() {
+            0 => None,
+            taken => Some(taken),
+        },
+    })
+}
+
+/// Remove the lock stored under `name`, whoever holds it.
+pub fn remove<B: StoreBackend>(backend: &B, name: &[u8]) -> Result<(), HatError> {
+    backend.delete(name)?;
+    backend.flush()?;
+    Ok(())
+}
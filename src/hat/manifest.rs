@@ -0,0 +1,107 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The manifest of a repository: the parameters its data was written with.
+
+use backend::StoreBackend;
+use blob::MANIFEST_NAME;
+use crypto;
+use crypto::keys::HashAlgorithm;
+use errors::HatError;
+use key::MAX_CHUNK_LEN;
+use serde_json;
+
+
+/// Version of the repository format written by this version of hat.
+pub const FORMAT_VERSION: u32 = 1;
+
+/// The parameters of a repository, stored in its backend by `hat init` as plain JSON, so that
+/// it can be inspected without the keys.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Manifest {
+    pub format_version: u32,
+    pub hash_algorithm: String,
+    /// How files are cut into chunks, e.g. `fixed-131072` for chunks of 128 KiB.
+    pub chunking: String,
+    pub compression: String,
+    pub encryption: String,
+    /// Whether clients refuse to delete or overwrite data, unless privileged (see
+    /// `AppendOnlyBackend`). Set when the repository is created through an append-only backend.
+    #[serde(default)]
+    pub append_only: bool,
+}
+
+impl Manifest {
+    /// The parameters this version of hat writes data with.
+    pub fn current(hash_algorithm: HashAlgorithm) -> Manifest {
+        Manifest {
+            format_version: FORMAT_VERSION,
+            hash_algorithm: hash_algorithm.as_str().to_string(),
+            chunking: format!("fixed-{}", MAX_CHUNK_LEN),
+            compression: "none".to_string(),
+            encryption: "chacha20poly1305".to_string(),
+            append_only: false,
+        }
+    }
+
+    /// The manifest of the repository in `backend`, if it has been initialized.
+    pub fn load<B: StoreBackend>(backend: &B) -> Result<Option<Manifest>, HatError> {
+        match backend.retrieve(MANIFEST_NAME)? {
+            None => Ok(None),
+            Some(bytes) => {
+                let manifest = serde_json::from_slice(&bytes[..]).map_err(|e| {
+                    From::from(format!("Invalid repository manifest: {}", e))
+                })?;
+                Ok(Some(manifest))
+            }
+        }
+    }
+
+    pub fn store<B: StoreBackend>(&self, backend: &B) -> Result<(), HatError> {
+        let bytes = serde_json::to_vec_pretty(self).unwrap();
+        backend.store(MANIFEST_NAME, &crypto::CipherText::new(bytes))?;
+        backend.flush()?;
+        Ok(())
+    }
+
+    /// The hash algorithm the repository was created with.
+    pub fn hash_algorithm(&self) -> Result<HashAlgorithm, HatError> {
+        Ok(self.hash_algorithm.parse::<HashAlgorithm>()?)
+    }
+
+    /// Fail unless data written with `expected` can be mixed with the data of this repository.
+    pub fn check(&self, expected: &Manifest) -> Result<(), HatError> {
+        if self.format_version > expected.format_version {
+            return Err(From::from(format!(
+                "Repository format {} is newer than this hat supports ({})",
+                self.format_version,
+                expected.format_version
+            )));
+        }
+        let params = [
+            ("hash algorithm", &self.hash_algorithm, &expected.hash_algorithm),
+            ("chunking", &self.chunking, &expected.chunking),
+            ("compression", &self.compression, &expected.compression),
+            ("encryption", &self.encryption, &expected.encryption),
+        ];
+        for &(what, found, wanted) in &params {
+            if found != wanted {
+                return Err(From::from(
+                    format!("Repository uses {} {}, not {}", what, found, wanted),
+                ));
+            }
+        }
+        Ok(())
+    }
+}
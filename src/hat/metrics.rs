@@ -0,0 +1,305 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Backup health in the Prometheus text exposition format.
+
+use chrono;
+use std::fmt::Write;
+use std::fs;
+use std::io::{self, BufRead, BufReader};
+use std::net::{TcpListener, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use util::Progress;
+
+
+/// The state of the most recent backups of one family.
+#[derive(Clone, Debug)]
+pub struct FamilyMetrics {
+    pub family: String,
+    /// When the newest completed snapshot was taken.
+    pub last_success: Option<chrono::DateTime<chrono::Utc>>,
+    /// Whether the newest snapshot, complete or not, was completed.
+    pub last_run_ok: bool,
+}
+
+/// Backup health of a repository, as reported by `Hat::backup_metrics`.
+#[derive(Clone, Debug)]
+pub struct BackupMetrics {
+    pub families: Vec<FamilyMetrics>,
+    /// Number of committed blobs.
+    pub blobs: u64,
+    /// File data first stored by a completed snapshot, summed over all snapshots.
+    pub data_bytes: u64,
+}
+
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+impl BackupMetrics {
+    /// Render the metrics as of `now`, e.g. for the node exporter's textfile collector.
+    pub fn to_prometheus(&self, now: chrono::DateTime<chrono::Utc>) -> String {
+        let mut out = String::new();
+
+        out.push_str(
+            "# HELP hat_last_success_age_seconds Age of the newest completed snapshot.\n\
+             # TYPE hat_last_success_age_seconds gauge\n",
+        );
+        for f in &self.families {
+            if let Some(ts) = f.last_success {
+                writeln!(
+                    out,
+                    "hat_last_success_age_seconds{{family=\"{}\"}} {}",
+                    escape_label(&f.family),
+                    now.signed_duration_since(ts).num_seconds()
+                ).unwrap();
+            }
+        }
+
+        out.push_str(
+            "# HELP hat_last_success_timestamp_seconds When the newest completed snapshot was \
+             taken.\n\
+             # TYPE hat_last_success_timestamp_seconds gauge\n",
+        );
+        for f in &self.families {
+            if let Some(ts) = f.last_success {
+                writeln!(
+                    out,
+                    "hat_last_success_timestamp_seconds{{family=\"{}\"}} {}",
+                    escape_label(&f.family),
+                    ts.timestamp()
+                ).unwrap();
+            }
+        }
+
+        out.push_str(
+            "# HELP hat_last_run_success Whether the newest snapshot was completed.\n\
+             # TYPE hat_last_run_success gauge\n",
+        );
+        for f in &self.families {
+            writeln!(
+                out,
+                "hat_last_run_success{{family=\"{}\"}} {}",
+                escape_label(&f.family),
+                if f.last_run_ok { 1 } else { 0 }
+            ).unwrap();
+        }
+
+        out.push_str(
+            "# HELP hat_repository_blobs Number of committed blobs.\n\
+             # TYPE hat_repository_blobs gauge\n",
+        );
+        writeln!(out, "hat_repository_blobs {}", self.blobs).unwrap();
+
+        out.push_str(
+            "# HELP hat_repository_data_bytes New file data stored by completed snapshots.\n\
+             # TYPE hat_repository_data_bytes gauge\n",
+        );
+        writeln!(out, "hat_repository_data_bytes {}", self.data_bytes).unwrap();
+
+        out
+    }
+}
+
+/// Counters of the work done by this process, updated while a commit, checkout or gc runs.
+///
+/// It is used as the `Progress` of a `Hat`, and passes every event on to `inner`, so that it
+/// can be combined with e.g. a terminal progress line.
+pub struct RunMetrics {
+    inner: Arc<Progress>,
+    files: AtomicUsize,
+    read_bytes: AtomicUsize,
+    uploaded_bytes: AtomicUsize,
+    stored_bytes: AtomicUsize,
+    deduped_chunks: AtomicUsize,
+    deduped_bytes: AtomicUsize,
+    backend_errors: Arc<AtomicUsize>,
+}
+
+impl RunMetrics {
+    pub fn new(inner: Arc<Progress>) -> RunMetrics {
+        RunMetrics {
+            inner: inner,
+            files: AtomicUsize::new(0),
+            read_bytes: AtomicUsize::new(0),
+            uploaded_bytes: AtomicUsize::new(0),
+            stored_bytes: AtomicUsize::new(0),
+            deduped_chunks: AtomicUsize::new(0),
+            deduped_bytes: AtomicUsize::new(0),
+            backend_errors: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Report the failed backend calls counted by `errors`, e.g. from a `CountingBackend`.
+    pub fn with_backend_errors(mut self, errors: Arc<AtomicUsize>) -> RunMetrics {
+        self.backend_errors = errors;
+        self
+    }
+
+    /// Render the counters in the Prometheus text exposition format.
+    pub fn to_prometheus(&self) -> String {
+        let uploaded = self.uploaded_bytes.load(Ordering::SeqCst);
+        let stored = self.stored_bytes.load(Ordering::SeqCst);
+        let metrics = [
+            (
+                "hat_files_total",
+                "counter",
+                "Files processed by this run.",
+                self.files.load(Ordering::SeqCst),
+            ),
+            (
+                "hat_read_bytes_total",
+                "counter",
+                "File data read or written by this run.",
+                self.read_bytes.load(Ordering::SeqCst),
+            ),
+            (
+                "hat_uploaded_bytes_total",
+                "counter",
+                "New file data handed to the backend by this run.",
+                uploaded,
+            ),
+            (
+                "hat_deduped_chunks_total",
+                "counter",
+                "File chunks that were already stored.",
+                self.deduped_chunks.load(Ordering::SeqCst),
+            ),
+            (
+                "hat_deduped_bytes_total",
+                "counter",
+                "File data that was already stored.",
+                self.deduped_bytes.load(Ordering::SeqCst),
+            ),
+            (
+                "hat_backend_errors_total",
+                "counter",
+                "Backend calls that failed.",
+                self.backend_errors.load(Ordering::SeqCst),
+            ),
+            (
+                "hat_upload_queue_bytes",
+                "gauge",
+                "New file data waiting to be committed to the backend.",
+                uploaded.saturating_sub(stored),
+            ),
+        ];
+
+        let mut out = String::new();
+        for &(name, kind, help, value) in &metrics {
+            writeln!(out, "# HELP {} {}", name, help).unwrap();
+            writeln!(out, "# TYPE {} {}", name, kind).unwrap();
+            writeln!(out, "{} {}", name, value).unwrap();
+        }
+        out
+    }
+}
+
+impl Progress for RunMetrics {
+    fn start(&self, operation: &str, total_bytes: Option<u64>) {
+        self.inner.start(operation, total_bytes);
+    }
+
+    fn file(&self, path: &Path) {
+        self.files.fetch_add(1, Ordering::SeqCst);
+        self.inner.file(path);
+    }
+
+    fn read(&self, bytes: u64) {
+        self.read_bytes.fetch_add(bytes as usize, Ordering::SeqCst);
+        self.inner.read(bytes);
+    }
+
+    fn uploaded(&self, bytes: u64) {
+        self.uploaded_bytes.fetch_add(bytes as usize, Ordering::SeqCst);
+        self.inner.uploaded(bytes);
+    }
+
+    fn stored(&self, bytes: u64) {
+        self.stored_bytes.fetch_add(bytes as usize, Ordering::SeqCst);
+        self.inner.stored(bytes);
+    }
+
+    fn deduped(&self, bytes: u64) {
+        self.deduped_chunks.fetch_add(1, Ordering::SeqCst);
+        self.deduped_bytes.fetch_add(bytes as usize, Ordering::SeqCst);
+        self.inner.deduped(bytes);
+    }
+
+    fn finish(&self) {
+        self.inner.finish();
+    }
+}
+
+/// Write `text` to `path` for the node exporter's textfile collector.
+///
+/// The file is replaced in one step, so that the collector never reads half of it.
+pub fn write_textfile(path: &Path, text: &str) -> io::Result<()> {
+    let mut tmp = PathBuf::from(path);
+    tmp.set_extension("prom.tmp");
+    {
+        let mut file = fs::File::create(&tmp)?;
+        io::Write::write_all(&mut file, text.as_bytes())?;
+        file.sync_all()?;
+    }
+    fs::rename(&tmp, path)
+}
+
+/// Answer every HTTP request to `addr` with the current metrics, from a background thread.
+///
+/// `backup` holds the latest `BackupMetrics` rendering; the counters of `run` are read afresh
+/// for every scrape.
+pub fn serve_metrics<A: ToSocketAddrs>(
+    addr: A,
+    run: Arc<RunMetrics>,
+    backup: Arc<Mutex<String>>,
+) -> io::Result<()> {
+    let listener = TcpListener::bind(addr)?;
+    thread::spawn(move || for stream in listener.incoming() {
+        let mut stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                warn!("Could not accept metrics connection: {}", e);
+                continue;
+            }
+        };
+        // Skip the request; there is only one thing to serve.
+        let mut reader = BufReader::new(match stream.try_clone() {
+            Ok(s) => s,
+            Err(_) => continue,
+        });
+        let mut line = String::new();
+        while reader.read_line(&mut line).map(|n| n > 0).unwrap_or(false) &&
+            line.trim_right().len() > 0
+        {
+            line.clear();
+        }
+
+        let body = format!("{}{}", backup.lock().unwrap(), run.to_prometheus());
+        let response = format!(
+            "HTTP/1.0 200 OK\r\n\
+             Content-Type: text/plain; version=0.0.4\r\n\
+             Content-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        );
+        if let Err(e) = io::Write::write_all(&mut stream, response.as_bytes()) {
+            debug!("Could not answer metrics request: {}", e);
+        }
+    });
+    Ok(())
+}
@@ -14,7 +14,7 @@
 
 use crypto;
 use chrono;
-use backend::StoreBackend;
+use backend::{ObjectTags, StoreBackend};
 use blob;
 use capnp;
 use db;
@@ -24,21 +24,78 @@ use gc::{self, Gc, GcRc};
 use hash;
 use key;
 use root_capnp;
+use scoped_pool;
 use snapshot;
 use std::cmp;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ffi::OsStr;
 use std::fs;
+use std::io::{self, Write};
+use std::os::unix::ffi::OsStrExt;
 use std::path::{Path, PathBuf};
-use std::str;
-use std::sync::{Arc, mpsc};
+use std::sync::{Arc, Mutex, mpsc};
+use std::sync::atomic::AtomicUsize;
 use tags;
-use util::Process;
+use time;
+use util::{self, Process};
 use void::Void;
+use xattr;
 use hex::ToHex;
 
+pub use crypto::keys::HashAlgorithm;
+pub use db::{IndexReport, Provenance, SnapshotContents, SnapshotStats};
+pub use key::{Fidelity, Limits};
+pub use util::{ChownMap, Pattern, Progress, SilentProgress, TerminalProgress};
+pub use util::{handle_pause_signals, is_paused, lower_priority, set_paused, signal_pause};
+pub use util::{human_bytes, parse_bytes};
+pub use self::builder::{CommitReport, DEFAULT_MAX_BLOB_SIZE, HatBuilder};
+pub use self::check::{CheckReport, DAMAGED_LABEL, DamagedSnapshot, Problem, ProblemKind,
+                      RepairReport, ScrubReport};
+pub use self::config::{Config, RepositoryConfig};
+pub use self::diff::{ChangeKind, FileChange, Mismatch, MismatchKind, RestoreCheck, ScanOptions,
+                     Status};
+pub use self::events::{Event, EventLog};
+pub use self::family::{FileError, snapshot_dirs};
+pub use self::gc_report::{GcDrift, GcEstimate, GcPhase, GcReport, PinnedData};
+pub use self::hooks::{Hook, Hooks};
+pub use self::listing::{DirEntries, DiskUsage, EntryKind, FindFilter, ListEntry};
+pub use self::lock::{LockInfo, LockKind, LockWatch, RepositoryLock};
+pub use self::manifest::{FORMAT_VERSION, Manifest};
+pub use self::metrics::{BackupMetrics, FamilyMetrics, RunMetrics, serve_metrics, write_textfile};
+pub use self::passphrase::{DEFAULT_PASSPHRASE, PassphraseSource};
+pub use self::patch::CopyReport;
+pub use self::retention::RetentionPolicy;
+pub use self::stats::{FamilyStats, Quota, QuotaStatus, RepositoryStats};
+#[cfg(feature = "web")]
+pub use self::web::WebAccess;
+
+mod archive;
+mod builder;
+mod check;
+mod config;
+mod diff;
+mod events;
 mod family;
+mod gc_report;
+mod hooks;
 mod insert_path_handler;
+mod labels;
+mod listing;
+mod lock;
+mod manifest;
+mod metrics;
+#[cfg(feature = "mount")]
+mod mount;
+mod passphrase;
+mod patch;
+mod retention;
+mod root;
+mod stats;
 mod walker;
+#[cfg(feature = "web")]
+mod web;
 use self::family::Family;
+use self::gc_report::gc_phase;
 
 #[cfg(test)]
 mod tests;
@@ -110,6 +167,13 @@ impl gc::GcBackend for GcBackend {
         Ok(receiver)
     }
 
+    fn list_ids(&self) -> Result<mpsc::Receiver<gc::Id>, Self::Err> {
+        let (sender, receiver) = mpsc::channel();
+        self.hash_index.list_ids().iter().map(|i| sender.send(*i)).last();
+
+        Ok(receiver)
+    }
+
     fn manual_commit(&mut self) -> Result<(), Self::Err> {
         self.hash_index.manual_commit();
         Ok(())
@@ -129,11 +193,475 @@ pub struct Hat<B: StoreBackend, G: gc::Gc<GcBackend>> {
     blob_index: Arc<blob::BlobIndex>,
     blob_store: Arc<blob::BlobStore<B>>,
     blob_max_size: usize,
+    limits: key::Limits,
+    inline_max: Option<usize>,
+    progress: Arc<Progress>,
+    namespace: Option<String>,
+    fanout: usize,
+    hashing_threads: usize,
+    root_doc: Option<root::RootDoc>,
+    object_tags: ObjectTags,
+    gc_grace_period: Option<time::Duration>,
+    allow_unsigned: bool,
+    /// This host as named in locks (see `lock::this_host`).
+    host: String,
+    /// The lock this repository is used under, if watched (see `watch_lock`).
+    lock_watch: Option<LockWatch>,
     gc: G,
 }
 
 pub type HatRc<B> = Hat<B, GcRc<GcBackend>>;
 
+const HASH_ALGORITHM_CONFIG: &'static str = "hash_algorithm";
+const FINGERPRINT_SECRET_CONFIG: &'static str = "fingerprint_secret";
+const REPOSITORY_ID_CONFIG: &'static str = "repository_id";
+const FANOUT_CONFIG: &'static str = "hash_tree_fanout";
+/// The snapshot listing that the root document is being pointed at, while that is in progress.
+const PENDING_ROOT_CONFIG: &'static str = "pending_root";
+
+/// Separates the namespace of a source machine from a family name. Family names could never
+/// contain it, as they double as key index file names.
+const NAMESPACE_SEPARATOR: char = '/';
+
+/// Files of a family read and hashed at once, unless set with `set_hashing_threads`.
+const DEFAULT_HASHING_THREADS: usize = 3;
+
+/// Backend name of the sealed per-repository fingerprint secret.
+/// Names this short are never mistaken for data blobs (see `BlobStore::recover`).
+const FINGERPRINT_SECRET_NAME: &'static [u8] = b"keys";
+
+/// Reported when the keys of a repository do not open what it sealed with its access key.
+const WRONG_PASSPHRASE: &'static str = "Wrong passphrase for this repository";
+
+/// Overall access granted by the key material a repository was opened with.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AccessLevel {
+    NoAccess,
+    ReadOnly,
+    AppendOnly,
+    Admin,
+}
+
+/// Summary of what the current key material allows, as reported by `Hat::permissions`.
+#[derive(Clone, Debug)]
+pub struct Permissions {
+    pub access: AccessLevel,
+    pub keys: crypto::keys::Capabilities,
+    /// Known families and whether their snapshots can be decrypted.
+    pub families: Vec<(String, bool)>,
+}
+
+/// Where a snapshot is in its life cycle.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SnapshotState {
+    InProgress,
+    Complete,
+    Deleting,
+}
+
+/// A snapshot, as reported by `Hat::list_snapshots` and `Hat::list_snapshot_history`.
+#[derive(Clone, Debug, Serialize)]
+pub struct SnapshotSummary {
+    pub family: String,
+    pub snapshot_id: u64,
+    pub state: SnapshotState,
+    pub created: chrono::DateTime<chrono::Utc>,
+    /// When the backup run finished; missing for unfinished and older snapshots.
+    pub finished: Option<chrono::DateTime<chrono::Utc>>,
+    /// Missing for snapshots taken before statistics were recorded.
+    pub stats: Option<SnapshotStats>,
+    /// Missing for snapshots taken before their contents were counted.
+    pub contents: Option<SnapshotContents>,
+    /// Missing for snapshots taken before provenance was recorded.
+    pub provenance: Option<Provenance>,
+    pub labels: Vec<String>,
+    /// Hex digest of the root of the snapshot's hash tree.
+    pub root: Option<String>,
+}
+
+/// The outcome of `Hat::verify_snapshot`.
+#[derive(Clone, Debug)]
+pub struct VerifiedSnapshot {
+    /// Hex digest of the root of the snapshot's hash tree, recomputed from stored data.
+    pub root: String,
+    /// Chunks read back and checked against their hash, including directory listings.
+    pub chunks: u64,
+    /// File data read back.
+    pub bytes: u64,
+}
+
+/// The order in which a checkout writes files.
+#[derive(Clone, Debug)]
+pub enum RestoreOrder {
+    /// Depth-first, in directory listing order.
+    Listing,
+    /// Smallest files first, so that many files become usable early.
+    SmallestFirst,
+    /// Files matching the first pattern first, then those matching the second, and so on.
+    /// Files matching no pattern come last, in listing order.
+    Patterns(Vec<Pattern>),
+}
+
+impl Default for RestoreOrder {
+    fn default() -> RestoreOrder {
+        RestoreOrder::Listing
+    }
+}
+
+/// What a checkout does with a file that already exists where it would restore one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConflictPolicy {
+    /// Replace the existing file.
+    Overwrite,
+    /// Keep the existing file.
+    SkipExisting,
+    /// Replace the existing file only if it is older than the one in the snapshot.
+    OnlyNewer,
+    /// Rename the existing file to `<name>.~N~`, with the first free N, and restore.
+    BackupExisting,
+}
+
+impl Default for ConflictPolicy {
+    fn default() -> ConflictPolicy {
+        ConflictPolicy::Overwrite
+    }
+}
+
+impl ConflictPolicy {
+    /// Clear the way for restoring `info` to `output`, or return false if the file that is
+    /// there should be kept. Existing directories are left for `restore_content` to fail on.
+    fn make_room(&self, output: &Path, info: &key::Info) -> Result<bool, HatError> {
+        let meta = match fs::symlink_metadata(output) {
+            Ok(meta) => meta,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(true),
+            Err(e) => return Err(From::from(e)),
+        };
+        if meta.is_dir() {
+            return Ok(true);
+        }
+        match *self {
+            ConflictPolicy::Overwrite => (),
+            ConflictPolicy::SkipExisting => return Ok(false),
+            ConflictPolicy::OnlyNewer => {
+                let mtime = filetime::FileTime::from_last_modification_time(&meta);
+                match info.modified_ts_secs {
+                    Some(m) if m > mtime.seconds_relative_to_1970() => (),
+                    _ => return Ok(false),
+                }
+            }
+            ConflictPolicy::BackupExisting => {
+                let backup = |n: u64| {
+                    let mut name = output.as_os_str().to_owned();
+                    name.push(format!(".~{}~", n));
+                    PathBuf::from(name)
+                };
+                let mut n = 1;
+                while fs::symlink_metadata(backup(n)).is_ok() {
+                    n += 1;
+                }
+                fs::rename(output, backup(n))?;
+                return Ok(true);
+            }
+        }
+        // Remove rather than truncate, so that links are replaced instead of followed.
+        fs::remove_file(output)?;
+        Ok(true)
+    }
+}
+
+/// What a checkout restores, and how.
+#[derive(Clone, Debug, Default)]
+pub struct RestoreOptions {
+    pub order: RestoreOrder,
+    /// Restore only this file or directory of the snapshot, given by its absolute path in the
+    /// snapshot. It is written to the same place below the output directory as it would be
+    /// by a full checkout.
+    pub subpath: Option<PathBuf>,
+    /// If not empty, restore only files that match one of these patterns or are below a
+    /// directory that does. Patterns are matched against paths from the snapshot root, such as
+    /// `home/user/notes.txt`.
+    pub include: Vec<Pattern>,
+    /// Do not restore files or directories that match one of these patterns.
+    pub exclude: Vec<Pattern>,
+    /// What to do with files that already exist in the output directory.
+    pub conflict: ConflictPolicy,
+    /// Number of files to restore at once, each by its own worker that fetches, decrypts and
+    /// writes the file's chunks in order. Up to 1, files are restored one at a time.
+    pub jobs: usize,
+    /// Do not restore extended attributes, e.g. when the output file system lacks support.
+    pub skip_xattrs: bool,
+    /// Do not restore POSIX ACLs, leaving only the permission bits.
+    pub skip_acls: bool,
+    /// Leave restored files owned by the user restoring them, e.g. when not running as root.
+    pub skip_owner: bool,
+    /// Restore the recorded user and group ids as they are, instead of looking up the recorded
+    /// user and group names on this system.
+    pub numeric_ids: bool,
+    /// Owners to restore instead of those recorded; takes precedence over names and ids.
+    pub chown_map: util::ChownMap,
+}
+
+/// The directories above `path`, nearest first.
+fn ancestors(path: &[u8]) -> Vec<&[u8]> {
+    path.iter()
+        .enumerate()
+        .rev()
+        .filter(|&(_, c)| *c == b'/')
+        .map(|(i, _)| &path[..i])
+        .collect()
+}
+
+impl RestoreOptions {
+    /// The user and group ids to give a restored file.
+    fn owner(&self, info: &key::Info) -> (Option<u64>, Option<u64>) {
+        let user_name = info.user_name.as_ref().map(|s| &s[..]);
+        let group_name = info.group_name.as_ref().map(|s| &s[..]);
+        let (by_user_name, by_group_name) = if self.numeric_ids {
+            (None, None)
+        } else {
+            (user_name.and_then(util::user_id), group_name.and_then(util::group_id))
+        };
+        (
+            self.chown_map.user(info.user_id, user_name).or(by_user_name).or(info.user_id),
+            self.chown_map.group(info.group_id, group_name).or(by_group_name).or(info.group_id),
+        )
+    }
+
+    fn is_filtered(&self) -> bool {
+        !self.include.is_empty() || !self.exclude.is_empty()
+    }
+
+    fn included(&self, path: &[u8]) -> bool {
+        self.include.is_empty() ||
+            self.include.iter().any(|p| {
+                p.matches(path) || ancestors(path).into_iter().any(|a| p.matches(a))
+            })
+    }
+
+    fn wants_file(&self, path: &Path) -> bool {
+        let path = path.as_os_str().as_bytes();
+        !self.exclude.iter().any(|p| p.matches(path)) && self.included(path)
+    }
+
+    /// Whether anything below directory `path` may be restored.
+    fn wants_dir(&self, path: &Path) -> bool {
+        let path = path.as_os_str().as_bytes();
+        !self.exclude.iter().any(|p| p.matches(path)) &&
+            (self.included(path) || self.include.iter().any(|p| p.may_match_below(path)))
+    }
+}
+
+impl RestoreOrder {
+    fn schedule(&self, files: &mut Vec<(PathBuf, key::Entry, walker::Content)>) {
+        match *self {
+            RestoreOrder::Listing => (),
+            RestoreOrder::SmallestFirst => {
+                files.sort_by_key(|&(_, ref entry, ref content)| match *content {
+                    walker::Content::Inline(ref bytes) => bytes.len() as u64,
+                    walker::Content::Link(_) => 0,
+                    _ => entry.info.byte_length.unwrap_or(u64::max_value()),
+                })
+            }
+            RestoreOrder::Patterns(ref patterns) => {
+                files.sort_by_key(|&(ref path, _, _)| {
+                    let bytes = path.as_os_str().as_bytes();
+                    patterns.iter().position(|p| p.matches(bytes)).unwrap_or(
+                        patterns.len(),
+                    )
+                })
+            }
+        }
+    }
+}
+
+fn fidelity_config(family: &str) -> String {
+    format!("fidelity:{}", family)
+}
+
+fn resume_appends_config(family: &str) -> String {
+    format!("resume_appends:{}", family)
+}
+
+/// Present while a snapshot is being restored, to keep it from being deleted meanwhile.
+fn restore_marker_config(family: &str, snapshot_id: u64) -> String {
+    format!("restoring:{}:{}", family, snapshot_id)
+}
+
+/// The files written so far by a restore, by the hash of their data, so that files with the
+/// same data can share it on disk instead of writing it again.
+#[derive(Default)]
+struct RestoredFiles(Mutex<HashMap<Vec<u8>, PathBuf>>);
+
+/// Write the contents of a single non-directory entry to `output`.
+fn restore_content<B: StoreBackend>(
+    backend: key::HashStoreBackend<B>,
+    output: &Path,
+    info: &key::Info,
+    content: walker::Content,
+    restored: &RestoredFiles,
+    progress: &Progress,
+) -> Result<(), HatError> {
+    progress.file(output);
+    match content {
+        walker::Content::Data(hash_ref) => {
+            let earlier = restored.0.lock().unwrap().get(&hash_ref.hash.bytes).cloned();
+            if let Some(earlier) = earlier {
+                match util::reflink(&earlier, output) {
+                    Ok(()) => return Ok(()),
+                    Err(e) => debug!("Could not share data of {:?}: {}", earlier, e),
+                }
+            }
+            let mut fd = restore_file(output, info)?;
+            if let Some(mut tree) = hash::tree::LeafIterator::new(backend, hash_ref)? {
+                while let Some(chunk) = tree.try_next()? {
+                    fd.write_all(&chunk[..])?;
+                    progress.read(chunk.len() as u64);
+                }
+            }
+            finish_file(fd, info)?;
+            restored.0.lock().unwrap().insert(hash_ref.hash.bytes.clone(), output.to_owned());
+        }
+        walker::Content::Link(link_path) => {
+            use std::os::unix::fs::symlink;
+            symlink(link_path, output)?
+        }
+        walker::Content::Inline(bytes) => {
+            let mut fd = restore_file(output, info)?;
+            fd.write_all(&bytes[..])?;
+            finish_file(fd, info)?;
+            progress.read(bytes.len() as u64);
+        }
+        walker::Content::Dir(_) => unreachable!("directories are restored by the caller"),
+    }
+    Ok(())
+}
+
+/// Create a file to restore the content of `info` into. The content of a sparse file is written
+/// to its data extents only, leaving holes in between.
+fn restore_file(output: &Path, info: &key::Info) -> io::Result<util::ExtentWriter<fs::File>> {
+    // A file without holes is a single extent.
+    let extents = info.sparse_map.clone().unwrap_or_else(|| vec![(0, u64::max_value())]);
+    Ok(util::ExtentWriter::new(fs::File::create(output)?, extents))
+}
+
+fn finish_file(fd: util::ExtentWriter<fs::File>, info: &key::Info) -> io::Result<()> {
+    match info.sparse_map {
+        // Extend the file past any trailing hole.
+        Some(ref extents) => fd.finish(info.byte_length.unwrap_or_else(|| sparse_end(extents))),
+        None => fd.into_inner().flush(),
+    }
+}
+
+/// The end of the last data extent of a sparse file, for files stored without their length.
+fn sparse_end(extents: &[(u64, u64)]) -> u64 {
+    extents.last().map_or(0, |&(offset, len)| offset + len)
+}
+
+/// Restore owner, permissions, timestamps, extended attributes and ACLs of a checked out file or
+/// directory, as far as the family's fidelity level and the restore options allow.
+fn restore_metadata(
+    output: &Path,
+    info: &key::Info,
+    fidelity: key::Fidelity,
+    options: &RestoreOptions,
+) -> Result<(), HatError> {
+    if fidelity < key::Fidelity::Permissions {
+        return Ok(());
+    }
+
+    // First, as changing the owner clears set-id bits and file capabilities.
+    if fidelity >= key::Fidelity::Ownership && !options.skip_owner {
+        let (uid, gid) = options.owner(info);
+        if uid.is_some() || gid.is_some() {
+            if let Err(e) = util::lchown(output, uid, gid) {
+                warn!("Could not restore owner of {}: {}", output.display(), e);
+            }
+        }
+    }
+
+    // Before permissions, which may make the file read-only.
+    if fidelity >= key::Fidelity::Extended && !options.skip_xattrs {
+        for &(ref name, ref value) in &info.xattrs {
+            // Security attributes may need privileges; restore those that are permitted.
+            if let Err(e) = xattr::set(output, OsStr::from_bytes(name), value) {
+                warn!(
+                    "Could not restore extended attribute {} of {}: {}",
+                    String::from_utf8_lossy(name),
+                    output.display(),
+                    e
+                );
+            }
+        }
+    }
+
+    // Symbolic links keep their own timestamps, but have no permissions of their own; setting
+    // either through the link would change its target instead.
+    let is_link = fs::symlink_metadata(output)?.file_type().is_symlink();
+    if let Some(ref perms) = info.permissions {
+        if !is_link {
+            fs::set_permissions(output, perms.clone())?;
+        }
+    }
+
+    // After permissions, as changing the mode rewrites the access ACL.
+    if fidelity >= key::Fidelity::Extended && !options.skip_acls && !is_link {
+        let acls = [
+            (util::ACL_ACCESS_XATTR, &info.acl),
+            (util::ACL_DEFAULT_XATTR, &info.default_acl),
+        ];
+        for &(name, acl) in &acls {
+            if let Some(ref text) = *acl {
+                let value = util::acl_to_xattr(text)?;
+                if let Err(e) = xattr::set(output, name, &value) {
+                    warn!("Could not restore ACL of {}: {}", output.display(), e);
+                }
+            }
+        }
+    }
+
+    if let Some(m) = info.modified_ts_secs {
+        // Without a recorded access time, use the modification time for both.
+        let a = match fidelity {
+            key::Fidelity::Forensic => info.accessed_ts_secs.unwrap_or(m),
+            _ => m,
+        };
+        let atime = filetime::FileTime::from_seconds_since_1970(a, 0 /* nanos */);
+        let mtime = filetime::FileTime::from_seconds_since_1970(m, 0 /* nanos */);
+        if is_link {
+            filetime::set_symlink_file_times(output, atime, mtime)?;
+        } else {
+            filetime::set_file_times(output, atime, mtime)?;
+        }
+    }
+    Ok(())
+}
+
+/// Take the files that are further hard links to a file listed before them out of `files`,
+/// as the link, its entry and the path of the first file.
+fn split_hardlinks(
+    files: &mut Vec<(PathBuf, key::Entry, walker::Content)>,
+) -> Vec<(PathBuf, key::Entry, PathBuf)> {
+    let mut first = HashMap::new();
+    let mut links = vec![];
+    files.retain(|&(ref path, ref entry, _)| match entry.info.hardlink_id {
+        None => true,
+        Some(id) => {
+            match first.get(&id).cloned() {
+                Some(target) => {
+                    links.push((path.clone(), entry.clone(), target));
+                    false
+                }
+                None => {
+                    first.insert(id, path.clone());
+                    true
+                }
+            }
+        }
+    });
+    links
+}
+
 fn concat_filename(mut a: PathBuf, b: &str) -> String {
     a.push(b);
     a.into_os_string().into_string().unwrap()
@@ -143,6 +671,69 @@ fn hash_index_name(root: PathBuf) -> String {
     concat_filename(root, "hash_index.sqlite3")
 }
 
+/// What the signature of a snapshot covers: its family, its id and its root hash.
+fn signed_message(family_name: &str, snapshot_id: u64, hash: &hash::Hash) -> Vec<u8> {
+    let mut msg = b"hat-snapshot:".to_vec();
+    msg.extend_from_slice(family_name.as_bytes());
+    msg.push(0);
+    msg.extend_from_slice(snapshot_id.to_string().as_bytes());
+    msg.push(0);
+    msg.extend_from_slice(&hash.bytes[..]);
+    msg
+}
+
+/// The unused hashes that a gc run with a grace period keeps in the index until their blob is
+/// deleted, so that a commit can still take them up again.
+#[derive(Default)]
+struct KeptHashes {
+    hashes: HashSet<hash::Hash>,
+    by_blob: HashMap<Vec<u8>, Vec<gc::Id>>,
+    parents: HashMap<gc::Id, Vec<gc::Id>>,
+    taken: HashSet<gc::Id>,
+}
+
+impl KeptHashes {
+    fn new(hash_index: &hash::HashIndex, ids: Vec<gc::Id>) -> KeptHashes {
+        let mut kept = KeptHashes::default();
+        for id in ids {
+            let entry = match hash_index.get_hash(id) {
+                Some(entry) => entry,
+                None => continue,
+            };
+            for child in entry.childs.unwrap_or_default() {
+                kept.parents.entry(child).or_insert_with(Vec::new).push(id);
+            }
+            if let Some(pref) = entry.persistent_ref {
+                kept.by_blob.entry(pref.blob_name).or_insert_with(Vec::new).push(id);
+            }
+            kept.hashes.insert(entry.hash);
+        }
+        kept
+    }
+
+    fn contains(&self, hash: &hash::Hash) -> bool {
+        self.hashes.contains(hash)
+    }
+
+    /// The kept hashes to delete along with the blob `name`: those stored in it, and every
+    /// kept hash that refers to them, parents first. Hashes are only handed out once.
+    fn take_doomed(&mut self, name: &[u8]) -> Vec<gc::Id> {
+        let mut doomed = vec![];
+        let mut todo: Vec<gc::Id> = self.by_blob.remove(name).unwrap_or_default();
+        let mut next = 0;
+        while next < todo.len() {
+            let id = todo[next];
+            next += 1;
+            if self.taken.insert(id) {
+                doomed.push(id);
+                todo.extend(self.parents.get(&id).cloned().unwrap_or_default());
+            }
+        }
+        doomed.reverse();
+        doomed
+    }
+}
+
 fn synthetic_roots_family() -> String {
     From::from("__hat__roots__")
 }
@@ -205,29 +796,231 @@ fn list_snapshot<'a, B: StoreBackend>(
 }
 
 
+/// Pick the hash algorithm for a repository.
+///
+/// The algorithm recorded in the repository always wins; asking for a different one is an error.
+/// A repository without a recorded algorithm is assumed to predate the setting and uses the
+/// requested algorithm if it has no hashes yet, or the original algorithm otherwise.
+fn select_hash_algorithm(
+    db: &db::Index,
+    requested: Option<HashAlgorithm>,
+) -> Result<HashAlgorithm, HatError> {
+    let mut index = db.lock();
+    match index.config_get(HASH_ALGORITHM_CONFIG) {
+        Some(stored) => {
+            let stored = stored.parse::<HashAlgorithm>()?;
+            match requested {
+                Some(r) if r != stored => Err(From::from(format!(
+                    "Repository uses hash algorithm {}, not {}",
+                    stored.as_str(),
+                    r.as_str()
+                ))),
+                _ => Ok(stored),
+            }
+        }
+        None => {
+            let algorithm = if !index.hash_any() {
+                requested.unwrap_or_default()
+            } else {
+                match requested {
+                    Some(r) if r != HashAlgorithm::default() => {
+                        return Err(From::from(format!(
+                            "Existing repository uses hash algorithm {}, not {}",
+                            HashAlgorithm::default().as_str(),
+                            r.as_str()
+                        )))
+                    }
+                    _ => HashAlgorithm::default(),
+                }
+            };
+            index.config_set(HASH_ALGORITHM_CONFIG, algorithm.as_str());
+            index.flush();
+            Ok(algorithm)
+        }
+    }
+}
+
+/// Tags for objects written by this run, under the repository's ID.
+///
+/// The repository ID is generated once and kept in the index. The run is identified by the
+/// time it started.
+fn object_tags_for_run(db: &db::Index) -> ObjectTags {
+    let mut index = db.lock();
+    let repository = match index.config_get(REPOSITORY_ID_CONFIG) {
+        Some(id) => id,
+        None => {
+            let id = crypto::keys::random_bytes(16).unsecure().to_hex();
+            index.config_set(REPOSITORY_ID_CONFIG, &id);
+            index.flush();
+            id
+        }
+    };
+    let run = chrono::Utc::now().format("%Y%m%dT%H%M%S%.3fZ").to_string();
+    ObjectTags::new(repository, run)
+}
+
+/// The hash tree fanout recorded for a repository.
+fn load_fanout(db: &db::Index) -> Result<usize, HatError> {
+    match db.lock().config_get(FANOUT_CONFIG) {
+        Some(fanout) => {
+            fanout.parse::<usize>().map_err(|e| {
+                From::from(format!("Invalid hash tree fanout {:?}: {}", fanout, e))
+            })
+        }
+        None => Ok(hash::tree::DEFAULT_FANOUT),
+    }
+}
+
+/// Load the per-repository fingerprint secret from the backend, creating it for new repositories.
+///
+/// The secret is sealed with the access key and only ever stored in the backend, so the local
+/// index alone is not enough to test whether known content is part of a backup. Repositories
+/// that already hold hashes from before the secret existed keep using unmixed fingerprints.
+fn load_fingerprint_secret<B: StoreBackend>(
+    db: &db::Index,
+    keys: &crypto::keys::Keeper,
+    backend: &B,
+) -> Result<Option<Vec<u8>>, HatError> {
+    let mut index = db.lock();
+    match index.config_get(FINGERPRINT_SECRET_CONFIG).as_ref().map(|s| &s[..]) {
+        Some("backend") => {
+            let sealed = backend.retrieve(FINGERPRINT_SECRET_NAME)?.ok_or(
+                "Fingerprint secret is missing from the backend",
+            )?;
+            Ok(Some(keys.access_unlock(&sealed).map_err(|_| WRONG_PASSPHRASE)?))
+        }
+        Some(_) => Ok(None),
+        None => {
+            if let Some(sealed) = backend.retrieve(FINGERPRINT_SECRET_NAME)? {
+                // A fresh index for an existing repository.
+                index.config_set(FINGERPRINT_SECRET_CONFIG, "backend");
+                index.flush();
+                return Ok(Some(keys.access_unlock(&sealed).map_err(|_| WRONG_PASSPHRASE)?));
+            }
+            if index.hash_any() {
+                index.config_set(FINGERPRINT_SECRET_CONFIG, "none");
+                index.flush();
+                return Ok(None);
+            }
+
+            let secret = crypto::keys::random_bytes(32).unsecure().to_vec();
+            backend.store(
+                FINGERPRINT_SECRET_NAME,
+                &crypto::CipherText::new(keys.access_lock(&secret)),
+            )?;
+            backend.flush()?;
+            index.config_set(FINGERPRINT_SECRET_CONFIG, "backend");
+            index.flush();
+            Ok(Some(secret))
+        }
+    }
+}
+
 impl<B: StoreBackend> HatRc<B> {
+    /// Open a repository created with `init_repository`, with the passphrase it was created
+    /// with. It fails if the repository has not been initialized, or was written with
+    /// parameters that this hat does not use.
     pub fn open_repository(
         migrations_dir: &Path,
         repository_root: PathBuf,
         backend: Arc<B>,
         max_blob_size: usize,
+        hash_algorithm: Option<HashAlgorithm>,
+        passphrase: Option<&[u8]>,
+    ) -> Result<HatRc<B>, HatError> {
+        let manifest = Manifest::load(&*backend)?.ok_or(
+            "The repository has not been initialized; run `hat init` first",
+        )?;
+        let hash_algorithm = match hash_algorithm {
+            Some(algorithm) => algorithm,
+            None => manifest.hash_algorithm()?,
+        };
+        manifest.check(&Manifest::current(hash_algorithm))?;
+        if manifest.append_only && !backend.append_only() && !backend.privileged() {
+            return Err(From::from(
+                "The repository is append-only; open it through an append-only backend, or as a \
+                 privileged client to prune it",
+            ));
+        }
+        HatRc::open_indexes(
+            migrations_dir,
+            repository_root,
+            backend,
+            max_blob_size,
+            Some(hash_algorithm),
+            passphrase,
+        )
+    }
+
+    /// Create a repository: write its manifest to `backend` and set up its local indexes. A
+    /// repository written by an older hat, without a manifest, is adopted as it is.
+    ///
+    /// The repository keys are derived from `passphrase`, or from `DEFAULT_PASSPHRASE`; it must
+    /// be given again whenever the repository is opened.
+    pub fn init_repository(
+        migrations_dir: &Path,
+        repository_root: PathBuf,
+        backend: Arc<B>,
+        max_blob_size: usize,
+        hash_algorithm: Option<HashAlgorithm>,
+        passphrase: Option<&[u8]>,
+    ) -> Result<(HatRc<B>, Manifest), HatError> {
+        if Manifest::load(&*backend)?.is_some() {
+            return Err(From::from("The repository has already been initialized"));
+        }
+        let hat = HatRc::open_indexes(
+            migrations_dir,
+            repository_root,
+            backend,
+            max_blob_size,
+            hash_algorithm,
+            passphrase,
+        )?;
+        let mut manifest = Manifest::current(hat.keys.hash_algorithm());
+        manifest.append_only = hat.backend.append_only();
+        manifest.store(&*hat.backend)?;
+        Ok((hat, manifest))
+    }
+
+    fn open_indexes(
+        migrations_dir: &Path,
+        repository_root: PathBuf,
+        backend: Arc<B>,
+        max_blob_size: usize,
+        hash_algorithm: Option<HashAlgorithm>,
+        passphrase: Option<&[u8]>,
     ) -> Result<HatRc<B>, HatError> {
-        let keys = Arc::new(crypto::keys::Keeper::new("hat-master-key"));
-        let migrations_path = migrations_dir.canonicalize().unwrap();
+        let migrations_path = migrations_dir.canonicalize().unwrap_or_else(
+            |_| migrations_dir.to_path_buf(),
+        );
 
         let hash_index_path = hash_index_name(repository_root.clone());
-        let db_p = Arc::new(db::Index::new(&migrations_path, &hash_index_path)?);
+        let mut keeper = crypto::keys::Keeper::new(passphrase.unwrap_or(DEFAULT_PASSPHRASE));
+        let index_key = db::index_key(&keeper);
+        let db_p = Arc::new(db::Index::new(
+            &migrations_path,
+            &hash_index_path,
+            index_key.as_ref().map(|k| k.unsecure()),
+        )?);
+
+        let algorithm = select_hash_algorithm(&db_p, hash_algorithm)?;
+        keeper = keeper.with_hash_algorithm(algorithm);
+        if let Some(secret) = load_fingerprint_secret(&db_p, &keeper, &*backend)? {
+            keeper = keeper.with_fingerprint_secret(&secret);
+        }
+        let keys = Arc::new(keeper);
 
         let si_p = snapshot::SnapshotIndex::new(db_p.clone());
         let hi_p = Arc::new(hash::HashIndex::new(db_p.clone())?);
 
+        let object_tags = object_tags_for_run(&db_p);
+        let fanout = load_fanout(&db_p)?;
+
         let bi_p = Arc::new(blob::BlobIndex::new(keys.clone(), db_p.clone())?);
-        let bs_p = Arc::new(blob::BlobStore::new(
-            keys.clone(),
-            bi_p.clone(),
-            backend.clone(),
-            max_blob_size,
-        ));
+        let bs_p = Arc::new(
+            blob::BlobStore::new(keys.clone(), bi_p.clone(), backend.clone(), max_blob_size)
+                .with_object_tags(object_tags.clone()),
+        );
 
         let gc_backend = GcBackend { hash_index: hi_p.clone() };
         let gc = gc::Gc::new(gc_backend);
@@ -244,6 +1037,18 @@ impl<B: StoreBackend> HatRc<B> {
             blob_index: bi_p,
             blob_store: bs_p,
             blob_max_size: max_blob_size,
+            limits: key::Limits::default(),
+            inline_max: None,
+            progress: Arc::new(SilentProgress),
+            namespace: None,
+            fanout: fanout,
+            hashing_threads: DEFAULT_HASHING_THREADS,
+            root_doc: None,
+            object_tags: object_tags,
+            gc_grace_period: None,
+            allow_unsigned: false,
+            host: lock::this_host(&repository_root)?,
+            lock_watch: None,
             gc: gc,
         };
 
@@ -261,13 +1066,13 @@ impl<B: StoreBackend> HatRc<B> {
         let si_p = snapshot::SnapshotIndex::new(db_p.clone());
         let bi_p = Arc::new(blob::BlobIndex::new(keys.clone(), db_p.clone()).unwrap());
         let hi_p = Arc::new(hash::HashIndex::new(db_p.clone()).unwrap());
+        let object_tags = object_tags_for_run(&db_p);
+        let fanout = load_fanout(&db_p)?;
 
-        let bs_p = Arc::new(blob::BlobStore::new(
-            keys.clone(),
-            bi_p.clone(),
-            backend.clone(),
-            max_blob_size,
-        ));
+        let bs_p = Arc::new(
+            blob::BlobStore::new(keys.clone(), bi_p.clone(), backend.clone(), max_blob_size)
+                .with_object_tags(object_tags.clone()),
+        );
 
         let gc_backend = GcBackend { hash_index: hi_p.clone() };
         let gc = gc::Gc::new(gc_backend);
@@ -283,7 +1088,19 @@ impl<B: StoreBackend> HatRc<B> {
             blob_index: bi_p,
             blob_store: bs_p,
             blob_max_size: max_blob_size,
+            limits: key::Limits::default(),
+            inline_max: None,
+            progress: Arc::new(SilentProgress),
+            namespace: None,
+            fanout: fanout,
+            hashing_threads: DEFAULT_HASHING_THREADS,
+            root_doc: None,
+            object_tags: object_tags,
             backend: backend,
+            gc_grace_period: None,
+            allow_unsigned: false,
+            host: format!("test [{}]", crypto::keys::random_bytes(8).unsecure().to_hex()),
+            lock_watch: None,
             gc: gc,
         };
 
@@ -297,7 +1114,125 @@ impl<B: StoreBackend> HatRc<B> {
         &self,
         leaf: blob::LeafType,
     ) -> hash::tree::SimpleHashTreeWriter<key::HashStoreBackend<B>> {
-        hash::tree::SimpleHashTreeWriter::new(leaf, 8, self.hash_backend())
+        hash::tree::SimpleHashTreeWriter::new(leaf, self.fanout, self.hash_backend())
+    }
+
+    /// Record the number of children per hash tree branch for trees written from now on.
+    ///
+    /// A larger fanout gives shallower trees of large files at the cost of larger branch
+    /// chunks. Trees are readable whatever their fanout, so the setting can be changed at any
+    /// time; it takes effect for families opened after this call.
+    pub fn set_fanout(&mut self, fanout: usize) -> Result<(), HatError> {
+        if fanout < 2 || fanout > 4096 {
+            return Err(From::from(format!("Unsupported hash tree fanout: {}", fanout)));
+        }
+        let mut index = self.db.lock();
+        index.config_set(FANOUT_CONFIG, &fanout.to_string());
+        index.flush();
+        self.fanout = fanout;
+        Ok(())
+    }
+
+    /// Set the size limits enforced on families opened after this call.
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+
+    /// Read and hash at most `threads` files of a family at once, for families opened after this
+    /// call. Fewer threads make a commit slower, but easier on an interactive machine.
+    pub fn set_hashing_threads(&mut self, threads: usize) {
+        self.hashing_threads = cmp::max(1, threads);
+    }
+
+    /// Keep files of at most `max` bytes inline in directory listings, for families opened
+    /// after this call.
+    pub fn set_inline_max(&mut self, max: Option<usize>) {
+        self.inline_max = max;
+    }
+
+    /// Have `gc` condemn unused blobs rather than delete them, and delete them in a later run
+    /// once they have stayed unused for `period`. Until then they can still be read.
+    pub fn set_gc_grace_period(&mut self, period: Option<time::Duration>) {
+        self.gc_grace_period = period;
+    }
+
+    /// Accept snapshots without a signature, e.g. those committed before snapshots were
+    /// signed. They are refused by default, as a snapshot substituted in the backend could
+    /// simply leave out its signature.
+    pub fn set_allow_unsigned(&mut self, allow: bool) {
+        self.allow_unsigned = allow;
+    }
+
+    /// Report the progress of commits, checkouts and gc to `progress`, for families opened
+    /// after this call.
+    pub fn set_progress(&mut self, progress: Arc<Progress>) {
+        self.progress = progress;
+    }
+
+    /// The metadata fidelity of a family. Families without a setting keep all metadata.
+    pub fn fidelity(&self, family: &str) -> Result<key::Fidelity, HatError> {
+        match self.db.lock().config_get(&fidelity_config(family)) {
+            Some(level) => Ok(level.parse::<key::Fidelity>()?),
+            None => Ok(key::Fidelity::default()),
+        }
+    }
+
+    /// Set the metadata fidelity of a family, for commits and checkouts of families opened
+    /// after this call.
+    pub fn set_fidelity(&mut self, family: &str, fidelity: key::Fidelity) {
+        let mut index = self.db.lock();
+        index.config_set(&fidelity_config(family), fidelity.as_str());
+        index.flush();
+    }
+
+    /// Whether grown files of a family are taken to have only been appended to, so that only
+    /// their new data is read; see `key::Store::with_resume_appends`. Off unless set.
+    pub fn resume_appends(&self, family: &str) -> bool {
+        self.db.lock().config_get(&resume_appends_config(family)).map_or(false, |v| v == "true")
+    }
+
+    /// Set whether grown files of a family are taken to have only been appended to, for
+    /// commits of families opened after this call. Only for families of files that are never
+    /// changed in place, such as logs.
+    pub fn set_resume_appends(&mut self, family: &str, resume: bool) {
+        let mut index = self.db.lock();
+        index.config_set(&resume_appends_config(family), &resume.to_string());
+        index.flush();
+    }
+
+    /// Keep the families of this machine apart from those of other machines backing up into
+    /// the same repository. Chunks are only deduplicated against the local indexes of this
+    /// machine, so data stored from another namespace is stored again.
+    pub fn set_namespace(&mut self, namespace: Option<String>) -> Result<(), HatError> {
+        if let Some(ref ns) = namespace {
+            if ns.is_empty() || ns.contains(NAMESPACE_SEPARATOR) || ns.starts_with("__hat__") {
+                return Err(From::from(format!("Invalid namespace: {:?}", ns)));
+            }
+        }
+        self.namespace = namespace;
+        Ok(())
+    }
+
+    pub fn namespace(&self) -> Option<&str> {
+        self.namespace.as_ref().map(|ns| &ns[..])
+    }
+
+    /// The hash algorithm of this repository; a repository receiving its blobs must use it too.
+    pub fn hash_algorithm(&self) -> HashAlgorithm {
+        self.keys.hash_algorithm()
+    }
+
+    /// The stored name of `family` within the current namespace.
+    pub fn family_name(&self, family: &str) -> String {
+        match self.namespace {
+            Some(ref ns) => format!("{}{}{}", ns, NAMESPACE_SEPARATOR, family),
+            None => family.to_string(),
+        }
+    }
+
+    /// The provider-side tags attached to objects written by this process.
+    pub fn object_tags(&self) -> &ObjectTags {
+        &self.object_tags
     }
 
     pub fn open_family(&mut self, name: String) -> Result<Family<B>, HatError> {
@@ -312,28 +1247,53 @@ impl<B: StoreBackend> HatRc<B> {
         }
 
         let key_index_path = match self.repository_root {
-            Some(ref root) => concat_filename(root.clone(), &name),
+            Some(ref root) => {
+                // Namespaced families keep their key indexes in a directory per namespace.
+                let path = root.join(&name);
+                if let Some(dir) = path.parent() {
+                    fs::create_dir_all(dir)?;
+                }
+                concat_filename(root.clone(), &name)
+            }
             None => ":memory:".to_string(),
         };
 
-        let ki_p = Arc::new(key::KeyIndex::new(&self.migrations_dir, &key_index_path)?);
+        let index_key = match self.repository_root {
+            Some(_) => db::index_key(&self.keys),
+            None => None,
+        };
+        let ki_p = Arc::new(key::KeyIndex::new(
+            &self.migrations_dir,
+            &key_index_path,
+            index_key.as_ref().map(|k| k.unsecure()),
+        )?);
+        let commit_bytes = Arc::new(AtomicUsize::new(0));
+        let new_bytes = Arc::new(AtomicUsize::new(0));
+        let fidelity = self.fidelity(&name)?;
+        let resume_appends = self.resume_appends(&name);
 
         let mut kss = vec![];
-        for _ in 0..2 {
+        for _ in 1..self.hashing_threads {
             // To avoid mixing chunks from different files, each key store gets its own dedicated
             // blob store.
-            let bs = Arc::new(blob::BlobStore::new(
-                self.keys.clone(),
-                self.blob_index.clone(),
-                self.backend.clone(),
-                self.blob_max_size,
+            let bs = Arc::new(
+                blob::BlobStore::new(
+                    self.keys.clone(),
+                    self.blob_index.clone(),
+                    self.backend.clone(),
+                    self.blob_max_size,
+                ).with_object_tags(self.object_tags.clone()),
+            );
+            kss.push(Process::new(
+                key::Store::new(ki_p.clone(), self.hash_index.clone(), bs, self.keys.clone())
+                    .with_limits(self.limits, commit_bytes.clone())
+                    .with_new_bytes(new_bytes.clone())
+                    .with_inline_max(self.inline_max)
+                    .with_fidelity(fidelity)
+                    .with_resume_appends(resume_appends)
+                    .with_fanout(self.fanout)
+                    .with_progress(self.progress.clone()),
             ));
-            kss.push(Process::new(key::Store::new(
-                ki_p.clone(),
-                self.hash_index.clone(),
-                bs,
-                self.keys.clone(),
-            )));
         }
 
         let ks = key::Store::new(
@@ -341,19 +1301,73 @@ impl<B: StoreBackend> HatRc<B> {
             self.hash_index.clone(),
             self.blob_store.clone(),
             self.keys.clone(),
-        );
+        ).with_limits(self.limits, commit_bytes)
+            .with_new_bytes(new_bytes)
+            .with_inline_max(self.inline_max)
+            .with_fidelity(fidelity)
+            .with_resume_appends(resume_appends)
+            .with_fanout(self.fanout)
+            .with_progress(self.progress.clone());
         kss.push(Process::new(ks.clone()));
 
         let family = Family {
             name: name.clone(),
+            fidelity: fidelity,
             key_store: ks,
             key_store_process: kss,
+            provenance: None,
+            labels: vec![],
+            checkpoint_interval: Some(time::Duration::minutes(1)),
+            one_file_system: false,
+            follow_symlinks: false,
+            low_impact: false,
+            excludes: vec![],
+            errors: Arc::new(Mutex::new(vec![])),
+            progress: self.progress.clone(),
         };
         self.families.push(family.clone());
 
         Ok(family)
     }
 
+    /// Check, reindex and vacuum the hash index and the key index of every family, reporting
+    /// the size of each index before and after.
+    pub fn maintain_indexes(&mut self) -> Result<Vec<IndexReport>, HatError> {
+        let mut reports = vec![self.db.lock().maintain()?];
+        let names = self.db.lock().family_list();
+        for name in names {
+            let family = self.open_family(name)?;
+            reports.push(family.key_store.maintain_index()?);
+        }
+        Ok(reports)
+    }
+
+    /// Report what the loaded key material allows us to do with this repository.
+    pub fn permissions(&mut self) -> Permissions {
+        let keys = self.keys.capabilities();
+        let access = match (keys.read && keys.read_names, keys.write) {
+            (true, true) => AccessLevel::Admin,
+            (true, false) => AccessLevel::ReadOnly,
+            (false, true) => AccessLevel::AppendOnly,
+            (false, false) => AccessLevel::NoAccess,
+        };
+
+        let mut names: Vec<String> = self.snapshot_index
+            .list_all()
+            .into_iter()
+            .map(|s| s.family_name)
+            .filter(|name| *name != synthetic_roots_family())
+            .collect();
+        names.sort();
+        names.dedup();
+
+        Permissions {
+            access: access,
+            keys: keys,
+            families: names.into_iter().map(|name| (name, keys.read)).collect(),
+        }
+    }
+
     pub fn delete_all_snapshots(&mut self) -> Result<(), HatError> {
         // This function deletes ALL snapshots from ALL families, including meta snapshots used
         // for recovery. After calling this function and running the GC, all blobs should be gone.
@@ -364,22 +1378,163 @@ impl<B: StoreBackend> HatRc<B> {
         Ok(())
     }
 
-    pub fn meta_commit(&mut self) -> Result<(), HatError> {
-        let all_snapshots = self.snapshot_index.list_all();
+    /// Give a family a new name. Its snapshots keep their ids and data; the next `meta_commit`
+    /// records them under the new name.
+    pub fn rename_family(&mut self, from: &str, to: &str) -> Result<(), HatError> {
+        self.check_family_idle(from)?;
+        if to == synthetic_roots_family() || self.db.lock().family_id_from_name(to).is_some() {
+            return Err(From::from(format!("Family {} already exists", to)));
+        }
 
-        let mut message = capnp::message::Builder::new_default();
-        let mut all_root_ids = vec![];
+        self.close_family(from)?;
+        for (old, new) in self.key_index_files(from).into_iter().zip(self.key_index_files(to)) {
+            if old.exists() {
+                if let Some(dir) = new.parent() {
+                    fs::create_dir_all(dir)?;
+                }
+                fs::rename(old, new)?;
+            }
+        }
 
         {
-            let root = message.init_root::<root_capnp::snapshot_list::Builder>();
-            let mut snapshots = root.init_snapshots(all_snapshots.len() as u32);
+            let mut index = self.db.lock();
+            index.family_rename(from, to);
+            if let Some(level) = index.config_get(&fidelity_config(from)) {
+                index.config_set(&fidelity_config(to), &level);
+                index.config_remove(&fidelity_config(from));
+            }
+            if let Some(resume) = index.config_get(&resume_appends_config(from)) {
+                index.config_set(&resume_appends_config(to), &resume);
+                index.config_remove(&resume_appends_config(from));
+            }
+        }
 
-            for (i, snapshot) in all_snapshots.into_iter().enumerate() {
-                let mut s = snapshots.borrow().get(i as u32);
-                s.set_id(snapshot.info.snapshot_id);
-                s.set_family_name(&snapshot.family_name);
-                s.set_msg(&snapshot.msg.unwrap_or("".to_owned()));
-                s.set_utc_timestamp(snapshot.created.timestamp());
+        // Snapshots being restored stay protected from deletion under the new name.
+        let ids: Vec<u64> = self.snapshot_index
+            .list_all()
+            .into_iter()
+            .filter(|s| s.family_name == to)
+            .map(|s| s.info.snapshot_id)
+            .collect();
+        {
+            let mut index = self.db.lock();
+            for id in ids {
+                if let Some(since) = index.config_get(&restore_marker_config(from, id)) {
+                    index.config_set(&restore_marker_config(to, id), &since);
+                    index.config_remove(&restore_marker_config(from, id));
+                }
+            }
+        }
+
+        // Signatures cover the family name; sign the snapshots that had a valid one again.
+        for s in self.snapshot_index.list_all().into_iter().filter(|s| s.family_name == to) {
+            if let (Some(hash), Some(signature)) = (s.hash, s.signature) {
+                let id = s.info.snapshot_id;
+                if self.keys.verify_signature(&signed_message(from, id, &hash), &signature) {
+                    let signature = self.keys.sign(&signed_message(to, id, &hash));
+                    self.snapshot_index.set_signature(&s.info, &signature);
+                }
+            }
+        }
+        self.meta_flush();
+        Ok(())
+    }
+
+    /// Delete a family with all of its snapshots, and return how many snapshots it had. Their
+    /// data is reclaimed by the next `gc`.
+    pub fn delete_family(&mut self, name: &str) -> Result<usize, HatError> {
+        self.check_family_idle(name)?;
+
+        let ids: Vec<u64> = self.snapshot_index
+            .list_all()
+            .into_iter()
+            .filter(|s| s.family_name == name)
+            .map(|s| s.info.snapshot_id)
+            .collect();
+        for &id in &ids {
+            self.deregister_by_name(name.to_string(), id)?;
+        }
+
+        self.close_family(name)?;
+        for path in self.key_index_files(name) {
+            if path.exists() {
+                fs::remove_file(path)?;
+            }
+        }
+
+        let mut index = self.db.lock();
+        index.family_delete(name);
+        index.config_remove(&fidelity_config(name));
+        index.flush();
+        Ok(ids.len())
+    }
+
+    /// Refuse to rename or delete a family that does not exist or that has unfinished snapshots.
+    fn check_family_idle(&mut self, name: &str) -> Result<(), HatError> {
+        if name == synthetic_roots_family() || self.db.lock().family_id_from_name(name).is_none() {
+            return Err(From::from(format!("Unknown family: {}", name)));
+        }
+        if self.snapshot_index.list_not_done().iter().any(|s| s.family_name == name) {
+            return Err(From::from(format!(
+                "Family {} has unfinished snapshots; run resume first",
+                name
+            )));
+        }
+        Ok(())
+    }
+
+    /// Flush and close a family, so that its key index can be moved or removed.
+    fn close_family(&mut self, name: &str) -> Result<(), HatError> {
+        if let Some(pos) = self.families.iter().position(|f| f.name == name) {
+            self.families.remove(pos).flush()?;
+        }
+        Ok(())
+    }
+
+    /// The files of a family's key index, including the SQLite write-ahead log.
+    fn key_index_files(&self, name: &str) -> Vec<PathBuf> {
+        match self.repository_root {
+            Some(ref root) => {
+                let path = concat_filename(root.clone(), name);
+                vec![
+                    PathBuf::from(format!("{}-wal", path)),
+                    PathBuf::from(format!("{}-shm", path)),
+                    PathBuf::from(path),
+                ]
+            }
+            None => vec![],
+        }
+    }
+
+    pub fn meta_commit(&mut self) -> Result<(), HatError> {
+        self.check_lock()?;
+        let all_snapshots = self.snapshot_index.list_all();
+
+        let mut message = capnp::message::Builder::new_default();
+        let mut all_root_ids = vec![];
+
+        {
+            let root = message.init_root::<root_capnp::snapshot_list::Builder>();
+            let mut snapshots = root.init_snapshots(all_snapshots.len() as u32);
+
+            for (i, snapshot) in all_snapshots.into_iter().enumerate() {
+                let mut s = snapshots.borrow().get(i as u32);
+                s.set_id(snapshot.info.snapshot_id);
+                s.set_family_name(&snapshot.family_name);
+                s.set_msg(&snapshot.msg.unwrap_or("".to_owned()));
+                s.set_utc_timestamp(snapshot.created.timestamp());
+                if let Some(ref provenance) = snapshot.provenance {
+                    provenance.populate_msg(s.borrow().init_provenance());
+                }
+                {
+                    let mut labels = s.borrow().init_labels(snapshot.labels.len() as u32);
+                    for (j, label) in snapshot.labels.iter().enumerate() {
+                        labels.set(j as u32, label);
+                    }
+                }
+                if let Some(ref signature) = snapshot.signature {
+                    s.set_signature(signature);
+                }
                 let hash_ref = snapshot.hash_ref.unwrap();
                 hash::tree::HashRef::from_bytes(&mut hash_ref.as_ref())?
                     .populate_msg(s.init_hash_ref());
@@ -416,6 +1571,11 @@ impl<B: StoreBackend> HatRc<B> {
         self.meta_flush();
         self.commit_finalize(snap_info, &top_ref.hash)?;
 
+        // Point the root document at the new listing and the latest snapshot of each family.
+        // This is done in two phases, so that `resume` can finish it after a crash.
+        self.prepare_root_commit(&top_ref);
+        self.complete_root_commit(top_ref)?;
+
         // Delete old root snapshots, but always keep the past 10.
         // FIXME(jos): Number of meta snapshots to keep to be configurable.
         all_root_ids.sort();
@@ -426,11 +1586,236 @@ impl<B: StoreBackend> HatRc<B> {
         Ok(())
     }
 
+    /// List completed snapshots of all families, oldest first within each family.
+    /// With a namespace set, only the families of that namespace are listed.
+    pub fn list_snapshots(&mut self) -> Vec<SnapshotSummary> {
+        self.list_snapshot_history()
+            .into_iter()
+            .filter(|s| s.state == SnapshotState::Complete)
+            .collect()
+    }
+
+    /// Like `list_snapshots`, but also list snapshots that are being committed or deleted.
+    pub fn list_snapshot_history(&mut self) -> Vec<SnapshotSummary> {
+        let prefix = self.family_name("");
+        let mut out: Vec<SnapshotSummary> = self.snapshot_index
+            .list_all()
+            .into_iter()
+            .filter(|s| s.family_name != synthetic_roots_family())
+            .filter(|s| s.family_name.starts_with(&prefix))
+            .map(|s| {
+                let state = match s.status {
+                    db::SnapshotWorkStatus::CommitInProgress |
+                    db::SnapshotWorkStatus::RecoverInProgress => SnapshotState::InProgress,
+                    db::SnapshotWorkStatus::CommitComplete => SnapshotState::Complete,
+                    db::SnapshotWorkStatus::DeleteInProgress |
+                    db::SnapshotWorkStatus::DeleteComplete => SnapshotState::Deleting,
+                };
+                SnapshotSummary {
+                    family: s.family_name,
+                    snapshot_id: s.info.snapshot_id,
+                    state: state,
+                    created: s.created,
+                    finished: s.finished,
+                    stats: s.stats,
+                    contents: s.contents,
+                    provenance: s.provenance,
+                    labels: s.labels,
+                    root: s.hash.map(|h| h.bytes.to_hex()),
+                }
+            })
+            .collect();
+        out.sort_by(|a, b| {
+            (&a.family, a.snapshot_id).cmp(&(&b.family, b.snapshot_id))
+        });
+        out
+    }
+
+    /// Read back every chunk of a snapshot and check it against its hash.
+    ///
+    /// Each node of the hash tree commits to the hashes of its children, so when all chunks
+    /// match, the root digest of the snapshot vouches for all of its data.
+    pub fn verify_snapshot(
+        &mut self,
+        family_name: &str,
+        snapshot_id: u64,
+    ) -> Result<VerifiedSnapshot, HatError> {
+        let (root, href) = match self.snapshot_index.lookup(family_name, snapshot_id) {
+            Some((_info, root, Some(href))) => (root, href),
+            _ => {
+                return Err(From::from(format!(
+                    "No complete snapshot {} in family {}",
+                    snapshot_id,
+                    family_name
+                )))
+            }
+        };
+        if href.hash != root {
+            return Err(From::from(format!(
+                "Snapshot {} in family {} has an inconsistent root",
+                snapshot_id,
+                family_name
+            )));
+        }
+        self.check_signature(family_name, snapshot_id)?;
+
+        let mut dir_v = family::recover::DirVisitor::new();
+        let mut file_v = family::verify::FileVisitor::new();
+        let mut walk = walker::Walker::new(self.hash_backend().failing_on_mismatch(), href)?;
+        let mut dir_chunks = 0;
+        while walk.resume(&mut file_v, &mut dir_v)? {
+            dir_chunks += dir_v.nodes().len() as u64;
+        }
+
+        Ok(VerifiedSnapshot {
+            root: root.bytes.to_hex(),
+            chunks: file_v.chunks + dir_chunks,
+            bytes: file_v.bytes,
+        })
+    }
+
+    /// Gather the age and outcome of the latest backup of each family, for monitoring.
+    /// With a namespace set, only the families of that namespace are included.
+    pub fn backup_metrics(&mut self) -> BackupMetrics {
+        let prefix = self.family_name("");
+        let mut families: BTreeMap<String, (u64, FamilyMetrics)> = BTreeMap::new();
+        let mut data_bytes = 0;
+        for s in self.snapshot_index.list_all() {
+            if s.family_name == synthetic_roots_family() || !s.family_name.starts_with(&prefix) {
+                continue;
+            }
+            let complete = match s.status {
+                db::SnapshotWorkStatus::CommitComplete => true,
+                db::SnapshotWorkStatus::CommitInProgress => false,
+                _ => continue,
+            };
+            if complete {
+                data_bytes += s.stats.map_or(0, |stats| stats.bytes_new);
+            }
+
+            let id = s.info.snapshot_id;
+            let entry = families.entry(s.family_name.clone()).or_insert((
+                0,
+                FamilyMetrics {
+                    family: s.family_name,
+                    last_success: None,
+                    last_run_ok: false,
+                },
+            ));
+            if id >= entry.0 {
+                entry.0 = id;
+                entry.1.last_run_ok = complete;
+            }
+            if complete && entry.1.last_success.map_or(true, |ts| ts < s.created) {
+                entry.1.last_success = Some(s.created);
+            }
+        }
+
+        BackupMetrics {
+            families: families.into_iter().map(|(_, (_, f))| f).collect(),
+            blobs: self.blob_store.list_by_tag(tags::Tag::Done).len() as u64,
+            data_bytes: data_bytes,
+        }
+    }
+
+    /// First phase of a root document commit: make the new snapshot listing durable, then
+    /// record that the root document is about to be pointed at it.
+    fn prepare_root_commit(&mut self, meta_ref: &hash::tree::HashRef) {
+        self.blob_store.flush();
+        let mut index = self.db.lock();
+        index.config_set(PENDING_ROOT_CONFIG, &meta_ref.as_bytes().to_hex());
+        index.flush();
+    }
+
+    /// Second phase of a root document commit: write the document and forget the intent.
+    fn complete_root_commit(&mut self, meta_ref: hash::tree::HashRef) -> Result<(), HatError> {
+        self.write_root_doc(meta_ref)?;
+        let mut index = self.db.lock();
+        index.config_remove(PENDING_ROOT_CONFIG);
+        index.flush();
+        Ok(())
+    }
+
+    /// Finish a root document commit that was interrupted. Its listing was made durable before
+    /// the commit started, so the commit is always rolled forward; the document is only
+    /// written if it does not already point at the listing.
+    fn resume_root_commit(&mut self) -> Result<(), HatError> {
+        use hex::FromHex;
+
+        let pending = match self.db.lock().config_get(PENDING_ROOT_CONFIG) {
+            Some(pending) => pending,
+            None => return Ok(()),
+        };
+        let bytes = Vec::from_hex(&pending[..]).map_err(|_| "Invalid pending root reference")?;
+        let meta_ref = hash::tree::HashRef::from_bytes(&mut &bytes[..])?;
+
+        self.root_doc = root::read_latest(&self.keys, &*self.backend)?;
+        if self.root_doc.as_ref().map_or(false, |doc| doc.meta_ref.hash == meta_ref.hash) {
+            let mut index = self.db.lock();
+            index.config_remove(PENDING_ROOT_CONFIG);
+            index.flush();
+            Ok(())
+        } else {
+            info!("Resuming commit of the root document");
+            self.complete_root_commit(meta_ref)
+        }
+    }
+
+    fn write_root_doc(&mut self, meta_ref: hash::tree::HashRef) -> Result<(), HatError> {
+        let mut heads: BTreeMap<String, root::FamilyHead> = BTreeMap::new();
+        for s in self.snapshot_index.list_all() {
+            if s.family_name == synthetic_roots_family() {
+                continue;
+            }
+            let hash_ref = match (&s.status, s.hash_ref) {
+                (&db::SnapshotWorkStatus::CommitComplete, Some(bytes)) => {
+                    hash::tree::HashRef::from_bytes(&mut &bytes[..])?
+                }
+                _ => continue,
+            };
+            if heads.get(&s.family_name).map_or(
+                false,
+                |h| h.snapshot_id > s.info.snapshot_id,
+            )
+            {
+                continue;
+            }
+            heads.insert(
+                s.family_name,
+                root::FamilyHead {
+                    snapshot_id: s.info.snapshot_id,
+                    utc_timestamp: s.created.timestamp(),
+                    hash_ref: hash_ref,
+                },
+            );
+        }
+
+        if self.root_doc.is_none() {
+            self.root_doc = root::read_latest(&self.keys, &*self.backend)?;
+        }
+        let doc = root::write(
+            &self.keys,
+            &*self.backend,
+            &self.object_tags,
+            self.root_doc.as_ref(),
+            meta_ref,
+            heads,
+        )?;
+        self.root_doc = Some(doc);
+        Ok(())
+    }
+
     fn recover_root(&mut self) -> Result<Option<hash::tree::HashRef>, HatError> {
+        // The root document names the latest listing directly.
+        if let Some(doc) = root::read_latest(&self.keys, &*self.backend)? {
+            info!("Using root document version {}", doc.version);
+            return Ok(Some(doc.meta_ref));
+        }
+
         let blobs = self.blob_store.list_by_tag(tags::Tag::Done);
         info!("{} blobs to investigate", blobs.len());
         for b in blobs.into_iter() {
-            info!("Inspecting blob: {}", b.name.to_hex());
+            info!("Inspecting blob: {}", b.name);
             for r in self.blob_store.retrieve_refs(b)?.unwrap_or(vec![]) {
                 match r.leaf {
                     blob::LeafType::SnapshotList => {
@@ -481,6 +1866,15 @@ impl<B: StoreBackend> HatRc<B> {
                 max_created = cmp::max(max_created, created);
 
                 let hash_ref = hash::tree::HashRef::read_msg(&s.get_hash_ref().unwrap()).unwrap();
+                let provenance = if s.has_provenance() {
+                    Some(db::Provenance::read(s.get_provenance()?)?)
+                } else {
+                    None
+                };
+                let mut labels = vec![];
+                for label in s.get_labels()?.iter() {
+                    labels.push(label?.to_string());
+                }
                 self.snapshot_index.recover(
                     s.get_id(),
                     s.get_family_name().unwrap(),
@@ -488,7 +1882,13 @@ impl<B: StoreBackend> HatRc<B> {
                     s.get_msg().unwrap(),
                     &hash_ref,
                     Some(db::SnapshotWorkStatus::RecoverInProgress),
+                    provenance.as_ref(),
+                    &labels[..],
                 );
+                if s.has_signature() {
+                    let family_name = s.get_family_name()?;
+                    self.set_received_signature(family_name, s.get_id(), s.get_signature()?);
+                }
             }
         }
 
@@ -510,6 +1910,8 @@ impl<B: StoreBackend> HatRc<B> {
             "",
             &root_href,
             Some(db::SnapshotWorkStatus::RecoverInProgress),
+            None,
+            &[],
         );
         self.flush_snapshot_index();
         self.resume()?;
@@ -617,6 +2019,8 @@ impl<B: StoreBackend> HatRc<B> {
     }
 
     pub fn resume(&mut self) -> Result<(), HatError> {
+        self.resume_root_commit()?;
+
         let need_work = self.snapshot_index.list_not_done();
 
         for snapshot in need_work {
@@ -643,14 +2047,14 @@ impl<B: StoreBackend> HatRc<B> {
                             self.commit_finalize(snapshot.info, hash)?
                         }
                         (None, db::SnapshotWorkStatus::CommitInProgress) => {
-                            println!("Resuming commit of: {}", snapshot.family_name);
+                            info!("Resuming commit of: {}", snapshot.family_name);
                             self.commit_by_name(
                                 snapshot.family_name,
                                 Some(snapshot.info),
                             )?
                         }
                         (None, db::SnapshotWorkStatus::RecoverInProgress) => {
-                            println!("Resuming recovery of: {}", snapshot.family_name);
+                            info!("Resuming recovery of: {}", snapshot.family_name);
                             let hash_ref_bytes = snapshot.hash_ref.ok_or(
                                 "Recovered hash tree has no root hash",
                             )?;
@@ -687,7 +2091,7 @@ impl<B: StoreBackend> HatRc<B> {
                     match status {
                         None |
                         Some(gc::Status::InProgress) => {
-                            println!(
+                            info!(
                                 "Resuming delete of: {} #{:?}",
                                 snapshot.family_name,
                                 snapshot.info.snapshot_id
@@ -743,6 +2147,11 @@ impl<B: StoreBackend> HatRc<B> {
         family: &mut Family<B>,
         resume_info: Option<db::SnapshotInfo>,
     ) -> Result<(), HatError> {
+        for label in &family.labels {
+            labels::check_label(label)?;
+        }
+        self.check_lock()?;
+
         //  Tag 1:
         //  Reserve the snapshot and commit the reservation.
         //  Register all but the last hashes.
@@ -754,10 +2163,16 @@ impl<B: StoreBackend> HatRc<B> {
                 self.snapshot_index.reserve(family.name.clone())
             }
         };
+        if let Some(ref provenance) = family.provenance {
+            self.snapshot_index.set_provenance(&snap_info, provenance);
+        }
+        for label in &family.labels {
+            self.snapshot_index.add_label(&snap_info, label);
+        }
         self.meta_flush();
 
         // Commit metadata while registering needed data-hashes (files and dirs).
-        let top_ref = {
+        let (top_ref, contents) = {
             let local_hash_index = self.hash_index.clone();
             family.commit(&|hash| {
                 let id = local_hash_index.get_id(hash).expect(&format!(
@@ -776,20 +2191,33 @@ impl<B: StoreBackend> HatRc<B> {
             &top_ref.hash,
             &top_ref,
         );
+        let signature = self.keys.sign(&signed_message(
+            &family.name,
+            snap_info.snapshot_id,
+            &top_ref.hash,
+        ));
+        self.snapshot_index.set_signature(&snap_info, &signature);
         self.meta_flush();
 
         // Register the final hash.
         // At this point, the GC should still be able to either resume or rollback safely.
         // After a successful flush, all GC work is done.
         // The GC must be able to tell if it has completed or not.
+        // A snapshot whose data may have been collected meanwhile must not be completed.
+        self.check_lock()?;
         let hash_id = self.hash_index.get_id(&top_ref.hash).expect(
             "Hash does not exist",
         );
         self.gc.register_final(&snap_info, hash_id)?;
         self.meta_flush();
 
+        let stats = family.key_store.commit_stats();
+        self.snapshot_index.set_stats(&snap_info, stats, contents);
         self.commit_finalize(snap_info, &top_ref.hash)?;
 
+        // The next commit of this family gets a fresh size budget.
+        family.key_store.reset_commit_bytes();
+
         Ok(())
     }
 
@@ -817,12 +2245,13 @@ impl<B: StoreBackend> HatRc<B> {
         self.db.lock().flush();
     }
 
+    /// Flush everything, and make the index durable (see `db::Index::flush_durably`).
     pub fn data_flush(&self) -> Result<(), HatError> {
         for family in &self.families {
             family.flush()?
         }
         self.blob_store.flush();
-        self.meta_flush();
+        self.db.lock().flush_durably();
         Ok(())
     }
 
@@ -839,67 +2268,546 @@ impl<B: StoreBackend> HatRc<B> {
         family_name: String,
         output_dir: PathBuf,
     ) -> Result<(), HatError> {
-        // Extract latest snapshot info:
-        let (_info, _dir_hash, dir_ref) = match self.snapshot_index.latest(&family_name) {
-            Some((i, h, Some(r))) => (i, h, r),
-            _ => {
-                panic!(
-                    "Tried to checkout family '{}' before first completed commit",
-                    family_name
-                )
-            }
+        self.checkout_in_dir_ordered(family_name, output_dir, RestoreOrder::Listing)
+    }
+
+    /// Checkout the latest snapshot of a family, writing files in the given order.
+    pub fn checkout_in_dir_ordered(
+        &mut self,
+        family_name: String,
+        output_dir: PathBuf,
+        order: RestoreOrder,
+    ) -> Result<(), HatError> {
+        let options = RestoreOptions {
+            order: order,
+            ..Default::default()
         };
+        self.checkout(family_name, None, output_dir, &options)
+    }
 
+    /// Checkout a snapshot of a family, or its latest snapshot if no id is given.
+    pub fn checkout(
+        &mut self,
+        family_name: String,
+        snapshot_id: Option<u64>,
+        output_dir: PathBuf,
+        options: &RestoreOptions,
+    ) -> Result<(), HatError> {
+        let (info, dir_ref) = self.complete_snapshot(&family_name, snapshot_id)?;
         let family = self.open_family(family_name.clone()).expect(&format!(
             "Could not open family '{}'",
             family_name
         ));
 
-        let mut output_dir = output_dir;
-        self.checkout_dir_ref(&family, &mut output_dir, dir_ref)
+        // The size of the snapshot only estimates a full checkout.
+        let total_bytes = match options.subpath {
+            Some(_) => None,
+            None => self.snapshot_bytes(&family_name, info.snapshot_id),
+        };
+        self.progress.start("Restoring", total_bytes);
+        self.set_restore_marker(&family_name, info.snapshot_id, true);
+        let res = match options.subpath {
+            Some(ref subpath) => {
+                self.checkout_subpath(&family, output_dir, dir_ref, subpath, options)
+            }
+            None => self.checkout_tree(&family, &output_dir, PathBuf::new(), dir_ref, options),
+        };
+        self.set_restore_marker(&family_name, info.snapshot_id, false);
+        self.progress.finish();
+        res
+    }
+
+    /// The total size of the files in a snapshot, if it was recorded when it was committed.
+    pub fn snapshot_bytes(&mut self, family_name: &str, snapshot_id: u64) -> Option<u64> {
+        self.snapshot_index
+            .list_all()
+            .into_iter()
+            .find(|s| s.family_name == family_name && s.info.snapshot_id == snapshot_id)
+            .and_then(|s| s.contents)
+            .map(|c| c.bytes)
+    }
+
+    /// Write the data of one file in a snapshot to `out`, or in the latest snapshot if no id is
+    /// given, and return its size. Only the chunks of that file are fetched.
+    pub fn cat<W: Write>(
+        &mut self,
+        family_name: &str,
+        snapshot_id: Option<u64>,
+        path: &Path,
+        mut out: W,
+    ) -> Result<u64, HatError> {
+        let (_, dir_ref) = self.complete_snapshot(family_name, snapshot_id)?;
+        let family = self.open_family(family_name.to_string())?;
+
+        let (_, entry, content) = self.lookup_path(&family, dir_ref, path)?;
+        let info = entry.info;
+        let mut size = 0;
+        match (content, info.sparse_map) {
+            (walker::Content::Data(hash_ref), Some(extents)) => {
+                // The holes of a sparse file are filled in with zeros.
+                let len = info.byte_length.unwrap_or_else(|| sparse_end(&extents));
+                let leafs = hash::tree::LeafIterator::new(self.hash_backend(), hash_ref)?;
+                let data = archive::DataReader::new(leafs, util::data_length(&extents));
+                size = io::copy(&mut util::HoleFiller::new(data, extents, len), &mut out)?;
+            }
+            (walker::Content::Data(hash_ref), None) => {
+                let leafs = hash::tree::LeafIterator::new(self.hash_backend(), hash_ref)?;
+                if let Some(mut tree) = leafs {
+                    while let Some(chunk) = tree.try_next()? {
+                        out.write_all(&chunk[..])?;
+                        size += chunk.len() as u64;
+                    }
+                }
+            }
+            (walker::Content::Inline(bytes), Some(extents)) => {
+                let len = info.byte_length.unwrap_or_else(|| sparse_end(&extents));
+                size = io::copy(&mut util::HoleFiller::new(&bytes[..], extents, len), &mut out)?;
+            }
+            (walker::Content::Inline(bytes), None) => {
+                out.write_all(&bytes[..])?;
+                size = bytes.len() as u64;
+            }
+            (walker::Content::Dir(_), _) |
+            (walker::Content::Link(_), _) => {
+                return Err(From::from(format!("{} is not a regular file", path.display())));
+            }
+        }
+        out.flush()?;
+        Ok(size)
+    }
+
+    /// Look up a completed snapshot of a family, or its latest one if no id is given.
+    fn complete_snapshot(
+        &mut self,
+        family_name: &str,
+        snapshot_id: Option<u64>,
+    ) -> Result<(db::SnapshotInfo, hash::tree::HashRef), HatError> {
+        let snapshot_id = match snapshot_id {
+            Some(id) => id,
+            None => {
+                match self.snapshot_index.latest(family_name) {
+                    Some((i, _, Some(_))) => i.snapshot_id,
+                    _ => {
+                        return Err(From::from(format!(
+                            "Tried to checkout family '{}' before first completed commit",
+                            family_name
+                        )))
+                    }
+                }
+            }
+        };
+        let (info, dir_ref) = match self.snapshot_index.lookup(family_name, snapshot_id) {
+            Some((i, _, Some(r))) => (i, r),
+            _ => {
+                return Err(From::from(format!(
+                    "No complete snapshot {} in family {}",
+                    snapshot_id,
+                    family_name
+                )))
+            }
+        };
+        self.check_signature(family_name, snapshot_id)?;
+        Ok((info, dir_ref))
+    }
+
+    /// Check the signature of a snapshot's root hash, so that a snapshot substituted in the
+    /// backend is not restored. Unsigned snapshots are refused unless allowed with
+    /// `set_allow_unsigned`.
+    ///
+    /// The signing key is derived from the passphrase like all other keys, so a valid
+    /// signature only proves that the snapshot was made by someone who knows the passphrase;
+    /// it does not tell apart the machines or users sharing it.
+    pub fn check_signature(&mut self, family_name: &str, snapshot_id: u64) -> Result<(), HatError> {
+        let status = self.snapshot_index
+            .list_all()
+            .into_iter()
+            .find(|s| s.family_name == family_name && s.info.snapshot_id == snapshot_id)
+            .ok_or_else(|| format!("Unknown snapshot: {} #{}", family_name, snapshot_id))?;
+        let hash = status.hash.ok_or("Snapshot has not been committed")?;
+        match status.signature {
+            None if self.allow_unsigned => {
+                warn!("Snapshot {} #{} is not signed", family_name, snapshot_id);
+                Ok(())
+            }
+            None => Err(From::from(format!(
+                "Snapshot {} #{} is not signed (allow it with --allow_unsigned)",
+                family_name,
+                snapshot_id
+            ))),
+            Some(ref signature) if self.keys.verify_signature(
+                &signed_message(family_name, snapshot_id, &hash),
+                signature,
+            ) => Ok(()),
+            Some(_) => Err(From::from(format!(
+                "Snapshot {} #{} has an invalid signature",
+                family_name,
+                snapshot_id
+            ))),
+        }
+    }
+
+    /// Record the signature that came with a recovered or received snapshot. It is checked
+    /// when the snapshot is used.
+    fn set_received_signature(&mut self, family_name: &str, snapshot_id: u64, signature: &[u8]) {
+        if let Some((info, _, _)) = self.snapshot_index.lookup(family_name, snapshot_id) {
+            self.snapshot_index.set_signature(&info, signature);
+        }
+    }
+
+    /// Restore directory `dir` of the snapshot, given by its path from the snapshot root, to
+    /// the same path below `output`.
+    fn checkout_tree(
+        &self,
+        family: &Family<B>,
+        output: &Path,
+        dir: PathBuf,
+        dir_ref: hash::tree::HashRef,
+        options: &RestoreOptions,
+    ) -> Result<(), HatError> {
+        match options.order {
+            RestoreOrder::Listing if options.jobs <= 1 => {
+                let mut links = HashMap::new();
+                let restored = RestoredFiles::default();
+                self.checkout_dir_ref(family, output, dir, dir_ref, options, &mut links, &restored)
+            }
+            _ => self.checkout_scheduled(family, output, dir, dir_ref, options),
+        }
+    }
+
+    fn find_entry(
+        &self,
+        family: &Family<B>,
+        dir_ref: hash::tree::HashRef,
+        name: &[u8],
+    ) -> Result<Option<(key::Entry, walker::Content)>, HatError> {
+        for res in family.iter_dir_data(dir_ref, self.hash_backend())? {
+            let (entry, content) = res?;
+            if entry.info.name == name {
+                return Ok(Some((entry, content)));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Walk down the snapshot's directories to `subpath`, given by its absolute path in the
+    /// snapshot, and return its path from the snapshot root with its entry. Directories
+    /// outside of `subpath` are not read.
+    fn lookup_path(
+        &self,
+        family: &Family<B>,
+        dir_ref: hash::tree::HashRef,
+        subpath: &Path,
+    ) -> Result<(PathBuf, key::Entry, walker::Content), HatError> {
+        use std::path::Component;
+
+        let mut names = vec![];
+        for component in subpath.components() {
+            match component {
+                Component::Normal(name) => names.push(name),
+                Component::RootDir | Component::CurDir => (),
+                _ => return Err(From::from(format!("Invalid path {}", subpath.display()))),
+            }
+        }
+        let not_found = || HatError::from(format!("No {} in snapshot", subpath.display()));
+
+        let mut dir = PathBuf::new();
+        let mut dir_ref = dir_ref;
+        let (last, parents) = names.split_last().ok_or_else(&not_found)?;
+        for name in parents {
+            dir_ref = match self.find_entry(family, dir_ref, name.as_bytes())? {
+                Some((_, walker::Content::Dir(href))) => href,
+                _ => return Err(not_found()),
+            };
+            dir.push(name);
+        }
+
+        let (entry, content) = self.find_entry(family, dir_ref, last.as_bytes())?
+            .ok_or_else(&not_found)?;
+        Ok((dir.join(last), entry, content))
+    }
+
+    /// Restore only `subpath` of the snapshot, to the same place below `output_dir` as a full
+    /// checkout would.
+    fn checkout_subpath(
+        &self,
+        family: &Family<B>,
+        output_dir: PathBuf,
+        dir_ref: hash::tree::HashRef,
+        subpath: &Path,
+        options: &RestoreOptions,
+    ) -> Result<(), HatError> {
+        let (path, entry, content) = self.lookup_path(family, dir_ref, subpath)?;
+        let dir = path.parent().unwrap().to_owned();
+        match content {
+            walker::Content::Dir(href) => {
+                self.checkout_tree(family, &output_dir, path.clone(), href, options)?;
+            }
+            content => {
+                if !options.wants_file(&path) {
+                    return Ok(());
+                }
+                fs::create_dir_all(output_dir.join(&dir))?;
+                if !options.conflict.make_room(&output_dir.join(&path), &entry.info)? {
+                    return Ok(());
+                }
+                info!("Restoring {}", output_dir.join(&path).display());
+                let out = output_dir.join(&path);
+                let restored = RestoredFiles::default();
+                restore_content(
+                    self.hash_backend(),
+                    &out,
+                    &entry.info,
+                    content,
+                    &restored,
+                    &*self.progress,
+                )?;
+            }
+        }
+        if output_dir.join(&path).exists() {
+            restore_metadata(&output_dir.join(&path), &entry.info, family.fidelity, options)?;
+        }
+        Ok(())
+    }
+
+    fn set_restore_marker(&mut self, family_name: &str, snapshot_id: u64, restoring: bool) {
+        let mut index = self.db.lock();
+        let marker = restore_marker_config(family_name, snapshot_id);
+        if restoring {
+            index.config_set(&marker, &chrono::Utc::now().to_rfc3339());
+        } else {
+            index.config_remove(&marker);
+        }
+        index.flush();
+    }
+
+    /// Whether a snapshot is being restored, by this or another process. A restore that was
+    /// interrupted leaves the snapshot marked until `clear_restore_marker` is called.
+    pub fn restore_in_progress(&mut self, family_name: &str, snapshot_id: u64) -> bool {
+        self.db.lock()
+            .config_get(&restore_marker_config(family_name, snapshot_id))
+            .is_some()
+    }
+
+    /// Forget that a snapshot is being restored, after a restore was interrupted.
+    pub fn clear_restore_marker(&mut self, family_name: &str, snapshot_id: u64) {
+        self.set_restore_marker(family_name, snapshot_id, false);
     }
 
+    /// Restore a tree depth-first. With filters, directories are only created when something
+    /// below them is restored, and directories no filter can match below are not read.
+    /// `links` holds the first restored file of each group of hard links.
     fn checkout_dir_ref(
         &self,
         family: &Family<B>,
-        output: &mut PathBuf,
+        output: &Path,
+        dir: PathBuf,
         dir_hash: hash::tree::HashRef,
+        options: &RestoreOptions,
+        links: &mut HashMap<u64, PathBuf>,
+        restored: &RestoredFiles,
     ) -> Result<(), HatError> {
-        fs::create_dir_all(&output).unwrap();
-        for (entry, hash_ref) in family.fetch_dir_data(dir_hash, self.hash_backend())? {
+        if !options.is_filtered() {
+            fs::create_dir_all(output.join(&dir))?;
+        }
+        for res in family.iter_dir_data(dir_hash, self.hash_backend())? {
+            let (entry, content) = res?;
             assert!(entry.info.name.len() > 0);
 
-            output.push(str::from_utf8(&entry.info.name[..]).unwrap());
-            println!("{}", output.display());
-
-            match hash_ref {
-                walker::Content::Data(hash_ref) => {
-                    let mut fd = fs::File::create(&output).unwrap();
-                    let tree_opt = hash::tree::LeafIterator::new(self.hash_backend(), hash_ref)?;
-                    if let Some(tree) = tree_opt {
-                        family.write_file_chunks(&mut fd, tree);
+            let path = dir.join(OsStr::from_bytes(&entry.info.name[..]));
+            let out = output.join(&path);
+            match content {
+                walker::Content::Dir(hash_ref) => {
+                    if !options.wants_dir(&path) {
+                        continue;
+                    }
+                    self.checkout_dir_ref(
+                        family,
+                        output,
+                        path,
+                        hash_ref,
+                        options,
+                        links,
+                        restored,
+                    )?;
+                    if !out.exists() {
+                        // Nothing below it was restored.
+                        continue;
                     }
                 }
-                walker::Content::Dir(hash_ref) => {
-                    self.checkout_dir_ref(family, output, hash_ref)?;
+                content => {
+                    if !options.wants_file(&path) {
+                        continue;
+                    }
+                    fs::create_dir_all(output.join(&dir))?;
+                    if !options.conflict.make_room(&out, &entry.info)? {
+                        continue;
+                    }
+                    let first = entry.info.hardlink_id.and_then(|id| links.get(&id).cloned());
+                    if let Some(first) = first {
+                        // The metadata is shared with the first link.
+                        info!("Restoring {}", out.display());
+                        fs::hard_link(first, &out)?;
+                        continue;
+                    }
+                    restore_content(
+                        self.hash_backend(),
+                        &out,
+                        &entry.info,
+                        content,
+                        restored,
+                        &*self.progress,
+                    )?;
+                    if let Some(id) = entry.info.hardlink_id {
+                        links.insert(id, out.clone());
+                    }
                 }
-                walker::Content::Link(link_path) => {
-                    use std::os::unix::fs::symlink;
-                    symlink(link_path, &output)?
+            }
+            info!("Restoring {}", out.display());
+            restore_metadata(&out, &entry.info, family.fidelity, options)?;
+        }
+        Ok(())
+    }
+
+    /// Restore a tree in two passes: first create the directories and collect the files, then
+    /// write the files in the requested order. Directory metadata is applied last, deepest
+    /// directories first, so that writing files does not disturb it.
+    fn checkout_scheduled(
+        &self,
+        family: &Family<B>,
+        output: &Path,
+        dir: PathBuf,
+        dir_hash: hash::tree::HashRef,
+        options: &RestoreOptions,
+    ) -> Result<(), HatError> {
+        let mut dirs = vec![];
+        let mut files = vec![];
+        if !options.is_filtered() {
+            fs::create_dir_all(output.join(&dir))?;
+        }
+        self.collect_checkout(family, dir, dir_hash, options, &mut dirs, &mut files)?;
+        if !options.is_filtered() {
+            for &(ref path, _) in &dirs {
+                fs::create_dir_all(output.join(path))?;
+            }
+        }
+
+        options.order.schedule(&mut files);
+        let links = split_hardlinks(&mut files);
+        let restored = RestoredFiles::default();
+        if options.jobs > 1 {
+            self.restore_in_pool(family, output, files, options, &restored)?;
+        } else {
+            for (path, entry, content) in files {
+                let path = output.join(path);
+                fs::create_dir_all(path.parent().unwrap())?;
+                if !options.conflict.make_room(&path, &entry.info)? {
+                    continue;
                 }
+                info!("Restoring {}", path.display());
+                restore_content(
+                    self.hash_backend(),
+                    &path,
+                    &entry.info,
+                    content,
+                    &restored,
+                    &*self.progress,
+                )?;
+                restore_metadata(&path, &entry.info, family.fidelity, options)?;
             }
+        }
 
-            if let Some(perms) = entry.info.permissions {
-                fs::set_permissions(&output, perms)?;
+        // Further links to a file are made once the file has been written.
+        for (path, entry, first) in links {
+            let path = output.join(path);
+            fs::create_dir_all(path.parent().unwrap())?;
+            if !options.conflict.make_room(&path, &entry.info)? {
+                continue;
             }
+            info!("Restoring {}", path.display());
+            fs::hard_link(output.join(first), &path)?;
+        }
 
-            if let (Some(m), Some(a)) = (entry.info.modified_ts_secs, entry.info.accessed_ts_secs) {
-                let atime = filetime::FileTime::from_seconds_since_1970(a, 0 /* nanos */);
-                let mtime = filetime::FileTime::from_seconds_since_1970(m, 0 /* nanos */);
-                filetime::set_file_times(&output, atime, mtime)?;
+        for (path, entry) in dirs.into_iter().rev() {
+            let path = output.join(path);
+            if path.exists() {
+                restore_metadata(&path, &entry.info, family.fidelity, options)?;
             }
+        }
+        Ok(())
+    }
 
-            output.pop();
+    /// Restore files on `options.jobs` threads, starting them in the given order. Each file
+    /// is written by a single worker, so its chunks are written in order.
+    fn restore_in_pool(
+        &self,
+        family: &Family<B>,
+        output: &Path,
+        files: Vec<(PathBuf, key::Entry, walker::Content)>,
+        options: &RestoreOptions,
+        restored: &RestoredFiles,
+    ) -> Result<(), HatError> {
+        let (sender, receiver) = mpsc::channel();
+        let progress = &*self.progress;
+        let pool = scoped_pool::Pool::new(options.jobs);
+        let scheduled = pool.scoped(|scope| -> Result<(), HatError> {
+            for (path, entry, content) in files {
+                let path = output.join(path);
+                fs::create_dir_all(path.parent().unwrap())?;
+                if !options.conflict.make_room(&path, &entry.info)? {
+                    continue;
+                }
+                info!("Restoring {}", path.display());
+
+                let backend = self.hash_backend();
+                let fidelity = family.fidelity;
+                let sender = sender.clone();
+                scope.execute(move || {
+                    let res =
+                        restore_content(backend, &path, &entry.info, content, restored, progress)
+                            .and_then(|()| restore_metadata(&path, &entry.info, fidelity, options));
+                    sender.send(res).unwrap();
+                });
+            }
+            Ok(())
+        });
+        pool.shutdown();
+        drop(sender);
+
+        scheduled?;
+        for res in receiver {
+            res?;
+        }
+        Ok(())
+    }
+
+    fn collect_checkout(
+        &self,
+        family: &Family<B>,
+        dir: PathBuf,
+        dir_hash: hash::tree::HashRef,
+        options: &RestoreOptions,
+        dirs: &mut Vec<(PathBuf, key::Entry)>,
+        files: &mut Vec<(PathBuf, key::Entry, walker::Content)>,
+    ) -> Result<(), HatError> {
+        for res in family.iter_dir_data(dir_hash, self.hash_backend())? {
+            let (entry, content) = res?;
+            assert!(entry.info.name.len() > 0);
+
+            let path = dir.join(OsStr::from_bytes(&entry.info.name[..]));
+            match content {
+                walker::Content::Dir(hash_ref) => {
+                    if options.wants_dir(&path) {
+                        let dir = path.clone();
+                        self.collect_checkout(family, dir, hash_ref, options, dirs, files)?;
+                        dirs.push((path, entry));
+                    }
+                }
+                content => {
+                    if options.wants_file(&path) {
+                        files.push((path, entry, content));
+                    }
+                }
+            }
         }
         Ok(())
     }
@@ -915,7 +2823,62 @@ impl<B: StoreBackend> HatRc<B> {
         Ok(())
     }
 
+    /// Deregister the completed snapshots of a family that `policy` does not keep, oldest
+    /// first, and return their ids. With `dry_run`, only report what would be forgotten.
+    /// With a `label`, the policy only applies to the snapshots that have it; the others are
+    /// left alone.
+    ///
+    /// The data of forgotten snapshots is reclaimed by the next `gc`.
+    pub fn forget(
+        &mut self,
+        family_name: &str,
+        policy: &RetentionPolicy,
+        label: Option<&str>,
+        dry_run: bool,
+    ) -> Result<Vec<u64>, HatError> {
+        if *policy == RetentionPolicy::default() {
+            return Err(From::from("Refusing a retention policy that keeps no snapshots"));
+        }
+
+        let snapshots: Vec<_> = self.snapshot_index
+            .list_all()
+            .into_iter()
+            .filter(|s| s.family_name == family_name)
+            .filter(|s| match s.status {
+                db::SnapshotWorkStatus::CommitComplete => true,
+                _ => false,
+            })
+            .filter(|s| label.map_or(true, |l| s.labels.iter().any(|x| x == l)))
+            .map(|s| (s.info.snapshot_id, s.created))
+            .collect();
+        let keep = policy.keep(&snapshots[..]);
+
+        let mut forget: Vec<u64> = snapshots
+            .into_iter()
+            .map(|(id, _)| id)
+            .filter(|id| !keep.contains(id))
+            .collect();
+        forget.sort();
+
+        if !dry_run {
+            for &id in &forget {
+                self.deregister_by_name(family_name.to_string(), id)?;
+            }
+        }
+        Ok(forget)
+    }
+
+    /// Deregister one snapshot and release its references. Its data is reclaimed by the next
+    /// `gc`. Snapshots that are being restored are refused.
     pub fn deregister(&mut self, family: &Family<B>, snapshot_id: u64) -> Result<(), HatError> {
+        self.check_lock()?;
+        if self.restore_in_progress(&family.name, snapshot_id) {
+            return Err(From::from(format!(
+                "Snapshot {} of family {} is being restored",
+                snapshot_id,
+                family.name
+            )));
+        }
         let (info, top_hash, top_ref) =
             match self.snapshot_index.lookup(&family.name, snapshot_id) {
                 Some((i, h, Some(r))) => (i, h, r),
@@ -938,7 +2901,8 @@ impl<B: StoreBackend> HatRc<B> {
         );
 
         {
-            let hash_backend = self.hash_backend();
+            // Chunks read while walking the snapshot are verified anyway; keep track of it.
+            let hash_backend = self.hash_backend().recording_verified();
             let &mut Hat {
                 ref hash_index,
                 ref mut gc,
@@ -956,11 +2920,15 @@ impl<B: StoreBackend> HatRc<B> {
                             let href = match res {
                                 walker::Content::Data(href) => href,
                                 walker::Content::Dir(href) => href,
-                                walker::Content::Link(_) => continue,
+                                walker::Content::Link(_) |
+                                walker::Content::Inline(_) => continue,
                             };
                             match hash_index.get_id(&href.hash) {
                                 Some(id) => id_sender.send(id).unwrap(),
-                                None => panic!("Unexpected reply from hash index."),
+                                // Dropped by `repair` after its data was lost.
+                                None => {
+                                    warn!("Hash not in index: {}", href.hash.bytes.to_hex())
+                                }
                             }
                         }
                     }
@@ -1019,33 +2987,456 @@ impl<B: StoreBackend> HatRc<B> {
         Ok(self.flush_snapshot_index())
     }
 
-    pub fn gc(&mut self) -> Result<(u64, u64), HatError> {
-        // Remove unused hashes.
-        let mut deleted_hashes = 0;
+    /// Take a cooperative lock on the repository, held until the returned lock is dropped.
+    /// Fails if another client holds a conflicting lock that is not stale.
+    pub fn lock(&self, kind: LockKind) -> Result<RepositoryLock<B>, HatError> {
+        lock::acquire(self.backend.clone(), kind, lock::owner(&self.host))
+    }
+
+    /// Take a lock like `lock`, before the repository in `repository_root` is opened. A commit
+    /// must lock first, or its lock would look younger than what it read from the indexes, and
+    /// `gc_concurrent` could miss it.
+    pub fn lock_unopened(
+        backend: Arc<B>,
+        repository_root: &Path,
+        kind: LockKind,
+    ) -> Result<RepositoryLock<B>, HatError> {
+        lock::acquire(backend, kind, lock::owner(&lock::this_host(repository_root)?))
+    }
+
+    /// Refuse to go on with commits, forgets and deletions once `lock` is lost (see
+    /// `RepositoryLock::is_lost`), as others may then remove what they rely on.
+    pub fn watch_lock(&mut self, lock: &RepositoryLock<B>) {
+        self.lock_watch = Some(lock.watch());
+    }
+
+    fn check_lock(&self) -> Result<(), HatError> {
+        match self.lock_watch {
+            Some(ref watch) => watch.check(),
+            None => Ok(()),
+        }
+    }
+
+    /// This host as named in the locks it takes.
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    /// The locks currently held on the repository, including stale ones.
+    pub fn list_locks(&self) -> Result<Vec<LockInfo>, HatError> {
+        Ok(lock::list(&*self.backend)?.into_iter().map(|(_, info)| info).collect())
+    }
+
+    /// Remove the locks whose holders are gone (see `LockInfo::holder_is_gone`), or with
+    /// `force`, all locks not held by this process. Returns the removed locks. Only locks taken
+    /// on this host, by name and id, are broken for a process that no longer exists.
+    pub fn break_locks(&self, force: bool) -> Result<Vec<LockInfo>, HatError> {
+        let now = chrono::Utc::now().timestamp();
+        let own = lock::owner(&self.host);
+        let mut removed = vec![];
+        for (name, info) in lock::list(&*self.backend)? {
+            if info.owner == own || !(force || info.holder_is_gone(now, &self.host)) {
+                continue;
+            }
+            lock::remove(&*self.backend, &name)?;
+            removed.push(info);
+        }
+        Ok(removed)
+    }
+
+    pub fn gc(&mut self) -> Result<GcReport, HatError> {
+        if self.backend.append_only() {
+            return self.gc_mark_only();
+        }
+
+        self.check_lock()?;
+        self.progress.start("Collecting garbage", None);
+        let mut report = GcReport::default();
+        report.snapshots = self.count_complete_snapshots();
+
+        let start = time::SteadyTime::now();
         let (sender, receiver) = mpsc::channel();
         self.gc.list_unused_ids(sender)?;
-        for id in receiver.iter() {
-            deleted_hashes += 1;
-            self.hash_index.delete(id);
+        let unused: Vec<gc::Id> = receiver.iter().collect();
+        report.phases.push(gc_phase("mark", start));
+
+        // Remove unused hashes.
+        let start = time::SteadyTime::now();
+        let mut kept = self.delete_unused_hashes(unused, &mut report);
+        self.hash_index.flush();
+        report.phases.push(gc_phase("delete hashes", start));
+
+        self.sweep_blobs(&mut kept, &mut report)?;
+        self.prune_key_indexes(&mut report)?;
+        self.progress.finish();
+
+        Ok(report)
+    }
+
+    /// Garbage collection that does not trust the reference counts: every snapshot is walked
+    /// from its root, and whatever is not reached is deleted. The counts are then rebuilt from
+    /// the snapshots, so this also recovers a repository whose counts have drifted.
+    pub fn gc_mark_sweep(&mut self) -> Result<GcReport, HatError> {
+        if self.backend.append_only() {
+            return self.gc_mark_only();
         }
+
+        self.check_lock()?;
+        self.progress.start("Collecting garbage", None);
+        let mut report = GcReport::default();
+
+        let start = time::SteadyTime::now();
+        let roots = self.snapshot_roots()?;
+        report.snapshots = roots.len() as u64;
+        let live = self.mark_live(&roots);
+        report.phases.push(gc_phase("mark", start));
+
+        let start = time::SteadyTime::now();
+        let unused = self.hash_index
+            .list_ids()
+            .into_iter()
+            .filter(|id| !live.contains(id))
+            .collect();
+        let mut kept = self.delete_unused_hashes(unused, &mut report);
+        self.gc.reset_roots(&roots)?;
         self.hash_index.flush();
-        // Mark used blobs.
+        report.phases.push(gc_phase("delete hashes", start));
+
+        self.sweep_blobs(&mut kept, &mut report)?;
+        self.prune_key_indexes(&mut report)?;
+        self.progress.finish();
+
+        Ok(report)
+    }
+
+    fn count_complete_snapshots(&mut self) -> u64 {
+        self.snapshot_index
+            .list_all()
+            .into_iter()
+            .filter(|s| match s.status {
+                db::SnapshotWorkStatus::CommitComplete => true,
+                _ => false,
+            })
+            .count() as u64
+    }
+
+    /// The root hashes of all snapshots, once per snapshot. Fails if a snapshot is still being
+    /// committed or deleted, as its hashes may not be reachable from a root yet.
+    fn snapshot_roots(&mut self) -> Result<Vec<gc::Id>, HatError> {
+        let mut roots = vec![];
+        for s in self.snapshot_index.list_all() {
+            match s.status {
+                db::SnapshotWorkStatus::CommitComplete => (),
+                _ => {
+                    return Err(From::from(format!(
+                        "Snapshot {} of {} is unfinished; run `hat resume` first",
+                        s.info.snapshot_id,
+                        s.family_name
+                    )))
+                }
+            }
+            let hash = match s.hash {
+                Some(hash) => hash,
+                None => continue,
+            };
+            match self.hash_index.get_id(&hash) {
+                Some(id) => roots.push(id),
+                None => {
+                    return Err(From::from(format!(
+                        "Snapshot {} of {} has lost its root hash",
+                        s.info.snapshot_id,
+                        s.family_name
+                    )))
+                }
+            }
+        }
+        Ok(roots)
+    }
+
+    /// Every hash reachable from `roots`.
+    fn mark_live(&self, roots: &[gc::Id]) -> HashSet<gc::Id> {
+        let mut live = HashSet::new();
+        let mut todo = roots.to_vec();
+        while let Some(id) = todo.pop() {
+            if !live.insert(id) {
+                continue;
+            }
+            if let Some(entry) = self.hash_index.get_hash(id) {
+                todo.extend(entry.childs.unwrap_or_default());
+            }
+        }
+        live
+    }
+
+    /// Drop the rows of every family's key index that refer to hashes that are gone.
+    fn prune_key_indexes(&mut self, report: &mut GcReport) -> Result<(), HatError> {
+        let start = time::SteadyTime::now();
+        let names = self.db.lock().family_list();
+        for name in names {
+            let family = self.open_family(name)?;
+            report.key_rows_pruned += family.key_store.prune_index()?;
+        }
+        report.phases.push(gc_phase("prune key indexes", start));
+        Ok(())
+    }
+
+    /// Delete the `unused` hashes. With a grace period they are kept instead, until their blob
+    /// is deleted: a commit that finds one takes it up again, which spares the blob.
+    fn delete_unused_hashes(&mut self, unused: Vec<gc::Id>, report: &mut GcReport) -> KeptHashes {
+        if self.gc_grace_period.is_some() {
+            return KeptHashes::new(&self.hash_index, unused);
+        }
+        for id in unused {
+            report.hashes_deleted += 1;
+            self.hash_index.delete(id);
+        }
+        KeptHashes::default()
+    }
+
+    /// Delete the blobs that no hash refers to, and the hashes left pointing at deleted blobs.
+    /// Blobs that only `kept` hashes refer to are unused too.
+    fn sweep_blobs(
+        &mut self,
+        kept: &mut KeptHashes,
+        report: &mut GcReport,
+    ) -> Result<(), HatError> {
+        let start = time::SteadyTime::now();
         let entries = self.hash_index.list();
         self.blob_store.tag_all(tags::Tag::InProgress);
 
-        let mut live_blobs = 0;
         for entry in entries {
+            if kept.contains(&entry.hash) {
+                continue;
+            }
             if let Some(pref) = entry.persistent_ref {
-                live_blobs += 1;
+                report.live_chunks += 1;
+                self.progress.read(pref.length as u64);
                 self.blob_store.tag(pref, tags::Tag::Reserved);
             }
         }
+        self.blob_index.clear_condemned_by_tag(tags::Tag::Reserved);
+
         // Anything still marked "in progress" is not referenced by any hash.
+        let now = chrono::Utc::now().timestamp();
+        for blob in self.blob_store.list_by_tag(tags::Tag::InProgress) {
+            if self.grace_period_over(&blob, now, report) {
+                report.blobs_deleted += 1;
+                report.bytes_reclaimed += self.blob_length(&blob)?;
+                for id in kept.take_doomed(&blob.name) {
+                    report.hashes_deleted += 1;
+                    self.hash_index.delete(id);
+                }
+            } else {
+                self.blob_index.tag(&blob, tags::Tag::Done);
+            }
+        }
+        self.check_lock()?;
         self.blob_store.delete_by_tag(tags::Tag::InProgress)?;
         self.blob_store.tag_all(tags::Tag::Done);
         self.blob_store.flush();
 
-        Ok((deleted_hashes, live_blobs))
+        // Drop index entries left pointing at deleted blobs and reclaim the space.
+        report.hashes_deleted += self.hash_index.prune() as u64;
+        report.phases.push(gc_phase("sweep blobs", start));
+
+        Ok(())
+    }
+
+    /// The bytes `blob` takes in the backend. Blobs stored before their length was recorded
+    /// are asked about, which can mean reading them.
+    fn blob_length(&self, blob: &blob::BlobDesc) -> Result<u64, HatError> {
+        match self.blob_index.length(blob) {
+            Some(bytes) => Ok(bytes),
+            None => Ok(self.backend.size(&blob.name[..])?.unwrap_or(0)),
+        }
+    }
+
+    /// Whether the unused `blob` is to be deleted now. With a grace period, it is condemned
+    /// the first time it is found unused, and only deleted once the period has passed.
+    fn grace_period_over(&self, blob: &blob::BlobDesc, now: i64, report: &mut GcReport) -> bool {
+        let period = match self.gc_grace_period {
+            Some(period) => period,
+            None => return true,
+        };
+        match self.blob_index.condemned_at(blob) {
+            Some(since) if now - since >= period.num_seconds() => true,
+            Some(_) => {
+                report.blobs_condemned += 1;
+                false
+            }
+            None => {
+                self.blob_index.condemn(blob, now);
+                report.blobs_condemned += 1;
+                false
+            }
+        }
+    }
+
+    /// Garbage collection that commits can run alongside, holding only a shared lock.
+    ///
+    /// Deletions are deferred by one run: each run starts a new GC epoch, and commits stamp
+    /// the hashes they reuse or add with the current epoch. A run deletes the unused hashes
+    /// that were not stamped since the previous run started, and the unused blobs that were
+    /// already there then, provided that no commit that started before the previous run is
+    /// still going (its hashes may not be registered yet). Otherwise it deletes nothing, and
+    /// only starts the next epoch.
+    ///
+    /// Commits read the epoch once per index transaction, in which no run can start, and take
+    /// their lock before opening the index (see `lock_unopened`). What a commit stamps with the
+    /// previous epoch is thus still kept by this run; the next run only deletes it if that commit
+    /// is gone, or is still running with a lock older than this run, which defers deletion.
+    /// Only hosts whose clocks are further apart than `lock::CLOCK_SKEW_SECS` can defeat this.
+    pub fn gc_concurrent(&mut self) -> Result<GcReport, HatError> {
+        if self.backend.append_only() {
+            return self.gc_mark_only();
+        }
+
+        self.check_lock()?;
+        self.progress.start("Collecting garbage", None);
+        let mut report = GcReport::default();
+        report.snapshots = self.count_complete_snapshots();
+
+        let now = chrono::Utc::now().timestamp();
+        let previous = self.db.lock().gc_epoch();
+        let blob_mark = self.db.lock().blob_next_id();
+        self.db.lock().gc_set_epoch(&db::GcEpoch {
+            epoch: previous.map_or(1, |p| p.epoch + 1),
+            started_utc: now,
+            blob_mark: blob_mark,
+        });
+        let previous = match previous {
+            Some(p) if !self.commits_running_since(p.started_utc, now)? => Some(p),
+            _ => None,
+        };
+
+        let start = time::SteadyTime::now();
+        let (sender, receiver) = mpsc::channel();
+        self.gc.list_unused_ids(sender)?;
+        let unused: Vec<gc::Id> = receiver.iter().collect();
+        report.phases.push(gc_phase("mark", start));
+
+        // Remove unused hashes that have not been used since the previous run started. With a
+        // grace period they are kept until their blob is deleted.
+        let start = time::SteadyTime::now();
+        let mut kept = KeptHashes::default();
+        if let Some(p) = previous {
+            if self.gc_grace_period.is_some() {
+                kept = KeptHashes::new(&self.hash_index, unused);
+            } else {
+                for id in unused {
+                    if self.hash_index.delete_untouched(id, p.epoch) {
+                        report.hashes_deleted += 1;
+                    }
+                }
+            }
+        }
+        self.hash_index.flush();
+        report.phases.push(gc_phase("delete hashes", start));
+
+        // Blobs are only deleted by name, as commits tag the blobs they write.
+        let start = time::SteadyTime::now();
+        let mut used = HashSet::new();
+        for entry in self.hash_index.list() {
+            if kept.contains(&entry.hash) {
+                continue;
+            }
+            if let Some(pref) = entry.persistent_ref {
+                report.live_chunks += 1;
+                self.progress.read(pref.length as u64);
+                used.insert(pref.blob_name);
+            }
+        }
+        if let Some(p) = previous {
+            let now = chrono::Utc::now().timestamp();
+            let mut doomed = vec![];
+            for blob in self.blob_store.list_by_tag(tags::Tag::Done) {
+                if used.contains(&blob.name) {
+                    self.blob_index.clear_condemned(&blob);
+                    continue;
+                }
+                if blob.id <= p.blob_mark && self.grace_period_over(&blob, now, &mut report) {
+                    // Take the kept hashes of the blob out of reach of commits first. One that a
+                    // commit has used since the previous run started spares the blob.
+                    let mut spared = false;
+                    for id in kept.take_doomed(&blob.name) {
+                        if self.hash_index.delete_untouched(id, p.epoch) {
+                            report.hashes_deleted += 1;
+                        } else {
+                            spared = true;
+                            break;
+                        }
+                    }
+                    if spared {
+                        continue;
+                    }
+                    report.blobs_deleted += 1;
+                    report.bytes_reclaimed += self.blob_length(&blob)?;
+                    doomed.push(blob);
+                }
+            }
+            self.check_lock()?;
+            self.blob_store.delete_all(&doomed)?;
+        } else {
+            info!("Deferring deletions to the next gc run");
+        }
+        self.blob_store.flush();
+        report.phases.push(gc_phase("sweep blobs", start));
+        self.progress.finish();
+
+        Ok(report)
+    }
+
+    /// Whether a process that may still be committing took its lock before `since`, allowing
+    /// for some clock skew between hosts.
+    fn commits_running_since(&self, since: i64, now: i64) -> Result<bool, HatError> {
+        let own = lock::owner(&self.host);
+        Ok(lock::list(&*self.backend)?.into_iter().any(|(_, info)| {
+            info.owner != own && !info.holder_is_gone(now, &self.host) &&
+                info.taken_utc.map_or(true, |t| t <= since + lock::CLOCK_SKEW_SECS)
+        }))
+    }
+
+    /// Garbage collection for an append-only repository: unused hashes and blobs are found and
+    /// reported, but nothing is deleted. A privileged client can reclaim them with a full `gc`.
+    fn gc_mark_only(&mut self) -> Result<GcReport, HatError> {
+        let mut report = GcReport::default();
+        report.snapshots = self.count_complete_snapshots();
+
+        let start = time::SteadyTime::now();
+        let (sender, receiver) = mpsc::channel();
+        self.gc.list_unused_ids(sender)?;
+        let unused_hashes = receiver.iter().count();
+
+        let mut used = HashSet::new();
+        for entry in self.hash_index.list() {
+            if let Some(pref) = entry.persistent_ref {
+                report.live_chunks += 1;
+                used.insert(pref.blob_name);
+            }
+        }
+        let unused_blobs = self.backend
+            .list()?
+            .into_iter()
+            .filter_map(|name| blob::BlobId::new(name.into_vec()).ok())
+            .filter(|name| !used.contains(name))
+            .count();
+        report.phases.push(gc_phase("mark", start));
+
+        info!(
+            "Append-only repository: leaving {} unused hashes and {} unused blobs in place",
+            unused_hashes,
+            unused_blobs
+        );
+        Ok(report)
+    }
+
+    /// Iterate over the entries of a directory in a snapshot of `family`.
+    pub fn iter_snapshot_dir(
+        &self,
+        family: &Family<B>,
+        dir_ref: hash::tree::HashRef,
+    ) -> Result<family::DirIterator<key::HashStoreBackend<B>>, HatError> {
+        family.iter_dir_data(dir_ref, self.hash_backend())
     }
 
     fn hash_backend(&self) -> key::HashStoreBackend<B> {
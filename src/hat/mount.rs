@@ -0,0 +1,543 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Snapshots as a read-only file system in user space.
+//!
+//! The root holds a directory per family, which holds a directory per completed snapshot, named
+//! by its id. Directories are listed from the repository when first visited and file data is
+//! fetched when it is read, a block at a time. An open file keeps the list of its chunks, so
+//! that reading it out of order only fetches the chunks it needs.
+
+use backend::StoreBackend;
+use errors::HatError;
+use fuse::{FileAttr, FileType, Filesystem, ReplyAttr, ReplyData, ReplyDirectory, ReplyEmpty,
+           ReplyEntry, ReplyOpen, Request};
+use fuse;
+use hash;
+use hash::tree::HashTreeBackend;
+use key;
+use libc;
+use std::cmp::{self, Ordering};
+use std::collections::{HashMap, VecDeque};
+use std::ffi::{OsStr, OsString};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use time::{self, Timespec};
+
+use super::HatRc;
+use super::family::Family;
+use super::walker::Content;
+
+
+/// How long the kernel may keep names and attributes; snapshots do not change.
+const TTL: Timespec = Timespec { sec: 3600, nsec: 0 };
+
+/// File data is fetched and cached in blocks of this size.
+const BLOCK_SIZE: u64 = 128 * 1024;
+
+/// The number of recently read blocks kept for each open file.
+const CACHE_BLOCKS: usize = 16;
+
+pub const ROOT_INO: u64 = 1;
+
+#[derive(Clone)]
+enum NodeKind {
+    Root,
+    Family(String),
+    Snapshot(String, u64),
+    Dir(String, hash::tree::HashRef),
+    /// The content of a file, and where its data is in the file; the rest is holes.
+    File(Content, Vec<(u64, u64)>),
+    Link(PathBuf),
+}
+
+struct Node {
+    parent: u64,
+    attr: FileAttr,
+    kind: NodeKind,
+    /// The entries of a directory, once it has been listed.
+    children: Option<Vec<(OsString, u64)>>,
+}
+
+struct OpenFile {
+    /// Where the data of the file is in the file; the rest is holes.
+    extents: Vec<(u64, u64)>,
+    size: u64,
+    /// The leaf chunks of the file data, which is the data of its extents one after the other.
+    chunks: Vec<hash::tree::HashRef>,
+    /// Where in the file data each chunk read so far ends. Chunks are read in order the first
+    /// time, as only then is it known where they end.
+    ends: Vec<u64>,
+    /// The chunk read last, by index.
+    current: Option<(usize, Vec<u8>)>,
+    /// The most recently read blocks, by offset.
+    cache: VecDeque<(u64, Vec<u8>)>,
+}
+
+impl OpenFile {
+    /// The chunk of the file data holding byte `offset`, and where it starts.
+    fn chunk_at<B: StoreBackend>(
+        &mut self,
+        backend: &key::HashStoreBackend<B>,
+        offset: u64,
+    ) -> Result<(u64, &[u8]), HatError> {
+        let mut i = self.ends
+            .binary_search_by(|&end| if end > offset {
+                Ordering::Greater
+            } else {
+                Ordering::Less
+            })
+            .unwrap_err();
+        loop {
+            if self.current.as_ref().map(|c| c.0) != Some(i) {
+                let data = match self.chunks.get(i) {
+                    Some(href) => backend.fetch_chunk(href)?,
+                    None => None,
+                };
+                let data = data.ok_or_else(|| format!("File data ends before byte {}", offset))?;
+                if i == self.ends.len() {
+                    let start = self.ends.last().cloned().unwrap_or(0);
+                    self.ends.push(start + data.len() as u64);
+                }
+                self.current = Some((i, data));
+            }
+            if self.ends[i] > offset {
+                break;
+            }
+            i += 1;
+        }
+        let start = if i == 0 { 0 } else { self.ends[i - 1] };
+        Ok((start, &self.current.as_ref().expect("just read").1[..]))
+    }
+}
+
+/// Collects the leafs of a hash tree, without reading them.
+struct LeafRefs(Vec<hash::tree::HashRef>);
+
+impl hash::tree::Visitor for LeafRefs {
+    fn leaf_enter(&mut self, href: &hash::tree::HashRef) -> bool {
+        self.0.push(href.clone());
+        false
+    }
+}
+
+pub struct SnapshotFs<'a, B: StoreBackend> {
+    hat: &'a mut HatRc<B>,
+    families: HashMap<String, Family<B>>,
+    /// Node `ino` is at index `ino - 1`.
+    nodes: Vec<Node>,
+    open: HashMap<u64, OpenFile>,
+    next_fh: u64,
+    mounted: Timespec,
+    uid: u32,
+    gid: u32,
+}
+
+impl<'a, B: StoreBackend> SnapshotFs<'a, B> {
+    pub fn new(hat: &'a mut HatRc<B>) -> SnapshotFs<'a, B> {
+        let mut fs = SnapshotFs {
+            hat: hat,
+            families: HashMap::new(),
+            nodes: vec![],
+            open: HashMap::new(),
+            next_fh: 1,
+            mounted: time::get_time(),
+            uid: unsafe { libc::getuid() },
+            gid: unsafe { libc::getgid() },
+        };
+        let mounted = fs.mounted;
+        fs.add_node(ROOT_INO, NodeKind::Root, FileType::Directory, 0, None, mounted);
+        fs
+    }
+
+    fn node(&self, ino: u64) -> Option<&Node> {
+        if ino == 0 {
+            None
+        } else {
+            self.nodes.get(ino as usize - 1)
+        }
+    }
+
+    /// Add a node below `parent` with the attributes of `info`, or read-only defaults.
+    fn add_node(
+        &mut self,
+        parent: u64,
+        kind: NodeKind,
+        file_type: FileType,
+        size: u64,
+        info: Option<&key::Info>,
+        mtime: Timespec,
+    ) -> u64 {
+        let ino = self.nodes.len() as u64 + 1;
+        let perm = match info.and_then(|i| i.permissions.as_ref()) {
+            Some(p) => (p.mode() & 0o7777) as u16,
+            None if file_type == FileType::Directory => 0o555,
+            None if file_type == FileType::Symlink => 0o777,
+            None => 0o444,
+        };
+        let mtime = info.and_then(|i| i.modified_ts_secs).map_or(mtime, |secs| {
+            Timespec::new(secs as i64, 0)
+        });
+        let attr = FileAttr {
+            ino: ino,
+            size: size,
+            blocks: (size + 511) / 512,
+            atime: mtime,
+            mtime: mtime,
+            ctime: mtime,
+            crtime: mtime,
+            kind: file_type,
+            perm: perm,
+            nlink: if file_type == FileType::Directory { 2 } else { 1 },
+            uid: info.and_then(|i| i.user_id).map_or(self.uid, |id| id as u32),
+            gid: info.and_then(|i| i.group_id).map_or(self.gid, |id| id as u32),
+            rdev: 0,
+            flags: 0,
+        };
+        self.nodes.push(Node {
+            parent: if ino == ROOT_INO { ROOT_INO } else { parent },
+            attr: attr,
+            kind: kind,
+            children: None,
+        });
+        ino
+    }
+
+    /// The entries of directory `ino`, listing it from the repository on first use.
+    pub fn list(&mut self, ino: u64) -> Result<Vec<(OsString, u64)>, HatError> {
+        let kind = match self.node(ino) {
+            Some(&Node { children: Some(ref children), .. }) => return Ok(children.clone()),
+            Some(node) => node.kind.clone(),
+            None => return Err(From::from(format!("No inode {}", ino))),
+        };
+
+        let mut children = vec![];
+        match kind {
+            NodeKind::Root => {
+                let mut families: Vec<String> =
+                    self.hat.list_snapshots().into_iter().map(|s| s.family).collect();
+                families.sort();
+                families.dedup();
+                for name in families {
+                    let mounted = self.mounted;
+                    let kind = NodeKind::Family(name.clone());
+                    let child = self.add_node(ino, kind, FileType::Directory, 0, None, mounted);
+                    children.push((OsString::from(name), child));
+                }
+            }
+            NodeKind::Family(name) => {
+                for s in self.hat.list_snapshots().into_iter().filter(|s| s.family == name) {
+                    let created = Timespec::new(s.created.timestamp(), 0);
+                    let kind = NodeKind::Snapshot(name.clone(), s.snapshot_id);
+                    let child = self.add_node(ino, kind, FileType::Directory, 0, None, created);
+                    children.push((OsString::from(s.snapshot_id.to_string()), child));
+                }
+            }
+            NodeKind::Snapshot(name, id) => {
+                let (_, dir_ref) = self.hat.complete_snapshot(&name, Some(id))?;
+                children = self.list_dir(ino, &name, dir_ref)?;
+            }
+            NodeKind::Dir(name, dir_ref) => {
+                children = self.list_dir(ino, &name, dir_ref)?;
+            }
+            NodeKind::File(..) |
+            NodeKind::Link(_) => {
+                return Err(From::from(format!("Inode {} is not a directory", ino)));
+            }
+        }
+
+        self.nodes[ino as usize - 1].children = Some(children.clone());
+        Ok(children)
+    }
+
+    fn list_dir(
+        &mut self,
+        ino: u64,
+        family_name: &str,
+        dir_ref: hash::tree::HashRef,
+    ) -> Result<Vec<(OsString, u64)>, HatError> {
+        if !self.families.contains_key(family_name) {
+            let family = self.hat.open_family(family_name.to_string())?;
+            self.families.insert(family_name.to_string(), family);
+        }
+        let entries = {
+            let family = &self.families[family_name];
+            self.hat.iter_snapshot_dir(family, dir_ref)?.collect::<Result<Vec<_>, _>>()?
+        };
+
+        let mounted = self.mounted;
+        let mut children = vec![];
+        for (entry, content) in entries {
+            let info = entry.info;
+            let (kind, file_type, size) = match content {
+                Content::Dir(href) => {
+                    (NodeKind::Dir(family_name.to_string(), href), FileType::Directory, 0)
+                }
+                Content::Link(target) => {
+                    let size = target.as_os_str().len() as u64;
+                    (NodeKind::Link(target), FileType::Symlink, size)
+                }
+                Content::Inline(bytes) => {
+                    let size = info.byte_length.unwrap_or(bytes.len() as u64);
+                    let extents = info.sparse_map.clone().unwrap_or_else(|| vec![(0, size)]);
+                    (NodeKind::File(Content::Inline(bytes), extents), FileType::RegularFile, size)
+                }
+                Content::Data(href) => {
+                    let size = info.byte_length.unwrap_or(0);
+                    let extents = info.sparse_map.clone().unwrap_or_else(|| vec![(0, size)]);
+                    (NodeKind::File(Content::Data(href), extents), FileType::RegularFile, size)
+                }
+            };
+            let child = self.add_node(ino, kind, file_type, size, Some(&info), mounted);
+            children.push((OsString::from_vec(info.name), child));
+        }
+        Ok(children)
+    }
+
+    /// Open file `ino` for reading, and list the chunks of its data.
+    pub fn open_file(&mut self, ino: u64) -> Result<u64, HatError> {
+        let (content, extents, size) = match self.node(ino) {
+            Some(&Node {
+                     kind: NodeKind::File(ref content, ref extents),
+                     ref attr,
+                     ..
+                 }) => (content.clone(), extents.clone(), attr.size),
+            Some(_) => return Err(From::from(format!("Inode {} is not a regular file", ino))),
+            None => return Err(From::from(format!("No inode {}", ino))),
+        };
+        let mut open = OpenFile {
+            extents: extents,
+            size: size,
+            chunks: vec![],
+            ends: vec![],
+            current: None,
+            cache: VecDeque::new(),
+        };
+        match content {
+            Content::Inline(bytes) => {
+                open.ends.push(bytes.len() as u64);
+                open.current = Some((0, bytes));
+            }
+            Content::Data(href) => {
+                let mut leafs = LeafRefs(vec![]);
+                if let Some(mut walker) = hash::tree::Walker::new(self.hat.hash_backend(), href)? {
+                    while walker.resume(&mut leafs)? {}
+                }
+                open.chunks = leafs.0;
+            }
+            Content::Dir(_) |
+            Content::Link(_) => {
+                return Err(From::from(format!("Inode {} is not a regular file", ino)));
+            }
+        }
+
+        let fh = self.next_fh;
+        self.next_fh += 1;
+        self.open.insert(fh, open);
+        Ok(fh)
+    }
+
+    /// Fill `buf` with the file data of open file `fh` from `offset` on.
+    fn read_data(&mut self, fh: u64, mut offset: u64, buf: &mut [u8]) -> Result<(), HatError> {
+        let backend = self.hat.hash_backend();
+        let open = self.open.get_mut(&fh).ok_or_else(|| format!("No open file {}", fh))?;
+        let mut pos = 0;
+        while pos < buf.len() {
+            let (start, chunk) = open.chunk_at(&backend, offset)?;
+            let from = (offset - start) as usize;
+            let n = cmp::min(chunk.len() - from, buf.len() - pos);
+            buf[pos..pos + n].copy_from_slice(&chunk[from..from + n]);
+            pos += n;
+            offset += n as u64;
+        }
+        Ok(())
+    }
+
+    /// The block of open file `fh` at `offset`, from its cache if it was read recently.
+    fn block(&mut self, fh: u64, offset: u64) -> Result<Vec<u8>, HatError> {
+        let (extents, size) = match self.open.get(&fh) {
+            Some(open) => {
+                if let Some(&(_, ref data)) = open.cache.iter().find(|&&(o, _)| o == offset) {
+                    return Ok(data.clone());
+                }
+                (open.extents.clone(), open.size)
+            }
+            None => return Err(From::from(format!("No open file {}", fh))),
+        };
+
+        // Holes stay zero; the data of each extent follows that of the extents before it.
+        let end = cmp::min(offset + BLOCK_SIZE, size);
+        let mut data = vec![0; end.saturating_sub(offset) as usize];
+        let mut data_offset = 0;
+        for (start, len) in extents {
+            let from = cmp::max(start, offset);
+            let to = cmp::min(start + len, end);
+            if from < to {
+                let buf = &mut data[(from - offset) as usize..(to - offset) as usize];
+                self.read_data(fh, data_offset + from - start, buf)?;
+            }
+            data_offset += len;
+        }
+
+        let open = self.open.get_mut(&fh).expect("checked above");
+        if open.cache.len() == CACHE_BLOCKS {
+            open.cache.pop_front();
+        }
+        open.cache.push_back((offset, data.clone()));
+        Ok(data)
+    }
+
+    /// Read up to `size` bytes of open file `fh` at `offset`.
+    pub fn read_range(
+        &mut self,
+        ino: u64,
+        fh: u64,
+        offset: u64,
+        size: u64,
+    ) -> Result<Vec<u8>, HatError> {
+        let file_size = self.node(ino).map_or(0, |n| n.attr.size);
+        let end = cmp::min(offset + size, file_size);
+        let mut out = vec![];
+        let mut pos = offset;
+        while pos < end {
+            let start = pos - pos % BLOCK_SIZE;
+            let block = self.block(fh, start)?;
+            let to = cmp::min(block.len() as u64, end - start);
+            if pos - start >= to {
+                break;
+            }
+            out.extend_from_slice(&block[(pos - start) as usize..to as usize]);
+            pos = start + to;
+        }
+        Ok(out)
+    }
+}
+
+impl<'a, B: StoreBackend> Filesystem for SnapshotFs<'a, B> {
+    fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        let children = match self.list(parent) {
+            Ok(children) => children,
+            Err(e) => {
+                warn!("Could not list inode {}: {}", parent, e);
+                return reply.error(libc::EIO);
+            }
+        };
+        match children.iter().find(|&&(ref n, _)| n == name) {
+            Some(&(_, ino)) => reply.entry(&TTL, &self.nodes[ino as usize - 1].attr, 0),
+            None => reply.error(libc::ENOENT),
+        }
+    }
+
+    fn getattr(&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
+        match self.node(ino) {
+            Some(node) => reply.attr(&TTL, &node.attr),
+            None => reply.error(libc::ENOENT),
+        }
+    }
+
+    fn readlink(&mut self, _req: &Request, ino: u64, reply: ReplyData) {
+        match self.node(ino).map(|n| &n.kind) {
+            Some(&NodeKind::Link(ref target)) => reply.data(target.as_os_str().as_bytes()),
+            Some(_) => reply.error(libc::EINVAL),
+            None => reply.error(libc::ENOENT),
+        }
+    }
+
+    fn open(&mut self, _req: &Request, ino: u64, flags: u32, reply: ReplyOpen) {
+        if flags & libc::O_ACCMODE as u32 != libc::O_RDONLY as u32 {
+            return reply.error(libc::EROFS);
+        }
+        match self.open_file(ino) {
+            Ok(fh) => reply.opened(fh, 0),
+            Err(e) => {
+                warn!("Could not open inode {}: {}", ino, e);
+                reply.error(libc::EIO);
+            }
+        }
+    }
+
+    fn read(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        size: u32,
+        reply: ReplyData,
+    ) {
+        match self.read_range(ino, fh, offset as u64, size as u64) {
+            Ok(data) => reply.data(&data[..]),
+            Err(e) => {
+                warn!("Could not read inode {}: {}", ino, e);
+                reply.error(libc::EIO);
+            }
+        }
+    }
+
+    fn release(
+        &mut self,
+        _req: &Request,
+        _ino: u64,
+        fh: u64,
+        _flags: u32,
+        _lock_owner: u64,
+        _flush: bool,
+        reply: ReplyEmpty,
+    ) {
+        self.open.remove(&fh);
+        reply.ok();
+    }
+
+    fn readdir(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectory,
+    ) {
+        let children = match self.list(ino) {
+            Ok(children) => children,
+            Err(e) => {
+                warn!("Could not list inode {}: {}", ino, e);
+                return reply.error(libc::EIO);
+            }
+        };
+        let parent = self.nodes[ino as usize - 1].parent;
+        let mut entries = vec![
+            (ino, FileType::Directory, OsString::from(".")),
+            (parent, FileType::Directory, OsString::from("..")),
+        ];
+        for (name, child) in children {
+            entries.push((child, self.nodes[child as usize - 1].attr.kind, name));
+        }
+        for (i, (child, kind, name)) in entries.into_iter().enumerate().skip(offset as usize) {
+            if reply.add(child, i as i64 + 1, kind, name) {
+                break;
+            }
+        }
+        reply.ok();
+    }
+}
+
+impl<B: StoreBackend> HatRc<B> {
+    /// Mount the completed snapshots of the repository read-only at `mountpoint`, and serve
+    /// them until the file system is unmounted.
+    pub fn mount(&mut self, mountpoint: &Path) -> Result<(), HatError> {
+        let options = [OsStr::new("-o"), OsStr::new("ro,fsname=hat")];
+        fuse::mount(SnapshotFs::new(self), &mountpoint, &options)?;
+        Ok(())
+    }
+}
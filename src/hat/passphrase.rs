@@ -0,0 +1,95 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Where the passphrase that the repository keys are derived from is read.
+
+use errors::HatError;
+use secstr;
+use std::env;
+use std::fs;
+use std::io::Read;
+use std::os::unix::ffi::OsStringExt;
+use std::os::unix::io::FromRawFd;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+
+/// The passphrase used by repositories that were not given one.
+pub const DEFAULT_PASSPHRASE: &'static [u8] = b"hat-master-key";
+
+/// A place to read the passphrase from without prompting for it, so that it stays out of
+/// the command line and shell history.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PassphraseSource {
+    /// An environment variable, e.g. `HAT_PASSPHRASE`.
+    Env(String),
+    /// The contents of a file, e.g. one only readable by the backup user.
+    File(PathBuf),
+    /// An open file descriptor, read to its end.
+    Fd(i32),
+    /// The output of a shell command, e.g. `pass show hat`.
+    Command(String),
+}
+
+impl PassphraseSource {
+    /// Read the passphrase. A single trailing newline is not part of it, as files and commands
+    /// usually end their output with one.
+    pub fn read(&self) -> Result<secstr::SecStr, HatError> {
+        let mut bytes = match *self {
+            PassphraseSource::Env(ref name) => {
+                match env::var_os(name) {
+                    Some(value) => value.into_vec(),
+                    None => return Err(From::from(format!("{} is not set", name))),
+                }
+            }
+            PassphraseSource::File(ref path) => {
+                let mut bytes = vec![];
+                fs::File::open(path)?.read_to_end(&mut bytes)?;
+                bytes
+            }
+            PassphraseSource::Fd(fd) => {
+                let mut bytes = vec![];
+                unsafe { fs::File::from_raw_fd(fd) }.read_to_end(&mut bytes)?;
+                bytes
+            }
+            PassphraseSource::Command(ref command) => {
+                // The command may need the terminal, e.g. to unlock a key agent.
+                let output = Command::new("sh")
+                    .arg("-c")
+                    .arg(command)
+                    .stdin(Stdio::inherit())
+                    .stderr(Stdio::inherit())
+                    .output()?;
+                if !output.status.success() {
+                    return Err(From::from(format!(
+                        "Passphrase command `{}` failed: {}",
+                        command,
+                        output.status
+                    )));
+                }
+                output.stdout
+            }
+        };
+        if bytes.last() == Some(&b'\n') {
+            bytes.pop();
+            if bytes.last() == Some(&b'\r') {
+                bytes.pop();
+            }
+        }
+        if bytes.is_empty() {
+            return Err(From::from("The passphrase is empty"));
+        }
+        Ok(secstr::SecStr::new(bytes))
+    }
+}
@@ -0,0 +1,324 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Patch bundles: the blobs needed to bring an offline replica from one snapshot to the next.
+
+use backend::StoreBackend;
+use blob::{self, BlobId};
+use capnp;
+use chrono;
+use crypto;
+use crypto::CipherText;
+use db;
+use errors::HatError;
+use hash;
+use root_capnp;
+use std::collections::{BTreeSet, HashSet};
+use std::io;
+
+use super::{FINGERPRINT_SECRET_NAME, HatRc, Manifest, synthetic_roots_family};
+
+
+/// What `copy_snapshots` transferred to the destination repository.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CopyReport {
+    /// Snapshots the destination did not have before.
+    pub snapshots: usize,
+    /// Snapshots the destination already had, as copied by an earlier run.
+    pub present: usize,
+    /// Snapshots that were not copied because the destination has a different snapshot with
+    /// the same family and id.
+    pub conflicts: Vec<(String, u64)>,
+    /// Blobs the destination did not have before, and their size in bytes.
+    pub blobs: usize,
+    pub bytes: u64,
+}
+
+impl<B: StoreBackend> HatRc<B> {
+    fn snapshot_status(&mut self, family: &str, id: u64) -> Result<db::SnapshotStatus, HatError> {
+        self.snapshot_index
+            .list_all()
+            .into_iter()
+            .find(|s| s.family_name == family && s.info.snapshot_id == id)
+            .ok_or_else(|| From::from(format!("Unknown snapshot: {} #{}", family, id)))
+    }
+
+    /// List the names of all blobs holding data reachable from the given top hash.
+    fn reachable_blob_names(&self, top: &hash::Hash) -> Result<BTreeSet<BlobId>, HatError> {
+        let mut names = BTreeSet::new();
+        let mut seen = HashSet::new();
+        let mut queue = vec![self.hash_index.get_id(top).ok_or("Snapshot hash is unknown")?];
+
+        while let Some(id) = queue.pop() {
+            if !seen.insert(id) {
+                continue;
+            }
+            let entry = self.hash_index.get_hash(id).ok_or("Hash id is unknown")?;
+            if let Some(r) = entry.persistent_ref {
+                if r.length > 0 {
+                    names.insert(r.blob_name);
+                }
+            }
+            if let Some(childs) = entry.childs {
+                queue.extend(childs);
+            }
+        }
+
+        Ok(names)
+    }
+
+    /// Write a patch bundle containing snapshot `to_id` of `family` and the blobs it needs that
+    /// are not already referenced by snapshot `from_id`. Returns the number of blobs written.
+    pub fn create_patch<W: io::Write>(
+        &mut self,
+        family: &str,
+        from_id: Option<u64>,
+        to_id: u64,
+        out: &mut W,
+    ) -> Result<usize, HatError> {
+        let to = self.snapshot_status(family, to_id)?;
+        let to_hash_ref = to.hash_ref.ok_or("Snapshot has not been committed")?;
+        let mut names = self.reachable_blob_names(
+            &to.hash.ok_or("Snapshot has not been committed")?,
+        )?;
+
+        if let Some(id) = from_id {
+            let from = self.snapshot_status(family, id)?;
+            let known = self.reachable_blob_names(
+                &from.hash.ok_or("Base snapshot has not been committed")?,
+            )?;
+            names = names.difference(&known).cloned().collect();
+        }
+
+        let mut message = capnp::message::Builder::new_default();
+        {
+            let mut root = message.init_root::<root_capnp::patch_bundle::Builder>();
+            root.set_from_id(from_id.unwrap_or(0));
+            {
+                let mut s = root.borrow().init_snapshot();
+                s.set_id(to.info.snapshot_id);
+                s.set_family_name(&to.family_name);
+                s.set_msg(&to.msg.unwrap_or("".to_owned()));
+                s.set_utc_timestamp(to.created.timestamp());
+                if let Some(ref provenance) = to.provenance {
+                    provenance.populate_msg(s.borrow().init_provenance());
+                }
+                if let Some(ref signature) = to.signature {
+                    s.set_signature(signature);
+                }
+                {
+                    let mut labels = s.borrow().init_labels(to.labels.len() as u32);
+                    for (i, label) in to.labels.iter().enumerate() {
+                        labels.set(i as u32, label);
+                    }
+                }
+                hash::tree::HashRef::from_bytes(&mut &to_hash_ref[..])?
+                    .populate_msg(s.init_hash_ref());
+            }
+
+            let mut blobs = root.init_blobs(names.len() as u32);
+            for (i, name) in names.iter().enumerate() {
+                let data = self.backend.retrieve(name)?.ok_or(
+                    "Blob is missing from backend",
+                )?;
+                let mut b = blobs.borrow().get(i as u32);
+                b.set_name(name);
+                b.set_data(&data);
+            }
+        }
+
+        capnp::serialize_packed::write_message(out, &message)?;
+        Ok(names.len())
+    }
+
+    /// Apply a patch bundle created by `create_patch`: store its blobs and register its snapshot.
+    /// Returns false, having applied nothing, if this repository has the snapshot already.
+    pub fn apply_patch<R: io::BufRead>(&mut self, input: &mut R) -> Result<bool, HatError> {
+        use chrono::TimeZone;
+
+        let mut options = capnp::message::ReaderOptions::new();
+        options.traversal_limit_in_words(u64::max_value());
+        let reader = capnp::serialize_packed::read_message(input, options)?;
+        let bundle = reader.get_root::<root_capnp::patch_bundle::Reader>()?;
+
+        let snapshot = bundle.get_snapshot()?;
+        let family = snapshot.get_family_name()?;
+        let hash_ref = hash::tree::HashRef::read_msg(&snapshot.get_hash_ref()?)?;
+        if let Some((_, hash, _)) = self.snapshot_index.lookup(family, snapshot.get_id()) {
+            if hash != hash_ref.hash {
+                return Err(From::from(format!(
+                    "Snapshot {} #{} is a different snapshot in this repository",
+                    family,
+                    snapshot.get_id()
+                )));
+            }
+            return Ok(false);
+        }
+
+        let from_id = bundle.get_from_id();
+        if from_id > 0 && self.snapshot_index.lookup(family, from_id).is_none() {
+            return Err(From::from(format!(
+                "Patch requires snapshot {} #{} which this repository does not have",
+                family,
+                from_id
+            )));
+        }
+
+        for b in bundle.get_blobs()?.iter() {
+            let name = b.get_name()?;
+            if self.backend.retrieve(name)?.is_none() {
+                self.backend.store(name, &CipherText::new(b.get_data()?.to_vec()))?;
+            }
+        }
+        self.backend.flush()?;
+        self.blob_store.recover()?;
+
+        let provenance = if snapshot.has_provenance() {
+            Some(db::Provenance::read(snapshot.get_provenance()?)?)
+        } else {
+            None
+        };
+        let mut labels = vec![];
+        for label in snapshot.get_labels()?.iter() {
+            labels.push(label?.to_string());
+        }
+        self.snapshot_index.recover(
+            snapshot.get_id(),
+            family,
+            chrono::Utc.timestamp(snapshot.get_utc_timestamp(), 0),
+            snapshot.get_msg()?,
+            &hash_ref,
+            Some(db::SnapshotWorkStatus::RecoverInProgress),
+            provenance.as_ref(),
+            &labels[..],
+        );
+        if snapshot.has_signature() {
+            self.set_received_signature(family, snapshot.get_id(), snapshot.get_signature()?);
+        }
+        self.flush_snapshot_index();
+
+        // Resuming completes the recovery of the new snapshot.
+        self.resume()?;
+        Ok(true)
+    }
+}
+
+impl<B: StoreBackend> HatRc<B> {
+    /// Give the backend of a repository that is about to be created as a copy of this one the
+    /// same fingerprint secret, so that the chunks copied there match their hashes. Call it
+    /// before `init_repository`.
+    pub fn share_fingerprint_secret<D: StoreBackend>(&self, dest: &D) -> Result<(), HatError> {
+        if Manifest::load(dest)?.is_some() {
+            return Err(From::from("The destination repository has already been initialized"));
+        }
+        if let Some(sealed) = self.backend.retrieve(FINGERPRINT_SECRET_NAME)? {
+            dest.store(FINGERPRINT_SECRET_NAME, &CipherText::new(sealed))?;
+            dest.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Whether chunks hash the same here and in `other`, i.e. both were opened with the same
+    /// passphrase, hash algorithm and fingerprint secret.
+    fn same_fingerprints<D: StoreBackend>(&self, other: &HatRc<D>) -> bool {
+        let probe = |keys: &crypto::keys::Keeper| {
+            hash::Hash::new(keys, blob::NodeType::Leaf, blob::LeafType::FileChunk, b"")
+        };
+        probe(&self.keys) == probe(&other.keys)
+    }
+
+    /// Copy the committed snapshots of `family`, or of all families, to `dest`. Only blobs that
+    /// `dest` does not have are transferred, and snapshots it already has are skipped.
+    ///
+    /// Blobs are copied as they are, so `dest` must have the same keys and fingerprint secret;
+    /// create it with `share_fingerprint_secret` and the same passphrase. The caller should
+    /// `meta_commit` and `data_flush` the destination afterwards.
+    pub fn copy_snapshots<D: StoreBackend>(
+        &mut self,
+        dest: &mut HatRc<D>,
+        family: Option<&str>,
+    ) -> Result<CopyReport, HatError> {
+        if !self.same_fingerprints(dest) {
+            return Err(From::from(
+                "The destination repository has other keys or another fingerprint secret, so \
+                 copied data would not match its hashes there",
+            ));
+        }
+        let mut report = CopyReport::default();
+        let mut known: HashSet<BlobId> =
+            dest.backend.list()?.into_iter().map(|name| BlobId::from(name.into_vec())).collect();
+
+        let mut snapshots: Vec<_> = self.snapshot_index
+            .list_all()
+            .into_iter()
+            .filter(|s| s.family_name != synthetic_roots_family())
+            .filter(|s| family.map_or(true, |f| s.family_name == f))
+            .filter(|s| match s.status {
+                db::SnapshotWorkStatus::CommitComplete => true,
+                _ => false,
+            })
+            .collect();
+        snapshots.sort_by(|a, b| {
+            (&a.family_name, a.info.snapshot_id).cmp(&(&b.family_name, b.info.snapshot_id))
+        });
+
+        for s in snapshots {
+            let id = s.info.snapshot_id;
+            if let Some((_, hash, _)) = dest.snapshot_index.lookup(&s.family_name, id) {
+                if Some(hash) == s.hash {
+                    report.present += 1;
+                } else {
+                    warn!("{} #{} is a different snapshot in the destination", s.family_name, id);
+                    report.conflicts.push((s.family_name.clone(), id));
+                }
+                continue;
+            }
+            let hash_ref = hash::tree::HashRef::from_bytes(
+                &mut &s.hash_ref.ok_or("Snapshot has not been committed")?[..],
+            )?;
+
+            for name in self.reachable_blob_names(&hash_ref.hash)? {
+                if known.contains(&name) {
+                    continue;
+                }
+                let data = self.backend.retrieve(&name)?.ok_or("Blob is missing from backend")?;
+                dest.backend.store(&name, &CipherText::new(data.clone()))?;
+                report.blobs += 1;
+                report.bytes += data.len() as u64;
+                known.insert(name);
+            }
+            dest.backend.flush()?;
+            dest.blob_store.recover()?;
+
+            dest.snapshot_index.recover(
+                id,
+                &s.family_name,
+                s.created,
+                s.msg.as_ref().map_or("", |m| &m[..]),
+                &hash_ref,
+                Some(db::SnapshotWorkStatus::RecoverInProgress),
+                s.provenance.as_ref(),
+                &s.labels[..],
+            );
+            if let Some(ref signature) = s.signature {
+                dest.set_received_signature(&s.family_name, id, signature);
+            }
+            dest.flush_snapshot_index();
+            dest.resume()?;
+            report.snapshots += 1;
+        }
+
+        Ok(report)
+    }
+}
@@ -0,0 +1,91 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Snapshot retention policies.
+
+use chrono::{self, Datelike};
+use std::collections::BTreeSet;
+
+
+/// Which snapshots of a family to keep; all others may be forgotten.
+///
+/// A snapshot is kept if any rule selects it. `keep_daily` keeps the newest snapshot of each of
+/// the last that many days that have a snapshot, and likewise for the other periods. The
+/// default policy keeps nothing.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetentionPolicy {
+    pub keep_last: usize,
+    pub keep_daily: usize,
+    pub keep_weekly: usize,
+    pub keep_monthly: usize,
+    pub keep_yearly: usize,
+}
+
+#[derive(Clone, Copy)]
+enum Period {
+    Day,
+    Week,
+    Month,
+    Year,
+}
+
+impl Period {
+    fn of(&self, t: &chrono::DateTime<chrono::Utc>) -> (i32, u32) {
+        match *self {
+            Period::Day => (t.year(), t.ordinal()),
+            Period::Week => (t.iso_week().year(), t.iso_week().week()),
+            Period::Month => (t.year(), t.month()),
+            Period::Year => (t.year(), 0),
+        }
+    }
+}
+
+impl RetentionPolicy {
+    /// Ids of the snapshots to keep among `snapshots`, given as (id, creation time).
+    pub fn keep(&self, snapshots: &[(u64, chrono::DateTime<chrono::Utc>)]) -> BTreeSet<u64> {
+        let mut newest_first = snapshots.to_vec();
+        newest_first.sort_by(|a, b| b.1.cmp(&a.1).then(b.0.cmp(&a.0)));
+
+        let mut keep: BTreeSet<u64> = newest_first
+            .iter()
+            .take(self.keep_last)
+            .map(|&(id, _)| id)
+            .collect();
+
+        let rules = [
+            (self.keep_daily, Period::Day),
+            (self.keep_weekly, Period::Week),
+            (self.keep_monthly, Period::Month),
+            (self.keep_yearly, Period::Year),
+        ];
+        for &(count, period) in &rules {
+            let mut last = None;
+            let mut kept = 0;
+            for &(id, ref created) in &newest_first {
+                if kept == count {
+                    break;
+                }
+                let p = period.of(created);
+                if last != Some(p) {
+                    // The newest snapshot of a period not seen yet.
+                    last = Some(p);
+                    keep.insert(id);
+                    kept += 1;
+                }
+            }
+        }
+        keep
+    }
+}
@@ -0,0 +1,205 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The root document: a small named blob pointing at the latest snapshot of every family.
+//!
+//! Every meta commit writes a new version of the document. Most versions only record what
+//! changed since the previous version; every `FULL_ROOT_INTERVAL` versions a complete document
+//! is written and older versions are deleted.
+
+use backend::{ObjectTags, StoreBackend};
+use blob::ROOT_PREFIX;
+use capnp;
+use crypto;
+use errors::HatError;
+use hash::tree::HashRef;
+use root_capnp;
+use std::collections::BTreeMap;
+use std::str;
+
+/// Number of versions between complete root documents.
+const FULL_ROOT_INTERVAL: u64 = 16;
+
+#[derive(Clone, Debug)]
+pub struct FamilyHead {
+    pub snapshot_id: u64,
+    pub utc_timestamp: i64,
+    pub hash_ref: HashRef,
+}
+
+impl FamilyHead {
+    fn same_as(&self, other: &FamilyHead) -> bool {
+        self.snapshot_id == other.snapshot_id && self.hash_ref.hash == other.hash_ref.hash
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct RootDoc {
+    pub version: u64,
+    pub meta_ref: HashRef,
+    pub heads: BTreeMap<String, FamilyHead>,
+}
+
+/// Backend name of a root document version.
+fn root_name(version: u64) -> Vec<u8> {
+    let mut name = ROOT_PREFIX.to_vec();
+    name.extend_from_slice(format!("{}", version).as_bytes());
+    name
+}
+
+fn list_versions<B: StoreBackend>(backend: &B) -> Result<Vec<u64>, HatError> {
+    let mut versions: Vec<u64> = backend
+        .list()?
+        .into_iter()
+        .filter(|n| n.starts_with(ROOT_PREFIX))
+        .filter_map(|n| {
+            str::from_utf8(&n[ROOT_PREFIX.len()..])
+                .ok()
+                .and_then(|v| v.parse().ok())
+        })
+        .collect();
+    versions.sort();
+    Ok(versions)
+}
+
+fn read_version<B: StoreBackend>(
+    keys: &crypto::keys::Keeper,
+    backend: &B,
+    version: u64,
+) -> Result<RootDoc, HatError> {
+    let sealed = backend.retrieve(&root_name(version))?.ok_or(
+        "Root document is missing",
+    )?;
+    let bytes = keys.access_unlock(&sealed).map_err(|_| super::WRONG_PASSPHRASE)?;
+
+    let reader = capnp::serialize_packed::read_message(
+        &mut &bytes[..],
+        capnp::message::ReaderOptions::new(),
+    )?;
+    let root = reader.get_root::<root_capnp::root::Reader>()?;
+
+    let mut doc = match root.get_base_version() {
+        0 => {
+            RootDoc {
+                version: 0,
+                meta_ref: HashRef::read_msg(&root.get_meta_ref()?)?,
+                heads: BTreeMap::new(),
+            }
+        }
+        base => read_version(keys, backend, base)?,
+    };
+
+    doc.version = root.get_version();
+    doc.meta_ref = HashRef::read_msg(&root.get_meta_ref()?)?;
+    for name in root.get_removed_families()?.iter() {
+        doc.heads.remove(name?);
+    }
+    for h in root.get_heads()?.iter() {
+        doc.heads.insert(
+            h.get_family_name()?.to_owned(),
+            FamilyHead {
+                snapshot_id: h.get_snapshot_id(),
+                utc_timestamp: h.get_utc_timestamp(),
+                hash_ref: HashRef::read_msg(&h.get_hash_ref()?)?,
+            },
+        );
+    }
+
+    Ok(doc)
+}
+
+/// Read the latest root document, if any has been written.
+pub fn read_latest<B: StoreBackend>(
+    keys: &crypto::keys::Keeper,
+    backend: &B,
+) -> Result<Option<RootDoc>, HatError> {
+    match list_versions(backend)?.last() {
+        None => Ok(None),
+        Some(&v) => Ok(Some(read_version(keys, backend, v)?)),
+    }
+}
+
+/// Write the next root document, encoded relative to `previous` when possible.
+pub fn write<B: StoreBackend>(
+    keys: &crypto::keys::Keeper,
+    backend: &B,
+    tags: &ObjectTags,
+    previous: Option<&RootDoc>,
+    meta_ref: HashRef,
+    heads: BTreeMap<String, FamilyHead>,
+) -> Result<RootDoc, HatError> {
+    let version = previous.map(|p| p.version).unwrap_or(0) + 1;
+    let base = match previous {
+        Some(p) if version % FULL_ROOT_INTERVAL != 0 => Some(p),
+        _ => None,
+    };
+
+    let changed: Vec<(&String, &FamilyHead)> = heads
+        .iter()
+        .filter(|&(name, head)| match base.and_then(|b| b.heads.get(name)) {
+            Some(old) => !old.same_as(head),
+            None => true,
+        })
+        .collect();
+    let removed: Vec<&String> = match base {
+        Some(b) => b.heads.keys().filter(|n| !heads.contains_key(*n)).collect(),
+        None => vec![],
+    };
+
+    let mut message = capnp::message::Builder::new_default();
+    {
+        let mut root = message.init_root::<root_capnp::root::Builder>();
+        root.set_version(version);
+        root.set_base_version(base.map(|b| b.version).unwrap_or(0));
+        meta_ref.populate_msg(root.borrow().init_meta_ref());
+        {
+            let mut list = root.borrow().init_heads(changed.len() as u32);
+            for (i, &(name, head)) in changed.iter().enumerate() {
+                let mut h = list.borrow().get(i as u32);
+                h.set_family_name(name);
+                h.set_snapshot_id(head.snapshot_id);
+                h.set_utc_timestamp(head.utc_timestamp);
+                head.hash_ref.populate_msg(h.init_hash_ref());
+            }
+        }
+        let mut list = root.init_removed_families(removed.len() as u32);
+        for (i, name) in removed.iter().enumerate() {
+            list.set(i as u32, name);
+        }
+    }
+
+    let mut bytes = Vec::new();
+    capnp::serialize_packed::write_message(&mut bytes, &message)?;
+    backend.store(
+        &root_name(version),
+        &crypto::CipherText::new(keys.access_lock(&bytes)),
+    )?;
+    if let Err(e) = backend.set_object_tags(&root_name(version), &tags.with_class("root")) {
+        warn!("Could not tag root document {}: {}", version, e);
+    }
+    backend.flush()?;
+
+    if base.is_none() && !backend.append_only() {
+        // A complete document makes all older versions obsolete.
+        for v in list_versions(backend)?.into_iter().filter(|v| *v < version) {
+            backend.delete(&root_name(v))?;
+        }
+    }
+
+    Ok(RootDoc {
+        version: version,
+        meta_ref: meta_ref,
+        heads: heads,
+    })
+}
@@ -0,0 +1,187 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Repository-wide statistics.
+
+use backend::StoreBackend;
+use db;
+use errors::HatError;
+use std::collections::BTreeMap;
+use tags;
+
+use super::HatRc;
+
+
+/// Statistics of the completed snapshots of one family.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct FamilyStats {
+    pub family: String,
+    pub snapshots: u64,
+    /// Size of the files in the newest snapshot.
+    pub latest_bytes: u64,
+    /// File data read by the backup runs, summed over all snapshots.
+    pub bytes_read: u64,
+    /// File data first stored by the backup runs, summed over all snapshots.
+    pub bytes_new: u64,
+    /// Bytes used by the family's local key index.
+    pub index_bytes: u64,
+}
+
+/// A limit on the size of a repository, configured as `[repositories.NAME.quota]`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Quota {
+    /// Largest size of the committed blobs, in bytes.
+    pub max_bytes: u64,
+    /// Only warn when a commit is expected to exceed the quota, instead of refusing to run it.
+    #[serde(default)]
+    pub warn_only: bool,
+}
+
+/// How the size of a repository compares to its quota, as reported by `Hat::quota_status`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub struct QuotaStatus {
+    /// Bytes the committed blobs take in the backend.
+    pub used_bytes: u64,
+    /// Bytes they are expected to take after the data about to be committed.
+    pub projected_bytes: u64,
+    pub max_bytes: u64,
+}
+
+impl QuotaStatus {
+    pub fn is_exceeded(&self) -> bool {
+        self.projected_bytes > self.max_bytes
+    }
+
+    /// How many bytes must be pruned to stay within the quota.
+    pub fn excess_bytes(&self) -> u64 {
+        self.projected_bytes.saturating_sub(self.max_bytes)
+    }
+}
+
+/// Statistics of a repository, as reported by `Hat::repository_stats`.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct RepositoryStats {
+    /// Number of committed blobs, and the bytes they take in the backend.
+    pub blobs: u64,
+    pub blob_bytes: u64,
+    /// Number of committed chunks, and their stored size.
+    pub chunks: u64,
+    pub chunk_bytes: u64,
+    /// Bytes used by the local hash index.
+    pub index_bytes: u64,
+    pub families: Vec<FamilyStats>,
+}
+
+impl RepositoryStats {
+    /// How many bytes were read for each byte of new data stored, over all snapshots.
+    pub fn dedup_ratio(&self) -> Option<f64> {
+        let read: u64 = self.families.iter().map(|f| f.bytes_read).sum();
+        let new: u64 = self.families.iter().map(|f| f.bytes_new).sum();
+        if new > 0 {
+            Some(read as f64 / new as f64)
+        } else {
+            None
+        }
+    }
+}
+
+impl<B: StoreBackend> HatRc<B> {
+    /// Gather statistics of the repository. With a namespace set, only the families of that
+    /// namespace are included.
+    ///
+    /// Chunk and blob totals are kept in the hash index as they are counted, so only what was
+    /// added since the last call is read; deleting anything makes the next call count again.
+    pub fn repository_stats(&mut self) -> Result<RepositoryStats, HatError> {
+        let (chunks, chunk_bytes) = self.db.lock().hash_totals();
+        let (blobs, blob_bytes) = self.blob_totals()?;
+
+        let mut families: BTreeMap<String, FamilyStats> = BTreeMap::new();
+        for s in self.list_snapshots() {
+            let f = families.entry(s.family.clone()).or_insert_with(FamilyStats::default);
+            f.family = s.family;
+            f.snapshots += 1;
+            // Snapshots are listed by id, oldest first.
+            f.latest_bytes = s.contents.map_or(0, |c| c.bytes);
+            if let Some(stats) = s.stats {
+                f.bytes_read += stats.bytes_read;
+                f.bytes_new += stats.bytes_new;
+            }
+        }
+        for f in families.values_mut() {
+            let family = self.open_family(f.family.clone())?;
+            f.index_bytes = family.key_store.index_size()?;
+        }
+
+        Ok(RepositoryStats {
+            blobs: blobs,
+            blob_bytes: blob_bytes,
+            chunks: chunks,
+            chunk_bytes: chunk_bytes,
+            index_bytes: self.db.lock().size()?,
+            families: families.into_iter().map(|(_, f)| f).collect(),
+        })
+    }
+
+    /// Compare the size of the repository, once `new_bytes` more are stored, to `quota`. The
+    /// size is kept like the blob totals of `repository_stats`, so this is cheap.
+    pub fn quota_status(&mut self, quota: &Quota, new_bytes: u64) -> Result<QuotaStatus, HatError> {
+        let (_, used) = self.blob_totals()?;
+        Ok(QuotaStatus {
+            used_bytes: used,
+            projected_bytes: used + new_bytes,
+            max_bytes: quota.max_bytes,
+        })
+    }
+
+    /// The new data the next commit of `family` is expected to store: as much as its latest
+    /// snapshot did.
+    pub fn expected_growth(&mut self, family: &str) -> u64 {
+        self.list_snapshots()
+            .into_iter()
+            .rev()
+            .find(|s| s.family == family)
+            .and_then(|s| s.stats)
+            .map_or(0, |stats| stats.bytes_new)
+    }
+
+    /// The number of committed blobs and their size in the backend, kept like
+    /// `db::Index::hash_totals`.
+    fn blob_totals(&mut self) -> Result<(u64, u64), HatError> {
+        let mut index = self.db.lock();
+        let (mut mark, mut count, mut bytes) =
+            match index.config_get(db::BLOB_TOTALS_CONFIG).and_then(|v| db::parse_totals(&v)) {
+                Some(ref t) if t.len() == 3 => (t[0] as i64, t[1], t[2]),
+                _ => (0, 0, 0),
+            };
+        // A blob that is still being written can be committed later, so counting stops
+        // before it.
+        let pending = index.blob_list_by_tag(tags::Tag::InProgress).iter().map(|b| b.id).min();
+        let mut done = index.blob_list_by_tag(tags::Tag::Done);
+        done.sort_by_key(|b| b.id);
+        for blob in done.into_iter().filter(|b| b.id > mark) {
+            if pending.map_or(false, |p| blob.id > p) {
+                break;
+            }
+            count += 1;
+            bytes += match index.blob_length(&blob) {
+                Some(length) => length,
+                None => self.backend.size(&blob.name[..])?.unwrap_or(0),
+            };
+            mark = blob.id;
+        }
+        index.config_set(db::BLOB_TOTALS_CONFIG, &format!("{} {} {}", mark, count, bytes));
+        Ok((count, bytes))
+    }
+}
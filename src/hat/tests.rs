@@ -13,13 +13,19 @@
 // limitations under the License.
 
 
-use backend::{MemoryBackend, StoreBackend};
+use backend::{AppendOnlyBackend, MemoryBackend, StoreBackend};
 use errors::HatError;
-use hat::HatRc;
+use gc::Gc;
+use hat::{AccessLevel, CopyReport, GcReport, HatRc, LockInfo, LockKind, Pattern,
+          RestoreOrder};
 use hat::family::Family;
+use hat::lock;
+use hat::walker;
 use key;
 use std::collections::HashMap;
+use std::path::PathBuf;
 use std::sync::Arc;
+use time;
 use util::FileIterator;
 
 
@@ -121,11 +127,233 @@ fn snapshot_commit() {
     hat.commit(&mut fam, None).unwrap();
     hat.meta_commit().unwrap();
 
-    let (deleted, live) = hat.gc().unwrap();
+    let GcReport { hashes_deleted: deleted, live_chunks: live, .. } = hat.gc().unwrap();
     assert_eq!(deleted, 0);
     assert!(live > 0);
 }
 
+#[test]
+fn concurrent_gc_defers_deletions() {
+    let (_, mut hat, mut fam) = setup_family();
+    snapshot_files(&fam, vec![("a", vec![1; 1000]), ("b", vec![2; 1000])]).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.meta_commit().unwrap();
+    hat.data_flush().unwrap();
+    hat.delete_all_snapshots().unwrap();
+
+    // The first run only starts an epoch.
+    assert_eq!(hat.gc_concurrent().unwrap().hashes_deleted, 0);
+
+    // A commit that reuses some of the data while the next run goes keeps it alive.
+    let mut fam = hat.open_family("familyname".to_string()).unwrap();
+    snapshot_files(&fam, vec![("a", vec![1; 1000])]).unwrap();
+    fam.flush().unwrap();
+    let GcReport { hashes_deleted: deleted, .. } = hat.gc_concurrent().unwrap();
+    assert!(deleted > 0);
+
+    hat.commit(&mut fam, None).unwrap();
+    hat.meta_commit().unwrap();
+    hat.data_flush().unwrap();
+    assert_eq!(hat.check(Some(1.0)).unwrap().problems, vec![]);
+}
+
+#[test]
+fn concurrent_gc_waits_for_older_commits() {
+    use chrono;
+
+    let (backend, mut hat, mut fam) = setup_family();
+    snapshot_files(&fam, vec![("a", vec![1; 1000])]).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.meta_commit().unwrap();
+    hat.data_flush().unwrap();
+    hat.delete_all_snapshots().unwrap();
+
+    // A commit elsewhere that locked before the first run may use hashes it has not stamped.
+    let now = chrono::Utc::now().timestamp();
+    let commit = LockInfo {
+        kind: LockKind::Shared,
+        owner: "elsewhere (pid 1)".to_string(),
+        refreshed_utc: now,
+        operation: "hat commit".to_string(),
+        taken_utc: Some(now - 60),
+    };
+    lock::write(&*backend, b"lock:commit", &commit).unwrap();
+    assert_eq!(hat.gc_concurrent().unwrap().hashes_deleted, 0);
+    assert_eq!(hat.gc_concurrent().unwrap().hashes_deleted, 0);
+
+    lock::remove(&*backend, b"lock:commit").unwrap();
+    assert!(hat.gc_concurrent().unwrap().hashes_deleted > 0);
+}
+
+#[test]
+fn gc_estimate_matches_gc() {
+    let (_, mut hat, mut fam) = setup_family();
+    snapshot_files(&fam, vec![("a", vec![1; 1000]), ("b", vec![2; 50_000])]).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    snapshot_files(&fam, vec![("a", vec![1; 1000]), ("c", vec![3; 10])]).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.meta_commit().unwrap();
+    hat.data_flush().unwrap();
+
+    let estimate = hat.gc_estimate(10).unwrap();
+    assert_eq!((estimate.unused_hashes, estimate.blobs), (0, 0));
+    assert_eq!(estimate.pinned.len(), 2);
+    assert_eq!(estimate.pinned[0].snapshot_id, 1);
+    assert!(estimate.pinned[0].bytes > 50_000);
+    assert_eq!(hat.gc_estimate(1).unwrap().pinned.len(), 1);
+
+    hat.deregister(&fam, 1).unwrap();
+    let estimate = hat.gc_estimate(10).unwrap();
+    assert!(estimate.unused_bytes > 50_000);
+    assert_eq!(estimate.pinned.len(), 1);
+
+    let report = hat.gc().unwrap();
+    assert!(report.snapshots > 0);
+    assert_eq!(report.hashes_deleted, estimate.unused_hashes);
+    assert_eq!(report.blobs_deleted, estimate.blobs);
+    assert_eq!(report.bytes_reclaimed, estimate.blob_bytes);
+    let phases: Vec<&str> = report.phases.iter().map(|p| p.name).collect();
+    assert_eq!(phases, vec!["mark", "delete hashes", "sweep blobs", "prune key indexes"]);
+}
+
+#[test]
+fn gc_grace_period_condemns_blobs_first() {
+    let (_, mut hat, mut fam) = setup_family();
+    snapshot_files(&fam, vec![("a", vec![1; 1000])]).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    snapshot_files(&fam, vec![("b", vec![2; 50_000])]).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.meta_commit().unwrap();
+    hat.data_flush().unwrap();
+
+    hat.deregister(&fam, 2).unwrap();
+    hat.set_gc_grace_period(Some(time::Duration::hours(1)));
+    let report = hat.gc().unwrap();
+    assert!(report.blobs_condemned > 0);
+    assert_eq!(report.blobs_deleted, 0);
+    // Condemned blobs are still readable, and found again by the next run.
+    assert_eq!(hat.check(Some(1.0)).unwrap().problems, vec![]);
+    assert_eq!(hat.gc().unwrap().blobs_condemned, report.blobs_condemned);
+
+    hat.set_gc_grace_period(Some(time::Duration::seconds(0)));
+    let last = hat.gc().unwrap();
+    assert_eq!((last.blobs_condemned, last.blobs_deleted), (0, report.blobs_condemned));
+}
+
+#[test]
+fn gc_grace_period_keeps_hashes_until_blobs_are_deleted() {
+    let (_, mut hat, mut fam) = setup_family();
+    snapshot_files(&fam, vec![("a", vec![1; 1000])]).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    snapshot_files(&fam, vec![("b", vec![2; 50_000])]).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.meta_commit().unwrap();
+    hat.data_flush().unwrap();
+
+    hat.deregister(&fam, 2).unwrap();
+    hat.set_gc_grace_period(Some(time::Duration::hours(1)));
+    let report = hat.gc().unwrap();
+    assert!(report.blobs_condemned > 0);
+    assert_eq!(report.hashes_deleted, 0);
+
+    // Committing the same data again takes up the condemned blobs rather than uploading them.
+    snapshot_files(&fam, vec![("b", vec![2; 50_000])]).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.meta_commit().unwrap();
+    hat.data_flush().unwrap();
+
+    // Which spares them once the grace period is over.
+    hat.set_gc_grace_period(Some(time::Duration::seconds(0)));
+    assert!(hat.gc().unwrap().blobs_deleted < report.blobs_condemned);
+    assert_eq!(hat.check(Some(1.0)).unwrap().problems, vec![]);
+}
+
+#[test]
+fn gc_throttles_blob_deletes() {
+    use backend::{Throttle, ThrottledBackend};
+    use std::time::{Duration, Instant};
+
+    let throttle = Throttle {
+        ops_per_sec: Some(20),
+        concurrency: Some(4),
+    };
+    let backend = Arc::new(ThrottledBackend::new(MemoryBackend::new(), throttle));
+    let mut hat = HatRc::new_for_testing(backend.clone(), 32 * 1024).unwrap();
+    let mut fam = hat.open_family("familyname".to_string()).unwrap();
+    let data: Vec<u8> = (0..200_000).map(|i| (i * 7 % 251) as u8).collect();
+    snapshot_files(&fam, vec![("a", data)]).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.meta_commit().unwrap();
+    hat.data_flush().unwrap();
+
+    hat.deregister(&fam, 1).unwrap();
+    let start = Instant::now();
+    let report = hat.gc().unwrap();
+    assert!(report.blobs_deleted > 2);
+    // Deletes start at most 50ms apart, however many run at once.
+    let spacing = Duration::from_millis(50) * (report.blobs_deleted as u32 - 1);
+    assert!(start.elapsed() >= spacing);
+}
+
+#[test]
+fn scrub_rotates_through_blobs() {
+    let mut hat = HatRc::new_for_testing(Arc::new(MemoryBackend::new()), 32 * 1024).unwrap();
+    let mut fam = hat.open_family("familyname".to_string()).unwrap();
+    let data: Vec<u8> = (0..200_000).map(|i| (i * 7 % 251) as u8).collect();
+    snapshot_files(&fam, vec![("a", data)]).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.meta_commit().unwrap();
+    hat.data_flush().unwrap();
+    let blobs = hat.blob_index.list_by_verified().len() as u64;
+    assert!(blobs > 1);
+
+    // The smallest budget still reads one blob.
+    let report = hat.scrub(1).unwrap();
+    assert!(report.is_ok());
+    assert_eq!(report.blobs, 1);
+    assert_eq!(report.never_verified, blobs - 1);
+
+    let report = hat.scrub(u64::max_value()).unwrap();
+    assert!(report.is_ok());
+    assert_eq!(report.blobs, blobs);
+    assert_eq!(report.never_verified, 0);
+    assert!(report.oldest_verified_utc.is_some());
+}
+
+#[test]
+fn mark_sweep_gc_repairs_drifted_counts() {
+    let (_, mut hat, mut fam) = setup_family();
+    snapshot_files(&fam, vec![("a", vec![1; 1000]), ("b", vec![2; 50_000])]).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.meta_commit().unwrap();
+    hat.data_flush().unwrap();
+    assert!(hat.gc_cross_check().unwrap().is_ok());
+
+    // Lose the count of the only snapshot; a plain gc would now delete all of it.
+    hat.gc.reset_roots(&[]).unwrap();
+    hat.hash_index.flush();
+    let drift = hat.gc_cross_check().unwrap();
+    assert!(drift.endangered > 0);
+    assert_eq!(drift.leaked, 0);
+
+    assert_eq!(hat.gc_mark_sweep().unwrap().hashes_deleted, 0);
+    assert!(hat.gc_cross_check().unwrap().is_ok());
+    assert_eq!(hat.gc().unwrap().hashes_deleted, 0);
+    assert_eq!(hat.check(Some(1.0)).unwrap().problems, vec![]);
+}
+
 #[test]
 fn snapshot_commit_many_empty_files() {
     let (_, mut hat, mut fam) = setup_family();
@@ -137,18 +365,18 @@ fn snapshot_commit_many_empty_files() {
     hat.commit(&mut fam, None).unwrap();
     hat.meta_commit().unwrap();
 
-    let (deleted, live) = hat.gc().unwrap();
+    let GcReport { hashes_deleted: deleted, live_chunks: live, .. } = hat.gc().unwrap();
     assert_eq!(deleted, 0);
     assert!(live > 0);
 
     hat.deregister(&fam, 1).unwrap();
-    let (deleted, live) = hat.gc().unwrap();
+    let GcReport { hashes_deleted: deleted, live_chunks: live, .. } = hat.gc().unwrap();
     assert!(deleted > 0);
     assert!(live > 0);
 
     // Delete everything including root.
     hat.delete_all_snapshots().unwrap();
-    let (deleted, live) = hat.gc().unwrap();
+    let GcReport { hashes_deleted: deleted, live_chunks: live, .. } = hat.gc().unwrap();
     assert!(deleted > 0);
     assert_eq!(live, 0);
 }
@@ -166,18 +394,18 @@ fn snapshot_commit_many_empty_directories() {
     hat.commit(&mut fam, None).unwrap();
     hat.meta_commit().unwrap();
 
-    let (deleted, live) = hat.gc().unwrap();
+    let GcReport { hashes_deleted: deleted, live_chunks: live, .. } = hat.gc().unwrap();
     assert_eq!(deleted, 0);
     assert!(live > 0);
 
     hat.deregister(&fam, 1).unwrap();
-    let (deleted, live) = hat.gc().unwrap();
+    let GcReport { hashes_deleted: deleted, live_chunks: live, .. } = hat.gc().unwrap();
     assert!(deleted > 0);
     assert!(live > 0);
 
     // Delete everything including root.
     hat.delete_all_snapshots().unwrap();
-    let (deleted, live) = hat.gc().unwrap();
+    let GcReport { hashes_deleted: deleted, live_chunks: live, .. } = hat.gc().unwrap();
     assert!(deleted > 0);
     assert_eq!(live, 0);
 }
@@ -196,7 +424,7 @@ fn snapshot_reuse_index() {
     fam.flush().unwrap();
 
     // No commit, so GC removes all the new hashes.
-    let (deleted, live) = hat.gc().unwrap();
+    let GcReport { hashes_deleted: deleted, live_chunks: live, .. } = hat.gc().unwrap();
     assert!(deleted > 0);
     assert_eq!(live, 0);
 
@@ -207,20 +435,20 @@ fn snapshot_reuse_index() {
     // Commit.
     hat.commit(&mut fam, None).unwrap();
     hat.data_flush().unwrap();
-    let (deleted, live) = hat.gc().unwrap();
+    let GcReport { hashes_deleted: deleted, live_chunks: live, .. } = hat.gc().unwrap();
     assert_eq!(deleted, 0);
     assert!(live > 0);
 
     // Inserting again does not increase number of hashes.
     basic_snapshot(&fam);
     fam.flush().unwrap();
-    let (deleted2, live2) = hat.gc().unwrap();
+    let GcReport { hashes_deleted: deleted2, live_chunks: live2, .. } = hat.gc().unwrap();
     assert_eq!(live2, live);
     assert_eq!(deleted2, 0);
 
     // Cleanup: only 1 snapshot was committed.
     hat.deregister(&fam, 1).unwrap();
-    let (deleted, live) = hat.gc().unwrap();
+    let GcReport { hashes_deleted: deleted, live_chunks: live, .. } = hat.gc().unwrap();
     assert!(deleted > 0);
     assert_eq!(live, 0);
 }
@@ -233,7 +461,7 @@ fn snapshot_gc() {
     fam.flush().unwrap();
 
     // No commit so everything is deleted.
-    let (deleted, live) = hat.gc().unwrap();
+    let GcReport { hashes_deleted: deleted, live_chunks: live, .. } = hat.gc().unwrap();
     assert!(deleted > 0);
     assert_eq!(live, 0);
 }
@@ -249,7 +477,7 @@ fn recover() {
     hat.meta_commit().unwrap();
     hat.data_flush().unwrap();
 
-    let (deleted, live1) = hat.gc().unwrap();
+    let GcReport { hashes_deleted: deleted, live_chunks: live1, .. } = hat.gc().unwrap();
     assert_eq!(deleted, 0);
     assert!(live1 > 0);
 
@@ -260,20 +488,2875 @@ fn recover() {
     hat2.recover().unwrap();
 
     // Check that we now reference all the blobs.
-    let (deleted, live2) = hat2.gc().unwrap();
+    let GcReport { hashes_deleted: deleted, live_chunks: live2, .. } = hat2.gc().unwrap();
     assert_eq!(deleted, 0);
     assert_eq!(live1, live2);
 
     // Check that we can delete the snapshot.
     hat2.deregister(&fam, 1).unwrap();
 
-    let (deleted, live3) = hat2.gc().unwrap();
+    let GcReport { hashes_deleted: deleted, live_chunks: live3, .. } = hat2.gc().unwrap();
     assert!(deleted > 0);
     assert!(live3 > 0);
 
     // Delete everything including root.
     hat2.delete_all_snapshots().unwrap();
-    let (deleted, live4) = hat2.gc().unwrap();
+    let GcReport { hashes_deleted: deleted, live_chunks: live4, .. } = hat2.gc().unwrap();
     assert!(deleted > 0);
     assert_eq!(live4, 0);
 }
+
+#[test]
+fn permissions() {
+    let (_, mut hat, mut fam) = setup_family();
+    basic_snapshot(&fam);
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.meta_commit().unwrap();
+
+    let perms = hat.permissions();
+    assert_eq!(perms.access, AccessLevel::Admin);
+    assert_eq!(perms.families, vec![("familyname".to_string(), true)]);
+}
+
+#[test]
+fn hash_algorithm_is_enforced() {
+    use db;
+    use hat::{HashAlgorithm, select_hash_algorithm};
+
+    let index = db::Index::new_for_testing();
+    assert_eq!(
+        select_hash_algorithm(&index, Some(HashAlgorithm::Blake3)).unwrap(),
+        HashAlgorithm::Blake3
+    );
+
+    // Later runs reuse the recorded algorithm and refuse to switch.
+    assert_eq!(
+        select_hash_algorithm(&index, None).unwrap(),
+        HashAlgorithm::Blake3
+    );
+    assert!(select_hash_algorithm(&index, Some(HashAlgorithm::Blake2b)).is_err());
+}
+
+#[test]
+fn snapshots_are_signed() {
+    use std::io;
+
+    let (_, mut hat, mut fam) = setup_family();
+    snapshot_files(&fam, vec![("a", vec![1; 10000])]).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.check_signature("familyname", 1).unwrap();
+
+    // Signatures follow a family to its new name.
+    hat.rename_family("familyname", "renamed").unwrap();
+    hat.check_signature("renamed", 1).unwrap();
+
+    // A snapshot whose signature does not match its root is refused.
+    let (info, _, _) = hat.snapshot_index.lookup("renamed", 1).unwrap();
+    let mut forged = hat.keys.sign(b"something else");
+    hat.snapshot_index.set_signature(&info, &forged);
+    assert!(hat.check_signature("renamed", 1).is_err());
+    assert!(hat.export_tar("renamed", Some(1), io::sink()).is_err());
+
+    forged.truncate(10);
+    hat.snapshot_index.set_signature(&info, &forged);
+    assert!(hat.verify_snapshot("renamed", 1).is_err());
+
+    // A snapshot without a signature is refused unless the user allows it.
+    hat.snapshot_index.clear_signature(&info);
+    assert!(hat.check_signature("renamed", 1).is_err());
+    hat.set_allow_unsigned(true);
+    hat.check_signature("renamed", 1).unwrap();
+}
+
+#[test]
+fn patch_bundle() {
+    let (_, mut hat, mut fam) = setup_family();
+    basic_snapshot(&fam);
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.data_flush().unwrap();
+
+    snapshot_files(&fam, vec![("new-file", vec![7; 100000])]).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.data_flush().unwrap();
+
+    let mut full = vec![];
+    let full_count = hat.create_patch("familyname", None, 1, &mut full).unwrap();
+    let mut incremental = vec![];
+    let incremental_count = hat.create_patch("familyname", Some(1), 2, &mut incremental)
+        .unwrap();
+    assert!(full_count > 0);
+    assert!(incremental_count > 0);
+    assert!(incremental.len() < full.len());
+
+    // The incremental patch cannot be applied before its base.
+    let mut replica = setup_hat(Arc::new(MemoryBackend::new()));
+    assert!(replica.apply_patch(&mut &incremental[..]).is_err());
+
+    assert!(replica.apply_patch(&mut &full[..]).unwrap());
+    assert!(replica.apply_patch(&mut &incremental[..]).unwrap());
+    // Applying a bundle again is reported as doing nothing.
+    assert!(!replica.apply_patch(&mut &full[..]).unwrap());
+
+    let ids: Vec<u64> = replica
+        .snapshot_index
+        .list_all()
+        .into_iter()
+        .map(|s| s.info.snapshot_id)
+        .collect();
+    assert_eq!(ids, vec![1, 2]);
+
+    let GcReport { hashes_deleted: deleted, live_chunks: live, .. } = replica.gc().unwrap();
+    assert_eq!(deleted, 0);
+    assert!(live > 0);
+}
+
+#[test]
+fn copy_snapshots_to_another_repository() {
+    let (_, mut hat, mut fam) = setup_family();
+    basic_snapshot(&fam);
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.data_flush().unwrap();
+
+    let mut dest = setup_hat(Arc::new(MemoryBackend::new()));
+    let first = hat.copy_snapshots(&mut dest, None).unwrap();
+    assert_eq!(first.snapshots, 1);
+    assert!(first.blobs > 0);
+
+    // Nothing is copied twice.
+    let again = hat.copy_snapshots(&mut dest, None).unwrap();
+    assert_eq!(
+        again,
+        CopyReport {
+            present: 1,
+            ..CopyReport::default()
+        }
+    );
+
+    snapshot_files(&fam, vec![("new-file", vec![7; 100000])]).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.data_flush().unwrap();
+
+    let second = hat.copy_snapshots(&mut dest, Some("familyname")).unwrap();
+    assert_eq!(second.snapshots, 1);
+    assert!(second.blobs > 0);
+
+    let ids: Vec<u64> = dest.snapshot_index
+        .list_all()
+        .into_iter()
+        .map(|s| s.info.snapshot_id)
+        .collect();
+    assert_eq!(ids, vec![1, 2]);
+
+    let mut out = vec![];
+    dest.export_tar("familyname", Some(2), &mut out).unwrap();
+    assert!(out.len() > 100000);
+}
+
+#[test]
+fn copy_snapshots_between_initialized_repositories() {
+    use std::env;
+    use std::fs;
+    use std::path::Path;
+    use std::process;
+
+    let root = env::temp_dir().join(format!("hat-copy-{}", process::id()));
+    for name in &["source", "other", "dest"] {
+        fs::create_dir_all(root.join(name)).unwrap();
+    }
+    let migrations = Path::new("migrations");
+    let passphrase = Some(&b"copy passphrase"[..]);
+    let init = |name: &str, backend: Arc<MemoryBackend>| {
+        HatRc::init_repository(migrations, root.join(name), backend, 1024 * 1024, None, passphrase)
+            .unwrap()
+            .0
+    };
+
+    let mut hat = init("source", Arc::new(MemoryBackend::new()));
+    let mut fam = hat.open_family("familyname".to_string()).unwrap();
+    snapshot_files(&fam, vec![("a", vec![5; 100000])]).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.meta_commit().unwrap();
+    hat.data_flush().unwrap();
+
+    // A repository with a fingerprint secret of its own would reject every copied chunk.
+    let mut other = init("other", Arc::new(MemoryBackend::new()));
+    assert!(hat.copy_snapshots(&mut other, None).is_err());
+
+    let dest_backend = Arc::new(MemoryBackend::new());
+    hat.share_fingerprint_secret(&*dest_backend).unwrap();
+    drop(init("dest", dest_backend.clone()));
+    let mut dest = HatRc::open_repository(
+        migrations,
+        root.join("dest"),
+        dest_backend,
+        1024 * 1024,
+        None,
+        passphrase,
+    ).unwrap();
+    assert_eq!(hat.copy_snapshots(&mut dest, None).unwrap().snapshots, 1);
+
+    let mut out = vec![];
+    dest.export_tar("familyname", Some(1), &mut out).unwrap();
+    assert!(out.len() > 100000);
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn wrong_passphrase_is_reported() {
+    use std::env;
+    use std::fs;
+    use std::path::Path;
+    use std::process;
+
+    let root = env::temp_dir().join(format!("hat-wrong-passphrase-{}", process::id()));
+    fs::create_dir_all(root.join("first")).unwrap();
+    fs::create_dir_all(root.join("second")).unwrap();
+    let migrations = Path::new("migrations");
+    let backend = Arc::new(MemoryBackend::new());
+    drop(
+        HatRc::init_repository(
+            migrations,
+            root.join("first"),
+            backend.clone(),
+            1024 * 1024,
+            None,
+            Some(&b"right"[..]),
+        ).unwrap(),
+    );
+
+    // A fresh index has to unseal the fingerprint secret kept in the backend.
+    match HatRc::open_repository(
+        migrations,
+        root.join("second"),
+        backend,
+        1024 * 1024,
+        None,
+        Some(&b"wrong"[..]),
+    ) {
+        Ok(_) => panic!("opened a repository with the wrong passphrase"),
+        Err(e) => assert!(e.to_string().contains("Wrong passphrase")),
+    }
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn rename_and_delete_family() {
+    let (_, mut hat, mut fam) = setup_family();
+    snapshot_files(&fam, vec![("a", vec![1; 10000])]).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.data_flush().unwrap();
+
+    let mut other = hat.open_family("other".to_string()).unwrap();
+    hat.commit(&mut other, None).unwrap();
+    assert!(hat.rename_family("familyname", "other").is_err());
+    assert!(hat.rename_family("missing", "renamed").is_err());
+
+    hat.rename_family("familyname", "renamed").unwrap();
+    let names: Vec<(String, u64)> = hat.snapshot_index
+        .list_all()
+        .into_iter()
+        .filter(|s| s.family_name != "other")
+        .map(|s| (s.family_name, s.info.snapshot_id))
+        .collect();
+    assert_eq!(names, vec![("renamed".to_string(), 1)]);
+    let mut out = vec![];
+    hat.export_tar("renamed", Some(1), &mut out).unwrap();
+
+    assert_eq!(hat.delete_family("renamed").unwrap(), 1);
+    assert!(hat.delete_family("renamed").is_err());
+    assert!(hat.snapshot_index.list_all().iter().all(|s| s.family_name == "other"));
+
+    let GcReport { hashes_deleted: deleted, .. } = hat.gc().unwrap();
+    assert!(deleted > 0);
+}
+
+#[test]
+fn fingerprint_secret_is_per_repository() {
+    use blob::{LeafType, NodeType};
+    use crypto::keys::Keeper;
+    use db;
+    use hash::Hash;
+    use hat::load_fingerprint_secret;
+
+    let keys = Keeper::new_for_testing();
+    let backend = MemoryBackend::new();
+
+    let index = db::Index::new_for_testing();
+    let secret = load_fingerprint_secret(&index, &keys, &backend).unwrap().unwrap();
+
+    // A second index on the same backend picks up the same secret.
+    let index2 = db::Index::new_for_testing();
+    assert_eq!(
+        load_fingerprint_secret(&index2, &keys, &backend).unwrap(),
+        Some(secret.clone())
+    );
+
+    // Another repository gets its own secret and thus its own fingerprints.
+    let other = load_fingerprint_secret(&db::Index::new_for_testing(), &keys, &MemoryBackend::new())
+        .unwrap()
+        .unwrap();
+    assert!(secret != other);
+
+    let a = Keeper::new_for_testing().with_fingerprint_secret(&secret);
+    let b = Keeper::new_for_testing().with_fingerprint_secret(&other);
+    assert!(
+        Hash::new(&a, NodeType::Leaf, LeafType::FileChunk, b"known") !=
+            Hash::new(&b, NodeType::Leaf, LeafType::FileChunk, b"known")
+    );
+}
+
+#[test]
+fn prune_hashes_without_blobs() {
+    use tags;
+
+    let (_, mut hat, mut fam) = setup_family();
+    basic_snapshot(&fam);
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.data_flush().unwrap();
+
+    // Nothing to prune in a consistent index.
+    assert_eq!(hat.hash_index.prune(), 0);
+
+    // Forget about one of the blobs.
+    let blob = hat.blob_store.list_by_tag(tags::Tag::Done).pop().unwrap();
+    {
+        let index = hat.db.lock();
+        index.blob_set_tag(tags::Tag::InProgress, Some(&blob));
+        index.blob_delete_by_tag(tags::Tag::InProgress);
+    }
+
+    assert!(hat.hash_index.prune() > 0);
+    assert_eq!(hat.hash_index.prune(), 0);
+    assert!(hat.hash_index.list().iter().all(|e| {
+        e.persistent_ref.as_ref().map_or(true, |r| r.blob_name != blob.name)
+    }));
+}
+
+#[test]
+fn limits_skip_large_files() {
+    use hat::Limits;
+
+    let backend = Arc::new(MemoryBackend::new());
+    let mut hat = setup_hat(backend);
+    hat.set_limits(Limits {
+        max_file_size: Some(100),
+        max_commit_bytes: Some(150),
+        abort: false,
+    });
+    let fam = hat.open_family("familyname".to_string()).unwrap();
+
+    let insert = |name: &str, size: usize| {
+        let mut e = entry(name.bytes().collect());
+        e.info.byte_length = Some(size as u64);
+        fam.snapshot_direct(e, false, Some(FileIterator::from_bytes(vec![1; size])))
+    };
+
+    assert!(insert("small", 50).is_ok());
+    assert!(insert("too-big", 101).is_err());
+    assert!(insert("fits", 100).is_ok());
+    // The commit budget is used up by now, but data that is stored already does not count.
+    assert!(insert("over-budget", 10).is_err());
+    assert!(insert("copy-of-fits", 100).is_ok());
+    fam.flush().unwrap();
+
+    let names: Vec<Vec<u8>> = fam.list_from_key_store(None)
+        .unwrap()
+        .into_iter()
+        .map(|(e, _, _)| e.info.name)
+        .collect();
+    assert_eq!(names.len(), 3);
+    assert!(names.contains(&b"small".to_vec()));
+    assert!(names.contains(&b"fits".to_vec()));
+    assert!(names.contains(&b"copy-of-fits".to_vec()));
+}
+
+#[test]
+fn root_document() {
+    use hat::root;
+
+    let (backend, mut hat, mut fam) = setup_family();
+    let mut other = hat.open_family("other".to_string()).unwrap();
+    assert!(root::read_latest(&hat.keys, &*backend).unwrap().is_none());
+
+    for i in 0..20 {
+        snapshot_files(&fam, vec![("file", vec![i; 10])]).unwrap();
+        fam.flush().unwrap();
+        hat.commit(&mut fam, None).unwrap();
+        if i == 0 {
+            snapshot_files(&other, vec![("file", vec![1; 10])]).unwrap();
+            other.flush().unwrap();
+            hat.commit(&mut other, None).unwrap();
+        }
+        hat.meta_commit().unwrap();
+    }
+    hat.data_flush().unwrap();
+
+    let doc = root::read_latest(&hat.keys, &*backend).unwrap().unwrap();
+    assert_eq!(doc.version, 20);
+    assert_eq!(doc.heads.len(), 2);
+    assert_eq!(doc.heads["familyname"].snapshot_id, 20);
+    assert_eq!(doc.heads["other"].snapshot_id, 1);
+
+    // Versions before the last complete document have been deleted.
+    let roots = backend
+        .list()
+        .unwrap()
+        .into_iter()
+        .filter(|n| n.starts_with(b"root:"))
+        .count();
+    assert_eq!(roots, 5);
+}
+
+#[test]
+fn interrupted_root_commit_is_resumed() {
+    use hat::{PENDING_ROOT_CONFIG, root};
+
+    let (backend, mut hat, mut fam) = setup_family();
+    snapshot_files(&fam, vec![("file", vec![1; 10])]).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.meta_commit().unwrap();
+    assert_eq!(root::read_latest(&hat.keys, &*backend).unwrap().unwrap().version, 1);
+    assert!(hat.db.lock().config_get(PENDING_ROOT_CONFIG).is_none());
+
+    // Stop after the first phase: the root document does not point at the listing yet.
+    let listing = hat.snapshot_index.latest("familyname").unwrap().2.unwrap();
+    hat.prepare_root_commit(&listing);
+    hat.resume().unwrap();
+    let doc = root::read_latest(&hat.keys, &*backend).unwrap().unwrap();
+    assert_eq!(doc.version, 2);
+    assert_eq!(doc.meta_ref.hash, listing.hash);
+    assert!(hat.db.lock().config_get(PENDING_ROOT_CONFIG).is_none());
+
+    // Stop after the document was written: only the intent is left to clear.
+    hat.prepare_root_commit(&listing);
+    hat.resume().unwrap();
+    assert_eq!(root::read_latest(&hat.keys, &*backend).unwrap().unwrap().version, 2);
+    assert!(hat.db.lock().config_get(PENDING_ROOT_CONFIG).is_none());
+    hat.data_flush().unwrap();
+}
+
+#[test]
+fn iterate_snapshot_dir() {
+    let (_, mut hat, mut fam) = setup_family();
+
+    // More files than fit in a single listing chunk.
+    let names: Vec<String> = (0..2500).map(|i| format!("file{}", i)).collect();
+    snapshot_files(
+        &fam,
+        names.iter().map(|n| (&n[..], vec![])).collect(),
+    ).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.meta_commit().unwrap();
+
+    let dir_ref = match hat.snapshot_index.latest(&fam.name) {
+        Some((_, _, Some(r))) => r,
+        _ => panic!("No snapshot"),
+    };
+    let mut listed: Vec<String> = hat.iter_snapshot_dir(&fam, dir_ref)
+        .unwrap()
+        .map(|r| String::from_utf8(r.unwrap().0.info.name).unwrap())
+        .collect();
+    listed.sort();
+    let mut expected = names.clone();
+    expected.sort();
+    assert_eq!(listed, expected);
+}
+
+#[test]
+fn small_files_are_inlined() {
+    let (_, mut hat, _) = setup_family();
+    hat.set_inline_max(Some(16));
+    let mut fam = hat.open_family("inline".to_string()).unwrap();
+
+    snapshot_files(
+        &fam,
+        vec![("small", "tiny".into()), ("empty", vec![]), ("dir/small2", "tiny2".into())],
+    ).unwrap();
+    fam.flush().unwrap();
+
+    // The local index only holds the contents sealed.
+    let small = fam.list_from_key_store(None)
+        .unwrap()
+        .into_iter()
+        .find(|&(ref e, _, _)| e.info.name == b"small".to_vec())
+        .unwrap()
+        .0;
+    match small.data {
+        key::Data::FileInline(sealed) => {
+            assert!(!sealed.windows(4).any(|w| w == b"tiny"));
+            assert_eq!(fam.key_store.unseal_inline(&sealed).unwrap(), b"tiny".to_vec());
+        }
+        _ => panic!("Not inline"),
+    }
+
+    hat.commit(&mut fam, None).unwrap();
+
+    let dir_ref = match hat.snapshot_index.latest(&fam.name) {
+        Some((_, _, Some(r))) => r,
+        _ => panic!("No snapshot"),
+    };
+    let mut files: Vec<(Vec<u8>, Vec<u8>)> = hat.iter_snapshot_dir(&fam, dir_ref)
+        .unwrap()
+        .filter_map(|r| match r.unwrap() {
+            (e, walker::Content::Inline(bytes)) => Some((e.info.name, bytes)),
+            _ => None,
+        })
+        .collect();
+    files.sort();
+    assert_eq!(
+        files,
+        vec![(b"empty".to_vec(), vec![]), (b"small".to_vec(), b"tiny".to_vec())]
+    );
+}
+
+#[test]
+fn deregister_records_verified_chunks() {
+    let (_, mut hat, mut fam) = setup_family();
+
+    basic_snapshot(&fam);
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.meta_commit().unwrap();
+
+    let dir_ref = match hat.snapshot_index.latest(&fam.name) {
+        Some((_, _, Some(r))) => r,
+        _ => panic!("No snapshot"),
+    };
+    assert_eq!(hat.hash_index.verified_at(&dir_ref.hash), None);
+
+    hat.deregister(&fam, 1).unwrap();
+    assert!(hat.hash_index.verified_at(&dir_ref.hash).is_some());
+}
+
+#[test]
+fn snapshot_dedup_stats() {
+    let (_, mut hat, mut fam) = setup_family();
+
+    snapshot_files(&fam, vec![("a", vec![1; 1000]), ("b", vec![1; 1000])]).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+
+    snapshot_files(&fam, vec![("c", vec![2; 500]), ("d", vec![1; 1000])]).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.meta_commit().unwrap();
+
+    let snapshots = hat.list_snapshots();
+    assert_eq!(snapshots.len(), 2);
+
+    let first = snapshots[0].stats.unwrap();
+    assert_eq!(first.bytes_read, 2000);
+    assert_eq!(first.bytes_new, 1000);
+
+    let second = snapshots[1].stats.unwrap();
+    assert_eq!(second.bytes_read, 1500);
+    assert_eq!(second.bytes_new, 500);
+    assert_eq!(second.bytes_deduplicated(), 1000);
+}
+
+#[test]
+fn snapshot_history_lists_contents_and_state() {
+    use hat::{SnapshotContents, SnapshotState};
+
+    let (_, mut hat, mut fam) = setup_family();
+    for &(name, len) in &[("a", 1000), ("b", 10)] {
+        let mut e = entry(name.into());
+        e.info.byte_length = Some(len as u64);
+        fam.snapshot_direct(e, false, Some(FileIterator::from_bytes(vec![7; len])))
+            .unwrap();
+    }
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.meta_commit().unwrap();
+
+    // A second backup run that has not finished yet.
+    hat.snapshot_index.reserve("familyname".to_string());
+
+    let history = hat.list_snapshot_history();
+    assert_eq!(history.len(), 2);
+    assert_eq!(history[0].state, SnapshotState::Complete);
+    assert_eq!(
+        history[0].contents,
+        Some(SnapshotContents {
+            files: 2,
+            bytes: 1010,
+        })
+    );
+    assert!(history[0].finished.unwrap() >= history[0].created);
+    assert_eq!(history[1].state, SnapshotState::InProgress);
+    assert_eq!(history[1].finished, None);
+    assert_eq!(hat.list_snapshots().len(), 1);
+}
+
+#[test]
+fn stored_objects_are_tagged() {
+    let (backend, mut hat, mut fam) = setup_family();
+
+    snapshot_files(&fam, vec![("a", vec![0; 1000])]).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.meta_commit().unwrap();
+
+    let tags = hat.object_tags().clone();
+    assert_eq!(tags.repository.len(), 32);
+
+    let mut classes = vec![];
+    for name in backend.list().unwrap() {
+        if let Some(t) = backend.object_tags(&name).unwrap() {
+            assert_eq!(t.repository, tags.repository);
+            assert_eq!(t.run, tags.run);
+            classes.push(t.class);
+        }
+    }
+    assert!(classes.iter().any(|c| c == "data"));
+    assert!(classes.iter().any(|c| c == "root"));
+}
+
+#[test]
+fn restore_order_schedules_files() {
+    let file = |path: &str, size: usize| {
+        let name = path.rsplit('/').next().unwrap().as_bytes().to_vec();
+        (PathBuf::from(path), entry(name), walker::Content::Inline(vec![0; size]))
+    };
+    let files = || vec![file("a/big.db", 30), file("b/app.conf", 20), file("c/tiny", 10)];
+    let order = |o: RestoreOrder| {
+        let mut fs = files();
+        o.schedule(&mut fs);
+        fs.into_iter().map(|(p, _, _)| p.to_str().unwrap().to_owned()).collect::<Vec<_>>()
+    };
+
+    assert_eq!(order(RestoreOrder::Listing), vec!["a/big.db", "b/app.conf", "c/tiny"]);
+    assert_eq!(order(RestoreOrder::SmallestFirst), vec!["c/tiny", "b/app.conf", "a/big.db"]);
+    assert_eq!(
+        order(RestoreOrder::Patterns(vec![Pattern::new("*.conf"), Pattern::new("*.db")])),
+        vec!["b/app.conf", "a/big.db", "c/tiny"]
+    );
+}
+
+#[test]
+fn schema_version_is_current() {
+    use db;
+
+    let hat = setup_hat(Arc::new(MemoryBackend::new()));
+    assert_eq!(hat.db.lock().schema_version().unwrap(), db::SCHEMA_VERSION);
+}
+
+#[test]
+fn family_fidelity_drops_metadata() {
+    use hat::Fidelity;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+
+    let mut hat = setup_hat(Arc::new(MemoryBackend::new()));
+    hat.set_fidelity("lightweight", Fidelity::Content);
+    assert_eq!(hat.fidelity("lightweight").unwrap(), Fidelity::Content);
+    assert_eq!(hat.fidelity("other").unwrap(), Fidelity::Forensic);
+
+    let fam = hat.open_family("lightweight".to_string()).unwrap();
+    assert_eq!(fam.fidelity, Fidelity::Content);
+
+    let mut e = entry(b"file".to_vec());
+    e.info.permissions = Some(fs::Permissions::from_mode(0o600));
+    e.info.user_id = Some(1000);
+    e.info.modified_ts_secs = Some(1234);
+    fam.snapshot_direct(e, false, Some(FileIterator::from_bytes(vec![1, 2, 3]))).unwrap();
+    fam.flush().unwrap();
+
+    let listing = fam.list_from_key_store(None).unwrap();
+    assert_eq!(listing.len(), 1);
+    let info = &listing[0].0.info;
+    assert_eq!(info.permissions, None);
+    assert_eq!(info.user_id, None);
+    assert_eq!(info.modified_ts_secs, Some(1234));
+}
+
+#[test]
+fn maintain_indexes_reports_every_index() {
+    let (_, mut hat, mut fam) = setup_family();
+
+    basic_snapshot(&fam);
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.meta_commit().unwrap();
+
+    let reports = hat.maintain_indexes().unwrap();
+    // The hash index and at least the key index of the family.
+    assert!(reports.len() >= 2);
+    assert!(reports.iter().all(|r| r.is_ok() && r.bytes_after > 0));
+
+    // The indexes are still usable afterwards.
+    basic_snapshot(&fam);
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+}
+
+#[test]
+fn namespaces_keep_families_apart() {
+    let mut hat = setup_hat(Arc::new(MemoryBackend::new()));
+    assert!(hat.set_namespace(Some("a/b".to_string())).is_err());
+
+    for host in &["alpha", "beta"] {
+        hat.set_namespace(Some(host.to_string())).unwrap();
+        let name = hat.family_name("home");
+        let mut fam = hat.open_family(name).unwrap();
+        basic_snapshot(&fam);
+        fam.flush().unwrap();
+        hat.commit(&mut fam, None).unwrap();
+    }
+    hat.meta_commit().unwrap();
+
+    let families = |hat: &mut HatRc<MemoryBackend>| {
+        hat.list_snapshots().into_iter().map(|s| (s.family, s.snapshot_id)).collect::<Vec<_>>()
+    };
+    assert_eq!(families(&mut hat), vec![("beta/home".to_string(), 1)]);
+
+    hat.set_namespace(None).unwrap();
+    assert_eq!(
+        families(&mut hat),
+        vec![("alpha/home".to_string(), 1), ("beta/home".to_string(), 1)]
+    );
+}
+
+#[test]
+fn backup_metrics_report_last_success() {
+    use chrono;
+
+    let (_, mut hat, mut fam) = setup_family();
+    basic_snapshot(&fam);
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.meta_commit().unwrap();
+
+    let metrics = hat.backup_metrics();
+    assert_eq!(metrics.families.len(), 1);
+    assert!(metrics.families[0].last_run_ok);
+    let taken = metrics.families[0].last_success.unwrap();
+
+    let text = metrics.to_prometheus(taken + chrono::Duration::seconds(90));
+    assert!(text.contains("hat_last_success_age_seconds{family=\"familyname\"} 90\n"));
+    assert!(text.contains("hat_last_run_success{family=\"familyname\"} 1\n"));
+}
+
+#[test]
+fn verify_snapshot_recomputes_root() {
+    let (_, mut hat, mut fam) = setup_family();
+    basic_snapshot(&fam);
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.meta_commit().unwrap();
+
+    let listed = hat.list_snapshots();
+    assert_eq!(listed.len(), 1);
+    let root = listed[0].root.clone().unwrap();
+
+    let verified = hat.verify_snapshot("familyname", 1).unwrap();
+    assert_eq!(verified.root, root);
+    assert!(verified.chunks > 0);
+    assert!(verified.bytes > 0);
+
+    assert!(hat.verify_snapshot("familyname", 2).is_err());
+}
+
+#[test]
+fn fanout_can_change_between_snapshots() {
+    let mut hat = setup_hat(Arc::new(MemoryBackend::new()));
+    assert!(hat.set_fanout(1).is_err());
+
+    hat.set_fanout(3).unwrap();
+    let mut fam = hat.open_family("narrow".to_string()).unwrap();
+    basic_snapshot(&fam);
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+
+    hat.set_fanout(64).unwrap();
+    let mut fam = hat.open_family("wide".to_string()).unwrap();
+    basic_snapshot(&fam);
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.meta_commit().unwrap();
+
+    let narrow = hat.verify_snapshot("narrow", 1).unwrap();
+    let wide = hat.verify_snapshot("wide", 1).unwrap();
+    assert_eq!(narrow.bytes, wide.bytes);
+    assert!(narrow.chunks > wide.chunks);
+}
+
+#[test]
+fn retention_policy_keeps_newest_per_period() {
+    use chrono::{TimeZone, Utc};
+    use hat::RetentionPolicy;
+
+    let snapshots = vec![
+        (1, Utc.ymd(2016, 12, 31).and_hms(10, 0, 0)),
+        (2, Utc.ymd(2017, 1, 1).and_hms(10, 0, 0)),
+        (3, Utc.ymd(2017, 1, 1).and_hms(20, 0, 0)),
+        (4, Utc.ymd(2017, 2, 3).and_hms(10, 0, 0)),
+        (5, Utc.ymd(2017, 2, 4).and_hms(10, 0, 0)),
+    ];
+    let keep = |policy: RetentionPolicy| {
+        policy.keep(&snapshots[..]).into_iter().collect::<Vec<_>>()
+    };
+
+    assert_eq!(keep(RetentionPolicy { keep_last: 2, ..Default::default() }), vec![4, 5]);
+    assert_eq!(keep(RetentionPolicy { keep_daily: 3, ..Default::default() }), vec![3, 4, 5]);
+    assert_eq!(keep(RetentionPolicy { keep_monthly: 5, ..Default::default() }), vec![1, 3, 5]);
+    assert_eq!(
+        keep(RetentionPolicy { keep_last: 1, keep_yearly: 2, ..Default::default() }),
+        vec![1, 5]
+    );
+}
+
+#[test]
+fn forget_deregisters_unkept_snapshots() {
+    use hat::RetentionPolicy;
+
+    let (_, mut hat, mut fam) = setup_family();
+    for _ in 0..3 {
+        basic_snapshot(&fam);
+        fam.flush().unwrap();
+        hat.commit(&mut fam, None).unwrap();
+    }
+    hat.meta_commit().unwrap();
+
+    let policy = RetentionPolicy { keep_last: 1, ..Default::default() };
+    assert!(hat.forget("familyname", &RetentionPolicy::default(), None, true).is_err());
+    assert_eq!(hat.forget("familyname", &policy, None, true).unwrap(), vec![1, 2]);
+    assert_eq!(hat.list_snapshots().len(), 3);
+
+    assert_eq!(hat.forget("familyname", &policy, None, false).unwrap(), vec![1, 2]);
+    let left: Vec<u64> = hat.list_snapshots().into_iter().map(|s| s.snapshot_id).collect();
+    assert_eq!(left, vec![3]);
+}
+
+#[test]
+fn snapshots_being_restored_are_not_deleted() {
+    let (_, mut hat, mut fam) = setup_family();
+    basic_snapshot(&fam);
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.meta_commit().unwrap();
+
+    // As left behind by a restore in another process.
+    hat.set_restore_marker("familyname", 1, true);
+    assert!(hat.restore_in_progress("familyname", 1));
+    assert!(hat.deregister(&fam, 1).is_err());
+    assert_eq!(hat.list_snapshots().len(), 1);
+
+    // The marker follows the family when it is renamed.
+    hat.rename_family("familyname", "renamed").unwrap();
+    assert!(!hat.restore_in_progress("familyname", 1));
+    assert!(hat.restore_in_progress("renamed", 1));
+    assert!(hat.deregister_by_name("renamed".to_string(), 1).is_err());
+
+    hat.clear_restore_marker("renamed", 1);
+    hat.deregister_by_name("renamed".to_string(), 1).unwrap();
+    assert_eq!(hat.list_snapshots().len(), 0);
+}
+
+#[test]
+fn diff_snapshots_reports_changes() {
+    use hat::{ChangeKind, FileChange};
+
+    let (_, mut hat, mut fam) = setup_family();
+    let put = |fam: &Family<MemoryBackend>, name: &str, ts: u64, contents: &str| {
+        let mut e = entry(name.as_bytes().to_vec());
+        e.info.modified_ts_secs = Some(ts);
+        e.info.byte_length = Some(contents.len() as u64);
+        fam.snapshot_direct(e, false, Some(FileIterator::from_bytes(contents.into())))
+            .unwrap();
+    };
+
+    put(&fam, "same", 1, "same");
+    put(&fam, "changed", 1, "old");
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+
+    put(&fam, "same", 1, "same");
+    put(&fam, "changed", 2, "newer");
+    put(&fam, "added", 2, "a");
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+
+    let change = |path: &str, kind, old_size, new_size| {
+        FileChange {
+            path: PathBuf::from(path),
+            kind: kind,
+            old_size: old_size,
+            new_size: new_size,
+        }
+    };
+    assert_eq!(
+        hat.diff_snapshots("familyname", 1, 2).unwrap(),
+        vec![
+            change("added", ChangeKind::Added, None, Some(1)),
+            change("changed", ChangeKind::Modified, Some(3), Some(5)),
+        ]
+    );
+    assert_eq!(
+        hat.diff_snapshots("familyname", 2, 1).unwrap(),
+        vec![
+            change("added", ChangeKind::Removed, Some(1), None),
+            change("changed", ChangeKind::Modified, Some(5), Some(3)),
+        ]
+    );
+    assert!(hat.diff_snapshots("familyname", 1, 1).unwrap().is_empty());
+}
+
+#[test]
+fn json_output_keeps_field_names() {
+    use hat::{ChangeKind, EntryKind, FileChange, ListEntry};
+    use serde_json;
+
+    let change = FileChange {
+        path: PathBuf::from("a/b"),
+        kind: ChangeKind::Added,
+        old_size: None,
+        new_size: Some(3),
+    };
+    assert_eq!(
+        serde_json::to_string(&change).unwrap(),
+        r#"{"path":"a/b","change":"added","old_size":null,"new_size":3}"#
+    );
+
+    let link = ListEntry {
+        path: PathBuf::from("l"),
+        kind: EntryKind::Symlink(PathBuf::from("/t")),
+        size: None,
+        mode: Some(0o777),
+        modified: Some(10),
+    };
+    assert_eq!(
+        serde_json::to_string(&link).unwrap(),
+        r#"{"path":"l","kind":{"symlink":"/t"},"size":null,"mode":511,"modified":10}"#
+    );
+}
+
+#[test]
+fn snapshot_dir_checkpoints_progress() {
+    use std::env;
+    use std::fs;
+    use std::io::Write;
+    use std::process;
+    use time::Duration;
+
+    let dir = env::temp_dir().join(format!("hat-checkpoints-{}", process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    fs::File::create(dir.join("a")).unwrap().write_all(&[1; 10000]).unwrap();
+
+    // Without checkpoints, the data waits in memory for the blob to fill up.
+    let (backend, _hat, mut fam) = setup_family();
+    let stored = backend.list().unwrap().len();
+    fam.checkpoint_interval = None;
+    fam.snapshot_dir(dir.clone());
+    assert_eq!(backend.list().unwrap().len(), stored);
+    fam.flush().unwrap();
+
+    let (backend, _hat, mut fam) = setup_family();
+    let stored = backend.list().unwrap().len();
+    fam.checkpoint_interval = Some(Duration::zero());
+    fam.snapshot_dir(dir.clone());
+    assert!(backend.list().unwrap().len() > stored);
+    fam.flush().unwrap();
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn one_file_system_keeps_other_devices_empty() {
+    use hat::insert_path_handler::InsertPathHandler;
+    use std::env;
+    use std::fs;
+    use std::io::Write;
+    use std::path::Path;
+    use std::process;
+    use util::PathHandler;
+
+    let dir = env::temp_dir().join(format!("hat-one-file-system-{}", process::id()));
+    fs::create_dir_all(dir.join("sub")).unwrap();
+    fs::File::create(dir.join("top")).unwrap().write_all(b"top").unwrap();
+    fs::File::create(dir.join("sub/file")).unwrap().write_all(b"file").unwrap();
+    let dir = fs::canonicalize(&dir).unwrap();
+
+    // On the same device, everything is kept.
+    let (_, mut hat, mut fam) = setup_family();
+    fam.one_file_system = true;
+    fam.snapshot_dir(dir.clone());
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    let (_, dir_ref) = hat.complete_snapshot("familyname", None).unwrap();
+    assert!(hat.lookup_path(&fam, dir_ref, &dir.join("sub/file")).is_ok());
+
+    // No directory is on this device, so the directories are kept without their contents.
+    let mut other = hat.open_family("other".to_string()).unwrap();
+    InsertPathHandler::new(other.key_store_process.clone())
+        .with_one_file_system(u64::max_value())
+        .recurse(dir.clone(), None);
+    match other.key_store_process[0].send_reply(key::Msg::CommitReservedNodes(Some(None))) {
+        Ok(key::Reply::Ok) => (),
+        _ => panic!("Unexpected reply from key store"),
+    }
+    other.flush().unwrap();
+    hat.commit(&mut other, None).unwrap();
+    let mut paths: Vec<PathBuf> = hat.list_dir("other", None, Path::new("/"), true)
+        .unwrap()
+        .into_iter()
+        .map(|e| e.path)
+        .collect();
+    paths.sort();
+    assert_eq!(paths, vec![PathBuf::from("sub"), PathBuf::from("top")]);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn snapshot_dirs_of_several_families() {
+    use hat::snapshot_dirs;
+    use std::env;
+    use std::fs;
+    use std::io::Write;
+    use std::process;
+
+    let root = env::temp_dir().join(format!("hat-snapshot-dirs-{}", process::id()));
+    for name in &["a", "b"] {
+        fs::create_dir_all(root.join(name)).unwrap();
+        fs::File::create(root.join(name).join("file"))
+            .unwrap()
+            .write_all(name.repeat(10000).as_bytes())
+            .unwrap();
+    }
+    // Snapshots hold the canonical path of their directory.
+    let root = fs::canonicalize(&root).unwrap();
+
+    let (_, mut hat, fam_a) = setup_family();
+    let fam_b = hat.open_family("other".to_string()).unwrap();
+    snapshot_dirs(vec![
+        (fam_a.clone(), root.join("a")),
+        (fam_b.clone(), root.join("b")),
+    ]).unwrap();
+
+    for mut fam in vec![fam_a, fam_b] {
+        fam.flush().unwrap();
+        hat.commit(&mut fam, None).unwrap();
+    }
+    let mut families: Vec<String> = hat.snapshot_index
+        .list_all()
+        .into_iter()
+        .map(|s| s.family_name)
+        .collect();
+    families.sort();
+    assert_eq!(families, vec!["familyname".to_string(), "other".to_string()]);
+
+    // Each family holds only its own directory.
+    let (_, dir_ref) = hat.complete_snapshot("other", None).unwrap();
+    let fam_b = hat.open_family("other".to_string()).unwrap();
+    let (_, entry, _) = hat.lookup_path(&fam_b, dir_ref.clone(), &root.join("b/file")).unwrap();
+    assert_eq!(entry.info.byte_length, Some(10000));
+    assert!(hat.lookup_path(&fam_b, dir_ref, &root.join("a/file")).is_err());
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn status_compares_files_with_latest_snapshot() {
+    use filetime::{self, FileTime};
+    use hat::ChangeKind;
+    use std::env;
+    use std::fs;
+    use std::io::Write;
+    use std::process;
+
+    let (_, mut hat, mut fam) = setup_family();
+    let dir = env::temp_dir().join(format!("hat-status-{}", process::id()));
+    let write = |name: &str, contents: &[u8]| {
+        let path = dir.join(name);
+        fs::File::create(&path).unwrap().write_all(contents).unwrap();
+        filetime::set_file_times(&path, FileTime::zero(), FileTime::zero()).unwrap();
+    };
+    fs::create_dir_all(dir.join("sub")).unwrap();
+    write("a", b"first version");
+    write("sub/b", b"unchanged");
+
+    fam.snapshot_dir(dir.clone());
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    assert!(hat.status("familyname", &dir).unwrap().changes.is_empty());
+
+    write("a", b"second version!");
+    write("sub/c", b"new");
+    let status = hat.status("familyname", &dir).unwrap();
+    let changes: Vec<_> = status
+        .changes
+        .iter()
+        .map(|c| (c.path.to_str().unwrap().to_owned(), c.kind))
+        .collect();
+    assert_eq!(
+        changes,
+        vec![
+            ("a".to_owned(), ChangeKind::Modified),
+            ("sub/c".to_owned(), ChangeKind::Added),
+        ]
+    );
+    assert_eq!(status.upload_bytes, 15 + 3);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn commit_dry_run_stores_nothing() {
+    use hat::{Limits, Pattern, ScanOptions};
+    use std::env;
+    use std::fs;
+    use std::io::Write;
+    use std::os::unix::fs::symlink;
+    use std::process;
+
+    let (_, mut hat, _) = setup_family();
+    let dir = env::temp_dir().join(format!("hat-dry-run-{}", process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    fs::File::create(dir.join("a")).unwrap().write_all(b"small file").unwrap();
+    fs::File::create(dir.join("big")).unwrap().write_all(&[7; 100]).unwrap();
+    hat.set_limits(Limits {
+        max_file_size: Some(50),
+        max_commit_bytes: None,
+        abort: false,
+    });
+
+    let hashes = hat.hash_index.list().len();
+    let status = hat.commit_dry_run("familyname", &dir, &ScanOptions::default()).unwrap();
+    assert_eq!(status.changes.len(), 2);
+    assert_eq!(status.skipped, vec![PathBuf::from("big")]);
+    assert_eq!(status.read_bytes, 10);
+    assert_eq!((status.chunks, status.upload_chunks, status.upload_bytes), (1, 1, 10));
+    assert_eq!(hat.hash_index.list().len(), hashes);
+    assert!(hat.list_snapshots().is_empty());
+
+    // Excluded files are left out, and links followed, as by the commit.
+    fs::File::create(dir.join("skip.tmp")).unwrap().write_all(b"left out").unwrap();
+    symlink(dir.join("a"), dir.join("link")).unwrap();
+    let options = ScanOptions {
+        one_file_system: false,
+        follow_symlinks: true,
+        excludes: vec![Pattern::new("*.tmp")],
+    };
+    let status = hat.commit_dry_run("familyname", &dir, &options).unwrap();
+    let mut paths: Vec<_> = status.changes.iter().map(|c| c.path.clone()).collect();
+    paths.sort();
+    assert_eq!(paths, vec![PathBuf::from("a"), PathBuf::from("big"), PathBuf::from("link")]);
+    // The link is read as the file it points to.
+    assert_eq!(status.read_bytes, 20);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn progress_sees_files_and_bytes() {
+    use std::env;
+    use std::fs;
+    use std::io::Write;
+    use std::path::Path;
+    use std::process;
+    use std::sync::Mutex;
+    use util::Progress;
+
+    #[derive(Default)]
+    struct Counts(Mutex<(Vec<String>, u64, u64, u64)>);
+    impl Progress for Counts {
+        fn start(&self, operation: &str, _total_bytes: Option<u64>) {
+            self.0.lock().unwrap().0.push(operation.to_string());
+        }
+        fn file(&self, _path: &Path) {
+            self.0.lock().unwrap().1 += 1;
+        }
+        fn read(&self, bytes: u64) {
+            self.0.lock().unwrap().2 += bytes;
+        }
+        fn uploaded(&self, bytes: u64) {
+            self.0.lock().unwrap().3 += bytes;
+        }
+    }
+
+    let backend = Arc::new(MemoryBackend::new());
+    let mut hat = setup_hat(backend);
+    let progress = Arc::new(Counts::default());
+    hat.set_progress(progress.clone());
+
+    let dir = env::temp_dir().join(format!("hat-progress-{}", process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    fs::File::create(dir.join("a")).unwrap().write_all(&[1; 3000]).unwrap();
+    fs::File::create(dir.join("b")).unwrap().write_all(&[2; 5000]).unwrap();
+
+    let mut fam = hat.open_family("familyname".to_string()).unwrap();
+    fam.snapshot_dir(dir.clone());
+    {
+        let counts = progress.0.lock().unwrap();
+        // The directories above `dir` are handled as well.
+        assert!(counts.1 >= 3);
+        assert_eq!(counts.2, 8000);
+        // Repeated chunks are only uploaded once.
+        assert!(counts.3 > 0 && counts.3 <= 8000);
+    }
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.meta_commit().unwrap();
+    hat.data_flush().unwrap();
+
+    hat.gc().unwrap();
+    assert_eq!(progress.0.lock().unwrap().0, vec!["Collecting garbage".to_string()]);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn manifest_guards_the_repository() {
+    use blob::{BlobId, MANIFEST_NAME};
+    use crypto::keys::HashAlgorithm;
+    use hat::Manifest;
+
+    let backend = MemoryBackend::new();
+    assert_eq!(Manifest::load(&backend).unwrap(), None);
+
+    let manifest = Manifest::current(HashAlgorithm::Blake3);
+    manifest.store(&backend).unwrap();
+    assert_eq!(Manifest::load(&backend).unwrap(), Some(manifest.clone()));
+    // The manifest is never mistaken for a data blob.
+    assert!(BlobId::new(MANIFEST_NAME.to_vec()).is_err());
+
+    manifest.check(&Manifest::current(HashAlgorithm::Blake3)).unwrap();
+    assert!(manifest.check(&Manifest::current(HashAlgorithm::Blake2b)).is_err());
+    let mut newer = manifest.clone();
+    newer.format_version += 1;
+    assert!(newer.check(&manifest).is_err());
+}
+
+#[test]
+fn low_impact_commit_uses_one_hashing_thread() {
+    use std::env;
+    use std::fs;
+    use std::io::{Read, Write};
+    use std::process;
+
+    let backend = Arc::new(MemoryBackend::new());
+    let mut hat = setup_hat(backend);
+    hat.set_hashing_threads(1);
+
+    let dir = env::temp_dir().join(format!("hat-low-impact-{}", process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    fs::File::create(dir.join("a")).unwrap().write_all(&[4; 70000]).unwrap();
+
+    let mut fam = hat.open_family("familyname".to_string()).unwrap();
+    assert_eq!(fam.key_store_process.len(), 1);
+    fam.low_impact = true;
+    fam.snapshot_dir(dir.clone());
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.meta_commit().unwrap();
+    hat.data_flush().unwrap();
+
+    let out = env::temp_dir().join(format!("hat-low-impact-out-{}", process::id()));
+    hat.checkout_in_dir("familyname".to_string(), out.clone()).unwrap();
+    let restored = out.join(dir.strip_prefix("/").unwrap()).join("a");
+    let mut contents = vec![];
+    fs::File::open(restored).unwrap().read_to_end(&mut contents).unwrap();
+    assert_eq!(contents, vec![4; 70000]);
+
+    fs::remove_dir_all(&dir).unwrap();
+    fs::remove_dir_all(&out).unwrap();
+}
+
+#[test]
+fn event_log_writes_json_lines() {
+    use hat::{Event, EventLog};
+    use serde_json;
+    use std::io::{self, Write};
+    use std::path::Path;
+    use std::sync::Mutex;
+    use util::{Progress, SilentProgress};
+
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let buf = Arc::new(Mutex::new(Vec::new()));
+    let events = EventLog::new(Box::new(Shared(buf.clone())), Arc::new(SilentProgress));
+    events.start("Committing", None);
+    events.file(Path::new("home/a"));
+    events.uploaded(100);
+    events.emit(&Event::SnapshotCommitted {
+        family: "familyname".to_string(),
+        snapshot_id: 1,
+    });
+
+    let text = String::from_utf8(buf.lock().unwrap().clone()).unwrap();
+    let lines: Vec<serde_json::Value> =
+        text.lines().map(|l| serde_json::from_str(l).unwrap()).collect();
+    assert_eq!(lines.len(), 4);
+    assert_eq!(lines[0]["event"], "started");
+    assert_eq!(lines[1]["path"], "home/a");
+    assert_eq!(lines[2]["event"], "chunk_uploaded");
+    assert_eq!(lines[2]["bytes"], 100);
+    assert_eq!(lines[3]["snapshot_id"], 1);
+    assert!(lines[3]["time"].is_string());
+}
+
+#[test]
+fn run_metrics_count_deduped_chunks() {
+    use backend::CountingBackend;
+    use hat::RunMetrics;
+    use std::env;
+    use std::fs;
+    use std::io::Write;
+    use std::process;
+    use util::SilentProgress;
+
+    let backend = Arc::new(CountingBackend::new(MemoryBackend::new()));
+    let mut hat = setup_hat(backend.clone());
+    let metrics = Arc::new(
+        RunMetrics::new(Arc::new(SilentProgress)).with_backend_errors(backend.errors()),
+    );
+    hat.set_progress(metrics.clone());
+
+    let dir = env::temp_dir().join(format!("hat-run-metrics-{}", process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    fs::File::create(dir.join("a")).unwrap().write_all(&[3; 5000]).unwrap();
+    fs::File::create(dir.join("b")).unwrap().write_all(&[3; 5000]).unwrap();
+
+    let mut fam = hat.open_family("familyname".to_string()).unwrap();
+    fam.snapshot_dir(dir.clone());
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.meta_commit().unwrap();
+    hat.data_flush().unwrap();
+
+    let text = metrics.to_prometheus();
+    assert!(text.contains("hat_read_bytes_total 10000\n"));
+    assert!(text.contains("hat_deduped_bytes_total 5000\n"));
+    assert!(text.contains("hat_upload_queue_bytes 0\n"));
+    assert!(text.contains("hat_backend_errors_total 0\n"));
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn provenance_survives_recovery() {
+    use hat::Provenance;
+
+    let (backend, mut hat, mut fam) = setup_family();
+    let provenance = Provenance {
+        hostname: "host".to_string(),
+        username: "user".to_string(),
+        version: "1.0".to_string(),
+        command_line: vec!["hat".to_string(), "commit".to_string(), "".to_string()],
+        source_path: "/home/user".to_string(),
+    };
+    basic_snapshot(&fam);
+    fam.flush().unwrap();
+    fam.provenance = Some(provenance.clone());
+    hat.commit(&mut fam, None).unwrap();
+    hat.meta_commit().unwrap();
+    hat.data_flush().unwrap();
+    assert_eq!(hat.list_snapshots()[0].provenance, Some(provenance.clone()));
+
+    let mut hat2 = setup_hat(backend);
+    hat2.recover().unwrap();
+    assert_eq!(hat2.list_snapshots()[0].provenance, Some(provenance));
+}
+
+#[test]
+fn labels_select_snapshots() {
+    use hat::RetentionPolicy;
+
+    let (_, mut hat, mut fam) = setup_family();
+    fam.labels = vec!["nightly".to_string()];
+    for _ in 0..2 {
+        basic_snapshot(&fam);
+        fam.flush().unwrap();
+        hat.commit(&mut fam, None).unwrap();
+    }
+    fam.labels = vec![];
+    basic_snapshot(&fam);
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+
+    assert!(hat.add_label("familyname", 3, "42").is_err());
+    hat.add_label("familyname", 1, "pre-upgrade").unwrap();
+    assert_eq!(hat.resolve_snapshot("familyname", "pre-upgrade").unwrap(), 1);
+    assert_eq!(hat.resolve_snapshot("familyname", "nightly").unwrap(), 2);
+    assert_eq!(hat.resolve_snapshot("familyname", "3").unwrap(), 3);
+    assert!(hat.resolve_snapshot("familyname", "weekly").is_err());
+
+    // Only the labelled snapshots are subject to the policy.
+    let policy = RetentionPolicy { keep_last: 1, ..Default::default() };
+    assert_eq!(hat.forget("familyname", &policy, Some("nightly"), false).unwrap(), vec![1]);
+    assert!(hat.resolve_snapshot("familyname", "pre-upgrade").is_err());
+
+    hat.remove_label("familyname", 2, "nightly").unwrap();
+    assert!(hat.remove_label("familyname", 2, "nightly").is_err());
+    let labels: Vec<Vec<String>> = hat.list_snapshots().into_iter().map(|s| s.labels).collect();
+    assert_eq!(labels, vec![Vec::<String>::new(), vec![]]);
+}
+
+#[test]
+fn checkout_restores_only_subpath() {
+    use hat::RestoreOptions;
+    use std::env;
+    use std::fs;
+    use std::io::Read;
+    use std::process;
+
+    let (_, mut hat, mut fam) = setup_family();
+    snapshot_files(
+        &fam,
+        vec![
+            ("photos/2019/a.jpg", "a".into()),
+            ("photos/2019/b.jpg", "b".into()),
+            ("photos/2020/c.jpg", "c".into()),
+            ("docs/notes", "notes".into()),
+        ],
+    ).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+
+    let out = env::temp_dir().join(format!("hat-subpath-{}", process::id()));
+    let checkout = |hat: &mut HatRc<MemoryBackend>, subpath: &str| {
+        let options = RestoreOptions {
+            subpath: Some(PathBuf::from(subpath)),
+            ..Default::default()
+        };
+        hat.checkout("familyname".to_string(), None, out.clone(), &options)
+    };
+
+    checkout(&mut hat, "/photos/2019").unwrap();
+    assert!(out.join("photos/2019/a.jpg").is_file());
+    assert!(out.join("photos/2019/b.jpg").is_file());
+    assert!(!out.join("photos/2020").exists());
+    assert!(!out.join("docs").exists());
+
+    checkout(&mut hat, "docs/notes").unwrap();
+    let mut notes = String::new();
+    fs::File::open(out.join("docs/notes")).unwrap().read_to_string(&mut notes).unwrap();
+    assert_eq!(notes, "notes");
+
+    assert!(checkout(&mut hat, "/photos/2021").is_err());
+    assert!(checkout(&mut hat, "/photos/2019/a.jpg/x").is_err());
+
+    fs::remove_dir_all(&out).unwrap();
+}
+
+#[test]
+fn checkout_filters_prune_the_tree() {
+    use hat::RestoreOptions;
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    let (_, mut hat, mut fam) = setup_family();
+    snapshot_files(
+        &fam,
+        vec![
+            ("docs/report.docx", "a".into()),
+            ("docs/notes.txt", "b".into()),
+            ("docs/tmp/draft.docx", "c".into()),
+            ("music/song.mp3", "d".into()),
+            ("work/old/plan.docx", "e".into()),
+        ],
+    ).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+
+    let out = env::temp_dir().join(format!("hat-filters-{}", process::id()));
+    for order in vec![RestoreOrder::Listing, RestoreOrder::SmallestFirst] {
+        let options = RestoreOptions {
+            order: order,
+            include: vec![Pattern::new("*.docx")],
+            exclude: vec![Pattern::new("tmp"), Pattern::new("work/*")],
+            ..Default::default()
+        };
+        hat.checkout("familyname".to_string(), None, out.clone(), &options).unwrap();
+
+        assert!(out.join("docs/report.docx").is_file());
+        assert!(!out.join("docs/notes.txt").exists());
+        assert!(!out.join("docs/tmp").exists());
+        assert!(!out.join("music").exists());
+        assert!(!out.join("work").exists());
+        fs::remove_dir_all(&out).unwrap();
+    }
+}
+
+#[test]
+fn checkout_conflict_policies() {
+    use hat::{ConflictPolicy, RestoreOptions};
+    use std::env;
+    use std::fs;
+    use std::io::{Read, Write};
+    use std::process;
+
+    let (_, mut hat, mut fam) = setup_family();
+    snapshot_files(&fam, vec![("a", "new".into())]).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+
+    let out = env::temp_dir().join(format!("hat-conflicts-{}", process::id()));
+    let read = |name: &str| {
+        let mut s = String::new();
+        fs::File::open(out.join(name)).unwrap().read_to_string(&mut s).unwrap();
+        s
+    };
+    let checkout = |hat: &mut HatRc<MemoryBackend>, policy| {
+        fs::create_dir_all(&out).unwrap();
+        fs::File::create(out.join("a")).unwrap().write_all(b"old").unwrap();
+        let options = RestoreOptions {
+            conflict: policy,
+            ..Default::default()
+        };
+        hat.checkout("familyname".to_string(), None, out.clone(), &options).unwrap();
+    };
+
+    checkout(&mut hat, ConflictPolicy::SkipExisting);
+    assert_eq!(read("a"), "old");
+
+    checkout(&mut hat, ConflictPolicy::BackupExisting);
+    checkout(&mut hat, ConflictPolicy::BackupExisting);
+    assert_eq!(read("a"), "new");
+    assert_eq!(read("a.~1~"), "old");
+    assert_eq!(read("a.~2~"), "old");
+
+    // The snapshot has no modification time for the file, so it is not known to be newer.
+    checkout(&mut hat, ConflictPolicy::OnlyNewer);
+    assert_eq!(read("a"), "old");
+
+    checkout(&mut hat, ConflictPolicy::Overwrite);
+    assert_eq!(read("a"), "new");
+    fs::remove_dir_all(&out).unwrap();
+}
+
+#[test]
+fn checkout_in_parallel() {
+    use hat::RestoreOptions;
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    let (_, mut hat, mut fam) = setup_family();
+    let files: Vec<(String, Vec<u8>)> = (0..20)
+        .map(|i| {
+            let contents = format!("{}", i).repeat((i + 1) * 1000);
+            (format!("dir{}/file{}", i % 3, i), contents.into())
+        })
+        .collect();
+    snapshot_files(&fam, files.iter().map(|&(ref n, ref c)| (&n[..], c.clone())).collect())
+        .unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+
+    let out = env::temp_dir().join(format!("hat-parallel-{}", process::id()));
+    let options = RestoreOptions {
+        jobs: 4,
+        ..Default::default()
+    };
+    hat.checkout("familyname".to_string(), None, out.clone(), &options).unwrap();
+
+    let check = hat.verify_restore("familyname", None, &out, &options).unwrap();
+    assert_eq!(check.mismatches, vec![]);
+    assert_eq!(check.files, 20);
+    fs::remove_dir_all(&out).unwrap();
+}
+
+#[test]
+fn cat_writes_one_file() {
+    use std::io;
+    use std::path::Path;
+
+    let (_, mut hat, mut fam) = setup_family();
+    let data: Vec<u8> = (0..300000).map(|i| (i % 13) as u8).collect();
+    snapshot_files(
+        &fam,
+        vec![("docs/a", data.clone()), ("docs/b", "b".into())],
+    ).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+
+    let mut out = vec![];
+    assert_eq!(
+        hat.cat("familyname", None, Path::new("docs/a"), &mut out).unwrap(),
+        300000
+    );
+    assert_eq!(out, data);
+
+    let mut out = vec![];
+    hat.cat("familyname", Some(1), Path::new("/docs/b"), &mut out).unwrap();
+    assert_eq!(out, b"b");
+
+    assert!(hat.cat("familyname", None, Path::new("docs"), io::sink()).is_err());
+    assert!(hat.cat("familyname", None, Path::new("docs/c"), io::sink()).is_err());
+}
+
+#[test]
+fn export_tar_streams_the_snapshot() {
+    use std::io::Read;
+    use tar;
+
+    let (_, mut hat, mut fam) = setup_family();
+    snapshot_files(
+        &fam,
+        vec![("docs/a", "x".repeat(300000).into()), ("b", "b".into())],
+    ).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+
+    let mut out = vec![];
+    hat.export_tar("familyname", None, &mut out).unwrap();
+
+    let mut archive = tar::Archive::new(&out[..]);
+    let mut found = vec![];
+    for entry in archive.entries().unwrap() {
+        let mut entry = entry.unwrap();
+        let path = entry.path().unwrap().into_owned();
+        let mut data = vec![];
+        entry.read_to_end(&mut data).unwrap();
+        found.push((path, entry.header().entry_type().is_dir(), data.len()));
+    }
+    found.sort();
+    assert_eq!(
+        found,
+        vec![
+            (PathBuf::from("b"), false, 1),
+            (PathBuf::from("docs"), true, 0),
+            (PathBuf::from("docs/a"), false, 300000),
+        ]
+    );
+}
+
+#[test]
+fn import_tar_commits_the_archive() {
+    use std::io::Read;
+    use tar;
+
+    let (_, mut hat, mut fam) = setup_family();
+    // A file from an earlier snapshot that the archive does not have.
+    snapshot_files(&fam, vec![("old", "old".into())]).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+
+    let mut builder = tar::Builder::new(vec![]);
+    for &(path, ref data) in &[("d/f", "x".repeat(300000)), ("./top", "top".to_string())] {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(data.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder.append_data(&mut header, path, data.as_bytes()).unwrap();
+    }
+    let archive = builder.into_inner().unwrap();
+
+    fam.snapshot_tar(&archive[..]).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+
+    let mut out = vec![];
+    hat.export_tar("familyname", None, &mut out).unwrap();
+
+    let mut archive = tar::Archive::new(&out[..]);
+    let mut found = vec![];
+    for entry in archive.entries().unwrap() {
+        let mut entry = entry.unwrap();
+        let path = entry.path().unwrap().into_owned();
+        let mut data = vec![];
+        entry.read_to_end(&mut data).unwrap();
+        found.push((path, entry.header().entry_type().is_dir(), data));
+    }
+    found.sort();
+    assert_eq!(
+        found,
+        vec![
+            (PathBuf::from("d"), true, vec![]),
+            (PathBuf::from("d/f"), false, "x".repeat(300000).into_bytes()),
+            (PathBuf::from("top"), false, b"top".to_vec()),
+        ]
+    );
+}
+
+#[test]
+fn snapshot_stream_commits_a_single_file() {
+    use std::io::{Cursor, Read};
+    use tar;
+
+    let (_, mut hat, mut fam) = setup_family();
+    snapshot_files(&fam, vec![("old", "old".into())]).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+
+    let dump: Vec<u8> = (0..200000).map(|i| (i % 251) as u8).collect();
+    fam.snapshot_stream(b"dump.sql".to_vec(), Cursor::new(dump.clone())).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+
+    let mut out = vec![];
+    hat.export_tar("familyname", None, &mut out).unwrap();
+    let mut archive = tar::Archive::new(&out[..]);
+    let mut found = vec![];
+    for entry in archive.entries().unwrap() {
+        let mut entry = entry.unwrap();
+        // The size of the stream is recorded once it has been read.
+        assert_eq!(entry.header().size().unwrap(), dump.len() as u64);
+        let mut data = vec![];
+        entry.read_to_end(&mut data).unwrap();
+        found.push((entry.path().unwrap().into_owned(), data));
+    }
+    assert_eq!(found, vec![(PathBuf::from("dump.sql"), dump)]);
+}
+
+#[test]
+fn verify_restore_reports_mismatches() {
+    use hat::{MismatchKind, RestoreOptions};
+    use std::env;
+    use std::fs;
+    use std::io::Write;
+    use std::process;
+
+    let (_, mut hat, mut fam) = setup_family();
+    snapshot_files(
+        &fam,
+        vec![
+            ("docs/a", "x".repeat(10000).into()),
+            ("docs/b", "b".into()),
+            ("c", "c".into()),
+        ],
+    ).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+
+    let out = env::temp_dir().join(format!("hat-verify-restore-{}", process::id()));
+    let options = RestoreOptions::default();
+    hat.checkout("familyname".to_string(), None, out.clone(), &options).unwrap();
+
+    let check = hat.verify_restore("familyname", None, &out, &options).unwrap();
+    assert_eq!(check.mismatches, vec![]);
+    assert_eq!(check.files, 3);
+    assert_eq!(check.bytes, 10002);
+
+    fs::OpenOptions::new().append(true).open(out.join("docs/a")).unwrap().write_all(b"y").unwrap();
+    fs::remove_file(out.join("c")).unwrap();
+    let check = hat.verify_restore("familyname", None, &out, &options).unwrap();
+    let mut found: Vec<_> = check.mismatches.into_iter().map(|m| (m.path, m.kind)).collect();
+    found.sort_by(|a, b| a.0.cmp(&b.0));
+    assert_eq!(
+        found,
+        vec![
+            (PathBuf::from("c"), MismatchKind::Missing),
+            (PathBuf::from("docs/a"), MismatchKind::Content),
+        ]
+    );
+    fs::remove_dir_all(&out).unwrap();
+}
+
+#[test]
+fn append_only_gc_deletes_nothing() {
+    let backend = Arc::new(AppendOnlyBackend::new(MemoryBackend::new()));
+    let mut hat = setup_hat(backend.clone());
+    let mut fam = hat.open_family("familyname".to_string()).unwrap();
+
+    snapshot_files(&fam, vec![("a", vec![1; 10000]), ("b", vec![2; 10000])]).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.meta_commit().unwrap();
+
+    hat.deregister(&fam, 1).unwrap();
+    hat.meta_commit().unwrap();
+    let stored = backend.list().unwrap().len();
+
+    let GcReport { hashes_deleted: deleted, .. } = hat.gc().unwrap();
+    assert_eq!(deleted, 0);
+    assert_eq!(backend.list().unwrap().len(), stored);
+
+    let name = backend.list().unwrap()[0].clone();
+    assert!(backend.delete(&name).is_err());
+    assert!(backend.inner().retrieve(&name).unwrap().is_some());
+}
+
+#[test]
+fn repositories_created_append_only_stay_so() {
+    use crypto::CipherText;
+    use std::env;
+    use std::fs;
+    use std::path::Path;
+    use std::process;
+
+    let root = env::temp_dir().join(format!("hat-append-only-{}", process::id()));
+    let migrations = Path::new("migrations");
+    let backend = Arc::new(AppendOnlyBackend::new(MemoryBackend::new()));
+    let (_, manifest) =
+        HatRc::init_repository(migrations, root.clone(), backend.clone(), 1024, None, None)
+            .unwrap();
+    assert!(manifest.append_only);
+
+    // The same blobs, without the wrapper.
+    let copy = || {
+        let plain = MemoryBackend::new();
+        for name in backend.list().unwrap() {
+            let data = backend.retrieve(&name).unwrap().unwrap();
+            plain.store(&name, &CipherText::new(data)).unwrap();
+        }
+        plain
+    };
+    let open =
+        |backend| HatRc::open_repository(migrations, root.clone(), backend, 1024, None, None);
+    assert!(open(Arc::new(AppendOnlyBackend::new(copy()).with_enforcing(false))).is_err());
+    assert!(open(Arc::new(AppendOnlyBackend::new(copy()))).is_ok());
+    let privileged = AppendOnlyBackend::new(copy()).with_privileged(true);
+    assert!(!privileged.append_only());
+    assert!(open(Arc::new(privileged)).is_ok());
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn repository_locks_conflict() {
+    use chrono;
+
+    let (backend, hat, _) = setup_family();
+
+    let first = hat.lock(LockKind::Shared).unwrap();
+    let second = hat.lock(LockKind::Shared).unwrap();
+    assert!(hat.lock(LockKind::Exclusive).is_err());
+    assert_eq!(hat.list_locks().unwrap().len(), 2);
+
+    drop(first);
+    drop(second);
+    assert_eq!(hat.list_locks().unwrap().len(), 0);
+
+    let exclusive = hat.lock(LockKind::Exclusive).unwrap();
+    assert!(hat.lock(LockKind::Shared).is_err());
+    drop(exclusive);
+
+    // A lock that has not been refreshed in a long time belongs to a client that died.
+    let stale = LockInfo {
+        kind: LockKind::Exclusive,
+        owner: "elsewhere".to_string(),
+        refreshed_utc: chrono::Utc::now().timestamp() - lock::STALE_LOCK_SECS - 1,
+        operation: "hat gc".to_string(),
+        taken_utc: None,
+    };
+    lock::write(&*backend, b"lock:stale", &stale).unwrap();
+    hat.lock(LockKind::Shared).unwrap();
+}
+
+#[test]
+fn lost_lock_stops_commits_and_gc() {
+    let (_, mut hat, mut fam) = setup_family();
+    let held = hat.lock(LockKind::Shared).unwrap();
+    hat.watch_lock(&held);
+    snapshot_files(&fam, vec![("name1", vec![1, 2, 3])]).unwrap();
+    hat.commit(&mut fam, None).unwrap();
+
+    held.watch().lose();
+    assert!(held.is_lost());
+    snapshot_files(&fam, vec![("name2", vec![4, 5, 6])]).unwrap();
+    assert!(hat.commit(&mut fam, None).is_err());
+    assert!(hat.meta_commit().is_err());
+    assert!(hat.gc().is_err());
+}
+
+#[test]
+fn locks_name_their_host_by_name_and_id() {
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    let dir = env::temp_dir().join(format!("hat-host-{}", process::id()));
+    let host = lock::this_host(&dir).unwrap();
+    assert!(host.starts_with(&lock::hostname().unwrap()));
+    assert_eq!(lock::this_host(&dir).unwrap(), host);
+    fs::remove_dir_all(&dir).unwrap();
+
+    let (_, hat, _) = setup_family();
+    let held = hat.lock(LockKind::Shared).unwrap();
+    assert_eq!(held.info().local_pid(hat.host()), Some(process::id()));
+
+    // Another host of the same name has another id, so its pids mean nothing here.
+    let name = hat.host().split(" [").next().unwrap();
+    let namesake = LockInfo {
+        owner: format!("{} [elsewhere] (pid {})", name, process::id()),
+        ..held.info().clone()
+    };
+    assert_eq!(namesake.local_pid(hat.host()), None);
+}
+
+#[test]
+fn break_locks_keeps_live_holders() {
+    use chrono;
+
+    let (backend, hat, _) = setup_family();
+    let now = chrono::Utc::now().timestamp();
+    let held = hat.lock(LockKind::Shared).unwrap();
+    assert!(held.info().taken_utc.is_some());
+    assert!(!held.info().operation.is_empty());
+
+    let other_host = LockInfo {
+        kind: LockKind::Shared,
+        owner: "elsewhere (pid 1)".to_string(),
+        refreshed_utc: now,
+        operation: "hat commit".to_string(),
+        taken_utc: Some(now),
+    };
+    let stale = LockInfo {
+        refreshed_utc: now - lock::STALE_LOCK_SECS - 1,
+        ..other_host.clone()
+    };
+    lock::write(&*backend, b"lock:live", &other_host).unwrap();
+    lock::write(&*backend, b"lock:stale", &stale).unwrap();
+    assert_eq!(hat.list_locks().unwrap().len(), 3);
+
+    // Only the stale lock is known to be abandoned; our own lock is never broken.
+    assert_eq!(hat.break_locks(false).unwrap(), vec![stale]);
+    assert_eq!(hat.break_locks(true).unwrap(), vec![other_host.clone()]);
+
+    // A process of this host that no longer exists is gone, but the same pid on a host of the
+    // same name tells nothing.
+    let dead_pid = i32::max_value();
+    let dead = LockInfo {
+        owner: format!("{} (pid {})", hat.host(), dead_pid),
+        ..other_host.clone()
+    };
+    let name = hat.host().split(" [").next().unwrap();
+    let namesake = LockInfo {
+        owner: format!("{} [elsewhere] (pid {})", name, dead_pid),
+        ..other_host
+    };
+    lock::write(&*backend, b"lock:dead", &dead).unwrap();
+    lock::write(&*backend, b"lock:namesake", &namesake).unwrap();
+    assert_eq!(hat.break_locks(false).unwrap(), vec![dead]);
+    assert_eq!(hat.break_locks(true).unwrap(), vec![namesake]);
+    assert_eq!(hat.list_locks().unwrap(), vec![held.info().clone()]);
+}
+
+#[test]
+fn symlinks_are_restored_as_links() {
+    use filetime;
+    use hat::RestoreOptions;
+    use std::env;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+    use std::process;
+
+    let (_, mut hat, mut fam) = setup_family();
+    let mut file = entry(b"target".to_vec());
+    file.info.permissions = Some(fs::Permissions::from_mode(0o600));
+    file.info.modified_ts_secs = Some(1000000000);
+    fam.snapshot_direct(file, false, Some(FileIterator::from_bytes(b"data".to_vec())))
+        .unwrap();
+    let mut link = key::Entry::new(
+        None,
+        b"link".to_vec(),
+        key::Data::Symlink(PathBuf::from("target")),
+        None,
+    );
+    link.info.permissions = Some(fs::Permissions::from_mode(0o777));
+    link.info.modified_ts_secs = Some(1200000000);
+    fam.snapshot_direct(link, false, None).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+
+    let out = env::temp_dir().join(format!("hat-symlinks-{}", process::id()));
+    let options = RestoreOptions::default();
+    hat.checkout("familyname".to_string(), None, out.clone(), &options).unwrap();
+
+    assert_eq!(fs::read_link(out.join("link")).unwrap(), PathBuf::from("target"));
+    // The link's own metadata must not have been applied to its target.
+    let target = fs::metadata(out.join("target")).unwrap();
+    assert_eq!(target.permissions().mode() & 0o7777, 0o600);
+    let link = fs::symlink_metadata(out.join("link")).unwrap();
+    assert_eq!(
+        filetime::FileTime::from_last_modification_time(&link).seconds_relative_to_1970(),
+        1200000000
+    );
+
+    let check = hat.verify_restore("familyname", None, &out, &options).unwrap();
+    assert_eq!(check.mismatches, vec![]);
+    fs::remove_dir_all(&out).unwrap();
+}
+
+#[test]
+fn hardlinks_are_restored_as_links() {
+    use hat::RestoreOptions;
+    use std::env;
+    use std::fs;
+    use std::io::Read;
+    use std::os::unix::fs::MetadataExt;
+    use std::process;
+
+    let (_, mut hat, mut fam) = setup_family();
+    let dir = fam.snapshot_direct(entry(b"dir".to_vec()), true, None).unwrap();
+    for &(parent, name) in &[(None, "a"), (Some(dir), "b"), (None, "c")] {
+        let mut e = entry(name.as_bytes().to_vec());
+        e.parent_id = parent;
+        if name != "c" {
+            e.info.hardlink_id = Some(42);
+        }
+        fam.snapshot_direct(e, false, Some(FileIterator::from_bytes(vec![7; 10000])))
+            .unwrap();
+    }
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+
+    let mut scheduled = RestoreOptions::default();
+    scheduled.order = RestoreOrder::SmallestFirst;
+    for (i, options) in vec![RestoreOptions::default(), scheduled].into_iter().enumerate() {
+        let out = env::temp_dir().join(format!("hat-hardlinks-{}-{}", process::id(), i));
+        hat.checkout("familyname".to_string(), None, out.clone(), &options).unwrap();
+
+        let a = fs::metadata(out.join("a")).unwrap();
+        let b = fs::metadata(out.join("dir/b")).unwrap();
+        let c = fs::metadata(out.join("c")).unwrap();
+        assert_eq!(a.ino(), b.ino());
+        assert_eq!(a.nlink(), 2);
+        assert!(a.ino() != c.ino());
+        let mut data = vec![];
+        fs::File::open(out.join("dir/b")).unwrap().read_to_end(&mut data).unwrap();
+        assert_eq!(data, vec![7; 10000]);
+        fs::remove_dir_all(&out).unwrap();
+    }
+}
+
+#[test]
+fn xattrs_are_restored() {
+    use hat::RestoreOptions;
+    use std::env;
+    use std::fs;
+    use std::process;
+    use xattr;
+
+    let (_, mut hat, mut fam) = setup_family();
+    let mut e = entry(b"tagged".to_vec());
+    e.info.xattrs = vec![(b"user.hat.test".to_vec(), b"value".to_vec())];
+    fam.snapshot_direct(e, false, Some(FileIterator::from_bytes(vec![1; 10])))
+        .unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+
+    let out = env::temp_dir().join(format!("hat-xattrs-{}", process::id()));
+    fs::create_dir_all(&out).unwrap();
+    if xattr::set(&out, "user.hat.probe", b"").is_err() {
+        // The file system holding the temporary directory lacks user attributes.
+        fs::remove_dir_all(&out).unwrap();
+        return;
+    }
+
+    let options = RestoreOptions::default();
+    hat.checkout("familyname".to_string(), None, out.clone(), &options).unwrap();
+    assert_eq!(
+        xattr::get(out.join("tagged"), "user.hat.test").unwrap(),
+        Some(b"value".to_vec())
+    );
+    fs::remove_file(out.join("tagged")).unwrap();
+
+    let options = RestoreOptions {
+        skip_xattrs: true,
+        ..Default::default()
+    };
+    hat.checkout("familyname".to_string(), None, out.clone(), &options).unwrap();
+    assert_eq!(xattr::get(out.join("tagged"), "user.hat.test").unwrap(), None);
+    fs::remove_dir_all(&out).unwrap();
+}
+
+#[test]
+fn acls_are_restored() {
+    use hat::RestoreOptions;
+    use std::env;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+    use std::process;
+    use util;
+    use xattr;
+
+    let acl = "user::rw-,user:4321:r--,group::r--,mask::r--,other::---";
+    let (_, mut hat, mut fam) = setup_family();
+    let mut e = entry(b"shared".to_vec());
+    e.info.permissions = Some(fs::Permissions::from_mode(0o640));
+    e.info.acl = Some(acl.to_string());
+    fam.snapshot_direct(e, false, Some(FileIterator::from_bytes(vec![1; 10])))
+        .unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+
+    let out = env::temp_dir().join(format!("hat-acls-{}", process::id()));
+    fs::create_dir_all(&out).unwrap();
+    let probe = util::acl_to_xattr("user::rwx,group::r-x,other::r-x").unwrap();
+    if xattr::set(&out, util::ACL_ACCESS_XATTR, &probe).is_err() {
+        // The file system holding the temporary directory lacks ACL support.
+        fs::remove_dir_all(&out).unwrap();
+        return;
+    }
+
+    hat.checkout("familyname".to_string(), None, out.clone(), &RestoreOptions::default())
+        .unwrap();
+    let value = xattr::get(out.join("shared"), util::ACL_ACCESS_XATTR).unwrap().unwrap();
+    assert_eq!(util::acl_from_xattr(&value).unwrap(), acl);
+    fs::remove_dir_all(&out).unwrap();
+}
+
+#[test]
+fn sparse_files_are_restored_with_holes() {
+    use hat::RestoreOptions;
+    use std::env;
+    use std::fs;
+    use std::io::Read;
+    use std::path::Path;
+    use std::process;
+
+    let (_, mut hat, mut fam) = setup_family();
+    let mut file = entry(b"sparse".to_vec());
+    file.info.byte_length = Some(3 << 20);
+    file.info.sparse_map = Some(vec![(1 << 20, 4)]);
+    fam.snapshot_direct(file, false, Some(FileIterator::from_bytes(b"data".to_vec())))
+        .unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+
+    let out = env::temp_dir().join(format!("hat-sparse-{}", process::id()));
+    let options = RestoreOptions::default();
+    hat.checkout("familyname".to_string(), None, out.clone(), &options).unwrap();
+
+    let mut contents = vec![];
+    fs::File::open(out.join("sparse")).unwrap().read_to_end(&mut contents).unwrap();
+    assert_eq!(contents.len(), 3 << 20);
+    assert_eq!(&contents[1 << 20..(1 << 20) + 4], b"data");
+    assert!(contents[..1 << 20].iter().all(|&b| b == 0));
+    assert!(contents[(1 << 20) + 4..].iter().all(|&b| b == 0));
+
+    let check = hat.verify_restore("familyname", None, &out, &options).unwrap();
+    assert_eq!(check.mismatches, vec![]);
+    fs::remove_dir_all(&out).unwrap();
+
+    let mut catted = vec![];
+    assert_eq!(
+        hat.cat("familyname", None, Path::new("sparse"), &mut catted).unwrap(),
+        3 << 20
+    );
+    assert_eq!(catted, contents);
+
+    fs::create_dir_all(&out).unwrap();
+    fam.checkout_in_dir(out.clone(), None).unwrap();
+    let mut checked_out = vec![];
+    fs::File::open(out.join("sparse")).unwrap().read_to_end(&mut checked_out).unwrap();
+    assert_eq!(checked_out, contents);
+    fs::remove_dir_all(&out).unwrap();
+}
+
+#[test]
+fn owners_are_restored_through_chown_map() {
+    use hat::RestoreOptions;
+    use libc;
+    use std::env;
+    use std::fs;
+    use std::os::unix::fs::MetadataExt;
+    use std::process;
+
+    let (_, mut hat, mut fam) = setup_family();
+    let mut file = entry(b"owned".to_vec());
+    file.info.user_id = Some(54321);
+    file.info.group_id = Some(54321);
+    file.info.user_name = Some("hat-no-such-user".to_string());
+    fam.snapshot_direct(file, false, Some(FileIterator::from_bytes(b"data".to_vec())))
+        .unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+
+    // Map the recorded owner to ourselves, which is allowed without privileges.
+    let (uid, gid) = unsafe { (libc::geteuid(), libc::getegid()) };
+    let mut options = RestoreOptions::default();
+    options.chown_map.add(&format!("user:hat-no-such-user:{}", uid)).unwrap();
+    options.chown_map.add(&format!("group:54321:{}", gid)).unwrap();
+
+    let out = env::temp_dir().join(format!("hat-owners-{}", process::id()));
+    hat.checkout("familyname".to_string(), None, out.clone(), &options).unwrap();
+    let meta = fs::metadata(out.join("owned")).unwrap();
+    assert_eq!((meta.uid(), meta.gid()), (uid, gid));
+    fs::remove_dir_all(&out).unwrap();
+}
+
+#[test]
+fn non_utf8_names_are_kept() {
+    use hat::RestoreOptions;
+    use std::env;
+    use std::ffi::OsStr;
+    use std::fs;
+    use std::io::{Read, Write};
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::fs::symlink;
+    use std::process;
+
+    let name = OsStr::from_bytes(b"caf\xe9");
+    let dir = env::temp_dir().join(format!("hat-non-utf8-{}", process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    fs::File::create(dir.join(name)).unwrap().write_all(b"latin-1").unwrap();
+    symlink(name, dir.join(OsStr::from_bytes(b"link\xff"))).unwrap();
+    // Snapshots hold the canonical path of their directory.
+    let dir = fs::canonicalize(&dir).unwrap();
+
+    let (_, mut hat, mut fam) = setup_family();
+    fam.snapshot_dir(dir.clone());
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+
+    let out = env::temp_dir().join(format!("hat-non-utf8-out-{}", process::id()));
+    let options = RestoreOptions::default();
+    hat.checkout("familyname".to_string(), None, out.clone(), &options).unwrap();
+
+    let restored = out.join(dir.strip_prefix("/").unwrap());
+    let mut contents = vec![];
+    fs::File::open(restored.join(name)).unwrap().read_to_end(&mut contents).unwrap();
+    assert_eq!(contents, b"latin-1");
+    let target = fs::read_link(restored.join(OsStr::from_bytes(b"link\xff"))).unwrap();
+    assert_eq!(target.as_os_str(), name);
+
+    fs::remove_dir_all(&dir).unwrap();
+    fs::remove_dir_all(&out).unwrap();
+}
+
+#[test]
+fn snapshot_paths_keeps_only_listed_entries() {
+    use hat::RestoreOptions;
+    use std::env;
+    use std::fs;
+    use std::io::Write;
+    use std::process;
+
+    let dir = env::temp_dir().join(format!("hat-files-from-{}", process::id()));
+    fs::create_dir_all(dir.join("sub")).unwrap();
+    for name in &["a", "b", "sub/c", "sub/d"] {
+        fs::File::create(dir.join(name)).unwrap().write_all(name.as_bytes()).unwrap();
+    }
+    // Snapshots hold the canonical path of their directory.
+    let dir = fs::canonicalize(&dir).unwrap();
+
+    let (_, mut hat, mut fam) = setup_family();
+    fam.snapshot_paths(vec![dir.join("a"), dir.join("sub").join("c")]).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+
+    let out = env::temp_dir().join(format!("hat-files-from-out-{}", process::id()));
+    let options = RestoreOptions::default();
+    hat.checkout("familyname".to_string(), None, out.clone(), &options).unwrap();
+
+    let restored = out.join(dir.strip_prefix("/").unwrap());
+    assert!(restored.join("a").is_file());
+    assert!(restored.join("sub/c").is_file());
+    assert!(!restored.join("b").exists());
+    assert!(!restored.join("sub/d").exists());
+
+    fs::remove_dir_all(&dir).unwrap();
+    fs::remove_dir_all(&out).unwrap();
+}
+
+#[test]
+fn follow_symlinks_stops_at_cycles() {
+    use hat::RestoreOptions;
+    use std::env;
+    use std::fs;
+    use std::io::Write;
+    use std::os::unix::fs::symlink;
+    use std::process;
+
+    let root = env::temp_dir().join(format!("hat-follow-{}", process::id()));
+    let (dir, ext) = (root.join("dir"), root.join("ext"));
+    fs::create_dir_all(&dir).unwrap();
+    fs::create_dir_all(&ext).unwrap();
+    fs::File::create(dir.join("f")).unwrap().write_all(b"file").unwrap();
+    fs::File::create(ext.join("x")).unwrap().write_all(b"elsewhere").unwrap();
+    symlink("f", dir.join("lf")).unwrap();
+    symlink(&ext, dir.join("ld")).unwrap();
+    symlink(".", dir.join("loop")).unwrap();
+    // Snapshots hold the canonical path of their directory.
+    let dir = fs::canonicalize(&dir).unwrap();
+
+    let (_, mut hat, mut fam) = setup_family();
+    fam.follow_symlinks = true;
+    fam.snapshot_dir(dir.clone());
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+
+    let out = env::temp_dir().join(format!("hat-follow-out-{}", process::id()));
+    let options = RestoreOptions::default();
+    hat.checkout("familyname".to_string(), None, out.clone(), &options).unwrap();
+
+    let restored = out.join(dir.strip_prefix("/").unwrap());
+    assert!(fs::symlink_metadata(restored.join("lf")).unwrap().is_file());
+    assert!(fs::symlink_metadata(restored.join("ld")).unwrap().is_dir());
+    assert!(restored.join("ld").join("x").is_file());
+    // The link back to the snapshot directory is kept as a link.
+    assert_eq!(fs::read_link(restored.join("loop")).unwrap(), PathBuf::from("."));
+
+    fs::remove_dir_all(&root).unwrap();
+    fs::remove_dir_all(&out).unwrap();
+}
+
+#[test]
+fn unreadable_files_are_recorded_and_skipped() {
+    use std::env;
+    use std::fs;
+    use std::io::Write;
+    use std::process;
+
+    let dir = env::temp_dir().join(format!("hat-file-errors-{}", process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    fs::File::create(dir.join("a")).unwrap().write_all(b"a").unwrap();
+    let dir = fs::canonicalize(&dir).unwrap();
+
+    let (_, mut hat, mut fam) = setup_family();
+    fam.snapshot_paths(vec![dir.join("a"), dir.join("vanished")]).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+
+    let errors = fam.errors.lock().unwrap().clone();
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].path, dir.join("vanished"));
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn identical_files_are_restored_from_shared_data() {
+    use hat::RestoreOptions;
+    use std::env;
+    use std::fs;
+    use std::io::Read;
+    use std::process;
+
+    let data: Vec<u8> = (0..300_000).map(|i| (i % 251) as u8).collect();
+    let (_, mut hat, mut fam) = setup_family();
+    for name in &[b"first".to_vec(), b"second".to_vec()] {
+        let contents = FileIterator::from_bytes(data.clone());
+        fam.snapshot_direct(entry(name.clone()), false, Some(contents)).unwrap();
+    }
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+
+    let out = env::temp_dir().join(format!("hat-reflink-{}", process::id()));
+    let options = RestoreOptions::default();
+    hat.checkout("familyname".to_string(), None, out.clone(), &options).unwrap();
+
+    for name in &["first", "second"] {
+        let mut contents = vec![];
+        fs::File::open(out.join(name)).unwrap().read_to_end(&mut contents).unwrap();
+        assert_eq!(contents, data);
+    }
+    fs::remove_dir_all(&out).unwrap();
+}
+
+#[test]
+fn list_dir_lists_one_directory_or_all_below_it() {
+    use hat::{EntryKind, ListEntry};
+    use std::path::Path;
+
+    let (_, mut hat, mut fam) = setup_family();
+    snapshot_files(
+        &fam,
+        vec![("docs/a", "aaa".into()), ("docs/sub/b", "b".into()), ("top", vec![])],
+    ).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+
+    let paths = |entries: Vec<ListEntry>| {
+        let mut paths: Vec<String> = entries
+            .into_iter()
+            .map(|e| e.path.to_string_lossy().into_owned())
+            .collect();
+        paths.sort();
+        paths
+    };
+    let docs = hat.list_dir("familyname", None, Path::new("docs"), false).unwrap();
+    let a = docs.iter().find(|e| e.path == Path::new("a")).unwrap();
+    assert_eq!(a.kind, EntryKind::File);
+    assert_eq!(paths(docs), vec!["a", "sub"]);
+
+    let all = hat.list_dir("familyname", None, Path::new("/"), true).unwrap();
+    assert_eq!(paths(all), vec!["docs", "docs/a", "docs/sub", "docs/sub/b", "top"]);
+    assert!(hat.list_dir("familyname", None, Path::new("top"), false).is_err());
+}
+
+#[test]
+fn find_reports_the_snapshots_with_matches() {
+    use hat::FindFilter;
+
+    let (_, mut hat, mut fam) = setup_family();
+    snapshot_files(&fam, vec![("docs/b.conf", "b".into()), ("a.conf", "a".into())]).unwrap();
+    let mut notes = entry(b"notes.txt".to_vec());
+    notes.info.byte_length = Some(5);
+    fam.snapshot_direct(notes, false, Some(FileIterator::from_bytes(b"notes".to_vec())))
+        .unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+
+    let mut fam = hat.open_family("familyname".to_string()).unwrap();
+    snapshot_files(&fam, vec![("a.conf", "a".into())]).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+
+    // Shares its directory with the first snapshot.
+    let mut fam = hat.open_family("familyname".to_string()).unwrap();
+    snapshot_files(&fam, vec![("docs/b.conf", "b".into())]).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+
+    let found = |hat: &mut HatRc<MemoryBackend>, pattern: &str, filter: &FindFilter| {
+        hat.find("familyname", &Pattern::new(pattern), filter)
+            .unwrap()
+            .into_iter()
+            .map(|(id, e)| (id, e.path.to_string_lossy().into_owned()))
+            .collect::<Vec<_>>()
+    };
+    let any = FindFilter::default();
+    assert_eq!(
+        found(&mut hat, "b.conf", &any),
+        vec![(1, "docs/b.conf".to_string()), (3, "docs/b.conf".to_string())]
+    );
+    assert_eq!(found(&mut hat, "a.conf", &any).len(), 2);
+
+    let unbounded = FindFilter {
+        max_size: Some(u64::max_value()),
+        ..FindFilter::default()
+    };
+    assert_eq!(found(&mut hat, "notes.txt", &unbounded), vec![(1, "notes.txt".to_string())]);
+
+    let large = FindFilter {
+        min_size: Some(2),
+        ..FindFilter::default()
+    };
+    assert_eq!(found(&mut hat, "*", &large), vec![(1, "notes.txt".to_string())]);
+}
+
+#[test]
+fn disk_usage_counts_shared_data_as_unique_to_none() {
+    use std::path::Path;
+
+    let shared: Vec<u8> = (0..300_000).map(|i| (i % 251) as u8).collect();
+    let own: Vec<u8> = (0..300_000).map(|i| (i % 241) as u8).collect();
+    let (_, mut hat, mut fam) = setup_family();
+    snapshot_files(
+        &fam,
+        vec![("a/x", shared.clone()), ("b/y", shared.clone()), ("b/z", own.clone())],
+    ).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+
+    let mut usage = hat.disk_usage("familyname", None, Path::new("/")).unwrap();
+    usage.sort_by(|a, b| a.path.cmp(&b.path));
+    assert_eq!(usage.len(), 2);
+    assert_eq!((&usage[0].path, usage[0].unique_bytes), (&PathBuf::from("a"), 0));
+    assert!(usage[1].unique_bytes > 0);
+}
+
+#[test]
+fn repository_stats_are_counted_incrementally() {
+    let (_, mut hat, mut fam) = setup_family();
+    let data: Vec<u8> = (0..300_000).map(|i| (i % 251) as u8).collect();
+    snapshot_files(&fam, vec![("a", data.clone())]).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.meta_commit().unwrap();
+    hat.data_flush().unwrap();
+
+    let first = hat.repository_stats().unwrap();
+    assert!(first.chunks > 0 && first.chunk_bytes > 0);
+    assert!(first.blobs > 0 && first.blob_bytes > 0);
+    assert_eq!(first.families.len(), 1);
+    assert_eq!(first.families[0].snapshots, 1);
+    assert_eq!(hat.repository_stats().unwrap(), first);
+
+    let mut fam = hat.open_family("familyname".to_string()).unwrap();
+    let other: Vec<u8> = (0..300_000).map(|i| (i % 241) as u8).collect();
+    snapshot_files(&fam, vec![("a", data), ("b", other)]).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.meta_commit().unwrap();
+    hat.data_flush().unwrap();
+
+    let second = hat.repository_stats().unwrap();
+    assert!(second.chunks > first.chunks);
+    assert!(second.blob_bytes > first.blob_bytes);
+    assert_eq!(second.families[0].snapshots, 2);
+}
+
+#[test]
+fn quota_status_projects_the_next_commit() {
+    use hat::Quota;
+
+    let (_, mut hat, mut fam) = setup_family();
+    let data: Vec<u8> = (0..300_000).map(|i| (i % 251) as u8).collect();
+    snapshot_files(&fam, vec![("a", data)]).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.meta_commit().unwrap();
+    hat.data_flush().unwrap();
+
+    let growth = hat.expected_growth("familyname");
+    assert_eq!(growth, 300_000);
+    assert_eq!(hat.expected_growth("unknown"), 0);
+
+    let used = hat.repository_stats().unwrap().blob_bytes;
+    let roomy = Quota {
+        max_bytes: used + growth,
+        warn_only: false,
+    };
+    let status = hat.quota_status(&roomy, growth).unwrap();
+    assert_eq!((status.used_bytes, status.projected_bytes), (used, used + growth));
+    assert!(!status.is_exceeded());
+
+    let tight = Quota {
+        max_bytes: used - 10,
+        ..roomy
+    };
+    let status = hat.quota_status(&tight, growth).unwrap();
+    assert!(status.is_exceeded());
+    assert_eq!(status.excess_bytes(), growth + 10);
+}
+
+#[test]
+fn check_reports_missing_and_orphaned_blobs() {
+    use crypto;
+    use hat::ProblemKind;
+    use tags;
+
+    let (backend, mut hat, mut fam) = setup_family();
+    let data: Vec<u8> = (0..300_000).map(|i| (i % 251) as u8).collect();
+    snapshot_files(&fam, vec![("a", data)]).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.meta_commit().unwrap();
+    hat.data_flush().unwrap();
+
+    let report = hat.check(Some(1.0)).unwrap();
+    assert!(report.is_ok(), "{:?}", report.problems);
+    assert!(report.snapshots > 0);
+    assert!(report.chunks > 0 && report.chunks_read > 0);
+
+    let junk = crypto::CipherText::new(b"junk".to_vec());
+    backend.store(b"not a blob of this repository", &junk).unwrap();
+    let report = hat.check(None).unwrap();
+    assert_eq!(report.problems.len(), 1);
+    assert_eq!(report.problems[0].kind, ProblemKind::Orphaned);
+    backend.delete(b"not a blob of this repository").unwrap();
+
+    let name = hat.blob_store.list_by_tag(tags::Tag::Done)[0].name.as_bytes().to_vec();
+    backend.delete(&name).unwrap();
+    let report = hat.check(None).unwrap();
+    assert!(report.problems.iter().any(|p| p.kind == ProblemKind::Missing));
+}
+
+#[test]
+fn repair_adopts_orphaned_blobs_and_drops_lost_ones() {
+    use blob;
+    use hat::DAMAGED_LABEL;
+    use tags;
+
+    let (backend, mut hat, mut fam) = setup_family();
+    let a: Vec<u8> = (0..300_000).map(|i| (i % 251) as u8).collect();
+    snapshot_files(&fam, vec![("a", a.clone())]).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.meta_commit().unwrap();
+    hat.data_flush().unwrap();
+
+    // Forget about one of the blobs; repair takes it back.
+    let blob = hat.blob_store.list_by_tag(tags::Tag::Done).pop().unwrap();
+    {
+        let index = hat.db.lock();
+        index.blob_set_tag(tags::Tag::WillDelete, Some(&blob));
+        index.blob_delete_by_tag(tags::Tag::WillDelete);
+    }
+    let report = hat.repair().unwrap();
+    assert_eq!(report.blobs_adopted, 1);
+    assert_eq!(report.blobs_lost, 0);
+    assert!(report.damaged.is_empty());
+    assert!(hat.check(None).unwrap().is_ok());
+
+    // Lose the blob holding a second file; only the snapshot using it is damaged.
+    let before: Vec<_> = hat.blob_store.list_by_tag(tags::Tag::Done);
+    let mut fam = hat.open_family("familyname".to_string()).unwrap();
+    let b: Vec<u8> = (0..300_000).map(|i| (i % 241) as u8).collect();
+    snapshot_files(&fam, vec![("a", a), ("b", b)]).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.meta_commit().unwrap();
+    hat.data_flush().unwrap();
+
+    let lost = hat.hash_index
+        .list()
+        .into_iter()
+        .filter(|e| e.leaf == blob::LeafType::FileChunk)
+        .filter_map(|e| e.persistent_ref)
+        .map(|r| r.blob_name)
+        .find(|name| before.iter().all(|b| b.name != *name))
+        .unwrap();
+    backend.delete(&lost[..]).unwrap();
+    let report = hat.repair().unwrap();
+    assert_eq!(report.blobs_lost, 1);
+    assert!(report.chunks_dropped > 0);
+    assert!(report.damaged.iter().all(|d| d.snapshot_id == 2));
+    assert!(!report.damaged.is_empty());
+    assert_eq!(hat.resolve_snapshot("familyname", DAMAGED_LABEL).unwrap(), 2);
+
+    assert_eq!(hat.repair().unwrap().blobs_lost, 0);
+}
+
+#[test]
+fn hooks_see_their_context() {
+    use hat::{Config, Hook};
+    use std::env;
+    use std::fs;
+    use std::io::Read;
+    use std::process;
+
+    let out = env::temp_dir().join(format!("hat-hooks-{}", process::id()));
+    let config = Config::parse(&format!(
+        r#"
+        [repositories.home.hooks]
+        pre_commit = "echo $HAT_HOOK $HAT_FAMILY > {}"
+        post_commit = "exit 3"
+        "#,
+        out.display()
+    )).unwrap();
+    let hooks = config.repository(None).unwrap().hooks;
+
+    let env = vec![("HAT_FAMILY", "familyname".to_string())];
+    hooks.run(Hook::PreCommit, &env).unwrap();
+    let mut seen = String::new();
+    fs::File::open(&out).unwrap().read_to_string(&mut seen).unwrap();
+    assert_eq!(seen, "pre_commit familyname\n");
+
+    assert!(hooks.run(Hook::PostCommit, &env).is_err());
+    // Hooks that are not configured do nothing.
+    hooks.run(Hook::OnError, &env).unwrap();
+
+    fs::remove_file(&out).unwrap();
+}
+
+#[test]
+fn passphrase_sources_drop_the_trailing_newline() {
+    use hat::PassphraseSource;
+    use std::env;
+    use std::fs;
+    use std::io::Write;
+    use std::process;
+
+    let file = env::temp_dir().join(format!("hat-passphrase-{}", process::id()));
+    fs::File::create(&file).unwrap().write_all(b"correct horse\n").unwrap();
+    let from_file = PassphraseSource::File(file.clone()).read().unwrap();
+    assert_eq!(from_file.unsecure(), b"correct horse");
+    fs::remove_file(&file).unwrap();
+
+    let command = PassphraseSource::Command("printf 'battery\\r\\n'".to_string());
+    assert_eq!(command.read().unwrap().unsecure(), b"battery");
+
+    env::set_var("HAT_TEST_PASSPHRASE", "staple");
+    let from_env = PassphraseSource::Env("HAT_TEST_PASSPHRASE".to_string());
+    assert_eq!(from_env.read().unwrap().unsecure(), b"staple");
+
+    assert!(PassphraseSource::Command("exit 1".to_string()).read().is_err());
+    assert!(PassphraseSource::Command("true".to_string()).read().is_err());
+    assert!(PassphraseSource::Env("HAT_TEST_NO_PASSPHRASE".to_string()).read().is_err());
+}
+
+#[test]
+fn config_selects_repository() {
+    use hat::{Config, Quota, RepositoryConfig, RetentionPolicy};
+
+    let config = Config::parse(
+        r#"
+        default_repository = "home"
+
+        [repositories.home]
+        blob_dir = "/mnt/blobs"
+        exclude = ["*.tmp"]
+
+        [repositories.home.retention]
+        keep_daily = 7
+
+        [repositories.home.quota]
+        max_bytes = 1000000
+
+        [repositories.work]
+        fanout = 16
+        gc_grace_hours = 48
+        gc_deletes_per_sec = 100
+        "#,
+    ).unwrap();
+
+    let home = config.repository(None).unwrap();
+    assert_eq!(home.blob_dir, Some(PathBuf::from("/mnt/blobs")));
+    assert_eq!(home.exclude, vec!["*.tmp".to_string()]);
+    assert_eq!(
+        home.retention,
+        Some(RetentionPolicy {
+            keep_daily: 7,
+            ..Default::default()
+        })
+    );
+    assert_eq!(
+        home.quota,
+        Some(Quota {
+            max_bytes: 1_000_000,
+            warn_only: false,
+        })
+    );
+    assert_eq!(config.repository(Some("work")).unwrap().fanout, Some(16));
+    assert_eq!(config.repository(Some("work")).unwrap().gc_grace_hours, Some(48));
+    assert_eq!(config.repository(Some("work")).unwrap().gc_deletes_per_sec, Some(100));
+    assert!(config.repository(Some("other")).is_err());
+    assert_eq!(config.default_name(), Some("home"));
+
+    // Without a default, only a single repository is picked.
+    let mut several = config.clone();
+    several.default_repository = None;
+    assert_eq!(several.default_name(), None);
+    assert!(several.repository(None).is_err());
+    several.repositories.remove("work");
+    assert_eq!(several.default_name(), Some("home"));
+
+    assert!(Config::parse("[repositories.home]\nblob_directory = \"x\"").is_err());
+    assert_eq!(Config::default().repository(None).unwrap(), RepositoryConfig::default());
+}
+
+#[test]
+fn commit_dir_reports_the_snapshot() {
+    use std::env;
+    use std::fs;
+    use std::io::Write;
+    use std::process;
+
+    let backend = Arc::new(MemoryBackend::new());
+    let mut hat = setup_hat(backend);
+    let dir = env::temp_dir().join(format!("hat-commit-dir-{}", process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    fs::File::create(dir.join("a")).unwrap().write_all(b"contents").unwrap();
+
+    let report = hat.commit_dir("familyname", &dir).unwrap();
+    assert_eq!(report.snapshot.family, "familyname");
+    assert_eq!(report.snapshot.snapshot_id, 1);
+    assert!(report.errors.is_empty());
+    assert_eq!(hat.list_snapshots().len(), 1);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[cfg(feature = "web")]
+#[test]
+fn web_query_round_trip() {
+    use hat::web::{encode, parse_query};
+
+    let path = "/home/a b/100% & more+";
+    let query = parse_query(&format!("family=home&path={}&id=3", encode(path)));
+    assert_eq!(query["path"], path);
+    assert_eq!(query["family"], "home");
+    assert_eq!(parse_query("name=a+b&empty")["name"], "a b");
+}
+
+#[cfg(feature = "web")]
+#[test]
+fn web_serves_byte_ranges_and_pages() {
+    use hat::{EntryKind, ListEntry};
+    use hat::web::{ByteRange, byte_range, page_of};
+
+    assert_eq!(byte_range(None, 100), ByteRange::Whole);
+    assert_eq!(byte_range(Some("bytes=10-19"), 100), ByteRange::Part(10, 19));
+    assert_eq!(byte_range(Some("bytes=90-"), 100), ByteRange::Part(90, 99));
+    assert_eq!(byte_range(Some("bytes=-5"), 100), ByteRange::Part(95, 99));
+    assert_eq!(byte_range(Some("bytes=50-500"), 100), ByteRange::Part(50, 99));
+    assert_eq!(byte_range(Some("bytes=100-"), 100), ByteRange::Unsatisfiable);
+    assert_eq!(byte_range(Some("bytes=0-1,5-6"), 100), ByteRange::Whole);
+    assert_eq!(byte_range(Some("items=0-1"), 100), ByteRange::Whole);
+
+    let entries: Vec<ListEntry> = ["c", "a", "d", "b"]
+        .iter()
+        .map(|name| {
+            ListEntry {
+                path: PathBuf::from(name),
+                kind: EntryKind::File,
+                size: Some(1),
+                mode: None,
+                modified: None,
+            }
+        })
+        .collect();
+    let names = |page: &[ListEntry]| -> Vec<PathBuf> {
+        page.iter().map(|e| e.path.clone()).collect()
+    };
+    let (first, more) = page_of(entries.clone().into_iter().map(Ok), None, 3).unwrap();
+    assert_eq!(names(&first), vec![PathBuf::from("a"), PathBuf::from("b"), PathBuf::from("c")]);
+    assert!(more);
+    let (rest, more) = page_of(entries.into_iter().map(Ok), Some("c"), 3).unwrap();
+    assert_eq!(names(&rest), vec![PathBuf::from("d")]);
+    assert!(!more);
+}
+
+#[cfg(feature = "web")]
+#[test]
+fn web_needs_a_token_of_the_user_to_serve_other_machines() {
+    use hat::WebAccess;
+
+    let (_, mut hat, _) = setup_family();
+    let access = WebAccess::session();
+    assert_eq!(access.token().len(), 32);
+    assert!(access.token() != WebAccess::session().token());
+    assert!(hat.serve_web("0.0.0.0:0", PathBuf::from("restored"), &access).is_err());
+}
+
+#[cfg(feature = "mount")]
+#[test]
+fn mounted_files_read_out_of_order_and_families_list_once() {
+    use hat::mount::{ROOT_INO, SnapshotFs};
+    use std::ffi::OsString;
+
+    let (_, mut hat, mut fam) = setup_family();
+    let data: Vec<u8> = (0..600000u32).map(|i| (i % 251) as u8 ^ (i / 4096) as u8).collect();
+    let mut e = entry(b"big".to_vec());
+    e.info.byte_length = Some(data.len() as u64);
+    fam.snapshot_direct(e, false, Some(FileIterator::from_bytes(data.clone())))
+        .unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+
+    // Snapshots of the two families alternate.
+    let mut other = hat.open_family("other".to_string()).unwrap();
+    snapshot_files(&other, vec![("a", "a".into())]).unwrap();
+    other.flush().unwrap();
+    hat.commit(&mut other, None).unwrap();
+    let mut fam = hat.open_family("familyname".to_string()).unwrap();
+    snapshot_files(&fam, vec![("a", "a".into())]).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.meta_commit().unwrap();
+
+    let mut fs = SnapshotFs::new(&mut hat);
+    let families = fs.list(ROOT_INO).unwrap();
+    let names: Vec<OsString> = families.iter().map(|f| f.0.clone()).collect();
+    assert_eq!(names, vec![OsString::from("familyname"), OsString::from("other")]);
+    let snapshots = fs.list(families[0].1).unwrap();
+    assert_eq!(snapshots[0].0, OsString::from("1"));
+    let files = fs.list(snapshots[0].1).unwrap();
+    assert_eq!(files[0].0, OsString::from("big"));
+
+    let ino = files[0].1;
+    let fh = fs.open_file(ino).unwrap();
+    for &(offset, len) in &[(500000, 4096), (1000, 300000), (599990, 100), (0, 600000)] {
+        let read = fs.read_range(ino, fh, offset as u64, len as u64).unwrap();
+        let end = ::std::cmp::min(offset + len, data.len());
+        assert_eq!(read, &data[offset..end]);
+    }
+}
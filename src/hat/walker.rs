@@ -22,6 +22,7 @@ pub enum Content {
     Data(hash::tree::HashRef),
     Dir(hash::tree::HashRef),
     Link(PathBuf),
+    Inline(Vec<u8>),
 }
 
 #[derive(Clone)]
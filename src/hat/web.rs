@@ -0,0 +1,778 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A small web interface to browse snapshots, download files and restore them.
+//!
+//! Pages are plain HTML without scripts. Everything is addressed by query parameters:
+//!
+//! - `/`: the families with their latest snapshot.
+//! - `/snapshots?family=F`: the completed snapshots of a family.
+//! - `/browse?family=F&id=N&path=P`: a directory of a snapshot, a page of entries at a time.
+//!   The next page is asked for with `after=NAME`, the last name shown, and `limit` sets the
+//!   number of entries on a page.
+//! - `/file?family=F&id=N&path=P`: the contents of a file. A single byte range can be asked
+//!   for with a `Range` header, e.g. to resume a download.
+//! - `/restore`, posted with `family`, `id` and `path`: restore a file or directory below the
+//!   restore directory, at the same path as in the snapshot.
+//! - `/stats`: the repository statistics.
+//!
+//! Every request needs the token of the session, given once as `?token=T` and then kept in a
+//! cookie. Restores also carry it in the form, and requests naming another site in their `Host`
+//! or `Origin` header are turned away, so that other web pages can neither post restores nor
+//! read snapshots through DNS rebinding.
+//!
+//! Snapshots do not change, so pages of snapshots and files carry an `ETag`, and a request
+//! with a matching `If-None-Match` gets a `304 Not Modified` without the contents being read.
+
+use backend::StoreBackend;
+use blake3;
+use chrono;
+use crypto;
+use errors::HatError;
+use hash;
+use hex::ToHex;
+use std::cmp::{self, Ordering};
+use std::collections::{BTreeMap, BinaryHeap, HashMap};
+use std::ffi::OsStr;
+use std::io::{self, Read};
+use std::net::ToSocketAddrs;
+use std::path::{Path, PathBuf};
+use tiny_http::{Header, Method, Request, Response, Server, StatusCode};
+use util::{self, HoleFiller};
+
+use super::{EntryKind, HatRc, ListEntry, RestoreOptions};
+use super::archive::DataReader;
+use super::walker::Content;
+
+
+const STYLE: &'static str = "body { font-family: sans-serif; margin: 2em; } \
+                             table { border-collapse: collapse; } \
+                             td, th { padding: 0.2em 1em; text-align: left; } \
+                             tr:nth-child(even) { background: #f0f0f0; }";
+
+/// Entries on a page of a directory, unless the request asks for another number.
+const PAGE_ENTRIES: usize = 1000;
+
+/// Escape text for HTML.
+fn escape(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// Encode a query parameter value.
+pub fn encode(value: &str) -> String {
+    let mut out = String::new();
+    for b in value.bytes() {
+        match b {
+            b'a'...b'z' | b'A'...b'Z' | b'0'...b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => {
+                out.push(b as char)
+            }
+            b => out.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    out
+}
+
+/// Decode a query string or form body into its parameters.
+pub fn parse_query(query: &str) -> HashMap<String, String> {
+    let decode = |s: &str| {
+        let s = s.as_bytes();
+        let mut out = vec![];
+        let mut i = 0;
+        while i < s.len() {
+            match s[i] {
+                b'+' => out.push(b' '),
+                b'%' if i + 2 < s.len() => {
+                    match u8::from_str_radix(&String::from_utf8_lossy(&s[i + 1..i + 3]), 16) {
+                        Ok(b) => {
+                            out.push(b);
+                            i += 2;
+                        }
+                        Err(_) => out.push(b'%'),
+                    }
+                }
+                b => out.push(b),
+            }
+            i += 1;
+        }
+        String::from_utf8_lossy(&out).into_owned()
+    };
+    query
+        .split('&')
+        .filter(|p| !p.is_empty())
+        .map(|p| match p.find('=') {
+            Some(i) => (decode(&p[..i]), decode(&p[i + 1..])),
+            None => (decode(p), String::new()),
+        })
+        .collect()
+}
+
+fn page(title: &str, body: &str) -> String {
+    format!(
+        "<!DOCTYPE html><html><head><meta charset=\"utf-8\"><title>{} - hat</title>\
+         <style>{}</style></head><body><p><a href=\"/\">Families</a> | \
+         <a href=\"/stats\">Statistics</a></p><h1>{}</h1>{}</body></html>",
+        escape(title),
+        STYLE,
+        escape(title),
+        body
+    )
+}
+
+/// The part of a file that a `Range` header asks for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ByteRange {
+    /// The whole file, also for headers that are missing or not understood.
+    Whole,
+    /// The bytes from the first offset to the last, inclusive.
+    Part(u64, u64),
+    /// A range that starts past the end of the file.
+    Unsatisfiable,
+}
+
+/// Parse the `Range` header of a request for a file of `size` bytes. Only single byte ranges
+/// are served; a list of ranges gets the whole file.
+pub fn byte_range(header: Option<&str>, size: u64) -> ByteRange {
+    let spec = match header.map(|h| h.trim()) {
+        Some(h) if h.starts_with("bytes=") && !h.contains(',') => &h["bytes=".len()..],
+        _ => return ByteRange::Whole,
+    };
+    let (first, last) = match spec.find('-') {
+        Some(i) => (spec[..i].trim(), spec[i + 1..].trim()),
+        None => return ByteRange::Whole,
+    };
+    let end = size.saturating_sub(1);
+    let (first, last) = match (first.parse::<u64>(), last.parse::<u64>()) {
+        // The last bytes of the file.
+        (Err(_), Ok(0)) if first.is_empty() => return ByteRange::Unsatisfiable,
+        (Err(_), Ok(n)) if first.is_empty() => (size.saturating_sub(n), end),
+        (Ok(first), Err(_)) if last.is_empty() => (first, end),
+        (Ok(first), Ok(last)) if first <= last => (first, cmp::min(last, end)),
+        _ => return ByteRange::Whole,
+    };
+    if first >= size {
+        ByteRange::Unsatisfiable
+    } else {
+        ByteRange::Part(first, last)
+    }
+}
+
+/// Orders directory entries by name, for `page_of`.
+struct ByName(ListEntry);
+
+impl PartialEq for ByName {
+    fn eq(&self, other: &ByName) -> bool {
+        self.0.path == other.0.path
+    }
+}
+
+impl Eq for ByName {}
+
+impl PartialOrd for ByName {
+    fn partial_cmp(&self, other: &ByName) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for ByName {
+    fn cmp(&self, other: &ByName) -> Ordering {
+        self.0.path.as_os_str().cmp(other.0.path.as_os_str())
+    }
+}
+
+/// A page of directory `entries` in the order of their names: those after the name `after`,
+/// at most `limit` of them. Also tells whether more entries follow.
+///
+/// Directories are not stored in name order, so all entries are looked at, but only the page
+/// is kept: the first `limit + 1` names seen so far, the last of which tells if there are more.
+pub fn page_of<I>(
+    entries: I,
+    after: Option<&str>,
+    limit: usize,
+) -> Result<(Vec<ListEntry>, bool), HatError>
+where
+    I: IntoIterator<Item = Result<ListEntry, HatError>>,
+{
+    let keep = limit.saturating_add(1);
+    let mut first = BinaryHeap::new();
+    for entry in entries {
+        let entry = entry?;
+        if after.map_or(true, |a| entry.path.as_os_str() > OsStr::new(a)) {
+            first.push(ByName(entry));
+            if first.len() > keep {
+                first.pop();
+            }
+        }
+    }
+    let mut page: Vec<ListEntry> = first.into_sorted_vec().into_iter().map(|e| e.0).collect();
+    let more = page.len() > limit;
+    page.truncate(limit);
+    Ok((page, more))
+}
+
+/// An entity tag for what a request shows of a snapshot, with root hash `root`. Snapshots do
+/// not change, so the root hash and the `parts` of the request that select what is shown
+/// identify it.
+fn etag(root: &hash::tree::HashRef, parts: &[&str]) -> String {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(&root.hash.bytes[..]);
+    for part in parts {
+        hasher.update(&[0]);
+        hasher.update(part.as_bytes());
+    }
+    format!("\"{}\"", hasher.finalize().as_bytes()[..16].to_hex())
+}
+
+/// Whether the `If-None-Match` header of a request names `etag`.
+fn not_modified(request: &Request, etag: &str) -> bool {
+    header(request, "If-None-Match").map_or(false, |tags| {
+        tags.split(',').map(|t| t.trim()).any(|t| t == etag || t == "*")
+    })
+}
+
+fn response_header(name: &str, value: &str) -> Header {
+    Header::from_bytes(name.as_bytes(), value.as_bytes()).unwrap()
+}
+
+fn html_response(status: u16, html: String) -> Response<io::Cursor<Vec<u8>>> {
+    let content_type = Header::from_bytes(&b"Content-Type"[..], &b"text/html; charset=utf-8"[..])
+        .unwrap();
+    Response::from_string(html).with_status_code(StatusCode(status)).with_header(content_type)
+}
+
+/// Who may use the web interface: whoever has the token.
+pub struct WebAccess {
+    token: String,
+    remote: bool,
+}
+
+impl WebAccess {
+    /// A random token for this session. The interface is then only served on addresses of this
+    /// machine.
+    pub fn session() -> WebAccess {
+        WebAccess {
+            token: crypto::keys::random_bytes(16).unsecure().to_hex(),
+            remote: false,
+        }
+    }
+
+    /// A token chosen by the user, with which the interface may be served to other machines.
+    pub fn with_token(token: String) -> WebAccess {
+        WebAccess {
+            token: token,
+            remote: true,
+        }
+    }
+
+    pub fn token(&self) -> &str {
+        &self.token
+    }
+
+    fn matches(&self, token: Option<&str>) -> bool {
+        // Compare in constant time, not to give the token away byte by byte.
+        token.map_or(false, |t| {
+            t.len() == self.token.len() &&
+                t.bytes().zip(self.token.bytes()).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+        })
+    }
+
+    /// Whether a request has the token, and names this server in its `Host` and `Origin`.
+    fn allows(&self, request: &Request, query: &HashMap<String, String>, hosts: &[String]) -> bool {
+        let host = match header(request, "Host") {
+            Some(host) => host,
+            None => return false,
+        };
+        // Names of other machines only reach a server on the loopback interface by rebinding.
+        if !self.remote && !hosts.iter().any(|h| h.eq_ignore_ascii_case(host)) {
+            return false;
+        }
+        if let Some(origin) = header(request, "Origin") {
+            if !origin.eq_ignore_ascii_case(&format!("http://{}", host)) {
+                return false;
+            }
+        }
+        self.matches(query.get("token").map(|t| &t[..])) || self.matches(cookie(request))
+    }
+
+    fn cookie(&self) -> Header {
+        let value = format!("hat_token={}; HttpOnly; SameSite=Strict; Path=/", self.token);
+        Header::from_bytes(&b"Set-Cookie"[..], value.as_bytes()).unwrap()
+    }
+}
+
+fn header<'a>(request: &'a Request, name: &'static str) -> Option<&'a str> {
+    request.headers().iter().find(|h| h.field.equiv(name)).map(|h| h.value.as_str())
+}
+
+/// The session token from the cookie of a request.
+fn cookie(request: &Request) -> Option<&str> {
+    header(request, "Cookie").and_then(|cookies| {
+        cookies
+            .split(';')
+            .map(|c| c.trim())
+            .find(|c| c.starts_with("hat_token="))
+            .map(|c| &c["hat_token=".len()..])
+    })
+}
+
+/// The names a browser on this machine may use for a server on the loopback address `addr`.
+fn local_hosts(addr: &str, port: u16) -> Vec<String> {
+    vec![
+        addr.to_string(),
+        format!("localhost:{}", port),
+        format!("127.0.0.1:{}", port),
+        format!("[::1]:{}", port),
+    ]
+}
+
+/// What a request asks for, and the response it gets.
+enum Reply {
+    Html(String, Option<String>),
+    /// The data of a file of `size` bytes, or of the `range` of it asked for.
+    File {
+        data: Box<Read>,
+        size: u64,
+        range: Option<(u64, u64)>,
+        etag: String,
+    },
+    NotModified(String),
+    /// A range past the end of a file of this size.
+    Unsatisfiable(u64),
+    NotFound,
+    Forbidden,
+}
+
+impl<B: StoreBackend> HatRc<B> {
+    /// Serve the web interface on `addr`, e.g. `127.0.0.1:8080`, until the process is stopped.
+    /// Restores are written below `restore_dir`. Without a token of the user, only addresses of
+    /// this machine are served on.
+    pub fn serve_web(
+        &mut self,
+        addr: &str,
+        restore_dir: PathBuf,
+        access: &WebAccess,
+    ) -> Result<(), HatError> {
+        let addrs: Vec<_> = addr.to_socket_addrs()
+            .map_err(|e| format!("Invalid address {}: {}", addr, e))?
+            .collect();
+        let port = match addrs.first() {
+            Some(a) => a.port(),
+            None => return Err(From::from(format!("Invalid address {}", addr))),
+        };
+        if !access.remote && addrs.iter().any(|a| !a.ip().is_loopback()) {
+            return Err(From::from(format!(
+                "Refusing to serve on {}, which other machines can reach, without a token \
+                 of your own (HAT_WEB_TOKEN)",
+                addr
+            )));
+        }
+        let hosts = local_hosts(addr, port);
+
+        let server = Server::http(addr)
+            .map_err(|e| format!("Could not listen on {}: {}", addr, e))?;
+        info!("Serving the web interface on http://{}/", addr);
+        for mut request in server.incoming_requests() {
+            let query = request.url().find('?').map_or_else(HashMap::new, |i| {
+                parse_query(&request.url()[i + 1..])
+            });
+            if !access.allows(&request, &query, &hosts) {
+                warn!("Refused a web request for {} without the token", request.url());
+                let res = request.respond(html_response(403, page("Forbidden", "")));
+                if let Err(e) = res {
+                    warn!("Could not answer a web request: {}", e);
+                }
+                continue;
+            }
+
+            let reply = self.web_reply(&mut request, &restore_dir, access);
+            let res = match reply {
+                Ok(Reply::Html(html, etag)) => {
+                    let mut response = html_response(200, html).with_header(access.cookie());
+                    if let Some(etag) = etag {
+                        response = response.with_header(response_header("ETag", &etag));
+                    }
+                    request.respond(response)
+                }
+                Ok(Reply::File {
+                       data,
+                       size,
+                       range,
+                       etag,
+                   }) => {
+                    let mut headers = vec![
+                        response_header("Content-Type", "application/octet-stream"),
+                        response_header("Accept-Ranges", "bytes"),
+                        response_header("ETag", &etag),
+                        access.cookie(),
+                    ];
+                    let (status, length) = match range {
+                        Some((first, last)) => {
+                            let value = format!("bytes {}-{}/{}", first, last, size);
+                            headers.push(response_header("Content-Range", &value));
+                            (206, last - first + 1)
+                        }
+                        None => (200, size),
+                    };
+                    request.respond(Response::new(
+                        StatusCode(status),
+                        headers,
+                        data,
+                        Some(length as usize),
+                        None,
+                    ))
+                }
+                Ok(Reply::NotModified(etag)) => {
+                    let etag = response_header("ETag", &etag);
+                    request.respond(Response::empty(StatusCode(304)).with_header(etag))
+                }
+                Ok(Reply::Unsatisfiable(size)) => {
+                    let range = response_header("Content-Range", &format!("bytes */{}", size));
+                    let html = page("Range not satisfiable", "");
+                    request.respond(html_response(416, html).with_header(range))
+                }
+                Ok(Reply::NotFound) => {
+                    request.respond(html_response(404, page("Not found", "")))
+                }
+                Ok(Reply::Forbidden) => {
+                    request.respond(html_response(403, page("Forbidden", "")))
+                }
+                Err(e) => {
+                    let body = format!("<p>{}</p>", escape(&e.to_string()));
+                    request.respond(html_response(500, page("Error", &body)))
+                }
+            };
+            if let Err(e) = res {
+                warn!("Could not answer a web request: {}", e);
+            }
+        }
+        Ok(())
+    }
+
+    fn web_reply(
+        &mut self,
+        request: &mut Request,
+        restore_dir: &Path,
+        access: &WebAccess,
+    ) -> Result<Reply, HatError> {
+        let url = request.url().to_string();
+        let (route, query) = match url.find('?') {
+            Some(i) => (&url[..i], parse_query(&url[i + 1..])),
+            None => (&url[..], HashMap::new()),
+        };
+        debug!("Web request: {} {}", request.method(), url);
+
+        let param = |name: &str| {
+            query.get(name).cloned().ok_or_else(|| {
+                HatError::from(format!("Missing parameter: {}", name))
+            })
+        };
+        match (request.method().clone(), route) {
+            (Method::Get, "/") => Ok(Reply::Html(self.web_families(), None)),
+            (Method::Get, "/snapshots") => {
+                Ok(Reply::Html(self.web_snapshots(&param("family")?), None))
+            }
+            (Method::Get, "/browse") => {
+                let family = param("family")?;
+                let id = param("id")?.parse().map_err(|_| "Invalid snapshot id")?;
+                let path = param("path").unwrap_or_else(|_| "/".to_string());
+                let after = query.get("after").map(|a| &a[..]);
+                let limit = match query.get("limit") {
+                    Some(limit) => cmp::max(1, limit.parse().map_err(|_| "Invalid limit")?),
+                    None => PAGE_ENTRIES,
+                };
+                // The page holds the token in its forms.
+                let (_, root) = self.complete_snapshot(&family, Some(id))?;
+                let limit_s = limit.to_string();
+                let etag = etag(&root, &[&path, after.unwrap_or(""), &limit_s, access.token()]);
+                if not_modified(request, &etag) {
+                    return Ok(Reply::NotModified(etag));
+                }
+                let html = self.web_browse(&family, id, &path, after, limit, access.token())?;
+                Ok(Reply::Html(html, Some(etag)))
+            }
+            (Method::Get, "/file") => {
+                let family = param("family")?;
+                let id = param("id")?.parse().map_err(|_| "Invalid snapshot id")?;
+                let path = param("path")?;
+                let (_, root) = self.complete_snapshot(&family, Some(id))?;
+                let etag = etag(&root, &[&path]);
+                if not_modified(request, &etag) {
+                    return Ok(Reply::NotModified(etag));
+                }
+                let (mut data, size) = self.web_file(&family, id, Path::new(&path))?;
+                // A range is only for the version of the file that `If-Range` names.
+                let range = match header(request, "If-Range") {
+                    Some(tag) if tag != etag => ByteRange::Whole,
+                    _ => byte_range(header(request, "Range"), size),
+                };
+                match range {
+                    ByteRange::Whole => {
+                        Ok(Reply::File {
+                            data: data,
+                            size: size,
+                            range: None,
+                            etag: etag,
+                        })
+                    }
+                    ByteRange::Part(first, last) => {
+                        // Chunks are only found in order, so the data before the range is read
+                        // too, but not sent.
+                        io::copy(&mut (&mut data).take(first), &mut io::sink())?;
+                        Ok(Reply::File {
+                            data: Box::new(data.take(last - first + 1)),
+                            size: size,
+                            range: Some((first, last)),
+                            etag: etag,
+                        })
+                    }
+                    ByteRange::Unsatisfiable => Ok(Reply::Unsatisfiable(size)),
+                }
+            }
+            (Method::Post, "/restore") => {
+                let mut body = String::new();
+                request.as_reader().read_to_string(&mut body)?;
+                let form = parse_query(&body);
+                // The cookie alone would also come along with a form posted by another site.
+                if !access.matches(form.get("token").map(|t| &t[..])) {
+                    return Ok(Reply::Forbidden);
+                }
+                let field = |name: &str| {
+                    form.get(name).cloned().ok_or_else(|| {
+                        HatError::from(format!("Missing field: {}", name))
+                    })
+                };
+                let id = field("id")?.parse().map_err(|_| "Invalid snapshot id")?;
+                let path = PathBuf::from(field("path")?);
+                let options = RestoreOptions {
+                    subpath: Some(path.clone()),
+                    ..Default::default()
+                };
+                self.checkout(field("family")?, Some(id), restore_dir.to_owned(), &options)?;
+                let restored = restore_dir.join(path.strip_prefix("/").unwrap_or(&path));
+                let body = format!("<p>Restored to {}</p>", escape(&restored.to_string_lossy()));
+                Ok(Reply::Html(page("Restored", &body), None))
+            }
+            (Method::Get, "/stats") => self.web_stats().map(|html| Reply::Html(html, None)),
+            _ => Ok(Reply::NotFound),
+        }
+    }
+
+    fn web_families(&mut self) -> String {
+        let mut latest = BTreeMap::new();
+        for s in self.list_snapshots() {
+            latest.insert(s.family.clone(), s);
+        }
+        let mut body = String::from(
+            "<table><tr><th>Family</th><th>Latest snapshot</th><th>Files</th></tr>",
+        );
+        for (family, s) in latest {
+            body.push_str(&format!(
+                "<tr><td><a href=\"/snapshots?family={}\">{}</a></td><td>{}</td><td>{}</td></tr>",
+                encode(&family),
+                escape(&family),
+                s.created.format("%Y-%m-%d %H:%M"),
+                s.contents.map_or("-".to_string(), |c| c.files.to_string())
+            ));
+        }
+        body.push_str("</table>");
+        page("Families", &body)
+    }
+
+    fn web_snapshots(&mut self, family: &str) -> String {
+        let mut body = String::from(
+            "<table><tr><th>Snapshot</th><th>Created</th><th>Files</th><th>Size</th>\
+             <th>Labels</th></tr>",
+        );
+        for s in self.list_snapshots().into_iter().rev().filter(|s| s.family == family) {
+            body.push_str(&format!(
+                "<tr><td><a href=\"/browse?family={}&amp;id={}&amp;path=/\">{}</a></td>\
+                 <td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+                encode(family),
+                s.snapshot_id,
+                s.snapshot_id,
+                s.created.format("%Y-%m-%d %H:%M"),
+                s.contents.map_or("-".to_string(), |c| c.files.to_string()),
+                s.contents.map_or("-".to_string(), |c| c.bytes.to_string()),
+                escape(&s.labels.join(", "))
+            ));
+        }
+        body.push_str("</table>");
+        page(family, &body)
+    }
+
+    fn web_browse(
+        &mut self,
+        family: &str,
+        id: u64,
+        dir: &str,
+        after: Option<&str>,
+        limit: usize,
+        token: &str,
+    ) -> Result<String, HatError> {
+        let entries = self.iter_dir(family, Some(id), Path::new(dir))?;
+        let (entries, more) = page_of(entries, after, limit)?;
+        let next = match entries.last() {
+            Some(last) if more => {
+                Some(format!(
+                    "<p><a href=\"/browse?family={}&amp;id={}&amp;path={}&amp;after={}&amp;\
+                     limit={}\">Next page</a></p>",
+                    encode(family),
+                    id,
+                    encode(dir),
+                    encode(&last.path.to_string_lossy()),
+                    limit
+                ))
+            }
+            _ => None,
+        };
+        let restore_form = |path: &str| {
+            format!(
+                "<form method=\"post\" action=\"/restore\" style=\"margin:0\">\
+                 <input type=\"hidden\" name=\"family\" value=\"{}\">\
+                 <input type=\"hidden\" name=\"id\" value=\"{}\">\
+                 <input type=\"hidden\" name=\"path\" value=\"{}\">\
+                 <input type=\"hidden\" name=\"token\" value=\"{}\">\
+                 <input type=\"submit\" value=\"Restore\"></form>",
+                escape(family),
+                id,
+                escape(path),
+                escape(token)
+            )
+        };
+
+        let mut body = format!(
+            "<p>Snapshot {} of <a href=\"/snapshots?family={}\">{}</a>: {}</p>{}",
+            id,
+            encode(family),
+            escape(family),
+            escape(dir),
+            if dir == "/" { String::new() } else { restore_form(dir) }
+        );
+        body.push_str("<table><tr><th>Name</th><th>Size</th><th>Modified</th><th></th></tr>");
+        for e in entries {
+            let name = e.path.to_string_lossy().into_owned();
+            let path = Path::new(dir).join(&e.path).to_string_lossy().into_owned();
+            let link = match e.kind {
+                EntryKind::Dir => {
+                    format!(
+                        "<a href=\"/browse?family={}&amp;id={}&amp;path={}\">{}/</a>",
+                        encode(family),
+                        id,
+                        encode(&path),
+                        escape(&name)
+                    )
+                }
+                EntryKind::File => {
+                    format!(
+                        "<a href=\"/file?family={}&amp;id={}&amp;path={}\">{}</a>",
+                        encode(family),
+                        id,
+                        encode(&path),
+                        escape(&name)
+                    )
+                }
+                EntryKind::Symlink(ref target) => {
+                    format!("{} -&gt; {}", escape(&name), escape(&target.to_string_lossy()))
+                }
+            };
+            let modified = e.modified.map_or("-".to_string(), |secs| {
+                let time = chrono::NaiveDateTime::from_timestamp(secs as i64, 0);
+                time.format("%Y-%m-%d %H:%M").to_string()
+            });
+            body.push_str(&format!(
+                "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+                link,
+                e.size.map_or("-".to_string(), |s| s.to_string()),
+                modified,
+                restore_form(&path)
+            ));
+        }
+        body.push_str("</table>");
+        body.push_str(&next.unwrap_or_default());
+        Ok(page("Browse", &body))
+    }
+
+    /// A reader of the contents of a file in a snapshot, with its size.
+    fn web_file(
+        &mut self,
+        family_name: &str,
+        id: u64,
+        path: &Path,
+    ) -> Result<(Box<Read>, u64), HatError> {
+        let (_, dir_ref) = self.complete_snapshot(family_name, Some(id))?;
+        let family = self.open_family(family_name.to_string())?;
+        let (_, entry, content) = self.lookup_path(&family, dir_ref, path)?;
+        let info = entry.info;
+        match content {
+            Content::Inline(bytes) => {
+                let size = info.byte_length.unwrap_or(bytes.len() as u64);
+                let extents = info.sparse_map.clone().unwrap_or_else(|| vec![(0, size)]);
+                let data = io::Cursor::new(bytes);
+                Ok((Box::new(HoleFiller::new(data, extents, size)), size))
+            }
+            Content::Data(href) => {
+                let size = info.byte_length.unwrap_or(0);
+                let extents = info.sparse_map.clone().unwrap_or_else(|| vec![(0, size)]);
+                let leafs = hash::tree::LeafIterator::new(self.hash_backend(), href)?;
+                let data = DataReader::new(leafs, util::data_length(&extents));
+                Ok((Box::new(HoleFiller::new(data, extents, size)), size))
+            }
+            Content::Dir(_) |
+            Content::Link(_) => {
+                Err(From::from(format!("{} is not a regular file", path.display())))
+            }
+        }
+    }
+
+    fn web_stats(&mut self) -> Result<String, HatError> {
+        let stats = self.repository_stats()?;
+        let ratio = |r: Option<f64>| r.map_or("-".to_string(), |r| format!("{:.2}", r));
+        let mut body = format!(
+            "<table><tr><td>Blobs</td><td>{} ({} bytes)</td></tr>\
+             <tr><td>Chunks</td><td>{} ({} bytes)</td></tr>\
+             <tr><td>Dedup ratio</td><td>{}</td></tr></table>",
+            stats.blobs,
+            stats.blob_bytes,
+            stats.chunks,
+            stats.chunk_bytes,
+            ratio(stats.dedup_ratio())
+        );
+        body.push_str(
+            "<h2>Families</h2><table><tr><th>Family</th><th>Snapshots</th><th>Latest size</th>\
+             <th>Read</th><th>New</th></tr>",
+        );
+        for f in &stats.families {
+            body.push_str(&format!(
+                "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+                escape(&f.family),
+                f.snapshots,
+                f.latest_bytes,
+                f.bytes_read,
+                f.bytes_new
+            ));
+        }
+        body.push_str("</table>");
+        Ok(page("Statistics", &body))
+    }
+}
@@ -43,7 +43,7 @@ fn insert_1_key_x_128000_zeros(bench: &mut Bencher) {
         };
 
         ks_p.send_reply(Msg::Insert(entry.key_entry.clone(),
-                                    Some(Box::new(move |()| Some(entry)))))
+                                    Some(Box::new(move |_: u64| Some(entry.clone())))))
             .unwrap();
     });
 
@@ -79,7 +79,7 @@ fn insert_1_key_x_128000_unique(bench: &mut Bencher) {
         };
 
         ks_p.send_reply(Msg::Insert(entry.key_entry.clone(),
-                                    Some(Box::new(move |()| Some(entry)))))
+                                    Some(Box::new(move |_: u64| Some(entry.clone())))))
             .unwrap();
     });
 
@@ -101,7 +101,7 @@ fn insert_1_key_x_16_x_128000_zeros(bench: &mut Bencher) {
             key_entry: Entry::new(None, vec![1u8, 2, 3].to_vec(), Data::FilePlaceholder, None),
         };
         ks_p.send_reply(Msg::Insert(entry.key_entry.clone(),
-                                    Some(Box::new(move |()| Some(entry)))))
+                                    Some(Box::new(move |_: u64| Some(entry.clone())))))
             .unwrap();
 
         match ks_p.send_reply(Msg::Flush).unwrap() {
@@ -144,7 +144,7 @@ fn insert_1_key_x_16_x_128000_unique(bench: &mut Bencher) {
         };
 
         ks_p.send_reply(Msg::Insert(entry.key_entry.clone(),
-                                    Some(Box::new(move |()| Some(entry)))))
+                                    Some(Box::new(move |_: u64| Some(entry.clone())))))
             .unwrap();
 
         match ks_p.send_reply(Msg::Flush).unwrap() {
@@ -195,11 +195,19 @@ fn insert_1_key_updated_empty(bench: &mut Bencher) {
                     modified_ts_secs: Some(i),
                     accessed_ts_secs: Some(i),
                     group_id: None,
+                    group_name: None,
                     user_id: None,
+                    user_name: None,
                     permissions: None,
                     byte_length: None,
                     hat_snapshot_ts: 0,
+                    hardlink_id: None,
+                    xattrs: vec![],
+                    acl: None,
+                    default_acl: None,
+                    sparse_map: None,
                 },
+                stamp: None,
             },
         };
         ks_p.send_reply(Msg::Insert(entry.key_entry.clone(), None))
@@ -19,14 +19,21 @@ use crypto;
 use errors::RetryError;
 use hash;
 use hash::tree::HashTreeBackend;
+use hex::ToHex;
 use key::MsgError;
 use key;
 use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use util::Progress;
 
 pub struct HashStoreBackend<B> {
     hash_index: Arc<hash::HashIndex>,
     blob_store: Arc<blob::BlobStore<B>>,
     keys: Arc<crypto::keys::Keeper>,
+    record_verified: bool,
+    fail_on_mismatch: bool,
+    new_bytes: Option<Arc<AtomicUsize>>,
+    progress: Option<Arc<Progress>>,
 }
 impl<B> Clone for HashStoreBackend<B> {
     fn clone(&self) -> HashStoreBackend<B> {
@@ -34,6 +41,10 @@ impl<B> Clone for HashStoreBackend<B> {
             hash_index: self.hash_index.clone(),
             blob_store: self.blob_store.clone(),
             keys: self.keys.clone(),
+            record_verified: self.record_verified,
+            fail_on_mismatch: self.fail_on_mismatch,
+            new_bytes: self.new_bytes.clone(),
+            progress: self.progress.clone(),
         }
     }
 }
@@ -48,8 +59,36 @@ impl<B: StoreBackend> HashStoreBackend<B> {
             hash_index: hash_index,
             blob_store: blob_store,
             keys: keys,
+            record_verified: false,
+            fail_on_mismatch: false,
+            new_bytes: None,
+            progress: None,
         }
     }
+
+    /// Add the size of every file chunk that was not already stored to `counter`.
+    pub fn counting_new_bytes(mut self, counter: Arc<AtomicUsize>) -> HashStoreBackend<B> {
+        self.new_bytes = Some(counter);
+        self
+    }
+
+    /// Report the size of every file chunk that was not already stored to `progress`.
+    pub fn reporting_uploads(mut self, progress: Arc<Progress>) -> HashStoreBackend<B> {
+        self.progress = Some(progress);
+        self
+    }
+
+    /// Record in the hash index when fetched chunks are found to match their hash.
+    pub fn recording_verified(mut self) -> HashStoreBackend<B> {
+        self.record_verified = true;
+        self
+    }
+
+    /// Report fetched chunks that do not match their hash as errors rather than as missing.
+    pub fn failing_on_mismatch(mut self) -> HashStoreBackend<B> {
+        self.fail_on_mismatch = true;
+        self
+    }
 }
 
 impl<B: StoreBackend> HashTreeBackend for HashStoreBackend<B> {
@@ -58,19 +97,31 @@ impl<B: StoreBackend> HashTreeBackend for HashStoreBackend<B> {
     fn fetch_chunk(&self, href: &hash::tree::HashRef) -> Result<Option<Vec<u8>>, MsgError> {
         assert!(!href.hash.bytes.is_empty());
 
-        Ok(self.blob_store.retrieve(&href)?.and_then(|data| {
-            let actual_hash = hash::Hash::new(&self.keys, href.node, href.leaf, &data[..]);
-            if href.hash == actual_hash {
-                Some(data)
+        let data = match self.blob_store.retrieve(&href)? {
+            Some(data) => data,
+            None => return Ok(None),
+        };
+        let actual_hash = hash::Hash::new(&self.keys, href.node, href.leaf, &data[..]);
+        if href.hash == actual_hash {
+            if self.record_verified {
+                self.hash_index.mark_verified(&href.hash);
+            }
+            Ok(Some(data))
+        } else {
+            error!(
+                "Data hash does not match expectation: {:?} instead of {:?}",
+                actual_hash,
+                href.hash
+            );
+            if self.fail_on_mismatch {
+                Err(From::from(format!(
+                    "Chunk {} does not match its hash",
+                    href.hash.bytes.to_hex()
+                )))
             } else {
-                error!(
-                    "Data hash does not match expectation: {:?} instead of {:?}",
-                    actual_hash,
-                    href.hash
-                );
-                None
+                Ok(None)
             }
-        }))
+        }
     }
 
     fn fetch_persistent_ref(&self, hash: &hash::Hash) -> Option<blob::ChunkRef> {
@@ -117,6 +168,12 @@ impl<B: StoreBackend> HashTreeBackend for HashStoreBackend<B> {
                     chunk.len()
                 );
 
+                if leaf == blob::LeafType::FileChunk {
+                    if let Some(ref progress) = self.progress {
+                        progress.deduped(chunk.len() as u64);
+                    }
+                }
+
                 // Someone came before us: piggyback on their result.
                 let pref = self.fetch_persistent_ref(&hash_entry.hash).expect(
                     "Could not find persistent ref for known hash",
@@ -142,17 +199,34 @@ impl<B: StoreBackend> HashTreeBackend for HashStoreBackend<B> {
                 );
 
                 // We came first: this data-chunk is ours to process.
+                if leaf == blob::LeafType::FileChunk {
+                    if let Some(ref counter) = self.new_bytes {
+                        counter.fetch_add(chunk.len(), Ordering::SeqCst);
+                    }
+                    if let Some(ref progress) = self.progress {
+                        progress.uploaded(chunk.len() as u64);
+                    }
+                }
                 let local_hash_index = self.hash_index.clone();
 
                 let m = Arc::new(Mutex::new(()));
                 let guard = m.lock().unwrap();
 
                 let local_m = m.clone();
+                let stored = match self.progress {
+                    Some(ref progress) if leaf == blob::LeafType::FileChunk => {
+                        Some((progress.clone(), chunk.len() as u64))
+                    }
+                    _ => None,
+                };
                 let callback = Box::new(move |()| {
                     // The callback has to run *after* we called update_reserved in the outer body.
                     let guard = local_m.lock().unwrap();
                     local_hash_index.commit(id, None);
                     drop(guard);
+                    if let Some((progress, bytes)) = stored {
+                        progress.stored(bytes);
+                    }
                 });
 
                 let href = self.blob_store.store(
@@ -15,15 +15,18 @@
 //! Local state for keys in the snapshot in progress (the "index").
 
 
-use std::str;
+use std::ffi::OsString;
 use std::fs;
+use std::io::{self, Read};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
 use std::os::unix::fs::PermissionsExt;
 
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use chrono;
 use diesel;
 use diesel::prelude::*;
 use diesel::connection::TransactionManager;
-use diesel::sqlite::SqliteConnection;
+use db;
 use errors::DieselError;
 use hash;
 use capnp;
@@ -34,18 +37,59 @@ use std::sync::{Mutex, MutexGuard};
 use super::schema;
 use time::Duration;
 use std::path::{Path, PathBuf};
-use util::{InfoWriter, PeriodicTimer};
+use util::{self, PeriodicTimer};
 use tags::Tag;
 use root_capnp;
+use xattr;
 
 #[derive(Clone, Eq, PartialEq, Debug)]
 pub enum Data {
     FilePlaceholder,
     FileHash(Vec<u8>),
+    /// A small file whose contents are kept in the index and its directory listing. The
+    /// contents are sealed while in the index; see `Store::unseal_inline`.
+    FileInline(Vec<u8>),
     DirPlaceholder,
     Symlink(PathBuf),
 }
 
+/// The on-disk state of a file, used to recognize files that have not changed since they were
+/// last read without reading them again.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct FileStamp {
+    pub path: Vec<u8>,
+    pub inode: u64,
+    pub size: u64,
+    pub mtime_ns: i64,
+    pub ctime_ns: i64,
+}
+
+impl FileStamp {
+    pub fn new(path: &Path, meta: &fs::Metadata) -> FileStamp {
+        use std::os::linux::fs::MetadataExt;
+
+        FileStamp {
+            path: path.as_os_str().as_bytes().to_vec(),
+            inode: meta.st_ino(),
+            size: meta.st_size(),
+            mtime_ns: meta.st_mtime() * 1_000_000_000 + meta.st_mtime_nsec(),
+            ctime_ns: meta.st_ctime() * 1_000_000_000 + meta.st_ctime_nsec(),
+        }
+    }
+}
+
+/// Where hashing of a file stopped, so that a file that has only been appended to since can be
+/// hashed by reading just the new data.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct AppendState {
+    /// Bytes of the file covered by `tree`; always a whole number of chunks.
+    pub offset: u64,
+    /// BLAKE3 hash of the chunk just before `offset`, to check that the file was only appended.
+    pub tail_hash: Vec<u8>,
+    /// State of the file's hash tree writer at `offset`.
+    pub tree: Vec<u8>,
+}
+
 #[derive(Clone, Debug)]
 pub struct Entry {
     pub node_id: Option<u64>,
@@ -53,6 +97,9 @@ pub struct Entry {
 
     pub data: Data,
     pub info: Info,
+
+    /// Set for files read from disk; not stored in the index.
+    pub stamp: Option<FileStamp>,
 }
 
 #[derive(Clone, Debug, Eq, PartialEq)]
@@ -66,9 +113,27 @@ pub struct Info {
     pub permissions: Option<fs::Permissions>,
     pub user_id: Option<u64>,
     pub group_id: Option<u64>,
+    /// Names of the owning user and group, if they had any when the file was read.
+    pub user_name: Option<String>,
+    pub group_name: Option<String>,
 
     pub byte_length: Option<u64>,
     pub hat_snapshot_ts: i64,
+
+    /// Shared by the files of a snapshot that are hard links to the same inode, so that the
+    /// links can be recreated on restore. Missing for files with a single link.
+    pub hardlink_id: Option<u64>,
+
+    /// Extended attributes as (name, value), sorted by name. ACLs are kept apart.
+    pub xattrs: Vec<(Vec<u8>, Vec<u8>)>,
+
+    /// The access and default POSIX ACLs, in the portable text form of `util::acl_from_xattr`.
+    pub acl: Option<String>,
+    pub default_acl: Option<String>,
+
+    /// The data extents of a sparse file as (offset, length). The file's content is then only
+    /// the data of these extents; the rest of its `byte_length` is holes.
+    pub sparse_map: Option<Vec<(u64, u64)>>,
 }
 
 impl Entry {
@@ -83,11 +148,28 @@ impl Entry {
             parent_id: parent,
             data: data,
             info: Info::new(name, meta),
+            stamp: None,
         }
     }
 
+    /// Remember the on-disk state of the file this entry was read from.
+    pub fn with_stamp(mut self, stamp: FileStamp) -> Entry {
+        self.stamp = Some(stamp);
+        self
+    }
+
     pub fn data_looks_unchanged(&self, them: &Entry) -> bool {
-        self.info.modified_ts_secs.is_some() &&
+        if let Data::Symlink(_) = self.data {
+            // A link can be pointed elsewhere within the same second; its target is cheap to
+            // compare.
+            if self.data != them.data {
+                return false;
+            }
+        }
+        // Linking a file does not touch its modification time.
+        self.info.modified_ts_secs.is_some() && self.info.hardlink_id == them.info.hardlink_id &&
+            (&self.info.xattrs, &self.info.acl, &self.info.default_acl) ==
+                (&them.info.xattrs, &them.info.acl, &them.info.default_acl) &&
             ((self.parent_id, &self.info.name, self.info.modified_ts_secs) ==
                  (them.parent_id, &them.info.name, them.info.modified_ts_secs))
     }
@@ -120,21 +202,73 @@ impl Info {
 
             user_id: meta.map(|m| m.st_uid() as u64),
             group_id: meta.map(|m| m.st_gid() as u64),
+            user_name: meta.and_then(|m| util::user_name(m.st_uid() as u64)),
+            group_name: meta.and_then(|m| util::group_name(m.st_gid() as u64)),
 
             byte_length: meta.map(|m| m.len()),
             hat_snapshot_ts: chrono::Utc::now().timestamp(),
+
+            hardlink_id: meta.and_then(|m| if m.is_file() && m.st_nlink() > 1 {
+                // Inode numbers are only unique per device.
+                Some(m.st_ino() ^ m.st_dev().wrapping_mul(0x9e37_79b9_7f4a_7c15))
+            } else {
+                None
+            }),
+
+            xattrs: vec![],
+            acl: None,
+            default_acl: None,
+            sparse_map: None,
+        }
+    }
+
+    /// Read the extended attributes and ACLs of the file at `path`, without following links.
+    pub fn read_xattrs(&mut self, path: &Path) -> io::Result<()> {
+        let mut xattrs = vec![];
+        for name in xattr::list(path)? {
+            // The attribute may have been removed since listing.
+            let value = match xattr::get(path, &name)? {
+                Some(value) => value,
+                None => continue,
+            };
+            let acl = if name.to_str() == Some(util::ACL_ACCESS_XATTR) {
+                &mut self.acl
+            } else if name.to_str() == Some(util::ACL_DEFAULT_XATTR) {
+                &mut self.default_acl
+            } else {
+                xattrs.push((name.as_bytes().to_vec(), value));
+                continue;
+            };
+            match util::acl_from_xattr(&value) {
+                Ok(text) => *acl = Some(text),
+                Err(e) => {
+                    warn!("Keeping unreadable ACL of {:?} as is: {}", path, e);
+                    xattrs.push((name.as_bytes().to_vec(), value));
+                }
+            }
         }
+        xattrs.sort();
+        self.xattrs = xattrs;
+        Ok(())
     }
 
     pub fn read(msg: root_capnp::file_info::Reader) -> Result<Info, capnp::Error> {
         fn none_if_zero(x: u64) -> Option<u64> {
             if x == 0 { None } else { Some(x) }
         }
+        fn name(has: bool, text: capnp::Result<&str>) -> capnp::Result<Option<String>> {
+            if has { text.map(|s| Some(s.to_owned())) } else { Ok(None) }
+        }
         let owner = match msg.get_owner().which()? {
             root_capnp::file_info::owner::None(()) => None,
             root_capnp::file_info::owner::UserGroup(res) => {
                 let ug = res?;
-                Some((ug.get_user_id(), ug.get_group_id()))
+                Some((
+                    ug.get_user_id(),
+                    ug.get_group_id(),
+                    name(ug.has_user_name(), ug.get_user_name())?,
+                    name(ug.has_group_name(), ug.get_group_name())?,
+                ))
             }
         };
         Ok(Info {
@@ -147,12 +281,47 @@ impl Info {
                 root_capnp::file_info::permissions::Mode(m) => Some(fs::Permissions::from_mode(m)),
             },
 
-            user_id: owner.as_ref().map(|&(uid, _)| uid),
-            group_id: owner.as_ref().map(|&(_, gid)| gid),
+            user_id: owner.as_ref().map(|o| o.0),
+            group_id: owner.as_ref().map(|o| o.1),
+            user_name: owner.as_ref().and_then(|o| o.2.clone()),
+            group_name: owner.as_ref().and_then(|o| o.3.clone()),
 
             byte_length: Some(msg.get_byte_length()),
 
             hat_snapshot_ts: msg.get_utc_timestamp(),
+
+            hardlink_id: none_if_zero(msg.get_hardlink_id()),
+
+            acl: if msg.has_acl() {
+                Some(msg.get_acl()?.to_owned())
+            } else {
+                None
+            },
+            default_acl: if msg.has_default_acl() {
+                Some(msg.get_default_acl()?.to_owned())
+            } else {
+                None
+            },
+
+            sparse_map: if msg.has_sparse_map() {
+                let mut extents = vec![];
+                for e in msg.get_sparse_map()?.iter() {
+                    extents.push((e.get_offset(), e.get_length()));
+                }
+                Some(extents)
+            } else {
+                None
+            },
+
+            xattrs: if msg.has_xattrs() {
+                let mut xattrs = vec![];
+                for x in msg.get_xattrs()?.iter() {
+                    xattrs.push((x.get_name()?.to_vec(), x.get_value()?.to_vec()));
+                }
+                xattrs
+            } else {
+                vec![]
+            },
         })
     }
     pub fn populate_msg(&self, mut msg: root_capnp::file_info::Builder) {
@@ -174,6 +343,12 @@ impl Info {
                 let mut ug = msg.borrow().get_owner().init_user_group();
                 ug.set_user_id(uid);
                 ug.set_group_id(gid);
+                if let Some(ref name) = self.user_name {
+                    ug.set_user_name(name);
+                }
+                if let Some(ref name) = self.group_name {
+                    ug.set_group_name(name);
+                }
             }
             _ => {
                 msg.borrow().get_owner().set_none(());
@@ -186,23 +361,101 @@ impl Info {
         }
 
         msg.borrow().set_utc_timestamp(self.hat_snapshot_ts);
+        msg.borrow().set_hardlink_id(self.hardlink_id.unwrap_or(0));
+
+        if !self.xattrs.is_empty() {
+            let mut list = msg.borrow().init_xattrs(self.xattrs.len() as u32);
+            for (i, &(ref name, ref value)) in self.xattrs.iter().enumerate() {
+                let mut x = list.borrow().get(i as u32);
+                x.set_name(name);
+                x.set_value(value);
+            }
+        }
+        if let Some(ref acl) = self.acl {
+            msg.borrow().set_acl(acl);
+        }
+        if let Some(ref acl) = self.default_acl {
+            msg.borrow().set_default_acl(acl);
+        }
+        if let Some(ref extents) = self.sparse_map {
+            let mut list = msg.borrow().init_sparse_map(extents.len() as u32);
+            for (i, &(offset, length)) in extents.iter().enumerate() {
+                let mut e = list.borrow().get(i as u32);
+                e.set_offset(offset);
+                e.set_length(length);
+            }
+        }
+    }
+}
+
+/// Extended attributes as kept in the index: each name and value prefixed by its length.
+fn encode_xattrs(xattrs: &[(Vec<u8>, Vec<u8>)]) -> Vec<u8> {
+    let mut bytes = vec![];
+    for &(ref name, ref value) in xattrs {
+        bytes.write_u32::<LittleEndian>(name.len() as u32).unwrap();
+        bytes.extend_from_slice(name);
+        bytes.write_u32::<LittleEndian>(value.len() as u32).unwrap();
+        bytes.extend_from_slice(value);
+    }
+    bytes
+}
+
+fn decode_xattrs(mut bytes: &[u8]) -> Vec<(Vec<u8>, Vec<u8>)> {
+    fn field(bytes: &mut &[u8]) -> io::Result<Vec<u8>> {
+        let len = bytes.read_u32::<LittleEndian>()? as usize;
+        let mut field = vec![0; len];
+        bytes.read_exact(&mut field)?;
+        Ok(field)
+    }
+    let mut xattrs = vec![];
+    while !bytes.is_empty() {
+        let name = field(&mut bytes).expect("Malformed extended attributes in index");
+        let value = field(&mut bytes).expect("Malformed extended attributes in index");
+        xattrs.push((name, value));
     }
+    xattrs
+}
+
+/// A sparse map as kept in the index: each offset and length as a little-endian u64.
+fn encode_sparse_map(extents: &[(u64, u64)]) -> Vec<u8> {
+    let mut bytes = vec![];
+    for &(offset, length) in extents {
+        bytes.write_u64::<LittleEndian>(offset).unwrap();
+        bytes.write_u64::<LittleEndian>(length).unwrap();
+    }
+    bytes
+}
+
+fn decode_sparse_map(mut bytes: &[u8]) -> Vec<(u64, u64)> {
+    let mut extents = vec![];
+    while !bytes.is_empty() {
+        let offset = bytes.read_u64::<LittleEndian>().expect("Malformed sparse map in index");
+        let length = bytes.read_u64::<LittleEndian>().expect("Malformed sparse map in index");
+        extents.push((offset, length));
+    }
+    extents
 }
 
 pub struct KeyIndex(Mutex<InternalKeyIndex>);
 
 pub struct InternalKeyIndex {
-    conn: SqliteConnection,
+    conn: db::Connection,
+    url: String,
     flush_timer: PeriodicTimer,
 }
 
 
 impl InternalKeyIndex {
-    fn new(migrations_dir: &Path, path: &str) -> Result<InternalKeyIndex, DieselError> {
-        let conn = SqliteConnection::establish(path)?;
+    fn new(
+        migrations_dir: &Path,
+        path: &str,
+        key: Option<&[u8]>,
+    ) -> Result<InternalKeyIndex, DieselError> {
+        let conn = db::establish(path, key)?;
 
         let ki = InternalKeyIndex {
             conn: conn,
+            url: path.to_string(),
             flush_timer: PeriodicTimer::new(Duration::seconds(5)),
         };
 
@@ -212,12 +465,14 @@ impl InternalKeyIndex {
                 .execute(&ki.conn)?;
         }
 
-        diesel::migrations::run_pending_migrations_in_directory(
-            &ki.conn,
-            &migrations_dir,
-            &mut InfoWriter,
+        // See `db::Index`: fewer fsyncs for our periodic commits. What a crash undoes here only
+        // costs reading the files again.
+        ki.conn.execute(
+            "PRAGMA journal_mode = WAL; PRAGMA synchronous = NORMAL;",
         )?;
 
+        db::upgrade_schema(&ki.conn, path, migrations_dir)?;
+
         {
             let tm = ki.conn.transaction_manager();
             tm.begin_transaction(&ki.conn)?;
@@ -258,6 +513,18 @@ impl InternalKeyIndex {
         Ok(())
     }
 
+    fn size(&mut self) -> Result<u64, DieselError> {
+        db::index_size(&self.conn, &self.url)
+    }
+
+    fn maintain(&mut self) -> Result<db::IndexReport, DieselError> {
+        let tm = self.conn.transaction_manager();
+        tm.commit_transaction(&self.conn)?;
+        let report = db::maintain(&self.conn, &self.url);
+        tm.begin_transaction(&self.conn)?;
+        report
+    }
+
     /// Insert an entry in the key index.
     /// Returns `Id` with the new entry ID.
     fn insert(
@@ -277,15 +544,23 @@ impl InternalKeyIndex {
         }
 
         {
-            let link_path = match &entry.data {
+            let (link_path, inline) = match &entry.data {
                 &Data::DirPlaceholder |
-                &Data::FilePlaceholder => None,
-                &Data::Symlink(ref path) => path.to_str(),
+                &Data::FilePlaceholder => (None, None),
+                &Data::FileInline(ref bytes) => (None, Some(&bytes[..])),
+                &Data::Symlink(ref path) => (Some(path.as_os_str().as_bytes()), None),
                 &Data::FileHash(_) => unreachable!("Unexpected FileHash"),
             };
             assert!(!(link_path.is_some() && hash_ref_opt.is_some()));
+            assert!(!(inline.is_some() && hash_ref_opt.is_some()));
 
             let hash_ref_bytes = hash_ref_opt.map(|r| r.as_bytes());
+            let xattrs_bytes = if entry.info.xattrs.is_empty() {
+                None
+            } else {
+                Some(encode_xattrs(&entry.info.xattrs))
+            };
+            let sparse_map_bytes = entry.info.sparse_map.as_ref().map(|m| encode_sparse_map(m));
             let new = schema::NewKeyData {
                 node_id: entry.node_id.map(|i| i as i64),
                 committed: false,
@@ -296,9 +571,17 @@ impl InternalKeyIndex {
                 permissions: entry.info.permissions.as_ref().map(|p| p.mode() as i64),
                 group_id: entry.info.group_id.map(|u| u as i64),
                 user_id: entry.info.user_id.map(|u| u as i64),
-                symbolic_link_path: link_path.map(|s| s.as_bytes()),
+                user_name: entry.info.user_name.as_ref().map(|s| &s[..]),
+                group_name: entry.info.group_name.as_ref().map(|s| &s[..]),
+                symbolic_link_path: link_path,
                 hash: hash_ref_opt.map(|h| &h.hash.bytes[..]),
                 hash_ref: hash_ref_bytes.as_ref().map(|v| &v[..]),
+                inline_data: inline,
+                hardlink: entry.info.hardlink_id.map(|i| i as i64),
+                xattrs: xattrs_bytes.as_ref().map(|v| &v[..]),
+                acl: entry.info.acl.as_ref().map(|s| &s[..]),
+                default_acl: entry.info.default_acl.as_ref().map(|s| &s[..]),
+                sparse_map: sparse_map_bytes.as_ref().map(|v| &v[..]),
             };
 
             // Insert replaces when (node_id, committed) already exists.
@@ -345,9 +628,11 @@ impl InternalKeyIndex {
             Ok(Some(Entry {
                 node_id: node.node_id.map(|n| n as u64),
                 parent_id: node.parent_id.map(|p| p as u64),
-                data: data.hash.map(|h| Data::FileHash(h)).unwrap_or(
-                    Data::DirPlaceholder,
-                ),
+                data: match (data.hash, data.inline_data) {
+                    (Some(h), _) => Data::FileHash(h),
+                    (None, Some(bytes)) => Data::FileInline(bytes),
+                    (None, None) => Data::DirPlaceholder,
+                },
                 info: Info {
                     name: name_,
                     created_ts_secs: data.created.map(|i| i as u64),
@@ -358,9 +643,17 @@ impl InternalKeyIndex {
                     ),
                     user_id: data.user_id.map(|x| x as u64),
                     group_id: data.group_id.map(|x| x as u64),
+                    user_name: data.user_name,
+                    group_name: data.group_name,
                     byte_length: None,
                     hat_snapshot_ts: 0,
+                    hardlink_id: data.hardlink.map(|i| i as u64),
+                    xattrs: data.xattrs.as_ref().map_or(vec![], |b| decode_xattrs(b)),
+                    acl: data.acl,
+                    default_acl: data.default_acl,
+                    sparse_map: data.sparse_map.as_ref().map(|b| decode_sparse_map(b)),
                 },
+                stamp: None,
             }))
         } else {
             Ok(None)
@@ -403,9 +696,14 @@ impl InternalKeyIndex {
                             parent_id: node.parent_id.map(|i| i as u64),
                             data: match (data.hash.as_ref(), data.symbolic_link_path) {
                                 (Some(_), None) => Data::FilePlaceholder,
-                                (None, None) => Data::DirPlaceholder,
+                                (None, None) => {
+                                    match data.inline_data.take() {
+                                        Some(bytes) => Data::FileInline(bytes),
+                                        None => Data::DirPlaceholder,
+                                    }
+                                }
                                 (None, Some(path)) => {
-                                    Data::Symlink(PathBuf::from(str::from_utf8(&path[..]).unwrap()))
+                                    Data::Symlink(PathBuf::from(OsString::from_vec(path)))
                                 }
                                 (Some(_), Some(lp)) => {
                                     unreachable!(
@@ -424,9 +722,21 @@ impl InternalKeyIndex {
                                 }),
                                 user_id: data.user_id.map(|x| x as u64),
                                 group_id: data.group_id.map(|x| x as u64),
+                                user_name: data.user_name.take(),
+                                group_name: data.group_name.take(),
                                 byte_length: None,
                                 hat_snapshot_ts: 0,
+                                hardlink_id: data.hardlink.map(|i| i as u64),
+                                xattrs: data.xattrs
+                                    .as_ref()
+                                    .map_or(vec![], |b| decode_xattrs(b)),
+                                acl: data.acl.take(),
+                                default_acl: data.default_acl.take(),
+                                sparse_map: data.sparse_map
+                                    .as_ref()
+                                    .map(|b| decode_sparse_map(b)),
                             },
+                            stamp: None,
                         },
                         data.hash_ref.as_mut().map(|p| {
                             ::hash::tree::HashRef::from_bytes(&mut &p[..]).unwrap()
@@ -464,6 +774,11 @@ impl InternalKeyIndex {
     /// Delete children not marked reserved.
     /// This function applies recursively to child directories.
     fn cleanup_unused(&mut self, parent_opt: Option<u64>) -> Result<(), DieselError> {
+        self.cleanup_unused_below(parent_opt)?;
+        self.flush()
+    }
+
+    fn cleanup_unused_below(&mut self, parent_opt: Option<u64>) -> Result<(), DieselError> {
         use super::schema::key_tree::dsl::*;
         use super::schema::key_data::dsl::{tag, key_data};
 
@@ -484,31 +799,162 @@ impl InternalKeyIndex {
             }
         };
 
+        let mut unused = vec![];
         for (node_id_, tag_) in children {
             let id = node_id_.unwrap();
             if tag_ == Tag::Reserved as i64 {
-                self.cleanup_unused(Some(id as u64))?;
+                self.cleanup_unused_below(Some(id as u64))?;
             } else {
-                diesel::delete(key_tree.filter(node_id.eq(id))).execute(
-                    &self.conn,
-                )?;
+                unused.push(id);
             }
         }
 
-        self.flush()?;
+        // Delete in batches, staying below SQLite's limit on bound parameters.
+        for ids in unused.chunks(500) {
+            let ids: Vec<Option<i64>> = ids.iter().map(|i| Some(*i)).collect();
+            diesel::delete(key_tree.filter(node_id.eq_any(ids))).execute(
+                &self.conn,
+            )?;
+        }
 
         Ok(())
     }
+
+    /// Delete the entries and cached file hashes that refer to data for which `is_live` is
+    /// false, e.g. after garbage collection removed it. Returns the number of rows deleted.
+    fn prune_stale<F>(&mut self, is_live: F) -> Result<u64, DieselError>
+    where
+        F: Fn(&[u8]) -> bool,
+    {
+        let mut count = 0;
+        {
+            use super::schema::key_tree::dsl::{key_tree, node_id as tree_node_id};
+            use super::schema::key_data::dsl::*;
+
+            let rows = key_data
+                .filter(hash.is_not_null())
+                .select((node_id, hash))
+                .load::<(Option<i64>, Option<Vec<u8>>)>(&self.conn)?;
+            let mut stale: Vec<Option<i64>> = rows.into_iter()
+                .filter(|&(_, ref h)| h.as_ref().map_or(false, |h| !is_live(&h[..])))
+                .map(|(id, _)| id)
+                .collect();
+            stale.sort();
+            stale.dedup();
+            // Deleting the node also deletes its data, both committed and not.
+            for ids in stale.chunks(500) {
+                count += diesel::delete(key_tree.filter(tree_node_id.eq_any(ids.to_vec())))
+                    .execute(&self.conn)? as u64;
+            }
+        }
+        {
+            use super::schema::file_cache::dsl::*;
+
+            let rows = file_cache.select((path, hash)).load::<(Vec<u8>, Vec<u8>)>(&self.conn)?;
+            for (p, h) in rows {
+                if !is_live(&h[..]) {
+                    count += diesel::delete(file_cache.filter(path.eq(&p[..])))
+                        .execute(&self.conn)? as u64;
+                }
+            }
+        }
+        self.flush()?;
+
+        Ok(count)
+    }
+
+    /// Look up the top hash last recorded for a file with exactly this on-disk state.
+    fn file_cache_lookup(&mut self, stamp: &FileStamp) -> Result<Option<Vec<u8>>, DieselError> {
+        use super::schema::file_cache::dsl::*;
+
+        Ok(
+            file_cache
+                .filter(path.eq(&stamp.path[..]))
+                .filter(inode.eq(stamp.inode as i64))
+                .filter(size.eq(stamp.size as i64))
+                .filter(mtime_ns.eq(stamp.mtime_ns))
+                .filter(ctime_ns.eq(stamp.ctime_ns))
+                .select(hash)
+                .first::<Vec<u8>>(&self.conn)
+                .optional()?,
+        )
+    }
+
+    /// Record the top hash of a file, replacing what was known about its path.
+    fn file_cache_store(&mut self, stamp: &FileStamp, hash_: &[u8]) -> Result<(), DieselError> {
+        use super::schema::file_cache::dsl::*;
+
+        diesel::delete(file_cache.filter(path.eq(&stamp.path[..]))).execute(
+            &self.conn,
+        )?;
+        let new = schema::NewFileCache {
+            path: &stamp.path[..],
+            inode: stamp.inode as i64,
+            size: stamp.size as i64,
+            mtime_ns: stamp.mtime_ns,
+            ctime_ns: stamp.ctime_ns,
+            hash: hash_,
+        };
+        diesel::insert(&new).into(file_cache).execute(&self.conn)?;
+
+        self.maybe_flush()
+    }
+
+    fn append_state_lookup(
+        &mut self,
+        stamp: &FileStamp,
+    ) -> Result<Option<AppendState>, DieselError> {
+        use super::schema::append_cache::dsl::*;
+
+        let row = append_cache
+            .filter(path.eq(&stamp.path[..]))
+            .filter(inode.eq(stamp.inode as i64))
+            .first::<schema::AppendCache>(&self.conn)
+            .optional()?;
+        Ok(row.map(|r| {
+            AppendState {
+                offset: r.resume_offset as u64,
+                tail_hash: r.tail_hash,
+                tree: r.tree_state,
+            }
+        }))
+    }
+
+    fn append_state_store(
+        &mut self,
+        stamp: &FileStamp,
+        state: &AppendState,
+    ) -> Result<(), DieselError> {
+        use super::schema::append_cache::dsl::*;
+
+        diesel::delete(append_cache.filter(path.eq(&stamp.path[..]))).execute(
+            &self.conn,
+        )?;
+        let new = schema::NewAppendCache {
+            path: &stamp.path[..],
+            inode: stamp.inode as i64,
+            resume_offset: state.offset as i64,
+            tail_hash: &state.tail_hash[..],
+            tree_state: &state.tree[..],
+        };
+        diesel::insert(&new).into(append_cache).execute(&self.conn)?;
+
+        self.maybe_flush()
+    }
 }
 
 impl KeyIndex {
-    pub fn new(migration_dir: &Path, name: &str) -> Result<KeyIndex, DieselError> {
-        InternalKeyIndex::new(migration_dir, name).map(|index| KeyIndex(Mutex::new(index)))
+    pub fn new(
+        migration_dir: &Path,
+        name: &str,
+        key: Option<&[u8]>,
+    ) -> Result<KeyIndex, DieselError> {
+        InternalKeyIndex::new(migration_dir, name, key).map(|index| KeyIndex(Mutex::new(index)))
     }
 
     #[cfg(test)]
     pub fn new_for_testing() -> Result<KeyIndex, DieselError> {
-        KeyIndex::new(Path::new("migrations"), ":memory:")
+        KeyIndex::new(Path::new("migrations"), ":memory:", None)
     }
 
     fn lock(&self) -> MutexGuard<InternalKeyIndex> {
@@ -550,7 +996,48 @@ impl KeyIndex {
         self.lock().cleanup_unused(parent_opt)
     }
 
+    pub fn prune_stale<F>(&self, is_live: F) -> Result<u64, DieselError>
+    where
+        F: Fn(&[u8]) -> bool,
+    {
+        self.lock().prune_stale(is_live)
+    }
+
+    pub fn file_cache_lookup(&self, stamp: &FileStamp) -> Result<Option<Vec<u8>>, DieselError> {
+        self.lock().file_cache_lookup(stamp)
+    }
+
+    pub fn file_cache_store(&self, stamp: &FileStamp, hash: &[u8]) -> Result<(), DieselError> {
+        self.lock().file_cache_store(stamp, hash)
+    }
+
+    /// The saved hashing state of a file at the same path and inode, if any.
+    pub fn append_state_lookup(
+        &self,
+        stamp: &FileStamp,
+    ) -> Result<Option<AppendState>, DieselError> {
+        self.lock().append_state_lookup(stamp)
+    }
+
+    pub fn append_state_store(
+        &self,
+        stamp: &FileStamp,
+        state: &AppendState,
+    ) -> Result<(), DieselError> {
+        self.lock().append_state_store(stamp, state)
+    }
+
     pub fn flush(&self) -> Result<(), DieselError> {
         self.lock().flush()
     }
+
+    /// Bytes used by the index file.
+    pub fn size(&self) -> Result<u64, DieselError> {
+        self.lock().size()
+    }
+
+    /// Check, reindex and vacuum the index file.
+    pub fn maintain(&self) -> Result<db::IndexReport, DieselError> {
+        self.lock().maintain()
+    }
 }
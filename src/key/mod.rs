@@ -16,16 +16,22 @@
 
 
 use backend::StoreBackend;
+use blake3;
 use blob;
 use crypto;
-use errors::{DieselError, RetryError};
+use db;
+use errors::{CryptoError, DieselError, RetryError};
 use hash;
 use hash::tree::{LeafIterator, SimpleHashTreeWriter};
 use std::borrow::Cow;
-use std::io;
+use std::fs;
+use std::io::{self, Read};
+use std::path::Path;
+use std::str::FromStr;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
-use util::{FnBox, MsgHandler, Process};
+use util::{self, MsgHandler, Process, Progress, SilentProgress};
 
 mod schema;
 mod index;
@@ -37,7 +43,7 @@ mod tests;
 mod benchmarks;
 
 pub use self::hash_store_backend::HashStoreBackend;
-pub use self::index::{Data, Entry, Info, KeyIndex};
+pub use self::index::{AppendState, Data, Entry, FileStamp, Info, KeyIndex};
 
 
 error_type! {
@@ -79,12 +85,19 @@ impl<B: StoreBackend> HashTreeReaderInitializer<B> {
     }
 }
 
+// Files are hashed in chunks of this size.
+pub const MAX_CHUNK_LEN: usize = 128 * 1024;
+
+/// Opens the data of an inserted entry, positioned the given number of bytes in. It may be
+/// called more than once, e.g. to read a file from the start after resuming it failed.
+pub type Opener<IT> = Box<Fn(u64) -> Option<IT> + Send>;
+
 // Public structs
 pub enum Msg<IT> {
-    /// Insert a key into the index. If this key has associated data a "chunk-iterator creator"
-    /// can be passed along with it. If the data turns out to be unreadable, this iterator proc
-    /// can return `None`. Returns `Id` with the new entry ID.
-    Insert(Entry, Option<Box<FnBox<(), Option<IT>>>>),
+    /// Insert a key into the index. If this key has associated data an `Opener` can be passed
+    /// along with it. If the data turns out to be unreadable, the opener can return `None`.
+    /// Returns `Id` with the new entry ID.
+    Insert(Entry, Option<Opener<IT>>),
 
     /// List a "directory" (aka. a `level`) in the index.
     /// Returns `ListResult` with all the entries under the given parent.
@@ -101,16 +114,119 @@ pub enum Msg<IT> {
 
 pub enum Reply<B> {
     Id(u64),
+    /// The entry was left out of the snapshot because it exceeded a configured limit.
+    Skipped,
     ListResult(Vec<DirElem<B>>),
     Ok,
     FlushOk,
 }
 
+/// Guards against unexpectedly large commits.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Limits {
+    /// Files larger than this many bytes are not backed up.
+    pub max_file_size: Option<u64>,
+    /// Stop adding file data once a commit has stored this many bytes of new data. Data that is
+    /// already stored does not count, so a changed file whose chunks are all known still fits.
+    pub max_commit_bytes: Option<u64>,
+    /// Fail the commit instead of skipping files that exceed a limit.
+    pub abort: bool,
+}
+
+/// The chunks of a file, as counted by `Store::scan_chunks`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ChunkScan {
+    pub chunks: u64,
+    /// Chunks that are not stored yet, and their size.
+    pub new_chunks: u64,
+    pub new_bytes: u64,
+}
+
+/// How much file metadata a family keeps. Each level includes everything kept by the levels
+/// before it. Restores apply the metadata allowed by the family's level.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Ord, PartialOrd)]
+pub enum Fidelity {
+    /// File names and contents. Modification times are kept to detect changes, but not
+    /// restored.
+    Content,
+    /// Permission bits and modification times.
+    Permissions,
+    /// User and group ids.
+    Ownership,
+    /// Extended attributes and ACLs.
+    Extended,
+    /// Access and creation times.
+    Forensic,
+}
+
+impl Fidelity {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            Fidelity::Content => "content",
+            Fidelity::Permissions => "permissions",
+            Fidelity::Ownership => "ownership",
+            Fidelity::Extended => "extended",
+            Fidelity::Forensic => "forensic",
+        }
+    }
+
+    /// Drop the metadata that is not kept at this level.
+    pub fn filter(&self, info: &mut Info) {
+        if *self < Fidelity::Permissions {
+            info.permissions = None;
+        }
+        if *self < Fidelity::Ownership {
+            info.user_id = None;
+            info.group_id = None;
+            info.user_name = None;
+            info.group_name = None;
+        }
+        if *self < Fidelity::Extended {
+            info.xattrs.clear();
+            info.acl = None;
+            info.default_acl = None;
+        }
+        if *self < Fidelity::Forensic {
+            info.accessed_ts_secs = None;
+            info.created_ts_secs = None;
+        }
+    }
+}
+
+impl Default for Fidelity {
+    fn default() -> Fidelity {
+        Fidelity::Forensic
+    }
+}
+
+impl FromStr for Fidelity {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Fidelity, String> {
+        match s {
+            "content" => Ok(Fidelity::Content),
+            "permissions" => Ok(Fidelity::Permissions),
+            "ownership" => Ok(Fidelity::Ownership),
+            "extended" => Ok(Fidelity::Extended),
+            "forensic" => Ok(Fidelity::Forensic),
+            _ => Err(format!("Unknown fidelity level: {}", s)),
+        }
+    }
+}
+
 pub struct Store<B> {
     index: Arc<index::KeyIndex>,
     hash_index: Arc<hash::HashIndex>,
     blob_store: Arc<blob::BlobStore<B>>,
     keys: Arc<crypto::keys::Keeper>,
+    limits: Limits,
+    commit_bytes: Arc<AtomicUsize>,
+    new_bytes: Arc<AtomicUsize>,
+    inline_max: Option<usize>,
+    fidelity: Fidelity,
+    fanout: usize,
+    resume_appends: bool,
+    progress: Arc<Progress>,
 }
 impl<B> Clone for Store<B> {
     fn clone(&self) -> Store<B> {
@@ -119,6 +235,14 @@ impl<B> Clone for Store<B> {
             hash_index: self.hash_index.clone(),
             blob_store: self.blob_store.clone(),
             keys: self.keys.clone(),
+            limits: self.limits,
+            commit_bytes: self.commit_bytes.clone(),
+            new_bytes: self.new_bytes.clone(),
+            inline_max: self.inline_max,
+            fidelity: self.fidelity,
+            fanout: self.fanout,
+            resume_appends: self.resume_appends,
+            progress: self.progress.clone(),
         }
     }
 }
@@ -136,9 +260,104 @@ impl<B: StoreBackend> Store<B> {
             hash_index: hash_index,
             blob_store: blob_store,
             keys: keys,
+            limits: Limits::default(),
+            commit_bytes: Arc::new(AtomicUsize::new(0)),
+            new_bytes: Arc::new(AtomicUsize::new(0)),
+            inline_max: None,
+            fidelity: Fidelity::default(),
+            fanout: hash::tree::DEFAULT_FANOUT,
+            resume_appends: false,
+            progress: Arc::new(SilentProgress),
+        }
+    }
+
+    /// Enforce `limits` on inserted files. Stores sharing `commit_bytes` share the per-commit
+    /// byte budget.
+    pub fn with_limits(mut self, limits: Limits, commit_bytes: Arc<AtomicUsize>) -> Store<B> {
+        self.limits = limits;
+        self.commit_bytes = commit_bytes;
+        self
+    }
+
+    /// Keep files of at most `max` bytes inline in the index instead of storing them as chunks.
+    pub fn with_inline_max(mut self, max: Option<usize>) -> Store<B> {
+        self.inline_max = max;
+        self
+    }
+
+    /// Only keep the metadata of inserted entries that `fidelity` allows.
+    pub fn with_fidelity(mut self, fidelity: Fidelity) -> Store<B> {
+        self.fidelity = fidelity;
+        self
+    }
+
+    /// Write hash trees with `fanout` children per branch node.
+    pub fn with_fanout(mut self, fanout: usize) -> Store<B> {
+        self.fanout = fanout;
+        self
+    }
+
+    /// Take files that grew since they were last read, and still end their old data with the
+    /// same chunk, to have only been appended to, and hash just the new data. Only safe for
+    /// files that are never changed in place, such as logs: changes to the old data are missed.
+    pub fn with_resume_appends(mut self, resume: bool) -> Store<B> {
+        self.resume_appends = resume;
+        self
+    }
+
+    /// Count new file data in `new_bytes`, shared with other stores of the same family.
+    pub fn with_new_bytes(mut self, new_bytes: Arc<AtomicUsize>) -> Store<B> {
+        self.new_bytes = new_bytes;
+        self
+    }
+
+    /// Report file data read and stored to `progress`.
+    pub fn with_progress(mut self, progress: Arc<Progress>) -> Store<B> {
+        self.progress = progress;
+        self
+    }
+
+    /// Bytes read and newly stored since the last reset.
+    pub fn commit_stats(&self) -> db::SnapshotStats {
+        db::SnapshotStats {
+            bytes_read: self.commit_bytes.load(Ordering::SeqCst) as u64,
+            bytes_new: self.new_bytes.load(Ordering::SeqCst) as u64,
         }
     }
 
+    /// Start a new per-commit byte budget and statistics.
+    pub fn reset_commit_bytes(&self) {
+        self.commit_bytes.store(0, Ordering::SeqCst);
+        self.new_bytes.store(0, Ordering::SeqCst);
+    }
+
+    /// Check a file of `size` bytes against the limits. A file that may not fit in what is left
+    /// of the commit budget is read ahead through `open`, to count the data not stored yet.
+    fn check_limits<IT: io::Read>(&self, size: u64, open: &Opener<IT>) -> Result<(), String> {
+        if let Some(max) = self.limits.max_file_size {
+            if size > max {
+                return Err(format!("file size {} exceeds limit of {} bytes", size, max));
+            }
+        }
+        if let Some(max) = self.limits.max_commit_bytes {
+            let used = self.new_bytes.load(Ordering::SeqCst) as u64;
+            if used + size > max {
+                // A file that cannot be read is left to fail when it is stored.
+                let new = open(0)
+                    .and_then(|mut reader| self.scan_reader(&mut reader).ok())
+                    .map_or(0, |scan| scan.new_bytes);
+                if used + new > max {
+                    return Err(format!(
+                        "commit would grow to {} bytes of new data, exceeding limit of {} bytes",
+                        used + new,
+                        max
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
     #[cfg(test)]
     pub fn new_for_testing(backend: Arc<B>, max_blob_size: usize) -> Result<Store<B>, DieselError> {
         use crypto;
@@ -160,6 +379,14 @@ impl<B: StoreBackend> Store<B> {
             hash_index: hi_p,
             blob_store: bs_p,
             keys: Arc::new(crypto::keys::Keeper::new_for_testing()),
+            limits: Limits::default(),
+            commit_bytes: Arc::new(AtomicUsize::new(0)),
+            new_bytes: Arc::new(AtomicUsize::new(0)),
+            inline_max: None,
+            fidelity: Fidelity::default(),
+            fanout: hash::tree::DEFAULT_FANOUT,
+            resume_appends: false,
+            progress: Arc::new(SilentProgress),
         })
     }
 
@@ -171,30 +398,150 @@ impl<B: StoreBackend> Store<B> {
         Ok(())
     }
 
+    /// Split a file into chunks as a commit would, and count those that are not stored yet.
+    /// Only reads the file.
+    pub fn scan_chunks(&self, path: &Path) -> io::Result<ChunkScan> {
+        self.scan_reader(&mut fs::File::open(path)?)
+    }
+
+    /// Like `scan_chunks`, for data read from `file`.
+    fn scan_reader<R: io::Read>(&self, file: &mut R) -> io::Result<ChunkScan> {
+        let mut chunk = vec![0; MAX_CHUNK_LEN];
+        let mut scan = ChunkScan::default();
+        loop {
+            let mut chunk_len = 0;
+            while chunk_len < MAX_CHUNK_LEN {
+                chunk_len += match file.read(&mut chunk[chunk_len..]) {
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) => return Err(e),
+                    Ok(0) => break,
+                    Ok(size) => size,
+                }
+            }
+            if chunk_len == 0 {
+                return Ok(scan);
+            }
+            let hash = hash::Hash::new(
+                &self.keys,
+                blob::NodeType::Leaf,
+                blob::LeafType::FileChunk,
+                &chunk[..chunk_len],
+            );
+            scan.chunks += 1;
+            if !self.hash_index.hash_exists(&hash) {
+                scan.new_chunks += 1;
+                scan.new_bytes += chunk_len as u64;
+            }
+        }
+    }
+
+    /// Bytes used by the key index of this store.
+    pub fn index_size(&self) -> Result<u64, MsgError> {
+        Ok(self.index.size()?)
+    }
+
+    /// Check, reindex and vacuum the key index of this store.
+    pub fn maintain_index(&self) -> Result<db::IndexReport, MsgError> {
+        Ok(self.index.maintain()?)
+    }
+
+    /// Drop the key index rows that refer to data no longer in the hash index, so that the key
+    /// index does not keep growing with data that garbage collection has removed. The files
+    /// are hashed again if they are committed again. Returns the number of rows deleted.
+    pub fn prune_index(&self) -> Result<u64, MsgError> {
+        let hash_index = self.hash_index.clone();
+        Ok(self.index.prune_stale(move |bytes| {
+            hash_index.get_id(&hash::Hash { bytes: bytes.to_vec() }).is_some()
+        })?)
+    }
+
+    fn hash_store_backend(&self) -> HashStoreBackend<B> {
+        HashStoreBackend::new(
+            self.hash_index.clone(),
+            self.blob_store.clone(),
+            self.keys.clone(),
+        ).counting_new_bytes(self.new_bytes.clone())
+            .reporting_uploads(self.progress.clone())
+    }
+
+    /// Inline contents are kept in the index sealed with the data key, like chunks are in
+    /// blobs, as the index itself is only encrypted in some builds (see `db::index_key`).
+    fn seal_inline(&self, bytes: &[u8]) -> Vec<u8> {
+        crypto::FixedKey::new(&self.keys)
+            .seal_blob_data(crypto::PlainTextRef::new(bytes))
+            .to_vec()
+    }
+
+    /// The contents of a `Data::FileInline` entry, which are kept sealed.
+    pub fn unseal_inline(&self, sealed: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        let plain = crypto::FixedKey::new(&self.keys)
+            .unseal_blob_data(crypto::CipherTextRef::new(sealed))?;
+        Ok(plain.into_vec())
+    }
+
     pub fn hash_tree_writer(
         &mut self,
         leaf: blob::LeafType,
     ) -> SimpleHashTreeWriter<HashStoreBackend<B>> {
-        let backend = HashStoreBackend::new(
-            self.hash_index.clone(),
-            self.blob_store.clone(),
-            self.keys.clone(),
-        );
-        SimpleHashTreeWriter::new(leaf, 8, backend)
+        SimpleHashTreeWriter::new(leaf, self.fanout, self.hash_store_backend())
+    }
+
+    /// Prepare to hash only the new data of a file that has been appended to since it was last
+    /// read. Returns the resumed hash tree and the data opened where hashing stopped, or `None`
+    /// if the file has to be read from the start.
+    fn resume_append<IT: io::Read>(
+        &mut self,
+        stamp: &FileStamp,
+        open: &Opener<IT>,
+    ) -> Result<Option<(SimpleHashTreeWriter<HashStoreBackend<B>>, IT, u64)>, MsgError> {
+        let state = match self.index.append_state_lookup(stamp)? {
+            Some(ref s) if s.offset >= MAX_CHUNK_LEN as u64 && s.offset <= stamp.size => s.clone(),
+            _ => return Ok(None),
+        };
+        let tree = match SimpleHashTreeWriter::resume(
+            blob::LeafType::FileChunk,
+            self.fanout,
+            self.hash_store_backend(),
+            &state.tree[..],
+        ) {
+            Ok(tree) => tree,
+            Err(_) => return Ok(None),
+        };
+        if !tree.path_ids().into_iter().all(|id| self.hash_index.get_hash(id).is_some()) {
+            // Some of the hashed data has been deleted since.
+            return Ok(None);
+        }
+
+        // Check that the file still ends its hashed part with the same chunk. Together with
+        // the unchanged inode, this is taken as proof that the file has only been appended to,
+        // which is why the family has to ask for it (see `with_resume_appends`).
+        let mut file = match open(state.offset - MAX_CHUNK_LEN as u64) {
+            Some(f) => f,
+            None => return Ok(None),
+        };
+        let mut tail = vec![0; MAX_CHUNK_LEN];
+        if file.read_exact(&mut tail).is_err() {
+            return Ok(None);
+        }
+        if &blake3::hash(&tail[..]).as_bytes()[..] != &state.tail_hash[..] {
+            return Ok(None);
+        }
+
+        Ok(Some((tree, file, state.offset)))
     }
 }
 
 fn file_size_warning(name: &[u8], wanted: u64, got: u64) {
     if wanted < got {
-        println!(
-            "Warning: File grew while reading it: {:?} (wanted {}, got {})",
+        warn!(
+            "File grew while reading it: {:?} (wanted {}, got {})",
             name,
             wanted,
             got
         )
     } else if wanted > got {
-        println!(
-            "Warning: Could not read whole file (or it shrank): {:?} (wanted {}, got {})",
+        warn!(
+            "Could not read whole file (or it shrank): {:?} (wanted {}, got {})",
             name,
             wanted,
             got
@@ -263,8 +610,10 @@ impl<IT: io::Read, B: StoreBackend> MsgHandler<Msg<IT>, Reply<B>> for Store<B> {
                 return reply_ok!(Reply::Ok);
             }
 
-            Msg::Insert(insert_entry, chunk_it_opt) => {
-                let entry = match self.index.lookup(
+            Msg::Insert(mut insert_entry, chunk_it_opt) => {
+                self.fidelity.filter(&mut insert_entry.info);
+
+                let mut entry = match self.index.lookup(
                     insert_entry.parent_id,
                     insert_entry.info.name.clone(),
                 )? {
@@ -279,6 +628,12 @@ impl<IT: io::Read, B: StoreBackend> MsgHandler<Msg<IT>, Reply<B>> for Store<B> {
                                     return reply_ok!(Reply::Id(stored_entry.node_id.unwrap()));
                                 }
                             }
+                            &Data::FileInline(_) if chunk_it_opt.is_some() => {
+                                // Short-circuit: The data is stored with the entry.
+                                debug!("Skip inline entry: {:?}", stored_entry.info.name);
+                                self.index.mark_reserved(&stored_entry)?;
+                                return reply_ok!(Reply::Id(stored_entry.node_id.unwrap()));
+                            }
                             _ if chunk_it_opt.is_none() => {
                                 // Short-circuit: No data needed.
                                 debug!("Skip empty entry: {:?}", stored_entry.info.name);
@@ -302,9 +657,48 @@ impl<IT: io::Read, B: StoreBackend> MsgHandler<Msg<IT>, Reply<B>> for Store<B> {
                     None => insert_entry,
                 };
 
+                // Short-circuit: The file is unchanged on disk and we still have its data.
+                if let (true, Some(stamp)) = (chunk_it_opt.is_some(), entry.stamp.as_ref()) {
+                    if let Some(hash_bytes) = self.index.file_cache_lookup(stamp)? {
+                        let hash = hash::Hash { bytes: hash_bytes };
+                        if self.hash_index.hash_exists(&hash) {
+                            if let Some(hash_ref) = self.hash_index.fetch_hash_ref(&hash)? {
+                                debug!("Cached entry: {:?}", entry.info.name);
+                                let entry = self.index.insert(entry.clone(), Some(&hash_ref))?;
+                                return reply_ok!(Reply::Id(entry.node_id.unwrap()));
+                            }
+                        }
+                    }
+                }
+
+                // Check that the file fits within the configured limits:
+                if let (Some(open), Some(size)) = (chunk_it_opt.as_ref(), entry.info.byte_length) {
+                    if let Err(e) = self.check_limits(size, open) {
+                        if self.limits.abort {
+                            return reply_err!(From::from(e));
+                        }
+                        warn!("Skipping '{}': {}", String::from_utf8_lossy(&entry.info.name), e);
+                        return reply_ok!(Reply::Skipped);
+                    }
+                }
+
+                // Continue from where hashing stopped last time, if the file has only grown.
+                // Offsets into a sparse file do not match offsets into its data, so it is always
+                // read in full:
+                let resumed = match (chunk_it_opt.as_ref(), entry.stamp.as_ref()) {
+                    (Some(open), Some(stamp)) if self.resume_appends &&
+                                                 entry.info.sparse_map.is_none() => {
+                        self.resume_append(stamp, open)?
+                    }
+                    _ => None,
+                };
+
                 // Check if we have an data source:
-                let it_opt = chunk_it_opt.and_then(|open| open.call(()));
-                if it_opt.is_none() {
+                let it_opt = match resumed {
+                    Some(_) => None,
+                    None => chunk_it_opt.and_then(|open| open(0)),
+                };
+                if resumed.is_none() && it_opt.is_none() {
                     // No data is associated with this entry.
                     debug!("Insert entry: {:?}", entry.info.name);
                     let entry = self.index.insert(entry, None)?;
@@ -314,38 +708,120 @@ impl<IT: io::Read, B: StoreBackend> MsgHandler<Msg<IT>, Reply<B>> for Store<B> {
                 }
 
                 // Setup hash tree structure
-                let mut tree = self.hash_tree_writer(blob::LeafType::FileChunk);
+                let (mut tree, mut reader, skipped) = match resumed {
+                    Some((tree, file, offset)) => {
+                        debug!("Resume entry at {}: {:?}", offset, entry.info.name);
+                        (tree, file, offset)
+                    }
+                    None => {
+                        let tree = self.hash_tree_writer(blob::LeafType::FileChunk);
+                        (tree, it_opt.unwrap(), 0)
+                    }
+                };
 
                 // Read and insert all file chunks:
                 // (see HashStoreBackend::insert_chunk above)
-                let max_chunk_len = 128 * 1024;
-                let mut chunk = vec![0; max_chunk_len];
-                let mut reader = it_opt.unwrap();
-                let mut file_len = 0u64;
+                let mut chunk = vec![0; MAX_CHUNK_LEN];
+                let mut file_len = skipped;
+                let mut checkpoint = None;
+                let mut read_failed = false;
+                // Only the data extents of a sparse file are read.
+                let expected_len = match entry.info.sparse_map {
+                    Some(ref extents) => Some(util::data_length(extents)),
+                    None => entry.info.byte_length,
+                };
                 loop {
+                    util::wait_while_paused();
                     let mut chunk_len = 0;
-                    while chunk_len < max_chunk_len {
+                    while chunk_len < MAX_CHUNK_LEN {
                         chunk_len += match reader.read(&mut chunk[chunk_len..]) {
                             Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
-                            Ok(0) | Err(_) => break,
+                            Ok(0) => break,
+                            Err(_) => {
+                                read_failed = true;
+                                break;
+                            }
                             Ok(size) => size,
                         }
                     }
+                    if read_failed {
+                        // Keep what was read, but make sure the next commit reads the file
+                        // again rather than taking it to be unchanged.
+                        entry.info.modified_ts_secs = None;
+                        if entry.info.sparse_map.is_none() {
+                            entry.info.byte_length = Some(file_len + chunk_len as u64);
+                        }
+                    }
+                    if file_len == 0 && chunk_len < MAX_CHUNK_LEN &&
+                        self.inline_max.map_or(false, |max| chunk_len <= max)
+                    {
+                        // The whole file has been read and is small enough to keep inline.
+                        self.commit_bytes.fetch_add(chunk_len, Ordering::SeqCst);
+                        self.new_bytes.fetch_add(chunk_len, Ordering::SeqCst);
+                        self.progress.read(chunk_len as u64);
+                        self.progress.uploaded(chunk_len as u64);
+                        self.progress.stored(chunk_len as u64);
+                        expected_len.map(|s| {
+                            file_size_warning(&entry.info.name, s, chunk_len as u64);
+                        });
+                        if entry.info.byte_length.is_none() {
+                            entry.info.byte_length = Some(chunk_len as u64);
+                        }
+                        debug!("Insert inline entry: {:?}", entry.info.name);
+                        let sealed = self.seal_inline(&chunk[..chunk_len]);
+                        let entry = self.index.insert(
+                            Entry {
+                                data: Data::FileInline(sealed),
+                                ..entry
+                            },
+                            None,
+                        )?;
+                        return reply_ok!(Reply::Id(entry.node_id.unwrap()));
+                    }
                     if chunk_len == 0 {
                         break;
                     }
                     file_len += chunk_len as u64;
-                    tree.append(&chunk[..chunk_len])?
+                    self.progress.read(chunk_len as u64);
+                    tree.append(&chunk[..chunk_len])?;
+                    if read_failed {
+                        break;
+                    }
+
+                    if chunk_len == MAX_CHUNK_LEN && entry.stamp.is_some() &&
+                        entry.info.sparse_map.is_none()
+                    {
+                        // A later append can resume after this chunk.
+                        checkpoint = Some(AppendState {
+                            offset: file_len,
+                            tail_hash: blake3::hash(&chunk[..]).as_bytes().to_vec(),
+                            tree: tree.state(),
+                        });
+                    }
                 }
 
+                self.commit_bytes.fetch_add((file_len - skipped) as usize, Ordering::SeqCst);
+
                 // Warn the user if we did not read the expected size:
-                entry.info.byte_length.map(|s| {
+                expected_len.map(|s| {
                     file_size_warning(&entry.info.name, s, file_len);
                 });
 
+                // Data of unknown size, such as a stream, is as long as what was read:
+                if entry.info.byte_length.is_none() {
+                    entry.info.byte_length = Some(file_len);
+                }
+
                 // Get top tree hash:
                 let hash_ref = tree.hash(Some(&entry.info))?;
 
+                if let (false, Some(stamp)) = (read_failed, entry.stamp.as_ref()) {
+                    self.index.file_cache_store(stamp, &hash_ref.hash.bytes)?;
+                    if let Some(ref state) = checkpoint {
+                        self.index.append_state_store(stamp, state)?;
+                    }
+                }
+
                 // It is OK that this has is not yet valid, as we check hashes at snapshot time.
                 debug!("Insert entry: {:?}", entry.info.name);
                 let entry = self.index.insert(entry, Some(&hash_ref))?;
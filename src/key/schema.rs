@@ -42,6 +42,37 @@ table! {
 
         hash -> Nullable<Binary>,
         hash_ref -> Nullable<Binary>,
+
+        inline_data -> Nullable<Binary>,
+
+        hardlink -> Nullable<BigInt>,
+        xattrs -> Nullable<Binary>,
+        acl -> Nullable<Text>,
+        default_acl -> Nullable<Text>,
+        sparse_map -> Nullable<Binary>,
+        user_name -> Nullable<Text>,
+        group_name -> Nullable<Text>,
+    }
+}
+
+table! {
+    file_cache (path) {
+        path -> Binary,
+        inode -> BigInt,
+        size -> BigInt,
+        mtime_ns -> BigInt,
+        ctime_ns -> BigInt,
+        hash -> Binary,
+    }
+}
+
+table! {
+    append_cache (path) {
+        path -> Binary,
+        inode -> BigInt,
+        resume_offset -> BigInt,
+        tail_hash -> Binary,
+        tree_state -> Binary,
     }
 }
 
@@ -82,6 +113,16 @@ pub struct KeyData {
 
     pub hash: Option<Vec<u8>>,
     pub hash_ref: Option<Vec<u8>>,
+
+    pub inline_data: Option<Vec<u8>>,
+
+    pub hardlink: Option<i64>,
+    pub xattrs: Option<Vec<u8>>,
+    pub acl: Option<String>,
+    pub default_acl: Option<String>,
+    pub sparse_map: Option<Vec<u8>>,
+    pub user_name: Option<String>,
+    pub group_name: Option<String>,
 }
 
 #[derive(Insertable)]
@@ -103,4 +144,44 @@ pub struct NewKeyData<'a> {
 
     pub hash: Option<&'a [u8]>,
     pub hash_ref: Option<&'a [u8]>,
+
+    pub inline_data: Option<&'a [u8]>,
+
+    pub hardlink: Option<i64>,
+    pub xattrs: Option<&'a [u8]>,
+    pub acl: Option<&'a str>,
+    pub default_acl: Option<&'a str>,
+    pub sparse_map: Option<&'a [u8]>,
+    pub user_name: Option<&'a str>,
+    pub group_name: Option<&'a str>,
+}
+
+#[derive(Insertable)]
+#[table_name = "file_cache"]
+pub struct NewFileCache<'a> {
+    pub path: &'a [u8],
+    pub inode: i64,
+    pub size: i64,
+    pub mtime_ns: i64,
+    pub ctime_ns: i64,
+    pub hash: &'a [u8],
+}
+
+#[derive(Queryable)]
+pub struct AppendCache {
+    pub path: Vec<u8>,
+    pub inode: i64,
+    pub resume_offset: i64,
+    pub tail_hash: Vec<u8>,
+    pub tree_state: Vec<u8>,
+}
+
+#[derive(Insertable)]
+#[table_name = "append_cache"]
+pub struct NewAppendCache<'a> {
+    pub path: &'a [u8],
+    pub inode: i64,
+    pub resume_offset: i64,
+    pub tail_hash: &'a [u8],
+    pub tree_state: &'a [u8],
 }
@@ -102,10 +102,18 @@ fn rng_filesystem(size: usize) -> FileSystem {
 
                         permissions: None,
                         user_id: None,
+                        user_name: None,
                         group_id: None,
+                        group_name: None,
 
                         hat_snapshot_ts: 0,
+                        hardlink_id: None,
+                        xattrs: vec![],
+                        acl: None,
+                        default_acl: None,
+                        sparse_map: None,
                     },
+                    stamp: None,
                 },
             };
 
@@ -131,10 +139,18 @@ fn rng_filesystem(size: usize) -> FileSystem {
                 accessed_ts_secs: thread_rng().gen(),
                 permissions: None,
                 user_id: None,
+                user_name: None,
                 group_id: None,
+                group_name: None,
                 byte_length: None,
                 hat_snapshot_ts: 0,
+                hardlink_id: None,
+                xattrs: vec![],
+                acl: None,
+                default_acl: None,
+                sparse_map: None,
             },
+            stamp: None,
         },
     };
 
@@ -149,7 +165,7 @@ fn insert_and_update_fs<B: StoreBackend>(fs: &mut FileSystem, ks_p: &StoreProces
     fs.file.key_entry.node_id = match ks_p.send_reply(Msg::Insert(
         fs.file.key_entry.clone(),
         if fs.file.data.is_some() {
-            Some(Box::new(move |()| Some(local_file)))
+            Some(Box::new(move |_: u64| Some(local_file.clone())))
         } else {
             None
         },
@@ -255,3 +271,229 @@ fn identity() {
     }
     quickcheck::quickcheck(prop as fn(u8) -> bool);
 }
+
+#[test]
+fn file_cache_skips_unchanged_files() {
+    let backend = Arc::new(MemoryBackend::new());
+    let ks_p = Process::new(Store::new_for_testing(backend, 4096).unwrap());
+
+    let stamp = FileStamp {
+        path: b"/some/file".to_vec(),
+        inode: 1,
+        size: 5,
+        mtime_ns: 2,
+        ctime_ns: 3,
+    };
+    let insert = |name: &[u8], contents: &[u8]| {
+        let file = EntryStub {
+            key_entry: Entry::new(None, name.to_vec(), Data::FilePlaceholder, None)
+                .with_stamp(stamp.clone()),
+            data: Some(vec![contents.to_vec()]),
+        };
+        let local_file = file.clone();
+        match ks_p.send_reply(Msg::Insert(
+            file.key_entry,
+            Some(Box::new(move |_: u64| Some(local_file.clone()))),
+        )).unwrap() {
+            Reply::Id(_) => (),
+            _ => panic!("unexpected reply from key store"),
+        }
+    };
+
+    insert(b"a", b"hello");
+    // Same on-disk state: the cached hash is used instead of reading the new contents.
+    insert(b"b", b"world");
+
+    match ks_p.send_reply(Msg::CommitReservedNodes(None)).unwrap() {
+        Reply::Ok => (),
+        _ => panic!("Unexpected result from key store."),
+    }
+    match ks_p.send_reply(Msg::Flush).unwrap() {
+        Reply::FlushOk => (),
+        _ => panic!("Unexpected result from key store."),
+    }
+
+    let listing = match ks_p.send_reply(Msg::ListDir(None)).unwrap() {
+        Reply::ListResult(ls) => ls,
+        _ => panic!("Unexpected result from key store."),
+    };
+    assert_eq!(2, listing.len());
+    let hashes: Vec<_> = listing
+        .iter()
+        .map(|&(_, ref r, _)| r.as_ref().unwrap().hash.bytes.clone())
+        .collect();
+    assert_eq!(hashes[0], hashes[1]);
+}
+
+#[test]
+fn files_with_read_errors_are_read_again() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    // Fails after a few bytes, like a file with a bad sector.
+    struct Failing(usize);
+    impl io::Read for Failing {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.0 == 0 {
+                return Err(io::Error::new(io::ErrorKind::Other, "bad sector"));
+            }
+            self.0 -= 1;
+            buf[0] = 1;
+            Ok(1)
+        }
+    }
+
+    let backend = Arc::new(MemoryBackend::new());
+    let ks_p = Process::new(Store::new_for_testing(backend, 4096).unwrap());
+    let stamp = FileStamp {
+        path: b"/some/file".to_vec(),
+        inode: 1,
+        size: 100,
+        mtime_ns: 2,
+        ctime_ns: 3,
+    };
+    let opened = Arc::new(AtomicUsize::new(0));
+    let insert = || {
+        let mut entry = Entry::new(None, b"a".to_vec(), Data::FilePlaceholder, None)
+            .with_stamp(stamp.clone());
+        entry.info.modified_ts_secs = Some(2);
+        let opened = opened.clone();
+        match ks_p.send_reply(Msg::Insert(
+            entry,
+            Some(Box::new(move |_: u64| {
+                opened.fetch_add(1, Ordering::SeqCst);
+                Some(Failing(10))
+            })),
+        )).unwrap() {
+            Reply::Id(_) => (),
+            _ => panic!("unexpected reply from key store"),
+        }
+        match ks_p.send_reply(Msg::CommitReservedNodes(None)).unwrap() {
+            Reply::Ok => (),
+            _ => panic!("Unexpected result from key store."),
+        }
+    };
+
+    // The truncated file is neither cached nor taken to be unchanged.
+    insert();
+    insert();
+    assert_eq!(opened.load(Ordering::SeqCst), 2);
+}
+
+#[test]
+fn append_only_files_resume_hashing() {
+    use std::env;
+    use std::fs;
+    use std::io::Write;
+
+    use std::sync::Mutex;
+    use util::FileIterator;
+
+    let backend = Arc::new(MemoryBackend::new());
+    let store = Store::new_for_testing(backend, 4096).unwrap().with_resume_appends(true);
+    let ks_p = Process::new(store);
+
+    let name = String::from_utf8(random_ascii_bytes()).unwrap();
+    let path = env::temp_dir().join(format!("hat-append-{}", name));
+    let mut contents: Vec<u8> = (0..300 * 1024).map(|i| (i % 251) as u8).collect();
+    fs::File::create(&path).unwrap().write_all(&contents[..]).unwrap();
+
+    let opened = Arc::new(Mutex::new(vec![]));
+    let insert = || {
+        let stamp = FileStamp::new(&path, &fs::metadata(&path).unwrap());
+        let (path, opened) = (path.clone(), opened.clone());
+        match ks_p.send_reply(Msg::Insert(
+            Entry::new(None, b"log".to_vec(), Data::FilePlaceholder, None).with_stamp(stamp),
+            Some(Box::new(move |offset: u64| {
+                opened.lock().unwrap().push(offset);
+                FileIterator::new(&path).and_then(|it| it.skip_to(offset)).ok()
+            })),
+        )).unwrap() {
+            Reply::Id(_) => (),
+            _ => panic!("unexpected reply from key store"),
+        }
+    };
+
+    let flush = || match ks_p.send_reply(Msg::Flush).unwrap() {
+        Reply::FlushOk => (),
+        _ => panic!("Unexpected result from key store."),
+    };
+
+    insert();
+    flush();
+
+    let appended: Vec<u8> = (0..100 * 1024).map(|i| (i % 13) as u8).collect();
+    let mut fd = fs::OpenOptions::new().append(true).open(&path).unwrap();
+    fd.write_all(&appended[..]).unwrap();
+    contents.extend_from_slice(&appended[..]);
+
+    // Only the last hashed chunk, to check it is unchanged, and the new contents are read.
+    insert();
+    fs::remove_file(&path).unwrap();
+    assert_eq!(*opened.lock().unwrap(), vec![0, 128 * 1024]);
+
+    match ks_p.send_reply(Msg::CommitReservedNodes(None)).unwrap() {
+        Reply::Ok => (),
+        _ => panic!("Unexpected result from key store."),
+    }
+    flush();
+
+    let listing = match ks_p.send_reply(Msg::ListDir(None)).unwrap() {
+        Reply::ListResult(ls) => ls,
+        _ => panic!("Unexpected result from key store."),
+    };
+    assert_eq!(1, listing.len());
+
+    let tree = listing.into_iter().next().unwrap().2.unwrap().init().unwrap().unwrap();
+    let mut restored = vec![];
+    for chunk in tree {
+        restored.extend_from_slice(&chunk[..]);
+    }
+    assert_eq!(restored, contents);
+}
+
+#[test]
+fn prune_index_drops_entries_of_deleted_data() {
+    let backend = Arc::new(MemoryBackend::new());
+    let store = Store::new_for_testing(backend, 4096).unwrap();
+    let ks_p = Process::new(store.clone());
+
+    for &(name, contents) in &[(&b"a"[..], &b"hello"[..]), (&b"b"[..], &b"world"[..])] {
+        let file = EntryStub {
+            key_entry: Entry::new(None, name.to_vec(), Data::FilePlaceholder, None),
+            data: Some(vec![contents.to_vec()]),
+        };
+        let local_file = file.clone();
+        match ks_p.send_reply(Msg::Insert(
+            file.key_entry,
+            Some(Box::new(move |_: u64| Some(local_file.clone()))),
+        )).unwrap() {
+            Reply::Id(_) => (),
+            _ => panic!("unexpected reply from key store"),
+        }
+    }
+    match ks_p.send_reply(Msg::CommitReservedNodes(None)).unwrap() {
+        Reply::Ok => (),
+        _ => panic!("Unexpected result from key store."),
+    }
+    match ks_p.send_reply(Msg::Flush).unwrap() {
+        Reply::FlushOk => (),
+        _ => panic!("Unexpected result from key store."),
+    }
+    assert_eq!(store.prune_index().unwrap(), 0);
+
+    // Delete the data of one file, as garbage collection would.
+    let listing = match ks_p.send_reply(Msg::ListDir(None)).unwrap() {
+        Reply::ListResult(ls) => ls,
+        _ => panic!("Unexpected result from key store."),
+    };
+    let (_, ref hash_ref, _) = listing[0];
+    let hash = &hash_ref.as_ref().unwrap().hash;
+    store.hash_index.delete(store.hash_index.get_id(hash).unwrap());
+
+    assert_eq!(store.prune_index().unwrap(), 1);
+    let listing = match ks_p.send_reply(Msg::ListDir(None)).unwrap() {
+        Reply::ListResult(ls) => ls,
+        _ => panic!("Unexpected result from key store."),
+    };
+    assert_eq!(listing.len(), 1);
+}
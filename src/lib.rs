@@ -27,15 +27,30 @@ extern crate time;
 
 // Rust crates.
 extern crate argon2rs;
+extern crate blake3;
 extern crate byteorder;
 extern crate capnp;
 extern crate chrono;
+extern crate libc;
 extern crate libsodium_sys;
 extern crate hex;
 extern crate secstr;
 extern crate scoped_pool;
+extern crate serde;
+extern crate serde_json;
+extern crate tar;
+extern crate toml;
 extern crate void;
 extern crate filetime;
+extern crate xattr;
+#[cfg(feature = "mount")]
+extern crate fuse;
+#[cfg(feature = "web")]
+extern crate tiny_http;
+
+// Serialization of reports, for JSON output.
+#[macro_use]
+extern crate serde_derive;
 
 // Error definition macros.
 #[macro_use]
@@ -67,7 +82,7 @@ mod util;
 
 // Re-export the main type
 
-pub use hat::Hat;
+pub use hat::{Hat, HatBuilder};
 
 // The capnp module generated by build.rs and used internally
 #[allow(dead_code)]
@@ -16,26 +16,197 @@
 extern crate hat;
 
 // Rust crates.
+extern crate chrono;
 extern crate env_logger;
 extern crate libsodium_sys;
+extern crate log;
+extern crate serde_json;
+extern crate time;
+
+// JSON output of the listing commands.
+#[macro_use]
+extern crate serde_derive;
 
 // We use Clap for argument parsing.
 #[macro_use]
 extern crate clap;
 
 use std::env;
-use clap::{App, SubCommand};
+use clap::{App, Arg, SubCommand};
 
 use hat::backend;
+use hat::hat::Progress;
 use std::borrow::ToOwned;
 use std::convert::From;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::os::unix::io::FromRawFd;
 use std::path::{Path, PathBuf};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+
+/// Exit status of a commit that succeeded without some files that could not be read.
+static EXIT_PARTIAL: i32 = 3;
+
+type BlobBackend = backend::CountingBackend<backend::AppendOnlyBackend<backend::FileBackend>>;
+
+/// The repository's blobs; in append-only mode, deleting or overwriting them is refused.
+fn blob_backend(blob_dir: &Path, append_only: AppendOnly) -> Arc<BlobBackend> {
+    Arc::new(file_backend(blob_dir, append_only))
+}
+
+/// Lock the repository before opening it, so that the lock covers all that is read from it.
+/// Exits if another client holds a conflicting lock.
+fn lock_repository<B: backend::StoreBackend>(
+    backend: &Arc<B>,
+    cache_dir: &Path,
+    kind: hat::hat::LockKind,
+) -> hat::hat::RepositoryLock<B> {
+    match hat::Hat::lock_unopened(backend.clone(), cache_dir, kind) {
+        Ok(lock) => lock,
+        Err(e) => {
+            println!("{}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Whether deletes and overwrites of blobs are refused, or let through by a privileged client.
+#[derive(Clone, Copy)]
+struct AppendOnly {
+    enforcing: bool,
+    privileged: bool,
+}
+
+fn file_backend(blob_dir: &Path, append_only: AppendOnly) -> BlobBackend {
+    backend::CountingBackend::new(
+        backend::AppendOnlyBackend::new(backend::FileBackend::new(blob_dir.to_owned()))
+            .with_enforcing(append_only.enforcing)
+            .with_privileged(append_only.privileged),
+    )
+}
+
+/// The repository's blobs as `gc` uses them: its deletes are held within the limits given on
+/// the command line or in the configuration file.
+fn gc_backend(
+    blob_dir: &Path,
+    append_only: AppendOnly,
+    cmd: Option<&clap::ArgMatches>,
+    repository: &hat::hat::RepositoryConfig,
+) -> Arc<backend::ThrottledBackend<BlobBackend>> {
+    let flag = |name: &str| cmd.and_then(|cmd| cmd.value_of(name));
+    let throttle = backend::Throttle {
+        ops_per_sec: flag("deletes_per_sec")
+            .map(|n| n.parse().unwrap())
+            .or(repository.gc_deletes_per_sec),
+        concurrency: flag("delete_concurrency")
+            .map(|n| n.parse().unwrap())
+            .or(repository.gc_delete_concurrency),
+    };
+    Arc::new(backend::ThrottledBackend::new(file_backend(blob_dir, append_only), throttle))
+}
+
+/// Reports the progress of long-running commands on the terminal, and as events if requested.
+fn reporter(events: &Option<Arc<hat::hat::EventLog>>) -> Arc<Progress> {
+    match *events {
+        Some(ref events) => events.clone(),
+        None => Arc::new(hat::hat::TerminalProgress::new()),
+    }
+}
+
+/// Run the on_error hook, if any, telling it about `error`.
+fn run_on_error_hook(hooks: &hat::hat::Hooks, mut env: Vec<(&str, String)>, error: &str) {
+    env.push(("HAT_ERROR", error.to_string()));
+    if let Err(e) = hooks.run(hat::hat::Hook::OnError, &env) {
+        println!("{}", e);
+    }
+}
+
+/// Tell how much must be pruned for the repository to get back under its quota, if anything.
+fn report_quota<B: backend::StoreBackend>(
+    hat: &mut hat::hat::HatRc<B>,
+    quota: Option<hat::hat::Quota>,
+) {
+    if let Some(quota) = quota {
+        let status = hat.quota_status(&quota, 0).unwrap();
+        if status.is_exceeded() {
+            println!(
+                "The repository uses {} bytes, {} more than its quota of {} bytes",
+                status.used_bytes,
+                status.excess_bytes(),
+                status.max_bytes
+            );
+        }
+    }
+}
 
-static MAX_BLOB_SIZE: usize = 4 * 1024 * 1024;
+/// Write the bundle that takes a copy of `family` from snapshot `from` to snapshot `to`, into
+/// `output` or to standard output. Returns the number of blobs written.
+fn write_bundle<B: backend::StoreBackend>(
+    hat: &mut hat::hat::HatRc<B>,
+    family: &str,
+    from: Option<u64>,
+    to: u64,
+    output: Option<&str>,
+) -> usize {
+    match output {
+        Some(file) => {
+            let mut out = io::BufWriter::new(fs::File::create(file).unwrap());
+            hat.create_patch(family, from, to, &mut out).unwrap()
+        }
+        None => {
+            let stdout = io::stdout();
+            let mut out = io::BufWriter::new(stdout.lock());
+            hat.create_patch(family, from, to, &mut out).unwrap()
+        }
+    }
+}
+
+/// Apply a bundle read from `input` or from standard input, and commit it so that the copy
+/// can be recovered from its own blobs.
+fn apply_bundle<B: backend::StoreBackend>(hat: &mut hat::hat::HatRc<B>, input: Option<&str>) {
+    let applied = match input {
+        Some(file) => {
+            let mut input = io::BufReader::new(fs::File::open(file).unwrap());
+            hat.apply_patch(&mut input).unwrap()
+        }
+        None => {
+            let stdin = io::stdin();
+            hat.apply_patch(&mut stdin.lock()).unwrap()
+        }
+    };
+    if !applied {
+        println!("The repository has this snapshot already; nothing was applied");
+        return;
+    }
+    hat.meta_commit().unwrap();
+    hat.data_flush().unwrap();
+}
 
-fn blob_dir() -> PathBuf {
-    PathBuf::from("blobs")
+fn print_gc_report(report: &hat::hat::GcReport, json: bool) {
+    if json {
+        println!("{}", serde_json::to_string(report).unwrap());
+        return;
+    }
+    println!(
+        "Kept {} snapshots; deleted {} chunks and {} blobs, reclaiming {}",
+        report.snapshots,
+        report.hashes_deleted,
+        report.blobs_deleted,
+        hat::hat::human_bytes(report.bytes_reclaimed)
+    );
+    if report.blobs_condemned > 0 {
+        println!(
+            "Condemned {} unused blobs until their grace period is over",
+            report.blobs_condemned
+        );
+    }
+    println!("Live chunks after deletion: {}", report.live_chunks);
+    if report.key_rows_pruned > 0 {
+        println!("Pruned {} stale key index rows", report.key_rows_pruned);
+    }
+    for phase in &report.phases {
+        println!("  {:20} {:>8} ms", phase.name, phase.millis);
+    }
 }
 
 fn license() {
@@ -44,10 +215,133 @@ fn license() {
     println!(include_str!("../LICENSE-CLAP"));
 }
 
+/// Where and how a commit of `source` is being made by this process.
+fn provenance(source: &str) -> hat::hat::Provenance {
+    let hostname = env::var("HOSTNAME").ok().or_else(|| {
+        let mut name = String::new();
+        fs::File::open("/etc/hostname")
+            .and_then(|mut f| f.read_to_string(&mut name))
+            .ok()
+            .map(|_| name.trim().to_string())
+    });
+    let username = env::var("USER").or_else(|_| env::var("LOGNAME")).ok();
+    let source = fs::canonicalize(source).unwrap_or_else(|_| PathBuf::from(source));
 
-fn main() {
-    env_logger::init().unwrap();
+    hat::hat::Provenance {
+        hostname: hostname.unwrap_or_default(),
+        username: username.unwrap_or_default(),
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        command_line: env::args().collect(),
+        source_path: source.to_string_lossy().into_owned(),
+    }
+}
+
+/// Read a list of paths, one per line or, with `nul`, separated by NUL bytes.
+fn read_path_list<R: Read>(mut list: R, nul: bool) -> io::Result<Vec<PathBuf>> {
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+
+    let mut bytes = vec![];
+    list.read_to_end(&mut bytes)?;
+    let separator = if nul { b'\0' } else { b'\n' };
+    Ok(
+        bytes
+            .split(|b| *b == separator)
+            .filter(|p| !p.is_empty())
+            .map(|p| PathBuf::from(OsStr::from_bytes(p)))
+            .collect(),
+    )
+}
+
+/// An entry found by `find`, as printed by `find --json`.
+#[derive(Serialize)]
+struct FoundEntry {
+    snapshot_id: u64,
+    #[serde(flatten)]
+    entry: hat::hat::ListEntry,
+}
+
+
+/// The kind and permissions of an entry in the style of `ls -l`, e.g. `drwxr-xr-x`.
+fn mode_string(entry: &hat::hat::ListEntry) -> String {
+    let mut out = match entry.kind {
+        hat::hat::EntryKind::Dir => "d",
+        hat::hat::EntryKind::Symlink(_) => "l",
+        hat::hat::EntryKind::File => "-",
+    }.to_string();
+    match entry.mode {
+        Some(mode) => {
+            for (i, c) in "rwxrwxrwx".chars().enumerate() {
+                out.push(if mode & (0o400 >> i) != 0 { c } else { '-' });
+            }
+        }
+        None => out.push_str("?????????"),
+    }
+    out
+}
+
+#[cfg(feature = "mount")]
+fn mount<B: backend::StoreBackend>(hat: &mut hat::hat::HatRc<B>, mountpoint: &Path) {
+    hat.mount(mountpoint).unwrap();
+}
+
+#[cfg(not(feature = "mount"))]
+fn mount<B: backend::StoreBackend>(_hat: &mut hat::hat::HatRc<B>, _mountpoint: &Path) {
+    println!("This hat was built without the mount feature");
+    std::process::exit(1);
+}
+
+#[cfg(feature = "web")]
+fn web<B: backend::StoreBackend>(
+    hat: &mut hat::hat::HatRc<B>,
+    addr: &str,
+    restore_dir: PathBuf,
+) -> Result<(), String> {
+    // A token of the user's own allows serving other machines; otherwise one is made up.
+    let access = match env::var("HAT_WEB_TOKEN") {
+        Ok(token) => hat::hat::WebAccess::with_token(token),
+        Err(_) => hat::hat::WebAccess::session(),
+    };
+    println!("Serving http://{}/?token={}", addr, access.token());
+    hat.serve_web(addr, restore_dir, &access).map_err(|e| e.to_string())
+}
+
+#[cfg(not(feature = "web"))]
+fn web<B: backend::StoreBackend>(
+    _hat: &mut hat::hat::HatRc<B>,
+    _addr: &str,
+    _dir: PathBuf,
+) -> Result<(), String> {
+    Err("This hat was built without the web feature".to_string())
+}
+
+/// Log warnings and errors, or more with each `-v`. RUST_LOG, in the syntax of env_logger (e.g.
+/// `hat::blob=debug`), is applied on top, to pick out parts of the program.
+fn init_logging(verbosity: u64) {
+    let level = match verbosity {
+        0 => log::LogLevelFilter::Warn,
+        1 => log::LogLevelFilter::Info,
+        2 => log::LogLevelFilter::Debug,
+        _ => log::LogLevelFilter::Trace,
+    };
+    let mut builder = env_logger::LogBuilder::new();
+    builder.format(|record: &log::LogRecord| {
+        format!(
+            "{} {:5} {}: {}",
+            chrono::Local::now().format("%H:%M:%S%.3f"),
+            record.level(),
+            record.location().module_path(),
+            record.args()
+        )
+    });
+    builder.filter(None, level);
+    if let Ok(spec) = env::var("RUST_LOG") {
+        builder.parse(&spec);
+    }
+    builder.init().unwrap();
+}
 
+fn main() {
     // Because "snapshot" and "checkout" use the exact same type of arguments, we can make a
     // template. This template defines two positional arguments, both are required
     let arg_template = "<NAME> 'Name of the snapshot'
@@ -59,18 +353,161 @@ fn main() {
         .about("Create backup snapshots")
         .args_from_usage(
             "-l, --license 'Display the license'
-                          --hat_migrations_dir=[DIR] 'Location of Hat SQL migrations'
-                          --hat_cache_dir=[DIR] 'Location of Hat local state'",
+                          --hat_migrations_dir=[DIR] 'Location of Hat SQL migrations (default: built in)'
+                          --hat_cache_dir=[DIR] 'Location of Hat local state'
+                          --hash_algorithm=[ALGORITHM] 'Hash algorithm for a new repository (blake2b or blake3)'
+                          --namespace=[NAME] 'Namespace of this machine in a shared repository'
+                          --append_only 'Refuse to delete or overwrite blobs; gc only reports unused data. With init, the repository stays append-only'
+                          --privileged 'Delete and overwrite blobs of a repository created append-only, e.g. to prune it'
+                          --allow_unsigned 'Accept snapshots without a signature, e.g. those committed before snapshots were signed'
+                          --config=[FILE] 'Configuration file (default: ~/.config/hat/config.toml)'
+                          -v, --verbose... 'Log more: -v for progress, -vv for debugging, -vvv for everything'
+                          --events_fd=[FD] 'Write progress events as JSON lines to this open file descriptor'
+                          --events_file=[FILE] 'Write progress events as JSON lines to this file'
+                          --passphrase_file=[FILE] 'Read the repository passphrase from this file'
+                          --passphrase_fd=[FD] 'Read the repository passphrase from this open file descriptor'
+                          --passphrase_command=[COMMAND] 'Read the repository passphrase from the output of this shell command'",
+        )
+        .arg(
+            Arg::from_usage(
+                "--repository=[NAME] 'Repository of the configuration file to use (default: HAT_REPOSITORY, or the default_repository of the file)'",
+            ).visible_alias("repo"),
         )
+        .subcommand(SubCommand::with_name("repositories").about(
+            "List the repositories of the configuration file",
+        ))
+        .subcommand(SubCommand::with_name("init").about(
+            "Create a new repository, or adopt one written by an older hat",
+        ))
         .subcommand(
             SubCommand::with_name("commit")
                 .about("Commit a new snapshot")
-                .args_from_usage(arg_template),
+                .args_from_usage(
+                    "<NAME> 'Name of the snapshot'
+                     [PATH] 'The path of the snapshot'
+                     --stdin 'Commit the data read from standard input as a single file instead'
+                     --name=[FILE] 'Name of the file read with --stdin (default: stdin)'
+                     --files_from=[LIST] 'Commit exactly the paths listed in this file, one per line (- for standard input)'
+                     --null 'Paths in the --files_from list are separated by NUL bytes instead of newlines'
+                     --max_file_size=[BYTES] 'Skip files larger than this'
+                     --max_commit_size=[BYTES] 'Skip files once this much new data has been stored'
+                     --abort_on_limit 'Fail instead of skipping files that exceed a limit'
+                     --inline_max=[BYTES] 'Store files up to this size inside their directory listing (default 2048)'
+                     --exclude=[PATTERN]... 'Do not commit files or directories matching these patterns, in addition to those configured'
+                     --fidelity=[LEVEL] 'Metadata to keep for this family from now on: content, permissions, ownership, extended or forensic'
+                     --resume_appends=[BOOL] 'From now on, take grown files of this family to be only appended to, and read just their new data (for logs; true or false)'
+                     --fanout=[N] 'Children per hash tree node for this and later snapshots (default 8)'
+                     --label=[LABEL]... 'Attach this label to the new snapshot'
+                     --checkpoint_interval=[SECS] 'Save progress this often, so that an interrupted commit resumes where it stopped (default 60; 0 saves only at the end)'
+                     --follow_symlinks 'Commit what symbolic links point to instead of the links'
+                     --one_file_system 'Do not descend into directories on other file systems, such as /proc or network mounts'
+                     --also=[NAME=PATH]... 'Commit this family from this path too, concurrently'
+                     --dry_run 'Report what would be read and uploaded, without storing anything'
+                     --nice 'Run in the background: lowest CPU and idle I/O priority, little read-ahead and one hashing thread'
+                     --metrics_listen=[ADDR] 'Serve Prometheus metrics on this address while committing'
+                     --metrics_textfile=[FILE] 'Write Prometheus metrics to this file when done, for the node exporter'",
+                ),
         )
         .subcommand(
             SubCommand::with_name("checkout")
                 .about("Checkout a snapshot")
-                .args_from_usage(arg_template),
+                .args_from_usage(arg_template)
+                .args_from_usage(
+                    "[OUTPUT] 'Output directory; PATH is then the file or directory of the snapshot to restore'
+                     --replica=[DIR]... 'Copy of the blob directory to read from when a blob is damaged'
+                     --first=[PATTERN]... 'Restore files matching these patterns before all others'
+                     --smallest_first 'Restore the smallest files first'
+                     --snapshot=[SNAPSHOT] 'Id or label of the snapshot to restore (default: the latest)'
+                     --include=[PATTERN]... 'Only restore files matching these patterns'
+                     --exclude=[PATTERN]... 'Do not restore files or directories matching these patterns'
+                     --overwrite 'Replace files that already exist in the output directory (default)'
+                     --skip_existing 'Keep files that already exist in the output directory'
+                     --only_newer 'Replace existing files only if the snapshot has a newer version'
+                     --backup_existing 'Rename existing files to NAME.~N~ before restoring'
+                     --jobs=[N] 'Number of files to restore at once (default 4)'
+                     --no_xattrs 'Do not restore extended attributes'
+                     --no_acls 'Do not restore POSIX ACLs'
+                     --no_owner 'Do not restore file owners, e.g. when not restoring as root'
+                     --numeric_ids 'Restore the recorded user and group ids instead of mapping their names to local ids'
+                     --chown_map=[RULE]... 'Restore files of a user or group as another, as user:FROM:TO or group:FROM:TO'
+                     --verify_only 'Read back the snapshot and compare it with the output directory instead of writing to it'",
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("export")
+                .about("Write a snapshot as an archive")
+                .args_from_usage(
+                    "<NAME> 'Name of the snapshot family'
+                     [OUTPUT] 'File to write the archive to (default: standard output)'
+                     --snapshot=[SNAPSHOT] 'Id or label of the snapshot to export (default: the latest)'
+                     --format=[FORMAT] 'Archive format; only tar is supported'",
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("cat")
+                .about("Write a file of a snapshot to standard output")
+                .args_from_usage(
+                    "<NAME> 'Name of the snapshot family'
+                     <SNAPSHOT> 'Id or label of the snapshot'
+                     <PATH> 'Path of the file in the snapshot'",
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("ls")
+                .about("List a directory of a snapshot")
+                .args_from_usage(
+                    "<NAME> 'Name of the snapshot family'
+                     [PATH] 'Directory of the snapshot to list (default: its root)'
+                     --snapshot=[SNAPSHOT] 'Id or label of the snapshot to list (default: the latest)'
+                     -l, --long 'Show the mode, size and modification time of each entry'
+                     -R, --recursive 'List everything below the directory too'
+                     --json 'Print the entries as a JSON array'",
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("find")
+                .about("Find files by name in all snapshots of a family")
+                .args_from_usage(
+                    "<NAME> 'Name of the snapshot family'
+                     <PATTERN> 'Wildcard pattern of the paths to find, e.g. *.conf or home/*/notes.txt'
+                     --min_size=[BYTES] 'Only find files of at least this size'
+                     --max_size=[BYTES] 'Only find files of at most this size'
+                     --newer=[DATE] 'Only find entries modified on or after this date (YYYY-MM-DD, UTC)'
+                     --older=[DATE] 'Only find entries modified before this date (YYYY-MM-DD, UTC)'
+                     --json 'Print the entries found as a JSON array'",
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("du")
+                .about("Show the space taken by each entry of a directory of a snapshot")
+                .args_from_usage(
+                    "<NAME> 'Name of the snapshot family'
+                     [PATH] 'Directory of the snapshot to report on (default: its root)'
+                     --snapshot=[SNAPSHOT] 'Id or label of the snapshot (default: the latest)'
+                     --json 'Print the entries as a JSON array'",
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("mount")
+                .about("Serve the snapshots as a read-only file system until it is unmounted")
+                .args_from_usage("<MOUNTPOINT> 'Empty directory to mount the snapshots on'"),
+        )
+        .subcommand(
+            SubCommand::with_name("web")
+                .about("Serve a local web page to browse snapshots and restore files from them")
+                .args_from_usage(
+                    "--listen=[ADDR] 'Address to serve on (default: 127.0.0.1:8080); other than loopback addresses need a token in HAT_WEB_TOKEN'
+                     --restore_to=[DIR] 'Directory to restore files into (default: restored)'",
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("import")
+                .about("Commit a new snapshot from an archive read from standard input")
+                .args_from_usage(
+                    "<NAME> 'Name of the snapshot family'
+                     --format=[FORMAT] 'Archive format; only tar is supported'
+                     --label=[LABEL]... 'Attach this label to the new snapshot'",
+                ),
         )
         .subcommand(SubCommand::with_name("recover").about(
             "Recover list of commit'ed snapshots",
@@ -80,20 +517,213 @@ fn main() {
                 .about("Delete a snapshot")
                 .args_from_usage(
                     "<NAME> 'Name of the snapshot family'
-                                                        \
-                              <ID> 'The snapshot id to delete'",
+                     <ID> 'The snapshot id to delete'
+                     --force 'Delete the snapshot even if a restore of it seems to be running'",
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("forget")
+                .about("Deregister the snapshots of a family that a retention policy does not keep")
+                .args_from_usage(
+                    "<NAME> 'Name of the snapshot family'
+                     --keep_last=[N] 'Keep the N newest snapshots'
+                     --keep_daily=[N] 'Keep the newest snapshot of each of the last N days'
+                     --keep_weekly=[N] 'Keep the newest snapshot of each of the last N weeks'
+                     --keep_monthly=[N] 'Keep the newest snapshot of each of the last N months'
+                     --keep_yearly=[N] 'Keep the newest snapshot of each of the last N years'
+                     --label=[LABEL] 'Only apply the policy to snapshots with this label'
+                     --dry_run 'Only list the snapshots that would be forgotten'",
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("family")
+                .about("Rename or delete a snapshot family")
+                .subcommand(
+                    SubCommand::with_name("rename")
+                        .about("Give a family a new name, keeping its snapshots")
+                        .args_from_usage(
+                            "<NAME> 'Name of the snapshot family'
+                             <NEW_NAME> 'The new name'",
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("delete")
+                        .about("Delete a family and all of its snapshots")
+                        .args_from_usage(
+                            "<NAME> 'Name of the snapshot family'
+                             --gc 'Garbage collect the deleted data right away'",
+                        ),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("label")
+                .about("Attach a label to a snapshot, or detach it")
+                .args_from_usage(
+                    "<NAME> 'Name of the snapshot family'
+                     <ID> 'The snapshot id'
+                     <LABEL> 'The label, which can then select the snapshot in place of its id'
+                     --remove 'Detach the label instead'",
                 ),
         )
         .subcommand(
             SubCommand::with_name("gc")
                 .about("Garbage collect: identify and remove unused data blocks.")
-                .args_from_usage("-p --pretend 'Do not modify any data'"),
+                .args_from_usage(
+                    "-p --pretend 'Only report what would be reclaimed, and which snapshots hold the most'
+                     --concurrent 'Let commits run meanwhile; deletions wait for the next run'
+                     --json 'Print what was collected as a JSON object'
+                     --grace_hours=[HOURS] 'Only delete blobs that were already unused this long ago; newly unused ones are condemned'
+                     --deletes_per_sec=[N] 'Start at most this many blob deletes per second'
+                     --delete_concurrency=[N] 'Run this many blob deletes at once'
+                     --mark_sweep 'Find unused data by walking every snapshot instead of trusting the reference counts, and repair them; with --pretend, only compare the two'",
+
+                ),
         )
         .subcommand(SubCommand::with_name("resume").about(
             "Resume previous failed command.",
         ))
+        .subcommand(SubCommand::with_name("pause").about(
+            "Pause the commits running on this host, after their current uploads (or send SIGUSR1)",
+        ))
+        .subcommand(SubCommand::with_name("unpause").about(
+            "Continue the commits paused on this host (or send SIGUSR2)",
+        ))
+        .subcommand(
+            SubCommand::with_name("locks")
+                .about("List the locks held on the repository")
+                .args_from_usage("--json 'Print the locks as a JSON array'"),
+        )
+        .subcommand(
+            SubCommand::with_name("break-lock")
+                .about("Remove the locks whose holders are gone")
+                .args_from_usage(
+                    "--force 'Also remove locks held by processes that may still be running'",
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("patch")
+                .about("Write the data needed to go from one snapshot to another")
+                .args_from_usage(
+                    "<NAME> 'Name of the snapshot family'
+                     <ID> 'The snapshot id to patch to'
+                     <FILE> 'Where to write the patch bundle'
+                     --from=[FROM] 'The snapshot id the receiver already has'",
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("apply-patch")
+                .about("Apply a patch bundle to this repository")
+                .args_from_usage("<FILE> 'The patch bundle to apply'"),
+        )
+        .subcommand(
+            SubCommand::with_name("send")
+                .about("Write the data needed to advance a copy of a family to a snapshot")
+                .args_from_usage(
+                    "<NAME> 'Name of the snapshot family'
+                     <FROM> 'Id or label of the snapshot the receiver already has (0 if none)'
+                     <TO> 'Id or label of the snapshot to send'
+                     --output=[FILE] 'Where to write the bundle (default: standard output)'",
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("receive")
+                .about("Apply a bundle written by send to this repository")
+                .args_from_usage("[FILE] 'The bundle to apply (default: standard input)'"),
+        )
+        .subcommand(
+            SubCommand::with_name("copy")
+                .about("Copy snapshots to another repository, skipping data it already has")
+                .args_from_usage(
+                    "--to=[DIR] 'Blob directory of the destination repository'
+                     --to_cache=[DIR] 'Local state directory of the destination repository'
+                     --to_repo=[NAME] 'Destination repository of the configuration file, instead of --to and --to_cache'
+                     --from=[DIR] 'Blob directory of this repository (default: blobs)'
+                     --family=[NAME] 'Only copy the snapshots of this family'",
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("stats")
+                .about(
+                    "Show the size of the repository, its dedup ratio, and a summary of each \
+                     family.",
+                )
+                .args_from_usage("--json 'Print the statistics as a JSON object'"),
+        )
+        .subcommand(SubCommand::with_name("whoami").about(
+            "Show what the current key material allows.",
+        ))
+        .subcommand(
+            SubCommand::with_name("snapshots")
+                .about("List snapshots with their size, duration and status.")
+                .args_from_usage(
+                    "--export_prometheus 'Print the age and outcome of the latest backups as Prometheus metrics'
+                     --show_roots 'Print the root digest of each snapshot'
+                     --show_provenance 'Print where and how each snapshot was made'
+                     --json 'Print the snapshots as a JSON array'",
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("diff")
+                .about("List the files that changed between two snapshots")
+                .args_from_usage(
+                    "<NAME> 'Name of the snapshot family'
+                     <FROM> 'The older snapshot id or label'
+                     <TO> 'The newer snapshot id or label'
+                     --json 'Print the changes as a JSON array'",
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("status")
+                .about("List the files that changed since the latest snapshot")
+                .args_from_usage(
+                    "<NAME> 'Name of the snapshot family'
+                     <PATH> 'The directory that was committed'",
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("verify")
+                .about("Read back a snapshot and check its data against its root digest")
+                .args_from_usage(
+                    "<NAME> 'Name of the snapshot family'
+                     <ID> 'The snapshot id or label to verify'
+                     --root=[DIGEST] 'The root digest the snapshot must have'",
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("check")
+                .about("Cross-check the indexes, snapshot trees and stored blobs of the repository")
+                .args_from_usage(
+                    "--read_data=[PERCENT] 'Also read back and verify this share of all chunks (e.g. 10%)'
+                     --json 'Print the report as a JSON object'",
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("scrub")
+                .about("Verify the least recently checked blobs, rotating through the repository")
+                .args_from_usage(
+                    "--budget=[SIZE] 'Read about this much data (default: 1GiB)'
+                     --json 'Print the report as a JSON object'",
+                ),
+        )
+        .subcommand(SubCommand::with_name("repair").about(
+            "Adopt blobs missing from the index, drop references to lost data and label the \
+             snapshots that lost files.",
+        ))
+        .subcommand(
+            SubCommand::with_name("index")
+                .about("Maintain the local index files")
+                .subcommand(SubCommand::with_name("vacuum").about(
+                    "Check, reindex and vacuum all indexes and report their sizes.",
+                )),
+        )
         .get_matches();
 
+    init_logging(matches.occurrences_of("verbose"));
+
+    // Commits can be paused with `hat pause` or SIGUSR1, and resumed with SIGUSR2. Every
+    // command handles them, as `hat pause` signals all holders of a lock.
+    hat::hat::handle_pause_signals();
+
     // Check for license flag
     if matches.is_present("license") {
         license();
@@ -107,88 +737,1768 @@ fn main() {
             .or_else(|| {
                 env::var_os(name.to_uppercase()).map(|s| s.into_string().unwrap())
             })
-            .expect(&format!("{} required", name))
     };
 
+    // Flags and the environment take precedence over the configuration file, which need not
+    // exist unless it is named.
+    let config = match matches.value_of("config") {
+        Some(file) => hat::hat::Config::load(Path::new(file)).unwrap(),
+        None => {
+            match hat::hat::Config::default_path() {
+                Some(ref file) if file.exists() => hat::hat::Config::load(file).unwrap(),
+                _ => hat::hat::Config::default(),
+            }
+        }
+    };
+    let repository_name = matches
+        .value_of("repository")
+        .map(|x| x.to_string())
+        .or_else(|| env::var_os("HAT_REPOSITORY").map(|s| s.into_string().unwrap()));
+    if matches.subcommand_name() == Some("repositories") {
+        for (name, repo) in &config.repositories {
+            let default = if config.default_name() == Some(&name[..]) { "*" } else { " " };
+            let blob_dir = repo.blob_dir.as_ref().map(|d| d.display().to_string());
+            println!("{} {:16} {}", default, name, blob_dir.unwrap_or_else(|| "-".to_string()));
+        }
+        return;
+    }
+    let repository = config.repository(repository_name.as_ref().map(|s| &s[..])).unwrap();
+
     // Setup config variables that can take their value from either flag or environment.
-    let migrations_dir_str = flag_or_env("hat_migrations_dir");
+    let migrations_dir_str = matches
+        .value_of("hat_migrations_dir")
+        .map(|x| x.to_string())
+        .or_else(|| env::var_os("HAT_MIGRATIONS_DIR").map(|s| s.into_string().unwrap()))
+        .unwrap_or_default();
     let migrations_dir = Path::new(&migrations_dir_str);
-    let cache_dir = PathBuf::from(flag_or_env("hat_cache_dir"));
+    let cache_dir = flag_or_env("hat_cache_dir")
+        .map(PathBuf::from)
+        .or_else(|| repository.cache_dir.clone())
+        .expect("hat_cache_dir required");
+    let hash_algorithm = matches
+        .value_of("hash_algorithm")
+        .or_else(|| repository.hash_algorithm.as_ref().map(|s| &s[..]))
+        .map(|s| s.parse::<hat::hat::HashAlgorithm>().unwrap());
+    let namespace = matches
+        .value_of("namespace")
+        .map(|x| x.to_string())
+        .or_else(|| env::var_os("HAT_NAMESPACE").map(|s| s.into_string().unwrap()))
+        .or_else(|| repository.namespace.clone());
+    let allow_unsigned = matches.is_present("allow_unsigned");
+    let blob_dir = repository.blob_dir.clone().unwrap_or_else(|| PathBuf::from("blobs"));
+    // A repository created append-only stays so, except for privileged clients.
+    let privileged = matches.is_present("privileged");
+    let created_append_only = hat::hat::Manifest::load(&backend::FileBackend::new(blob_dir.clone()))
+        .ok()
+        .and_then(|m| m)
+        .map_or(false, |m| m.append_only);
+    let append_only = AppendOnly {
+        enforcing: !privileged &&
+            (matches.is_present("append_only") || env::var_os("HAT_APPEND_ONLY").is_some() ||
+                 repository.append_only || created_append_only),
+        privileged: privileged,
+    };
+    let max_blob_size = repository.max_blob_size.unwrap_or(hat::hat::DEFAULT_MAX_BLOB_SIZE);
+    let events_out: Option<Box<Write + Send>> =
+        match (matches.value_of("events_fd"), matches.value_of("events_file")) {
+            (Some(fd), _) => Some(Box::new(unsafe { fs::File::from_raw_fd(fd.parse().unwrap()) })),
+            (None, Some(file)) => Some(Box::new(fs::File::create(file).unwrap())),
+            (None, None) => None,
+        };
+    let events = events_out.map(|out| {
+        Arc::new(hat::hat::EventLog::new(out, Arc::new(hat::hat::TerminalProgress::new())))
+    });
+    let passphrase_source = if let Some(fd) = matches.value_of("passphrase_fd") {
+        Some(hat::hat::PassphraseSource::Fd(fd.parse().unwrap()))
+    } else if let Some(file) = matches.value_of("passphrase_file") {
+        Some(hat::hat::PassphraseSource::File(PathBuf::from(file)))
+    } else if let Some(command) = matches.value_of("passphrase_command") {
+        Some(hat::hat::PassphraseSource::Command(command.to_string()))
+    } else if env::var_os("HAT_PASSPHRASE").is_some() {
+        Some(hat::hat::PassphraseSource::Env("HAT_PASSPHRASE".to_string()))
+    } else if let Some(ref file) = repository.passphrase_file {
+        Some(hat::hat::PassphraseSource::File(file.clone()))
+    } else {
+        repository.passphrase_command.clone().map(hat::hat::PassphraseSource::Command)
+    };
+    let passphrase = passphrase_source.map(|source| source.read().unwrap());
+    let passphrase = passphrase.as_ref().map(|p| p.unsecure());
 
     // Initialize sodium (must only be called once)
     unsafe { libsodium_sys::sodium_init() };
 
     match matches.subcommand() {
+        ("init", Some(_cmd)) => {
+            fs::create_dir_all(&blob_dir).unwrap();
+            let backend = blob_backend(&blob_dir, append_only);
+            let (_hat, manifest) = hat::Hat::init_repository(
+                migrations_dir,
+                cache_dir,
+                backend,
+                max_blob_size,
+                hash_algorithm,
+                passphrase,
+            ).unwrap();
+            println!("Initialized repository in {}", blob_dir.display());
+            println!("  Format version: {}", manifest.format_version);
+            println!("  Hash algorithm: {}", manifest.hash_algorithm);
+            println!("  Chunking: {}", manifest.chunking);
+            println!("  Compression: {}", manifest.compression);
+            println!("  Encryption: {}", manifest.encryption);
+            if manifest.append_only {
+                println!("  Append-only: only privileged clients may delete data");
+            }
+        }
         ("resume", Some(_cmd)) => {
             // Setting up the repository triggers automatic resume.
-            let backend = Arc::new(backend::FileBackend::new(blob_dir()));
-            hat::Hat::open_repository(migrations_dir, cache_dir, backend, MAX_BLOB_SIZE).unwrap();
+            let backend = blob_backend(&blob_dir, append_only);
+            let _lock = lock_repository(&backend, &cache_dir, hat::hat::LockKind::Shared);
+            hat::Hat::open_repository(
+                migrations_dir,
+                cache_dir,
+                backend,
+                max_blob_size,
+                hash_algorithm,
+                passphrase,
+            ).unwrap();
         }
         ("commit", Some(cmd)) => {
-            let name = cmd.value_of("NAME").unwrap().to_owned();
+            let path = cmd.value_of("PATH");
+            let files_from = cmd.value_of("files_from");
+            let inputs = [path.is_some(), cmd.is_present("stdin"), files_from.is_some()];
+            if inputs.iter().filter(|x| **x).count() != 1 {
+                println!("Give one of a PATH, --stdin or --files_from");
+                std::process::exit(1);
+            }
+            let listed = files_from.map(|file| {
+                let nul = cmd.is_present("null");
+                if file == "-" {
+                    read_path_list(io::stdin(), nul).unwrap()
+                } else {
+                    read_path_list(fs::File::open(file).unwrap(), nul).unwrap()
+                }
+            });
+            let mut sources = vec![(cmd.value_of("NAME").unwrap(), path)];
+            for source in cmd.values_of("also").into_iter().flat_map(|v| v) {
+                match source.find('=') {
+                    Some(i) => sources.push((&source[..i], Some(&source[i + 1..]))),
+                    None => {
+                        println!("Expected NAME=PATH, not: {}", source);
+                        std::process::exit(1);
+                    }
+                }
+            }
+
+            // Lowered before the repository is opened, for the threads it starts to inherit it.
+            if cmd.is_present("nice") {
+                if let Err(e) = hat::hat::lower_priority() {
+                    println!("Could not lower the priority of this process: {}", e);
+                }
+            }
+
+            let backend = blob_backend(&blob_dir, append_only);
+            let backend_errors = backend.errors();
+            // Locked before opening the indexes, for `gc_concurrent` to see how old it is.
+            let lock = lock_repository(&backend, &cache_dir, hat::hat::LockKind::Shared);
+            let mut hat = hat::Hat::open_repository(
+                migrations_dir,
+                cache_dir,
+                backend,
+                max_blob_size,
+                hash_algorithm,
+                passphrase,
+            ).unwrap();
+            hat.watch_lock(&lock);
+            hat.set_namespace(namespace).unwrap();
+            hat.set_allow_unsigned(allow_unsigned);
+
+            if cmd.is_present("nice") {
+                hat.set_hashing_threads(1);
+            }
+            hat.set_limits(hat::hat::Limits {
+                max_file_size: cmd.value_of("max_file_size").map(|s| s.parse().unwrap()),
+                max_commit_bytes: cmd.value_of("max_commit_size").map(|s| s.parse().unwrap()),
+                abort: cmd.is_present("abort_on_limit"),
+            });
+            hat.set_inline_max(Some(
+                cmd.value_of("inline_max")
+                    .map(|s| s.parse().unwrap())
+                    .or(repository.inline_max)
+                    .unwrap_or(2048),
+            ));
+            let fanout = cmd.value_of("fanout").map(|s| s.parse().unwrap()).or(repository.fanout);
+            if let Some(fanout) = fanout {
+                hat.set_fanout(fanout).unwrap();
+            }
+            // Paths in a snapshot are absolute, but kept without the leading `/`.
+            let excludes: Vec<hat::hat::Pattern> = repository
+                .exclude
+                .iter()
+                .map(|p| &p[..])
+                .chain(cmd.values_of("exclude").into_iter().flat_map(|v| v))
+                .map(|p| hat::hat::Pattern::new(p.trim_left_matches('/')))
+                .collect();
+
+            if cmd.is_present("dry_run") {
+                for &(name, path) in &sources {
+                    let path = match path {
+                        Some(path) => path,
+                        None => {
+                            println!("--dry_run needs a PATH");
+                            drop(lock);
+                            std::process::exit(1);
+                        }
+                    };
+                    let name = hat.family_name(name);
+                    let options = hat::hat::ScanOptions {
+                        one_file_system: cmd.is_present("one_file_system"),
+                        follow_symlinks: cmd.is_present("follow_symlinks"),
+                        excludes: excludes.clone(),
+                    };
+                    let status = hat.commit_dry_run(&name, Path::new(path), &options).unwrap();
+                    for c in &status.changes {
+                        let kind = match c.kind {
+                            hat::hat::ChangeKind::Added => "added",
+                            hat::hat::ChangeKind::Removed => "removed",
+                            hat::hat::ChangeKind::Modified => "modified",
+                        };
+                        println!("{:8} {}", kind, c.path.display());
+                    }
+                    for path in &status.skipped {
+                        println!("{:8} {}", "skipped", path.display());
+                    }
+                    println!(
+                        "{}: {} changes; would read {} bytes in {} chunks and upload {} of them \
+                         ({} bytes)",
+                        name,
+                        status.changes.len(),
+                        status.read_bytes,
+                        status.chunks,
+                        status.upload_chunks,
+                        status.upload_bytes
+                    );
+                }
+                return;
+            }
+
+            // With several sources, the variables list them one per line, in the same order.
+            let family_names: Vec<String> =
+                sources.iter().map(|&(name, _)| hat.family_name(name)).collect();
+            let paths: Vec<&str> = sources.iter().map(|&(_, path)| path.unwrap_or("-")).collect();
+            let mut hook_env = vec![
+                ("HAT_FAMILY", family_names.join("\n")),
+                ("HAT_PATH", paths.join("\n")),
+            ];
+            if let Some(quota) = repository.quota {
+                // Each family is expected to grow by as much as it did the last time.
+                let growth: u64 = sources
+                    .iter()
+                    .map(|&(name, _)| {
+                        let name = hat.family_name(name);
+                        hat.expected_growth(&name)
+                    })
+                    .sum();
+                let status = hat.quota_status(&quota, growth).unwrap();
+                if status.is_exceeded() {
+                    let error = format!(
+                        "The repository uses {} bytes and is expected to grow by {}, exceeding \
+                         its quota of {} bytes by {}; forget snapshots and run gc",
+                        status.used_bytes,
+                        growth,
+                        status.max_bytes,
+                        status.excess_bytes()
+                    );
+                    println!("{}", error);
+                    if !quota.warn_only {
+                        run_on_error_hook(&repository.hooks, hook_env, &error);
+                        drop(lock);
+                        std::process::exit(1);
+                    }
+                }
+            }
+            if let Err(e) = repository.hooks.run(hat::hat::Hook::PreCommit, &hook_env) {
+                println!("{}", e);
+                run_on_error_hook(&repository.hooks, hook_env, &e.to_string());
+                drop(lock);
+                std::process::exit(1);
+            }
+
+            // The files kept by the latest snapshots estimate how much this commit reads.
+            let snapshots = hat.list_snapshots();
+            let estimate: u64 = sources
+                .iter()
+                .map(|&(name, _)| {
+                    let name = hat.family_name(name);
+                    snapshots
+                        .iter()
+                        .rev()
+                        .find(|s| s.family == name)
+                        .and_then(|s| s.contents)
+                        .map_or(0, |c| c.bytes)
+                })
+                .sum();
+            let progress = Arc::new(
+                hat::hat::RunMetrics::new(reporter(&events)).with_backend_errors(backend_errors),
+            );
+            hat.set_progress(progress.clone());
+            let backup_metrics = Arc::new(Mutex::new(
+                hat.backup_metrics().to_prometheus(chrono::Utc::now()),
+            ));
+
+            // From here on, failures still run the `on_error` hook and release the lock.
+            let mut names = vec![];
+            let mut families = vec![];
+            let committed = (|| -> Result<(), String> {
+                if let Some(addr) = cmd.value_of("metrics_listen") {
+                    hat::hat::serve_metrics(addr, progress.clone(), backup_metrics.clone())
+                        .map_err(|e| format!("Could not serve metrics on {}: {}", addr, e))?;
+                }
+                progress.start("Committing", if estimate > 0 { Some(estimate) } else { None });
+
+                // Update the family indexes, all at once.
+                let mut dirs = vec![];
+                for &(name, path) in &sources {
+                    let name = hat.family_name(name);
+                    if let Some(level) = cmd.value_of("fidelity") {
+                        hat.set_fidelity(&name, level.parse::<hat::hat::Fidelity>()?);
+                    }
+                    if let Some(resume) = cmd.value_of("resume_appends") {
+                        let resume = resume.parse::<bool>().map_err(|e| e.to_string())?;
+                        hat.set_resume_appends(&name, resume);
+                    }
+                    let mut family = hat.open_family(name.clone())
+                        .map_err(|e| format!("Could not open family '{}': {}", name, e))?;
+                    family.one_file_system = cmd.is_present("one_file_system");
+                    family.excludes = excludes.clone();
+                    family.follow_symlinks = cmd.is_present("follow_symlinks");
+                    family.low_impact = cmd.is_present("nice");
+                    if let Some(secs) = cmd.value_of("checkpoint_interval") {
+                        let secs = secs.parse().map_err(|_| "Invalid checkpoint interval")?;
+                        family.checkpoint_interval = match secs {
+                            0 => None,
+                            secs => Some(time::Duration::seconds(secs)),
+                        };
+                    }
+                    match path {
+                        Some(path) => dirs.push((family.clone(), PathBuf::from(path))),
+                        None if listed.is_some() => {
+                            family.snapshot_paths(listed.clone().unwrap())
+                                .map_err(|e| e.to_string())?;
+                        }
+                        None => {
+                            let file = cmd.value_of("name").unwrap_or("stdin");
+                            family.snapshot_stream(file.as_bytes().to_vec(), io::stdin())
+                                .map_err(|e| e.to_string())?;
+                        }
+                    }
+                    if !names.contains(&name) {
+                        names.push(name);
+                        family.provenance = Some(provenance(path.unwrap_or("-")));
+                        family.labels = cmd.values_of("label")
+                            .into_iter()
+                            .flat_map(|v| v)
+                            .map(|l| l.to_string())
+                            .collect();
+                        families.push(family);
+                    }
+                }
+                hat::hat::snapshot_dirs(dirs).map_err(|e| e.to_string())?;
+                progress.finish();
+
+                // Commit the updated indexes, one snapshot per family.
+                for family in &mut families {
+                    hat.commit(family, None).map_err(|e| e.to_string())?;
+                }
+
+                // Meta commit.
+                hat.meta_commit().map_err(|e| e.to_string())?;
+
+                // Flush any remaining blobs.
+                hat.data_flush().map_err(|e| e.to_string())?;
+
+                if let Some(ref events) = events {
+                    let snapshots = hat.list_snapshots();
+                    for name in &names {
+                        if let Some(s) = snapshots.iter().rev().find(|s| s.family == *name) {
+                            events.emit(&hat::hat::Event::SnapshotCommitted {
+                                family: name.clone(),
+                                snapshot_id: s.snapshot_id,
+                            });
+                        }
+                    }
+                    for e in families.iter().flat_map(|f| f.errors.lock().unwrap().clone()) {
+                        events.emit(&hat::hat::Event::Error {
+                            path: Some(e.path.to_string_lossy().into_owned()),
+                            error: e.error,
+                        });
+                    }
+                }
+
+                *backup_metrics.lock().unwrap() =
+                    hat.backup_metrics().to_prometheus(chrono::Utc::now());
+                if let Some(file) = cmd.value_of("metrics_textfile") {
+                    let text =
+                        format!("{}{}", backup_metrics.lock().unwrap(), progress.to_prometheus());
+                    hat::hat::write_textfile(Path::new(file), &text)
+                        .map_err(|e| format!("Could not write {}: {}", file, e))?;
+                }
+                Ok(())
+            })();
+            if let Err(e) = committed {
+                println!("{}", e);
+                run_on_error_hook(&repository.hooks, hook_env, &e);
+                drop(lock);
+                std::process::exit(1);
+            }
+
+            let snapshots = hat.list_snapshots();
+            let snapshot_ids: Vec<String> = family_names
+                .iter()
+                .map(|name| {
+                    snapshots
+                        .iter()
+                        .rev()
+                        .find(|s| s.family == *name)
+                        .map_or("-".to_string(), |s| s.snapshot_id.to_string())
+                })
+                .collect();
+            hook_env.push(("HAT_SNAPSHOT_ID", snapshot_ids.join("\n")));
+            if let Err(e) = repository.hooks.run(hat::hat::Hook::PostCommit, &hook_env) {
+                println!("{}", e);
+                run_on_error_hook(&repository.hooks, hook_env.clone(), &e.to_string());
+                drop(lock);
+                std::process::exit(1);
+            }
+
+            let errors: Vec<hat::hat::FileError> = families
+                .iter()
+                .flat_map(|f| f.errors.lock().unwrap().clone())
+                .collect();
+            if !errors.is_empty() {
+                println!("Could not read {} files; the snapshot is partial:", errors.len());
+                for e in &errors {
+                    println!("  {}: {}", e.path.display(), e.error);
+                }
+                let error = format!("Could not read {} files", errors.len());
+                run_on_error_hook(&repository.hooks, hook_env, &error);
+                // Exiting skips destructors, so release the lock first.
+                drop(lock);
+                std::process::exit(EXIT_PARTIAL);
+            }
+        }
+        ("checkout", Some(cmd)) => {
             let path = cmd.value_of("PATH").unwrap();
 
-            let backend = Arc::new(backend::FileBackend::new(blob_dir()));
-            let mut hat =
-                hat::Hat::open_repository(migrations_dir, cache_dir, backend, MAX_BLOB_SIZE)
-                    .unwrap();
+            let mut chown_map = hat::hat::ChownMap::default();
+            for rule in cmd.values_of("chown_map").into_iter().flat_map(|v| v) {
+                if let Err(e) = chown_map.add(rule) {
+                    println!("{}", e);
+                    std::process::exit(1);
+                }
+            }
 
-            // Update the family index.
+            let mut sources = vec![backend::FileBackend::new(blob_dir.clone())];
+            for dir in cmd.values_of("replica").into_iter().flat_map(|v| v) {
+                sources.push(backend::FileBackend::new(PathBuf::from(dir)));
+            }
+            for dir in &repository.replicas {
+                sources.push(backend::FileBackend::new(dir.clone()));
+            }
+            let backend = Arc::new(backend::MirrorBackend::new(sources));
+            let lock = lock_repository(&backend, &cache_dir, hat::hat::LockKind::Shared);
+            let mut hat = hat::Hat::open_repository(
+                migrations_dir,
+                cache_dir,
+                backend.clone(),
+                max_blob_size,
+                hash_algorithm,
+                passphrase,
+            ).unwrap();
+            hat.set_namespace(namespace).unwrap();
+            hat.set_allow_unsigned(allow_unsigned);
+            hat.set_progress(reporter(&events));
+            let name = hat.family_name(cmd.value_of("NAME").unwrap());
+
+            let patterns: Vec<hat::hat::Pattern> = cmd.values_of("first")
+                .into_iter()
+                .flat_map(|v| v)
+                .map(hat::hat::Pattern::new)
+                .collect();
+            let order = if !patterns.is_empty() {
+                hat::hat::RestoreOrder::Patterns(patterns)
+            } else if cmd.is_present("smallest_first") {
+                hat::hat::RestoreOrder::SmallestFirst
+            } else {
+                hat::hat::RestoreOrder::Listing
+            };
+            let (subpath, output) = match cmd.value_of("OUTPUT") {
+                Some(output) => (Some(PathBuf::from(path)), PathBuf::from(output)),
+                None => (None, PathBuf::from(path)),
+            };
+            // Paths in a snapshot are relative to its root.
+            let patterns = |arg: &str| -> Vec<hat::hat::Pattern> {
+                cmd.values_of(arg)
+                    .into_iter()
+                    .flat_map(|v| v)
+                    .map(|p| hat::hat::Pattern::new(p.trim_left_matches('/')))
+                    .collect()
+            };
+            let options = hat::hat::RestoreOptions {
+                order: order,
+                subpath: subpath,
+                include: patterns("include"),
+                exclude: patterns("exclude"),
+                conflict: if cmd.is_present("skip_existing") {
+                    hat::hat::ConflictPolicy::SkipExisting
+                } else if cmd.is_present("only_newer") {
+                    hat::hat::ConflictPolicy::OnlyNewer
+                } else if cmd.is_present("backup_existing") {
+                    hat::hat::ConflictPolicy::BackupExisting
+                } else {
+                    hat::hat::ConflictPolicy::Overwrite
+                },
+                jobs: cmd.value_of("jobs").map_or(4, |n| n.parse().unwrap()),
+                skip_xattrs: cmd.is_present("no_xattrs"),
+                skip_acls: cmd.is_present("no_acls"),
+                skip_owner: cmd.is_present("no_owner"),
+                numeric_ids: cmd.is_present("numeric_ids"),
+                chown_map: chown_map,
+            };
+            let id = cmd.value_of("snapshot").map(|selector| {
+                hat.resolve_snapshot(&name, selector).unwrap()
+            });
+            if cmd.is_present("verify_only") {
+                let check = hat.verify_restore(&name, id, &output, &options).unwrap();
+                for m in &check.mismatches {
+                    let what = match m.kind {
+                        hat::hat::MismatchKind::Missing => "missing".to_string(),
+                        hat::hat::MismatchKind::WrongType => "wrong file type".to_string(),
+                        hat::hat::MismatchKind::Content => "content differs".to_string(),
+                        hat::hat::MismatchKind::Unreadable(ref e) => format!("unreadable: {}", e),
+                        hat::hat::MismatchKind::Permissions => "permissions differ".to_string(),
+                        hat::hat::MismatchKind::ModifiedTime => {
+                            "modification time differs".to_string()
+                        }
+                    };
+                    println!("{}: {}", output.join(&m.path).display(), what);
+                }
+                println!(
+                    "{} files ({} bytes) checked; {} mismatches",
+                    check.files,
+                    check.bytes,
+                    check.mismatches.len()
+                );
+                if !check.mismatches.is_empty() {
+                    // Exiting skips destructors, so release the lock first.
+                    drop(lock);
+                    std::process::exit(1);
+                }
+            } else {
+                let mut hook_env = vec![
+                    ("HAT_FAMILY", name.clone()),
+                    ("HAT_PATH", output.display().to_string()),
+                ];
+                if let Some(id) = id {
+                    hook_env.push(("HAT_SNAPSHOT_ID", id.to_string()));
+                }
+                let res = repository
+                    .hooks
+                    .run(hat::hat::Hook::PreRestore, &hook_env)
+                    .and_then(|()| hat.checkout(name, id, output, &options));
+                if let Err(e) = res {
+                    println!("{}", e);
+                    run_on_error_hook(&repository.hooks, hook_env, &e.to_string());
+                    drop(lock);
+                    std::process::exit(1);
+                }
+            }
+
+            for (i, count) in backend.served_counts().into_iter().enumerate().skip(1) {
+                if count > 0 {
+                    println!("{} blobs were read from replica {}", count, i);
+                }
+            }
+        }
+        ("cat", Some(cmd)) => {
+            let backend = blob_backend(&blob_dir, append_only);
+            let _lock = lock_repository(&backend, &cache_dir, hat::hat::LockKind::Shared);
+            let mut hat = hat::Hat::open_repository(
+                migrations_dir,
+                cache_dir,
+                backend,
+                max_blob_size,
+                hash_algorithm,
+                passphrase,
+            ).unwrap();
+            hat.set_namespace(namespace).unwrap();
+            hat.set_allow_unsigned(allow_unsigned);
+            let name = hat.family_name(cmd.value_of("NAME").unwrap());
+
+            let id = hat.resolve_snapshot(&name, cmd.value_of("SNAPSHOT").unwrap()).unwrap();
+            let path = Path::new(cmd.value_of("PATH").unwrap());
+            let stdout = io::stdout();
+            hat.cat(&name, Some(id), path, io::BufWriter::new(stdout.lock())).unwrap();
+        }
+        ("ls", Some(cmd)) => {
+            let backend = blob_backend(&blob_dir, append_only);
+            let _lock = lock_repository(&backend, &cache_dir, hat::hat::LockKind::Shared);
+            let mut hat = hat::Hat::open_repository(
+                migrations_dir,
+                cache_dir,
+                backend,
+                max_blob_size,
+                hash_algorithm,
+                passphrase,
+            ).unwrap();
+            hat.set_namespace(namespace).unwrap();
+            hat.set_allow_unsigned(allow_unsigned);
+            let name = hat.family_name(cmd.value_of("NAME").unwrap());
+
+            let id = cmd.value_of("snapshot").map(|selector| {
+                hat.resolve_snapshot(&name, selector).unwrap()
+            });
+            let path = Path::new(cmd.value_of("PATH").unwrap_or("/"));
+            let entries = hat.list_dir(&name, id, path, cmd.is_present("recursive")).unwrap();
+            if cmd.is_present("json") {
+                println!("{}", serde_json::to_string(&entries).unwrap());
+                return;
+            }
+            for e in entries {
+                let target = match e.kind {
+                    hat::hat::EntryKind::Symlink(ref target) => format!(" -> {}", target.display()),
+                    _ => String::new(),
+                };
+                if cmd.is_present("long") {
+                    let modified = e.modified.map_or("-".to_string(), |secs| {
+                        let time = chrono::NaiveDateTime::from_timestamp(secs as i64, 0);
+                        time.format("%Y-%m-%d %H:%M").to_string()
+                    });
+                    println!(
+                        "{} {:>12} {:16} {}{}",
+                        mode_string(&e),
+                        e.size.map_or("-".to_string(), |s| s.to_string()),
+                        modified,
+                        e.path.display(),
+                        target
+                    );
+                } else {
+                    println!("{}{}", e.path.display(), target);
+                }
+            }
+        }
+        ("find", Some(cmd)) => {
+            let date = |arg: &str| {
+                cmd.value_of(arg).map(|s| {
+                    let day = chrono::NaiveDate::parse_from_str(s, "%Y-%m-%d").unwrap();
+                    day.and_hms(0, 0, 0).timestamp() as u64
+                })
+            };
+            let filter = hat::hat::FindFilter {
+                min_size: cmd.value_of("min_size").map(|s| s.parse().unwrap()),
+                max_size: cmd.value_of("max_size").map(|s| s.parse().unwrap()),
+                modified_after: date("newer"),
+                modified_before: date("older"),
+            };
+            let pattern = hat::hat::Pattern::new(cmd.value_of("PATTERN").unwrap());
+
+            let backend = blob_backend(&blob_dir, append_only);
+            let _lock = lock_repository(&backend, &cache_dir, hat::hat::LockKind::Shared);
+            let mut hat = hat::Hat::open_repository(
+                migrations_dir,
+                cache_dir,
+                backend,
+                max_blob_size,
+                hash_algorithm,
+                passphrase,
+            ).unwrap();
+            hat.set_namespace(namespace).unwrap();
+            hat.set_allow_unsigned(allow_unsigned);
+            let name = hat.family_name(cmd.value_of("NAME").unwrap());
+
+            let found = hat.find(&name, &pattern, &filter).unwrap();
+            if cmd.is_present("json") {
+                let found: Vec<FoundEntry> = found
+                    .into_iter()
+                    .map(|(id, entry)| FoundEntry {
+                        snapshot_id: id,
+                        entry: entry,
+                    })
+                    .collect();
+                println!("{}", serde_json::to_string(&found).unwrap());
+                return;
+            }
+            let mut snapshots: Vec<u64> = found.iter().map(|&(id, _)| id).collect();
+            snapshots.dedup();
+            for (id, entry) in found {
+                println!(
+                    "{:>6} {:>12} {}",
+                    id,
+                    entry.size.map_or("-".to_string(), |s| s.to_string()),
+                    entry.path.display()
+                );
+            }
+            println!("Found in {} snapshots", snapshots.len());
+        }
+        ("du", Some(cmd)) => {
+            let backend = blob_backend(&blob_dir, append_only);
+            let _lock = lock_repository(&backend, &cache_dir, hat::hat::LockKind::Shared);
+            let mut hat = hat::Hat::open_repository(
+                migrations_dir,
+                cache_dir,
+                backend,
+                max_blob_size,
+                hash_algorithm,
+                passphrase,
+            ).unwrap();
+            hat.set_namespace(namespace).unwrap();
+            hat.set_allow_unsigned(allow_unsigned);
+            let name = hat.family_name(cmd.value_of("NAME").unwrap());
+
+            let id = cmd.value_of("snapshot").map(|selector| {
+                hat.resolve_snapshot(&name, selector).unwrap()
+            });
+            let path = Path::new(cmd.value_of("PATH").unwrap_or("/"));
+            let usage = hat.disk_usage(&name, id, path).unwrap();
+            if cmd.is_present("json") {
+                println!("{}", serde_json::to_string(&usage).unwrap());
+                return;
+            }
+            println!("{:>14} {:>14} {}", "SIZE", "UNIQUE", "PATH");
+            for u in usage {
+                println!("{:>14} {:>14} {}", u.logical_bytes, u.unique_bytes, u.path.display());
+            }
+        }
+        ("mount", Some(cmd)) => {
+            let backend = blob_backend(&blob_dir, append_only);
+            let _lock = lock_repository(&backend, &cache_dir, hat::hat::LockKind::Shared);
+            let mut hat = hat::Hat::open_repository(
+                migrations_dir,
+                cache_dir,
+                backend,
+                max_blob_size,
+                hash_algorithm,
+                passphrase,
+            ).unwrap();
+            hat.set_namespace(namespace).unwrap();
+            hat.set_allow_unsigned(allow_unsigned);
+
+            mount(&mut hat, Path::new(cmd.value_of("MOUNTPOINT").unwrap()));
+        }
+        ("web", Some(cmd)) => {
+            let backend = blob_backend(&blob_dir, append_only);
+            let lock = lock_repository(&backend, &cache_dir, hat::hat::LockKind::Shared);
+            let mut hat = hat::Hat::open_repository(
+                migrations_dir,
+                cache_dir,
+                backend,
+                max_blob_size,
+                hash_algorithm,
+                passphrase,
+            ).unwrap();
+            hat.set_namespace(namespace).unwrap();
+            hat.set_allow_unsigned(allow_unsigned);
+
+            let addr = cmd.value_of("listen").unwrap_or("127.0.0.1:8080");
+            let restore_dir = PathBuf::from(cmd.value_of("restore_to").unwrap_or("restored"));
+            if let Err(e) = web(&mut hat, addr, restore_dir) {
+                println!("{}", e);
+                drop(lock);
+                std::process::exit(1);
+            }
+        }
+        ("import", Some(cmd)) => {
+            match cmd.value_of("format").unwrap_or("tar") {
+                "tar" => (),
+                format => {
+                    println!("Unsupported archive format: {}", format);
+                    std::process::exit(1);
+                }
+            }
+
+            let backend = blob_backend(&blob_dir, append_only);
+            let lock = lock_repository(&backend, &cache_dir, hat::hat::LockKind::Shared);
+            let mut hat = hat::Hat::open_repository(
+                migrations_dir,
+                cache_dir,
+                backend,
+                max_blob_size,
+                hash_algorithm,
+                passphrase,
+            ).unwrap();
+            hat.watch_lock(&lock);
+            hat.set_namespace(namespace).unwrap();
+            hat.set_allow_unsigned(allow_unsigned);
+            let name = hat.family_name(cmd.value_of("NAME").unwrap());
+
+            // Update the family index from the archive.
             let mut family = hat.open_family(name.clone()).expect(&format!(
                 "Could not open family '{}'",
                 name
             ));
-            family.snapshot_dir(PathBuf::from(path));
+            let stdin = io::stdin();
+            family.snapshot_tar(stdin.lock()).unwrap();
+            family.provenance = Some(provenance("-"));
+            family.labels = cmd.values_of("label")
+                .into_iter()
+                .flat_map(|v| v)
+                .map(|l| l.to_string())
+                .collect();
 
-            // Commit the updated index.
             hat.commit(&mut family, None).unwrap();
-
-            // Meta commit.
             hat.meta_commit().unwrap();
-
-            // Flush any remaining blobs.
             hat.data_flush().unwrap();
         }
-        ("checkout", Some(cmd)) => {
-            let name = cmd.value_of("NAME").unwrap().to_owned();
-            let path = cmd.value_of("PATH").unwrap();
+        ("export", Some(cmd)) => {
+            // Standard output may carry the archive, so report on standard error.
+            match cmd.value_of("format").unwrap_or("tar") {
+                "tar" => (),
+                format => {
+                    writeln!(io::stderr(), "Unsupported archive format: {}", format).unwrap();
+                    std::process::exit(1);
+                }
+            }
 
-            let backend = Arc::new(backend::FileBackend::new(blob_dir()));
-            let mut hat =
-                hat::Hat::open_repository(migrations_dir, cache_dir, backend, MAX_BLOB_SIZE)
-                    .unwrap();
+            let backend = blob_backend(&blob_dir, append_only);
+            let _lock = lock_repository(&backend, &cache_dir, hat::hat::LockKind::Shared);
+            let mut hat = hat::Hat::open_repository(
+                migrations_dir,
+                cache_dir,
+                backend,
+                max_blob_size,
+                hash_algorithm,
+                passphrase,
+            ).unwrap();
+            hat.set_namespace(namespace).unwrap();
+            hat.set_allow_unsigned(allow_unsigned);
+            let name = hat.family_name(cmd.value_of("NAME").unwrap());
 
-            hat.checkout_in_dir(name, PathBuf::from(path)).unwrap();
+            let id = cmd.value_of("snapshot").map(|selector| {
+                hat.resolve_snapshot(&name, selector).unwrap()
+            });
+            match cmd.value_of("OUTPUT") {
+                Some(path) => {
+                    let file = io::BufWriter::new(fs::File::create(path).unwrap());
+                    hat.export_tar(&name, id, file).unwrap();
+                }
+                None => {
+                    let stdout = io::stdout();
+                    hat.export_tar(&name, id, io::BufWriter::new(stdout.lock())).unwrap();
+                }
+            }
         }
         ("recover", Some(_cmd)) => {
-            let backend = Arc::new(backend::FileBackend::new(blob_dir()));
-            let mut hat =
-                hat::Hat::open_repository(migrations_dir, cache_dir, backend, MAX_BLOB_SIZE)
-                    .unwrap();
+            let backend = blob_backend(&blob_dir, append_only);
+            let lock = lock_repository(&backend, &cache_dir, hat::hat::LockKind::Exclusive);
+            let mut hat = hat::Hat::open_repository(
+                migrations_dir,
+                cache_dir,
+                backend,
+                max_blob_size,
+                hash_algorithm,
+                passphrase,
+            ).unwrap();
+            hat.watch_lock(&lock);
 
             hat.recover().unwrap();
         }
         ("delete", Some(cmd)) => {
-            let name = cmd.value_of("NAME").unwrap().to_owned();
             let id = cmd.value_of("ID").unwrap().to_owned();
 
-            let backend = Arc::new(backend::FileBackend::new(blob_dir()));
-            let mut hat =
-                hat::Hat::open_repository(migrations_dir, cache_dir, backend, MAX_BLOB_SIZE)
-                    .unwrap();
+            let backend = blob_backend(&blob_dir, append_only);
+            let lock = lock_repository(&backend, &cache_dir, hat::hat::LockKind::Exclusive);
+            let mut hat = hat::Hat::open_repository(
+                migrations_dir,
+                cache_dir,
+                backend,
+                max_blob_size,
+                hash_algorithm,
+                passphrase,
+            ).unwrap();
+            hat.watch_lock(&lock);
+            hat.set_namespace(namespace).unwrap();
+            hat.set_allow_unsigned(allow_unsigned);
+            let name = hat.family_name(cmd.value_of("NAME").unwrap());
+
+            let id = id.parse::<u64>().unwrap();
+            if cmd.is_present("force") {
+                hat.clear_restore_marker(&name, id);
+            }
+            hat.deregister_by_name(name, id).unwrap();
+            println!("Run gc to reclaim the space of the deleted snapshot");
+        }
+        ("forget", Some(cmd)) => {
+            let count = |arg: &str| cmd.value_of(arg).map_or(0, |s| s.parse().unwrap());
+            let keep_args = ["keep_last", "keep_daily", "keep_weekly", "keep_monthly", "keep_yearly"];
+            let policy = match repository.retention {
+                // The configured policy is used as a whole, unless the command line gives one.
+                Some(policy) if !keep_args.iter().any(|arg| cmd.is_present(arg)) => policy,
+                _ => hat::hat::RetentionPolicy {
+                    keep_last: count("keep_last"),
+                    keep_daily: count("keep_daily"),
+                    keep_weekly: count("keep_weekly"),
+                    keep_monthly: count("keep_monthly"),
+                    keep_yearly: count("keep_yearly"),
+                },
+            };
+            let dry_run = cmd.is_present("dry_run");
+
+            let backend = blob_backend(&blob_dir, append_only);
+            let kind = if dry_run {
+                hat::hat::LockKind::Shared
+            } else {
+                hat::hat::LockKind::Exclusive
+            };
+            let lock = lock_repository(&backend, &cache_dir, kind);
+            let mut hat = hat::Hat::open_repository(
+                migrations_dir,
+                cache_dir,
+                backend,
+                max_blob_size,
+                hash_algorithm,
+                passphrase,
+            ).unwrap();
+            hat.watch_lock(&lock);
+            hat.set_namespace(namespace).unwrap();
+            hat.set_allow_unsigned(allow_unsigned);
+            let name = hat.family_name(cmd.value_of("NAME").unwrap());
+
+            let label = cmd.value_of("label");
+            for id in hat.forget(&name, &policy, label, dry_run).unwrap() {
+                if dry_run {
+                    println!("Would forget {} {}", name, id);
+                } else {
+                    println!("Forgot {} {}", name, id);
+                }
+            }
+            if !dry_run {
+                println!("Run gc to reclaim the space of forgotten snapshots");
+            }
+            report_quota(&mut hat, repository.quota);
+        }
+        ("family", Some(cmd)) => {
+            // `family delete --gc` deletes blobs like `gc` does.
+            let backend = gc_backend(&blob_dir, append_only, None, &repository);
+            // Renaming or deleting a family under a running commit or restore is not safe.
+            let lock = lock_repository(&backend, &cache_dir, hat::hat::LockKind::Exclusive);
+            let mut hat = hat::Hat::open_repository(
+                migrations_dir,
+                cache_dir,
+                backend,
+                max_blob_size,
+                hash_algorithm,
+                passphrase,
+            ).unwrap();
+            hat.watch_lock(&lock);
+            hat.set_namespace(namespace).unwrap();
+            hat.set_allow_unsigned(allow_unsigned);
+
+            match cmd.subcommand() {
+                ("rename", Some(cmd)) => {
+                    let name = hat.family_name(cmd.value_of("NAME").unwrap());
+                    let new_name = hat.family_name(cmd.value_of("NEW_NAME").unwrap());
+                    hat.rename_family(&name, &new_name).unwrap();
+                }
+                ("delete", Some(cmd)) => {
+                    let name = hat.family_name(cmd.value_of("NAME").unwrap());
+                    let count = hat.delete_family(&name).unwrap();
+                    println!("Deleted {} snapshots of family {}", count, name);
+                    if cmd.is_present("gc") {
+                        hat.set_gc_grace_period(
+                            repository.gc_grace_hours.map(|h| time::Duration::hours(h as i64)),
+                        );
+                        print_gc_report(&hat.gc().unwrap(), false);
+                    }
+                }
+                _ => {
+                    println!("{}", cmd.usage());
+                    drop(lock);
+                    std::process::exit(1);
+                }
+            }
+
+            // The snapshot listing kept with the blobs names the families.
+            hat.meta_commit().unwrap();
+            hat.data_flush().unwrap();
+        }
+        ("label", Some(cmd)) => {
+            let id = cmd.value_of("ID").unwrap().parse::<u64>().unwrap();
+            let label = cmd.value_of("LABEL").unwrap();
+
+            let backend = blob_backend(&blob_dir, append_only);
+            let lock = lock_repository(&backend, &cache_dir, hat::hat::LockKind::Shared);
+            let mut hat = hat::Hat::open_repository(
+                migrations_dir,
+                cache_dir,
+                backend,
+                max_blob_size,
+                hash_algorithm,
+                passphrase,
+            ).unwrap();
+            hat.watch_lock(&lock);
+            hat.set_namespace(namespace).unwrap();
+            hat.set_allow_unsigned(allow_unsigned);
+            let name = hat.family_name(cmd.value_of("NAME").unwrap());
+
+            if cmd.is_present("remove") {
+                hat.remove_label(&name, id, label).unwrap();
+            } else {
+                hat.add_label(&name, id, label).unwrap();
+            }
+            // Labels are part of the snapshot listing kept with the blobs.
+            hat.meta_commit().unwrap();
+            hat.data_flush().unwrap();
+        }
+        ("gc", Some(cmd)) => {
+            let backend = gc_backend(&blob_dir, append_only, Some(cmd), &repository);
+            // Only a sweep that does not wait out running commits needs the repository to itself.
+            let exclusive = !cmd.is_present("pretend") &&
+                (cmd.is_present("mark_sweep") || !cmd.is_present("concurrent"));
+            let kind = if exclusive {
+                hat::hat::LockKind::Exclusive
+            } else {
+                hat::hat::LockKind::Shared
+            };
+            let lock = lock_repository(&backend, &cache_dir, kind);
+            let mut hat = hat::Hat::open_repository(
+                migrations_dir,
+                cache_dir,
+                backend,
+                max_blob_size,
+                hash_algorithm,
+                passphrase,
+            ).unwrap();
+            hat.watch_lock(&lock);
+            hat.set_progress(reporter(&events));
+            let grace_hours = cmd.value_of("grace_hours")
+                .map(|h| h.parse::<u64>().unwrap())
+                .or(repository.gc_grace_hours);
+            hat.set_gc_grace_period(grace_hours.map(|h| time::Duration::hours(h as i64)));
+            if cmd.is_present("pretend") && cmd.is_present("mark_sweep") {
+                let drift = hat.gc_cross_check().unwrap();
+                if cmd.is_present("json") {
+                    println!("{}", serde_json::to_string(&drift).unwrap());
+                } else {
+                    println!("Unreachable hashes kept by reference counts: {}", drift.leaked);
+                    println!("Reachable hashes that gc would delete: {}", drift.endangered);
+                    if !drift.is_ok() {
+                        println!("Run `hat gc --mark_sweep` to repair the reference counts");
+                    }
+                }
+                if !drift.is_ok() {
+                    // Exiting skips destructors, so release the lock first.
+                    drop(lock);
+                    std::process::exit(1);
+                }
+                return;
+            }
+            if cmd.is_present("pretend") {
+                let estimate = hat.gc_estimate(10).unwrap();
+                if cmd.is_present("json") {
+                    println!("{}", serde_json::to_string(&estimate).unwrap());
+                    return;
+                }
+                println!(
+                    "Would free {} unused chunks and tree nodes ({} bytes), deleting {} blobs \
+                     ({} bytes)",
+                    estimate.unused_hashes,
+                    estimate.unused_bytes,
+                    estimate.blobs,
+                    estimate.blob_bytes
+                );
+                if !estimate.pinned.is_empty() {
+                    println!("Snapshots holding the most data no other snapshot uses:");
+                    for p in &estimate.pinned {
+                        println!("  {:20} {:>6} {:>14} bytes", p.family, p.snapshot_id, p.bytes);
+                    }
+                }
+                return;
+            }
+            let report = if cmd.is_present("mark_sweep") {
+                hat.gc_mark_sweep().unwrap()
+            } else if cmd.is_present("concurrent") {
+                hat.gc_concurrent().unwrap()
+            } else {
+                hat.gc().unwrap()
+            };
+            print_gc_report(&report, cmd.is_present("json"));
+            if !cmd.is_present("json") {
+                report_quota(&mut hat, repository.quota);
+            }
+        }
+        ("patch", Some(cmd)) => {
+            let id = cmd.value_of("ID").unwrap().parse::<u64>().unwrap();
+            let from = cmd.value_of("from").map(|s| s.parse::<u64>().unwrap());
+            let file = cmd.value_of("FILE").unwrap();
+
+            let backend = blob_backend(&blob_dir, append_only);
+            let _lock = lock_repository(&backend, &cache_dir, hat::hat::LockKind::Shared);
+            let mut hat = hat::Hat::open_repository(
+                migrations_dir,
+                cache_dir,
+                backend,
+                max_blob_size,
+                hash_algorithm,
+                passphrase,
+            ).unwrap();
+            hat.set_namespace(namespace).unwrap();
+            hat.set_allow_unsigned(allow_unsigned);
+            let name = hat.family_name(cmd.value_of("NAME").unwrap());
+
+            let count = write_bundle(&mut hat, &name, from, id, Some(file));
+            println!("Wrote {} blobs to {}", count, file);
+        }
+        ("apply-patch", Some(cmd)) => {
+            let file = cmd.value_of("FILE").unwrap();
+
+            let backend = blob_backend(&blob_dir, append_only);
+            let lock = lock_repository(&backend, &cache_dir, hat::hat::LockKind::Shared);
+            let mut hat = hat::Hat::open_repository(
+                migrations_dir,
+                cache_dir,
+                backend,
+                max_blob_size,
+                hash_algorithm,
+                passphrase,
+            ).unwrap();
+            hat.watch_lock(&lock);
+
+            apply_bundle(&mut hat, Some(file));
+        }
+        ("send", Some(cmd)) => {
+            let backend = blob_backend(&blob_dir, append_only);
+            let _lock = lock_repository(&backend, &cache_dir, hat::hat::LockKind::Shared);
+            let mut hat = hat::Hat::open_repository(
+                migrations_dir,
+                cache_dir,
+                backend,
+                max_blob_size,
+                hash_algorithm,
+                passphrase,
+            ).unwrap();
+            hat.set_namespace(namespace).unwrap();
+            hat.set_allow_unsigned(allow_unsigned);
+            let name = hat.family_name(cmd.value_of("NAME").unwrap());
+
+            let from = match hat.resolve_snapshot(&name, cmd.value_of("FROM").unwrap()).unwrap() {
+                0 => None,
+                id => Some(id),
+            };
+            let to = hat.resolve_snapshot(&name, cmd.value_of("TO").unwrap()).unwrap();
+
+            // Standard output may carry the bundle, so report on standard error.
+            let count = write_bundle(&mut hat, &name, from, to, cmd.value_of("output"));
+            writeln!(io::stderr(), "Sent {} blobs", count).unwrap();
+        }
+        ("receive", Some(cmd)) => {
+            let backend = blob_backend(&blob_dir, append_only);
+            let lock = lock_repository(&backend, &cache_dir, hat::hat::LockKind::Shared);
+            let mut hat = hat::Hat::open_repository(
+                migrations_dir,
+                cache_dir,
+                backend,
+                max_blob_size,
+                hash_algorithm,
+                passphrase,
+            ).unwrap();
+            hat.watch_lock(&lock);
+
+            apply_bundle(&mut hat, cmd.value_of("FILE"));
+        }
+        ("copy", Some(cmd)) => {
+            let from = cmd.value_of("from").map_or_else(|| blob_dir.clone(), PathBuf::from);
+            let backend = Arc::new(backend::FileBackend::new(from));
+            let lock = lock_repository(&backend, &cache_dir, hat::hat::LockKind::Shared);
+            let mut hat = hat::Hat::open_repository(
+                migrations_dir,
+                cache_dir,
+                backend,
+                max_blob_size,
+                hash_algorithm,
+                passphrase,
+            ).unwrap();
+            hat.set_namespace(namespace).unwrap();
+            hat.set_allow_unsigned(allow_unsigned);
+            let family = cmd.value_of("family").map(|name| hat.family_name(name));
+
+            let dest_repository = match cmd.value_of("to_repo") {
+                Some(name) => config.repository(Some(name)).unwrap(),
+                None => hat::hat::RepositoryConfig::default(),
+            };
+            let dest_dir = cmd.value_of("to").map(PathBuf::from).or(dest_repository.blob_dir);
+            let dest_cache =
+                cmd.value_of("to_cache").map(PathBuf::from).or(dest_repository.cache_dir);
+            let (dest_dir, dest_cache) = match (dest_dir, dest_cache) {
+                (Some(dir), Some(cache)) => (dir, cache),
+                _ => {
+                    println!("Give the destination with --to and --to_cache, or with --to_repo");
+                    drop(lock);
+                    std::process::exit(1);
+                }
+            };
+            // A destination that does not exist yet is created like the source.
+            let dest_backend = Arc::new(backend::FileBackend::new(dest_dir));
+            let dest_lock =
+                lock_repository(&dest_backend, &dest_cache, hat::hat::LockKind::Shared);
+            let mut dest = match hat::hat::Manifest::load(&*dest_backend).unwrap() {
+                Some(_) => {
+                    hat::Hat::open_repository(
+                        migrations_dir,
+                        dest_cache,
+                        dest_backend,
+                        max_blob_size,
+                        Some(hat.hash_algorithm()),
+                        passphrase,
+                    ).unwrap()
+                }
+                None => {
+                    hat.share_fingerprint_secret(&*dest_backend).unwrap();
+                    hat::Hat::init_repository(
+                        migrations_dir,
+                        dest_cache,
+                        dest_backend,
+                        max_blob_size,
+                        Some(hat.hash_algorithm()),
+                        passphrase,
+                    ).unwrap()
+                        .0
+                }
+            };
+            dest.watch_lock(&dest_lock);
 
-            hat.deregister_by_name(name, id.parse::<u64>().unwrap())
-                .unwrap();
+            let report = hat.copy_snapshots(&mut dest, family.as_ref().map(|f| &f[..])).unwrap();
+
+            // Meta commit so the destination can be recovered from its own blobs.
+            dest.meta_commit().unwrap();
+            dest.data_flush().unwrap();
+            println!(
+                "Copied {} snapshots: {} blobs, {} bytes",
+                report.snapshots,
+                report.blobs,
+                report.bytes
+            );
+            if report.present > 0 {
+                println!("Skipped {} snapshots the destination already has", report.present);
+            }
+            if !report.conflicts.is_empty() {
+                for &(ref family, id) in &report.conflicts {
+                    println!("Not copied: {} #{} is a different snapshot there", family, id);
+                }
+                drop(dest_lock);
+                drop(lock);
+                std::process::exit(1);
+            }
+        }
+        ("stats", Some(cmd)) => {
+            let backend = blob_backend(&blob_dir, append_only);
+            let _lock = lock_repository(&backend, &cache_dir, hat::hat::LockKind::Shared);
+            let mut hat = hat::Hat::open_repository(
+                migrations_dir,
+                cache_dir,
+                backend,
+                max_blob_size,
+                hash_algorithm,
+                passphrase,
+            ).unwrap();
+            hat.set_namespace(namespace).unwrap();
+            hat.set_allow_unsigned(allow_unsigned);
+
+            let stats = hat.repository_stats().unwrap();
+            if cmd.is_present("json") {
+                println!("{}", serde_json::to_string(&stats).unwrap());
+                return;
+            }
+            let ratio = |r: Option<f64>| r.map_or("-".to_string(), |r| format!("{:.2}", r));
+            println!("Blobs:             {} ({} bytes)", stats.blobs, stats.blob_bytes);
+            println!("Chunks:            {} ({} bytes)", stats.chunks, stats.chunk_bytes);
+            println!("Dedup ratio:       {}", ratio(stats.dedup_ratio()));
+            println!("Hash index:        {} bytes", stats.index_bytes);
+            println!("");
+            println!(
+                "{:20} {:>9} {:>14} {:>14} {:>14} {:>12}",
+                "FAMILY",
+                "SNAPSHOTS",
+                "LATEST",
+                "READ",
+                "NEW",
+                "INDEX"
+            );
+            for f in &stats.families {
+                println!(
+                    "{:20} {:>9} {:>14} {:>14} {:>14} {:>12}",
+                    f.family,
+                    f.snapshots,
+                    f.latest_bytes,
+                    f.bytes_read,
+                    f.bytes_new,
+                    f.index_bytes
+                );
+            }
         }
-        ("gc", Some(_cmd)) => {
-            let backend = Arc::new(backend::FileBackend::new(blob_dir()));
-            let mut hat =
-                hat::Hat::open_repository(migrations_dir, cache_dir, backend, MAX_BLOB_SIZE)
-                    .unwrap();
-            let (deleted_hashes, live_blobs) = hat.gc().unwrap();
-            println!("Deleted hashes: {:?}", deleted_hashes);
-            println!("Live data blobs after deletion: {:?}", live_blobs);
+        ("pause", Some(_cmd)) | ("unpause", Some(_cmd)) => {
+            let backend = blob_backend(&blob_dir, append_only);
+            let hat = hat::Hat::open_repository(
+                migrations_dir,
+                cache_dir,
+                backend,
+                max_blob_size,
+                hash_algorithm,
+                passphrase,
+            ).unwrap();
+            let paused = matches.subcommand_name() == Some("pause");
+
+            // Only live processes on this host are signalled; a stale lock's pid may be reused.
+            let now = chrono::Utc::now().timestamp();
+            let mut signalled = 0;
+            for lock in hat.list_locks().unwrap() {
+                if lock.holder_is_gone(now, hat.host()) {
+                    continue;
+                }
+                match lock.local_pid(hat.host()) {
+                    Some(pid) if pid != std::process::id() => {
+                        hat::hat::signal_pause(pid, paused).unwrap();
+                        println!("Signalled {}", lock.owner);
+                        signalled += 1;
+                    }
+                    _ => (),
+                }
+            }
+            if signalled == 0 {
+                println!("No hat process on this host holds a lock on the repository");
+                std::process::exit(1);
+            }
+        }
+        ("locks", Some(cmd)) => {
+            let backend = blob_backend(&blob_dir, append_only);
+            let hat = hat::Hat::open_repository(
+                migrations_dir,
+                cache_dir,
+                backend,
+                max_blob_size,
+                hash_algorithm,
+                passphrase,
+            ).unwrap();
+            let locks = hat.list_locks().unwrap();
+
+            if cmd.is_present("json") {
+                println!("{}", serde_json::to_string(&locks).unwrap());
+                return;
+            }
+            let now = chrono::Utc::now().timestamp();
+            println!(
+                "{:9} {:32} {:>8} {:6} {}",
+                "KIND",
+                "HOLDER",
+                "AGE",
+                "STATUS",
+                "OPERATION"
+            );
+            for lock in locks {
+                let status = if lock.is_stale(now) {
+                    "stale"
+                } else if lock.holder_is_gone(now, hat.host()) {
+                    "gone"
+                } else {
+                    "active"
+                };
+                let age = now - lock.taken_utc.unwrap_or(lock.refreshed_utc);
+                println!(
+                    "{:9} {:32} {:>7}s {:6} {}",
+                    format!("{:?}", lock.kind).to_lowercase(),
+                    lock.owner,
+                    age,
+                    status,
+                    lock.operation
+                );
+            }
+        }
+        ("break-lock", Some(cmd)) => {
+            let backend = blob_backend(&blob_dir, append_only);
+            let hat = hat::Hat::open_repository(
+                migrations_dir,
+                cache_dir,
+                backend,
+                max_blob_size,
+                hash_algorithm,
+                passphrase,
+            ).unwrap();
+
+            let removed = hat.break_locks(cmd.is_present("force")).unwrap();
+            for lock in &removed {
+                println!("Removed lock held by {}", lock.owner);
+            }
+            let kept = hat.list_locks().unwrap().len();
+            if kept > 0 {
+                println!(
+                    "Kept {} lock(s) whose holders may still be running; see `hat locks`",
+                    kept
+                );
+            }
+        }
+        ("whoami", Some(_cmd)) => {
+            let backend = blob_backend(&blob_dir, append_only);
+            let _lock = lock_repository(&backend, &cache_dir, hat::hat::LockKind::Shared);
+            let mut hat = hat::Hat::open_repository(
+                migrations_dir,
+                cache_dir,
+                backend,
+                max_blob_size,
+                hash_algorithm,
+                passphrase,
+            ).unwrap();
+
+            let yes_no = |b: bool| if b { "yes" } else { "no" };
+            let perms = hat.permissions();
+            println!("Access: {:?}", perms.access);
+            println!("  Write new data: {}", yes_no(perms.keys.write));
+            println!("  Read data: {}", yes_no(perms.keys.read));
+            println!("  Read blob names: {}", yes_no(perms.keys.read_names));
+            println!(
+                "  Recover from blobs: {}",
+                yes_no(perms.keys.read_unreferenced)
+            );
+            println!("Families:");
+            for (name, decryptable) in perms.families {
+                println!("  {} (decryptable: {})", name, yes_no(decryptable));
+            }
+        }
+        ("snapshots", Some(cmd)) => {
+            let backend = blob_backend(&blob_dir, append_only);
+            let _lock = lock_repository(&backend, &cache_dir, hat::hat::LockKind::Shared);
+            let mut hat = hat::Hat::open_repository(
+                migrations_dir,
+                cache_dir,
+                backend,
+                max_blob_size,
+                hash_algorithm,
+                passphrase,
+            ).unwrap();
+            hat.set_namespace(namespace).unwrap();
+            hat.set_allow_unsigned(allow_unsigned);
+
+            if cmd.is_present("export_prometheus") {
+                print!("{}", hat.backup_metrics().to_prometheus(chrono::Utc::now()));
+                return;
+            }
+            if cmd.is_present("json") {
+                println!("{}", serde_json::to_string(&hat.list_snapshot_history()).unwrap());
+                return;
+            }
+
+            let show_roots = cmd.is_present("show_roots");
+            let unknown = || "-".to_string();
+            println!(
+                "{:20} {:>6} {:25} {:>8} {:>9} {:>14} {:>14} {:>6} {}",
+                "FAMILY",
+                "ID",
+                "CREATED",
+                "DURATION",
+                "FILES",
+                "SIZE",
+                "NEW",
+                "DEDUP",
+                "STATUS"
+            );
+            for s in hat.list_snapshot_history() {
+                if show_roots {
+                    print!("{} ", s.root.as_ref().map_or("-", |r| &r[..]));
+                }
+                let duration = s.finished.map_or_else(unknown, |t| {
+                    format!("{}s", t.signed_duration_since(s.created).num_seconds())
+                });
+                let dedup = s.stats.map_or_else(unknown, |stats| if stats.bytes_read > 0 {
+                    let saved = stats.bytes_deduplicated() as f64 / stats.bytes_read as f64;
+                    format!("{:.1}%", 100.0 * saved)
+                } else {
+                    unknown()
+                });
+                let mut state = match s.state {
+                    hat::hat::SnapshotState::InProgress => "in-progress",
+                    hat::hat::SnapshotState::Complete => "complete",
+                    hat::hat::SnapshotState::Deleting => "deleting",
+                }.to_string();
+                if !s.labels.is_empty() {
+                    state = format!("{} [{}]", state, s.labels.join(", "));
+                }
+                println!(
+                    "{:20} {:>6} {:25} {:>8} {:>9} {:>14} {:>14} {:>6} {}",
+                    s.family,
+                    s.snapshot_id,
+                    s.created.to_rfc3339(),
+                    duration,
+                    s.contents.map_or_else(unknown, |c| c.files.to_string()),
+                    s.contents.map_or_else(unknown, |c| c.bytes.to_string()),
+                    s.stats.map_or_else(unknown, |stats| stats.bytes_new.to_string()),
+                    dedup,
+                    state
+                );
+                if let (true, Some(p)) = (cmd.is_present("show_provenance"), s.provenance) {
+                    println!(
+                        "    {} from {}@{} by hat {}: {}",
+                        p.source_path,
+                        p.username,
+                        p.hostname,
+                        p.version,
+                        p.command_line.join(" ")
+                    );
+                }
+            }
+        }
+        ("diff", Some(cmd)) => {
+            let backend = blob_backend(&blob_dir, append_only);
+            let _lock = lock_repository(&backend, &cache_dir, hat::hat::LockKind::Shared);
+            let mut hat = hat::Hat::open_repository(
+                migrations_dir,
+                cache_dir,
+                backend,
+                max_blob_size,
+                hash_algorithm,
+                passphrase,
+            ).unwrap();
+            hat.set_namespace(namespace).unwrap();
+            hat.set_allow_unsigned(allow_unsigned);
+            let name = hat.family_name(cmd.value_of("NAME").unwrap());
+
+            let from = hat.resolve_snapshot(&name, cmd.value_of("FROM").unwrap()).unwrap();
+            let to = hat.resolve_snapshot(&name, cmd.value_of("TO").unwrap()).unwrap();
+            let changes = hat.diff_snapshots(&name, from, to).unwrap();
+            let kind = |c: &hat::hat::FileChange| match c.kind {
+                hat::hat::ChangeKind::Added => "added",
+                hat::hat::ChangeKind::Removed => "removed",
+                hat::hat::ChangeKind::Modified => "modified",
+            };
+            if cmd.is_present("json") {
+                println!("{}", serde_json::to_string(&changes).unwrap());
+            } else {
+                let size = |s: Option<u64>| s.map_or("-".to_string(), |s| s.to_string());
+                for c in &changes {
+                    println!(
+                        "{:8} {:>12} {:>12} {}",
+                        kind(c),
+                        size(c.old_size),
+                        size(c.new_size),
+                        c.path.display()
+                    );
+                }
+            }
+        }
+        ("status", Some(cmd)) => {
+            let path = PathBuf::from(cmd.value_of("PATH").unwrap());
+
+            let backend = blob_backend(&blob_dir, append_only);
+            let _lock = lock_repository(&backend, &cache_dir, hat::hat::LockKind::Shared);
+            let mut hat = hat::Hat::open_repository(
+                migrations_dir,
+                cache_dir,
+                backend,
+                max_blob_size,
+                hash_algorithm,
+                passphrase,
+            ).unwrap();
+            hat.set_namespace(namespace).unwrap();
+            hat.set_allow_unsigned(allow_unsigned);
+            let name = hat.family_name(cmd.value_of("NAME").unwrap());
+
+            let status = hat.status(&name, &path).unwrap();
+            for c in &status.changes {
+                let kind = match c.kind {
+                    hat::hat::ChangeKind::Added => "added",
+                    hat::hat::ChangeKind::Removed => "removed",
+                    hat::hat::ChangeKind::Modified => "modified",
+                };
+                println!("{:8} {}", kind, c.path.display());
+            }
+            println!(
+                "{} changes; {} bytes would be uploaded",
+                status.changes.len(),
+                status.upload_bytes
+            );
+        }
+        ("verify", Some(cmd)) => {
+            let backend = blob_backend(&blob_dir, append_only);
+            let lock = lock_repository(&backend, &cache_dir, hat::hat::LockKind::Shared);
+            let mut hat = hat::Hat::open_repository(
+                migrations_dir,
+                cache_dir,
+                backend,
+                max_blob_size,
+                hash_algorithm,
+                passphrase,
+            ).unwrap();
+            hat.set_namespace(namespace).unwrap();
+            hat.set_allow_unsigned(allow_unsigned);
+            let name = hat.family_name(cmd.value_of("NAME").unwrap());
+
+            let id = hat.resolve_snapshot(&name, cmd.value_of("ID").unwrap()).unwrap();
+            let verified = hat.verify_snapshot(&name, id).unwrap();
+            println!(
+                "{} {}: {} chunks ({} bytes of file data) match root {}",
+                name,
+                id,
+                verified.chunks,
+                verified.bytes,
+                verified.root
+            );
+            if let Some(expected) = cmd.value_of("root") {
+                if !expected.eq_ignore_ascii_case(&verified.root) {
+                    println!("Root does not match the expected {}", expected);
+                    drop(lock);
+                    std::process::exit(1);
+                }
+            }
+        }
+        ("check", Some(cmd)) => {
+            let read_data = cmd.value_of("read_data").map(|p| {
+                let percent: f64 = p.trim_right_matches('%').parse().unwrap();
+                percent / 100.0
+            });
+
+            let backend = blob_backend(&blob_dir, append_only);
+            let lock = lock_repository(&backend, &cache_dir, hat::hat::LockKind::Shared);
+            let mut hat = hat::Hat::open_repository(
+                migrations_dir,
+                cache_dir,
+                backend,
+                max_blob_size,
+                hash_algorithm,
+                passphrase,
+            ).unwrap();
+
+            let report = hat.check(read_data).unwrap();
+            if cmd.is_present("json") {
+                println!("{}", serde_json::to_string(&report).unwrap());
+            } else {
+                for p in &report.problems {
+                    let kind = match p.kind {
+                        hat::hat::ProblemKind::Missing => "missing",
+                        hat::hat::ProblemKind::Corrupt => "corrupt",
+                        hat::hat::ProblemKind::Orphaned => "orphaned",
+                    };
+                    println!("{:9} {}: {}", kind, p.object, p.detail);
+                }
+                println!(
+                    "Checked {} snapshots, {} tree chunks and {} blobs; read {} chunks: {} \
+                     problems",
+                    report.snapshots,
+                    report.chunks,
+                    report.blobs,
+                    report.chunks_read,
+                    report.problems.len()
+                );
+            }
+            if !report.is_ok() {
+                // Exiting skips destructors, so release the lock first.
+                drop(lock);
+                std::process::exit(1);
+            }
+        }
+        ("scrub", Some(cmd)) => {
+            let budget = hat::hat::parse_bytes(cmd.value_of("budget").unwrap_or("1GiB")).unwrap();
+
+            let backend = blob_backend(&blob_dir, append_only);
+            let lock = lock_repository(&backend, &cache_dir, hat::hat::LockKind::Shared);
+            let mut hat = hat::Hat::open_repository(
+                migrations_dir,
+                cache_dir,
+                backend,
+                max_blob_size,
+                hash_algorithm,
+                passphrase,
+            ).unwrap();
+            hat.set_progress(reporter(&events));
+
+            let report = hat.scrub(budget).unwrap();
+            if cmd.is_present("json") {
+                println!("{}", serde_json::to_string(&report).unwrap());
+            } else {
+                for p in &report.problems {
+                    println!("{:?} {}: {}", p.kind, p.object, p.detail);
+                }
+                println!(
+                    "Read {} blobs ({}) and {} chunks: {} problems",
+                    report.blobs,
+                    hat::hat::human_bytes(report.bytes),
+                    report.chunks_read,
+                    report.problems.len()
+                );
+                if report.never_verified > 0 {
+                    println!("{} blobs have never been verified", report.never_verified);
+                } else if let Some(oldest) = report.oldest_verified_utc {
+                    let time = chrono::NaiveDateTime::from_timestamp(oldest, 0);
+                    println!("All blobs verified since {} UTC", time.format("%Y-%m-%d %H:%M"));
+                }
+            }
+            if !report.is_ok() {
+                // Exiting skips destructors, so release the lock first.
+                drop(lock);
+                std::process::exit(1);
+            }
+        }
+        ("repair", Some(_cmd)) => {
+            let backend = blob_backend(&blob_dir, append_only);
+            let lock = lock_repository(&backend, &cache_dir, hat::hat::LockKind::Exclusive);
+            let mut hat = hat::Hat::open_repository(
+                migrations_dir,
+                cache_dir,
+                backend,
+                max_blob_size,
+                hash_algorithm,
+                passphrase,
+            ).unwrap();
+            hat.watch_lock(&lock);
+
+            let report = hat.repair().unwrap();
+            // Damage labels are part of the snapshot listing kept with the blobs.
+            hat.meta_commit().unwrap();
+            hat.data_flush().unwrap();
+
+            for d in &report.damaged {
+                println!("Snapshot {} {} is damaged:", d.family, d.snapshot_id);
+                for path in &d.paths {
+                    println!("  {}", path.display());
+                }
+            }
+            println!(
+                "Adopted {} blobs and restored {} chunks; dropped {} lost blobs and {} chunks",
+                report.blobs_adopted,
+                report.chunks_restored,
+                report.blobs_lost,
+                report.chunks_dropped
+            );
+        }
+        ("index", Some(cmd)) => {
+            if cmd.subcommand_matches("vacuum").is_none() {
+                println!("{}", cmd.usage());
+                std::process::exit(1);
+            }
+
+            let backend = blob_backend(&blob_dir, append_only);
+            let lock = lock_repository(&backend, &cache_dir, hat::hat::LockKind::Shared);
+            let mut hat = hat::Hat::open_repository(
+                migrations_dir,
+                cache_dir,
+                backend,
+                max_blob_size,
+                hash_algorithm,
+                passphrase,
+            ).unwrap();
 
+            let mut damaged = false;
+            for report in hat.maintain_indexes().unwrap() {
+                println!(
+                    "{}: {} bytes, {} after vacuum",
+                    report.path,
+                    report.bytes_before,
+                    report.bytes_after
+                );
+                for problem in &report.problems {
+                    println!("  {}", problem);
+                }
+                damaged |= !report.is_ok();
+            }
+            if damaged {
+                drop(lock);
+                std::process::exit(1);
+            }
         }
         _ => {
             println!(
@@ -65,6 +65,42 @@ impl SnapshotIndex {
         );
     }
 
+    /// Record where and how this snapshot was made.
+    pub fn set_provenance(&mut self, snapshot: &db::SnapshotInfo, provenance: &db::Provenance) {
+        self.index.lock().snapshot_set_provenance(snapshot, provenance)
+    }
+
+    /// Record the signature of this snapshot's root hash.
+    pub fn set_signature(&mut self, snapshot: &db::SnapshotInfo, signature: &[u8]) {
+        self.index.lock().snapshot_set_signature(snapshot, Some(signature))
+    }
+
+    /// Forget the signature of this snapshot.
+    pub fn clear_signature(&mut self, snapshot: &db::SnapshotInfo) {
+        self.index.lock().snapshot_set_signature(snapshot, None)
+    }
+
+    /// Attach a label to this snapshot.
+    pub fn add_label(&mut self, snapshot: &db::SnapshotInfo, label: &str) {
+        self.index.lock().snapshot_add_label(snapshot, label)
+    }
+
+    /// Detach a label from this snapshot. Returns whether it had the label.
+    pub fn remove_label(&mut self, snapshot: &db::SnapshotInfo, label: &str) -> bool {
+        self.index.lock().snapshot_remove_label(snapshot, label)
+    }
+
+    /// Record how much data the backup run behind this snapshot read and stored, and what
+    /// the snapshot holds.
+    pub fn set_stats(
+        &mut self,
+        snapshot: &db::SnapshotInfo,
+        stats: db::SnapshotStats,
+        contents: db::SnapshotContents,
+    ) {
+        self.index.lock().snapshot_set_stats(snapshot, stats, contents)
+    }
+
     /// ReadyCommit.
     pub fn ready_commit(&mut self, snapshot: &db::SnapshotInfo) {
         self.index.lock().snapshot_set_tag(
@@ -128,6 +164,8 @@ impl SnapshotIndex {
         msg: &str,
         hash_ref: &hash::tree::HashRef,
         work_opt: Option<db::SnapshotWorkStatus>,
+        provenance: Option<&db::Provenance>,
+        labels: &[String],
     ) {
         self.index.lock().snapshot_recover(
             snapshot_id,
@@ -136,6 +174,8 @@ impl SnapshotIndex {
             msg,
             hash_ref,
             work_opt,
+            provenance,
+            labels,
         )
     }
 
@@ -0,0 +1,122 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! POSIX access control lists, in a form that can be kept in snapshots.
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::io;
+
+/// Extended attribute holding the access ACL of a file on Linux.
+pub const ACL_ACCESS_XATTR: &'static str = "system.posix_acl_access";
+/// Extended attribute holding the default ACL of a directory on Linux.
+pub const ACL_DEFAULT_XATTR: &'static str = "system.posix_acl_default";
+
+// The extended attribute is a version header followed by (tag, permissions, id) entries.
+const ACL_XATTR_VERSION: u32 = 2;
+const ACL_UNDEFINED_ID: u32 = 0xffff_ffff;
+
+const TAGS: [(u16, &'static str, bool); 6] = [
+    (0x01, "user", false),
+    (0x02, "user", true),
+    (0x04, "group", false),
+    (0x08, "group", true),
+    (0x10, "mask", false),
+    (0x20, "other", false),
+];
+
+/// Convert an ACL from its Linux extended attribute to the portable text form of
+/// `getfacl --numeric`, such as `user::rw-,user:1000:r--,group::r--,mask::r--,other::---`.
+pub fn acl_from_xattr(value: &[u8]) -> Result<String, String> {
+    let mut bytes = value;
+    let err = |_: io::Error| "Truncated ACL".to_string();
+    if bytes.read_u32::<LittleEndian>().map_err(&err)? != ACL_XATTR_VERSION {
+        return Err("Unknown ACL version".to_string());
+    }
+    let mut entries = vec![];
+    while !bytes.is_empty() {
+        let tag = bytes.read_u16::<LittleEndian>().map_err(&err)?;
+        let perm = bytes.read_u16::<LittleEndian>().map_err(&err)?;
+        let id = bytes.read_u32::<LittleEndian>().map_err(&err)?;
+        let &(_, name, qualified) = TAGS.iter().find(|t| t.0 == tag).ok_or_else(|| {
+            format!("Unknown ACL tag: {}", tag)
+        })?;
+        let qualifier = if qualified { id.to_string() } else { String::new() };
+        entries.push(format!(
+            "{}:{}:{}{}{}",
+            name,
+            qualifier,
+            if perm & 4 != 0 { 'r' } else { '-' },
+            if perm & 2 != 0 { 'w' } else { '-' },
+            if perm & 1 != 0 { 'x' } else { '-' }
+        ));
+    }
+    Ok(entries.join(","))
+}
+
+/// Convert an ACL in the text form written by `acl_from_xattr` back to its extended attribute.
+pub fn acl_to_xattr(text: &str) -> Result<Vec<u8>, String> {
+    let mut value = vec![];
+    value.write_u32::<LittleEndian>(ACL_XATTR_VERSION).unwrap();
+    for entry in text.split(',').filter(|e| !e.is_empty()) {
+        let invalid = || format!("Invalid ACL entry: {}", entry);
+        let fields: Vec<&str> = entry.split(':').collect();
+        if fields.len() != 3 || fields[2].len() != 3 {
+            return Err(invalid());
+        }
+        let qualified = !fields[1].is_empty();
+        let &(tag, _, _) = TAGS.iter()
+            .find(|t| t.1 == fields[0] && t.2 == qualified)
+            .ok_or_else(&invalid)?;
+        let id = if qualified {
+            fields[1].parse::<u32>().map_err(|_| invalid())?
+        } else {
+            ACL_UNDEFINED_ID
+        };
+        let mut perm = 0;
+        for (c, bit) in fields[2].chars().zip(&[(b'r', 4), (b'w', 2), (b'x', 1)]) {
+            match c {
+                '-' => (),
+                c if c as u8 == bit.0 => perm |= bit.1,
+                _ => return Err(invalid()),
+            }
+        }
+        value.write_u16::<LittleEndian>(tag).unwrap();
+        value.write_u16::<LittleEndian>(perm).unwrap();
+        value.write_u32::<LittleEndian>(id).unwrap();
+    }
+    Ok(value)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn text_round_trip() {
+        let text = "user::rw-,user:1000:r-x,group::r--,group:50:rwx,mask::rwx,other::---";
+        let value = acl_to_xattr(text).unwrap();
+        assert_eq!(value.len(), 4 + 6 * 8);
+        assert_eq!(acl_from_xattr(&value).unwrap(), text);
+    }
+
+    #[test]
+    fn invalid_acls() {
+        assert!(acl_to_xattr("user:alice:rw-").is_err());
+        assert!(acl_to_xattr("mask:1:rwx").is_err());
+        assert!(acl_to_xattr("other::rw").is_err());
+        assert!(acl_from_xattr(&[1, 0, 0, 0]).is_err());
+        assert!(acl_from_xattr(&[2, 0, 0, 0, 1]).is_err());
+    }
+}
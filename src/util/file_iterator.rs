@@ -14,13 +14,13 @@
 
 use std::fs;
 use std::io;
-use std::io::Read;
+use std::io::{Read, Seek, SeekFrom};
 use std::path::PathBuf;
+use super::ExtentReader;
 
 pub enum FileIterator {
     File(io::BufReader<fs::File>),
     Buf(Vec<u8>, usize),
-    #[cfg(all(test, feature = "benchmarks"))]
     Reader(Box<Read + Send>),
 }
 
@@ -31,11 +31,39 @@ impl FileIterator {
             Err(e) => Err(e),
         }
     }
+    /// Read only the data extents of a sparse file, as given by `util::data_extents`.
+    pub fn sparse(path: &PathBuf, extents: Vec<(u64, u64)>) -> io::Result<FileIterator> {
+        let f = fs::File::open(path)?;
+        Ok(FileIterator::from_reader(
+            Box::new(ExtentReader::new(io::BufReader::new(f), extents)),
+        ))
+    }
+    /// Continue `offset` bytes into the file. Only files opened with `new` can skip ahead.
+    pub fn skip_to(self, offset: u64) -> io::Result<FileIterator> {
+        match self {
+            FileIterator::File(mut f) => {
+                f.seek(SeekFrom::Start(offset))?;
+                Ok(FileIterator::File(f))
+            }
+            it => {
+                if offset > 0 {
+                    return Err(io::Error::new(io::ErrorKind::Other, "cannot skip ahead"));
+                }
+                Ok(it)
+            }
+        }
+    }
+    /// Read without crowding out other programs; see `util::limit_read_ahead`.
+    pub fn with_low_impact(self) -> FileIterator {
+        if let FileIterator::File(ref f) = self {
+            super::limit_read_ahead(f.get_ref());
+        }
+        self
+    }
     pub fn from_bytes(contents: Vec<u8>) -> FileIterator {
         FileIterator::Buf(contents, 0)
     }
 
-    #[cfg(all(test, feature = "benchmarks"))]
     pub fn from_reader<R>(r: Box<R>) -> FileIterator
     where
         R: Read + Send + 'static,
@@ -59,7 +87,6 @@ impl Read for FileIterator {
                     Ok(next.len())
                 }
             }
-            #[cfg(all(test, feature = "benchmarks"))]
             FileIterator::Reader(ref mut r) => r.read(buf),
         }
     }
@@ -0,0 +1,65 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Making background backups easy on interactive machines.
+
+use libc;
+use std::fs;
+use std::io;
+#[cfg(target_os = "linux")]
+use std::os::unix::io::AsRawFd;
+
+/// Lower the CPU and I/O priority of this process: the lowest scheduling priority and, where
+/// supported, the idle I/O class, which only gets the disk when nobody else wants it.
+///
+/// On Linux both only apply to the calling thread and the threads it starts afterwards, so
+/// call it before starting any.
+pub fn lower_priority() -> io::Result<()> {
+    if unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, 19) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    set_idle_io_class()
+}
+
+#[cfg(target_os = "linux")]
+fn set_idle_io_class() -> io::Result<()> {
+    const IOPRIO_WHO_PROCESS: libc::c_long = 1;
+    const IOPRIO_CLASS_IDLE: libc::c_long = 3;
+    const IOPRIO_CLASS_SHIFT: libc::c_long = 13;
+
+    let prio = IOPRIO_CLASS_IDLE << IOPRIO_CLASS_SHIFT;
+    if unsafe { libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, prio) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_idle_io_class() -> io::Result<()> {
+    Ok(())
+}
+
+/// Have the kernel read ahead no further than each read asks for, and not keep the data of
+/// `file` cached at the expense of other programs. This is only advice; it never fails.
+#[cfg(target_os = "linux")]
+pub fn limit_read_ahead(file: &fs::File) {
+    let fd = file.as_raw_fd();
+    unsafe {
+        libc::posix_fadvise(fd, 0, 0, libc::POSIX_FADV_RANDOM);
+        libc::posix_fadvise(fd, 0, 0, libc::POSIX_FADV_NOREUSE);
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn limit_read_ahead(_file: &fs::File) {}
@@ -12,23 +12,41 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+mod acl;
 mod counter;
 mod file_iterator;
 mod fnbox;
 mod infowriter;
 mod listdir;
+mod low_impact;
 mod sync_pool;
 mod ordered_collection;
+mod owner;
+mod pattern;
+mod pause;
 mod periodic_timer;
 mod process;
+mod progress;
+mod reflink;
+mod sparse;
 mod unique_priority_queue;
 
+pub use self::acl::{ACL_ACCESS_XATTR, ACL_DEFAULT_XATTR, acl_from_xattr, acl_to_xattr};
 pub use self::counter::Counter;
 pub use self::file_iterator::FileIterator;
 pub use self::fnbox::FnBox;
 pub use self::infowriter::InfoWriter;
 pub use self::listdir::{HasPath, PathHandler};
+pub use self::low_impact::{limit_read_ahead, lower_priority};
+pub use self::owner::{ChownMap, group_id, group_name, lchown, user_id, user_name};
+pub use self::pattern::Pattern;
+pub use self::pause::{handle_pause_signals, is_paused, set_paused, signal_pause,
+                      wait_while_paused};
 pub use self::periodic_timer::PeriodicTimer;
 pub use self::process::{MsgHandler, Process};
+pub use self::progress::{Progress, SilentProgress, TerminalProgress, human_bytes, parse_bytes};
+pub use self::reflink::reflink;
+pub use self::sparse::{ExtentReader, ExtentWriter, HoleFiller};
+pub use self::sparse::{data_extents, data_length, looks_sparse};
 pub use self::sync_pool::{SyncPool, SyncPoolGuard};
 pub use self::unique_priority_queue::UniquePriorityQueue;
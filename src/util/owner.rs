@@ -0,0 +1,213 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! File owners: user and group names, and remapping ids on restore.
+
+use libc;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::ffi::{CStr, CString};
+use std::io;
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::ptr;
+
+thread_local! {
+    // Lookups may go to the network; files mostly share a few owners.
+    static USER_NAMES: RefCell<HashMap<u64, Option<String>>> = RefCell::new(HashMap::new());
+    static GROUP_NAMES: RefCell<HashMap<u64, Option<String>>> = RefCell::new(HashMap::new());
+    static USER_IDS: RefCell<HashMap<String, Option<u64>>> = RefCell::new(HashMap::new());
+    static GROUP_IDS: RefCell<HashMap<String, Option<u64>>> = RefCell::new(HashMap::new());
+}
+
+/// Call a reentrant passwd or group lookup, growing its buffer until the entry fits.
+fn lookup<T, O, F, R>(f: F, read: R) -> Option<O>
+where
+    F: Fn(*mut T, *mut libc::c_char, libc::size_t, *mut *mut T) -> libc::c_int,
+    R: Fn(&T) -> O,
+{
+    let mut buf = vec![0 as libc::c_char; 1024];
+    loop {
+        let mut entry: T = unsafe { mem::zeroed() };
+        let mut result = ptr::null_mut();
+        match f(&mut entry, buf.as_mut_ptr(), buf.len(), &mut result) {
+            libc::ERANGE if buf.len() < 1 << 20 => {
+                let len = buf.len() * 2;
+                buf.resize(len, 0);
+            }
+            0 if !result.is_null() => return Some(read(&entry)),
+            _ => return None,
+        }
+    }
+}
+
+fn name_of(name: *const libc::c_char) -> String {
+    unsafe { CStr::from_ptr(name) }.to_string_lossy().into_owned()
+}
+
+/// The name of the user with id `uid`, if it has one.
+pub fn user_name(uid: u64) -> Option<String> {
+    USER_NAMES.with(|names| {
+        names.borrow_mut().entry(uid).or_insert_with(|| {
+            lookup(
+                |pwd, buf, len, res| unsafe {
+                    libc::getpwuid_r(uid as libc::uid_t, pwd, buf, len, res)
+                },
+                |pwd: &libc::passwd| name_of(pwd.pw_name),
+            )
+        }).clone()
+    })
+}
+
+/// The name of the group with id `gid`, if it has one.
+pub fn group_name(gid: u64) -> Option<String> {
+    GROUP_NAMES.with(|names| {
+        names.borrow_mut().entry(gid).or_insert_with(|| {
+            lookup(
+                |grp, buf, len, res| unsafe {
+                    libc::getgrgid_r(gid as libc::gid_t, grp, buf, len, res)
+                },
+                |grp: &libc::group| name_of(grp.gr_name),
+            )
+        }).clone()
+    })
+}
+
+/// The id of the user named `name` on this system.
+pub fn user_id(name: &str) -> Option<u64> {
+    let cname = match CString::new(name) {
+        Ok(cname) => cname,
+        Err(_) => return None,
+    };
+    USER_IDS.with(|ids| {
+        *ids.borrow_mut().entry(name.to_string()).or_insert_with(|| {
+            lookup(
+                |pwd, buf, len, res| unsafe {
+                    libc::getpwnam_r(cname.as_ptr(), pwd, buf, len, res)
+                },
+                |pwd: &libc::passwd| pwd.pw_uid as u64,
+            )
+        })
+    })
+}
+
+/// The id of the group named `name` on this system.
+pub fn group_id(name: &str) -> Option<u64> {
+    let cname = match CString::new(name) {
+        Ok(cname) => cname,
+        Err(_) => return None,
+    };
+    GROUP_IDS.with(|ids| {
+        *ids.borrow_mut().entry(name.to_string()).or_insert_with(|| {
+            lookup(
+                |grp, buf, len, res| unsafe {
+                    libc::getgrnam_r(cname.as_ptr(), grp, buf, len, res)
+                },
+                |grp: &libc::group| grp.gr_gid as u64,
+            )
+        })
+    })
+}
+
+/// Change the owning user and group of `path`, or of the link itself if it is a symbolic link.
+/// An id left out is not changed.
+pub fn lchown(path: &Path, uid: Option<u64>, gid: Option<u64>) -> io::Result<()> {
+    let cpath = CString::new(path.as_os_str().as_bytes())?;
+    // An id of -1 is left as it is.
+    let uid = uid.map_or(!0, |u| u as libc::uid_t);
+    let gid = gid.map_or(!0, |g| g as libc::gid_t);
+    if unsafe { libc::lchown(cpath.as_ptr(), uid, gid) } == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// Owners to give restored files instead of those recorded in the snapshot, from rules such as
+/// `user:1000:1001` or `group:staff:users`. A rule's first part matches the id or name in the
+/// snapshot; its second part is an id or name on this system.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ChownMap {
+    users: Vec<(String, u64)>,
+    groups: Vec<(String, u64)>,
+}
+
+fn matches(from: &str, id: Option<u64>, name: Option<&str>) -> bool {
+    id.map_or(false, |id| from == id.to_string()) || name == Some(from)
+}
+
+impl ChownMap {
+    pub fn add(&mut self, rule: &str) -> Result<(), String> {
+        let parts: Vec<&str> = rule.split(':').collect();
+        if parts.len() != 3 || parts[1].is_empty() || parts[2].is_empty() {
+            return Err(format!("Invalid owner mapping (want user:FROM:TO): {}", rule));
+        }
+        let (from, to) = (parts[1].to_string(), parts[2]);
+        match parts[0] {
+            "user" => {
+                let id = to.parse().ok().or_else(|| user_id(to)).ok_or_else(|| {
+                    format!("Unknown user: {}", to)
+                })?;
+                self.users.push((from, id));
+            }
+            "group" => {
+                let id = to.parse().ok().or_else(|| group_id(to)).ok_or_else(|| {
+                    format!("Unknown group: {}", to)
+                })?;
+                self.groups.push((from, id));
+            }
+            _ => return Err(format!("Invalid owner mapping (want user or group): {}", rule)),
+        }
+        Ok(())
+    }
+
+    /// The local user for the snapshot's user `uid` named `name`, if a rule maps it.
+    pub fn user(&self, uid: Option<u64>, name: Option<&str>) -> Option<u64> {
+        self.users.iter().find(|r| matches(&r.0, uid, name)).map(|r| r.1)
+    }
+
+    /// The local group for the snapshot's group `gid` named `name`, if a rule maps it.
+    pub fn group(&self, gid: Option<u64>, name: Option<&str>) -> Option<u64> {
+        self.groups.iter().find(|r| matches(&r.0, gid, name)).map(|r| r.1)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn root_is_known() {
+        assert_eq!(user_id("root"), Some(0));
+        assert_eq!(user_name(0), Some("root".to_string()));
+    }
+
+    #[test]
+    fn chown_map_rules() {
+        let mut map = ChownMap::default();
+        map.add("user:1000:1001").unwrap();
+        map.add("user:alice:2000").unwrap();
+        map.add("group:100:200").unwrap();
+        assert!(map.add("user:1000").is_err());
+        assert!(map.add("owner:1:2").is_err());
+
+        assert_eq!(map.user(Some(1000), None), Some(1001));
+        assert_eq!(map.user(Some(5), Some("alice")), Some(2000));
+        assert_eq!(map.user(Some(100), None), None);
+        assert_eq!(map.group(Some(100), Some("users")), Some(200));
+        assert_eq!(map.group(None, None), None);
+    }
+}
@@ -0,0 +1,118 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/// A shell-style wildcard pattern over paths.
+///
+/// `?` matches any single byte and `*` matches any sequence of bytes, including `/`. A pattern
+/// without a `/` is matched against the last path component only, so `*.conf` matches
+/// `etc/app/main.conf`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Pattern {
+    pattern: Vec<u8>,
+}
+
+impl Pattern {
+    pub fn new(pattern: &str) -> Pattern {
+        Pattern { pattern: pattern.as_bytes().to_vec() }
+    }
+
+    pub fn matches(&self, path: &[u8]) -> bool {
+        if self.pattern.contains(&b'/') {
+            wildcard_match(&self.pattern[..], path)
+        } else {
+            let name = match path.iter().rposition(|c| *c == b'/') {
+                Some(i) => &path[i + 1..],
+                None => path,
+            };
+            wildcard_match(&self.pattern[..], name)
+        }
+    }
+
+    /// Whether the pattern can match some path below directory `dir`. A pattern without a `/`
+    /// can match files in any directory.
+    pub fn may_match_below(&self, dir: &[u8]) -> bool {
+        if !self.pattern.contains(&b'/') {
+            return true;
+        }
+        let mut prefix = dir.to_vec();
+        prefix.push(b'/');
+        wildcard_prefix_match(&self.pattern[..], &prefix[..])
+    }
+}
+
+/// Whether `text` is a prefix of some text matching `pattern`.
+fn wildcard_prefix_match(pattern: &[u8], text: &[u8]) -> bool {
+    for (p, c) in pattern.iter().zip(text) {
+        match *p {
+            // A star can swallow all of the remaining text.
+            b'*' => return true,
+            b'?' => (),
+            p if p != *c => return false,
+            _ => (),
+        }
+    }
+    // Whatever is left of the pattern can match some suffix.
+    pattern.len() >= text.len()
+}
+
+fn wildcard_match(pattern: &[u8], text: &[u8]) -> bool {
+    // Position of the last `*` seen and the text position it currently covers up to.
+    let mut star: Option<(usize, usize)> = None;
+    let (mut p, mut t) = (0, 0);
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == b'?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == b'*' {
+            star = Some((p, t));
+            p += 1;
+        } else if let Some((sp, st)) = star {
+            // Let the last star swallow one more byte.
+            p = sp + 1;
+            t = st + 1;
+            star = Some((sp, st + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|c| *c == b'*')
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wildcards() {
+        assert!(Pattern::new("*.conf").matches(b"etc/app/main.conf"));
+        assert!(!Pattern::new("*.conf").matches(b"etc/app/main.conf.bak"));
+        assert!(Pattern::new("etc/*").matches(b"etc/app/main.conf"));
+        assert!(!Pattern::new("etc/*").matches(b"var/etc/x"));
+        assert!(Pattern::new("db-?.sqlite").matches(b"data/db-1.sqlite"));
+        assert!(Pattern::new("*").matches(b""));
+        assert!(!Pattern::new("a").matches(b""));
+    }
+
+    #[test]
+    fn matches_below() {
+        assert!(Pattern::new("*.conf").may_match_below(b"var"));
+        assert!(Pattern::new("etc/app/*.conf").may_match_below(b"etc"));
+        assert!(Pattern::new("etc/app/*.conf").may_match_below(b"etc/app"));
+        assert!(!Pattern::new("etc/app/*.conf").may_match_below(b"etc/other"));
+        assert!(!Pattern::new("etc/app/*.conf").may_match_below(b"var"));
+        assert!(Pattern::new("home/*/docs/x").may_match_below(b"home/user/music"));
+        assert!(Pattern::new("h?me/a").may_match_below(b"home"));
+    }
+}
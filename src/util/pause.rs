@@ -0,0 +1,86 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Pausing the backup pipeline, e.g. from a signal handler, while the user needs their
+//! bandwidth or disk.
+
+use libc;
+use std::sync::atomic::{ATOMIC_BOOL_INIT, AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+// Set from signal handlers, so this has to be a plain static.
+static PAUSED: AtomicBool = ATOMIC_BOOL_INIT;
+
+/// Pause or resume every pipeline of this process. Work already handed to the backend
+/// finishes; no new files are read and no new blobs are uploaded until resumed.
+pub fn set_paused(paused: bool) {
+    PAUSED.store(paused, Ordering::SeqCst);
+}
+
+pub fn is_paused() -> bool {
+    PAUSED.load(Ordering::SeqCst)
+}
+
+/// Block the calling thread for as long as the pipelines are paused.
+pub fn wait_while_paused() {
+    if !is_paused() {
+        return;
+    }
+    info!("Paused");
+    while is_paused() {
+        thread::sleep(Duration::from_millis(200));
+    }
+    info!("Resumed");
+}
+
+extern "C" fn on_signal(signal: libc::c_int) {
+    set_paused(signal == libc::SIGUSR1);
+}
+
+/// Pause on SIGUSR1 and resume on SIGUSR2.
+pub fn handle_pause_signals() {
+    unsafe {
+        libc::signal(libc::SIGUSR1, on_signal as libc::sighandler_t);
+        libc::signal(libc::SIGUSR2, on_signal as libc::sighandler_t);
+    }
+}
+
+/// Ask the process with the given id to pause, or to resume.
+pub fn signal_pause(pid: u32, paused: bool) -> Result<(), String> {
+    let signal = if paused { libc::SIGUSR1 } else { libc::SIGUSR2 };
+    if unsafe { libc::kill(pid as libc::pid_t, signal) } == 0 {
+        Ok(())
+    } else {
+        Err(format!("Could not signal process {}: {}", pid, ::std::io::Error::last_os_error()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libc;
+    use super::*;
+
+    #[test]
+    fn signals_pause_and_resume() {
+        handle_pause_signals();
+
+        // Raised signals are handled before `raise` returns.
+        unsafe { libc::raise(libc::SIGUSR1) };
+        assert!(is_paused());
+        unsafe { libc::raise(libc::SIGUSR2) };
+        assert!(!is_paused());
+        wait_while_paused();
+    }
+}
@@ -0,0 +1,211 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use time::{Duration, SteadyTime};
+
+use util::PeriodicTimer;
+
+
+/// Receives the progress of long-running operations such as commit, checkout and gc. It is
+/// called from several threads, for every file and chunk, so calls must be cheap.
+pub trait Progress: Send + Sync {
+    /// An operation starts. `total_bytes` is how much data it expects to process, if known.
+    fn start(&self, _operation: &str, _total_bytes: Option<u64>) {}
+
+    /// A file is being processed.
+    fn file(&self, _path: &Path) {}
+
+    /// File data was read by a commit or written by a checkout; for gc, chunk data was examined.
+    fn read(&self, _bytes: u64) {}
+
+    /// New data was handed to the backend.
+    fn uploaded(&self, _bytes: u64) {}
+
+    /// Uploaded data was committed to the backend; the rest is still queued.
+    fn stored(&self, _bytes: u64) {}
+
+    /// A chunk of file data was already stored, and was not uploaded again.
+    fn deduped(&self, _bytes: u64) {}
+
+    /// The operation is done.
+    fn finish(&self) {}
+}
+
+/// Ignores all progress; the default for library users.
+pub struct SilentProgress;
+
+impl Progress for SilentProgress {}
+
+struct TerminalState {
+    operation: String,
+    total_bytes: Option<u64>,
+    files: u64,
+    read: u64,
+    uploaded: u64,
+    path: PathBuf,
+    started: SteadyTime,
+    redraw: PeriodicTimer,
+}
+
+/// Draws a status line on standard error, redrawn a few times per second.
+pub struct TerminalProgress(Mutex<Option<TerminalState>>);
+
+impl TerminalProgress {
+    pub fn new() -> TerminalProgress {
+        TerminalProgress(Mutex::new(None))
+    }
+
+    fn update<F: FnOnce(&mut TerminalState)>(&self, f: F) {
+        let mut guard = self.0.lock().unwrap();
+        if let Some(ref mut state) = *guard {
+            f(state);
+            if state.redraw.did_fire() {
+                draw(state);
+            }
+        }
+    }
+}
+
+/// Parse a size such as `10GiB`, `500M` or `4096`. Units are powers of 1024.
+pub fn parse_bytes(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let split = s.find(|c: char| !c.is_digit(10)).unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+    let number: u64 = number.parse().map_err(|_| format!("Not a size: {}", s))?;
+    let unit = unit.trim().to_lowercase();
+    let shift = match unit.trim_right_matches("ib").trim_right_matches('b') {
+        "" => 0,
+        "k" => 10,
+        "m" => 20,
+        "g" => 30,
+        "t" => 40,
+        _ => return Err(format!("Unknown unit in size: {}", s)),
+    };
+    number.checked_mul(1 << shift).ok_or_else(
+        || format!("Size too large: {}", s),
+    )
+}
+
+pub fn human_bytes(bytes: u64) -> String {
+    let units = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit + 1 < units.len() {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", size, units[unit])
+    }
+}
+
+fn eta(state: &TerminalState) -> Option<Duration> {
+    let total = match state.total_bytes {
+        Some(total) if total > state.read && state.read > 0 => total,
+        _ => return None,
+    };
+    let elapsed = (SteadyTime::now() - state.started).num_milliseconds() as f64;
+    let remaining = elapsed * (total - state.read) as f64 / state.read as f64;
+    Some(Duration::milliseconds(remaining as i64))
+}
+
+fn draw(state: &TerminalState) {
+    let mut line = format!(
+        "{}: {} files, {} read, {} uploaded",
+        state.operation,
+        state.files,
+        human_bytes(state.read),
+        human_bytes(state.uploaded)
+    );
+    if let Some(eta) = eta(state) {
+        line.push_str(&format!(
+            ", {}m{:02}s left",
+            eta.num_minutes(),
+            eta.num_seconds() % 60
+        ));
+    }
+    if state.files > 0 {
+        line.push_str(&format!(" - {}", state.path.display()));
+    }
+    // Keep to one line of a common terminal, leaving the start of the status visible.
+    let line: String = line.chars().take(120).collect();
+    let mut err = io::stderr();
+    let _ = write!(err, "\r\x1b[K{}", line);
+    let _ = err.flush();
+}
+
+impl Progress for TerminalProgress {
+    fn start(&self, operation: &str, total_bytes: Option<u64>) {
+        *self.0.lock().unwrap() = Some(TerminalState {
+            operation: operation.to_string(),
+            total_bytes: total_bytes,
+            files: 0,
+            read: 0,
+            uploaded: 0,
+            path: PathBuf::new(),
+            started: SteadyTime::now(),
+            redraw: PeriodicTimer::new(Duration::milliseconds(200)),
+        });
+    }
+
+    fn file(&self, path: &Path) {
+        self.update(|s| {
+            s.files += 1;
+            s.path = path.to_path_buf();
+        })
+    }
+
+    fn read(&self, bytes: u64) {
+        self.update(|s| s.read += bytes)
+    }
+
+    fn uploaded(&self, bytes: u64) {
+        self.update(|s| s.uploaded += bytes)
+    }
+
+    fn finish(&self) {
+        if let Some(state) = self.0.lock().unwrap().take() {
+            draw(&state);
+            let _ = writeln!(io::stderr(), "");
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn human_bytes_picks_a_unit() {
+        assert_eq!(human_bytes(512), "512 B");
+        assert_eq!(human_bytes(1536), "1.5 KiB");
+        assert_eq!(human_bytes(3 * 1024 * 1024 * 1024), "3.0 GiB");
+    }
+
+    #[test]
+    fn parse_bytes_reads_units() {
+        assert_eq!(parse_bytes("4096"), Ok(4096));
+        assert_eq!(parse_bytes("10GiB"), Ok(10 * 1024 * 1024 * 1024));
+        assert_eq!(parse_bytes("500M"), Ok(500 * 1024 * 1024));
+        assert_eq!(parse_bytes("2 kb"), Ok(2048));
+        assert!(parse_bytes("10 furlongs").is_err());
+        assert!(parse_bytes("GiB").is_err());
+    }
+}
@@ -0,0 +1,269 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Sparse files: files with holes that read as zeros but take no space on disk.
+//!
+//! The data of a sparse file is kept as the concatenation of its data extents, given as
+//! (offset, length) pairs in file order. Everything outside the extents is a hole.
+
+use libc;
+use std::cmp;
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::io::AsRawFd;
+
+/// Whether a file with this metadata has holes that are worth looking for.
+pub fn looks_sparse(meta: &fs::Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    meta.is_file() && meta.blocks() * 512 < meta.len()
+}
+
+/// The data extents of the first `len` bytes of `file`, found with `SEEK_DATA` and `SEEK_HOLE`.
+/// File systems without support for these report the whole file as data.
+pub fn data_extents(file: &fs::File, len: u64) -> io::Result<Vec<(u64, u64)>> {
+    let fd = file.as_raw_fd();
+    let mut extents = vec![];
+    let mut pos = 0;
+    while pos < len {
+        let start = unsafe { libc::lseek(fd, pos as libc::off_t, libc::SEEK_DATA) };
+        if start < 0 {
+            let e = io::Error::last_os_error();
+            if e.raw_os_error() == Some(libc::ENXIO) {
+                // Only a hole is left.
+                break;
+            }
+            return Err(e);
+        }
+        let end = unsafe { libc::lseek(fd, start, libc::SEEK_HOLE) };
+        if end < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let (start, end) = (start as u64, cmp::min(end as u64, len));
+        if start >= len {
+            break;
+        }
+        extents.push((start, end - start));
+        pos = end;
+    }
+    Ok(extents)
+}
+
+/// Total length of the data in `extents`.
+pub fn data_length(extents: &[(u64, u64)]) -> u64 {
+    extents.iter().map(|&(_, len)| len).sum()
+}
+
+/// Reads only the data extents of a file, one after the other.
+pub struct ExtentReader<R> {
+    inner: R,
+    extents: Vec<(u64, u64)>,
+    next: usize,
+    remaining: u64,
+}
+
+impl<R: Read + Seek> ExtentReader<R> {
+    pub fn new(inner: R, extents: Vec<(u64, u64)>) -> ExtentReader<R> {
+        ExtentReader {
+            inner: inner,
+            extents: extents,
+            next: 0,
+            remaining: 0,
+        }
+    }
+}
+
+impl<R: Read + Seek> Read for ExtentReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.remaining == 0 {
+            if self.next == self.extents.len() {
+                return Ok(0);
+            }
+            let (offset, len) = self.extents[self.next];
+            self.inner.seek(SeekFrom::Start(offset))?;
+            self.next += 1;
+            self.remaining = len;
+        }
+        let want = cmp::min(buf.len() as u64, self.remaining) as usize;
+        let n = self.inner.read(&mut buf[..want])?;
+        if n == 0 {
+            // The file shrank; stop rather than read past the extent.
+            self.remaining = 0;
+            self.next = self.extents.len();
+        }
+        self.remaining -= n as u64;
+        Ok(n)
+    }
+}
+
+/// Writes the concatenated data of `extents` back into place, leaving holes in between.
+/// `finish` extends the file to its full length, so that a trailing hole is kept.
+pub struct ExtentWriter<W> {
+    inner: W,
+    extents: Vec<(u64, u64)>,
+    next: usize,
+    remaining: u64,
+}
+
+impl<W: Write + Seek> ExtentWriter<W> {
+    pub fn new(inner: W, extents: Vec<(u64, u64)>) -> ExtentWriter<W> {
+        ExtentWriter {
+            inner: inner,
+            extents: extents,
+            next: 0,
+            remaining: 0,
+        }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+impl ExtentWriter<fs::File> {
+    pub fn finish(mut self, len: u64) -> io::Result<()> {
+        self.inner.flush()?;
+        self.inner.set_len(len)
+    }
+}
+
+impl<W: Write + Seek> Write for ExtentWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        while self.remaining == 0 {
+            if self.next == self.extents.len() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "more data than the extents of a sparse file hold",
+                ));
+            }
+            let (offset, len) = self.extents[self.next];
+            self.inner.seek(SeekFrom::Start(offset))?;
+            self.next += 1;
+            self.remaining = len;
+        }
+        let want = cmp::min(buf.len() as u64, self.remaining) as usize;
+        let n = self.inner.write(&buf[..want])?;
+        self.remaining -= n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Reads the full contents of a sparse file from the concatenated data of its extents, with
+/// zeros for the holes.
+pub struct HoleFiller<R> {
+    inner: R,
+    extents: Vec<(u64, u64)>,
+    next: usize,
+    pos: u64,
+    len: u64,
+}
+
+impl<R: Read> HoleFiller<R> {
+    pub fn new(inner: R, extents: Vec<(u64, u64)>, len: u64) -> HoleFiller<R> {
+        HoleFiller {
+            inner: inner,
+            extents: extents,
+            next: 0,
+            pos: 0,
+            len: len,
+        }
+    }
+}
+
+impl<R: Read> Read for HoleFiller<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.next < self.extents.len() {
+            let (offset, len) = self.extents[self.next];
+            if self.pos < offset + len {
+                break;
+            }
+            self.next += 1;
+        }
+        let n = match self.extents.get(self.next) {
+            Some(&(offset, _)) if self.pos < offset => {
+                let n = cmp::min(buf.len() as u64, offset - self.pos) as usize;
+                for b in &mut buf[..n] {
+                    *b = 0;
+                }
+                n
+            }
+            Some(&(offset, len)) => {
+                let n = cmp::min(buf.len() as u64, offset + len - self.pos) as usize;
+                let n = self.inner.read(&mut buf[..n])?;
+                if n == 0 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "sparse file data ended early",
+                    ));
+                }
+                n
+            }
+            None => {
+                let n = cmp::min(buf.len() as u64, self.len.saturating_sub(self.pos)) as usize;
+                for b in &mut buf[..n] {
+                    *b = 0;
+                }
+                n
+            }
+        };
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Cursor, Read, Write};
+
+    fn sparse() -> (Vec<u8>, Vec<(u64, u64)>) {
+        let mut file = vec![0; 100];
+        for i in 10..20 {
+            file[i] = i as u8;
+        }
+        for i in 50..60 {
+            file[i] = i as u8;
+        }
+        (file, vec![(10, 10), (50, 10)])
+    }
+
+    #[test]
+    fn extents_round_trip() {
+        let (file, extents) = sparse();
+
+        let mut data = vec![];
+        ExtentReader::new(Cursor::new(file.clone()), extents.clone())
+            .read_to_end(&mut data)
+            .unwrap();
+        assert_eq!(data.len() as u64, data_length(&extents));
+        assert_eq!(&data[..10], &file[10..20]);
+
+        let mut written = ExtentWriter::new(Cursor::new(vec![0; 100]), extents.clone());
+        written.write_all(&data).unwrap();
+        assert_eq!(written.into_inner().into_inner(), file);
+        assert!(
+            ExtentWriter::new(Cursor::new(vec![]), extents.clone())
+                .write_all(&[0; 21])
+                .is_err()
+        );
+
+        let mut filled = vec![];
+        HoleFiller::new(&data[..], extents, 100).read_to_end(&mut filled).unwrap();
+        assert_eq!(filled, file);
+    }
+}